@@ -0,0 +1,30 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::env;
+use std::process::Command;
+
+// Plugin libraries are compatible with the server built by the same compiler with the same features
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc).arg("-V").output().ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_string())
+        .unwrap_or_else(|| "rustc unknown".to_string());
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|feature| feature.to_lowercase()))
+        .collect();
+    features.sort();
+
+    // FNV-1a, stable between the builds
+    let hash = features.join(",").bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    println!("cargo:rustc-env=WS_RUSTC_VERSION={}", version);
+    println!("cargo:rustc-env=WS_FEATURES_HASH={:016x}", hash);
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
@@ -0,0 +1,38 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use ws_platform_plugin::prelude::*;
+
+// 'hello: World' in the route responds with 'Hello, World!'
+pub struct Hello {}
+
+impl Plugin for Hello {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Hello"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+        add_command!(Context::ROUTE, "hello", |route: &mut RouteContext, name: HttpComplexValue| {
+            route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                let text = format!("Hello, {}!", r.expand(&name));
+                let mut resp = HttpResponse::new(r);
+                resp.send(HttpStatus::OK, "text/plain", Some(text.as_bytes()));
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        Ok(Code::OK)
+    }
+}
+
+impl Hello {
+    pub fn new() -> Hello {
+        Hello {}
+    }
+}
+
+export_plugins!(HttpModule: Hello);
@@ -0,0 +1,78 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+// Facade of the server for the external plugins. Only the items re-exported here are kept
+// compatible within the major version of the crate, the rest of the server may change.
+//
+//  use ws_platform_plugin::prelude::*;
+//
+//  pub struct Hello {}
+//
+//  impl Plugin for Hello {
+//      type ModuleType = HTTP;
+//
+//      fn configure(&mut self) -> ActionResult {
+//          add_command!(Context::ROUTE, "hello", |route: &mut RouteContext, text: String| {
+//              route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+//                  let mut resp = HttpResponse::new(r);
+//                  resp.send(HttpStatus::OK, "text/plain", Some(text.as_bytes()));
+//                  resp
+//              }));
+//              Ok(None)
+//          })?;
+//          Ok(Code::OK)
+//      }
+//  }
+//
+//  impl Hello {
+//      pub fn new() -> Hello {
+//          Hello {}
+//      }
+//  }
+//
+//  export_plugins!(HttpModule: Hello);
+
+pub use web_server::{ add_command, add_block, add_schema, add_expander, add_empty_block, export_plugins, throw };
+
+// Version of the server the plugin is built against, checked by 'load_plugin'
+pub const API_VERSION: &str = web_server::plugin::PLUGIN_API_VERSION;
+
+pub mod error {
+    pub use web_server::error::{ Code, CoreError, CoreResult };
+}
+
+pub mod plugin {
+    pub use web_server::plugin::{ Plugin, ActionResult };
+}
+
+// Commands, blocks and schemas of the config
+pub mod config {
+    pub use web_server::config::{ Value, ConfigBlock, CommandResult, CommandContext, Schema, SchemaType, NoValue };
+    pub use yaml_rust::Yaml;
+}
+
+// Complex values with the variables, 'Hello, ${remote_addr}'
+pub mod variable {
+    pub use web_server::variable::{ Variable, LazyHandler };
+}
+
+pub mod http {
+    pub use web_server::http::{
+        HTTP, HttpModule, Context, HttpContext, ServerContext, RouteContext,
+        HttpRequest, HttpResponse, HttpStatus, HttpMethod, HttpHeaders, HttpQuery,
+        HttpComplexValue, HttpMap, HttpList,
+        SetVarHandler, RewriteHandler, AccessHandler, ContentHandler,
+        HeaderFilterHandler, BodyFilterHandler, LogHandler
+    };
+    pub use web_server::module::{ Request, Response };
+}
+
+pub mod prelude {
+    pub use crate::{ add_command, add_block, add_schema, add_expander, add_empty_block, export_plugins, throw };
+    pub use crate::error::*;
+    pub use crate::plugin::*;
+    pub use crate::config::*;
+    pub use crate::variable::*;
+    pub use crate::http::*;
+}
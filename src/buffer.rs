@@ -2,15 +2,26 @@
  * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
  */
 
-use std::io::prelude::*;
-use mio::net::TcpStream;
+use std::io::IoSlice;
 use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::bytes::Bytes;
+use crate::core::buffers::BufferPool;
+use crate::tcp_socket::TcpSocket;
 
 pub struct Buffer {
     data: Vec<u8>,
     rpos: usize,
     wpos: usize,
-    end: usize
+    end: usize,
+    // data is rented from the pool on demand and returned on release
+    pool: Option<Arc<BufferPool>>,
+    // shared data sent after the buffered one without copying and the data written after it,
+    // all of them go out with one writev
+    attached: Option<(Bytes, usize)>,
+    trailer: Vec<u8>,
+    tpos: usize
 }
 
 impl Default for Buffer {
@@ -19,7 +30,21 @@ impl Default for Buffer {
             data: Vec::with_capacity(4096),
             rpos: 0,
             wpos: 0,
-            end: 0
+            end: 0,
+            pool: None,
+            attached: None,
+            trailer: Vec::new(),
+            tpos: 0
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            if self.data.capacity() != 0 {
+                pool.put(std::mem::take(&mut self.data));
+            }
         }
     }
 }
@@ -32,11 +57,62 @@ impl Deref for Buffer {
 }
 
 impl Buffer {
+    pub fn pooled(pool: Option<Arc<BufferPool>>) -> Buffer {
+        match pool {
+            Some(pool) => Buffer {
+                data: Vec::new(),
+                rpos: 0,
+                wpos: 0,
+                end: 0,
+                pool: Some(pool),
+                attached: None,
+                trailer: Vec::new(),
+                tpos: 0
+            },
+            None => Buffer::default()
+        }
+    }
+
+    fn rent_data(&mut self, size: usize) {
+        if self.data.capacity() == 0 {
+            if let Some(pool) = &self.pool {
+                self.data = pool.rent(std::cmp::max(size, 4096));
+            }
+        }
+    }
+
+    // Pooled data goes back to the pool, the next read or write rents it again
+    pub fn release(&mut self) {
+        self.reset();
+        if let Some(pool) = &self.pool {
+            if self.data.capacity() != 0 {
+                pool.put(std::mem::take(&mut self.data));
+            }
+        }
+    }
+
+    // Buffer of the same pool, e.g. for the request body
+    pub fn rent(&self, size: usize) -> Vec<u8> {
+        match &self.pool {
+            Some(pool) => pool.rent(size),
+            None => Vec::with_capacity(size)
+        }
+    }
+
+    pub fn give_back(&self, buf: Vec<u8>) {
+        if let Some(pool) = &self.pool {
+            pool.put(buf);
+        }
+    }
+
     pub fn reset(&mut self) {
         self.rpos = 0;
         self.wpos = 0;
         self.end = 0;
         self.data.clear();
+        self.attached = None;
+        self.trailer.clear();
+        self.tpos = 0;
     }
 
     pub fn getc(&mut self) -> u8 {
@@ -45,32 +121,82 @@ impl Buffer {
         c
     }
 
-    pub fn read(&mut self, stream: &mut TcpStream) -> std::io::Result<(bool, usize)> {
+    pub fn read(&mut self, stream: &mut TcpSocket) -> std::io::Result<(bool, usize)> {
+        self.rent_data(4096);
         if self.end >= self.data.len() / 2 {
             self.data.resize(match self.data.len() {
-                0 => 4096,
+                0 => std::cmp::max(4096, self.data.capacity()),
                 len => len * 2
             }, 0);
         }
-        let sz = stream.read(&mut self.data)?;
+        let sz = stream.recv(&mut self.data)?;
         self.end += sz;
         Ok((sz == 0, sz))
     }
 
-    pub fn write(&mut self, stream: &mut TcpStream) -> std::io::Result<(bool, usize)> {
-        if self.end > self.wpos {
-            let sz = stream.write(&mut self.data[self.wpos..self.end])?;
-            self.wpos += sz;
-            return Ok((self.wpos == self.end, sz));
+    pub fn write(&mut self, stream: &mut TcpSocket) -> std::io::Result<(bool, usize)> {
+        self.write_limited(stream, usize::MAX)
+    }
+
+    pub fn write_limited(&mut self, stream: &mut TcpSocket, limit: usize) -> std::io::Result<(bool, usize)> {
+        let attached: &[u8] = match &self.attached {
+            Some((data, pos)) => &data[*pos..],
+            None => &[]
+        };
+        let segments = [&self.data[self.wpos..self.end], attached, &self.trailer[self.tpos..]];
+        let pending: usize = segments.iter().map(|segment| segment.len()).sum();
+        if pending == 0 {
+            return Ok((true, 0));
+        }
+
+        let mut left = limit;
+        let mut slices: [IoSlice; 3] = [IoSlice::new(&[]), IoSlice::new(&[]), IoSlice::new(&[])];
+        let mut count = 0;
+        for segment in segments.iter().filter(|segment| !segment.is_empty()) {
+            if left == 0 {
+                break;
+            }
+            let len = std::cmp::min(segment.len(), left);
+            slices[count] = IoSlice::new(&segment[..len]);
+            count += 1;
+            left -= len;
         }
-        Ok((true, 0))
+
+        let sz = stream.send(&slices[..count])?;
+
+        let mut rest = sz;
+        let head = std::cmp::min(rest, self.end - self.wpos);
+        self.wpos += head;
+        rest -= head;
+        if let Some((data, pos)) = &mut self.attached {
+            let body = std::cmp::min(rest, data.len() - *pos);
+            *pos += body;
+            rest -= body;
+        }
+        self.tpos += rest;
+
+        Ok((sz == pending, sz))
     }
 
     pub fn extend(&mut self, slice: &[u8]) {
+        if self.attached.is_some() {
+            self.trailer.extend_from_slice(slice);
+            return;
+        }
+        self.rent_data(slice.len());
         self.data.extend_from_slice(slice);
         self.end += slice.len();
     }
 
+    // Shared data is queued without copying, the next writes go after it.
+    // Only one piece is queued, the others are copied.
+    pub fn attach(&mut self, data: Bytes) {
+        match self.attached {
+            Some(_) => self.extend(&data),
+            None => self.attached = Some((data, 0))
+        }
+    }
+
     pub fn end(&self) -> bool {
         self.rpos >= self.end
     }
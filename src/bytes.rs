@@ -0,0 +1,129 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::fmt;
+use std::ops::{ Bound, Deref, RangeBounds };
+use std::sync::Arc;
+
+// Reference counted bytes, clones and slices share the same allocation.
+// Appending is done in place while the bytes are not shared.
+#[derive(Clone, Default)]
+pub struct Bytes {
+    data: Arc<Vec<u8>>,
+    start: usize,
+    end: usize
+}
+
+impl Bytes {
+    pub fn new() -> Bytes {
+        Bytes::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Bytes {
+        Bytes::from(Vec::with_capacity(capacity))
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> Bytes {
+        let start = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => start + 1,
+            Bound::Unbounded => 0
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => end + 1,
+            Bound::Excluded(end) => *end,
+            Bound::Unbounded => self.len()
+        };
+        assert!(start <= end && end <= self.len(), "Bytes::slice: {}..{} is out of 0..{}", start, end, self.len());
+        Bytes {
+            data: Arc::clone(&self.data),
+            start: self.start + start,
+            end: self.start + end
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        let whole = self.start == 0 && self.end == self.data.len();
+        match Arc::get_mut(&mut self.data) {
+            Some(vec) if whole => vec.extend_from_slice(data),
+            _ => {
+                let mut vec = Vec::with_capacity(self.len() + data.len());
+                vec.extend_from_slice(self);
+                vec.extend_from_slice(data);
+                self.data = Arc::new(vec);
+                self.start = 0;
+            }
+        }
+        self.end = self.data.len();
+    }
+
+    // Vector is taken back without copying if it is not shared
+    pub fn try_into_vec(self) -> Result<Vec<u8>, Bytes> {
+        if self.start != 0 || self.end != self.data.len() {
+            return Err(self);
+        }
+        let (start, end) = (self.start, self.end);
+        Arc::try_unwrap(self.data).map_err(|data| Bytes { data, start, end })
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.try_into_vec().unwrap_or_else(|bytes| bytes.to_vec())
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.data[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(data: Vec<u8>) -> Bytes {
+        let end = data.len();
+        Bytes {
+            data: Arc::new(data),
+            start: 0,
+            end
+        }
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(data: &[u8]) -> Bytes {
+        Bytes::from(data.to_vec())
+    }
+}
+
+impl From<String> for Bytes {
+    fn from(data: String) -> Bytes {
+        Bytes::from(data.into_bytes())
+    }
+}
+
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Bytes) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
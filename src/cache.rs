@@ -0,0 +1,375 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::panic::{ catch_unwind, AssertUnwindSafe };
+use std::sync::{ Arc, Condvar, Mutex, RwLock, Weak };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ channel, Sender };
+use std::time::{ Duration, Instant };
+use std::thread;
+
+use crate::error::CoreError;
+use crate::core::status::{ self, Json };
+
+pub type Fetcher<V> = Arc<dyn Fn(&str) -> Result<V, CoreError> + Send + Sync>;
+
+// Source of the time the entries are aged by, tests move it by hand
+type Clock = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+type Entries<V> = RwLock<HashMap<String, Entry<V>>>;
+
+struct Entry<V> {
+    value: Arc<V>,
+    updated: Instant,
+    // set by the first caller seeing the stale value, taken by the refresher
+    refreshing: AtomicBool
+}
+
+// Fetch of the expired value shared by the callers waiting for it
+struct Flight<V> {
+    result: Mutex<Option<Result<Arc<V>, String>>>,
+    done: Condvar
+}
+
+// Ends the flight of the leader even when the fetcher panics, the waiters get an error instead of hanging
+struct Landing<'a, V> {
+    flights: &'a Mutex<HashMap<String, Arc<Flight<V>>>>,
+    key: &'a str,
+    flight: Arc<Flight<V>>
+}
+
+impl<'a, V> Drop for Landing<'a, V> {
+    fn drop(&mut self) {
+        self.flights.lock().unwrap_or_else(|err| err.into_inner()).remove(self.key);
+        let mut result = self.flight.result.lock().unwrap_or_else(|err| err.into_inner());
+        if result.is_none() {
+            *result = Some(Err("fetch has panicked".to_string()));
+        }
+        self.flight.done.notify_all();
+    }
+}
+
+// Cache of the values looked up remotely, the redis keyval zones use it for the lookups of the variables.
+// Value is fresh during 'ttl', after that it is returned as is during 'stale_ttl'
+// while the refresher thread of the cache fetches it, so the fetch is on the request path
+// only on warm-up or when the value is completely expired, once for all the callers.
+// Values above max_entries are returned without being cached, zero means unlimited.
+pub struct RefreshCache<V: Send + Sync + 'static> {
+    name: String,
+    ttl: Duration,
+    stale_ttl: Duration,
+    max_entries: usize,
+    fetcher: Fetcher<V>,
+    clock: Clock,
+    entries: Arc<Entries<V>>,
+    flights: Arc<Mutex<HashMap<String, Arc<Flight<V>>>>>,
+    // keys to refresh, the refresher stops when the cache is dropped
+    refresher: Arc<Mutex<Sender<String>>>
+}
+
+impl<V: Send + Sync + 'static> Clone for RefreshCache<V> {
+    fn clone(&self) -> Self {
+        RefreshCache {
+            name: self.name.clone(),
+            ttl: self.ttl,
+            stale_ttl: self.stale_ttl,
+            max_entries: self.max_entries,
+            fetcher: Arc::clone(&self.fetcher),
+            clock: Arc::clone(&self.clock),
+            entries: Arc::clone(&self.entries),
+            flights: Arc::clone(&self.flights),
+            refresher: Arc::clone(&self.refresher)
+        }
+    }
+}
+
+fn store<V>(entries: &Entries<V>, max_entries: usize, key: &str, value: Arc<V>, updated: Instant) {
+    let mut entries = entries.write().unwrap();
+    if max_entries != 0 && entries.len() >= max_entries && !entries.contains_key(key) {
+        return;
+    }
+    entries.insert(key.to_string(), Entry {
+        value,
+        updated,
+        refreshing: AtomicBool::new(false)
+    });
+}
+
+impl<V: Send + Sync + 'static> RefreshCache<V> {
+    pub fn new(name: &str, ttl: Duration, stale_ttl: Duration, max_entries: usize, fetcher: Fetcher<V>) -> RefreshCache<V> {
+        RefreshCache::with_clock(name, ttl, stale_ttl, max_entries, fetcher, Arc::new(Instant::now))
+    }
+
+    fn with_clock(name: &str, ttl: Duration, stale_ttl: Duration, max_entries: usize, fetcher: Fetcher<V>, clock: Clock) -> RefreshCache<V> {
+        let entries: Arc<Entries<V>> = Arc::new(RwLock::new(HashMap::new()));
+        // cache is reported while it is alive
+        let weak = Arc::downgrade(&entries);
+        let now = Arc::clone(&clock);
+        status::register("caches", name, Box::new(move || {
+            weak.upgrade().map(|entries| {
+                let entries = entries.read().unwrap();
+                let now = now();
+                let stale = entries.values().filter(|entry| now.saturating_duration_since(entry.updated) >= ttl).count();
+                Json::object(vec![
+                    ("entries", entries.len().into()),
+                    ("stale", stale.into()),
+                    ("ttl", (ttl.as_millis() as u64).into()),
+                    ("stale_ttl", (stale_ttl.as_millis() as u64).into())
+                ])
+            })
+        }));
+        RefreshCache {
+            name: name.to_string(),
+            ttl,
+            stale_ttl,
+            max_entries,
+            refresher: Arc::new(Mutex::new(RefreshCache::refresher(name, max_entries, &fetcher, &clock, Arc::downgrade(&entries)))),
+            fetcher,
+            clock,
+            entries,
+            flights: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    // One thread of the cache refreshes the stale values one by one
+    fn refresher(name: &str, max_entries: usize, fetcher: &Fetcher<V>, clock: &Clock, entries: Weak<Entries<V>>) -> Sender<String> {
+        let (sender, receiver) = channel::<String>();
+        let cache_name = name.to_string();
+        let fetcher = Arc::clone(fetcher);
+        let clock = Arc::clone(clock);
+        let spawned = thread::Builder::new().name(format!("ws: cache {}", name)).spawn(move || {
+            while let Ok(key) = receiver.recv() {
+                // panic of the fetcher fails the refresh of the key, not the refresher
+                let result = match catch_unwind(AssertUnwindSafe(|| fetcher(&key))) {
+                    Ok(result) => result.map_err(|err| err.what().to_string()),
+                    Err(_) => Err("fetch has panicked".to_string())
+                };
+                let entries = match entries.upgrade() {
+                    Some(entries) => entries,
+                    None => break
+                };
+                match result {
+                    Ok(value) => store(&entries, max_entries, &key, Arc::new(value), clock()),
+                    Err(err) => {
+                        // keep serving the stale value until it is expired
+                        log_error!("warn", "cache '{}': failed to refresh '{}', {}", cache_name, key, err);
+                        if let Some(entry) = entries.read().unwrap().get(&key) {
+                            entry.refreshing.store(false, Ordering::Release);
+                        }
+                    }
+                }
+            }
+        });
+        if let Err(err) = spawned {
+            log_error!("error", "cache '{}': failed to start the refresher, {}", name, err);
+        }
+        sender
+    }
+
+    pub fn get(&self, key: &str) -> Result<Arc<V>, CoreError> {
+        if let Some(entry) = self.entries.read().unwrap().get(key) {
+            let age = (self.clock)().saturating_duration_since(entry.updated);
+            if age < self.ttl {
+                return Ok(Arc::clone(&entry.value));
+            }
+            if age < self.ttl + self.stale_ttl {
+                if !entry.refreshing.swap(true, Ordering::AcqRel) {
+                    self.refresh_background(key);
+                }
+                return Ok(Arc::clone(&entry.value));
+            }
+        }
+        self.fetch(key)
+    }
+
+    // Fetches the value on startup to keep the first requests off the fetch
+    pub fn warm(&self, key: &str) -> Result<(), CoreError> {
+        self.fetch(key).map(|_| ())
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        self.entries.write().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.read().unwrap().is_empty()
+    }
+
+    // The first caller fetches the value, the others wait for its result
+    fn fetch(&self, key: &str) -> Result<Arc<V>, CoreError> {
+        let (flight, leader) = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(key) {
+                Some(flight) => (Arc::clone(flight), false),
+                None => {
+                    let flight = Arc::new(Flight { result: Mutex::new(None), done: Condvar::new() });
+                    flights.insert(key.to_string(), Arc::clone(&flight));
+                    (flight, true)
+                }
+            }
+        };
+        if !leader {
+            let mut result = flight.result.lock().unwrap();
+            while result.is_none() {
+                result = flight.done.wait(result).unwrap();
+            }
+            return match result.as_ref().unwrap() {
+                Ok(value) => Ok(Arc::clone(value)),
+                Err(err) => throw!("{}", err)
+            };
+        }
+        let landing = Landing { flights: &self.flights, key, flight };
+        let result = (self.fetcher)(key).map(Arc::new);
+        if let Ok(value) = &result {
+            store(&self.entries, self.max_entries, key, Arc::clone(value), (self.clock)());
+        }
+        *landing.flight.result.lock().unwrap() = Some(match &result {
+            Ok(value) => Ok(Arc::clone(value)),
+            Err(err) => Err(err.what().to_string())
+        });
+        result
+    }
+
+    fn refresh_background(&self, key: &str) {
+        if self.refresher.lock().unwrap().send(key.to_string()).is_err() {
+            log_error!("warn", "cache '{}': refresher is not running, '{}' is not refreshed", self.name, key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn counting(delay: Duration) -> (Arc<AtomicUsize>, Fetcher<usize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_ = Arc::clone(&calls);
+        (calls, Arc::new(move |_: &str| {
+            thread::sleep(delay);
+            Ok(calls_.fetch_add(1, Ordering::SeqCst) + 1)
+        }))
+    }
+
+    #[test]
+    fn single_flight() {
+        let (calls, fetcher) = counting(Duration::from_millis(100));
+        let cache = RefreshCache::new("test_single_flight", Duration::from_secs(60), Duration::from_secs(60), 0, fetcher);
+        let thrs: Vec<_> = (0..8).map(|_| {
+            let cache = cache.clone();
+            thread::spawn(move || *cache.get("key").unwrap())
+        }).collect();
+        for thr in thrs {
+            assert_eq!(thr.join().unwrap(), 1);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    // Clock moved by the test
+    fn manual() -> (Arc<Mutex<Instant>>, Clock) {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let now_ = Arc::clone(&now);
+        (now, Arc::new(move || *now_.lock().unwrap()))
+    }
+
+    #[test]
+    fn stale() {
+        let (now, clock) = manual();
+        // the refresh waits for the test to let it go
+        let (release, released) = channel::<()>();
+        let released = Mutex::new(released);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_ = Arc::clone(&calls);
+        let fetcher: Fetcher<usize> = Arc::new(move |_: &str| {
+            let call = calls_.fetch_add(1, Ordering::SeqCst) + 1;
+            if call > 1 {
+                released.lock().unwrap().recv().unwrap();
+            }
+            Ok(call)
+        });
+        let cache = RefreshCache::with_clock("test_stale", Duration::from_millis(50), Duration::from_secs(60), 0, fetcher, clock);
+        assert_eq!(*cache.get("key").unwrap(), 1);
+        *now.lock().unwrap() += Duration::from_millis(60);
+        // stale value is returned at once, refreshed once in background
+        assert_eq!(*cache.get("key").unwrap(), 1);
+        assert_eq!(*cache.get("key").unwrap(), 1);
+        release.send(()).unwrap();
+        while *cache.get("key").unwrap() == 1 {
+            thread::yield_now();
+        }
+        assert_eq!(*cache.get("key").unwrap(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn expired() {
+        let (now, clock) = manual();
+        let (calls, fetcher) = counting(Duration::from_millis(0));
+        let cache = RefreshCache::with_clock("test_expired", Duration::from_millis(50), Duration::from_millis(50), 0, fetcher, clock);
+        assert_eq!(*cache.get("key").unwrap(), 1);
+        *now.lock().unwrap() += Duration::from_millis(100);
+        // completely expired value is fetched on the call
+        assert_eq!(*cache.get("key").unwrap(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn leader_panics() {
+        let (entered, entering) = channel::<()>();
+        let (release, released) = channel::<()>();
+        let entered = Mutex::new(entered);
+        let released = Mutex::new(released);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_ = Arc::clone(&calls);
+        let fetcher: Fetcher<usize> = Arc::new(move |_: &str| {
+            match calls_.fetch_add(1, Ordering::SeqCst) {
+                0 => {
+                    entered.lock().unwrap().send(()).unwrap();
+                    released.lock().unwrap().recv().unwrap();
+                    panic!("fetcher");
+                },
+                1 => throw!("unavailable"),
+                call => Ok(call)
+            }
+        });
+        let cache = RefreshCache::new("test_leader_panics", Duration::from_secs(60), Duration::from_secs(60), 0, fetcher);
+        let leader = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.get("key").map(|value| *value))
+        };
+        entering.recv().unwrap();
+        let waiter = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.get("key").map(|value| *value))
+        };
+        release.send(()).unwrap();
+        assert!(leader.join().is_err());
+        // the waiter gets the error of the panicked flight or fails the next fetch itself
+        assert!(waiter.join().unwrap().is_err());
+        // the flight is over, the next caller fetches again
+        while calls.load(Ordering::SeqCst) < 2 {
+            cache.get("key").ok();
+        }
+        assert_eq!(*cache.get("key").unwrap(), 2);
+    }
+
+    #[test]
+    fn bounded() {
+        let (_, fetcher) = counting(Duration::from_millis(0));
+        let cache = RefreshCache::new("test_bounded", Duration::from_secs(60), Duration::from_secs(60), 2, fetcher);
+        for key in &["a", "b", "c"] {
+            cache.get(key).unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+    }
+}
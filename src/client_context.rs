@@ -4,20 +4,30 @@
 
 use std::ops::{ Deref, DerefMut };
 use std::net::SocketAddr;
-use std::io::ErrorKind;
+use std::fs::File;
+use std::io::{ ErrorKind, Seek, SeekFrom };
 use std::time::Duration;
 use mio::{ Events, Interest, Poll, Token };
 
 use crate::connection_pool::StreamType;
 use crate::buffer::Buffer;
+use crate::bytes::Bytes;
 use crate::error::{ CoreError, Code, Code::* };
-use crate::core::State;
+use crate::core::{ State, buffers, poller };
+
+// Smaller data is cheaper to copy than to send as a separate segment
+const WRITE_COPY_MAX: usize = 1024;
+
+// Portion of the file sent at once, the IO thread serves the other sockets between the portions
+const SENDFILE_MAX: usize = 1024 * 1024;
 
 pub struct ClientContext {
     stream: StreamType,
     pub (crate) inner: Option<State>,
     pub server_addr: SocketAddr,
-    pub buf: Buffer
+    pub buf: Buffer,
+    // edge-triggered registration in the event pool, it stays armed until the interests change
+    pub (crate) armed: Option<(Token, Interest)>
 }
 
 impl Deref for ClientContext {
@@ -39,7 +49,8 @@ impl ClientContext {
             server_addr: server_addr,
             inner: None,
             stream: stream,
-            buf: Buffer::default()
+            buf: Buffer::default(),
+            armed: None
         }
     }
 
@@ -48,7 +59,8 @@ impl ClientContext {
             server_addr: server_addr,
             inner: Some(state),
             stream: stream,
-            buf: Buffer::default()
+            buf: Buffer::pooled(buffers::get(&server_addr)),
+            armed: None
         }
     }
 
@@ -71,6 +83,9 @@ impl ClientContext {
     }
 
     pub fn read(&mut self) -> Result<Code, CoreError> {
+        if self.throttle().is_some() {
+            return Ok(AGAIN);
+        }
         self.buf.reset();
         loop {
             match self.buf.read(&mut self.stream) {
@@ -78,7 +93,10 @@ impl ClientContext {
                     /* eof */
                     return Ok(DECLINED);
                 },
-                Ok(_) => {
+                Ok((_, sz)) => {
+                    if let Some(state) = &mut self.inner {
+                        state.add_received(sz);
+                    }
                     return Ok(OK);
                 },
                 Err(err) => {
@@ -97,8 +115,31 @@ impl ClientContext {
         }
     }
 
+    pub fn request_id(&self) -> Option<String> {
+        self.inner.as_ref().map(|state| state.request_id().to_string())
+    }
+
+    // Serial number of the connection
+    pub fn connection(&self) -> Option<u64> {
+        self.inner.as_ref().map(|state| state.connection())
+    }
+
+    // Number of the current request of the connection, starting from 1
+    pub fn connection_requests(&self) -> Option<u64> {
+        self.inner.as_ref().map(|state| state.requests() + 1)
+    }
+
+    pub fn response_timeout(&self) -> Option<Duration> {
+        self.inner.as_ref().and_then(|state| state.response_timeout())
+    }
+
+    pub fn throttle(&self) -> Option<Duration> {
+        self.inner.as_ref().and_then(|state| state.throttle())
+    }
+
+    // Buffer is returned to the pool of the workgroup until the next read or write
     pub fn reset(&mut self) {
-        self.buf.reset()
+        self.buf.release()
     }
 
     pub fn write_str(&mut self, s: &str) {
@@ -109,10 +150,48 @@ impl ClientContext {
         self.buf.extend(buf)
     }
 
+    // Large data is sent with the buffered one by writev instead of being copied
+    pub fn write_bytes(&mut self, buf: Bytes) {
+        match buf.len() < WRITE_COPY_MAX {
+            true => self.buf.extend(&buf),
+            false => self.buf.attach(buf)
+        }
+    }
+
+    // File is sent from its position, the position is moved by the sent size, zero size is the end of the file
+    pub fn sendfile(&mut self, file: &mut File, limit: usize) -> Result<(Code, usize), CoreError> {
+        let offset = file.stream_position().or_else(|err| throw!("Failed to send file to client: {}", err))?;
+        loop {
+            match poller::sendfile(&self.stream, file, offset, std::cmp::min(limit, SENDFILE_MAX)) {
+                Ok(sz) => {
+                    file.seek(SeekFrom::Current(sz as i64)).or_else(|err| throw!("Failed to send file to client: {}", err))?;
+                    return Ok((OK, sz));
+                },
+                Err(err) => {
+                    match err.kind() {
+                        ErrorKind::Interrupted => continue,
+                        ErrorKind::WouldBlock => return Ok((AGAIN, 0)),
+                        _ => {
+                            return throw!("Failed to send file to client: {}", err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     pub fn flush(&mut self) -> Result<(Code, usize), CoreError> {
+        self.flush_limited(usize::MAX)
+    }
+
+    pub fn flush_limited(&mut self, limit: usize) -> Result<(Code, usize), CoreError> {
         let mut sent = 0;
         loop {
-            match self.buf.write(&mut self.stream) {
+            match self.buf.write_limited(&mut self.stream, limit - sent) {
+                // short write, drained until the socket would block
+                Ok((false, sz)) if sz != 0 && sent + sz < limit => {
+                    sent += sz;
+                },
                 Ok((false, sz)) => {
                     return Ok((AGAIN, sent + sz));
                 },
@@ -6,12 +6,13 @@ use std::time::Duration;
 use std::rc::Rc;
 use std::cell::{ RefCell, RefMut };
 use std::any::Any;
-use yaml_rust::{ yaml, yaml::Yaml };
+use yaml_rust::{ yaml, yaml::Yaml, YamlEmitter };
 use std::ops::Deref;
 use std::convert::TryInto;
 use std::net::SocketAddr;
 use std::mem::take;
 use std::collections::LinkedList;
+use std::sync::Mutex;
 
 use crate::keyval::*;
 use crate::plugin::ActionResult;
@@ -25,11 +26,12 @@ pub type ConfigBlock = Yaml;
 pub type CommandData = Box<dyn Any>;
 pub type CommandContextType = Rc<RefCell<CommandContext>>;
 pub type CommandResult = Result<Option<CommandContextType>, CoreError>;
+pub type CommandFlush = Box<dyn FnMut(CommandContextType) -> Result<(), CoreError>>;
 
 pub struct CommandContext {
     parent: Option<CommandContextType>,
     pub data: Option<CommandData>,
-    pub flush: Option<Box<dyn FnMut(CommandContextType)>>
+    pub flush: Option<CommandFlush>
 }
 
 impl Drop for CommandContext {
@@ -40,7 +42,7 @@ impl Drop for CommandContext {
                 parent: self.parent.take(),
                 data: self.data.take(),
                 flush: None
-            })));
+            }))).unwrap();
         }
     }
 }
@@ -81,6 +83,19 @@ impl CommandContext {
         }
     }
 
+    // Exit of the block, its error is returned instead of the panic on the release of the context
+    fn leave(context: &CommandContextType) -> Result<(), CoreError> {
+        let mut context = context.borrow_mut();
+        match context.flush.take() {
+            Some(mut flush) => flush(Rc::new(RefCell::new(CommandContext {
+                parent: context.parent.take(),
+                data: context.data.take(),
+                flush: None
+            }))),
+            None => Ok(())
+        }
+    }
+
     pub fn parent(&self) -> Option<RefMut<CommandContext>> {
         match self.parent {
             None => None,
@@ -118,6 +133,203 @@ pub struct Command {
 
 pub struct Config;
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SchemaType {
+    Any,
+    Bool,
+    Integer,
+    String,
+    Map,
+    List
+}
+
+impl SchemaType {
+    fn matches(&self, v: &Yaml) -> bool {
+        matches!((self, v),
+            (SchemaType::Any, _) |
+            (SchemaType::Bool, Yaml::Boolean(_)) |
+            (SchemaType::Integer, Yaml::Integer(_)) |
+            (SchemaType::String, Yaml::String(_)) |
+            (SchemaType::Map, Yaml::Hash(_)) |
+            (SchemaType::List, Yaml::Array(_)))
+    }
+}
+
+struct SchemaKey {
+    name: String,
+    value_type: SchemaType,
+    required: bool,
+    range: Option<(i64, i64)>
+}
+
+// Declaration of the block keys, checked before the block commands are handled
+#[derive(Default)]
+pub struct Schema {
+    keys: Vec<SchemaKey>
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Schema::default()
+    }
+
+    pub fn required(mut self, name: &str, value_type: SchemaType) -> Schema {
+        self.keys.push(SchemaKey {
+            name: name.to_string(),
+            value_type,
+            required: true,
+            range: None
+        });
+        self
+    }
+
+    pub fn optional(mut self, name: &str, value_type: SchemaType) -> Schema {
+        self.keys.push(SchemaKey {
+            name: name.to_string(),
+            value_type,
+            required: false,
+            range: None
+        });
+        self
+    }
+
+    // Value of the plain command, e.g. 'error_log_ring: 1000', its range is given by the empty name
+    pub fn value(self, value_type: SchemaType) -> Schema {
+        self.required("", value_type)
+    }
+
+    pub fn range(mut self, name: &str, min: i64, max: i64) -> Schema {
+        if let Some(key) = self.keys.iter_mut().find(|key| key.name == name) {
+            key.range = Some((min, max));
+        }
+        self
+    }
+
+    pub fn validate(&self, path: &str, v: &Yaml) -> Result<(), CoreError> {
+        if let Some(key) = self.keys.iter().find(|key| key.name.is_empty()) {
+            return key.check(path, v);
+        }
+
+        let h = match v {
+            Yaml::Hash(h) => h,
+            _ => {
+                let required: Vec<&str> = self.keys.iter().filter(|key| key.required).map(|key| key.name.as_str()).collect();
+                if !required.is_empty() {
+                    return throw!("'{}': block with keys {:?} expected", path, required);
+                }
+                return Ok(());
+            }
+        };
+
+        for key in self.keys.iter() {
+            match h.get(&Yaml::String(key.name.clone())) {
+                None => if key.required {
+                    return throw!("'{}.{}': required key is missing", path, key.name);
+                },
+                Some(v) => key.check(&format!("{}.{}", path, key.name), v)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SchemaKey {
+    fn check(&self, path: &str, v: &Yaml) -> Result<(), CoreError> {
+        if !self.value_type.matches(v) {
+            return throw!("'{}': {:?} value expected", path, self.value_type);
+        }
+        if let (Some((min, max)), Yaml::Integer(i)) = (self.range, v) {
+            if *i < min || *i > max {
+                return throw!("'{}': value {} is out of range {}..{}", path, i, min, max);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == b[j] {
+                prev
+            } else {
+                1 + std::cmp::min(prev, std::cmp::min(row[j], row[j + 1]))
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+// The closest name to misspelled one
+pub fn suggest<'a, I: Iterator<Item=&'a str>>(name: &str, candidates: I) -> Option<String> {
+    candidates.map(|candidate| (distance(name, candidate), candidate))
+              .filter(|(d, _)| *d <= std::cmp::max(2, name.len() / 3))
+              .min_by_key(|(d, _)| *d)
+              .map(|(_, candidate)| candidate.to_string())
+}
+
+// Provider puts the runtime state of the plugin into the merged document of the snapshot
+pub type SnapshotProvider = Box<dyn Fn(&mut Yaml) + Send>;
+
+// Node of the document by the keys of the nested maps
+pub fn node_mut<'a>(doc: &'a mut Yaml, path: &[&str]) -> Option<&'a mut Yaml> {
+    let mut node = doc;
+    for key in path {
+        node = match node {
+            Yaml::Hash(h) => h.get_mut(&Yaml::String(key.to_string()))?,
+            _ => return None
+        };
+    }
+    Some(node)
+}
+
+// Blocks of the list, 'upstream' of '[ { upstream: { ... } }, ... ]'
+pub fn blocks_mut<'a>(list: Option<&'a mut Yaml>, block: &str) -> Vec<&'a mut yaml::Hash> {
+    let key = Yaml::String(block.to_string());
+    match list {
+        Some(Yaml::Array(items)) => items.iter_mut().filter_map(|item| match item {
+            Yaml::Hash(h) => match h.get_mut(&key) {
+                Some(Yaml::Hash(block)) => Some(block),
+                _ => None
+            },
+            _ => None
+        }).collect(),
+        _ => vec![]
+    }
+}
+
+// Documents of the modules in the order they are parsed
+#[derive(Default)]
+struct Snapshot {
+    docs: Vec<(&'static str, Vec<Yaml>)>,
+    providers: Vec<SnapshotProvider>
+}
+
+impl Snapshot {
+    fn reset(&mut self, module: &'static str) {
+        self.docs.retain(|(name, _)| *name != module);
+        self.docs.push((module, Vec::new()));
+    }
+
+    fn push(&mut self, module: &'static str, doc: Yaml) {
+        match self.docs.iter_mut().find(|(name, _)| *name == module) {
+            Some((_, docs)) => docs.push(doc),
+            None => self.docs.push((module, vec![doc]))
+        }
+    }
+}
+
+lazy_static! {
+    static ref SNAPSHOT: Mutex<Snapshot> = Mutex::new(Snapshot::default());
+}
+
 pub trait Value {
     type Type;
     fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError>;
@@ -185,11 +397,34 @@ impl Value for String {
     }
 }
 
+// A string or a list of the strings
+// Document of the command as is
+impl Value for Yaml {
+    type Type = Yaml;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        Ok(v.clone())
+    }
+}
+
+impl Value for Vec<String> {
+    type Type = Vec<String>;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        match v {
+            Yaml::String(s) => Ok(vec![s.clone()]),
+            Yaml::Array(list) => list.iter().map(|item| match item.as_str() {
+                Some(s) => Ok(s.to_string()),
+                None => throw!("type mismatch")
+            }).collect(),
+            _ => throw!("type mismatch")
+        }
+    }
+}
+
 impl<T: Request> Value for Variable<T> {
     type Type = Variable<T>;
     fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
         match v {
-            Yaml::String(s) => Ok(Variable::complex(s)),
+            Yaml::String(s) => Variable::parse(s).or_else(|err| throw!("{}", err)),
             Yaml::Null | Yaml::Hash(_) => Ok(Variable::complex("")),
             _ => throw!("type mismatch")
         }
@@ -199,7 +434,7 @@ impl<T: Request> Value for Variable<T> {
 fn val_to_cv<T: Request>(y: ConfigBlock) -> Result<Variable<T>, CoreError> {
     match y {
         Yaml::String(s)
-            => Ok(Variable::from(s)),
+            => Variable::parse(&s).or_else(|err| throw!("{}", err)),
         Yaml::Boolean(b)
             => Ok(Variable::from(b)),
         Yaml::Integer(i)
@@ -277,9 +512,119 @@ impl Value for Duration {
     }
 }
 
+// Replaces the list item '- name: value' with the list of items before the config is validated
+pub type Expander = Box<dyn Fn(&Yaml) -> Result<Vec<Yaml>, CoreError>>;
+
 pub type CommandCallback<T, Context> = Box<dyn Fn(&mut Context, <T as Value>::Type) -> CommandResult>;
 pub type CommandCallbackBlock<T> = Box<dyn Fn(&mut CommandContext, <T as Value>::Type) -> CommandResult>;
 
+// Expanded items may contain the items to expand as well
+fn expand_node<T: ModuleType + 'static>(path: &str, doc: &mut Yaml) -> ActionResult {
+    match doc {
+        Yaml::Array(v) => {
+            let mut expanded = Vec::with_capacity(v.len());
+            for mut x in take(v) {
+                let expander = match &x {
+                    Yaml::Hash(h) if h.len() == 1 => h.front().and_then(|(k, v)| {
+                        let key = k.as_str()?;
+                        GenericModule::<T>::expander(&format!("{}.{}", path, key)).map(|expander| (key, expander, v))
+                    }),
+                    _ => None
+                };
+                match expander {
+                    Some((key, expander, v)) => {
+                        let items = expander(v).or_else(|err| throw!("Failed to expand '{}.{}': {}", path, key, err.what()))?;
+                        if items.len() == 1 && items[0] == x {
+                            // left as is by its expander
+                            expand_node::<T>(path, &mut x)?;
+                            expanded.push(x);
+                            continue;
+                        }
+                        let mut items = Yaml::Array(items);
+                        expand_node::<T>(path, &mut items)?;
+                        if let Yaml::Array(items) = items {
+                            expanded.extend(items);
+                        }
+                    },
+                    None => {
+                        expand_node::<T>(path, &mut x)?;
+                        expanded.push(x);
+                    }
+                }
+            }
+            *v = expanded;
+        }
+        Yaml::Hash(h) => {
+            for (k, v) in h.iter_mut() {
+                if let Some(key) = k.as_str() {
+                    expand_node::<T>(&format!("{}.{}", path, key), v)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(OK)
+}
+
+// Values of plain commands are not validated, they may be maps of any keys
+fn validate_node<T: ModuleType + 'static>(path: &str, doc: &Yaml) -> ActionResult {
+    match doc {
+        Yaml::Array(v) => {
+            for x in v {
+                validate_node::<T>(path, x)?;
+            }
+        }
+        Yaml::Hash(h) => {
+            for (k, v) in h {
+                let key = match k.as_str() {
+                    Some(key) => key,
+                    None => continue
+                };
+                if !GenericModule::<T>::has_command(path, key) {
+                    return Err(GenericModule::<T>::unknown_command(path, key));
+                }
+                let path = format!("{}.{}", path, key);
+                if let Some(schema) = GenericModule::<T>::schema(&path) {
+                    schema.validate(&path, v)?;
+                }
+                if GenericModule::<T>::is_block(&path) {
+                    validate_node::<T>(&path, v)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(OK)
+}
+
+fn parse_node<T: ModuleType + 'static>(path: &str, context: &mut CommandContextType, doc: &mut Yaml)-> ActionResult {
+    match *doc {
+        Yaml::Array(ref mut v) => {
+            for x in v {
+                parse_node::<T>(path, context, x)?;
+            }
+        }
+        Yaml::Hash(ref mut h) => {
+            for (k, v) in h {
+                let key = k.as_str().unwrap();
+                if let Some(ref mut new_context) = GenericModule::<T>::handle_command(path, key, context.clone(), v)? {
+                    parse_node::<T>(&format!("{}.{}", path, key), new_context, v)?;
+                    // exit of the block
+                    CommandContext::leave(new_context)?;
+                } else {
+                    // values of plain commands are not commands
+                    let path = format!("{}.{}", path, key);
+                    if GenericModule::<T>::is_block(&path) {
+                        parse_node::<T>(&path, context, v)?;
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(OK)
+}
+
 impl Config {
 
     pub fn add_block<M: ModuleType + 'static, T: Value + 'static>(
@@ -289,6 +634,7 @@ impl Config {
     )
         -> ActionResult
     {
+        GenericModule::<M>::add_block(path, cmd);
         GenericModule::<M>::add_command(path, cmd, CommandHandler::new(
             move |context: CommandContextType, v: &mut ConfigBlock| -> CommandResult {
                 let block = handler(&mut * context.borrow_mut(), T::get(v)?)?;
@@ -322,34 +668,27 @@ impl Config {
         ))
     }
 
-    pub fn parse<T: ModuleType + 'static>(s: &str) -> ActionResult {
+    pub fn add_schema<M: ModuleType + 'static>(path: &str, cmd: &str, schema: Schema) -> ActionResult {
+        GenericModule::<M>::add_schema(path, cmd, schema)
+    }
 
-        fn parse_node<T: ModuleType + 'static>(path: &str, context: &mut CommandContextType, doc: &mut Yaml)-> ActionResult {
-            match *doc {
-                Yaml::Array(ref mut v) => {
-                    for x in v {
-                        parse_node::<T>(path, context, x)?;
-                    }
-                }
-                Yaml::Hash(ref mut h) => {
-                    for (k, v) in h {
-                        let key = k.as_str().unwrap();
-                        if let Some(ref mut new_context) = GenericModule::<T>::handle_command(path, key, context.clone(), v)? {
-                            parse_node::<T>(&format!("{}.{}", path, key), new_context, v)?;
-                        } else {
-                            parse_node::<T>(&format!("{}.{}", path, key), context, v)?;
-                        }
-                    }
-                }
-                _ => {}
-            }
-            Ok(OK)
-        }
+    pub fn add_expander<M: ModuleType + 'static>(path: &str, cmd: &str, expander: Expander) -> ActionResult {
+        GenericModule::<M>::add_expander(path, cmd, expander)
+    }
 
+    pub fn parse<T: ModuleType + 'static>(s: &str) -> ActionResult {
         match yaml::YamlLoader::load_from_str(&s) {
             Ok(mut docs) => {
+                let origins = docs.clone();
                 for doc in &mut docs {
+                    expand_node::<T>("root", doc)?;
+                    validate_node::<T>("root", doc)?;
+                }
+                // the documents of the module are replaced by the last parse, e.g. the import of the snapshot
+                SNAPSHOT.lock().unwrap().reset(GenericModule::<T>::name());
+                for (doc, origin) in docs.iter_mut().zip(origins) {
                     parse_node::<T>("root", &mut CommandContext::new_default::<MainContext>(), doc)?;
+                    SNAPSHOT.lock().unwrap().push(GenericModule::<T>::name(), origin);
                 }
                 return Ok(OK);    
             },
@@ -359,6 +698,82 @@ impl Config {
             }
         }    
     }
+
+    // Block of the running server, e.g. the routes added at runtime: the document is parsed
+    // at the path in the context, it is not a part of the snapshot
+    pub fn parse_block<T: ModuleType + 'static>(path: &str, mut context: CommandContextType, doc: &Yaml) -> ActionResult {
+        let mut doc = doc.clone();
+        expand_node::<T>(path, &mut doc)?;
+        validate_node::<T>(path, &doc)?;
+        parse_node::<T>(path, &mut context, &mut doc)
+    }
+
+    pub fn import<T: ModuleType + 'static>(s: &str) -> ActionResult {
+        let docs = match yaml::YamlLoader::load_from_str(s) {
+            Ok(docs) => docs,
+            Err(err) => {
+                eprintln!("{}", err);
+                return throw!("Failed to parse snapshot")
+            }
+        };
+
+        let mut own = yaml::Hash::new();
+
+        for doc in docs {
+            if let Yaml::Hash(h) = doc {
+                for (k, v) in h {
+                    if let Some(key) = k.as_str() {
+                        if GenericModule::<T>::has_command("root", key) {
+                            own.insert(k, v);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut out = String::new();
+
+        YamlEmitter::new(&mut out).dump(&Yaml::Hash(own)).or_else(|err| throw!("{:?}", err))?;
+
+        Config::parse::<T>(&out)
+    }
+
+    // File named by 'config_snapshot' of the http block of the config
+    pub fn snapshot_file(s: &str) -> Option<String> {
+        let docs = yaml::YamlLoader::load_from_str(s).ok()?;
+        docs.iter().find_map(|doc| doc["http"]["config_snapshot"].as_str().map(|filename| filename.to_string()))
+    }
+
+    pub fn add_snapshot_provider(provider: SnapshotProvider) {
+        SNAPSHOT.lock().unwrap().providers.push(provider);
+    }
+
+    pub fn snapshot() -> Result<String, CoreError> {
+        let snapshot = SNAPSHOT.lock().unwrap();
+
+        let mut merged = yaml::Hash::new();
+
+        for doc in snapshot.docs.iter().flat_map(|(_, docs)| docs.iter()) {
+            if let Yaml::Hash(h) = doc {
+                for (k, v) in h {
+                    merged.insert(k.clone(), v.clone());
+                }
+            }
+        }
+
+        let mut doc = Yaml::Hash(merged);
+
+        for provider in &snapshot.providers {
+            provider(&mut doc);
+        }
+
+        let mut out = String::new();
+
+        YamlEmitter::new(&mut out).dump(&doc).or_else(|err| throw!("{:?}", err))?;
+        out.push('\n');
+
+        Ok(out)
+    }
 }
 
 #[macro_export]
@@ -375,7 +790,7 @@ macro_rules! add_command {
         Self::add_command::<$ctx_t,$data_t>(&$base, $name, Box::new(|$ctx: &mut $ctx_t, $data: $data_t| $body))
     };
     ($base:path, $name:tt, |$ctx:ident: &mut $ctx_t:ty| $body:expr) => {
-        Self::add_command::<$ctx_t,crate::config::NoValue>(&$base, $name, Box::new(|$ctx: &mut $ctx_t, _| $body))
+        Self::add_command::<$ctx_t,$crate::config::NoValue>(&$base, $name, Box::new(|$ctx: &mut $ctx_t, _| $body))
     };
     ($base:path, $name:tt, move |_: &mut $ctx_t:ty, $data:ident: $data_t:ty| $body:expr) => {
         Self::add_command::<$ctx_t,$data_t>(&$base, $name, Box::new(move |_: &mut $ctx_t, $data: $data_t| $body))
@@ -384,7 +799,14 @@ macro_rules! add_command {
         Self::add_command::<$ctx_t,$data_t>(&$base, $name, Box::new(move |$ctx: &mut $ctx_t, $data: $data_t| $body))
     };
     ($base:path, $name:tt, move |$ctx:ident: &mut $ctx_t:ty| $body:expr) => {
-        Self::add_command::<$ctx_t,crate::config::NoValue>(&$base, $name, Box::new(move |$ctx: &mut $ctx_t, _| $body))
+        Self::add_command::<$ctx_t,$crate::config::NoValue>(&$base, $name, Box::new(move |$ctx: &mut $ctx_t, _| $body))
+    }
+}
+
+#[macro_export]
+macro_rules! add_schema {
+    ($base:path, $name:tt, $schema:expr) => {
+        Self::add_schema(&$base, $name, $schema)
     }
 }
 
@@ -394,19 +816,55 @@ macro_rules! add_block {
         Self::add_block::<$data_t>(&$base, $name, Box::new(|$ctx, $data: $data_t| $body))
     };
     ($base:path, $name:tt, |$ctx:ident| $body:expr) => {
-        Self::add_block::<crate::config::NoValue>(&$base, $name, Box::new(|$ctx, _| $body))
+        Self::add_block::<$crate::config::NoValue>(&$base, $name, Box::new(|$ctx, _| $body))
     };
     ($base:path, $name:tt, move |$ctx:ident, $data:ident: $data_t:ty| $body:expr) => {
         Self::add_block::<$data_t>(&$base, $name, Box::new(move |$ctx, $data: $data_t| $body))
     };
     ($base:path, $name:tt, move |$ctx:ident| $body:expr) => {
-        Self::add_block::<crate::config::NoValue>(&$base, $name, Box::new(move |$ctx, _| $body))
+        Self::add_block::<$crate::config::NoValue>(&$base, $name, Box::new(move |$ctx, _| $body))
+    }
+}
+
+#[macro_export]
+macro_rules! add_expander {
+    ($base:path, $name:tt, $expander:expr) => {
+        Self::add_expander(&$base, $name, Box::new($expander))
     }
 }
 
 #[macro_export]
 macro_rules! add_empty_block {
     ($base:path, $name:tt) => {
-        Self::add_block::<crate::config::NoValue>(&$base, $name, Box::new(|_,_| { Ok(None) }))
+        Self::add_block::<$crate::config::NoValue>(&$base, $name, Box::new(|_,_| { Ok(None) }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn schema_value() {
+        let schema = Schema::new()
+            .value(SchemaType::Integer)
+            .range("", 1, i64::MAX);
+        assert!(schema.validate("root.error_log_ring", &Yaml::Integer(1)).is_ok());
+        assert!(schema.validate("root.error_log_ring", &Yaml::Integer(0)).is_err());
+        assert!(schema.validate("root.error_log_ring", &Yaml::String("10".to_string())).is_err());
+    }
+
+    #[test]
+    fn snapshot_reset() {
+        let mut snapshot = Snapshot::default();
+        snapshot.reset("core");
+        snapshot.push("core", Yaml::Integer(1));
+        snapshot.reset("http");
+        snapshot.push("http", Yaml::Integer(2));
+        // reload of the module replaces its documents only
+        snapshot.reset("http");
+        snapshot.push("http", Yaml::Integer(3));
+        let docs: Vec<Yaml> = snapshot.docs.iter().flat_map(|(_, docs)| docs.clone()).collect();
+        assert_eq!(docs, vec![Yaml::Integer(1), Yaml::Integer(3)]);
     }
 }
@@ -6,22 +6,42 @@ use std::any::Any;
 use std::cmp::Ordering;
 use std::ops::{ Deref, DerefMut };
 use std::collections::{ BTreeSet, HashMap };
-use std::sync::{ mpsc, Once, Arc, Mutex, atomic::AtomicUsize, atomic };
+use std::sync::{ mpsc, Once, Arc, Mutex, atomic::{ AtomicBool, AtomicUsize }, atomic };
 use mio::net::TcpStream;
 use std::net::SocketAddr;
 use std::io::ErrorKind;
 use mio::{ Events, Interest, Poll, Token, Waker };
 use std::time::{ SystemTime, Duration };
+use std::mem::take;
 
 use crate::error::CoreError;
 use crate::tcp_socket::TcpSocket;
+use crate::core::fd;
 
 const KEEPALIVE_TIMEOUT_DEFAULT: u64 = 86400;
 
 pub type StreamType = TcpSocket;
 
+// TLS identity of the upstream: the server name sent and verified by the certificate,
+// sha256 digests of the accepted public keys and the CAs verifying the certificate
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct TlsPin {
+    pub server_name: Option<String>,
+    pub pin_sha256: Vec<Vec<u8>>,
+    pub trusted_certificate: Option<String>
+}
+
+// Identity a keep-alived connection was established for
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct PeerPin {
+    pub upstream: String,
+    pub route: Option<String>,
+    pub tls: Option<TlsPin>
+}
+
 pub struct Peer {
     upstream: Option<String>,
+    pin: PeerPin,
     pool: Option<ConnectionPool>,
     active: Option<Arc<i8>>,
     keepalive: Option<Arc<i8>>,
@@ -45,6 +65,10 @@ pub struct ConnectionPool {
     timeout: Option<Duration>,
     keepalive_timeout: Duration,
     keepalive_requests: u64,
+    // failed connection attempts
+    fails: Arc<AtomicUsize>,
+    // no new connections, released ones are closed
+    draining: Arc<AtomicBool>,
     peers: Arc<Mutex<BTreeSet<Peer>>>,
     monitor: Arc<Mutex<mpsc::Sender<Message>>>
 }
@@ -84,6 +108,8 @@ impl Clone for ConnectionPool {
             timeout: self.timeout,
             keepalive_timeout: self.keepalive_timeout,
             keepalive_requests: self.keepalive_requests,
+            fails: Arc::clone(&self.fails),
+            draining: Arc::clone(&self.draining),
             peers: Arc::clone(&self.peers),
             monitor: self.monitor.clone()
         }
@@ -255,6 +281,29 @@ impl ConnectionPool {
                             }
                         }
                     }
+
+                    // fd pressure, the oldest idle connections are closed first
+                    let excess = fd::excess();
+                    if excess > 0 {
+                        let mut count = 0;
+
+                        while count < excess {
+                            let mut peer = match keepalive.pop_first() {
+                                Some(peer) => peer,
+                                None => break
+                            };
+
+                            log_error!("info", "Keep-alived connection remote={} local={} has closed (fd pressure)",
+                                       peer.remote_addr(), peer.local_addr());
+
+                            let _ = poll.registry().deregister(&mut peer.stream);
+                            ConnectionPool::remove_keepalive(&mut peer);
+
+                            count += 1;
+                        }
+
+                        fd::scavenged_upstreams(count);
+                    }
                 }
             }).unwrap();
         });
@@ -275,6 +324,8 @@ impl ConnectionPool {
             timeout: timeout,
             keepalive_timeout: keepalive_timeout.unwrap_or(Duration::from_secs(KEEPALIVE_TIMEOUT_DEFAULT)),
             keepalive_requests: keepalive_requests.unwrap_or(std::u64::MAX),
+            fails: Arc::new(AtomicUsize::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
             peers: Arc::new(Mutex::new(BTreeSet::new())),
             monitor: Arc::new(Mutex::new(tx))
         }
@@ -296,7 +347,38 @@ impl ConnectionPool {
         Arc::strong_count(&self.keepalive) - Arc::strong_count(&self.active)
     }
 
-    pub fn connect(&self, addr: &SocketAddr, timeout: Option<Duration>) -> Result<Peer, CoreError> {
+    pub fn fails(&self) -> usize {
+        self.fails.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn draining(&self) -> bool {
+        self.draining.load(atomic::Ordering::Relaxed)
+    }
+
+    // Idle connections are closed at once, active ones are closed on release.
+    // Drain is completed when there are no connections left.
+    pub fn drain(&self, draining: bool) {
+        self.draining.store(draining, atomic::Ordering::Relaxed);
+        if !draining {
+            return;
+        }
+        let idle: Vec<Peer> = take(&mut * self.peers.lock().unwrap()).into_iter().collect();
+        for peer in idle {
+            log_error!("info", "Keep-alived connection remote={} local={} has closed (drain)",
+                       peer.remote_addr(), peer.local_addr());
+            self.send(Message::Remove(peer.weak()));
+        }
+    }
+
+    pub fn drained(&self) -> bool {
+        self.draining() && self.active() == 0 && self.idle() == 0
+    }
+
+    pub fn connect(&self, addr: &SocketAddr, timeout: Option<Duration>, pin: &PeerPin) -> Result<Peer, CoreError> {
+        if self.draining() {
+            return throw!("{} in '{}' is draining", addr, self.name);
+        }
+
         let mut guard = self.peers.lock().unwrap();
         let peers = &mut * guard;
 
@@ -305,11 +387,20 @@ impl ConnectionPool {
         }
 
         loop {
-            let peer = match peers.iter().next() {
+            let peer = match peers.iter().find(|peer| peer.pin == *pin) {
                 Some(peer) => peer.weak(),
                 None => {
-                    let stream = StreamType::connect(*addr, timeout.or(self.timeout)).or_else(|err| throw!(err))?;
+                    let stream = StreamType::connect(*addr, timeout.or(self.timeout)).and_then(|mut stream| {
+                        if let Some(tls) = &pin.tls {
+                            stream.handshake(tls)?;
+                        }
+                        Ok(stream)
+                    }).or_else(|err| {
+                        self.fails.fetch_add(1, atomic::Ordering::Relaxed);
+                        throw!(err)
+                    })?;
                     let mut peer = Peer::new(stream, Some(self.name.clone()));
+                    peer.pin = pin.clone();
                     peer.pool = Some(self.clone());
                     peer.active = Some(Arc::clone(&self.active));
                     peer.keepalive = Some(Arc::clone(&self.keepalive));
@@ -322,6 +413,21 @@ impl ConnectionPool {
                 continue;
             }
 
+            if peer.remote_addr() != *addr || peer.upstream.as_ref() != Some(&self.name) {
+                log_error!("warn", "Keep-alived connection remote={} local={} is not pinned to {} in '{}', closed",
+                           peer.remote_addr(), peer.local_addr(), addr, self.name);
+                self.send(Message::Remove(peer.weak()));
+                continue;
+            }
+
+            // the session itself is checked, not only the pin it was established for
+            if !peer.stream.identified(pin.tls.as_ref()) {
+                log_error!("warn", "Keep-alived connection remote={} local={} has another TLS identity in '{}', closed",
+                           peer.remote_addr(), peer.local_addr(), self.name);
+                self.send(Message::Remove(peer.weak()));
+                continue;
+            }
+
             drop(peers);
 
             self.send(Message::Remove(peer.weak()));
@@ -337,7 +443,7 @@ impl ConnectionPool {
     }
 
     fn set_keepalive(&self, mut peer: Peer, timeout: Option<Duration>) {
-        if !peer.stream.valid() {
+        if !peer.stream.valid() || self.draining() {
             return;
         }
 
@@ -404,6 +510,7 @@ impl Peer {
     pub fn new(stream: StreamType, upstream: Option<String>) -> Peer {
         Peer {
             upstream: upstream,
+            pin: PeerPin::default(),
             pool: None,
             active: None,
             keepalive: None,
@@ -421,6 +528,15 @@ impl Peer {
         }
     }
 
+    pub fn pin(&self) -> &PeerPin {
+        &self.pin
+    }
+
+    // Requests completed by the kept alive connection, zero for the new one
+    pub fn requests(&self) -> u64 {
+        self.requests
+    }
+
     pub fn local_addr(&self) -> SocketAddr {
         self.stream.local_addr()
     }
@@ -452,6 +568,7 @@ impl Peer {
     pub fn take(&mut self) -> Peer {
         Peer {
             upstream: self.upstream.take(),
+            pin: take(&mut self.pin),
             pool: self.pool.take(),
             active: self.active.take(),
             keepalive: self.keepalive.take(),
@@ -465,6 +582,7 @@ impl Peer {
     pub fn weak(&self) -> Peer {
         Peer {
             upstream: self.upstream.clone(),
+            pin: self.pin.clone(),
             pool: None,
             active: None,
             keepalive: None,
@@ -501,4 +619,48 @@ fn next_token() -> Token {
     unsafe {
         Token(UNIQUE_TOKEN.fetch_add(1, atomic::Ordering::SeqCst))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn pin(route: &str, tls: Option<TlsPin>) -> PeerPin {
+        PeerPin {
+            upstream: "test".to_string(),
+            route: Some(route.to_string()),
+            tls
+        }
+    }
+
+    #[test]
+    fn pinned() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = ConnectionPool::new("test", 10, 10);
+
+        let peer = pool.connect(&addr, None, &pin("a", None)).unwrap();
+        let local = peer.local_addr();
+        drop(peer);
+
+        // same identity
+        let peer = pool.connect(&addr, None, &pin("a", None)).unwrap();
+        assert_eq!(peer.local_addr(), local);
+        assert_eq!(peer.requests(), 1);
+        drop(peer);
+
+        // another route
+        let peer = pool.connect(&addr, None, &pin("b", None)).unwrap();
+        assert_ne!(peer.local_addr(), local);
+        assert_eq!(peer.requests(), 0);
+
+        // plain connection has no TLS identity
+        let tls = TlsPin {
+            server_name: Some("upstream.test".to_string()),
+            ..TlsPin::default()
+        };
+        assert!(peer.stream.identified(None));
+        assert!(!peer.stream.identified(Some(&tls)));
+        assert!(pool.connect(&addr, Some(Duration::from_millis(100)), &pin("a", Some(tls))).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{ SocketAddr, TcpListener };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+
+use crate::core::listen;
+use crate::core::status::Json;
+use crate::error::CoreError;
+
+// Distribution of the new connections between the event pools of the workgroup,
+// 'accept_balancing' of the workgroup
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Default)]
+pub enum Balancing {
+    // every event pool listens its own socket, the kernel picks one by the connection hash
+    #[default]
+    Reuseport,
+    // event pools share one socket, the loaded ones leave accepting to the others
+    Leader
+}
+
+
+impl Balancing {
+    pub fn parse(name: &str) -> Result<Balancing, CoreError> {
+        match name {
+            "reuseport" => Ok(Balancing::Reuseport),
+            "leader" => Ok(Balancing::Leader),
+            _ => throw!("accept_balancing: unknown strategy '{}', reuseport or leader expected", name)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Balancing::Reuseport => "reuseport",
+            Balancing::Leader => "leader"
+        }
+    }
+}
+
+// Connections of the event pool
+#[derive(Default)]
+struct Counters {
+    accepted: AtomicU64,
+    active: AtomicUsize,
+    yielded: AtomicU64
+}
+
+pub struct AcceptGroup {
+    balancing: Balancing,
+    // connections accepted by the event pool at once, zero is unlimited
+    multi_accept: usize,
+    pools: Vec<Counters>,
+    // leader: socket of the address shared by the event pools and the number of them using it
+    shared: Mutex<HashMap<SocketAddr, (TcpListener, usize)>>
+}
+
+impl AcceptGroup {
+    pub fn new(balancing: Balancing, event_pool_size: usize, multi_accept: usize) -> Arc<AcceptGroup> {
+        Arc::new(AcceptGroup {
+            balancing,
+            multi_accept,
+            pools: (0..std::cmp::max(event_pool_size, 1)).map(|_| Counters::default()).collect(),
+            shared: Mutex::new(HashMap::new())
+        })
+    }
+
+    pub fn balancing(&self) -> Balancing {
+        self.balancing
+    }
+
+    fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+        match listen::prepared(addr) {
+            Some(listener) => Ok(listener),
+            None => listen::bind_reuseport(addr)
+        }
+    }
+
+    // Listener of the event pool
+    fn listener(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        if self.balancing == Balancing::Reuseport {
+            return AcceptGroup::bind(addr);
+        }
+        let mut shared = self.shared.lock().unwrap();
+        match shared.get_mut(&addr) {
+            Some((listener, refs)) => {
+                // socket prepared for this event pool is not used
+                drop(listen::prepared(addr));
+                let listener = listener.try_clone()?;
+                *refs += 1;
+                Ok(listener)
+            },
+            None => {
+                let listener = AcceptGroup::bind(addr)?;
+                shared.insert(addr, (listener.try_clone()?, 1));
+                Ok(listener)
+            }
+        }
+    }
+
+    fn release(&self, addr: SocketAddr) {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some((_, refs)) = shared.get_mut(&addr) {
+            *refs -= 1;
+            if *refs == 0 {
+                shared.remove(&addr);
+            }
+        }
+    }
+
+    pub fn report(&self) -> Json {
+        Json::object(vec![
+            ("accept_balancing", self.balancing.name().into()),
+            ("multi_accept", self.multi_accept.into()),
+            ("event_pools", Json::Array(self.pools.iter().map(|counters| {
+                Json::object(vec![
+                    ("accepted", counters.accepted.load(Ordering::Relaxed).into()),
+                    ("active", counters.active.load(Ordering::Relaxed).into()),
+                    ("yielded", counters.yielded.load(Ordering::Relaxed).into())
+                ])
+            }).collect()))
+        ])
+    }
+}
+
+// Event pool in its accept group
+#[derive(Clone)]
+pub struct Accept {
+    group: Arc<AcceptGroup>,
+    slot: usize
+}
+
+impl Default for Accept {
+    fn default() -> Accept {
+        Accept::new(&AcceptGroup::new(Balancing::Reuseport, 1, 0), 0)
+    }
+}
+
+impl Accept {
+    pub fn new(group: &Arc<AcceptGroup>, slot: usize) -> Accept {
+        Accept {
+            group: Arc::clone(group),
+            slot: slot % group.pools.len()
+        }
+    }
+
+    fn counters(&self) -> &Counters {
+        &self.group.pools[self.slot]
+    }
+
+    pub (crate) fn listener(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        self.group.listener(addr)
+    }
+
+    pub (crate) fn release(&self, addr: SocketAddr) {
+        self.group.release(addr)
+    }
+
+    pub (crate) fn multi_accept(&self) -> usize {
+        match self.group.multi_accept {
+            0 => usize::MAX,
+            multi_accept => multi_accept
+        }
+    }
+
+    pub (crate) fn accepted(&self) {
+        self.counters().accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub (crate) fn set_active(&self, active: usize) {
+        self.counters().active.store(active, Ordering::Relaxed);
+    }
+
+    // Event pool with more connections than the average of the group leaves the shared socket to the others,
+    // the least loaded one always accepts
+    pub (crate) fn should_yield(&self) -> bool {
+        let pools = &self.group.pools;
+        if self.group.balancing != Balancing::Leader || pools.len() == 1 {
+            return false;
+        }
+        let total: usize = pools.iter().map(|counters| counters.active.load(Ordering::Relaxed)).sum();
+        match self.counters().active.load(Ordering::Relaxed) * pools.len() > total + pools.len() {
+            true => {
+                self.counters().yielded.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            false => false
+        }
+    }
+}
@@ -0,0 +1,66 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::os::unix::thread::JoinHandleExt;
+use std::thread::JoinHandle;
+
+use crate::core::master;
+use crate::error::CoreError;
+
+// Cores of the event pool threads, empty ones leave the threads unpinned
+#[derive(Clone, Default)]
+pub struct Affinity {
+    pub io: Vec<usize>,
+    pub workers: Vec<usize>
+}
+
+// '0-3,6' is 0, 1, 2, 3 and 6
+pub fn parse(spec: &str) -> Result<Vec<usize>, CoreError> {
+    let mut cpus = Vec::new();
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let mut range = item.splitn(2, '-');
+        let first = range.next().unwrap().trim().parse::<usize>();
+        let last = range.next().map(|last| last.trim().parse::<usize>());
+        match (first, last) {
+            (Ok(first), None) => cpus.push(first),
+            (Ok(first), Some(Ok(last))) if first <= last => cpus.extend(first..=last),
+            _ => return throw!("invalid cpu list '{}'", spec)
+        }
+    }
+    if let Some(cpu) = cpus.iter().find(|cpu| **cpu >= libc::CPU_SETSIZE as usize) {
+        return throw!("cpu {} is out of range", cpu);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+// Event pool 'n' of the workgroup: its IO thread is pinned to one of the cores in turn,
+// the worker threads may run on any of them.
+// Worker processes start from different cores to spread the IO threads of the same pool.
+pub fn event_pool(cpus: &[usize], n: usize, event_pool_size: usize) -> Affinity {
+    if cpus.is_empty() {
+        return Affinity::default();
+    }
+    let offset = master::worker_slot().unwrap_or(0) * event_pool_size;
+    Affinity {
+        io: vec![cpus[(offset + n) % cpus.len()]],
+        workers: cpus.to_vec()
+    }
+}
+
+// Pins the started thread
+pub fn pin<T>(thread: &JoinHandle<T>, cpus: &[usize]) {
+    if cpus.is_empty() {
+        return;
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        cpus.iter().for_each(|cpu| libc::CPU_SET(*cpu, &mut set));
+        let err = libc::pthread_setaffinity_np(thread.as_pthread_t(), std::mem::size_of::<libc::cpu_set_t>(), &set);
+        if err != 0 {
+            log_error!("warn", "Failed to set cpu affinity {:?}: {}", cpus, std::io::Error::from_raw_os_error(err));
+        }
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{ Arc, RwLock };
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+
+use crate::core::status::{ self, Json };
+use crate::error::CoreError;
+
+#[derive(Clone, Copy)]
+pub enum Category {
+    // request headers and bodies
+    Request = 0,
+    // response bodies held in memory
+    Body = 1,
+    // buffered log lines
+    Log = 2
+}
+
+const CATEGORIES: [&str; 3] = ["request", "body", "log"];
+
+// Memory budget of the workgroup, zero limit means accounting only
+pub struct Budget {
+    limit: usize,
+    total: AtomicUsize,
+    peak: AtomicUsize,
+    used: [AtomicUsize; 3],
+    exceeded: [AtomicU64; 3]
+}
+
+// Memory is accounted until the reservation is dropped
+pub struct Reservation {
+    budget: Arc<Budget>,
+    category: Category,
+    size: usize
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.total.fetch_sub(self.size, Ordering::Relaxed);
+        self.budget.used[self.category as usize].fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+impl Reservation {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Accounts more memory, e.g. for the body of unknown size read in parts
+    pub fn grow(&mut self, size: usize) -> Result<(), CoreError> {
+        match Budget::reserve(&self.budget, self.category, size) {
+            Some(mut more) => {
+                self.size += more.size;
+                more.size = 0;
+                Ok(())
+            },
+            None => throw!("memory budget exceeded")
+        }
+    }
+}
+
+impl Budget {
+    fn new(limit: usize) -> Budget {
+        Budget {
+            limit,
+            total: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            used: Default::default(),
+            exceeded: Default::default()
+        }
+    }
+
+    // Returns None if the budget would be exceeded
+    pub fn reserve(budget: &Arc<Budget>, category: Category, size: usize) -> Option<Reservation> {
+        let mut total = budget.total.load(Ordering::Relaxed);
+        // size comes from the client, the overflow is the exceeded budget
+        let next = loop {
+            let next = match total.checked_add(size) {
+                Some(next) if budget.limit == 0 || next <= budget.limit => next,
+                _ => {
+                    budget.exceeded[category as usize].fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+            };
+            match budget.total.compare_exchange_weak(total, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break next,
+                Err(current) => total = current
+            }
+        };
+        budget.used[category as usize].fetch_add(size, Ordering::Relaxed);
+        budget.peak.fetch_max(next, Ordering::Relaxed);
+        Some(Reservation {
+            budget: Arc::clone(budget),
+            category,
+            size
+        })
+    }
+
+    pub fn report(&self) -> Json {
+        let mut fields = vec![
+            ("limit".to_string(), self.limit.into()),
+            ("used".to_string(), self.total.load(Ordering::Relaxed).into()),
+            ("peak".to_string(), self.peak.load(Ordering::Relaxed).into())
+        ];
+        for (i, name) in CATEGORIES.iter().enumerate() {
+            fields.push((name.to_string(), Json::object(vec![
+                ("used", self.used[i].load(Ordering::Relaxed).into()),
+                ("exceeded", self.exceeded[i].load(Ordering::Relaxed).into())
+            ])));
+        }
+        Json::Object(fields)
+    }
+}
+
+lazy_static! {
+    static ref BUDGETS: RwLock<HashMap<String, Arc<Budget>>> = RwLock::new(HashMap::new());
+    static ref SERVERS: RwLock<HashMap<SocketAddr, Arc<Budget>>> = RwLock::new(HashMap::new());
+}
+
+pub fn create(workgroup: &str, limit: usize) {
+    let budget = Arc::new(Budget::new(limit));
+    BUDGETS.write().unwrap().insert(workgroup.to_string(), Arc::clone(&budget));
+    status::register("budgets", workgroup, Box::new(move || Some(budget.report())));
+}
+
+// Requests of the server are accounted in the budget of its workgroup
+pub fn attach(addr: SocketAddr, workgroup: &str) {
+    if let Some(budget) = BUDGETS.read().unwrap().get(workgroup) {
+        SERVERS.write().unwrap().insert(addr, Arc::clone(budget));
+    }
+}
+
+pub fn get(addr: &SocketAddr) -> Option<Arc<Budget>> {
+    SERVERS.read().unwrap().get(addr).cloned()
+}
+
+// Servers without budget are not accounted
+pub fn reserve(addr: &SocketAddr, category: Category, size: usize) -> Result<Option<Reservation>, CoreError> {
+    match get(addr) {
+        Some(budget) => match Budget::reserve(&budget, category, size) {
+            Some(reservation) => Ok(Some(reservation)),
+            None => throw!("memory budget exceeded")
+        },
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overflow() {
+        let budget = Arc::new(Budget::new(1000));
+        assert!(Budget::reserve(&budget, Category::Request, usize::MAX).is_none());
+        let mut reservation = Budget::reserve(&budget, Category::Request, 10).unwrap();
+        assert!(Budget::reserve(&budget, Category::Body, usize::MAX).is_none());
+        assert!(reservation.grow(usize::MAX - 5).is_err());
+        assert_eq!(budget.total.load(Ordering::Relaxed), 10);
+        assert_eq!(budget.peak.load(Ordering::Relaxed), 10);
+        assert_eq!(budget.exceeded[Category::Request as usize].load(Ordering::Relaxed), 2);
+        drop(reservation);
+        assert_eq!(budget.total.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn unlimited_overflow() {
+        let budget = Arc::new(Budget::new(0));
+        let _reservation = Budget::reserve(&budget, Category::Request, 10).unwrap();
+        assert!(Budget::reserve(&budget, Category::Request, usize::MAX).is_none());
+        assert_eq!(budget.total.load(Ordering::Relaxed), 10);
+    }
+}
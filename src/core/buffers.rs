@@ -0,0 +1,129 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{ Arc, Mutex, RwLock };
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+
+use crate::core::status::{ self, Json };
+
+// Size classes of the pooled buffers, larger ones are released
+const CLASSES: [usize; 5] = [4096, 8192, 16384, 32768, 65536];
+
+// Connection buffers and request bodies of the workgroup,
+// rented on first use and returned when the connection is reset or closed
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<Vec<u8>>>>,
+    capacity: usize,
+    cached: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    returned: AtomicU64,
+    dropped: AtomicU64
+}
+
+impl BufferPool {
+    fn new(capacity: usize) -> BufferPool {
+        BufferPool {
+            free: Mutex::new(CLASSES.iter().map(|_| Vec::new()).collect()),
+            capacity,
+            cached: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            returned: AtomicU64::new(0),
+            dropped: AtomicU64::new(0)
+        }
+    }
+
+    // Smallest cached buffer of at least 'size' bytes
+    pub fn rent(&self, size: usize) -> Vec<u8> {
+        let class = match CLASSES.iter().position(|class| *class >= size) {
+            Some(class) => class,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return Vec::with_capacity(size);
+            }
+        };
+        if self.cached.load(Ordering::Relaxed) != 0 {
+            let mut free = self.free.lock().unwrap();
+            if let Some(buf) = free[class..].iter_mut().find_map(|list| list.pop()) {
+                self.cached.fetch_sub(1, Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return buf;
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        Vec::with_capacity(CLASSES[class])
+    }
+
+    pub fn put(&self, mut buf: Vec<u8>) {
+        let class = match CLASSES.iter().rposition(|class| *class <= buf.capacity()) {
+            // grown over twice the largest class, e.g. by a large body
+            Some(class) if buf.capacity() <= CLASSES[CLASSES.len() - 1] * 2 => class,
+            Some(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            },
+            None => return
+        };
+        if self.cached.load(Ordering::Relaxed) >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        buf.clear();
+        self.free.lock().unwrap()[class].push(buf);
+        self.cached.fetch_add(1, Ordering::Relaxed);
+        self.returned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> Json {
+        let (hits, misses) = (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed));
+        let classes = self.free.lock().unwrap().iter().zip(CLASSES.iter()).map(|(list, class)| {
+            (class.to_string(), list.len().into())
+        }).collect();
+        Json::object(vec![
+            ("capacity", self.capacity.into()),
+            ("cached", self.cached.load(Ordering::Relaxed).into()),
+            ("hits", hits.into()),
+            ("misses", misses.into()),
+            // percent of the rented buffers taken from the pool
+            ("hit_rate", match hits + misses {
+                0 => 0u64,
+                total => hits * 100 / total
+            }.into()),
+            ("returned", self.returned.load(Ordering::Relaxed).into()),
+            ("dropped", self.dropped.load(Ordering::Relaxed).into()),
+            ("classes", Json::Object(classes))
+        ])
+    }
+}
+
+lazy_static! {
+    static ref POOLS: RwLock<HashMap<String, Arc<BufferPool>>> = RwLock::new(HashMap::new());
+    static ref SERVERS: RwLock<HashMap<SocketAddr, Arc<BufferPool>>> = RwLock::new(HashMap::new());
+}
+
+// Zero capacity disables pooling in the workgroup
+pub fn create(workgroup: &str, capacity: usize) {
+    if capacity == 0 {
+        POOLS.write().unwrap().remove(workgroup);
+        return;
+    }
+    let pool = Arc::new(BufferPool::new(capacity));
+    POOLS.write().unwrap().insert(workgroup.to_string(), Arc::clone(&pool));
+    status::register("buffers", workgroup, Box::new(move || Some(pool.report())));
+}
+
+// Connections of the server rent from the pool of its workgroup
+pub fn attach(addr: SocketAddr, workgroup: &str) {
+    match POOLS.read().unwrap().get(workgroup) {
+        Some(pool) => SERVERS.write().unwrap().insert(addr, Arc::clone(pool)),
+        None => SERVERS.write().unwrap().remove(&addr)
+    };
+}
+
+pub fn get(addr: &SocketAddr) -> Option<Arc<BufferPool>> {
+    SERVERS.read().unwrap().get(addr).cloned()
+}
@@ -0,0 +1,77 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::fs;
+
+use crate::core::status::{ self, Json };
+
+// Limits of the container the process is running in
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    // fractional number of cpus allowed by the cfs quota
+    pub cpus: Option<f64>,
+    pub memory: Option<usize>
+}
+
+fn read(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+// cgroup v2: "max 100000" or "150000 100000"
+fn cpu_v2() -> Option<f64> {
+    let cpu_max = read("/sys/fs/cgroup/cpu.max")?;
+    let mut parts = cpu_max.split_whitespace();
+    let quota = parts.next()?.parse::<f64>().ok()?;
+    let period = parts.next()?.parse::<f64>().ok()?;
+    match period > 0.0 {
+        true => Some(quota / period),
+        false => None
+    }
+}
+
+// cgroup v1: quota is -1 when unlimited
+fn cpu_v1() -> Option<f64> {
+    let quota = read("/sys/fs/cgroup/cpu/cpu.cfs_quota_us")?.parse::<i64>().ok()?;
+    let period = read("/sys/fs/cgroup/cpu/cpu.cfs_period_us")?.parse::<i64>().ok()?;
+    match quota > 0 && period > 0 {
+        true => Some(quota as f64 / period as f64),
+        false => None
+    }
+}
+
+// v1 reports a huge number instead of "max" when unlimited
+fn memory() -> Option<usize> {
+    let limit = read("/sys/fs/cgroup/memory.max")
+        .or_else(|| read("/sys/fs/cgroup/memory/memory.limit_in_bytes"))?
+        .parse::<u64>().ok()?;
+    match limit < (1u64 << 60) {
+        true => Some(limit as usize),
+        false => None
+    }
+}
+
+lazy_static! {
+    static ref LIMITS: Limits = {
+        let limits = Limits {
+            cpus: cpu_v2().or_else(cpu_v1),
+            memory: memory()
+        };
+        status::register("system", "cgroup", Box::new(move || Some(Json::object(vec![
+            ("cpus", limits.cpus.map(|cpus| cpus.ceil() as usize).into()),
+            ("memory", limits.memory.into())
+        ]))));
+        limits
+    };
+}
+
+pub fn limits() -> Limits {
+    *LIMITS
+}
+
+impl Limits {
+    // Whole cpus, not less than one
+    pub fn cpus(&self) -> Option<usize> {
+        self.cpus.map(|cpus| std::cmp::max(cpus.ceil() as usize, 1))
+    }
+}
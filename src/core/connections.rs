@@ -0,0 +1,119 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{ Arc, RwLock };
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+
+use crate::core::status::{ self, Json };
+
+// Client connections of the listener over all event pools
+#[derive(Default)]
+pub struct ListenerStats {
+    open: AtomicUsize,
+    // keep-alive connections waiting for the next request
+    idle: AtomicUsize,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    max_connections: AtomicUsize
+}
+
+impl ListenerStats {
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.load(Ordering::Relaxed)
+    }
+
+    // Connection over max_connections is rejected
+    pub (crate) fn exhausted(&self) -> bool {
+        match self.max_connections.load(Ordering::Relaxed) {
+            0 => false,
+            max_connections => self.open() >= max_connections
+        }
+    }
+
+    pub (crate) fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub (crate) fn set_max_connections(&self, max_connections: usize) {
+        self.max_connections.store(max_connections, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> Json {
+        Json::object(vec![
+            ("open", self.open().into()),
+            ("idle", self.idle().into()),
+            ("accepted", self.accepted.load(Ordering::Relaxed).into()),
+            ("rejected", self.rejected.load(Ordering::Relaxed).into()),
+            ("max_connections", self.max_connections.load(Ordering::Relaxed).into())
+        ])
+    }
+}
+
+// Serial numbers of the connections over all listeners
+static SERIAL: AtomicU64 = AtomicU64::new(0);
+
+// Open connection counted until it is closed
+pub (crate) struct Connection {
+    stats: Arc<ListenerStats>,
+    serial: u64,
+    idle: bool
+}
+
+impl Connection {
+    pub (crate) fn open(stats: &Arc<ListenerStats>) -> Connection {
+        stats.open.fetch_add(1, Ordering::Relaxed);
+        stats.accepted.fetch_add(1, Ordering::Relaxed);
+        Connection {
+            stats: Arc::clone(stats),
+            serial: SERIAL.fetch_add(1, Ordering::Relaxed) + 1,
+            idle: false
+        }
+    }
+
+    pub (crate) fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    pub (crate) fn set_idle(&mut self, idle: bool) {
+        if self.idle == idle {
+            return;
+        }
+        self.idle = idle;
+        match idle {
+            true => self.stats.idle.fetch_add(1, Ordering::Relaxed),
+            false => self.stats.idle.fetch_sub(1, Ordering::Relaxed)
+        };
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.set_idle(false);
+        self.stats.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+lazy_static! {
+    static ref LISTENERS: RwLock<HashMap<SocketAddr, Arc<ListenerStats>>> = RwLock::new(HashMap::new());
+}
+
+pub fn get(addr: &SocketAddr) -> Arc<ListenerStats> {
+    if let Some(stats) = LISTENERS.read().unwrap().get(addr) {
+        return Arc::clone(stats);
+    }
+    let mut listeners = LISTENERS.write().unwrap();
+    let stats = listeners.entry(*addr).or_insert_with(|| {
+        let stats = Arc::new(ListenerStats::default());
+        let stats_ = Arc::clone(&stats);
+        status::register("connections", &addr.to_string(), Box::new(move || Some(stats_.report())));
+        stats
+    });
+    Arc::clone(stats)
+}
@@ -0,0 +1,84 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicUsize, AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+
+// Percent of RLIMIT_NOFILE, idle keepalive connections are scavenged above it, zero disables
+static HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+
+static SCAVENGED_CLIENTS: AtomicU64 = AtomicU64::new(0);
+static SCAVENGED_UPSTREAMS: AtomicU64 = AtomicU64::new(0);
+
+// Counting of open descriptors is not cheap, usage is sampled not often than once per interval
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref SAMPLE: Mutex<Option<(Instant, usize)>> = Mutex::new(None);
+}
+
+pub fn set_high_water(percent: usize) {
+    HIGH_WATER.store(percent, Ordering::Relaxed)
+}
+
+pub fn high_water() -> usize {
+    HIGH_WATER.load(Ordering::Relaxed)
+}
+
+pub fn limit() -> usize {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0
+    };
+    match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } {
+        0 => rlim.rlim_cur as usize,
+        _ => 0
+    }
+}
+
+pub fn open() -> usize {
+    std::fs::read_dir("/proc/self/fd").map(|dir| dir.count()).unwrap_or(0)
+}
+
+// Number of descriptors above the high-water mark
+pub fn excess() -> usize {
+    let high_water = high_water();
+    if high_water == 0 {
+        return 0;
+    }
+    let mut sample = SAMPLE.lock().unwrap();
+    match *sample {
+        Some((at, excess)) if at.elapsed() < SAMPLE_INTERVAL => excess,
+        _ => {
+            let mark = limit() * high_water / 100;
+            let excess = open().saturating_sub(mark);
+            *sample = Some((Instant::now(), excess));
+            excess
+        }
+    }
+}
+
+// Scavenged connections are accounted in the current sample to not close more than needed
+fn scavenged(n: usize) {
+    if let Some((_, excess)) = SAMPLE.lock().unwrap().as_mut() {
+        *excess = excess.saturating_sub(n);
+    }
+}
+
+pub fn scavenged_clients(n: usize) {
+    SCAVENGED_CLIENTS.fetch_add(n as u64, Ordering::Relaxed);
+    scavenged(n);
+}
+
+pub fn scavenged_upstreams(n: usize) {
+    SCAVENGED_UPSTREAMS.fetch_add(n as u64, Ordering::Relaxed);
+    scavenged(n);
+}
+
+pub fn report() -> String {
+    format!("open: {}\nlimit: {}\nhigh_water: {}%\nscavenged_clients: {}\nscavenged_upstreams: {}\n",
+            open(), limit(), high_water(),
+            SCAVENGED_CLIENTS.load(Ordering::Relaxed), SCAVENGED_UPSTREAMS.load(Ordering::Relaxed))
+}
@@ -2,27 +2,32 @@
  * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
  */
 
-use net2::unix::UnixTcpBuilderExt;
 use std::collections::{ LinkedList, HashMap, BTreeSet };
-use std::io::{ Error, ErrorKind };
+use std::io::{ Error, ErrorKind, Read, Write };
 use std::sync::{ Arc, Mutex };
-use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
 use std::{ thread, thread::JoinHandle };
-use std::time::{ Duration, SystemTime };
+use std::time::{ Duration, SystemTime, Instant };
 use std::net::SocketAddr;
-use mio::net::TcpListener;
-use mio::{ Events, Interest, Poll, Token, Registry, Waker };
+use std::os::unix::io::AsRawFd;
+use mio::net::{ TcpListener, TcpStream };
+use mio::{ Interest, Token };
 use uuid::Uuid;
 
 use crate::client_context::*;
 use crate::module::*;
-use crate::core::{ *, worker::ThreadPool };
+use crate::core::{ *, worker::ThreadPool, listen, fd, upgrade, accept::Accept };
+use crate::core::connections::{ self, Connection };
+use crate::core::affinity::{ self, Affinity };
+use crate::core::poller::{ Engine, Events, Poll, Registry, Waker };
 use crate::error::{ *, Code::* };
 use crate::connection_pool::{ Peer, StreamType };
 
 const SIGNAL: Token = Token(0);
 const SERVER: Token = Token(1);
 const CLIENT: Token = Token(100000);
+// Incomplete method token of the shared address is peeked again after the delay
+const SNIFF_DELAY: Duration = Duration::from_millis(10);
 
 enum OneOf {
     Invalid(SocketAddr),
@@ -46,7 +51,11 @@ pub (crate) struct IO {
     server_token: Token,
     servers: Arc<Mutex<HashMap<Token, Server>>>,
     stop: Arc<AtomicBool>,
-    updated: Arc<AtomicBool>
+    updated: Arc<AtomicBool>,
+    // wakes up the poll to apply the updated listeners without delay
+    waker: Arc<Waker>,
+    queue: Arc<AtomicUsize>,
+    engine: Engine
 }
 
 impl IO {
@@ -54,23 +63,32 @@ impl IO {
     pub fn new<T: ModuleType + 'static, F: 'static>(
         worker_pool_size: usize,
         socket_poll_size: usize,
+        affinity: Affinity,
+        engine: Engine,
+        accept: Accept,
         handler: F
     )
         -> Result<IO, CoreError>
     where
         F: Fn(T::Request) -> T::Response + Clone + Sync + Send
     {
-        let mut poll = Poll::new().unwrap();
+        let mut poll = Poll::new(engine, socket_poll_size).unwrap();
         let mut events = Events::with_capacity(socket_poll_size);
+        let engine = poll.engine();
 
         let (ready, ready_) = pair(|| Mutex::new(LinkedList::new()));
         let (servers, servers_) = pair(|| Mutex::new(HashMap::new()));
 
         let signaller = Arc::new(Waker::new(poll.registry(), SIGNAL).expect("Failed to register signaller"));
         let signaller_ = Arc::clone(&signaller);
+        let waker = Arc::clone(&signaller);
 
         let mut clients: HashMap<Token, Item<T>> = HashMap::new();
         let mut keepalive: BTreeSet<(SystemTime, Token)> = BTreeSet::new();
+        let mut delayed: BTreeSet<(SystemTime, Token)> = BTreeSet::new();
+        let mut half_read: HashMap<SocketAddr, usize> = HashMap::new();
+        // listeners left with pending connections by multi_accept, no new event comes for them
+        let mut backlog: Vec<Token> = Vec::new();
 
         let mut unique_token = CLIENT;
         let server_token = next(&mut SERVER);
@@ -81,16 +99,26 @@ impl IO {
         let updated = Arc::new(AtomicBool::new(true));
         let updated_ = updated.clone();
 
-        let mut workers = ThreadPool::<T, _>::new(worker_pool_size, move |r| {
-            ready_.lock().unwrap().push_back(handler(r));
-            signaller_.wake().expect("Failed to wake up poll");
+        let completion = Completion::new(Arc::clone(&ready_), Arc::clone(&signaller_));
+
+        let mut workers = ThreadPool::<T, _>::new(worker_pool_size, &affinity.workers, move |r| {
+            let mut resp = handler(r);
+            match resp.take_deferred() {
+                Some(deferred) => deferred(resp, completion.clone()),
+                None => {
+                    ready_.lock().unwrap().push_back(resp);
+                    signaller_.wake().expect("Failed to wake up poll");
+                }
+            }
         });
 
+        let queue = workers.queue();
+
         let thr = thread::Builder::new().name("ws: io".to_string()).spawn(move || {
             while !stop.load(Ordering::Relaxed) {
                 if updated.load(Ordering::Acquire) {
                     if let Ok(ref mut servers) = servers.lock() {
-                        IO::update_servers(&mut poll, servers);
+                        IO::update_servers(&mut poll, servers, &accept);
                         updated.store(false, Ordering::Release);
                     }
                 }
@@ -115,31 +143,91 @@ impl IO {
                             Item::Idle(mut client) => {
                                 log_error!("info", "Client keep-alived connection client={} local={} timedout",
                                            &client.remote_addr(), &client.local_addr());
-                                deregister(poll.registry(), &mut client);
+                                disarm(poll.registry(), &mut client);
                             },
                             Item::Request(mut r) => {
-                                log_error!("warn", "Client connection client={} local={} request timedout",
-                                           r.context().remote_addr(), r.context().local_addr());
-                                deregister(poll.registry(), r.context());
+                                let headers_received = r.context().inner.as_ref().unwrap().headers_received;
+                                log_error!("warn", "Client connection client={} local={} request timedout{}",
+                                           r.context().remote_addr(), r.context().local_addr(),
+                                           if headers_received { "" } else { " (client_header_timeout)" });
+                                IO::release_half_read(&mut half_read, r.const_context());
+                                disarm(poll.registry(), r.context());
                                 r.on_timedout();
                             },
                             Item::Response((mut resp, None)) => {
                                 log_error!("warn", "Client connection client={} local={} response timedout",
                                            resp.context().remote_addr(), resp.context().local_addr());
-                                deregister(poll.registry(), &mut resp.context());
+                                disarm(poll.registry(), resp.context());
                                 resp.on_timedout();
                             },
                             Item::Response((mut resp, Some(mut peer))) => {
                                 log_error!("warn", "Client connection client={} local={} peer={} response timedout",
                                            resp.context().remote_addr(), resp.context().local_addr(), peer.remote_addr());
                                 deregister(poll.registry(), &mut peer.stream);
-                                deregister(poll.registry(), &mut resp.context());
+                                disarm(poll.registry(), resp.context());
                                 resp.on_timedout();
                             }
                         }
                     }
                 }
 
+                // fd pressure, the oldest idle keepalive connections are closed first
+
+                let excess = fd::excess();
+                if excess != 0 {
+                    let idle: Vec<(SystemTime, Token)> = keepalive.iter().filter(|(_, token)| {
+                        match clients.get(token) {
+                            Some(Item::Idle(client)) => client.inner.as_ref().map(|state| state.requests != 0).unwrap_or(false),
+                            _ => false
+                        }
+                    }).take(excess).cloned().collect();
+
+                    for key in idle.iter() {
+                        keepalive.remove(key);
+                        if let Some(Item::Idle(mut client)) = clients.remove(&key.1) {
+                            log_error!("info", "Client keep-alived connection client={} local={} has closed (fd pressure)",
+                                       &client.remote_addr(), &client.local_addr());
+                            disarm(poll.registry(), &mut client);
+                        }
+                    }
+
+                    fd::scavenged_clients(idle.len());
+                }
+
+                // throttled
+
+                loop {
+                    let key = match delayed.iter().next() {
+                        Some((exp, _)) if *exp > now => {
+                            timeout = std::cmp::min(timeout, exp.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(0)));
+                            break;
+                        },
+                        Some(key) => *key,
+                        None => break
+                    };
+
+                    let token = delayed.take(&key).unwrap().1;
+
+                    // rearm the socket, the event loop resumes read or write
+                    match clients.get_mut(&token) {
+                        Some(Item::Request(r)) => {
+                            rearm(poll.registry(), r.context(), token, Interest::READABLE);
+                        },
+                        Some(Item::Response((resp, None))) => {
+                            rearm(poll.registry(), resp.context(), token, Interest::WRITABLE);
+                        },
+                        Some(Item::Idle(client)) => {
+                            // sniffed connection of the shared address
+                            rearm(poll.registry(), client, token, Interest::READABLE);
+                        },
+                        _ => {}
+                    }
+                }
+
+                if !backlog.is_empty() {
+                    timeout = Duration::from_secs(0);
+                }
+
                 if let Err(err) = poll.poll(&mut events, Some(timeout)) {
                     match err.kind() {
                         ErrorKind::TimedOut | ErrorKind::Interrupted => { /* skip */ },
@@ -148,8 +236,12 @@ impl IO {
                     continue;
                 }
 
-                for event in events.iter() {
-                    match event.token() {
+                accept.set_active(clients.len());
+
+                let pending: Vec<Token> = std::mem::take(&mut backlog);
+
+                for token in events.iter().map(|event| event.token()).chain(pending) {
+                    match token {
                         SIGNAL => {
 
                             // Content phase completed
@@ -158,7 +250,7 @@ impl IO {
 
                             while let Some(mut resp) = ready.pop_front() {
                                 let token = next(&mut unique_token);
-                                if register(poll.registry(), resp.context(), token, Interest::WRITABLE) {
+                                if arm(poll.registry(), resp.context(), token, Interest::WRITABLE) {
                                     let response_timeout = resp.context().inner.as_ref().unwrap().opts.response_timeout;
                                     if let Some(exp) = resp.set_timeout(response_timeout) {
                                         keepalive.insert((exp, token));
@@ -170,31 +262,47 @@ impl IO {
 
                         token if token.0 < CLIENT.0 => {
 
-                            // New client
+                            // New clients, the queue is drained as the listener is edge-triggered,
+                            // up to multi_accept connections at once, the rest on the next turn of the loop
 
                             let mut servers = servers.lock().unwrap();
 
-                            if let Some(server) = servers.remove(&token) {
-                                if let Server::Valid((mut listener, opts, server_token)) = server  {
+                            if let Some(Server::Valid((mut listener, opts, server_token))) = servers.remove(&token) {
+                                if !poll.registry().oneshot() && accept.should_yield() {
+                                    servers.insert(server_token, Server::Valid((listener, opts, server_token)));
+                                    continue;
+                                }
+                                let mut accepted = 0;
+                                loop {
+                                    if accepted == accept.multi_accept() {
+                                        if !backlog.contains(&server_token) {
+                                            backlog.push(server_token);
+                                        }
+                                        servers.insert(server_token, Server::Valid((listener, opts, server_token)));
+                                        break;
+                                    }
+                                    accepted += 1;
                                     let client_token = next(&mut unique_token);
                                     match IO::handle_accept(&mut poll, &mut listener, client_token, &opts) {
                                         Ok(mut client) => {
-                                            if let Err(err) = poll.registry().reregister(&mut listener, server_token, Interest::READABLE) {
-                                                log_error!("error", err);
-                                            }
-                                            if let Some(exp) = client.set_timeout(opts.request_timeout) {
-                                                keepalive.insert((exp, token));
+                                            accept.accepted();
+                                            let header_timeout = client.inner.as_ref().unwrap().header_timeout();
+                                            if let Some(exp) = client.set_timeout(header_timeout) {
+                                                keepalive.insert((exp, client_token));
                                             }
                                             clients.insert(client_token, Item::Idle(client));
-                                            servers.insert(server_token, Server::Valid((listener, opts, server_token)));
                                         },
                                         Err(DECLINED) => {
                                             /* may be no space in poll ? */
+                                        },
+                                        Err(OK) => {
+                                            // accept queue is empty
                                             servers.insert(server_token, Server::Valid((listener, opts, server_token)));
+                                            break;
                                         },
                                         Err(AGAIN) => {
                                             let server_addr = listener.local_addr();
-                                            match IO::create_listener(OneOf::Valid(listener), server_token, &mut poll) {
+                                            match IO::create_listener(OneOf::Valid(listener), server_token, &mut poll, &accept) {
                                                 Ok(listener) => {
                                                     servers.insert(server_token, Server::Valid((listener, opts, server_token)));
                                                 },
@@ -203,8 +311,8 @@ impl IO {
                                                     log_error!("error", "Failed to create listener: {}", err);
                                                 }
                                             }
-                                        },
-                                        Err(OK) => panic!("Unreachable")
+                                            break;
+                                        }
                                     }
                                 }
                             }
@@ -216,6 +324,8 @@ impl IO {
                                 token,
                                 &mut clients,
                                 &mut keepalive,
+                                &mut delayed,
+                                &mut half_read,
                                 &workers
                             );
                         }
@@ -223,19 +333,42 @@ impl IO {
                 }
             }
 
+            // listeners removed right before the stop are closed before waiting for the workers,
+            // connections queued on them would wait for nothing otherwise
+            if updated.load(Ordering::Acquire) {
+                if let Ok(ref mut servers) = servers.lock() {
+                    IO::update_servers(&mut poll, servers, &accept);
+                }
+            }
+
             workers.stop();
             workers.wait();
         }).unwrap();
 
+        affinity::pin(&thr, &affinity.io);
+
         return Ok(IO {
             thr: Some(thr),
             servers: servers_,
             server_token: server_token,
             stop: stop_,
-            updated: updated_
+            updated: updated_,
+            waker,
+            queue,
+            engine
         });
     }
 
+    // Requests waiting for a worker thread
+    pub fn queue(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.queue)
+    }
+
+    // Engine in use, epoll when io_uring is not available
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
     pub fn add_listener(&mut self, addr: SocketAddr, opts: Option<Options>) -> CoreResult {
         let mut token = None;
         let mut servers = self.servers.lock().unwrap();
@@ -273,6 +406,8 @@ impl IO {
             }
         }
 
+        connections::get(&addr).set_max_connections(opts.as_ref().map(|opts| opts.max_connections).unwrap_or(0));
+
         let res = match token {
             Some(token) => {
                 // found
@@ -300,6 +435,7 @@ impl IO {
             },
             None => {
                 // new one
+                listen::prepare(addr)?;
                 let token = next(&mut self.server_token);
                 servers.insert(token, Server::Invalid((addr, opts.unwrap_or_default(), token)));
                 Ok(OK)
@@ -307,6 +443,7 @@ impl IO {
         };
 
         self.updated.store(true, Ordering::Release);
+        let _ = self.waker.wake();
 
         res
     }
@@ -347,6 +484,7 @@ impl IO {
         }
 
         self.updated.store(true, Ordering::Release);
+        let _ = self.waker.wake();
     }
 
     pub fn stop(&mut self) {
@@ -359,12 +497,15 @@ impl IO {
 
     fn update_servers(
         poll: &mut Poll,
-        servers: &mut HashMap<Token, Server>
+        servers: &mut HashMap<Token, Server>,
+        accept: &Accept
     ) {
         servers.retain(|_, server| {
             if let Server::Removed(state) = server {
                 if let OneOf::Valid(ref mut listener) = state {
-                    poll.registry().deregister(listener).unwrap();
+                    poll.registry().deregister_listener(listener).unwrap();
+                    upgrade::untrack(listener.as_raw_fd());
+                    accept.release(listener.local_addr().unwrap());
                 }
                 false
             } else {
@@ -374,7 +515,7 @@ impl IO {
 
         for (token, server) in servers.iter_mut() {
             if let Server::Invalid((addr, opts, _)) = server {
-                match IO::create_listener(OneOf::Invalid(*addr), *token, poll) {
+                match IO::create_listener(OneOf::Invalid(*addr), *token, poll, accept) {
                     Ok(listener) => *server = Server::Valid((listener, opts.clone(), *token)),
                     Err(err) => log_error!("error", "Failed to create listener: {}", err)
                 }
@@ -385,21 +526,25 @@ impl IO {
     fn create_listener(
         listen: OneOf,
         token: Token,
-        poll: &mut Poll
+        poll: &mut Poll,
+        accept: &Accept
     ) -> Result<TcpListener, Error> {
         let addr = match listen {
             OneOf::Valid(mut listener) => {
-                poll.registry().deregister(&mut listener).unwrap();
+                poll.registry().deregister_listener(&mut listener).unwrap();
                 let addr = listener.local_addr().unwrap();
+                upgrade::untrack(listener.as_raw_fd());
                 drop(listener);
+                accept.release(addr);
                 addr
             },
             OneOf::Invalid(addr) => addr,
         };
 
-        let mut listener = TcpListener::from_std(net2::TcpBuilder::new_v4()?.reuse_address(true)?.reuse_port(true)?.bind(addr)?.listen(512)?);
+        let mut listener = TcpListener::from_std(accept.listener(addr)?);
 
-        poll.registry().register(&mut listener, token, Interest::READABLE)?;
+        poll.registry().register_listener(&mut listener, token)?;
+        upgrade::track(addr, listener.as_raw_fd());
 
         Ok(listener)
     }
@@ -410,27 +555,47 @@ impl IO {
         token: Token,
         opts: &Options
     ) -> Result<ClientContext, Code> {
-        match server.accept() {
+        match poll.registry().accept(server) {
             Ok((mut stream, _)) => {
+                let server_addr = server.local_addr().unwrap();
+                let stats = connections::get(&server_addr);
+                if stats.exhausted() {
+                    stats.rejected();
+                    log_error!("warn", "Client connection local={} rejected, max_connections ({}) reached", server_addr, stats.open());
+                    IO::reject(&mut stream, opts);
+                    return Err(DECLINED);
+                }
                 match poll.registry().register(&mut stream, token, Interest::READABLE) {
                     Ok(()) => {
-                        Ok(ClientContext::with_state(StreamType::from(stream).or_else(|err| {
+                        let mut client = ClientContext::with_state(StreamType::from(stream).map_err(|err| {
                                log_error!("error", "Failed to create client context: {}", err);
-                               Err(DECLINED)
+                               DECLINED
                            })?,
                            server.local_addr().unwrap(),
                            State {
                                requests: 0,
                                opts: opts.clone(),
-                               request_id: Uuid::new_v4()
-                           }))
+                               request_id: Uuid::new_v4(),
+                               received: 0,
+                               read_timer: Instant::now(),
+                               headers_received: false,
+                               conn: Connection::open(&stats),
+                               retrans: 0
+                           });
+                        client.armed = Some((token, Interest::READABLE));
+                        Ok(client)
                     },
                     Err(err) =>  {
                         log_error!("error", "Failed to register read event for client socket: {}", err);
+                        stats.rejected();
+                        IO::reject(&mut stream, opts);
                         Err(DECLINED)
                     }
                 }
             },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(OK),
+            // connection has gone before being accepted
+            Err(err) if matches!(err.kind(), ErrorKind::ConnectionAborted | ErrorKind::Interrupted) => Err(DECLINED),
             Err(err) => {
                 log_error!("error", "Failed to accept: {}", err);
                 Err(AGAIN)
@@ -443,6 +608,8 @@ impl IO {
         token: Token,
         clients: &mut HashMap<Token, Item<T>>,
         keepalive: &mut BTreeSet<(SystemTime, Token)>,
+        delayed: &mut BTreeSet<(SystemTime, Token)>,
+        half_read: &mut HashMap<SocketAddr, usize>,
         workers: &ThreadPool<T, F>
     )
     where
@@ -459,12 +626,56 @@ impl IO {
                     if let Some(exp) = client.exp() {
                         keepalive.remove(&(exp, token));
                     }
-                    let request_timeout = client.inner.as_ref().unwrap().opts.request_timeout;
-                    if let Some(exp) = client.set_timeout(request_timeout) {
+                    client.inner.as_mut().unwrap().conn.set_idle(false);
+                    let state = client.inner.as_ref().unwrap();
+                    if state.opts.shared && state.requests == 0 {
+                        // address is shared with stream module
+                        let mut data = [0u8; 16];
+                        let sniffing = state.read_timer.elapsed() < listen::SNIFF_TIMEOUT;
+                        if let Ok(sz) = client.peek(&mut data) {
+                            let http = match listen::sniff_http(&data[..sz]) {
+                                Some(http) => http,
+                                None if sniffing => {
+                                    // method token is incomplete, peeked again with more bytes
+                                    if let Some(exp) = client.exp() {
+                                        keepalive.insert((exp, token));
+                                    }
+                                    delayed.insert((SystemTime::now() + SNIFF_DELAY, token));
+                                    clients.insert(token, Item::Idle(client));
+                                    return;
+                                },
+                                None => false
+                            };
+                            if !http {
+                                disarm(poll.registry(), &mut client);
+                                match listen::handoff(&client.server_addr) {
+                                    Some(handoff) => handoff(client),
+                                    None => log_error!("warn", "Client connection client={} local={} is not HTTP, closed",
+                                                       client.remote_addr(), client.local_addr())
+                                }
+                                return;
+                            }
+                        }
+                    }
+                    let max_half_read_connections = client.inner.as_ref().unwrap().opts.max_half_read_connections;
+                    let count = half_read.entry(client.server_addr).or_default();
+                    if max_half_read_connections != 0 && *count >= max_half_read_connections {
+                        // shed slow clients before they occupy more resources
+                        log_error!("warn", "Client connection client={} local={} closed, too many half-read connections ({})",
+                                   client.remote_addr(), client.local_addr(), *count);
+                        disarm(poll.registry(), &mut client);
+                        return;
+                    }
+                    *count += 1;
+                    let header_timeout = client.inner.as_ref().unwrap().header_timeout();
+                    if let Some(exp) = client.set_timeout(header_timeout) {
                         keepalive.insert((exp, token));
                     }
                     let mut inner = client.inner.as_mut().unwrap();
                     inner.request_id = Uuid::new_v4();
+                    inner.received = 0;
+                    inner.read_timer = Instant::now();
+                    inner.headers_received = false;
                     clients.insert(token, Item::Request(T::Request::new(client)));
                 },
 
@@ -472,10 +683,18 @@ impl IO {
                     if let Some(exp) = r.context().exp() {
                         keepalive.remove(&(exp, token));
                     }
-                    return match r.parse() {
+                    let parsed = r.parse();
+                    if let Ok(AGAIN) = parsed {
+                        if r.headers_received() {
+                            IO::headers_received(r.context());
+                        }
+                    } else {
+                        IO::release_half_read(half_read, r.const_context());
+                    }
+                    return match parsed {
                         Ok(OK) => {
                             // request has received
-                            deregister(poll.registry(), r.context());
+                            disarm(poll.registry(), r.context());
                             r.context().reset();
                             if let Err(err) = workers.post(r) {
                                 log_error!("error", err);
@@ -486,22 +705,36 @@ impl IO {
                             if let Some(exp) = r.context().exp() {
                                 keepalive.insert((exp, token));
                             }
+                            match r.context().throttle() {
+                                Some(delay) => {
+                                    delayed.insert((SystemTime::now() + delay, token));
+                                },
+                                None => {
+                                    let interests = match r.write_pending() {
+                                        true => Interest::READABLE | Interest::WRITABLE,
+                                        false => Interest::READABLE
+                                    };
+                                    if !arm(poll.registry(), r.context(), token, interests) {
+                                        return;
+                                    }
+                                }
+                            }
                             clients.insert(token, Item::Request(r));
                         },
                         Ok(DECLINED) => {
                             // closed
                             log_error!("info", "Keep-alived connection client={} local={} has closed",
                                        r.context().remote_addr(), r.context().local_addr());
-                            deregister(poll.registry(), r.context());
+                            disarm(poll.registry(), r.context());
                         }
                         Err(err) => {
-                            deregister(poll.registry(), r.context());
+                            disarm(poll.registry(), r.context());
                             log_error!("error", "{} client={} local={}", err, r.context().remote_addr(), r.context().local_addr());
                         }
                     }
                 },
 
-                Some(Item::Response((mut resp, _))) => {
+                Some(Item::Response((mut resp, mut peer))) => {
                     if let Some(exp) = resp.context().exp() {
                         keepalive.remove(&(exp, token));
                     }
@@ -509,7 +742,7 @@ impl IO {
                         match resp.flush() {
                             Ok(Flush::OK(None)) => {
                                 // request completed
-                                if register(poll.registry(), resp.context(), token, Interest::READABLE) {
+                                if arm(poll.registry(), resp.context(), token, Interest::READABLE) {
                                     let mut client = resp.close();
                                     let keepalive_timeout = match &mut client.inner {
                                         Some(state) => {
@@ -518,6 +751,7 @@ impl IO {
                                                 // close keep-alive session
                                                 log_error!("info", "Client keep-alived connection client={} local={} has closed (keepalive_requests)",
                                                            client.remote_addr(), client.local_addr());
+                                                disarm(poll.registry(), &mut client);
                                                 return;
                                             }
                                             state.opts.keepalive_timeout
@@ -525,6 +759,9 @@ impl IO {
                                         None => None
                                     };
                                     client.reset();
+                                    if let Some(state) = &mut client.inner {
+                                        state.conn.set_idle(true);
+                                    }
                                     if let Some(exp) = client.set_timeout(keepalive_timeout) {
                                         keepalive.insert((exp, token));
                                     }
@@ -565,19 +802,34 @@ impl IO {
                             },
                             Ok(Flush::AGAIN) => {
                                 // need more data
-                                if register(poll.registry(), resp.context(), token, Interest::WRITABLE) {
+                                if arm(poll.registry(), resp.context(), token, Interest::WRITABLE) {
                                     if let Some(exp) = resp.context().exp() {
                                         keepalive.insert((exp, token));
                                     }
                                     clients.insert(token, Item::Response((resp, None)));
                                 }
                             },
+                            Ok(Flush::DELAY(delay)) => {
+                                // throttled
+                                if let Some(exp) = resp.context().exp() {
+                                    keepalive.insert((exp, token));
+                                }
+                                delayed.insert((SystemTime::now() + delay, token));
+                                clients.insert(token, Item::Response((resp, None)));
+                            },
                             Ok(Flush::DECLINED) => {
                                 // closed
-                                deregister(poll.registry(), resp.context());
+                                if let Some(peer) = peer.as_mut() {
+                                    deregister(poll.registry(), &mut peer.stream);
+                                }
+                                disarm(poll.registry(), resp.context());
                             }
                             Err(err) => {
                                 log_error!("error", "Failed to send response: {}", err);
+                                if let Some(peer) = peer.as_mut() {
+                                    deregister(poll.registry(), &mut peer.stream);
+                                }
+                                disarm(poll.registry(), resp.context());
                             }
                         }
                         return;
@@ -588,6 +840,39 @@ impl IO {
     }
 }
 
+impl IO {
+    // Client is answered before closing instead of being reset, unless the address is shared with the stream module
+    fn reject(stream: &mut TcpStream, opts: &Options) {
+        if opts.shared {
+            return;
+        }
+        // unread request would make the close reset the connection
+        let mut data = [0u8; 4096];
+        let _ = stream.read(&mut data);
+        let _ = stream.write(b"HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+    }
+
+    fn release_half_read(half_read: &mut HashMap<SocketAddr, usize>, client: &ClientContext) {
+        if let Some(count) = half_read.get_mut(&client.server_addr) {
+            *count -= 1;
+        }
+    }
+
+    // Headers are completed, switch from client_header_timeout to request_timeout
+    fn headers_received(client: &mut ClientContext) {
+        let state = client.inner.as_mut().unwrap();
+        if state.headers_received {
+            return;
+        }
+        state.headers_received = true;
+        if state.opts.client_header_timeout.is_some() {
+            let timeout = state.opts.request_timeout.map(|timeout| timeout.saturating_sub(state.read_timer.elapsed()));
+            client.set_timeout(timeout);
+        }
+    }
+}
+
 fn pair<T, F: 'static>(f: F) -> (Arc<T>, Arc<T>)
 where
     F: Fn() -> T
@@ -626,4 +911,47 @@ fn register(registry: &Registry, stream: &mut StreamType, token: Token, interest
 
 fn deregister(registry: &Registry, stream: &mut StreamType) {
     let _ = registry.deregister(stream);
+}
+
+// Client socket is edge-triggered, it isn't re-armed for the same interests unless the registration is one-shot
+fn arm(registry: &Registry, client: &mut ClientContext, token: Token, interests: Interest) -> bool {
+    if client.armed == Some((token, interests)) && !registry.oneshot() {
+        return true;
+    }
+    let res = match client.armed {
+        Some(_) => registry.reregister(&mut **client, token, interests),
+        None => registry.register(&mut **client, token, interests)
+    };
+    match res {
+        Ok(()) => {
+            client.armed = Some((token, interests));
+            true
+        },
+        Err(err) => {
+            log_error!("error", "Failed to register event: {}", err);
+            false
+        }
+    }
+}
+
+// Throttled socket is re-armed anyway, the readiness gained while it was delayed is reported again
+fn rearm(registry: &Registry, client: &mut ClientContext, token: Token, interests: Interest) -> bool {
+    if client.armed.is_none() {
+        return arm(registry, client, token, interests);
+    }
+    match registry.reregister(&mut **client, token, interests) {
+        Ok(()) => {
+            client.armed = Some((token, interests));
+            true
+        },
+        Err(err) => {
+            log_error!("error", "Failed to register event: {}", err);
+            false
+        }
+    }
+}
+
+fn disarm(registry: &Registry, client: &mut ClientContext) {
+    let _ = registry.deregister(&mut **client);
+    client.armed = None;
 }
\ No newline at end of file
@@ -0,0 +1,344 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ self, Write };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use percent_encoding::{ percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS };
+
+use crate::cache::RefreshCache;
+use crate::core::status::{ self, Json };
+use crate::core::zone::Zone;
+use crate::error::CoreError;
+use crate::redis::Redis;
+
+// Separator of the fields and the escape character are encoded in the state file
+const STATE: &AsciiSet = &CONTROLS.add(b' ').add(b'%');
+
+// Snapshot of the zone, written not more often than once per sync_interval
+pub struct State {
+    pub path: String,
+    pub sync_interval: Duration
+}
+
+// Cached values of the shared zone evaluated by the variables are this many at most by default
+const CACHE_MAX_ENTRIES: usize = 10000;
+
+// Entries are kept by the redis server under the prefix, all the instances share them.
+// The variables take the values from the cache when it is configured, the API goes to the server.
+pub struct Shared {
+    redis: Arc<Redis>,
+    prefix: String,
+    cache: Option<RefreshCache<Option<String>>>
+}
+
+impl Shared {
+    // cache is (ttl, stale_ttl) of the values looked up by the variables
+    pub fn new(name: &str, redis: Arc<Redis>, prefix: String, cache: Option<(Duration, Duration)>, max_entries: Option<usize>) -> Shared {
+        let cache = cache.map(|(ttl, stale_ttl)| {
+            let redis = Arc::clone(&redis);
+            let prefix = prefix.clone();
+            RefreshCache::new(&format!("keyval_zone:{}", name), ttl, stale_ttl, max_entries.unwrap_or(CACHE_MAX_ENTRIES),
+                              Arc::new(move |key: &str| redis.get(&format!("{}{}", prefix, key))))
+        });
+        Shared {
+            redis,
+            prefix,
+            cache
+        }
+    }
+
+    fn invalidate(&self, key: Option<&str>) {
+        match (&self.cache, key) {
+            (Some(cache), Some(key)) => cache.invalidate(key),
+            (Some(cache), None) => cache.clear(),
+            (None, _) => {}
+        }
+    }
+}
+
+// Named store of the values set at runtime, entries expire after the timeout of the zone.
+// Zone without redis is per process, the worker processes do not see the entries of each other.
+pub struct KeyvalZone {
+    entries: Zone<String, String>,
+    timeout: Option<Duration>,
+    state: Option<State>,
+    shared: Option<Shared>,
+    // changed since the last snapshot
+    dirty: AtomicBool,
+    synced: Mutex<Instant>
+}
+
+// Unix time in milliseconds of the instant, 0 is never
+fn unix_ms(instant: Option<Instant>, now: Instant) -> u128 {
+    match instant {
+        Some(instant) => (SystemTime::now() + instant.saturating_duration_since(now))
+            .duration_since(UNIX_EPOCH).unwrap_or_default().as_millis(),
+        None => 0
+    }
+}
+
+fn instant(unix_ms: u64, now: Instant) -> Option<Instant> {
+    match unix_ms {
+        0 => None,
+        _ => {
+            let at = UNIX_EPOCH + Duration::from_millis(unix_ms);
+            Some(now + at.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+impl KeyvalZone {
+    fn new(timeout: Option<Duration>, max_entries: Option<usize>, state: Option<State>, shared: Option<Shared>) -> KeyvalZone {
+        KeyvalZone {
+            entries: Zone::new(timeout, max_entries),
+            timeout,
+            state,
+            shared,
+            dirty: AtomicBool::new(false),
+            synced: Mutex::new(Instant::now())
+        }
+    }
+
+    // Lines of the state file are 'key value expires', expires is unix time in milliseconds or 0,
+    // the missing file is an empty zone. Entries above max_entries are skipped, their number is returned.
+    fn load(&self, path: &str) -> io::Result<usize> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err)
+        };
+        let now = Instant::now();
+        let mut skipped = 0;
+        for (n, line) in text.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let expires = match fields.as_slice() {
+                [_, _, expires] => expires.parse::<u64>().ok(),
+                _ => None
+            };
+            let expires = match expires {
+                Some(expires) => instant(expires, now),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid line {}", n + 1)))
+            };
+            let ttl = match expires {
+                Some(expires) if expires <= now => continue,
+                Some(expires) => Some(expires - now),
+                None => None
+            };
+            let key = percent_decode_str(fields[0]).decode_utf8_lossy().into_owned();
+            let value = percent_decode_str(fields[1]).decode_utf8_lossy().into_owned();
+            if !self.entries.insert_ttl(key, value, ttl) {
+                skipped += 1;
+            }
+        }
+        Ok(skipped)
+    }
+
+    // The file is replaced at once, the reader never sees the partial snapshot.
+    // Temporary file is per process, the processes of the same config do not write into the same one.
+    fn save(&self, path: &str) -> io::Result<()> {
+        self.dirty.store(false, Ordering::SeqCst);
+        let now = Instant::now();
+        let mut text = String::new();
+        for (key, value, expires) in self.entries.entries() {
+            text.push_str(&format!("{} {} {}\n",
+                utf8_percent_encode(&key, STATE), utf8_percent_encode(&value, STATE), unix_ms(expires, now)));
+        }
+        let temp = format!("{}.{}.tmp", path, std::process::id());
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    }
+
+    // Snapshot of the changed zone once the sync_interval is expired, at once if forced
+    fn sync(&self, name: &str, force: bool) {
+        let state = match &self.state {
+            Some(state) => state,
+            None => return
+        };
+        let mut synced = self.synced.lock().unwrap();
+        if !self.dirty.load(Ordering::SeqCst) || (!force && synced.elapsed() < state.sync_interval) {
+            return;
+        }
+        if let Err(err) = self.save(&state.path) {
+            self.dirty.store(true, Ordering::SeqCst);
+            log_error!("error", "keyval_zone '{}': failed to save '{}', {}", name, state.path, err);
+        }
+        *synced = Instant::now();
+    }
+
+    // Entries outlive the process in the state file or on the redis server
+    pub fn persistent(&self) -> bool {
+        self.state.is_some() || self.shared.is_some()
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, CoreError> {
+        if let Some(shared) = &self.shared {
+            return shared.redis.get(&format!("{}{}", shared.prefix, key));
+        }
+        Ok(self.entries.get(key))
+    }
+
+    // Value of the variable, the cache of the shared zone keeps the round trip off the request
+    pub fn lookup(&self, key: &str) -> Result<Option<String>, CoreError> {
+        match self.shared.as_ref().and_then(|shared| shared.cache.as_ref()) {
+            Some(cache) => cache.get(key).map(|value| (*value).clone()),
+            None => self.get(key)
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), CoreError> {
+        if let Some(shared) = &self.shared {
+            shared.invalidate(Some(key));
+            return shared.redis.set(&format!("{}{}", shared.prefix, key), value, self.timeout);
+        }
+        if !self.entries.insert(key.to_string(), value.to_string()) {
+            return throw!("zone is full");
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // False if the key is not found
+    pub fn delete(&self, key: &str) -> Result<bool, CoreError> {
+        if let Some(shared) = &self.shared {
+            shared.invalidate(Some(key));
+            return shared.redis.del(&format!("{}{}", shared.prefix, key));
+        }
+        match self.entries.remove(key) {
+            Some(_) => {
+                self.dirty.store(true, Ordering::SeqCst);
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    pub fn clear(&self) -> Result<(), CoreError> {
+        if let Some(shared) = &self.shared {
+            shared.invalidate(None);
+            let keys = shared.redis.keys(&shared.prefix)?;
+            for keys in keys.chunks(1000) {
+                let mut args: Vec<&[u8]> = vec![b"DEL"];
+                args.extend(keys.iter().map(|key| key.as_bytes()));
+                shared.redis.command(&args)?;
+            }
+            return Ok(());
+        }
+        self.entries.clear();
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Live entries sorted by key
+    pub fn entries(&self) -> Result<Vec<(String, String)>, CoreError> {
+        let mut entries: Vec<(String, String)> = match &self.shared {
+            Some(shared) => {
+                let keys = shared.redis.keys(&shared.prefix)?;
+                let values = shared.redis.mget(&keys)?;
+                // keys expired after the scan are skipped
+                keys.into_iter().zip(values)
+                    .filter_map(|(key, value)| value.map(|value| (key[shared.prefix.len()..].to_string(), value)))
+                    .collect()
+            },
+            None => self.entries.entries().into_iter().map(|(key, value, _)| (key, value)).collect()
+        };
+        entries.sort();
+        Ok(entries)
+    }
+
+    // Entries of the shared zone are not counted, it takes the scan of the server
+    fn report(&self) -> Json {
+        let entries = match self.shared {
+            Some(_) => None,
+            None => Some(self.entries.len())
+        };
+        Json::object(vec![
+            ("entries", entries.into()),
+            ("max_entries", self.entries.max_entries().into()),
+            ("timeout", self.timeout.map(|timeout| timeout.as_millis() as u64).into()),
+            ("state_file", self.state.as_ref().map(|state| state.path.as_str()).into()),
+            ("redis", self.shared.as_ref().map(|shared| shared.redis.name()).into()),
+            ("cached", self.shared.as_ref().and_then(|shared| shared.cache.as_ref()).map(|cache| cache.len()).into())
+        ])
+    }
+}
+
+lazy_static! {
+    static ref ZONES: RwLock<HashMap<String, Arc<KeyvalZone>>> = RwLock::new(HashMap::new());
+}
+
+// Zone of the same name is declared once, the zone with the state is loaded from its file
+pub fn create(name: &str, timeout: Option<Duration>, max_entries: Option<usize>,
+              state: Option<State>, shared: Option<Shared>) -> Result<(), String> {
+    let mut zones = ZONES.write().unwrap();
+    if zones.contains_key(name) {
+        return Err(format!("'{}' is already defined", name));
+    }
+    if state.is_some() && shared.is_some() {
+        return Err("'state_file' and 'redis' are exclusive".to_string());
+    }
+    let zone = KeyvalZone::new(timeout, max_entries, state, shared);
+    if let Some(state) = &zone.state {
+        let skipped = zone.load(&state.path).map_err(|err| format!("failed to load '{}', {}", state.path, err))?;
+        if skipped != 0 {
+            log_error!("warn", "keyval_zone '{}': {} entries of '{}' are above max_entries, skipped", name, skipped, state.path);
+        }
+    }
+    zones.insert(name.to_string(), Arc::new(zone));
+    let name_ = name.to_string();
+    status::register("keyval_zones", name, Box::new(move || get(&name_).map(|zone| zone.report())));
+    Ok(())
+}
+
+// Zones with the state file, the periodic snapshots are not needed without them
+pub fn persistent() -> bool {
+    ZONES.read().unwrap().values().any(|zone| zone.state.is_some())
+}
+
+pub fn sync_all(force: bool) {
+    let zones: Vec<(String, Arc<KeyvalZone>)> = ZONES.read().unwrap().iter()
+        .map(|(name, zone)| (name.clone(), Arc::clone(zone)))
+        .collect();
+    for (name, zone) in zones {
+        zone.sync(&name, force);
+    }
+}
+
+pub fn get(name: &str) -> Option<Arc<KeyvalZone>> {
+    ZONES.read().unwrap().get(name).cloned()
+}
+
+// Failure of the shared zone is logged, the value is empty.
+// Lookup of the shared zone blocks the worker for the round trip to the redis server, unless it is cached.
+pub fn lookup(name: &str, key: &str) -> Option<String> {
+    match get(name)?.lookup(key) {
+        Ok(value) => value,
+        Err(err) => {
+            log_error!("error", "keyval_zone '{}': {}", name, err);
+            None
+        }
+    }
+}
+
+// Key of ${keyval_<zone>[...]}: 'text' or the name of the variable holding the key
+pub enum KeyRef<'a> {
+    Text(&'a str),
+    Var(&'a str)
+}
+
+pub fn parse_var(var: &str) -> Option<(&str, KeyRef<'_>)> {
+    let var = var.strip_prefix("keyval_")?.strip_suffix(']')?;
+    let (zone, key) = var.split_at(var.find('[')?);
+    let key = key[1..].trim();
+    match key.strip_prefix('\'').and_then(|key| key.strip_suffix('\'')) {
+        Some(text) => Some((zone, KeyRef::Text(text))),
+        None => Some((zone, KeyRef::Var(key)))
+    }
+}
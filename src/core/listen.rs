@@ -0,0 +1,155 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{ SocketAddr, TcpListener };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::Duration;
+
+use net2::unix::UnixTcpBuilderExt;
+
+use crate::client_context::ClientContext;
+use crate::core::upgrade;
+use crate::error::{ Code::*, CoreResult };
+
+pub type Handoff = Arc<dyn Fn(ClientContext) + Send + Sync>;
+
+struct Binding {
+    module: String,
+    shared: bool
+}
+
+lazy_static! {
+    static ref BINDINGS: Mutex<HashMap<SocketAddr, Vec<Binding>>> = Mutex::new(HashMap::new());
+    static ref HANDOFF: RwLock<HashMap<SocketAddr, Handoff>> = RwLock::new(HashMap::new());
+    static ref PREPARED: Mutex<Vec<(SocketAddr, TcpListener)>> = Mutex::new(Vec::new());
+}
+
+// Registers the address for the module.
+// Different modules may listen the same address only if all of them declare it shared,
+// in this case the first bytes of connection decide which module serves it.
+pub fn bind(addr: SocketAddr, module: &str, shared: bool) -> CoreResult {
+    let mut bindings = BINDINGS.lock().unwrap();
+    let bindings = bindings.entry(addr).or_default();
+
+    for binding in bindings.iter() {
+        if binding.module == module {
+            continue;
+        }
+        if !binding.shared || !shared {
+            return throw!("Address {} is already bound by '{}' module, 'shared: true' is required in both '{}' and '{}' to share it",
+                          addr, binding.module, binding.module, module);
+        }
+        log_error!("warn", "Address {} is shared between '{}' and '{}' modules", addr, binding.module, module);
+    }
+
+    match bindings.iter_mut().find(|binding| binding.module == module) {
+        Some(binding) => binding.shared &= shared,
+        None => bindings.push(Binding {
+            module: module.to_string(),
+            shared
+        })
+    }
+
+    Ok(OK)
+}
+
+pub fn unbind(addr: SocketAddr, module: &str) {
+    let mut bindings = BINDINGS.lock().unwrap();
+    if let Some(list) = bindings.get_mut(&addr) {
+        list.retain(|binding| binding.module != module);
+        if list.is_empty() {
+            bindings.remove(&addr);
+        }
+    }
+}
+
+pub fn bindings() -> Vec<(SocketAddr, String, bool)> {
+    BINDINGS.lock().unwrap().iter().flat_map(|(addr, list)| {
+        list.iter().map(move |binding| (*addr, binding.module.clone(), binding.shared))
+    }).collect()
+}
+
+// Every event pool listens its own socket of the address
+pub (crate) fn bind_reuseport(addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = net2::TcpBuilder::new_v4()?.reuse_address(true)?.reuse_port(true)?.bind(addr)?.listen(512)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+// Socket is inherited from the previous binary or bound on configuration,
+// while the process may still have the privileges to bind the low ports
+pub (crate) fn prepare(addr: SocketAddr) -> CoreResult {
+    let listener = match upgrade::inherited(addr) {
+        Some(listener) => listener,
+        None => bind_reuseport(addr).or_else(|err| throw!("Failed to bind {}: {}", addr, err))?
+    };
+    if let Err(err) = listener.set_nonblocking(true) {
+        return throw!("Failed to bind {}: {}", addr, err);
+    }
+    PREPARED.lock().unwrap().push((addr, listener));
+    Ok(OK)
+}
+
+// Listener prepared for the event pool
+pub (crate) fn prepared(addr: SocketAddr) -> Option<TcpListener> {
+    let mut prepared = PREPARED.lock().unwrap();
+    let pos = prepared.iter().position(|(prepared, _)| *prepared == addr)?;
+    Some(prepared.remove(pos).1)
+}
+
+// Stream module accepts non HTTP connections of the shared address
+pub fn set_handoff(addr: SocketAddr, handoff: Handoff) {
+    HANDOFF.write().unwrap().insert(addr, handoff);
+}
+
+pub fn remove_handoff(addr: SocketAddr) {
+    HANDOFF.write().unwrap().remove(&addr);
+}
+
+pub (crate) fn handoff(addr: &SocketAddr) -> Option<Handoff> {
+    HANDOFF.read().unwrap().get(addr).cloned()
+}
+
+// Slow client completes the method token in this time, the incomplete one is not HTTP
+pub const SNIFF_TIMEOUT: Duration = Duration::from_secs(1);
+
+// HTTP request starts with uppercase method token followed by space,
+// None is the incomplete token, the decision waits for more bytes
+pub fn sniff_http(data: &[u8]) -> Option<bool> {
+    if data.is_empty() {
+        // closed by the client, HTTP handles it
+        return Some(true);
+    }
+    for (i, c) in data.iter().enumerate() {
+        match *c {
+            b'A'..=b'Z' if i < 16 => continue,
+            b' ' if i > 0 => return Some(true),
+            _ => return Some(false)
+        }
+    }
+    match data.len() < 16 {
+        true => None,
+        false => Some(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sniffed() {
+        assert_eq!(sniff_http(b"GET / HTTP/1.1"), Some(true));
+        assert_eq!(sniff_http(b"OPTIONS "), Some(true));
+        assert_eq!(sniff_http(b""), Some(true));
+        assert_eq!(sniff_http(b"GE"), None);
+        assert_eq!(sniff_http(b"P"), None);
+        assert_eq!(sniff_http(b"\x16\x03\x01"), Some(false));
+        assert_eq!(sniff_http(b"Get /"), Some(false));
+        assert_eq!(sniff_http(b" GET"), Some(false));
+        assert_eq!(sniff_http(b"ABCDEFGHIJKLMNOPQ"), Some(false));
+    }
+}
@@ -0,0 +1,270 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::env;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+
+use crate::core::CoreModule;
+use crate::core::plugins::process::Process;
+use crate::core::{ signals, upgrade };
+use crate::error::CoreError;
+
+// New workers must be ready in this time on reload, the old ones are kept otherwise
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(10);
+// Worker crashed faster is respawned with a delay
+const RESPAWN_DELAY: Duration = Duration::from_secs(1);
+
+// Slot of the worker process starting from 1, 0 in the master or single process
+static SLOT: AtomicUsize = AtomicUsize::new(0);
+
+struct Worker {
+    pid: libc::pid_t,
+    started: Instant
+}
+
+pub fn is_worker() -> bool {
+    SLOT.load(Ordering::Relaxed) != 0
+}
+
+// Slot of the worker process, stays the same when the worker is respawned
+pub fn worker_slot() -> Option<usize> {
+    match SLOT.load(Ordering::Relaxed) {
+        0 => None,
+        slot => Some(slot - 1)
+    }
+}
+
+fn sigset() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in &[libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1, libc::SIGCHLD] {
+            libc::sigaddset(&mut set, *signal);
+        }
+        set
+    }
+}
+
+fn status_text(status: libc::c_int) -> String {
+    match libc::WIFSIGNALED(status) {
+        true => format!("killed by signal {}", libc::WTERMSIG(status)),
+        false => format!("exit code {}", libc::WEXITSTATUS(status))
+    }
+}
+
+// None is returned in the worker process
+fn fork_worker(slot: usize, ready: bool) -> Result<Option<(Worker, Option<RawFd>)>, CoreError> {
+    let mut pipe = [-1 as RawFd; 2];
+    if ready && unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return throw!("worker_processes: pipe, {}", std::io::Error::last_os_error());
+    }
+    match unsafe { libc::fork() } {
+        -1 => {
+            if ready {
+                unsafe { libc::close(pipe[0]); libc::close(pipe[1]) };
+            }
+            throw!("worker_processes: fork, {}", std::io::Error::last_os_error())
+        },
+        0 => {
+            SLOT.store(slot + 1, Ordering::Relaxed);
+            unsafe {
+                // workers are stopped with the master
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+                let mut set: libc::sigset_t = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, libc::SIGCHLD);
+                libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+            }
+            if ready {
+                unsafe { libc::close(pipe[0]) };
+                env::set_var(upgrade::READY_FD, pipe[1].to_string());
+            }
+            Ok(None)
+        },
+        pid => {
+            log_error!("info", "worker_processes: worker #{} pid={} has started", slot, pid);
+            let read_fd = match ready {
+                true => {
+                    unsafe { libc::close(pipe[1]) };
+                    Some(pipe[0])
+                },
+                false => None
+            };
+            Ok(Some((Worker { pid, started: Instant::now() }, read_fd)))
+        }
+    }
+}
+
+// Waits until all the workers have written to their ready pipes or one of them has closed it
+fn wait_ready(fds: &[RawFd], timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut pending: Vec<RawFd> = fds.to_vec();
+    while !pending.is_empty() {
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        let mut pfds: Vec<libc::pollfd> = pending.iter().map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0
+        }).collect();
+        let n = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, (deadline - now).as_millis() as libc::c_int) };
+        if n < 0 {
+            if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return false;
+        }
+        for pfd in pfds.iter().filter(|pfd| pfd.revents != 0) {
+            let mut byte = [0u8; 1];
+            if unsafe { libc::read(pfd.fd, byte.as_mut_ptr() as *mut libc::c_void, 1) } != 1 {
+                return false;
+            }
+            pending.retain(|fd| *fd != pfd.fd);
+        }
+    }
+    true
+}
+
+fn kill_all<'a>(pids: impl Iterator<Item = &'a libc::pid_t>, signal: libc::c_int) {
+    pids.for_each(|pid| { unsafe { libc::kill(*pid, signal) }; });
+}
+
+// Forks the worker processes when 'worker_processes' is set and supervises them.
+// Must be called after the main configuration, before any thread is started.
+// Returns in the worker process only, each one configures the rest of the modules
+// and binds its own listeners with SO_REUSEPORT.
+pub fn prefork() {
+    let process = CoreModule::get_plugin::<Process>();
+    let count = process.worker_processes();
+    if count == 0 {
+        return;
+    }
+
+    // connections queued on the listener of the stopped worker are reset otherwise
+    if std::fs::read_to_string("/proc/sys/net/ipv4/tcp_migrate_req").map(|v| v.trim() != "1").unwrap_or(true) {
+        log_error!("warn", "worker_processes: net.ipv4.tcp_migrate_req is not enabled, reload may reset some connections");
+    }
+
+    let set = sigset();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+
+    if let Err(err) = process.write_pid() {
+        log_error!("error", "{}", err.what());
+        std::process::exit(1);
+    }
+
+    let mut slots: Vec<Option<Worker>> = (0..count).map(|_| None).collect();
+    let mut respawn_at: Vec<Option<Instant>> = vec![Some(Instant::now()); count];
+    // previous generation of the workers still serving the accepted connections
+    let mut retired: Vec<libc::pid_t> = Vec::new();
+    let mut stopping = false;
+
+    loop {
+        if !stopping {
+            for slot in 0..count {
+                match respawn_at[slot] {
+                    Some(at) if at <= Instant::now() => {},
+                    _ => continue
+                }
+                match fork_worker(slot, false) {
+                    Ok(None) => return,
+                    Ok(Some((worker, _))) => {
+                        slots[slot] = Some(worker);
+                        respawn_at[slot] = None;
+                    },
+                    Err(err) => {
+                        log_error!("error", "{}", err.what());
+                        respawn_at[slot] = Some(Instant::now() + RESPAWN_DELAY);
+                    }
+                }
+            }
+        } else if slots.iter().all(Option::is_none) && retired.is_empty() {
+            log_error!("info", "worker_processes: all workers have exited");
+            process.remove_pid();
+            std::process::exit(0);
+        }
+
+        let timeout = libc::timespec {
+            tv_sec: RESPAWN_DELAY.as_secs() as libc::time_t,
+            tv_nsec: 0
+        };
+        let signal = unsafe { libc::sigtimedwait(&set, std::ptr::null_mut(), &timeout) };
+
+        match signal {
+            libc::SIGCHLD => loop {
+                let mut status: libc::c_int = 0;
+                let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+                if pid <= 0 {
+                    break;
+                }
+                if let Some(pos) = retired.iter().position(|retired| *retired == pid) {
+                    retired.remove(pos);
+                    continue;
+                }
+                let slot = match slots.iter().position(|worker| worker.as_ref().map(|w| w.pid) == Some(pid)) {
+                    Some(slot) => slot,
+                    None => continue
+                };
+                let worker = slots[slot].take().unwrap();
+                if stopping {
+                    continue;
+                }
+                log_error!("error", "worker_processes: worker #{} pid={} has exited, {}", slot, pid, status_text(status));
+                respawn_at[slot] = Some(match worker.started.elapsed() < RESPAWN_DELAY {
+                    true => Instant::now() + RESPAWN_DELAY,
+                    false => Instant::now()
+                });
+            },
+            libc::SIGTERM | libc::SIGINT => {
+                log_error!("info", "signal {}: stopping the workers", signal);
+                stopping = true;
+                // the second one stops the workers immediately as well
+                kill_all(slots.iter().flatten().map(|worker| &worker.pid).chain(retired.iter()), signal);
+            },
+            libc::SIGUSR1 => {
+                log_error!("info", "signal {}: reopen logs", signal);
+                signals::reopen();
+                kill_all(slots.iter().flatten().map(|worker| &worker.pid), signal);
+            },
+            libc::SIGHUP if !stopping => {
+                log_error!("info", "signal {}: reload, starting new workers", signal);
+                let mut workers: Vec<Worker> = Vec::new();
+                let mut fds: Vec<RawFd> = Vec::new();
+                for slot in 0..count {
+                    match fork_worker(slot, true) {
+                        Ok(None) => return,
+                        Ok(Some((worker, fd))) => {
+                            workers.push(worker);
+                            fds.extend(fd);
+                        },
+                        Err(err) => {
+                            log_error!("error", "{}", err.what());
+                            break;
+                        }
+                    }
+                }
+                let ready = workers.len() == count && wait_ready(&fds, RELOAD_TIMEOUT);
+                fds.iter().for_each(|fd| { unsafe { libc::close(*fd) }; });
+                if ready {
+                    let old: Vec<libc::pid_t> = slots.iter().flatten().map(|worker| worker.pid).collect();
+                    kill_all(old.iter(), libc::SIGTERM);
+                    retired.extend(old);
+                    slots = workers.into_iter().map(Some).collect();
+                    respawn_at = vec![None; count];
+                    log_error!("info", "worker_processes: reloaded");
+                } else {
+                    log_error!("error", "worker_processes: reload failed, new workers are not ready, keeping the old ones");
+                    let failed: Vec<libc::pid_t> = workers.iter().map(|worker| worker.pid).collect();
+                    kill_all(failed.iter(), libc::SIGKILL);
+                    retired.extend(failed);
+                }
+            },
+            _ => {}
+        }
+    }
+}
@@ -3,7 +3,7 @@
  */
 
 use std::ops::Deref;
-use std::time::Duration;
+use std::time::{ Duration, Instant };
 use uuid::Uuid;
 
 use crate::module::*;
@@ -99,7 +99,13 @@ pub (crate) struct Options {
     pub request_timeout: Option<Duration>,
     pub response_timeout: Option<Duration>,
     pub keepalive_timeout: Option<Duration>,
-    pub keepalive_requests: u64
+    pub keepalive_requests: u64,
+    pub limit_upload_rate: usize,
+    pub shared: bool,
+    pub client_header_timeout: Option<Duration>,
+    pub max_half_read_connections: usize,
+    // open connections of the listener, zero is unlimited
+    pub max_connections: usize
 }
 
 impl Default for Options {
@@ -108,7 +114,12 @@ impl Default for Options {
             request_timeout: None,
             response_timeout: None,
             keepalive_timeout: None,
-            keepalive_requests: std::u64::MAX
+            keepalive_requests: u64::MAX,
+            limit_upload_rate: 0,
+            shared: false,
+            client_header_timeout: None,
+            max_half_read_connections: 0,
+            max_connections: 0
         }
     }
 }
@@ -116,10 +127,84 @@ impl Default for Options {
 pub (crate) struct State {
     opts: Options,
     requests: u64,
-    request_id: Uuid
+    request_id: Uuid,
+    received: usize,
+    read_timer: Instant,
+    headers_received: bool,
+    conn: connections::Connection,
+    // total_retrans of TCP_INFO at the end of the previous response
+    retrans: u32
+}
+
+impl State {
+    pub fn request_id(&self) -> &Uuid {
+        &self.request_id
+    }
+
+    pub fn connection(&self) -> u64 {
+        self.conn.serial()
+    }
+
+    // Completed requests of the connection
+    pub fn requests(&self) -> u64 {
+        self.requests
+    }
+
+    // Segments retransmitted since the previous response of the connection
+    pub (crate) fn retransmitted(&mut self, total_retrans: u32) -> u32 {
+        let retrans = total_retrans.saturating_sub(self.retrans);
+        self.retrans = total_retrans;
+        retrans
+    }
+
+    pub (crate) fn add_received(&mut self, sz: usize) {
+        self.received += sz;
+    }
+
+    pub (crate) fn response_timeout(&self) -> Option<Duration> {
+        self.opts.response_timeout
+    }
+
+    // Deadline for the request line and headers, not longer than the whole request
+    pub (crate) fn header_timeout(&self) -> Option<Duration> {
+        match (self.opts.client_header_timeout, self.opts.request_timeout) {
+            (Some(header_timeout), Some(request_timeout)) => Some(std::cmp::min(header_timeout, request_timeout)),
+            (header_timeout, request_timeout) => header_timeout.or(request_timeout)
+        }
+    }
+
+    pub (crate) fn throttle(&self) -> Option<Duration> {
+        if self.opts.limit_upload_rate == 0 {
+            return None;
+        }
+        let allowed = self.read_timer.elapsed().as_secs_f64() * self.opts.limit_upload_rate as f64;
+        match self.received as f64 - allowed {
+            excess if excess > 0.0 => Some(Duration::from_secs_f64(excess / self.opts.limit_upload_rate as f64)),
+            _ => None
+        }
+    }
 }
 
 pub mod plugins;
+pub mod listen;
+pub mod fd;
+pub mod ring;
+pub mod zone;
+pub mod keyval_zone;
+pub mod status;
+pub mod budget;
+pub mod buffers;
+pub mod pool;
+pub mod cgroup;
+pub mod sink;
+pub mod shipper;
+pub mod upgrade;
+pub mod signals;
+pub mod master;
+pub mod affinity;
+pub mod poller;
+pub mod accept;
+pub mod connections;
 mod io;
 mod worker;
 pub (crate) mod server;
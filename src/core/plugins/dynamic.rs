@@ -0,0 +1,96 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_core_plugin!(DynamicPlugins);
+
+use std::collections::HashSet;
+use std::ffi::{ CStr, CString };
+use std::os::raw::c_char;
+
+use crate::core::*;
+use crate::plugin::*;
+use crate::error::{ Code, CoreError };
+
+// Plugins of the shared libraries, the libraries are never unloaded
+pub struct DynamicPlugins {
+    loaded: HashSet<String>
+}
+
+fn dlerror() -> String {
+    unsafe {
+        let err = libc::dlerror();
+        match err.is_null() {
+            true => "unknown error".to_string(),
+            false => CStr::from_ptr(err).to_string_lossy().into_owned()
+        }
+    }
+}
+
+// The library is built by export_plugins! against the same version of the server
+fn load(path: &str) -> Result<(), CoreError> {
+    let filename = CString::new(path).or_else(|_| throw!("load_plugin: invalid path '{}'", path))?;
+    unsafe {
+        let handle = libc::dlopen(filename.as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL);
+        if handle.is_null() {
+            return throw!("load_plugin: {}", dlerror());
+        }
+        let symbol = |name: &str| {
+            let name = CString::new(name).unwrap();
+            libc::dlsym(handle, name.as_ptr())
+        };
+        let (version, register) = (symbol(PLUGIN_VERSION_SYMBOL), symbol(PLUGIN_REGISTER_SYMBOL));
+        if version.is_null() || register.is_null() {
+            libc::dlclose(handle);
+            return throw!("load_plugin: '{}' is not a plugin library", path);
+        }
+        let version: extern "C" fn() -> *const c_char = std::mem::transmute(version);
+        let version = CStr::from_ptr(version()).to_string_lossy().into_owned();
+        let expected = PLUGIN_API_VERSION.trim_end_matches('\0');
+        if version != expected {
+            libc::dlclose(handle);
+            return throw!("load_plugin: '{}' is built for {}, the server is {}", path, version, expected);
+        }
+        let register: extern "C" fn() = std::mem::transmute(register);
+        register();
+    }
+    log_error!("info", "Plugin library '{}' has loaded", path);
+    Ok(())
+}
+
+impl Plugin for DynamicPlugins {
+    type ModuleType = Core;
+
+    fn name() -> &'static str {
+        "DynamicPlugins"
+    }
+
+    // Plugins are registered before the modules are configured, so the directive is in the main config
+    fn configure(&mut self) -> ActionResult {
+        add_command!(Context::MAIN, "load_plugin", |_: &mut MainContext, paths: Vec<String>| {
+            let dynamic = CoreModule::get_plugin::<DynamicPlugins>();
+            for path in paths {
+                if dynamic.loaded.insert(path.clone()) {
+                    load(&path)?;
+                }
+            }
+            Ok(None)
+        })?;
+
+        Ok(Code::OK)
+    }
+}
+
+impl Default for DynamicPlugins {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DynamicPlugins {
+    pub fn new() -> DynamicPlugins {
+        DynamicPlugins {
+            loaded: HashSet::new()
+        }
+    }
+}
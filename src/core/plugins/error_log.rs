@@ -4,21 +4,70 @@
 
 register_core_plugin!(ErrorLog);
 
-use std::fs::File;
 use std::collections::HashMap;
 use std::sync::{ Arc, Mutex };
-use std::fs::OpenOptions;
-use std::io::prelude::*;
 use chrono::Utc;
+use yaml_rust::Yaml;
 
 use crate::core::*;
+use crate::core::{ ring, signals };
+use crate::core::sink::{ self, Sink };
 use crate::plugin::*;
-use crate::error::Code;
-use crate::config::CommandResult;
+use crate::error::{ Code, CoreError };
+use crate::config::{ CommandResult, ConfigBlock, Schema, SchemaType, Value };
+
+// 'error_log: error.log' or 'error_log: { file: error.log, level: warn }'
+#[derive(Clone, Default)]
+pub struct ErrorLogTarget {
+    pub filename: Option<String>,
+    pub level: Option<u8>
+}
+
+impl Value for ErrorLogTarget {
+    type Type = ErrorLogTarget;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        match v {
+            Yaml::String(filename) => Ok(ErrorLogTarget {
+                filename: Some(filename.clone()),
+                level: None
+            }),
+            Yaml::Hash(h) => {
+                let mut target = ErrorLogTarget::default();
+                // taken, so the keys are not processed as commands
+                for (k, v) in std::mem::take(h) {
+                    match (k.as_str(), v.as_str()) {
+                        (Some("file"), Some(filename)) => target.filename = Some(filename.to_string()),
+                        (Some("level"), Some(level)) => target.level = Some(level_of(level)?),
+                        _ => return throw!("error_log: 'file' and 'level' expected")
+                    }
+                }
+                Ok(target)
+            },
+            _ => throw!("type mismatch")
+        }
+    }
+}
+
+pub fn level_of(level: &str) -> Result<u8, CoreError> {
+    match level {
+        "error" => Ok(sink::ERROR),
+        "warn" => Ok(sink::WARN),
+        "info" => Ok(sink::INFO),
+        "debug" => Ok(sink::DEBUG),
+        _ => throw!("unknown log level '{}', error, warn, info or debug expected", level)
+    }
+}
+
+fn severity(level: &str) -> u8 {
+    level_of(level).unwrap_or(sink::INFO)
+}
 
 pub struct ErrorLog {
     filename: Option<String>,
-    files: Arc<Mutex<HashMap<String, File>>>
+    // messages less severe than the level are skipped
+    level: u8,
+    ring: bool,
+    files: Arc<Mutex<HashMap<String, Sink>>>
 }
 
 impl Plugin for ErrorLog {
@@ -30,12 +79,39 @@ impl Plugin for ErrorLog {
 
     fn configure(&mut self) -> ActionResult {
 
-        add_command!(Context::MAIN, "error_log", |_: &mut MainContext, filename: String| {
-            ErrorLog::open(&filename)?;
-            CoreModule::get_plugin::<ErrorLog>().filename = Some(filename);
+        let files = Arc::clone(&self.files);
+
+        signals::on_reopen(Box::new(move || {
+            for (filename, sink) in files.lock().unwrap().iter_mut() {
+                if let Err(err) = sink.reopen(filename) {
+                    eprintln!("Failed to reopen error_log '{}': {}", filename, err.what());
+                }
+            }
+        }));
+
+        add_command!(Context::MAIN, "error_log", |_: &mut MainContext, target: ErrorLogTarget| {
+            let error_log = CoreModule::get_plugin::<ErrorLog>();
+            if let Some(filename) = target.filename {
+                ErrorLog::open(&filename)?;
+                error_log.filename = Some(filename);
+            }
+            if let Some(level) = target.level {
+                error_log.level = level;
+            }
+            Ok(None)
+        })?;
+
+        // lines are kept in the ring 'error' for the tail endpoint
+        add_command!(Context::MAIN, "error_log_ring", |_: &mut MainContext, size: usize| {
+            ring::create("error", size);
+            CoreModule::get_plugin::<ErrorLog>().ring = true;
             Ok(None)
         })?;
 
+        add_schema!(Context::MAIN, "error_log_ring", Schema::new()
+            .value(SchemaType::Integer)
+            .range("", 1, i64::MAX))?;
+
         Ok(Code::OK)
     }
 }
@@ -44,6 +120,8 @@ impl ErrorLog {
     pub fn new() -> ErrorLog {
         ErrorLog {
             filename: None,
+            level: sink::DEBUG,
+            ring: false,
             files: Arc::new(Mutex::new(HashMap::new()))
         }
     }
@@ -53,15 +131,14 @@ impl ErrorLog {
 
         let mut files = error_log.files.lock().unwrap();
 
+        // 'stderr', 'stdout', 'syslog://host:port?facility=name' or a file
         if files.get(filename).is_none() {
-            return match OpenOptions::new().append(true)
-                                           .create(true)
-                                           .open(&filename) {
-                Ok(f) => {
-                    files.insert(filename.clone(), f);
+            return match Sink::open(filename) {
+                Ok(sink) => {
+                    files.insert(filename.clone(), sink);
                     Ok(None)
                 },
-                Err(err) => throw!("Failed to open error_log file '{}': {}", filename, err)
+                Err(err) => throw!("Failed to open error_log '{}': {}", filename, err.what())
             }
         }
 
@@ -69,11 +146,27 @@ impl ErrorLog {
     }
 
     pub fn log(tp: &str, level: &str, filename: &Option<String>, args: std::fmt::Arguments) {
+        ErrorLog::log_with(tp, level, filename, None, args)
+    }
+
+    // Context level overrides the main one, debug messages are compiled out with 'max_level_info'
+    pub fn log_with(tp: &str, level: &str, filename: &Option<String>, min_level: Option<u8>, args: std::fmt::Arguments) {
+        let severity = severity(level);
+        if cfg!(feature = "max_level_info") && severity > sink::INFO {
+            return;
+        }
         match CoreModule::get_plugin_ex::<ErrorLog>() {
             Some(error_log) => {
+                if severity > min_level.unwrap_or(error_log.level) {
+                    return;
+                }
+                if error_log.ring {
+                    ring::push("error", format!("{} [{}] [{}] {}", Utc::now().format("%Y/%m/%d-%H:%M:%S"), tp, level, args));
+                }
                 if let Some(filename) = filename.as_ref().or(error_log.filename.as_ref()) {
-                    if let Some(f) = error_log.files.lock().unwrap().get_mut(filename) {
-                        let _ = f.write_fmt(format_args!("{} [{}] [{}] {}\n", Utc::now().format("%Y/%m/%d-%H:%M:%S"), tp, level, args));
+                    if let Some(sink) = error_log.files.lock().unwrap().get_mut(filename) {
+                        let line = format!("{} [{}] [{}] {}\n", Utc::now().format("%Y/%m/%d-%H:%M:%S"), tp, level, args);
+                        let _ = sink.write(severity, line.as_bytes());
                         return;
                     }
                 }
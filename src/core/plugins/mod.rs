@@ -1 +1,3 @@
-pub mod error_log;
\ No newline at end of file
+pub mod error_log;
+pub mod process;
+pub mod dynamic;
@@ -0,0 +1,192 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_core_plugin!(Process);
+
+use std::ffi::CString;
+use std::fs;
+
+use crate::core::*;
+use crate::core::{ master, signals };
+use crate::plugin::*;
+use crate::error::{ Code, CoreError };
+
+pub struct Process {
+    worker_processes: usize,
+    pid: Option<String>,
+    user: Option<String>,
+    group: Option<String>
+}
+
+// Must be done before any thread is started, so it is done as soon as the directive is met
+fn daemonize() -> Result<(), CoreError> {
+    unsafe {
+        match libc::fork() {
+            -1 => return throw!("daemon: fork, {}", std::io::Error::last_os_error()),
+            0 => {},
+            _ => libc::_exit(0)
+        }
+        if libc::setsid() == -1 {
+            return throw!("daemon: setsid, {}", std::io::Error::last_os_error());
+        }
+        let null = CString::new("/dev/null").unwrap();
+        let fd = libc::open(null.as_ptr(), libc::O_RDWR);
+        if fd == -1 {
+            return throw!("daemon: /dev/null, {}", std::io::Error::last_os_error());
+        }
+        for std_fd in 0..3 {
+            libc::dup2(fd, std_fd);
+        }
+        if fd > 2 {
+            libc::close(fd);
+        }
+    }
+    Ok(())
+}
+
+fn drop_privileges(user: Option<&String>, group: Option<&String>) -> Result<(), CoreError> {
+    if unsafe { libc::geteuid() } != 0 {
+        log_error!("warn", "process: not running as root, 'user' and 'group' are ignored");
+        return Ok(());
+    }
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let name = CString::new(user.as_str()).or_else(|_| throw!("invalid user '{}'", user))?;
+            let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+            if pw.is_null() {
+                return throw!("unknown user '{}'", user);
+            }
+            unsafe { (Some((*pw).pw_uid), Some((*pw).pw_gid)) }
+        },
+        None => (None, None)
+    };
+    // primary group of the user unless the group is set explicitly
+    let gid = match group {
+        Some(group) => {
+            let name = CString::new(group.as_str()).or_else(|_| throw!("invalid group '{}'", group))?;
+            let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+            if gr.is_null() {
+                return throw!("unknown group '{}'", group);
+            }
+            Some(unsafe { (*gr).gr_gid })
+        },
+        None => user_gid
+    };
+    // group first, it can't be changed without root
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 || unsafe { libc::setgid(gid) } != 0 {
+            return throw!("setgid({}), {}", gid, std::io::Error::last_os_error());
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return throw!("setuid({}), {}", uid, std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl Plugin for Process {
+    type ModuleType = Core;
+
+    fn name() -> &'static str {
+        "Process"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        // before any thread is started, they inherit the signal mask
+        signals::block();
+
+        add_command!(Context::MAIN, "daemon", |_: &mut MainContext, daemon: bool| {
+            if daemon {
+                daemonize()?;
+            }
+            Ok(None)
+        })?;
+
+        add_command!(Context::MAIN, "worker_processes", |_: &mut MainContext, worker_processes: usize| {
+            CoreModule::get_plugin::<Process>().worker_processes = worker_processes;
+            Ok(None)
+        })?;
+
+        add_command!(Context::MAIN, "pid", |_: &mut MainContext, pid: String| {
+            CoreModule::get_plugin::<Process>().pid = Some(pid);
+            Ok(None)
+        })?;
+
+        add_command!(Context::MAIN, "user", |_: &mut MainContext, user: String| {
+            CoreModule::get_plugin::<Process>().user = Some(user);
+            Ok(None)
+        })?;
+
+        add_command!(Context::MAIN, "group", |_: &mut MainContext, group: String| {
+            CoreModule::get_plugin::<Process>().group = Some(group);
+            Ok(None)
+        })?;
+
+        Ok(Code::OK)
+    }
+
+    // Core is activated first, the listeners are already bound on configuration
+    fn activate(&mut self) -> ActionResult {
+        // after the daemon fork, only the forking thread survives it
+        signals::start();
+        // pid file belongs to the master process
+        if !master::is_worker() {
+            self.write_pid()?;
+        }
+        if self.user.is_some() || self.group.is_some() {
+            // never keep serving as root when it is not expected
+            if let Err(err) = drop_privileges(self.user.as_ref(), self.group.as_ref()) {
+                log_error!("error", "Failed to drop privileges: {}", err.what());
+                std::process::exit(1);
+            }
+        }
+        Ok(Code::OK)
+    }
+
+    fn wait(&mut self) {
+        self.remove_pid();
+    }
+}
+
+impl Default for Process {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Process {
+    pub fn new() -> Process {
+        Process {
+            worker_processes: 0,
+            pid: None,
+            user: None,
+            group: None
+        }
+    }
+
+    pub (crate) fn worker_processes(&self) -> usize {
+        self.worker_processes
+    }
+
+    pub (crate) fn write_pid(&self) -> Result<(), CoreError> {
+        if let Some(pid) = &self.pid {
+            if let Err(err) = fs::write(pid, format!("{}\n", std::process::id())) {
+                return throw!("Failed to write pid file '{}': {}", pid, err);
+            }
+        }
+        Ok(())
+    }
+
+    // Pid file may already belong to the upgraded binary
+    pub (crate) fn remove_pid(&self) {
+        if let Some(pid) = &self.pid {
+            if fs::read_to_string(pid).map(|s| s.trim() == std::process::id().to_string()).unwrap_or(false) {
+                let _ = fs::remove_file(pid);
+            }
+        }
+    }
+}
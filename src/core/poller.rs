@@ -0,0 +1,757 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::io::{ self, IoSlice, Read, Write };
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use mio::event::Source;
+use mio::net::{ TcpListener, TcpStream };
+use mio::{ Interest, Token };
+
+use crate::error::CoreError;
+
+// Event engine of the IO threads, 'io_engine' of the workgroup
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Engine {
+    #[default]
+    Epoll,
+    Uring
+}
+
+impl Engine {
+    pub fn parse(name: &str) -> Result<Engine, CoreError> {
+        match name {
+            "epoll" => Ok(Engine::Epoll),
+            #[cfg(feature = "uring")]
+            "uring" => Ok(Engine::Uring),
+            #[cfg(not(feature = "uring"))]
+            "uring" => throw!("io_engine: uring is not supported, the binary is built without the 'uring' feature"),
+            _ => throw!("io_engine: unknown engine '{}', epoll or uring expected", name)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Engine::Epoll => "epoll",
+            Engine::Uring => "uring"
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Event(Token);
+
+impl Event {
+    pub fn token(&self) -> Token {
+        self.0
+    }
+}
+
+pub struct Events {
+    inner: mio::Events,
+    tokens: Vec<Token>
+}
+
+impl Events {
+    pub fn with_capacity(capacity: usize) -> Events {
+        Events {
+            inner: mio::Events::with_capacity(capacity),
+            tokens: Vec::with_capacity(capacity)
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        self.tokens.iter().map(|token| Event(*token))
+    }
+}
+
+pub struct Poll {
+    engine: Engine,
+    mio: Option<mio::Poll>,
+    registry: Registry
+}
+
+pub struct Registry {
+    mio: Option<mio::Registry>,
+    #[cfg(feature = "uring")]
+    uring: Option<std::sync::Arc<std::sync::Mutex<uring::Ring>>>
+}
+
+impl Poll {
+    // io_uring falls back to epoll when the kernel doesn't support it
+    pub fn new(engine: Engine, entries: usize) -> io::Result<Poll> {
+        #[cfg(feature = "uring")]
+        {
+            if engine == Engine::Uring {
+                match uring::Ring::new(entries) {
+                    Ok(ring) => return Ok(Poll {
+                        engine: Engine::Uring,
+                        mio: None,
+                        registry: Registry {
+                            mio: None,
+                            uring: Some(std::sync::Arc::new(std::sync::Mutex::new(ring)))
+                        }
+                    }),
+                    Err(err) => log_error!("warn", "io_uring is not available, {}, falling back to epoll", err)
+                }
+            }
+        }
+        #[cfg(not(feature = "uring"))]
+        let _ = (engine, entries);
+        let poll = mio::Poll::new()?;
+        let registry = poll.registry().try_clone()?;
+        Ok(Poll {
+            engine: Engine::Epoll,
+            mio: Some(poll),
+            registry: Registry {
+                mio: Some(registry),
+                #[cfg(feature = "uring")]
+                uring: None
+            }
+        })
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        events.tokens.clear();
+        if let Some(poll) = self.mio.as_mut() {
+            poll.poll(&mut events.inner, timeout)?;
+            events.tokens.extend(events.inner.iter().map(|event| event.token()));
+            return Ok(());
+        }
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = self.registry.uring.as_ref() {
+                uring::attach(ring);
+                return ring.lock().unwrap().poll(&mut events.tokens, timeout);
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl Registry {
+    // Registrations of io_uring are one-shot, they are renewed after each event
+    pub fn oneshot(&self) -> bool {
+        self.mio.is_none()
+    }
+
+    pub fn register<S: Source + AsRawFd + ?Sized>(&self, source: &mut S, token: Token, interests: Interest)
+        -> io::Result<()>
+    {
+        if let Some(registry) = self.mio.as_ref() {
+            return registry.register(source, token, interests);
+        }
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = self.uring.as_ref() {
+                return ring.lock().unwrap().poll_add(source.as_raw_fd(), token, interests);
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn reregister<S: Source + AsRawFd + ?Sized>(&self, source: &mut S, token: Token, interests: Interest)
+        -> io::Result<()>
+    {
+        if let Some(registry) = self.mio.as_ref() {
+            return registry.reregister(source, token, interests);
+        }
+        self.register(source, token, interests)
+    }
+
+    pub fn deregister<S: Source + AsRawFd + ?Sized>(&self, source: &mut S) -> io::Result<()> {
+        if let Some(registry) = self.mio.as_ref() {
+            return registry.deregister(source);
+        }
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = self.uring.as_ref() {
+                return ring.lock().unwrap().poll_remove(source.as_raw_fd());
+            }
+        }
+        unreachable!()
+    }
+
+    // Connections are accepted by the ring itself, the listener is only polled by epoll
+    pub fn register_listener(&self, listener: &mut TcpListener, token: Token) -> io::Result<()> {
+        if let Some(registry) = self.mio.as_ref() {
+            return registry.register(listener, token, Interest::READABLE);
+        }
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = self.uring.as_ref() {
+                return ring.lock().unwrap().accept_add(listener.as_raw_fd(), token);
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn deregister_listener(&self, listener: &mut TcpListener) -> io::Result<()> {
+        if let Some(registry) = self.mio.as_ref() {
+            return registry.deregister(listener);
+        }
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = self.uring.as_ref() {
+                return ring.lock().unwrap().accept_remove(listener.as_raw_fd());
+            }
+        }
+        unreachable!()
+    }
+
+    // WouldBlock when the accept queue is empty
+    pub fn accept(&self, listener: &mut TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+        if self.mio.is_some() {
+            return listener.accept();
+        }
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = self.uring.as_ref() {
+                use std::os::unix::io::FromRawFd;
+                let fd = ring.lock().unwrap().accepted(listener.as_raw_fd())?;
+                let stream = unsafe { TcpStream::from_raw_fd(fd) };
+                let addr = stream.peer_addr()?;
+                return Ok((stream, addr));
+            }
+        }
+        unreachable!()
+    }
+}
+
+pub enum Waker {
+    Mio(mio::Waker),
+    #[cfg(feature = "uring")]
+    Uring(std::fs::File)
+}
+
+impl Waker {
+    pub fn new(registry: &Registry, token: Token) -> io::Result<Waker> {
+        if let Some(registry) = registry.mio.as_ref() {
+            return Ok(Waker::Mio(mio::Waker::new(registry, token)?));
+        }
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = registry.uring.as_ref() {
+                use std::os::unix::io::FromRawFd;
+                let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+                if fd == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                let eventfd = unsafe { std::fs::File::from_raw_fd(fd) };
+                ring.lock().unwrap().waker_add(fd, token)?;
+                return Ok(Waker::Uring(eventfd));
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn wake(&self) -> io::Result<()> {
+        match self {
+            Waker::Mio(waker) => waker.wake(),
+            #[cfg(feature = "uring")]
+            Waker::Uring(eventfd) => {
+                use std::io::Write;
+                match (&*eventfd).write(&1u64.to_ne_bytes()) {
+                    Ok(_) => Ok(()),
+                    // counter is full, the poll is woken up anyway
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+                    Err(err) => Err(err)
+                }
+            }
+        }
+    }
+}
+
+// Reads, writes and sendfile of the IO thread with io_uring are submitted to the ring of the thread,
+// the other threads and epoll make the syscalls
+pub fn recv(stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    #[cfg(feature = "uring")]
+    {
+        if let Some(result) = uring::current(|ring| ring.recv(stream.as_raw_fd(), buf)) {
+            return result;
+        }
+    }
+    stream.read(buf)
+}
+
+pub fn send(stream: &mut TcpStream, bufs: &[IoSlice]) -> io::Result<usize> {
+    #[cfg(feature = "uring")]
+    {
+        if let Some(result) = uring::current(|ring| ring.send(stream.as_raw_fd(), bufs)) {
+            return result;
+        }
+    }
+    match bufs.len() {
+        1 => stream.write(&bufs[0]),
+        _ => stream.write_vectored(bufs)
+    }
+}
+
+// Up to 'len' bytes of the file from 'offset', zero at the end of the file
+pub fn sendfile(stream: &TcpStream, file: &std::fs::File, offset: u64, len: usize) -> io::Result<usize> {
+    #[cfg(feature = "uring")]
+    {
+        if let Some(result) = uring::current(|ring| ring.sendfile(stream.as_raw_fd(), file.as_raw_fd(), offset, len)) {
+            return result;
+        }
+    }
+    let mut offset = offset as libc::off_t;
+    match unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, len) } {
+        -1 => Err(io::Error::last_os_error()),
+        sent => Ok(sent as usize)
+    }
+}
+
+#[cfg(feature = "uring")]
+mod uring {
+    use std::cell::RefCell;
+    use std::collections::{ HashMap, VecDeque };
+    use std::io::{ self, IoSlice };
+    use std::os::unix::io::RawFd;
+    use std::sync::{ Arc, Mutex, Weak };
+    use std::time::Duration;
+    use io_uring::{ IoUring, opcode, squeue, types };
+    use mio::{ Interest, Token };
+
+    // Attempts to make room in the full submission queue
+    const PUSH_RETRIES: usize = 3;
+
+    // Portion of the file spliced to the socket at once, the default capacity of the pipe
+    const PIPE_SIZE: usize = 65536;
+
+    thread_local! {
+        // ring of the IO thread, set by its first poll
+        static CURRENT: RefCell<Option<Weak<Mutex<Ring>>>> = const { RefCell::new(None) };
+    }
+
+    pub fn attach(ring: &Arc<Mutex<Ring>>) {
+        CURRENT.with(|current| {
+            if current.borrow().is_none() {
+                *current.borrow_mut() = Some(Arc::downgrade(ring));
+            }
+        })
+    }
+
+    // None on the threads without the ring
+    pub fn current<R, F: FnOnce(&mut Ring) -> io::Result<R>>(f: F) -> Option<io::Result<R>> {
+        let ring = CURRENT.with(|current| current.borrow().as_ref().and_then(|ring| ring.upgrade()))?;
+        let mut ring = ring.lock().unwrap();
+        Some(f(&mut ring))
+    }
+
+    enum Op {
+        Poll(RawFd),
+        Accept(RawFd),
+        Waker,
+        Cancel,
+        // read, write or splice waited for by the IO thread
+        Sync
+    }
+
+    // File data spliced into the pipe of the socket and not yet taken by the socket
+    #[derive(Clone, Copy)]
+    struct Pipe {
+        read: RawFd,
+        write: RawFd,
+        file: RawFd,
+        // offset of the first byte in the pipe
+        offset: u64,
+        pending: usize
+    }
+
+    impl Pipe {
+        fn new() -> io::Result<Pipe> {
+            let mut fds = [0 as RawFd; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Pipe { read: fds[0], write: fds[1], file: -1, offset: 0, pending: 0 })
+        }
+
+        fn close(&self) {
+            unsafe {
+                libc::close(self.read);
+                libc::close(self.write);
+            }
+        }
+    }
+
+    fn result(result: i32) -> io::Result<usize> {
+        match result {
+            sz if sz >= 0 => Ok(sz as usize),
+            err => Err(io::Error::from_raw_os_error(-err))
+        }
+    }
+
+    struct Listener {
+        token: Token,
+        // accept in flight
+        pending: Option<u64>,
+        accepted: VecDeque<io::Result<RawFd>>
+    }
+
+    // Readiness of the client sockets is polled by one-shot POLL_ADD, listeners are served by ACCEPT.
+    // The IO thread reads and writes the ready sockets by RECV and SENDMSG and sends the files
+    // by SPLICE through the pipe of the socket, these operations don't wait for the peer and complete at once.
+    // Completions of the cancelled operations are recognized by the user data and skipped.
+    pub struct Ring {
+        ring: IoUring,
+        next: u64,
+        ops: HashMap<u64, Op>,
+        // fd -> (user data, token, interests) of the poll in flight
+        polls: HashMap<RawFd, (u64, Token, Interest)>,
+        listeners: HashMap<RawFd, Listener>,
+        waker: Option<(RawFd, Token)>,
+        // completions taken out of the poll, handled by the next one
+        reaped: Vec<(u64, i32)>,
+        pipes: HashMap<RawFd, Pipe>
+    }
+
+    fn mask(interests: Interest) -> u32 {
+        let mut mask = 0;
+        if interests.is_readable() {
+            mask |= libc::POLLIN | libc::POLLRDHUP;
+        }
+        if interests.is_writable() {
+            mask |= libc::POLLOUT;
+        }
+        mask as u32
+    }
+
+    impl Ring {
+        pub fn new(entries: usize) -> io::Result<Ring> {
+            let entries = entries.max(64).next_power_of_two().min(4096) as u32;
+            let ring = IoUring::new(entries)?;
+            if !ring.params().is_feature_ext_arg() {
+                return Err(io::Error::other("kernel doesn't support IORING_FEAT_EXT_ARG"));
+            }
+            Ok(Ring {
+                ring,
+                next: 0,
+                ops: HashMap::new(),
+                polls: HashMap::new(),
+                listeners: HashMap::new(),
+                waker: None,
+                reaped: Vec::new(),
+                pipes: HashMap::new()
+            })
+        }
+
+        fn reap(&mut self) {
+            self.reaped.extend(self.ring.completion().map(|cqe| (cqe.user_data(), cqe.result())));
+        }
+
+        // The full submission queue is submitted, the completion queue is reaped when the kernel has no room for the completions,
+        // the entry is refused when the queue is still full after that
+        fn push(&mut self, entry: squeue::Entry, op: Op) -> io::Result<u64> {
+            self.next += 1;
+            let user_data = self.next;
+            let entry = entry.user_data(user_data);
+            let mut retries = 0;
+            while unsafe { self.ring.submission().push(&entry) }.is_err() {
+                if retries == PUSH_RETRIES {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "io_uring: submission queue is full"));
+                }
+                retries += 1;
+                match self.ring.submit() {
+                    Ok(_) => {},
+                    Err(err) if err.raw_os_error() == Some(libc::EBUSY) => self.reap(),
+                    Err(err) => return Err(err)
+                }
+            }
+            self.ops.insert(user_data, op);
+            Ok(user_data)
+        }
+
+        fn cancel(&mut self, user_data: u64) -> io::Result<()> {
+            self.push(opcode::AsyncCancel::new(user_data).build(), Op::Cancel).map(|_| ())
+        }
+
+        // Submits the operation and waits for its completion, the other completions are left to the poll
+        fn execute(&mut self, entry: squeue::Entry) -> io::Result<usize> {
+            let user_data = self.push(entry, Op::Sync)?;
+            loop {
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => {},
+                    Err(err) if err.raw_os_error() == Some(libc::EINTR) || err.raw_os_error() == Some(libc::EBUSY) => {},
+                    Err(err) => {
+                        self.ops.remove(&user_data);
+                        return Err(err);
+                    }
+                }
+                let mut completed = None;
+                for cqe in self.ring.completion() {
+                    match cqe.user_data() == user_data {
+                        true => completed = Some(cqe.result()),
+                        false => self.reaped.push((cqe.user_data(), cqe.result()))
+                    }
+                }
+                if let Some(completed) = completed {
+                    self.ops.remove(&user_data);
+                    return result(completed);
+                }
+            }
+        }
+
+        pub fn recv(&mut self, fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            self.execute(opcode::Recv::new(types::Fd(fd), buf.as_mut_ptr(), len).flags(libc::MSG_DONTWAIT).build())
+        }
+
+        pub fn send(&mut self, fd: RawFd, bufs: &[IoSlice]) -> io::Result<usize> {
+            let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+            // IoSlice has the layout of iovec
+            msg.msg_iov = bufs.as_ptr() as *mut libc::iovec;
+            msg.msg_iovlen = bufs.len() as _;
+            let flags = (libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL) as u32;
+            self.execute(opcode::SendMsg::new(types::Fd(fd), &msg).flags(flags).build())
+        }
+
+        // File is spliced into the pipe of the socket and from the pipe to the socket,
+        // the rest the socket has not taken stays in the pipe for the next call with the same file and offset
+        pub fn sendfile(&mut self, socket: RawFd, file: RawFd, offset: u64, len: usize) -> io::Result<usize> {
+            let mut pipe = match self.pipes.get(&socket) {
+                Some(pipe) if pipe.pending == 0 || (pipe.file == file && pipe.offset == offset) => *pipe,
+                Some(pipe) => {
+                    // left by another body
+                    pipe.close();
+                    self.pipes.remove(&socket);
+                    Pipe::new()?
+                },
+                None => Pipe::new()?
+            };
+            self.pipes.insert(socket, pipe);
+            if pipe.pending == 0 {
+                let len = len.min(PIPE_SIZE) as u32;
+                let flags = libc::SPLICE_F_NONBLOCK;
+                let spliced = self.execute(opcode::Splice::new(types::Fd(file), offset as i64, types::Fd(pipe.write), -1, len).flags(flags).build())?;
+                if spliced == 0 {
+                    // end of the file
+                    return Ok(0);
+                }
+                pipe.file = file;
+                pipe.offset = offset;
+                pipe.pending = spliced;
+                self.pipes.insert(socket, pipe);
+            }
+            let flags = libc::SPLICE_F_NONBLOCK;
+            let sent = self.execute(opcode::Splice::new(types::Fd(pipe.read), -1, types::Fd(socket), -1, pipe.pending as u32).flags(flags).build())?;
+            pipe.offset += sent as u64;
+            pipe.pending -= sent;
+            self.pipes.insert(socket, pipe);
+            Ok(sent)
+        }
+
+        fn pipe_remove(&mut self, fd: RawFd) {
+            if let Some(pipe) = self.pipes.remove(&fd) {
+                pipe.close();
+            }
+        }
+
+        pub fn poll_add(&mut self, fd: RawFd, token: Token, interests: Interest) -> io::Result<()> {
+            match self.polls.get(&fd) {
+                Some((_, pending_token, pending)) if *pending_token == token && *pending == interests => return Ok(()),
+                Some((user_data, ..)) => {
+                    let user_data = *user_data;
+                    self.cancel(user_data)?;
+                },
+                None => {}
+            }
+            let user_data = self.push(opcode::PollAdd::new(types::Fd(fd), mask(interests)).build(), Op::Poll(fd))?;
+            self.polls.insert(fd, (user_data, token, interests));
+            Ok(())
+        }
+
+        // The poll in flight holds the socket open, it is cancelled before the socket is closed
+        pub fn poll_remove(&mut self, fd: RawFd) -> io::Result<()> {
+            self.pipe_remove(fd);
+            match self.polls.remove(&fd) {
+                Some((user_data, ..)) => self.cancel(user_data),
+                None => Ok(())
+            }
+        }
+
+        fn accept(&mut self, fd: RawFd) -> io::Result<u64> {
+            let accept = opcode::Accept::new(types::Fd(fd), std::ptr::null_mut(), std::ptr::null_mut())
+                .flags(libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC);
+            self.push(accept.build(), Op::Accept(fd))
+        }
+
+        pub fn accept_add(&mut self, fd: RawFd, token: Token) -> io::Result<()> {
+            let user_data = self.accept(fd)?;
+            self.listeners.insert(fd, Listener {
+                token,
+                pending: Some(user_data),
+                accepted: VecDeque::new()
+            });
+            Ok(())
+        }
+
+        // Submitted at once, the listener must be closed without waiting for the next poll
+        pub fn accept_remove(&mut self, fd: RawFd) -> io::Result<()> {
+            if let Some(listener) = self.listeners.remove(&fd) {
+                if let Some(user_data) = listener.pending {
+                    self.cancel(user_data)?;
+                }
+                listener.accepted.into_iter().flatten().for_each(|fd| { unsafe { libc::close(fd) }; });
+                self.ring.submit()?;
+            }
+            Ok(())
+        }
+
+        pub fn accepted(&mut self, fd: RawFd) -> io::Result<RawFd> {
+            match self.listeners.get_mut(&fd).and_then(|listener| listener.accepted.pop_front()) {
+                Some(Ok(accepted)) => {
+                    // descriptor of the closed socket may come again
+                    self.pipe_remove(accepted);
+                    Ok(accepted)
+                },
+                Some(Err(err)) => Err(err),
+                None => Err(io::Error::from(io::ErrorKind::WouldBlock))
+            }
+        }
+
+        pub fn waker_add(&mut self, fd: RawFd, token: Token) -> io::Result<()> {
+            self.waker = Some((fd, token));
+            self.push(opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as u32).build(), Op::Waker).map(|_| ())
+        }
+
+        pub fn poll(&mut self, tokens: &mut Vec<Token>, timeout: Option<Duration>) -> io::Result<()> {
+            let submitted = match timeout {
+                // completions reaped before are handled without waiting
+                _ if !self.reaped.is_empty() => self.ring.submit(),
+                Some(timeout) => {
+                    let timespec = types::Timespec::from(timeout);
+                    let args = types::SubmitArgs::new().timespec(&timespec);
+                    self.ring.submitter().submit_with_args(1, &args)
+                },
+                None => self.ring.submitter().submit_and_wait(1)
+            };
+            match submitted {
+                Ok(_) => {},
+                Err(err) if err.raw_os_error() == Some(libc::ETIME) => {},
+                // completion queue is overflown, it is reaped below
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {},
+                Err(err) => return Err(err)
+            }
+
+            let capacity = tokens.capacity().max(1);
+            let mut completed = std::mem::take(&mut self.reaped);
+            completed.extend(self.ring.completion().take(capacity).map(|cqe| (cqe.user_data(), cqe.result())));
+
+            for (user_data, result) in completed {
+                match self.ops.remove(&user_data) {
+                    Some(Op::Poll(fd)) => {
+                        // stale completion of the cancelled or renewed poll
+                        if self.polls.get(&fd).map(|(pending, ..)| *pending) != Some(user_data) {
+                            continue;
+                        }
+                        let (_, token, _) = self.polls.remove(&fd).unwrap();
+                        tokens.push(token);
+                    },
+                    Some(Op::Accept(fd)) => {
+                        let current = self.listeners.get(&fd).map(|listener| listener.pending == Some(user_data)).unwrap_or(false);
+                        if !current {
+                            if result >= 0 {
+                                unsafe { libc::close(result) };
+                            }
+                            continue;
+                        }
+                        let listener = self.listeners.get_mut(&fd).unwrap();
+                        listener.pending = None;
+                        let token = listener.token;
+                        match result {
+                            err if err == -libc::ECANCELED => continue,
+                            accepted if accepted >= 0 => listener.accepted.push_back(Ok(accepted)),
+                            // aborted connection, accept the next one
+                            err if -err == libc::ECONNABORTED || -err == libc::EINTR || -err == libc::EAGAIN => {},
+                            // reported by the accept, the listener is recreated
+                            err => {
+                                listener.accepted.push_back(Err(io::Error::from_raw_os_error(-err)));
+                                tokens.push(token);
+                                continue;
+                            }
+                        }
+                        match self.accept(fd) {
+                            Ok(user_data) => self.listeners.get_mut(&fd).unwrap().pending = Some(user_data),
+                            Err(err) => log_error!("error", "io_uring: failed to accept, {}", err)
+                        }
+                        tokens.push(token);
+                    },
+                    Some(Op::Waker) => {
+                        if let Some((fd, token)) = self.waker {
+                            let mut counter = [0u8; 8];
+                            unsafe { libc::read(fd, counter.as_mut_ptr() as *mut libc::c_void, counter.len()) };
+                            if let Err(err) = self.push(opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as u32).build(), Op::Waker) {
+                                log_error!("error", "io_uring: failed to renew the waker, {}", err);
+                            }
+                            tokens.push(token);
+                        }
+                    },
+                    Some(Op::Cancel) | Some(Op::Sync) | None => {}
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use std::io::{ Read, Write };
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        #[test]
+        fn io() {
+            let ring = match Ring::new(64) {
+                Ok(ring) => Arc::new(Mutex::new(ring)),
+                // kernel without io_uring
+                Err(_) => return
+            };
+            attach(&ring);
+            let (local, mut peer) = UnixStream::pair().unwrap();
+            local.set_nonblocking(true).unwrap();
+
+            let mut buf = [0u8; 16];
+            let err = current(|ring| ring.recv(local.as_raw_fd(), &mut buf)).unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+            peer.write_all(b"ping").unwrap();
+            assert_eq!(current(|ring| ring.recv(local.as_raw_fd(), &mut buf)).unwrap().unwrap(), 4);
+            assert_eq!(&buf[..4], b"ping");
+
+            let bufs = [IoSlice::new(b"po"), IoSlice::new(b"ng")];
+            assert_eq!(current(|ring| ring.send(local.as_raw_fd(), &bufs)).unwrap().unwrap(), 4);
+            peer.read_exact(&mut buf[..4]).unwrap();
+            assert_eq!(&buf[..4], b"pong");
+
+            let file = tempfile();
+            (&file).write_all(b"0123456789").unwrap();
+            assert_eq!(current(|ring| ring.sendfile(local.as_raw_fd(), file.as_raw_fd(), 2, 5)).unwrap().unwrap(), 5);
+            assert_eq!(current(|ring| ring.sendfile(local.as_raw_fd(), file.as_raw_fd(), 7, 5)).unwrap().unwrap(), 3);
+            // end of the file
+            assert_eq!(current(|ring| ring.sendfile(local.as_raw_fd(), file.as_raw_fd(), 10, 5)).unwrap().unwrap(), 0);
+            peer.read_exact(&mut buf[..8]).unwrap();
+            assert_eq!(&buf[..8], b"23456789");
+        }
+
+        fn tempfile() -> std::fs::File {
+            let path = std::env::temp_dir().join(format!("ws-uring-{}", std::process::id()));
+            let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            file
+        }
+    }
+}
@@ -0,0 +1,207 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crossbeam_queue::ArrayQueue;
+
+use crate::core::status::{ self, Json };
+
+// Larger strings are released instead of being kept in the free list
+const RECYCLE_STRING: usize = 4096;
+
+// Object is reset before it returns to the free list
+pub trait Recycle {
+    fn recycle(&mut self);
+}
+
+#[derive(Default)]
+pub struct Stats {
+    allocated: AtomicU64,
+    reused: AtomicU64,
+    recycled: AtomicU64,
+    dropped: AtomicU64
+}
+
+impl Stats {
+    fn report(&self) -> Json {
+        Json::object(vec![
+            ("allocated", self.allocated.load(Ordering::Relaxed).into()),
+            ("reused", self.reused.load(Ordering::Relaxed).into()),
+            ("recycled", self.recycled.load(Ordering::Relaxed).into()),
+            ("dropped", self.dropped.load(Ordering::Relaxed).into())
+        ])
+    }
+}
+
+lazy_static! {
+    static ref STATS: Mutex<HashMap<String, Arc<Stats>>> = Mutex::new(HashMap::new());
+}
+
+// Counters are shared by free lists with the same name in all workers
+fn stats(name: &str) -> Arc<Stats> {
+    let mut all = STATS.lock().unwrap();
+    match all.get(name) {
+        Some(stats) => Arc::clone(stats),
+        None => {
+            let stats = Arc::new(Stats::default());
+            all.insert(name.to_string(), Arc::clone(&stats));
+            let probe = Arc::clone(&stats);
+            status::register("pools", name, Box::new(move || Some(probe.report())));
+            stats
+        }
+    }
+}
+
+// Free list shared by the threads: the objects taken by the IO threads are released by the workers and vice versa,
+// so the free list of a thread would fill on one side and stay empty on the other
+pub struct FreeList<T: Recycle> {
+    free: ArrayQueue<T>,
+    stats: Arc<Stats>
+}
+
+impl<T: Recycle> FreeList<T> {
+    pub fn new(name: &str, capacity: usize) -> FreeList<T> {
+        FreeList {
+            free: ArrayQueue::new(capacity),
+            stats: stats(name)
+        }
+    }
+
+    pub fn take<F: FnOnce() -> T>(&self, alloc: F) -> T {
+        match self.free.pop() {
+            Some(item) => {
+                self.stats.reused.fetch_add(1, Ordering::Relaxed);
+                item
+            },
+            None => {
+                self.stats.allocated.fetch_add(1, Ordering::Relaxed);
+                alloc()
+            }
+        }
+    }
+
+    pub fn put(&self, mut item: T) {
+        if self.free.is_full() {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        item.recycle();
+        match self.free.push(item) {
+            Ok(_) => self.stats.recycled.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.stats.dropped.fetch_add(1, Ordering::Relaxed)
+        };
+    }
+
+    pub fn reused(&self) -> u64 {
+        self.stats.reused.load(Ordering::Relaxed)
+    }
+}
+
+impl<K, V> Recycle for HashMap<K, V> {
+    fn recycle(&mut self) {
+        self.clear();
+    }
+}
+
+impl Recycle for String {
+    fn recycle(&mut self) {
+        self.clear();
+        self.shrink_to(RECYCLE_STRING);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::alloc::{ GlobalAlloc, Layout, System };
+    use std::cell::Cell;
+    use std::net::{ TcpListener, TcpStream };
+    use std::sync::mpsc::channel;
+
+    use crate::client_context::ClientContext;
+    use crate::module::{ Request, Response };
+    use crate::http::{ HttpRequest, HttpResponse };
+    use crate::tcp_socket::TcpSocket;
+
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|n| n.get())
+    }
+
+    #[test]
+    fn shared() {
+        let list: FreeList<String> = FreeList::new("test_shared", 2);
+        let s = list.take(|| String::with_capacity(16));
+        let list = std::thread::spawn(move || {
+            list.put(s);
+            list
+        }).join().unwrap();
+        let s = list.take(String::new);
+        assert_eq!(list.reused(), 1);
+        assert_eq!(s.capacity(), 16);
+    }
+
+    #[test]
+    fn released_by_another_thread() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _peer = TcpStream::connect(addr).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = Some(ClientContext::new(TcpSocket::from(mio::net::TcpStream::from_std(stream)).unwrap(), addr));
+
+        // the responses are closed by a worker, as if the handler was offloaded
+        let (to_worker, worker) = channel::<HttpResponse>();
+        let (to_io, io) = channel::<ClientContext>();
+        let worker = std::thread::spawn(move || {
+            for resp in worker {
+                to_io.send(resp.close()).unwrap();
+            }
+        });
+
+        let mut cycle = || {
+            let before = allocations();
+            let mut resp = HttpResponse::new(HttpRequest::new(client.take().unwrap()));
+            resp.set_context("test", ());
+            let allocated = allocations() - before;
+            to_worker.send(resp).unwrap();
+            client = Some(io.recv().unwrap());
+            allocated
+        };
+
+        let cold = cycle();
+        for _ in 0 .. 4 {
+            cycle();
+        }
+        let warm = cycle();
+
+        assert!(cold > 0);
+        assert_eq!(warm, 0, "cold cycle allocated {} times", cold);
+
+        drop(to_worker);
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Arc, Mutex, RwLock };
+
+use crate::core::status::{ self, Json };
+
+// Lines preallocated at once, the large ring grows as it is filled
+const PREALLOCATED_MAX: usize = 1024;
+
+// In-memory sink keeping the last lines of the log, lines are numbered
+// so the reader is able to continue from the last seen one.
+pub struct Ring {
+    lines: VecDeque<String>,
+    capacity: usize,
+    // number of the next line
+    next: u64
+}
+
+impl Ring {
+    // Ring keeps one line at least
+    fn new(capacity: usize) -> Ring {
+        let capacity = std::cmp::max(capacity, 1);
+        Ring {
+            lines: VecDeque::with_capacity(std::cmp::min(capacity, PREALLOCATED_MAX)),
+            capacity,
+            next: 0
+        }
+    }
+
+    pub fn push(&mut self, line: String) {
+        if self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+        self.next += 1;
+    }
+
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+
+    // Last n lines
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        self.lines.iter().skip(self.lines.len().saturating_sub(n)).cloned().collect()
+    }
+
+    // Lines starting from the number 'from', lines already evicted are skipped
+    pub fn since(&self, from: u64) -> Vec<String> {
+        let first = self.next - self.lines.len() as u64;
+        self.lines.iter().skip(from.saturating_sub(first) as usize).cloned().collect()
+    }
+}
+
+lazy_static! {
+    static ref RINGS: RwLock<HashMap<String, Arc<Mutex<Ring>>>> = RwLock::new(HashMap::new());
+}
+
+pub fn create(name: &str, capacity: usize) {
+    RINGS.write().unwrap().entry(name.to_string()).or_insert_with(|| Arc::new(Mutex::new(Ring::new(capacity))));
+    let name_ = name.to_string();
+    status::register("rings", name, Box::new(move || {
+        get(&name_).map(|ring| {
+            let ring = ring.lock().unwrap();
+            Json::object(vec![
+                ("capacity", ring.capacity.into()),
+                ("lines", ring.lines.len().into()),
+                ("next", ring.next.into())
+            ])
+        })
+    }));
+}
+
+pub fn get(name: &str) -> Option<Arc<Mutex<Ring>>> {
+    RINGS.read().unwrap().get(name).cloned()
+}
+
+pub fn push(name: &str, line: String) {
+    if let Some(ring) = get(name) {
+        ring.lock().unwrap().push(line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fill(ring: &mut Ring, n: usize) {
+        for i in 0..n {
+            ring.push(i.to_string());
+        }
+    }
+
+    #[test]
+    fn evicted() {
+        let mut ring = Ring::new(3);
+        fill(&mut ring, 3);
+        assert_eq!(ring.tail(10), vec!["0", "1", "2"]);
+        // the oldest line goes first
+        ring.push("3".to_string());
+        assert_eq!(ring.tail(10), vec!["1", "2", "3"]);
+        assert_eq!(ring.tail(2), vec!["2", "3"]);
+        assert_eq!(ring.next(), 4);
+        assert_eq!(ring.since(0), vec!["1", "2", "3"]);
+        assert_eq!(ring.since(3), vec!["3"]);
+        assert!(ring.since(4).is_empty());
+    }
+
+    #[test]
+    fn boundaries() {
+        // zero size keeps one line
+        let mut ring = Ring::new(0);
+        fill(&mut ring, 5);
+        assert_eq!(ring.tail(10), vec!["4"]);
+
+        let mut ring = Ring::new(1);
+        fill(&mut ring, 2);
+        assert_eq!(ring.tail(10), vec!["1"]);
+        assert_eq!(ring.since(1), vec!["1"]);
+
+        // large ring is not preallocated
+        let mut ring = Ring::new(1 << 30);
+        assert!(ring.lines.capacity() < 1 << 20);
+        fill(&mut ring, PREALLOCATED_MAX + 1);
+        assert_eq!(ring.tail(1), vec![PREALLOCATED_MAX.to_string()]);
+        assert_eq!(ring.lines.len(), PREALLOCATED_MAX + 1);
+    }
+}
@@ -4,10 +4,10 @@
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{ Arc, RwLock };
+use std::sync::{ Arc, RwLock, atomic::AtomicUsize };
 
 use crate::error::{ Code::*, CoreResult, CoreError };
-use crate::core::{ Options, io::IO };
+use crate::core::{ Options, io::IO, affinity::Affinity, poller::Engine, accept::Accept };
 use crate::module::{ ModuleType, Request };
 use crate::handler::sync::Handler;
 
@@ -20,6 +20,9 @@ impl<T: ModuleType> Server<T> {
     pub fn new(
         worker_pool_size: usize,
         socket_poll_size: usize,
+        affinity: Affinity,
+        engine: Engine,
+        accept: Accept,
         default_handler: Handler<T::Request, T::Response>
     )
         -> Result<Server<T>, CoreError>
@@ -30,6 +33,9 @@ impl<T: ModuleType> Server<T> {
         match IO::new::<T, _>(
             worker_pool_size,
             socket_poll_size,
+            affinity,
+            engine,
+            accept,
             move |r: T::Request| -> T::Response {
                 Server::<T>::handler(&handlers.read().unwrap(), &default_handler, r)
             }
@@ -80,6 +86,14 @@ impl<T: ModuleType> Server<T> {
         self.handlers.write().unwrap().remove(&addr);
     }
 
+    pub fn queue(&self) -> Arc<AtomicUsize> {
+        self.io.queue()
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.io.engine()
+    }
+
     pub fn stop(&mut self) {
         self.io.stop();
     }
@@ -0,0 +1,323 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::cmp::min;
+use std::collections::{ HashMap, VecDeque };
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpStream, ToSocketAddrs };
+use std::sync::{ Arc, Condvar, Mutex, MutexGuard };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::core::status::{ self, Json };
+use crate::error::CoreError;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Protocol {
+    // newline delimited records posted to the collector
+    Http,
+    // records posted to the topic of the Kafka REST proxy
+    Kafka
+}
+
+struct Queue {
+    records: VecDeque<Vec<u8>>,
+    // queued and being shipped
+    size: usize,
+    // process of the shipping thread, the forked worker starts its own
+    pid: libc::pid_t
+}
+
+// Records are shipped by the thread of the target in batches, once batch_size records are queued
+// or flush_interval is expired. The failed batch is retried with the backoff, the records over
+// max_buffer are dropped, so the slow collector never blocks the workers.
+pub struct Shipper {
+    target: String,
+    protocol: Protocol,
+    addr: SocketAddr,
+    host: String,
+    // path of the collector or the topic
+    path: String,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_buffer: usize,
+    retries: usize,
+    timeout: Duration,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    shipped: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64
+}
+
+// Size with optional 'k' or 'm' suffix
+fn parse_size(s: &str) -> Option<usize> {
+    let s = s.to_ascii_lowercase();
+    match s.chars().last() {
+        Some('k') => s[..s.len() - 1].parse::<usize>().ok().map(|n| n * 1024),
+        Some('m') => s[..s.len() - 1].parse::<usize>().ok().map(|n| n * 1024 * 1024),
+        _ => s.parse::<usize>().ok()
+    }
+}
+
+impl Shipper {
+    // http://collector:8080/logs?batch_size=500 or kafka://rest-proxy:8082/topic?batch_size=500,
+    // the parameters of the shipper are not sent to the collector
+    fn new(target: &str) -> Result<Shipper, CoreError> {
+        let (protocol, url) = match target.find("://") {
+            Some(pos) if &target[..pos] == "http" => (Protocol::Http, &target[pos + 3..]),
+            Some(pos) if &target[..pos] == "kafka" => (Protocol::Kafka, &target[pos + 3..]),
+            _ => return throw!("shipper: 'http://' or 'kafka://' expected, found '{}'", target)
+        };
+        let (host, path) = match url.find('/') {
+            Some(pos) => (&url[..pos], &url[pos..]),
+            None => (url, "/")
+        };
+        let (path, query) = match path.find('?') {
+            Some(pos) => (&path[..pos], &path[pos + 1..]),
+            None => (path, "")
+        };
+        let default_port = match protocol {
+            Protocol::Http => 80,
+            Protocol::Kafka => 8082
+        };
+        let authority = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:{}", host, default_port)
+        };
+        let addr = match authority.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+            Some(addr) => addr,
+            None => return throw!("shipper: failed to resolve '{}'", authority)
+        };
+        let mut shipper = Shipper {
+            target: target.to_string(),
+            protocol,
+            addr,
+            host: host.to_string(),
+            path: path.to_string(),
+            batch_size: 500,
+            flush_interval: Duration::from_secs(1),
+            max_buffer: 8 * 1024 * 1024,
+            retries: 3,
+            timeout: Duration::from_secs(5),
+            queue: Mutex::new(Queue {
+                records: VecDeque::new(),
+                size: 0,
+                pid: 0
+            }),
+            ready: Condvar::new(),
+            shipped: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            failed: AtomicU64::new(0)
+        };
+        let mut args = Vec::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            let (name, value) = (kv.next().unwrap_or_default(), kv.next().unwrap_or_default());
+            let number = value.parse::<u64>().ok();
+            match (name, number) {
+                ("batch_size", Some(n)) if n > 0 => shipper.batch_size = n as usize,
+                ("flush_interval", Some(ms)) if ms > 0 => shipper.flush_interval = Duration::from_millis(ms),
+                ("retries", Some(n)) => shipper.retries = n as usize,
+                ("timeout", Some(ms)) if ms > 0 => shipper.timeout = Duration::from_millis(ms),
+                ("max_buffer", _) => shipper.max_buffer = match parse_size(value) {
+                    Some(size) => size,
+                    None => return throw!("shipper: invalid max_buffer '{}'", value)
+                },
+                ("batch_size", _) | ("flush_interval", _) | ("retries", _) | ("timeout", _) =>
+                    return throw!("shipper: invalid parameter '{}'", param),
+                _ => args.push(param)
+            }
+        }
+        match protocol {
+            Protocol::Http if !args.is_empty() => shipper.path = format!("{}?{}", shipper.path, args.join("&")),
+            Protocol::Kafka => {
+                let topic = shipper.path.trim_matches('/');
+                if topic.is_empty() || topic.contains('/') {
+                    return throw!("shipper: topic expected, found '{}'", shipper.path);
+                }
+                shipper.path = format!("/topics/{}", topic);
+            },
+            _ => {}
+        }
+        Ok(shipper)
+    }
+
+    // Record is dropped when the buffer is full
+    pub fn push(self: &Arc<Self>, record: &[u8]) {
+        let mut queue = self.queue.lock().unwrap();
+        let pid = unsafe { libc::getpid() };
+        if queue.pid != pid {
+            queue.pid = pid;
+            let shipper = Arc::clone(self);
+            thread::spawn(move || shipper.run());
+        }
+        if queue.size + record.len() > self.max_buffer {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.size += record.len();
+        queue.records.push_back(record.to_vec());
+        if queue.records.len() >= self.batch_size {
+            self.ready.notify_one();
+        }
+    }
+
+    fn take_batch(&self, queue: &mut MutexGuard<Queue>) -> Vec<Vec<u8>> {
+        let n = min(queue.records.len(), self.batch_size);
+        queue.records.drain(..n).collect()
+    }
+
+    fn run(&self) {
+        loop {
+            let batch = {
+                let mut queue = self.queue.lock().unwrap();
+                let deadline = Instant::now() + self.flush_interval;
+                while queue.records.len() < self.batch_size {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    queue = self.ready.wait_timeout(queue, deadline - now).unwrap().0;
+                }
+                self.take_batch(&mut queue)
+            };
+            if !batch.is_empty() {
+                self.ship(batch);
+            }
+        }
+    }
+
+    // Records of the batch leave the buffer once shipped or given up
+    fn ship(&self, batch: Vec<Vec<u8>>) {
+        let size: usize = batch.iter().map(|record| record.len()).sum();
+        let (content_type, body) = self.encode(&batch);
+        let mut backoff = Duration::from_millis(100);
+        for attempt in 0..=self.retries {
+            match self.post(content_type, &body) {
+                Ok(()) => {
+                    self.shipped.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    break;
+                },
+                Err(err) if attempt == self.retries => {
+                    self.failed.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    log_error!("error", "shipper '{}': {} records are lost, {}", self.target, batch.len(), err);
+                },
+                Err(_) => {
+                    thread::sleep(backoff);
+                    backoff = min(backoff * 2, MAX_BACKOFF);
+                }
+            }
+        }
+        self.queue.lock().unwrap().size -= size;
+    }
+
+    // JSON records are passed as is, the others are strings
+    fn encode(&self, batch: &[Vec<u8>]) -> (&'static str, Vec<u8>) {
+        let json = batch.iter().all(|record| record.starts_with(b"{") && record.ends_with(b"}"));
+        match self.protocol {
+            Protocol::Http => {
+                let mut body = Vec::with_capacity(batch.iter().map(|record| record.len() + 1).sum());
+                for record in batch {
+                    body.extend_from_slice(record);
+                    body.push(b'\n');
+                }
+                (if json { "application/x-ndjson" } else { "text/plain" }, body)
+            },
+            Protocol::Kafka => {
+                let mut body = b"{\"records\":[".to_vec();
+                for (i, record) in batch.iter().enumerate() {
+                    if i != 0 {
+                        body.push(b',');
+                    }
+                    body.extend_from_slice(b"{\"value\":");
+                    match record.starts_with(b"{") && record.ends_with(b"}") {
+                        true => body.extend_from_slice(record),
+                        false => body.extend_from_slice(Json::Str(String::from_utf8_lossy(record).into_owned()).to_string().as_bytes())
+                    }
+                    body.push(b'}');
+                }
+                body.extend_from_slice(b"]}");
+                ("application/vnd.kafka.json.v2+json", body)
+            }
+        }
+    }
+
+    // HTTP/1.0 request, any 2xx is success
+    fn post(&self, content_type: &str, body: &[u8]) -> Result<(), String> {
+        let mut stream = TcpStream::connect_timeout(&self.addr, self.timeout).map_err(|err| err.to_string())?;
+        stream.set_read_timeout(Some(self.timeout)).map_err(|err| err.to_string())?;
+        stream.set_write_timeout(Some(self.timeout)).map_err(|err| err.to_string())?;
+        let head = format!("POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                           self.path, self.host, content_type, body.len());
+        stream.write_all(head.as_bytes()).map_err(|err| err.to_string())?;
+        stream.write_all(body).map_err(|err| err.to_string())?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).map_err(|err| err.to_string())?;
+        let line = response.split(|c| *c == b'\n').next().unwrap_or_default();
+        let status = String::from_utf8_lossy(line).split_whitespace().nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or("invalid response")?;
+        match status {
+            200..=299 => Ok(()),
+            _ => Err(format!("status {}", status))
+        }
+    }
+
+    // Rest of the records is shipped by the caller, e.g. at exit
+    fn flush(&self) {
+        loop {
+            let batch = self.take_batch(&mut self.queue.lock().unwrap());
+            if batch.is_empty() {
+                return;
+            }
+            self.ship(batch);
+        }
+    }
+
+    fn report(&self) -> Json {
+        let queue = self.queue.lock().unwrap();
+        Json::object(vec![
+            ("queued", queue.records.len().into()),
+            ("buffered", queue.size.into()),
+            ("max_buffer", self.max_buffer.into()),
+            ("shipped", self.shipped.load(Ordering::Relaxed).into()),
+            ("dropped", self.dropped.load(Ordering::Relaxed).into()),
+            ("failed", self.failed.load(Ordering::Relaxed).into())
+        ])
+    }
+}
+
+lazy_static! {
+    static ref SHIPPERS: Mutex<HashMap<String, Arc<Shipper>>> = Mutex::new(HashMap::new());
+}
+
+// Targets of the same url share the shipper
+pub fn open(target: &str) -> Result<Arc<Shipper>, CoreError> {
+    let mut shippers = SHIPPERS.lock().unwrap();
+    if let Some(shipper) = shippers.get(target) {
+        return Ok(Arc::clone(shipper));
+    }
+    let shipper = Arc::new(Shipper::new(target)?);
+    shippers.insert(target.to_string(), Arc::clone(&shipper));
+    let target_ = target.to_string();
+    status::register("shippers", target, Box::new(move || {
+        SHIPPERS.lock().unwrap().get(&target_).map(|shipper| shipper.report())
+    }));
+    Ok(shipper)
+}
+
+pub fn is_shipper(target: &str) -> bool {
+    target.starts_with("http://") || target.starts_with("kafka://")
+}
+
+pub fn flush_all() {
+    let shippers: Vec<Arc<Shipper>> = SHIPPERS.lock().unwrap().values().cloned().collect();
+    for shipper in shippers {
+        shipper.flush();
+    }
+}
@@ -0,0 +1,113 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::sync::{ Condvar, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::Duration;
+
+use crate::core::{ master, upgrade };
+
+pub type Hook = Box<dyn Fn() + Send + Sync>;
+
+// New binary must take over the listeners in this time on reload
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SIGNALS: [libc::c_int; 4] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1];
+
+lazy_static! {
+    static ref SHUTDOWN: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+    static ref REOPEN: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+}
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+fn sigset() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        SIGNALS.iter().for_each(|signal| { libc::sigaddset(&mut set, *signal); });
+        set
+    }
+}
+
+// Threads started later inherit the mask, so the signals are delivered only to sigwait
+pub fn block() {
+    let set = sigset();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+}
+
+// SIGTERM and SIGINT stop gracefully, the second one exits immediately,
+// SIGHUP reloads by starting the binary on the same listeners, SIGUSR1 reopens the logs
+pub fn start() {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    block();
+    thread::spawn(|| {
+        let set = sigset();
+        loop {
+            let mut signal: libc::c_int = 0;
+            if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+                continue;
+            }
+            match signal {
+                libc::SIGTERM | libc::SIGINT => {
+                    if shutdown_requested() {
+                        log_error!("warn", "signal {}: exit", signal);
+                        std::process::exit(1);
+                    }
+                    log_error!("info", "signal {}: graceful shutdown", signal);
+                    request_shutdown();
+                },
+                // workers are reloaded by the master
+                libc::SIGHUP if master::is_worker() => {
+                    log_error!("info", "signal {}: passed to the master", signal);
+                    unsafe { libc::kill(libc::getppid(), libc::SIGHUP) };
+                },
+                libc::SIGHUP => {
+                    log_error!("info", "signal {}: reload", signal);
+                    thread::spawn(|| {
+                        if let Err(err) = upgrade::upgrade(None, RELOAD_TIMEOUT) {
+                            log_error!("error", "reload failed, {}", err.what());
+                        }
+                    });
+                },
+                libc::SIGUSR1 => {
+                    log_error!("info", "signal {}: reopen logs", signal);
+                    reopen();
+                },
+                _ => {}
+            }
+        }
+    });
+}
+
+// Called on SIGUSR1, e.g. after the logs have been rotated
+pub fn on_reopen(hook: Hook) {
+    REOPEN.lock().unwrap().push(hook);
+}
+
+pub fn reopen() {
+    REOPEN.lock().unwrap().iter().for_each(|hook| hook());
+}
+
+pub fn request_shutdown() {
+    let (requested, cond) = &*SHUTDOWN;
+    *requested.lock().unwrap() = true;
+    cond.notify_all();
+}
+
+pub fn shutdown_requested() -> bool {
+    *SHUTDOWN.0.lock().unwrap()
+}
+
+// Blocks until the shutdown is requested
+pub fn wait_shutdown() {
+    let (requested, cond) = &*SHUTDOWN;
+    let mut requested = requested.lock().unwrap();
+    while !*requested {
+        requested = cond.wait(requested).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, prelude::* };
+use std::net::{ SocketAddr, ToSocketAddrs, UdpSocket };
+use std::sync::Arc;
+
+use chrono::Local;
+
+use crate::core::shipper::{ self, Shipper };
+use crate::error::CoreError;
+
+// Syslog severities
+pub const ERROR: u8 = 3;
+pub const WARN: u8 = 4;
+pub const INFO: u8 = 6;
+pub const DEBUG: u8 = 7;
+
+const FACILITIES: [(&str, u8); 20] = [
+    ("kern", 0), ("user", 1), ("mail", 2), ("daemon", 3), ("auth", 4), ("syslog", 5),
+    ("lpr", 6), ("news", 7), ("uucp", 8), ("cron", 9), ("authpriv", 10), ("ftp", 11),
+    ("local0", 16), ("local1", 17), ("local2", 18), ("local3", 19),
+    ("local4", 20), ("local5", 21), ("local6", 22), ("local7", 23)
+];
+
+pub struct Syslog {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    facility: u8,
+    tag: String,
+    hostname: String
+}
+
+// Log target: a file, stdout, stderr, a remote syslog or the collector the records are shipped to
+pub enum Sink {
+    File(File),
+    Stdout,
+    Stderr,
+    Syslog(Syslog),
+    Shipper(Arc<Shipper>)
+}
+
+impl Syslog {
+    // syslog://host:514?facility=local7&tag=web_server
+    fn open(url: &str) -> Result<Syslog, CoreError> {
+        let (host, query) = match url.find('?') {
+            Some(pos) => (&url[..pos], &url[pos + 1..]),
+            None => (url, "")
+        };
+        let host = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:514", host)
+        };
+        let addr = match host.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+            Some(addr) => addr,
+            None => return throw!("syslog: failed to resolve '{}'", host)
+        };
+        let mut facility = 1;
+        let mut tag = "web_server".to_string();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("facility"), Some(name)) => facility = match FACILITIES.iter().find(|(n, _)| *n == name) {
+                    Some((_, code)) => *code,
+                    None => return throw!("syslog: unknown facility '{}'", name)
+                },
+                (Some("tag"), Some(value)) => tag = value.to_string(),
+                _ => return throw!("syslog: invalid parameter '{}'", param)
+            }
+        }
+        let bind = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0"
+        };
+        let socket = match UdpSocket::bind(bind) {
+            Ok(socket) => socket,
+            Err(err) => return throw!("syslog: {}", err)
+        };
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_else(|_| "localhost".to_string());
+        Ok(Syslog {
+            socket,
+            addr,
+            facility,
+            tag,
+            hostname
+        })
+    }
+
+    // RFC 3164, one datagram per line
+    fn send(&self, severity: u8, line: &[u8]) -> io::Result<()> {
+        let mut packet = format!("<{}>{} {} {}: ", self.facility * 8 + severity,
+            Local::now().format("%b %e %H:%M:%S"), self.hostname, self.tag).into_bytes();
+        packet.extend_from_slice(line);
+        self.socket.send_to(&packet, self.addr).map(|_| ())
+    }
+}
+
+impl Sink {
+    pub fn open(target: &str) -> Result<Sink, CoreError> {
+        match target {
+            "stdout" => Ok(Sink::Stdout),
+            "stderr" => Ok(Sink::Stderr),
+            _ if target.starts_with("syslog://") => Ok(Sink::Syslog(Syslog::open(&target[9..])?)),
+            _ if shipper::is_shipper(target) => Ok(Sink::Shipper(shipper::open(target)?)),
+            _ => match OpenOptions::new().append(true).create(true).open(target) {
+                Ok(file) => Ok(Sink::File(file)),
+                Err(err) => throw!("Failed to open '{}': {}", target, err)
+            }
+        }
+    }
+
+    // Rotated file is opened again by its name, other targets are kept
+    pub fn reopen(&mut self, target: &str) -> Result<(), CoreError> {
+        if let Sink::File(_) = self {
+            *self = Sink::open(target)?;
+        }
+        Ok(())
+    }
+
+    // Only files are worth buffering, other targets take line by line
+    pub fn buffered(&self) -> bool {
+        matches!(self, Sink::File(_))
+    }
+
+    // Bytes are one or more lines terminated with '\n'
+    pub fn write(&mut self, severity: u8, text: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::File(file) => file.write_all(text),
+            Sink::Stdout => io::stdout().lock().write_all(text),
+            Sink::Stderr => io::stderr().lock().write_all(text),
+            Sink::Syslog(syslog) => {
+                for line in text.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+                    syslog.send(severity, line)?;
+                }
+                Ok(())
+            },
+            Sink::Shipper(shipper) => {
+                for line in text.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+                    shipper.push(line);
+                }
+                Ok(())
+            }
+        }
+    }
+}
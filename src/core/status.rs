@@ -0,0 +1,151 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::RwLock;
+
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+}
+
+impl From<bool> for Json {
+    fn from(v: bool) -> Json {
+        Json::Bool(v)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(v: usize) -> Json {
+        Json::Int(v as i64)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(v: u64) -> Json {
+        Json::Int(v as i64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(v: &str) -> Json {
+        Json::Str(v.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(v: String) -> Json {
+        Json::Str(v)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(v: Option<T>) -> Json {
+        v.map(|v| v.into()).unwrap_or(Json::Null)
+    }
+}
+
+fn escape(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(v) => write!(f, "{}", v),
+            Json::Int(v) => write!(f, "{}", v),
+            Json::Str(s) => escape(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    escape(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// Probe returns None when the object is gone, such probe is removed
+pub type Probe = Box<dyn Fn() -> Option<Json> + Send + Sync>;
+
+lazy_static! {
+    static ref SECTIONS: RwLock<BTreeMap<String, Vec<(String, Probe)>>> = RwLock::new(BTreeMap::new());
+}
+
+// Probes are grouped by sections (servers, workgroups, upstreams, caches, plugins),
+// probe with the same name replaces the previous one.
+pub fn register(section: &str, name: &str, probe: Probe) {
+    let mut sections = SECTIONS.write().unwrap();
+    let probes = sections.entry(section.to_string()).or_default();
+    match probes.iter_mut().find(|(n, _)| n == name) {
+        Some(entry) => entry.1 = probe,
+        None => probes.push((name.to_string(), probe))
+    }
+}
+
+pub fn report() -> Json {
+    let mut gone = Vec::new();
+    let mut report = Vec::new();
+    {
+        let sections = SECTIONS.read().unwrap();
+        for (section, probes) in sections.iter() {
+            let mut items = Vec::new();
+            for (name, probe) in probes.iter() {
+                match probe() {
+                    Some(json) => items.push((name.clone(), json)),
+                    None => gone.push((section.clone(), name.clone()))
+                }
+            }
+            report.push((section.clone(), Json::Object(items)));
+        }
+    }
+    if !gone.is_empty() {
+        let mut sections = SECTIONS.write().unwrap();
+        for (section, name) in gone {
+            if let Some(probes) = sections.get_mut(&section) {
+                probes.retain(|(n, _)| *n != name);
+            }
+        }
+    }
+    Json::Object(report)
+}
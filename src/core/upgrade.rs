@@ -0,0 +1,196 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::env;
+use std::net::{ SocketAddr, TcpListener };
+use std::os::unix::io::{ FromRawFd, RawFd };
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
+
+use crate::core::{ master, signals };
+use crate::error::CoreError;
+
+// Listening sockets passed to the new binary, 'addr=fd,addr=fd'
+const LISTEN_FDS: &str = "WS_LISTEN_FDS";
+// Pipe the new binary writes to when it has started to accept
+pub (crate) const READY_FD: &str = "WS_READY_FD";
+
+fn parse_inherited() -> Vec<(SocketAddr, RawFd)> {
+    let fds = match env::var(LISTEN_FDS) {
+        Ok(fds) => fds,
+        Err(_) => return vec![]
+    };
+    // not passed further to the processes started by this one
+    env::remove_var(LISTEN_FDS);
+    fds.split(',').filter_map(|item| {
+        let mut kv = item.splitn(2, '=');
+        match (kv.next().map(|addr| addr.parse()), kv.next().map(|fd| fd.parse())) {
+            (Some(Ok(addr)), Some(Ok(fd))) => Some((addr, fd)),
+            _ => {
+                log_error!("warn", "upgrade: invalid inherited listener '{}'", item);
+                None
+            }
+        }
+    }).collect()
+}
+
+lazy_static! {
+    // Listeners of all event pools, several ones per address with SO_REUSEPORT
+    static ref LISTENERS: Mutex<Vec<(SocketAddr, RawFd)>> = Mutex::new(Vec::new());
+    static ref INHERITED: Mutex<Vec<(SocketAddr, RawFd)>> = Mutex::new(parse_inherited());
+}
+
+static UPGRADING: AtomicBool = AtomicBool::new(false);
+// all modules of this binary are active
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+pub (crate) fn track(addr: SocketAddr, fd: RawFd) {
+    LISTENERS.lock().unwrap().push((addr, fd));
+}
+
+pub (crate) fn untrack(fd: RawFd) {
+    LISTENERS.lock().unwrap().retain(|(_, listener)| *listener != fd);
+}
+
+// Listener of the previous binary, keeps the connections waiting in its accept queue
+pub (crate) fn inherited(addr: SocketAddr) -> Option<TcpListener> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let pos = inherited.iter().position(|(inherited, _)| *inherited == addr)?;
+    let (_, fd) = inherited.remove(pos);
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    match listener.local_addr() {
+        Ok(local) if local == addr => Some(listener),
+        _ => {
+            log_error!("warn", "upgrade: inherited fd {} is not a listener of {}", fd, addr);
+            None
+        }
+    }
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags >= 0 {
+            libc::fcntl(fd, libc::F_SETFD, match cloexec {
+                true => flags | libc::FD_CLOEXEC,
+                false => flags & !libc::FD_CLOEXEC
+            });
+        }
+    }
+}
+
+// Called by the new binary when all modules are active: the inherited listeners
+// left unclaimed are closed and the previous binary is told to stop accepting
+pub fn ready() {
+    STARTED.store(true, Ordering::SeqCst);
+    for (addr, fd) in INHERITED.lock().unwrap().drain(..) {
+        log_error!("info", "upgrade: inherited listener {} is not used", addr);
+        unsafe { libc::close(fd) };
+    }
+    let fd = match env::var(READY_FD).ok().and_then(|fd| fd.parse::<RawFd>().ok()) {
+        Some(fd) => fd,
+        None => return
+    };
+    env::remove_var(READY_FD);
+    unsafe {
+        libc::write(fd, b"1".as_ptr() as *const libc::c_void, 1);
+        libc::close(fd);
+    }
+}
+
+pub fn started() -> bool {
+    STARTED.load(Ordering::SeqCst)
+}
+
+// New binary is being started on the listeners of this one
+pub fn upgrading() -> bool {
+    UPGRADING.load(Ordering::SeqCst)
+}
+
+// Current binary unless it has been replaced on disk
+fn current_binary() -> Result<String, CoreError> {
+    match env::current_exe() {
+        Ok(path) => Ok(path.to_string_lossy().trim_end_matches(" (deleted)").to_string()),
+        Err(err) => throw!("upgrade: {}", err)
+    }
+}
+
+// Starts the binary with the same arguments and the listeners of this process,
+// on its readiness in 'timeout' the shutdown of this process is requested
+pub fn upgrade(binary: Option<&str>, timeout: Duration) -> Result<u32, CoreError> {
+    if master::is_worker() {
+        return throw!("upgrade: not supported by worker processes, reload the master with SIGHUP");
+    }
+    if UPGRADING.swap(true, Ordering::SeqCst) {
+        return throw!("upgrade: already in progress");
+    }
+    let result = spawn(binary, timeout);
+    UPGRADING.store(false, Ordering::SeqCst);
+    let pid = result?;
+    log_error!("info", "upgrade: new binary pid={} is ready, shutting down", pid);
+    signals::request_shutdown();
+    Ok(pid)
+}
+
+fn spawn(binary: Option<&str>, timeout: Duration) -> Result<u32, CoreError> {
+    let binary = match binary {
+        Some(binary) => binary.to_string(),
+        None => current_binary()?
+    };
+    let listeners = LISTENERS.lock().unwrap().clone();
+    if listeners.is_empty() {
+        return throw!("upgrade: no listeners");
+    }
+
+    let mut pipe = [0 as RawFd; 2];
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return throw!("upgrade: pipe, {}", std::io::Error::last_os_error());
+    }
+    let (read_fd, write_fd) = (pipe[0], pipe[1]);
+
+    // inherited over exec only for the time of spawn
+    listeners.iter().for_each(|(_, fd)| set_cloexec(*fd, false));
+    set_cloexec(write_fd, false);
+
+    let fds = listeners.iter().map(|(addr, fd)| format!("{}={}", addr, fd)).collect::<Vec<String>>().join(",");
+    let child = Command::new(&binary)
+        .args(env::args().skip(1))
+        .env(LISTEN_FDS, fds)
+        .env(READY_FD, write_fd.to_string())
+        .spawn();
+
+    listeners.iter().for_each(|(_, fd)| set_cloexec(*fd, true));
+    unsafe { libc::close(write_fd) };
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            unsafe { libc::close(read_fd) };
+            return throw!("upgrade: failed to start '{}', {}", binary, err);
+        }
+    };
+
+    let mut pfd = libc::pollfd {
+        fd: read_fd,
+        events: libc::POLLIN,
+        revents: 0
+    };
+    let mut byte = [0u8; 1];
+    let ready = unsafe {
+        libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) == 1 &&
+            libc::read(read_fd, byte.as_mut_ptr() as *mut libc::c_void, 1) == 1
+    };
+    unsafe { libc::close(read_fd) };
+
+    if !ready {
+        // closed pipe means the new binary has failed to start
+        let _ = child.kill();
+        let _ = child.wait();
+        return throw!("upgrade: '{}' has not become ready in {}ms", binary, timeout.as_millis());
+    }
+
+    Ok(child.id())
+}
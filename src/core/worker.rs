@@ -3,11 +3,12 @@
  */
 
 use std::{ thread, thread::JoinHandle };
-use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
 use std::sync::{ mpsc, mpsc::Sender, Arc, Mutex };
 use std::time::Duration;
 
 use crate::module::*;
+use crate::core::affinity;
 use crate::error::{ Code::*, CoreResult };
 
 struct Worker {
@@ -20,6 +21,8 @@ where
     F: Fn(T::Request) + Clone + Sync + Send
 {
     tx: Sender<T::Request>,
+    // number of posted requests not picked up by workers yet
+    queue: Arc<AtomicUsize>,
     workers: Vec<Worker>,
     handler: Option<F>
 }
@@ -27,6 +30,8 @@ where
 impl Worker {
     pub fn new<F: 'static, T: 'static>(
         rx: Arc<Mutex<mpsc::Receiver<T::Request>>>,
+        queue: Arc<AtomicUsize>,
+        cpus: &[usize],
         handler: F
     ) -> Worker
     where
@@ -39,6 +44,7 @@ impl Worker {
             let msg = rx.lock().unwrap().recv_timeout(Duration::from_secs(1));
             match msg {
                 Ok(r) => {
+                    queue.fetch_sub(1, Ordering::Relaxed);
                     handler(r);
                 },
                 Err(mpsc::RecvTimeoutError::Timeout) if stop_flag.load(Ordering::Relaxed) => {
@@ -50,6 +56,7 @@ impl Worker {
                 }
             }
         }).unwrap();
+        affinity::pin(&thr, cpus);
         Worker {
             thr: Some(thr),
             stop: stop
@@ -71,26 +78,33 @@ where
 {
     pub fn new(
         size: usize,
+        cpus: &[usize],
         handler: F
     ) -> ThreadPool<T, F> {
         let (tx, rx) = mpsc::channel();
         let rx = Arc::new(Mutex::new(rx));
+        let queue = Arc::new(AtomicUsize::new(0));
         ThreadPool {
             tx: tx,
+            queue: Arc::clone(&queue),
             handler: match size {
                 0 => Some(handler.clone()),
                 _ => None
             },
-            workers: (0..size).map(|_| Worker::new::<_ ,T>(Arc::clone(&rx), handler.clone())).collect()
+            workers: (0..size).map(|_| Worker::new::<_ ,T>(Arc::clone(&rx), Arc::clone(&queue), cpus, handler.clone())).collect()
         }
     }
 
     pub fn post(&self, r: T::Request) -> CoreResult {
         match &self.handler {
             None => {
+                self.queue.fetch_add(1, Ordering::Relaxed);
                 match self.tx.send(r) {
                     Ok(()) => Ok(OK),
-                    Err(_) => throw!("Failed to post task")
+                    Err(_) => {
+                        self.queue.fetch_sub(1, Ordering::Relaxed);
+                        throw!("Failed to post task")
+                    }
                 }
             },
             Some(handler) => {
@@ -100,6 +114,10 @@ where
         }
     }
 
+    pub fn queue(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.queue)
+    }
+
     pub fn stop(&mut self) {
         (&mut self.workers).into_iter().for_each(|w| w.stop());
     }
@@ -0,0 +1,326 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{ BuildHasher, Hash };
+use std::sync::{ Mutex, MutexGuard };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+
+use crate::core::status::Json;
+
+const SHARDS: usize = 16;
+
+struct Item<V> {
+    value: V,
+    expires: Option<Instant>
+}
+
+impl<V> Item<V> {
+    fn alive(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+}
+
+struct Shard<K, V> {
+    map: HashMap<K, Item<V>>,
+    // expired entries are removed by the writers not more often than once per ttl
+    purged: Instant
+}
+
+impl<K: Eq + Hash, V> Shard<K, V> {
+    // Number of the removed entries
+    fn purge(&mut self, now: Instant) -> usize {
+        let len = self.map.len();
+        self.map.retain(|_, item| item.alive(now));
+        self.purged = now;
+        len - self.map.len()
+    }
+}
+
+// Lock-sharded map of the runtime state shared by the workgroups: counters of the limits,
+// remembered responses, keyval entries. Entries expire after the ttl, the zone keeps
+// max_entries at most, the new entry is rejected when the zone is full.
+pub struct Zone<K, V> {
+    shards: Vec<Mutex<Shard<K, V>>>,
+    hasher: RandomState,
+    ttl: Option<Duration>,
+    max_entries: Option<usize>,
+    // entries of all the shards, the expired ones are counted until they are purged
+    count: AtomicUsize
+}
+
+impl<K: Eq + Hash + Clone, V> Zone<K, V> {
+    pub fn new(ttl: Option<Duration>, max_entries: Option<usize>) -> Zone<K, V> {
+        let now = Instant::now();
+        Zone {
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard {
+                map: HashMap::new(),
+                purged: now
+            })).collect(),
+            hasher: RandomState::new(),
+            ttl,
+            max_entries,
+            count: AtomicUsize::new(0)
+        }
+    }
+
+    fn index<Q: ?Sized + Hash>(&self, key: &Q) -> usize {
+        self.hasher.hash_one(key) as usize % SHARDS
+    }
+
+    fn shard<Q: ?Sized + Hash>(&self, key: &Q) -> MutexGuard<'_, Shard<K, V>> {
+        self.shards[self.index(key)].lock().unwrap()
+    }
+
+    fn purge(&self, shard: &mut Shard<K, V>, now: Instant) {
+        let removed = shard.purge(now);
+        self.count.fetch_sub(removed, Ordering::AcqRel);
+    }
+
+    fn acquire(&self) -> bool {
+        match self.max_entries {
+            Some(max_entries) => self.count.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| match count < max_entries {
+                true => Some(count + 1),
+                false => None
+            }).is_ok(),
+            None => {
+                self.count.fetch_add(1, Ordering::AcqRel);
+                true
+            }
+        }
+    }
+
+    // Room for the new entry of the locked shard, the expired entries are removed first.
+    // Other shards are purged only if they are not locked, the locks are never waited in the other order.
+    fn reserve(&self, index: usize, shard: &mut Shard<K, V>, now: Instant) -> bool {
+        if let Some(ttl) = self.ttl {
+            if now.duration_since(shard.purged) >= ttl {
+                self.purge(shard, now);
+            }
+        }
+        if self.acquire() {
+            return true;
+        }
+        self.purge(shard, now);
+        if self.acquire() {
+            return true;
+        }
+        for (i, other) in self.shards.iter().enumerate() {
+            if i == index {
+                continue;
+            }
+            if let Ok(mut other) = other.try_lock() {
+                self.purge(&mut other, now);
+                if self.acquire() {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    pub fn max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        V: Clone
+    {
+        let now = Instant::now();
+        match self.shard(key).map.get(key) {
+            Some(item) if item.alive(now) => Some(item.value.clone()),
+            _ => None
+        }
+    }
+
+    // Entry expires after the ttl of the zone
+    pub fn insert(&self, key: K, value: V) -> bool {
+        self.insert_ttl(key, value, self.ttl)
+    }
+
+    // False if the zone is full
+    pub fn insert_ttl(&self, key: K, value: V, ttl: Option<Duration>) -> bool {
+        let now = Instant::now();
+        let index = self.index(&key);
+        let mut shard = self.shards[index].lock().unwrap();
+        if !shard.map.contains_key(&key) && !self.reserve(index, &mut shard, now) {
+            return false;
+        }
+        shard.map.insert(key, Item {
+            value,
+            expires: ttl.map(|ttl| now + ttl)
+        });
+        true
+    }
+
+    // f is applied to the entry, the missing or expired one is created by init first,
+    // None if the zone is full. Expiration of the existing entry is kept.
+    pub fn update<R>(&self, key: K, init: impl FnOnce() -> V, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        let now = Instant::now();
+        let index = self.index(&key);
+        let mut shard = self.shards[index].lock().unwrap();
+        if !shard.map.contains_key(&key) && !self.reserve(index, &mut shard, now) {
+            return None;
+        }
+        let alive = shard.map.get(&key).map(|item| item.alive(now));
+        if alive != Some(true) {
+            shard.map.insert(key.clone(), Item {
+                value: init(),
+                expires: self.ttl.map(|ttl| now + ttl)
+            });
+        }
+        shard.map.get_mut(&key).map(|item| f(&mut item.value))
+    }
+
+    // f is applied to the existing entry only
+    pub fn modify<Q, R>(&self, key: &Q, f: impl FnOnce(&mut V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash
+    {
+        let now = Instant::now();
+        match self.shard(key).map.get_mut(key) {
+            Some(item) if item.alive(now) => Some(f(&mut item.value)),
+            _ => None
+        }
+    }
+
+    // Entry is removed when f returns true
+    pub fn remove_if<Q>(&self, key: &Q, f: impl FnOnce(&mut V) -> bool) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash
+    {
+        let mut shard = self.shard(key);
+        let remove = match shard.map.get_mut(key) {
+            Some(item) => f(&mut item.value),
+            None => false
+        };
+        if remove {
+            shard.map.remove(key);
+            self.count.fetch_sub(1, Ordering::AcqRel);
+        }
+        remove
+    }
+
+    // None if the entry is missing or expired
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash
+    {
+        let now = Instant::now();
+        let item = self.shard(key).map.remove(key)?;
+        self.count.fetch_sub(1, Ordering::AcqRel);
+        Some(item).filter(|item| item.alive(now)).map(|item| item.value)
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            self.count.fetch_sub(shard.map.len(), Ordering::AcqRel);
+            shard.map.clear();
+        }
+    }
+
+    // Live entries
+    pub fn len(&self) -> usize {
+        let now = Instant::now();
+        self.shards.iter().map(|shard| shard.lock().unwrap().map.values().filter(|item| item.alive(now)).count()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Live entries with their expiration time
+    pub fn entries(&self) -> Vec<(K, V, Option<Instant>)>
+    where
+        V: Clone
+    {
+        let now = Instant::now();
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            entries.extend(shard.map.iter()
+                .filter(|(_, item)| item.alive(now))
+                .map(|(key, item)| (key.clone(), item.value.clone(), item.expires)));
+        }
+        entries
+    }
+
+    pub fn report(&self) -> Json {
+        Json::object(vec![
+            ("entries", self.len().into()),
+            ("max_entries", self.max_entries().into()),
+            ("ttl", self.ttl.map(|ttl| ttl.as_millis() as u64).into())
+        ])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn max_entries() {
+        let zone: Zone<usize, usize> = Zone::new(None, Some(3));
+        assert_eq!(zone.max_entries(), Some(3));
+        assert!((0..3).all(|i| zone.insert(i, i)));
+        assert!(!zone.insert(3, 3));
+        // the existing key is replaced
+        assert!(zone.insert(2, 20));
+        assert_eq!(zone.get(&2), Some(20));
+        assert_eq!(zone.len(), 3);
+    }
+
+    #[test]
+    fn count() {
+        let zone: Zone<usize, usize> = Zone::new(None, Some(100));
+        assert!((0..100).all(|i| zone.insert(i, i)));
+        assert!(zone.update(100, || 0, |_| ()).is_none());
+        assert_eq!(zone.remove(&5), Some(5));
+        assert!(zone.remove_if(&6, |_| true));
+        assert!(zone.insert(100, 100));
+        assert!(zone.update(101, || 0, |_| ()).is_some());
+        assert!(!zone.insert(102, 102));
+        zone.clear();
+        assert!((0..100).all(|i| zone.insert(i, i)));
+    }
+
+    #[test]
+    fn expired() {
+        let zone: Zone<usize, usize> = Zone::new(Some(Duration::from_millis(20)), Some(20));
+        assert!((0..20).all(|i| zone.insert(i, i)));
+        assert!(!zone.insert(20, 20));
+        thread::sleep(Duration::from_millis(30));
+        // expired entries of all the shards give the room
+        assert!((20..40).all(|i| zone.insert(i, i)));
+        assert_eq!(zone.len(), 20);
+    }
+
+    #[test]
+    fn concurrent() {
+        let zone: Arc<Zone<usize, usize>> = Arc::new(Zone::new(None, Some(50)));
+        let threads: Vec<_> = (0..4).map(|t| {
+            let zone = Arc::clone(&zone);
+            thread::spawn(move || (0..100).filter(|i| zone.insert(t * 100 + i, *i)).count())
+        }).collect();
+        let inserted: usize = threads.into_iter().map(|thr| thr.join().unwrap()).sum();
+        assert_eq!(inserted, 50);
+        assert_eq!(zone.len(), 50);
+    }
+}
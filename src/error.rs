@@ -2,6 +2,8 @@
  * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
  */
 
+use std::time::Duration;
+
 use crate::connection_pool::*;
 
 #[allow(non_camel_case_types)]
@@ -17,7 +19,9 @@ pub enum Flush {
     // Need write
     WRITE_MORE(Peer),
     // Need read and write
-    READ_WRITE_MORE(Peer)
+    READ_WRITE_MORE(Peer),
+    // Throttled, resume after delay
+    DELAY(Duration)
 }
 
 #[allow(non_camel_case_types)]
@@ -0,0 +1,117 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use regex::Regex;
+
+use crate::http::*;
+use crate::error::CoreError;
+
+#[derive(Clone)]
+enum Operator {
+    Eq,
+    Ne,
+    Ge,
+    Le,
+    Gt,
+    Lt,
+    Match(Regex),
+    NotMatch(Regex)
+}
+
+const OPERATORS: [&str; 8] = [" == ", " != ", " >= ", " <= ", " > ", " < ", " !~ ", " ~ "];
+
+// 'value' is true unless empty or "0", 'left op right' compares the expanded sides,
+// numerically when both are numbers, '~' and '!~' match the left side with a regex.
+#[derive(Clone)]
+pub struct Condition {
+    // as configured, conditions with the same text are the same
+    text: String,
+    left: HttpComplexValue,
+    op: Option<(Operator, HttpComplexValue)>
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> Result<Condition, CoreError> {
+        for op in OPERATORS.iter() {
+            if let Some(pos) = s.find(op) {
+                let left = s[..pos].trim();
+                let right = s[pos + op.len()..].trim();
+                let operator = match op.trim() {
+                    "==" => Operator::Eq,
+                    "!=" => Operator::Ne,
+                    ">=" => Operator::Ge,
+                    "<=" => Operator::Le,
+                    ">" => Operator::Gt,
+                    "<" => Operator::Lt,
+                    regex => match Regex::new(right) {
+                        Ok(re) if regex == "~" => Operator::Match(re),
+                        Ok(re) => Operator::NotMatch(re),
+                        Err(err) => return throw!("invalid regex '{}': {}", right, err)
+                    }
+                };
+                return Ok(Condition {
+                    text: s.trim().to_string(),
+                    left: HttpComplexValue::complex(left),
+                    op: Some((operator, HttpComplexValue::complex(right)))
+                });
+            }
+        }
+        Ok(Condition {
+            text: s.trim().to_string(),
+            left: HttpComplexValue::complex(s.trim()),
+            op: None
+        })
+    }
+
+    // Expanded value is the same as the text
+    pub fn equals(value: &str, text: &str) -> Condition {
+        Condition {
+            text: format!("{} == {}", value, text),
+            left: HttpComplexValue::complex(value),
+            op: Some((Operator::Eq, HttpComplexValue::simple(text)))
+        }
+    }
+
+    // Expanded value matches the regex
+    pub fn matches(value: &str, regex: &str) -> Result<Condition, CoreError> {
+        match Regex::new(regex) {
+            Ok(re) => Ok(Condition {
+                text: format!("{} ~ {}", value, regex),
+                left: HttpComplexValue::complex(value),
+                op: Some((Operator::Match(re), HttpComplexValue::simple(regex)))
+            }),
+            Err(err) => throw!("invalid regex '{}': {}", regex, err)
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn eval<F: Fn(&HttpComplexValue) -> String>(&self, expand: F) -> bool {
+        let left = expand(&self.left);
+        let (op, right) = match &self.op {
+            Some((op, right)) => (op, right),
+            None => return !left.is_empty() && left != "0"
+        };
+        let ordering = || {
+            let right = expand(right);
+            match (left.parse::<f64>(), right.parse::<f64>()) {
+                (Ok(l), Ok(r)) => l.partial_cmp(&r),
+                _ => Some(left.as_str().cmp(right.as_str()))
+            }
+        };
+        use std::cmp::Ordering::*;
+        match op {
+            Operator::Eq => ordering() == Some(Equal),
+            Operator::Ne => ordering() != Some(Equal),
+            Operator::Ge => matches!(ordering(), Some(Greater) | Some(Equal)),
+            Operator::Le => matches!(ordering(), Some(Less) | Some(Equal)),
+            Operator::Gt => ordering() == Some(Greater),
+            Operator::Lt => ordering() == Some(Less),
+            Operator::Match(re) => re.is_match(&left),
+            Operator::NotMatch(re) => !re.is_match(&left)
+        }
+    }
+}
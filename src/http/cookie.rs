@@ -0,0 +1,142 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use chrono::prelude::*;
+use std::fmt;
+use std::time::Duration;
+
+use crate::http::HttpCookies;
+
+// Pairs of the Cookie request header, values are opaque and kept as sent
+pub fn parse(header: &str, cookies: &mut HttpCookies) {
+    for pair in header.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (name, value) = match pair.find('=') {
+            Some(pos) => (pair[..pos].trim(), pair[pos + 1..].trim()),
+            None => continue
+        };
+        if name.is_empty() {
+            continue;
+        }
+        let value = match value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            true => &value[1..value.len() - 1],
+            false => value
+        };
+        cookies.add(name, value.to_string());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None
+}
+
+// Set-Cookie of the response, e.g. Cookie::new("route", "a1").path("/").http_only(true)
+#[derive(Clone, Debug)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<DateTime<Utc>>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None
+        }
+    }
+
+    // Asks the client to drop the cookie
+    pub fn removal(name: &str) -> Cookie {
+        Cookie::new(name, "").max_age(Duration::from_secs(0)).expires(Utc.timestamp_opt(0, 0).unwrap())
+    }
+
+    pub fn path(mut self, path: &str) -> Cookie {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Cookie {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn expires(mut self, expires: DateTime<Utc>) -> Cookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", expires.format("%a, %d %b %Y %H:%M:%S GMT"))?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(())
+        }
+    }
+}
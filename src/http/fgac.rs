@@ -0,0 +1,573 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use regex::Regex;
+use yaml_rust::{ Yaml, YamlLoader };
+
+use crate::error::CoreError;
+use crate::crypto;
+
+// Attribute based access control: policies are evaluated against subject attributes
+// (JWT claims, headers), resource (uri) and method.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Effect {
+    Allow,
+    Deny
+}
+
+impl Effect {
+    pub fn parse(s: &str) -> Result<Effect, CoreError> {
+        match s {
+            "allow" => Ok(Effect::Allow),
+            "deny" => Ok(Effect::Deny),
+            _ => throw!("invalid effect '{}', 'allow' or 'deny' expected", s)
+        }
+    }
+}
+
+impl std::fmt::Display for Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Effect::Allow => write!(f, "allow"),
+            Effect::Deny => write!(f, "deny")
+        }
+    }
+}
+
+pub enum Pattern {
+    Any,
+    Exact(String),
+    Prefix(String),
+    Regex(Regex)
+}
+
+impl Pattern {
+    // '*' - any value, '~ re' - regular expression, 'prefix*' - prefix, otherwise exact match
+    pub fn parse(s: &str) -> Result<Pattern, CoreError> {
+        if s == "*" {
+            return Ok(Pattern::Any);
+        }
+        if let Some(re) = s.strip_prefix("~ ") {
+            return match Regex::new(re.trim()) {
+                Ok(re) => Ok(Pattern::Regex(re)),
+                Err(err) => throw!("invalid pattern '{}': {}", s, err)
+            };
+        }
+        if let Some(prefix) = s.strip_suffix('*') {
+            return Ok(Pattern::Prefix(prefix.to_string()));
+        }
+        Ok(Pattern::Exact(s.to_string()))
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::Exact(s) => s == value,
+            Pattern::Prefix(s) => value.starts_with(s.as_str()),
+            Pattern::Regex(re) => re.is_match(value)
+        }
+    }
+}
+
+// Attribute may have several values, e.g. JWT 'roles' array
+pub type Attributes = HashMap<String, Vec<String>>;
+
+pub struct Rule {
+    pub effect: Effect,
+    pub subject: Vec<(String, Vec<Pattern>)>,
+    pub resources: Vec<Pattern>,
+    pub methods: Vec<String>
+}
+
+impl Rule {
+    // Empty resources or methods match anything, all subject attributes must match
+    fn matches(&self, subject: &Attributes, resource: &str, method: &str) -> bool {
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+            return false;
+        }
+        if !self.resources.is_empty() && !self.resources.iter().any(|p| p.matches(resource)) {
+            return false;
+        }
+        self.subject.iter().all(|(name, patterns)| {
+            match subject.get(name) {
+                Some(values) => values.iter().any(|v| patterns.iter().any(|p| p.matches(v))),
+                None => false
+            }
+        })
+    }
+}
+
+pub struct Policy {
+    pub name: String,
+    pub default: Effect,
+    pub rules: Vec<Rule>
+}
+
+pub struct Decision {
+    pub effect: Effect,
+    // index of the rule made the decision, None for the default effect
+    pub rule: Option<usize>
+}
+
+impl Policy {
+    // Deny overrides allow, the default effect is applied when no rule matches
+    pub fn evaluate(&self, subject: &Attributes, resource: &str, method: &str) -> Decision {
+        let mut allow = None;
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.matches(subject, resource, method) {
+                match rule.effect {
+                    Effect::Deny => return Decision {
+                        effect: Effect::Deny,
+                        rule: Some(i)
+                    },
+                    Effect::Allow => if allow.is_none() {
+                        allow = Some(i);
+                    }
+                }
+            }
+        }
+        match allow {
+            Some(i) => Decision {
+                effect: Effect::Allow,
+                rule: Some(i)
+            },
+            None => Decision {
+                effect: self.default,
+                rule: None
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref POLICIES: RwLock<HashMap<String, Arc<Policy>>> = RwLock::new(HashMap::new());
+    // policy files and names of the policies loaded from them on the last reload
+    static ref FILES: Mutex<(Vec<String>, HashSet<String>)> = Mutex::new((Vec::new(), HashSet::new()));
+}
+
+pub fn add(policy: Policy) {
+    POLICIES.write().unwrap().insert(policy.name.clone(), Arc::new(policy));
+}
+
+pub fn get(name: &str) -> Option<Arc<Policy>> {
+    POLICIES.read().unwrap().get(name).cloned()
+}
+
+pub fn names() -> Vec<String> {
+    let mut names: Vec<String> = POLICIES.read().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+}
+
+pub fn add_file(filename: &str) {
+    FILES.lock().unwrap().0.push(filename.to_string());
+}
+
+// Policies are replaced only if all files are loaded successfully,
+// policies removed from the files are removed as well.
+// Returns number of loaded policies.
+pub fn reload() -> Result<usize, CoreError> {
+    let mut files = FILES.lock().unwrap();
+
+    let mut policies = Vec::new();
+    for filename in files.0.iter() {
+        let text = match std::fs::read_to_string(filename) {
+            Ok(text) => text,
+            Err(err) => return throw!("failed to read '{}': {}", filename, err)
+        };
+        match parse(&text) {
+            Ok(mut loaded) => policies.append(&mut loaded),
+            Err(err) => return throw!("'{}': {}", filename, err.what())
+        }
+    }
+
+    let names: HashSet<String> = policies.iter().map(|policy| policy.name.clone()).collect();
+    let count = policies.len();
+
+    let mut registry = POLICIES.write().unwrap();
+    for name in files.1.difference(&names) {
+        registry.remove(name);
+    }
+    for policy in policies {
+        registry.insert(policy.name.clone(), Arc::new(policy));
+    }
+    files.1 = names;
+
+    Ok(count)
+}
+
+// Policies in the same format as in configuration: list of 'policy' or the single one
+pub fn parse(text: &str) -> Result<Vec<Policy>, CoreError> {
+    let docs = match YamlLoader::load_from_str(text) {
+        Ok(docs) => docs,
+        Err(err) => return throw!("{}", err)
+    };
+    let mut policies = Vec::new();
+    for doc in docs.iter() {
+        match doc {
+            Yaml::Array(items) => for item in items {
+                policies.push(parse_policy(&item["policy"])?);
+            },
+            Yaml::Hash(_) => policies.push(parse_policy(&doc["policy"])?),
+            _ => return throw!("list of policies expected")
+        }
+    }
+    Ok(policies)
+}
+
+fn strings(y: &Yaml) -> Vec<String> {
+    match y {
+        Yaml::Array(items) => items.iter().flat_map(strings).collect(),
+        Yaml::String(s) => vec![s.clone()],
+        Yaml::Integer(i) => vec![i.to_string()],
+        Yaml::Boolean(b) => vec![b.to_string()],
+        _ => vec![]
+    }
+}
+
+fn parse_policy(y: &Yaml) -> Result<Policy, CoreError> {
+    let name = match y["name"].as_str() {
+        Some(name) => name.to_string(),
+        None => return throw!("policy: 'name' required")
+    };
+    let default = match y["default"].as_str() {
+        Some(default) => Effect::parse(default)?,
+        None => Effect::Deny
+    };
+    let mut rules = Vec::new();
+    if let Yaml::Array(items) = &y["rules"] {
+        for item in items {
+            let rule = &item["rule"];
+            let effect = match rule["effect"].as_str() {
+                Some(effect) => Effect::parse(effect)?,
+                None => return throw!("policy '{}': rule 'effect' required", name)
+            };
+            let mut subject = Vec::new();
+            if let Yaml::Hash(h) = &rule["subject"] {
+                for (k, v) in h {
+                    let mut patterns = Vec::new();
+                    for s in strings(v) {
+                        patterns.push(Pattern::parse(&s)?);
+                    }
+                    subject.push((k.as_str().unwrap_or_default().to_string(), patterns));
+                }
+            }
+            let mut resources = Vec::new();
+            for s in strings(&rule["resources"]) {
+                resources.push(Pattern::parse(&s)?);
+            }
+            rules.push(Rule {
+                effect,
+                subject,
+                resources,
+                methods: strings(&rule["methods"])
+            });
+        }
+    }
+    Ok(Policy {
+        name,
+        default,
+        rules
+    })
+}
+
+// Claims of the JWT ('Bearer ' prefix is allowed), nested objects are flattened with '.'.
+// Only HS256 signed tokens are accepted, 'alg: none' and the others are rejected, 'exp' is always checked.
+pub fn jwt_claims(token: &str, secret: &[u8]) -> Result<Attributes, CoreError> {
+    let token = token.trim();
+    let token = token.strip_prefix("Bearer ").unwrap_or(token).trim();
+
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return throw!("invalid jwt");
+    }
+
+    let decode = |part: &str| -> Result<Attributes, CoreError> {
+        let json = match crypto::base64_decode(part) {
+            Some(json) => json,
+            None => return throw!("invalid jwt encoding")
+        };
+        let mut attrs = Attributes::new();
+        Json::new(&json).parse(&mut attrs)?;
+        Ok(attrs)
+    };
+
+    if secret.is_empty() {
+        return throw!("jwt secret is not defined");
+    }
+
+    // nothing but the signature is parsed before it is verified
+    let signature = match crypto::base64_decode(parts[2]) {
+        Some(signature) if !signature.is_empty() => signature,
+        _ => return throw!("jwt is not signed")
+    };
+    let expected = crypto::hmac_sha256(secret, &token.as_bytes()[..parts[0].len() + parts[1].len() + 1]);
+    if !crypto::constant_time_eq(&signature, &expected) {
+        return throw!("invalid jwt signature");
+    }
+    let header = decode(parts[0])?;
+    if header.get("alg").and_then(|alg| alg.first()).map(|alg| alg.as_str()) != Some("HS256") {
+        return throw!("unsupported jwt algorithm");
+    }
+
+    let claims = decode(parts[1])?;
+
+    if let Some(exp) = claims.get("exp").and_then(|exp| exp.first()) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        match exp.parse::<f64>() {
+            Ok(exp) if (exp as u64) > now => {},
+            _ => return throw!("jwt is expired")
+        }
+    }
+
+    Ok(claims)
+}
+
+// Nesting of the JSON objects and arrays, deeper documents are rejected
+const JSON_MAX_DEPTH: usize = 32;
+
+// Minimal JSON reader for the flat representation of JWT claims
+struct Json<'a> {
+    s: &'a [u8],
+    pos: usize,
+    depth: usize
+}
+
+impl<'a> Json<'a> {
+    fn new(s: &'a [u8]) -> Json<'a> {
+        Json {
+            s,
+            pos: 0,
+            depth: 0
+        }
+    }
+
+    fn parse(&mut self, attrs: &mut Attributes) -> Result<(), CoreError> {
+        self.skip_ws();
+        if self.peek() != Some(b'{') {
+            return throw!("json object expected");
+        }
+        self.value("", attrs)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), CoreError> {
+        self.skip_ws();
+        if self.peek() != Some(c) {
+            return throw!("invalid json at {}: '{}' expected", self.pos, c as char);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, name: &str, attrs: &mut Attributes) -> Result<(), CoreError> {
+        self.skip_ws();
+        match self.peek() {
+            Some(b'{') | Some(b'[') => {
+                if self.depth == JSON_MAX_DEPTH {
+                    return throw!("invalid json at {}: nesting is deeper than {}", self.pos, JSON_MAX_DEPTH);
+                }
+                self.depth += 1;
+                let result = self.nested(name, attrs);
+                self.depth -= 1;
+                result
+            },
+            Some(b'"') => {
+                let s = self.string()?;
+                attrs.entry(name.to_string()).or_default().push(s);
+                Ok(())
+            },
+            Some(_) => {
+                // number, true, false, null
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c == b',' || c == b'}' || c == b']' || c.is_ascii_whitespace() {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                let s = String::from_utf8_lossy(&self.s[start..self.pos]).to_string();
+                if s.is_empty() {
+                    return throw!("invalid json at {}", self.pos);
+                }
+                attrs.entry(name.to_string()).or_default().push(s);
+                Ok(())
+            },
+            None => throw!("unexpected end of json")
+        }
+    }
+
+    fn nested(&mut self, name: &str, attrs: &mut Attributes) -> Result<(), CoreError> {
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                self.skip_ws();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                loop {
+                    self.skip_ws();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    let key = if name.is_empty() { key } else { format!("{}.{}", name, key) };
+                    self.value(&key, attrs)?;
+                    self.skip_ws();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(());
+                        },
+                        _ => return throw!("invalid json at {}", self.pos)
+                    }
+                }
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                self.skip_ws();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                loop {
+                    self.value(name, attrs)?;
+                    self.skip_ws();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(());
+                        },
+                        _ => return throw!("invalid json at {}", self.pos)
+                    }
+                }
+            },
+            _ => throw!("invalid json at {}", self.pos)
+        }
+    }
+
+    fn string(&mut self) -> Result<String, CoreError> {
+        self.expect(b'"')?;
+        let mut s = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(String::from_utf8_lossy(&s).to_string());
+                },
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(b'n') => s.push(b'\n'),
+                        Some(b't') => s.push(b'\t'),
+                        Some(b'r') => s.push(b'\r'),
+                        Some(b'b') => s.push(8),
+                        Some(b'f') => s.push(12),
+                        Some(b'u') => {
+                            let hex = self.s.get(self.pos + 1..self.pos + 5).unwrap_or_default();
+                            let c = u32::from_str_radix(&String::from_utf8_lossy(hex), 16).ok()
+                                                                                          .and_then(std::char::from_u32)
+                                                                                          .unwrap_or('?');
+                            let mut buf = [0u8; 4];
+                            s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                            self.pos += 4;
+                        },
+                        Some(c) => s.push(c),
+                        None => return throw!("unexpected end of json")
+                    }
+                    self.pos += 1;
+                },
+                Some(c) => {
+                    s.push(c);
+                    self.pos += 1;
+                },
+                None => return throw!("unexpected end of json")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn base64(data: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let mut out = String::new();
+        for chunk in data.chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+            for i in 0..chunk.len() + 1 {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        out
+    }
+
+    fn token(header: &str, claims: &str, secret: &[u8]) -> String {
+        let signed = format!("{}.{}", base64(header.as_bytes()), base64(claims.as_bytes()));
+        let signature = crypto::hmac_sha256(secret, signed.as_bytes());
+        format!("{}.{}", signed, base64(&signature))
+    }
+
+    const HS256: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+    #[test]
+    fn signed() {
+        let claims = jwt_claims(&token(HS256, r#"{"sub":"alice","org":{"role":"admin"},"exp":4102444800}"#, SECRET), SECRET).unwrap();
+        assert_eq!(claims["sub"], vec!["alice"]);
+        assert_eq!(claims["org.role"], vec!["admin"]);
+        assert!(jwt_claims(&format!("Bearer {}", token(HS256, r#"{"sub":"alice"}"#, SECRET)), SECRET).is_ok());
+    }
+
+    #[test]
+    fn forged() {
+        assert!(jwt_claims(&token(HS256, r#"{"sub":"alice"}"#, b"other"), SECRET).is_err());
+        // claims replaced, signature of the original ones kept
+        let original = token(HS256, r#"{"sub":"alice"}"#, SECRET);
+        let signature = original.rsplit('.').next().unwrap();
+        let forged = format!("{}.{}.{}", base64(HS256.as_bytes()), base64(br#"{"sub":"admin"}"#), signature);
+        assert!(jwt_claims(&forged, SECRET).is_err());
+    }
+
+    #[test]
+    fn unsigned() {
+        let claims = base64(br#"{"sub":"admin"}"#);
+        assert!(jwt_claims(&format!("{}.{}.", base64(br#"{"alg":"none"}"#), claims), SECRET).is_err());
+        assert!(jwt_claims(&format!("{}.{}.", base64(HS256.as_bytes()), claims), SECRET).is_err());
+        assert!(jwt_claims(&token(r#"{"alg":"none"}"#, r#"{"sub":"admin"}"#, SECRET), SECRET).is_err());
+        // no secret, no trust
+        assert!(jwt_claims(&token(HS256, r#"{"sub":"admin"}"#, b""), b"").is_err());
+    }
+
+    #[test]
+    fn expired() {
+        assert!(jwt_claims(&token(HS256, r#"{"sub":"alice","exp":1}"#, SECRET), SECRET).is_err());
+        assert!(jwt_claims(&token(HS256, r#"{"sub":"alice","exp":"soon"}"#, SECRET), SECRET).is_err());
+    }
+
+    #[test]
+    fn nested() {
+        let claims = jwt_claims(&token(HS256, r#"{"a":[[{"b":["c"]}]]}"#, SECRET), SECRET).unwrap();
+        assert_eq!(claims["a.b"], vec!["c"]);
+        let deep = format!(r#"{{"a":{}1{}}}"#, "[".repeat(10000), "]".repeat(10000));
+        assert!(jwt_claims(&token(HS256, &deep, SECRET), SECRET).is_err());
+        assert!(jwt_claims(&token(&deep, r#"{"sub":"alice"}"#, SECRET), SECRET).is_err());
+    }
+}
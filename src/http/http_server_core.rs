@@ -2,22 +2,35 @@
  * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
  */
 
-use std::collections::{ HashMap, LinkedList };
+use std::collections::{ HashMap, HashSet, LinkedList };
 use std::net::SocketAddr;
-use std::sync::{ Arc, RwLock };
+use std::sync::{ Arc, RwLock, atomic::AtomicUsize };
 
 use crate::http::server::HttpServer;
-use crate::http::routers::{ trie::TrieRouter, re::RegexRouter, named::NamedRouter };
+use crate::http::routers::{ trie::{ TrieRouter, TrieOptions }, re::RegexRouter, named::NamedRouter, result::RouteResult };
 use crate::error::{ Code, CoreResult, CoreError };
 use crate::handler::sync::RefHandler;
 use crate::http::*;
+use crate::core::{ listen, budget, buffers, affinity::Affinity, poller::Engine, accept::Accept };
+use crate::http::{ inflight, limits, multipart, security_headers, spool, vhosts };
 
 impl RouteContext {
     pub fn copy(&mut self, src: &RouteContext) -> &'_ mut RouteContext {
         self.error_log = src.error_log.clone();
+        self.error_log_level = src.error_log_level;
         self.host = src.host.clone();
         self.pattern = src.pattern.clone();
         self.method = src.method.clone();
+        self.ignore_case = src.ignore_case;
+        self.ignore_trailing_slash = src.ignore_trailing_slash;
+        self.private_cache = src.private_cache;
+        self.auth = src.auth;
+        self.security_headers = src.security_headers.clone();
+        self.metrics = src.metrics.clone();
+        self.expect_continue = src.expect_continue;
+        self.max_body_size = src.max_body_size;
+        self.upstream = src.upstream.clone();
+        self.condition = src.condition.clone();
         self.setvar = src.setvar.clone();
         self.rewrite = src.rewrite.clone();
         self.access = src.access.clone();
@@ -28,6 +41,38 @@ impl RouteContext {
         self.log = src.log.clone();
         self
     }
+
+    fn condition_text(&self) -> Option<&str> {
+        self.condition.as_ref().map(|condition| condition.text())
+    }
+
+    // Adds or replaces the route with the same condition, the others are kept as alternatives
+    fn merge(&mut self, route: &RouteContext) {
+        if self.condition_text() == route.condition_text() {
+            self.copy(route);
+            return;
+        }
+        match self.alternatives.iter_mut().find(|alternative| alternative.condition_text() == route.condition_text()) {
+            Some(alternative) => {
+                alternative.copy(route);
+            },
+            None => {
+                let mut alternative = RouteContext::default();
+                alternative.copy(route);
+                self.alternatives.push(alternative);
+            }
+        }
+    }
+
+    // Conditional routes are checked first in order of configuration,
+    // None passes the request to the next route matching the uri
+    fn select(&self, r: &HttpRequest) -> Option<&RouteContext> {
+        let routes = || std::iter::once(self).chain(self.alternatives.iter());
+        routes()
+            .filter(|route| route.condition.is_some())
+            .find(|route| route.condition.as_ref().unwrap().eval(|cv| r.expand(cv)))
+            .or_else(|| routes().find(|route| route.condition.is_none()))
+    }
 }
 
 type HttpNamedRouter = NamedRouter<RouteContext>;
@@ -36,24 +81,332 @@ type HttpRegexRouter = RegexRouter<RouteContext>;
 
 #[derive(Default)]
 struct Routers {
+    // '= /path'
+    exact: HttpTrieRouter,
     trie: HttpTrieRouter,
     regex: HttpRegexRouter,
     named: HttpNamedRouter
 }
 
+type RoutesMap = Arc<RwLock<HashMap<(SocketAddr, String), Routers>>>;
+type PhasesMap = Arc<RwLock<HashMap<(SocketAddr, String), ServerContext>>>;
+
+// Route lookups of a request, as nginx does
+const MAX_REWRITE_CYCLES: usize = 10;
+
+// Methods listed in the Allow header, in this order
+const METHODS: [&str; 15] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "TRACE",
+    "MKCOL", "COPY", "MOVE", "PROPFIND", "PROPPATCH", "LOCK", "UNLOCK"
+];
+
+// Modifier of the match pattern with the pattern for its router
+enum Pattern<'a> {
+    // '= /path', the whole uri only
+    Exact(&'a str),
+    // '/path' or '^~ /path', which is not overridden by the regex routes
+    Prefix(&'a str),
+    // '~ regex' or '~* regex', which ignores the case
+    Regex(String),
+    // '@name'
+    Named(&'a str)
+}
+
+impl<'a> Pattern<'a> {
+    fn parse(path: &'a str) -> Pattern<'a> {
+        if let Some(re) = path.strip_prefix("~*") {
+            Pattern::Regex(format!("(?i){}", re.trim_start()))
+        } else if let Some(re) = path.strip_prefix("~") {
+            Pattern::Regex(re.trim_start().to_string())
+        } else if let Some(path) = path.strip_prefix("=") {
+            Pattern::Exact(path.trim_start())
+        } else if let Some(path) = path.strip_prefix("^~") {
+            Pattern::Prefix(path.trim_start())
+        } else if path.starts_with("@") {
+            Pattern::Named(path)
+        } else {
+            Pattern::Prefix(path)
+        }
+    }
+}
+
+impl Routers {
+    // Route of the request, the first found of:
+    //   '@name' for the named uri, nothing else is looked up for it,
+    //   '= /path' of the whole uri,
+    //   '/path' or '^~ /path' of the whole uri,
+    //   the longest '^~ /path' prefix of the uri,
+    //   '~ regex' or '~* regex' in the order of the config,
+    //   the longest '/path' prefix of the uri
+    fn route(&self, r: &mut HttpRequest) -> Option<RouteResult<'_, RouteContext>> {
+        if r.uri().starts_with("@") {
+            return self.named.get(r, &RouteContext::select);
+        }
+        if let Some(route) = self.exact.get_exact(r, &RouteContext::select) {
+            return Some(route);
+        }
+        match self.trie.get(r, &RouteContext::select) {
+            Some((route, true)) => Some(route),
+            Some((route, false)) if route.pattern.starts_with("^~") => Some(route),
+            Some((route, false)) => match self.regex.get(r, &RouteContext::select) {
+                Some(route) => Some(route),
+                None => Some(route)
+            },
+            None => self.regex.get(r, &RouteContext::select)
+        }
+    }
+
+    fn matched(&self, uri: &str, method: &str) -> bool {
+        match uri.starts_with("@") {
+            true => self.named.find(uri, method).is_some(),
+            false => self.exact.find(uri, method).filter(|(_, exact, _)| *exact).is_some()
+                  || self.trie.find(uri, method).is_some()
+                  || self.regex.find(uri, method).is_some()
+        }
+    }
+
+    // Methods of the routes matching the uri, of all routes for 'OPTIONS *'
+    fn allowed(&self, uri: &str) -> Vec<&'static str> {
+        if uri == "*" {
+            let mut methods: HashSet<String> = self.trie.methods();
+            methods.extend(self.exact.methods());
+            methods.extend(self.regex.methods());
+            methods.extend(self.named.methods());
+            return METHODS.iter().filter(|method| methods.contains("*") || methods.contains(**method)).cloned().collect();
+        }
+        METHODS.iter().filter(|method| self.matched(uri, method)).cloned().collect()
+    }
+}
+
+lazy_static! {
+    // Routes of all event pools serving the listener, they are the same for the routes test
+    static ref TABLES: RwLock<HashMap<SocketAddr, Vec<RoutesMap>>> = RwLock::new(HashMap::new());
+    // Server phase handlers of the listener for the 100-continue check
+    static ref PHASES: RwLock<HashMap<SocketAddr, PhasesMap>> = RwLock::new(HashMap::new());
+}
+
+fn upsert_route(routes: &RoutesMap, addr: SocketAddr, route: &RouteContext) -> CoreResult {
+    if !route.methods.is_empty() {
+        for method in route.methods.iter() {
+            let mut route = route.clone();
+            route.method = Some(*method);
+            route.methods.clear();
+            upsert_route(routes, addr, &route)?;
+        }
+        return Ok(OK);
+    }
+    let key = (addr, route.host.clone().unwrap_or("*".to_string()));
+    vhosts::add(addr, &key.1, false)?;
+    let method = get_method(route.method);
+    let options = TrieOptions { ignore_case: route.ignore_case, ignore_trailing_slash: route.ignore_trailing_slash };
+    let mut routes = routes.write().unwrap();
+    let f = move |context: &mut RouteContext, added| {
+        match added {
+            true => {
+                context.copy(route);
+            },
+            false => context.merge(route)
+        }
+    };
+    match Pattern::parse(&route.pattern) {
+        Pattern::Regex(re) => routes.entry(key).or_default().regex.upsert(&re, method, f),
+        Pattern::Named(name) => routes.entry(key).or_default().named.upsert(name, method, f),
+        Pattern::Exact(path) if !path.is_empty() => routes.entry(key).or_default().exact.upsert_with(path, method, options, f),
+        Pattern::Prefix(path) if !path.is_empty() => routes.entry(key).or_default().trie.upsert_with(path, method, options, f),
+        _ => throw!("Pattern required")
+    }
+}
+
+fn take_route(routes: &RoutesMap, key: &(SocketAddr, String), path: &str, method: Option<String>) -> Option<RouteContext> {
+    match routes.write().unwrap().get_mut(key) {
+        Some(routes) => match Pattern::parse(path) {
+            Pattern::Regex(re) => routes.regex.take(&re, method),
+            Pattern::Named(name) => routes.named.take(name, method),
+            Pattern::Exact(path) => routes.exact.take(path, method),
+            Pattern::Prefix(path) => routes.trie.take(path, method)
+        },
+        None => None
+    }
+}
+
+// Adds or replaces the route in all event pools of the running listener
+pub fn add_route(bind: &str, route: &RouteContext) -> CoreResult {
+    let addr = get_addr(bind)?;
+    match TABLES.read().unwrap().get(&addr) {
+        Some(tables) => {
+            for routes in tables {
+                upsert_route(routes, addr, route)?;
+            }
+            Ok(OK)
+        },
+        None => throw!("No server on {}", bind)
+    }
+}
+
+// Removes the route from all event pools of the running listener, returns the removed route
+pub fn remove_route(bind: &str, host: Option<String>, path: &str, method: Option<HttpMethod>) -> Result<Option<RouteContext>, CoreError> {
+    let addr = get_addr(bind)?;
+    let key = (addr, host.unwrap_or("*".to_string()));
+    let method = get_method(method);
+    match TABLES.read().unwrap().get(&addr) {
+        Some(tables) => Ok(tables.iter().fold(None, |removed, routes| {
+            take_route(routes, &key, path, method.clone()).or(removed)
+        })),
+        None => throw!("No server on {}", bind)
+    }
+}
+
+// Server and route which would be selected for the request
+pub struct Resolved {
+    pub host: String,
+    pub pattern: Option<String>,
+    pub upstream: Option<String>
+}
+
+pub fn resolve(addr: &SocketAddr, host: &str, method: &str, uri: &str) -> Option<Resolved> {
+    let routes = match TABLES.read().unwrap().get(addr).and_then(|tables| tables.first()) {
+        Some(routes) => Arc::clone(routes),
+        None => return None
+    };
+    let routes = routes.read().unwrap();
+    let host = vhosts::find(addr, host).name;
+    let (host, routers) = match routes.get(&(*addr, host.clone())) {
+        Some(routers) => (host.to_string(), routers),
+        None => match routes.get(&(*addr, "*".to_string())) {
+            Some(routers) => ("*".to_string(), routers),
+            None => return Some(Resolved { host: "*".to_string(), pattern: None, upstream: None })
+        }
+    };
+    let found = |route: &RouteContext| (Some(route.pattern.clone()), route.upstream.clone());
+    let (pattern, upstream) = if uri.starts_with("@") {
+        routers.named.find(uri, method).map(|route| found(&route)).unwrap_or((None, None))
+    } else if let Some((route, true, _)) = routers.exact.find(uri, method) {
+        found(&route)
+    } else {
+        match routers.trie.find(uri, method) {
+            Some((route, true, _)) => found(&route),
+            Some((route, false, _)) if route.pattern.starts_with("^~") => found(&route),
+            Some((route, false, _)) => match routers.regex.find(uri, method) {
+                Some((route, _)) => found(&route),
+                None => found(&route)
+            },
+            None => routers.regex.find(uri, method).map(|(route, _)| found(&route)).unwrap_or((None, None))
+        }
+    };
+    Some(Resolved { host, pattern, upstream })
+}
+
+// Decision on the request expecting 100-continue, taken before the body is solicited
+pub (crate) enum Expect {
+    Continue,
+    // expect_continue is off, the body is read when the client sends it
+    Wait,
+    // access phase has rejected the request, the body is not read
+    Reject
+}
+
+// Result of the access phase taken before the body
+struct Checked {
+    pattern: String,
+    uri: String,
+    rc: Code
+}
+
+// Access phase of the route is run on the IO thread before the body is solicited, so a rejected upload
+// (401, 403, 413, 417, ...) is not sent. Access handlers are in-memory checks, they must not block.
+// The content phase takes the result instead of running the access handlers again.
+pub (crate) fn expect(r: &mut HttpRequest) -> Expect {
+    let addr = r.const_context().server_addr;
+    let (routes, phases) = match (TABLES.read().unwrap().get(&addr).and_then(|tables| tables.first()), PHASES.read().unwrap().get(&addr)) {
+        (Some(routes), Some(phases)) => (Arc::clone(routes), Arc::clone(phases)),
+        _ => return Expect::Continue
+    };
+    let routes = routes.read().unwrap();
+    let phases = phases.read().unwrap();
+    let key = HttpServerCore::virtual_host(addr, r);
+    let key_default = (addr, "*".to_string());
+    let phase_handlers = phases.get(&key).or_else(|| phases.get(&key_default));
+    // server variables are seen by the route conditions
+    if let Some(phase_handlers) = phase_handlers {
+        HttpServerCore::phase_handler(&phase_handlers.setvar, r);
+    }
+    let route = match routes.get(&key).or_else(|| routes.get(&key_default)).and_then(|routes| routes.route(r)) {
+        Some(route) => route,
+        None => return Expect::Continue
+    };
+    let continued = match route.expect_continue.unwrap_or(true) {
+        true => Expect::Continue,
+        false => Expect::Wait
+    };
+    if !route.context.rewrite.is_empty() || phase_handlers.map(|phase_handlers| !phase_handlers.rewrite.is_empty()).unwrap_or(false) {
+        // the rewrite may select another route, its access phase is run with the body
+        return continued;
+    }
+    let uri = r.uri().clone();
+    let mut rc = DECLINED;
+    if let Some(phase_handlers) = phase_handlers {
+        rc = HttpServerCore::phase_handler(&phase_handlers.access, r);
+    }
+    if rc == DECLINED {
+        rc = HttpServerCore::phase_handler(&route.context.access, r);
+    }
+    if uri != *r.uri() {
+        // redirected to another route, checked with the body
+        return Expect::Continue;
+    }
+    let rejected = rc == AGAIN || match r.take_context::<ContentHandler>("content") {
+        Some(content) => {
+            r.set_context("content", content);
+            true
+        },
+        None => false
+    };
+    r.set_context("expect", Checked { pattern: route.pattern.clone(), uri, rc });
+    match rejected {
+        true => Expect::Reject,
+        false => continued
+    }
+}
+
+// Body limit of the route selected by the headers, the rewrite may select another route
+pub (crate) fn max_body_size(r: &mut HttpRequest) -> Option<usize> {
+    let addr = r.const_context().server_addr;
+    let (routes, phases) = match (TABLES.read().unwrap().get(&addr).and_then(|tables| tables.first()), PHASES.read().unwrap().get(&addr)) {
+        (Some(routes), Some(phases)) => (Arc::clone(routes), Arc::clone(phases)),
+        _ => return None
+    };
+    let routes = routes.read().unwrap();
+    let phases = phases.read().unwrap();
+    let key = HttpServerCore::virtual_host(addr, r);
+    let key_default = (addr, "*".to_string());
+    if let Some(phase_handlers) = phases.get(&key).or_else(|| phases.get(&key_default)) {
+        HttpServerCore::phase_handler(&phase_handlers.setvar, r);
+    }
+    routes.get(&key).or_else(|| routes.get(&key_default))
+          .and_then(|routes| routes.route(r))
+          .and_then(|route| route.max_body_size)
+}
+
 pub struct HttpServerCore {
     server: HttpServer,
     routes: Arc<RwLock<HashMap<(SocketAddr, String), Routers>>>,
-    phase_handlers: Arc<RwLock<HashMap<(SocketAddr, String), ServerContext>>>
+    phase_handlers: Arc<RwLock<HashMap<(SocketAddr, String), ServerContext>>>,
+    listeners: Vec<SocketAddr>
 }
 
 impl HttpServerCore {
     pub fn new(
         worker_pool_size: usize,
         socket_poll_size: usize,
+        affinity: Affinity,
+        engine: Engine,
+        accept: Accept
     ) -> Result<HttpServerCore, CoreError> {
         let server = match HttpServer::new(worker_pool_size,
             socket_poll_size,
+            affinity,
+            engine,
+            accept,
             ContentHandler::new(|r| -> HttpResponse {
                 let mut resp = HttpResponse::new(r);
                 resp.send(HttpStatus::NO_CONTENT, "text/plain", None);
@@ -68,9 +421,20 @@ impl HttpServerCore {
             server: server,
             routes: Arc::new(RwLock::new(HashMap::new())),
             phase_handlers: Arc::new(RwLock::new(HashMap::new())),
+            listeners: Vec::new()
         })
     }
 
+    // Key of the virtual host serving the request, named captures of its regex are the variables
+    fn virtual_host(addr: SocketAddr, r: &mut HttpRequest) -> (SocketAddr, String) {
+        let matched = vhosts::find(&addr, r.host());
+        let name = matched.name.clone();
+        for (var, value) in matched.vars() {
+            r.add_var(&var, value);
+        }
+        (addr, name)
+    }
+
     fn phase_handler(phase_handlers: &LinkedList<RefHandler<HttpRequest, Code>>, r: &mut HttpRequest) -> Code {
         for handler in phase_handlers.iter() {
             match handler.handle(r) {
@@ -90,24 +454,69 @@ impl HttpServerCore {
         })
     }
 
+    // Path is routed, but not for the method of the request
+    fn not_allowed(allowed: Vec<&'static str>) -> ContentHandler {
+        ContentHandler::new(move |r| -> HttpResponse {
+            let mut resp = HttpResponse::new(r);
+            resp.set_header("Allow", &allowed.join(", "));
+            resp.send(HttpStatus::NOT_ALLOWED, "text/plain", Some(b"Method not allowed"));
+            resp
+        })
+    }
+
+    // auto_options: OPTIONS answered from the routes
+    fn options(allowed: Vec<&'static str>) -> ContentHandler {
+        ContentHandler::new(move |r| -> HttpResponse {
+            let mut resp = HttpResponse::new(r);
+            resp.set_header("Allow", &allowed.join(", "));
+            resp.send(HttpStatus::NO_CONTENT, "text/plain", None);
+            resp
+        })
+    }
+
+    // Responses of the authenticated routes must not be stored by shared caches
+    fn private_cache(resp: &mut HttpResponse) {
+        resp.set_header("Cache-Control", "private, no-store");
+        resp.remove_header("ETag");
+        resp.remove_header("Last-Modified");
+        resp.remove_header("Expires");
+    }
+
     pub fn add_server(
         &mut self,
         server: &ServerContext,
         handler: Option<ContentHandler>
     ) -> CoreResult {
         let addr = get_addr(&server.bind)?;
+
+        listen::bind(addr, "http", server.shared)?;
+        limits::set(addr, server.virtual_host.as_deref().unwrap_or("*"), server.limits);
+        multipart::set(addr, server.multipart.clone());
+        spool::set(addr, server.spool.clone());
+        vhosts::add(addr, server.virtual_host.as_deref().unwrap_or("*"), server.default_server)?;
+        budget::attach(addr, &server.workgroup);
+        buffers::attach(addr, &server.workgroup);
+        #[cfg(feature = "async")]
+        crate::http::runtime::attach(addr, &server.workgroup);
+        let mut tables = TABLES.write().unwrap();
+        let tables = tables.entry(addr).or_default();
+        if !tables.iter().any(|routes| Arc::ptr_eq(routes, &self.routes)) {
+            tables.push(Arc::clone(&self.routes));
+        }
+        PHASES.write().unwrap().entry(addr).or_insert_with(|| Arc::clone(&self.phase_handlers));
         let routes = Arc::clone(&self.routes);
         let phase_handlers = Arc::clone(&self.phase_handlers);
         let key_default = (addr, "*".to_string());
         let server_ = server.clone();
 
         let code = self.server.add_server_handler(addr, ContentHandler::new(move |mut r| -> HttpResponse {
+            // released before the content handler, which may change the routes
             let guard = (
-                &* routes.read().unwrap(),
-                &* phase_handlers.read().unwrap()
+                routes.read().unwrap(),
+                phase_handlers.read().unwrap()
             );
 
-            let key = (addr, r.host().clone());
+            let key = HttpServerCore::virtual_host(addr, &mut r);
 
             let routes = match guard.0.get(&key) {
                 None => match guard.0.get(&key_default) {
@@ -125,45 +534,34 @@ impl HttpServerCore {
                 Some(phase_handlers) => Some(phase_handlers)
             };
 
+            let mut cycles = 0;
+
             loop {
-                let mut found = (None, None, None);
+                // rewrites may loop
+                cycles += 1;
+                if cycles > MAX_REWRITE_CYCLES {
+                    drop(guard);
+                    let mut resp = HttpResponse::new(r);
+                    resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(b"Rewrite cycle"));
+                    return resp;
+                }
 
-                if let Some(routes) = routes {
-                    if r.uri().starts_with("@") {
-                        if let Some(route) = routes.named.get(&r) {
-                            found.2 = Some(route);
-                        }
-                    } else if let Some(route) = routes.trie.get(&mut r) {
-                        match route {
-                            (route, true) => {
-                                // exact
-                                found.0 = Some(route);
-                            },
-                            (route, false) => {
-                                // partial
-                                match routes.regex.get(&mut r) {
-                                    Some(route) => found.1 = Some(route),
-                                    None => found.0 = Some(route)
-                                }
-                            }
-                        }
-                    } else {
-                        if let Some(route) = routes.regex.get(&mut r) {
-                            found.1 = Some(route);
-                        }
-                    }
+                // server variables are seen by the route conditions
+                if let Some(phase_handlers) = phase_handlers {
+                    HttpServerCore::phase_handler(&phase_handlers.setvar, &mut r);
                 }
 
                 let mut content_handler = None;
 
-                match found {
-                    /* (trie, regex, named) */
-                    (None, Some(route), None) | (Some(route), None, None) | (None, None, Some(route)) => {
+                match routes.and_then(|routes| routes.route(&mut r)) {
+                    Some(route) => {
+                        let endpoint = route.metrics.as_ref().or(server_.metrics.as_ref()).map(|endpoint| endpoint.name());
+                        let guard = inflight::track(endpoint.unwrap_or(&route.pattern), r.const_context().remote_addr(), r.uri());
+                        r.set_context("inflight", guard);
                         // phase handlers
                         let mut rc = DECLINED;
                         // rewrite
                         if let Some(phase_handlers) = phase_handlers {
-                            HttpServerCore::phase_handler(&phase_handlers.setvar, &mut r);
                             rc = HttpServerCore::phase_handler(&phase_handlers.rewrite, &mut r);
                             if rc == AGAIN {
                                 continue;
@@ -176,11 +574,17 @@ impl HttpServerCore {
                         }
                         // access
                         let uri = r.uri().clone();
-                        if let Some(phase_handlers) = phase_handlers {
-                            rc = HttpServerCore::phase_handler(&phase_handlers.access, &mut r);
-                        }
-                        if rc == DECLINED {
-                            rc = HttpServerCore::phase_handler(&route.context.access, &mut r);
+                        match r.take_context::<Checked>("expect") {
+                            // already passed before 100-continue
+                            Some(checked) if checked.pattern == route.pattern && checked.uri == uri => rc = checked.rc,
+                            _ => {
+                                if let Some(phase_handlers) = phase_handlers {
+                                    rc = HttpServerCore::phase_handler(&phase_handlers.access, &mut r);
+                                }
+                                if rc == DECLINED {
+                                    rc = HttpServerCore::phase_handler(&route.context.access, &mut r);
+                                }
+                            }
                         }
                         if rc == AGAIN {
                             if uri != *r.uri() {
@@ -188,8 +592,17 @@ impl HttpServerCore {
                                 continue;
                             }
                             content_handler = Some(HttpServerCore::unauthorized());
+                        } else if let Some(content) = r.take_context::<ContentHandler>("content") {
+                            // access handler has replaced the content, e.g. to reject the request
+                            content_handler = Some(content);
                         } else if let Some(content) = &route.content {
+                            // OPTIONS of the route with the content or the proxy is its own
                             content_handler = Some(content.clone());
+                        } else if server_.auto_options && route.method.is_none() && matches!(r.method(), HttpMethod::OPTIONS) {
+                            // route of any method without the content, the methods of the routes matching the uri
+                            if let Some(routes) = routes {
+                                content_handler = Some(HttpServerCore::options(routes.allowed(r.uri())));
+                            }
                         }
                         // server handlers
                         phase_handlers.map(|phase_handlers| {
@@ -199,12 +612,23 @@ impl HttpServerCore {
                         });
                         // header filter handlers
                         route.header_filter.iter().for_each(|h| r.add_header_filter(h.clone()));
+                        let private = route.private_cache.or(server_.private_cache).unwrap_or(route.auth || server_.auth);
+                        if private {
+                            r.add_header_filter(HeaderFilterHandler::new(HttpServerCore::private_cache));
+                        }
+                        if let Some(h) = security_headers::filter(route.security_headers.as_ref(), server_.security_headers.as_ref()) {
+                            r.add_header_filter(h);
+                        }
                         // body filter handlers
                         route.body_filter.iter().for_each(|h| r.add_body_filter(h.clone()));
                         // flush handlers
                         route.flush.iter().for_each(|h| r.add_flush(h.clone()));
                         // log handlers
                         route.context.log.iter().for_each(|h| r.add_log(h.clone()));
+                        if let Some(endpoint) = route.metrics.as_ref().or(server_.metrics.as_ref()) {
+                            let endpoint = Arc::clone(endpoint);
+                            r.add_log(LogHandler::new(move |resp| endpoint.log(resp)));
+                        }
                         // error_log
                         match &route.error_log {
                             Some(error_log) => r.set_error_log(error_log),
@@ -212,15 +636,20 @@ impl HttpServerCore {
                                 r.set_error_log(error_log)
                             }
                         }
+                        r.set_error_log_level(route.error_log_level.or(server_.error_log_level));
                     },
-                    (None, None, None) => {
+                    None => {
+                        let guard = inflight::track("-", r.const_context().remote_addr(), r.uri());
+                        r.set_context("inflight", guard);
                         if let Some(phase_handlers) = phase_handlers {
-                            HttpServerCore::phase_handler(&phase_handlers.setvar, &mut r);
                             if HttpServerCore::phase_handler(&phase_handlers.rewrite, &mut r) == AGAIN {
                                 continue;
                             }
                             if HttpServerCore::phase_handler(&phase_handlers.access, &mut r) == AGAIN {
                                 content_handler = Some(HttpServerCore::unauthorized());
+                            } else if let Some(content) = r.take_context::<ContentHandler>("content") {
+                                // e.g. external redirect of the rewrite
+                                content_handler = Some(content);
                             }
                             // server handlers
                             phase_handlers.header_filter.iter().for_each(|h| r.add_header_filter(h.clone()));
@@ -230,9 +659,21 @@ impl HttpServerCore {
                             if let Some(error_log) = &server_.error_log {
                                 r.set_error_log(error_log)
                             }
+                            r.set_error_log_level(server_.error_log_level);
                         }
-                    },
-                    _ => unreachable!()
+                        if let (None, Some(routes)) = (&content_handler, routes) {
+                            let options = server_.auto_options && matches!(r.method(), HttpMethod::OPTIONS);
+                            if r.uri() != "*" || options {
+                                let allowed = routes.allowed(r.uri());
+                                if !allowed.is_empty() {
+                                    content_handler = Some(match options {
+                                        true => HttpServerCore::options(allowed),
+                                        false => HttpServerCore::not_allowed(allowed)
+                                    });
+                                }
+                            }
+                        }
+                    }
                 }
 
                 return match content_handler {
@@ -258,7 +699,16 @@ impl HttpServerCore {
         server.request_timeout,
         server.response_timeout,
         server.keepalive_timeout,
-        server.keepalive_requests)?;
+        server.keepalive_requests,
+        server.limit_upload_rate,
+        server.shared,
+        server.client_header_timeout,
+        server.max_half_read_connections,
+        server.max_connections)?;
+
+        if !self.listeners.contains(&addr) {
+            self.listeners.push(addr);
+        }
 
         server.setvar.iter().for_each(|handler| {
             self.add_setvar_handler(&server.bind, server.virtual_host.clone(), handler.clone()).unwrap();
@@ -334,6 +784,18 @@ impl HttpServerCore {
         let addr = get_addr(bind)?;
         self.server.remove_listener(addr);
         self.server.remove_server_handler(addr);
+        limits::remove(addr);
+        multipart::remove(addr);
+        spool::remove(addr);
+        vhosts::remove(addr);
+        let mut tables = TABLES.write().unwrap();
+        if let Some(routes) = tables.get_mut(&addr) {
+            routes.retain(|routes| !Arc::ptr_eq(routes, &self.routes));
+            if routes.is_empty() {
+                tables.remove(&addr);
+                PHASES.write().unwrap().remove(&addr);
+            }
+        }
         Ok(OK)
     }
 
@@ -349,47 +811,33 @@ impl HttpServerCore {
         bind: &str,
         route: &RouteContext
     ) -> CoreResult {
-        let key = (get_addr(bind)?, route.host.clone().unwrap_or("*".to_string()));
-        let method = get_method(route.method);
-        let path = &route.pattern;
-        if let Ok(ref mut routes) = self.routes.write() {
-            if path.starts_with("~") {
-                routes.entry(key).or_default().regex.upsert(path.trim_start_matches("~ "), method, move |context, _| {
-                    context.copy(&route);
-                })?;
-            } else if path.starts_with("@") {
-                routes.entry(key).or_default().named.upsert(&path, method, move |context, _| {
-                    context.copy(&route);
-                })?;
-            } else if !path.is_empty() {
-                routes.entry(key).or_default().trie.upsert(&path, method, move |context, _| {
-                    context.copy(&route);
-                })?;
-            } else {
-                return throw!("Pattern required");
-            }
-            return Ok(OK);
-        }
-        unreachable!()
+        upsert_route(&self.routes, get_addr(bind)?, route)
     }
 
     pub fn remove_route(&mut self, bind: &str, host: Option<String>, path: &str, method: Option<HttpMethod>)
         -> Result<(), CoreError>
     {
-        let method = get_method(method);
         let key = (get_addr(bind)?, host.unwrap_or("*".to_string()));
-        if let Some(ref mut routes) = self.routes.write().unwrap().get_mut(&key) {
-            if path.starts_with("~") {
-                routes.regex.remove(path.trim_start_matches("~ "), method);
-            } else if path.starts_with("@") {
-                routes.named.remove(path, method);
-            } else {
-                routes.trie.remove(path, method);
-            }
-        }
+        take_route(&self.routes, &key, path, get_method(method));
         Ok(())
     }
 
+    // Stops accepting new connections, already accepted are served until stop
+    pub fn close_listeners(&mut self) {
+        for addr in self.listeners.drain(..) {
+            self.server.remove_listener(addr);
+            listen::unbind(addr, "http");
+        }
+    }
+
+    pub fn queue(&self) -> Arc<AtomicUsize> {
+        self.server.queue()
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.server.engine()
+    }
+
     pub fn stop(&mut self) {
         self.server.stop();
     }
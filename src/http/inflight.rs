@@ -0,0 +1,107 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::{ BTreeMap, HashMap };
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+
+struct Request {
+    route: String,
+    client: SocketAddr,
+    uri: String,
+    start: Instant
+}
+
+lazy_static! {
+    static ref INFLIGHT: Mutex<HashMap<u64, Request>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+// Request is in flight until the guard is dropped together with the request context
+pub struct InFlightGuard {
+    id: u64
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        INFLIGHT.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub fn track(route: &str, client: SocketAddr, uri: &str) -> InFlightGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    INFLIGHT.lock().unwrap().insert(id, Request {
+        route: route.to_string(),
+        client,
+        uri: uri.to_string(),
+        start: Instant::now()
+    });
+    InFlightGuard {
+        id
+    }
+}
+
+pub fn count() -> usize {
+    INFLIGHT.lock().unwrap().len()
+}
+
+pub fn set_draining(draining: bool) {
+    DRAINING.store(draining, Ordering::SeqCst)
+}
+
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+// Waits until all in flight requests are completed or timeout expired.
+// Returns number of requests still in flight.
+pub fn drain(timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let count = count();
+        if count == 0 || Instant::now() >= deadline {
+            return count;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// In flight requests grouped by route: count and age of the oldest one,
+// followed by the list of clients.
+pub fn report() -> String {
+    let now = Instant::now();
+    let inflight = INFLIGHT.lock().unwrap();
+
+    let mut routes: BTreeMap<&str, (usize, Duration)> = BTreeMap::new();
+    let mut oldest = Duration::from_millis(0);
+
+    for request in inflight.values() {
+        let age = now.duration_since(request.start);
+        let e = routes.entry(&request.route).or_insert((0, age));
+        e.0 += 1;
+        e.1 = e.1.max(age);
+        oldest = oldest.max(age);
+    }
+
+    let mut report = format!("draining: {}\ninflight: {}\noldest: {}ms\n",
+                             draining(), inflight.len(), oldest.as_millis());
+
+    for (route, (count, age)) in routes.iter() {
+        report.push_str(&format!("route {}: count {}, oldest {}ms\n", route, count, age.as_millis()));
+    }
+
+    let mut requests: Vec<&Request> = inflight.values().collect();
+    requests.sort_by_key(|request| request.start);
+
+    for request in requests {
+        report.push_str(&format!("client {}: {} {} {}ms\n",
+                                 request.client, request.route, request.uri, now.duration_since(request.start).as_millis()));
+    }
+
+    report
+}
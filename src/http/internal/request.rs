@@ -2,19 +2,66 @@
  * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
  */
 
-use percent_encoding::{ percent_decode, utf8_percent_encode, NON_ALPHANUMERIC };
+use percent_encoding::{ percent_decode, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC };
 use chrono::prelude::*;
-use std::time::Instant;
+use std::time::{ Duration, Instant };
+use std::io::{ ErrorKind, Write };
+use mio::net::TcpStream;
 
 use crate::client_context::ClientContext;
 use crate::http::error::HttpResult;
 use crate::http::*;
 use crate::keyval::Key;
 use crate::http::{ HttpMethod, HttpProtocol };
+use crate::http::limits::{ self, HttpLimits };
+use crate::http::cookie;
+use crate::http::http_server_core::{ self, Expect };
+use crate::http::spool::{ self, SpoolConfig, SpooledBody };
+use crate::core::budget::{ self, Category, Reservation };
+use crate::core::pool::{ FreeList, Recycle };
+use crate::bytes::Bytes;
 
 const CR: u8 = 0x0D;
 const LF: u8 = 0x0A;
 
+// Larger buffers are released instead of being kept in the free list
+const RECYCLE_BUFFER: usize = 4096;
+const RECYCLE_OBJECTS: usize = 256;
+
+// Unreserved characters of the formatted args are kept as is
+const QUERY: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+lazy_static! {
+    static ref CONTEXTS: FreeList<HttpRequestParseContext> = FreeList::new("request_context", RECYCLE_OBJECTS);
+    pub (crate) static ref HEADERS: FreeList<HttpHeaders> = FreeList::new("headers", RECYCLE_OBJECTS * 4);
+    static ref VARIABLES: FreeList<HttpVariables> = FreeList::new("variables", RECYCLE_OBJECTS);
+    static ref STRINGS: FreeList<String> = FreeList::new("strings", RECYCLE_OBJECTS * 4);
+}
+
+fn recycle_buffer(buf: &mut Vec<u8>) {
+    buf.clear();
+    buf.shrink_to(RECYCLE_BUFFER);
+}
+
+// Headers and args of requests and responses share the same free list
+pub (crate) fn take_headers() -> HttpHeaders {
+    HEADERS.take(HttpHeaders::default)
+}
+
+pub (crate) fn put_headers(headers: HttpHeaders) {
+    HEADERS.put(headers)
+}
+
+fn take_string() -> String {
+    STRINGS.take(String::new)
+}
+
+// Parsed value is copied to the string, so it keeps its buffer
+fn set_lossy(s: &mut String, value: &[u8]) {
+    s.clear();
+    s.push_str(&String::from_utf8_lossy(value));
+}
+
 #[derive(PartialEq, PartialOrd)]
 #[allow(non_camel_case_types)]
 enum HttpParseState {
@@ -33,6 +80,41 @@ enum HttpParseState {
     st_parsed
 }
 
+// Position in the chunked request body
+#[derive(PartialEq, Clone, Copy)]
+enum ChunkState {
+    Size,
+    Extension,
+    Data(usize),
+    DataEnd,
+    Trailer,
+    Done
+}
+
+// Lines of the chunked body end with CRLF, bare CR or LF is invalid
+fn chunk_line_end(cr: &mut bool, c: u8) -> bool {
+    let valid = *cr == (c == LF);
+    *cr = c == CR;
+    valid
+}
+
+// Hex digits of the chunk size, larger sizes overflow
+const MAX_CHUNK_SIZE_LENGTH: usize = 16;
+
+// Body buffer is grown as the data comes, the size sent by the client is not preallocated
+const MAX_BODY_PREALLOC: usize = 64 * 1024;
+
+// Body of the size fits the rest of max_body_size, zero is unlimited
+fn body_fits(max_body_size: usize, body_size: usize, size: usize) -> bool {
+    max_body_size == 0 || body_size.checked_add(size).map(|size| size <= max_body_size).unwrap_or(false)
+}
+
+// Only 'chunked' is decoded, the body of the other codings can't be framed
+fn chunked_only(value: &str) -> bool {
+    let mut codings = value.split(',').map(|coding| coding.trim()).filter(|coding| !coding.is_empty());
+    matches!((codings.next(), codings.next()), (Some(coding), None) if coding.eq_ignore_ascii_case("chunked"))
+}
+
 struct HttpRequestParseContext {
     state: HttpParseState,
     method: Vec<u8>,
@@ -41,7 +123,117 @@ struct HttpRequestParseContext {
     protocol: Vec<u8>,
     key: Option<Vec<u8>>,
     val: Option<Vec<u8>>,
-    expect_100_continue: bool
+    expect_100_continue: bool,
+    // rest of 100 Continue left by a short write, sent before anything else
+    interim: Vec<u8>,
+    // rejected before 100-continue, the body is not read and the connection is closed
+    body_skipped: bool,
+    // host of the absolute-form request target, the Host header must match it
+    authority: Option<String>,
+    host_header: bool,
+    chunked: bool,
+    chunk: ChunkState,
+    // chunk size or trailer line
+    chunk_line: Vec<u8>,
+    // CR of the line end of the chunked body, LF must follow it
+    chunk_cr: bool,
+    limits: Option<HttpLimits>,
+    // taken for the requests with body
+    spool: Option<SpoolConfig>,
+    header_line: usize,
+    headers_size: usize,
+    header_count: usize,
+    body_start: Option<Instant>,
+    reject: Option<HttpStatus>
+}
+
+impl HttpRequestParseContext {
+    fn reject(&mut self, status: HttpStatus, what: &str) -> HttpResult {
+        self.reject = Some(status);
+        http_throw!(what)
+    }
+
+    fn limits(&self) -> HttpLimits {
+        self.limits.unwrap_or_default()
+    }
+
+    // Body of the size is written to the temporary file
+    fn spooled(&self, size: usize) -> bool {
+        self.spool.as_ref().map(|spool| spool.exceeded(size)).unwrap_or(false)
+    }
+
+    // Checked on every byte, so the buffers never grow over the limits
+    fn check_request_line(&mut self) -> HttpResult {
+        let limits = self.limits();
+        let uri_length = self.uri.len() + self.query_string.len();
+        if limits.max_uri_length != 0 && uri_length > limits.max_uri_length {
+            return self.reject(HttpStatus::URI_TOO_LONG, "Request URI is too long");
+        }
+        let line_length = self.method.len() + uri_length + self.protocol.len();
+        if limits.max_request_line != 0 && line_length > limits.max_request_line {
+            return self.reject(HttpStatus::URI_TOO_LONG, "Request line is too long");
+        }
+        Ok(OK)
+    }
+
+    fn new() -> HttpRequestParseContext {
+        HttpRequestParseContext {
+            state: HttpParseState::st_unparsed,
+            method: Vec::with_capacity(16),
+            uri: Vec::with_capacity(128),
+            query_string: Vec::with_capacity(128),
+            protocol: Vec::with_capacity(8),
+            key: Some(Vec::with_capacity(16)),
+            val: None,
+            expect_100_continue: false,
+            interim: Vec::new(),
+            body_skipped: false,
+            authority: None,
+            host_header: false,
+            chunked: false,
+            chunk: ChunkState::Size,
+            chunk_line: Vec::with_capacity(16),
+            chunk_cr: false,
+            limits: None,
+            spool: None,
+            header_line: 0,
+            headers_size: 0,
+            header_count: 0,
+            body_start: None,
+            reject: None
+        }
+    }
+}
+
+impl Recycle for HttpRequestParseContext {
+    fn recycle(&mut self) {
+        self.state = HttpParseState::st_unparsed;
+        recycle_buffer(&mut self.method);
+        recycle_buffer(&mut self.uri);
+        recycle_buffer(&mut self.query_string);
+        recycle_buffer(&mut self.protocol);
+        match &mut self.key {
+            Some(key) => recycle_buffer(key),
+            None => self.key = Some(Vec::with_capacity(16))
+        }
+        self.val = None;
+        self.expect_100_continue = false;
+        self.interim.clear();
+        self.body_skipped = false;
+        self.authority = None;
+        self.host_header = false;
+        self.chunked = false;
+        self.chunk = ChunkState::Size;
+        recycle_buffer(&mut self.chunk_line);
+        self.chunk_cr = false;
+        self.limits = None;
+        self.spool = None;
+        self.header_line = 0;
+        self.headers_size = 0;
+        self.header_count = 0;
+        self.body_start = None;
+        self.reject = None;
+    }
 }
 
 pub (crate) struct HttpRequest {
@@ -57,20 +249,34 @@ pub (crate) struct HttpRequest {
 
     pub start: DateTime<Utc>,
     pub timer: Instant,
+    pub body_time: Duration,
 
     // parsed data
 
     pub content_length: Option<usize>,
     pub method: HttpMethod,
     pub protocol: HttpProtocol,
+    // of the absolute-form request target, http otherwise
+    pub scheme: &'static str,
     pub host: String,
     pub request_uri: String,
     pub uri: String,
     pub query_string: String,
     pub vars: HttpVariables,
     pub args: HttpQuery,
+    // args are changed by the handlers, the query string is not forwarded as is
+    pub args_modified: bool,
+    // application/x-www-form-urlencoded body
+    pub post_args: HttpQuery,
+    pub cookies: HttpCookies,
     pub headers: HttpHeaders,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<Bytes>,
+    // body over client_body_buffer_size
+    pub body_file: Option<SpooledBody>,
+
+    // memory accounted in the workgroup budget
+
+    pub memory: Option<Reservation>,
 
     // filters
 
@@ -137,31 +343,30 @@ impl std::fmt::Display for HttpMethod {
 
 impl HttpRequest {
     pub fn new(client: ClientContext) -> HttpRequest {
-        let host = format!("{}:{}", client.server_addr.ip(), client.server_addr.port());
+        let mut host = take_string();
+        let _ = std::fmt::Write::write_fmt(&mut host, format_args!("{}:{}", client.server_addr.ip(), client.server_addr.port()));
         HttpRequest {
-            context: HttpRequestParseContext {
-                state: HttpParseState::st_unparsed,
-                method: Vec::with_capacity(16),
-                uri: Vec::with_capacity(128),
-                query_string: Vec::with_capacity(128),
-                protocol: Vec::with_capacity(8),
-                key: Some(Vec::with_capacity(16)),
-                val: None,
-                expect_100_continue: false
-            },
+            context: CONTEXTS.take(HttpRequestParseContext::new),
             start: Utc::now(),
             timer: Instant::now(),
+            body_time: Duration::from_millis(0),
             content_length: None,
             method: HttpMethod::UNSUPPORTED,
             protocol: HttpProtocol::HTTP10,
+            scheme: "http",
             host: host,
-            uri: String::new(),
-            request_uri: String::new(),
-            query_string: String::new(),
-            vars: KeyVal::default(),
-            args: KeyVal::default(),
-            headers: KeyVal::default(),
+            uri: take_string(),
+            request_uri: take_string(),
+            query_string: take_string(),
+            vars: VARIABLES.take(HttpVariables::default),
+            args: take_headers(),
+            args_modified: false,
+            post_args: HttpQuery::default(),
+            cookies: HttpCookies::default(),
+            headers: take_headers(),
             body: None,
+            body_file: None,
+            memory: None,
             client: client,
             header_filter: LinkedList::new(),
             body_filter: LinkedList::new(),
@@ -170,6 +375,22 @@ impl HttpRequest {
         }
     }
 
+    // Returns parse context, maps and strings to the free lists
+    pub fn recycle(self) -> ClientContext {
+        CONTEXTS.put(self.context);
+        VARIABLES.put(self.vars);
+        put_headers(self.args);
+        put_headers(self.headers);
+        for s in [self.host, self.uri, self.request_uri, self.query_string] {
+            STRINGS.put(s);
+        }
+        // still shared body is released by its last owner
+        if let Some(Ok(body)) = self.body.map(Bytes::try_into_vec) {
+            self.client.buf.give_back(body);
+        }
+        self.client
+    }
+
     pub fn add_flush(&mut self, h: FlushHandler) {
         self.flush.push_back(h)
     }
@@ -190,15 +411,80 @@ impl HttpRequest {
         this.inner.context.state < HttpParseState::st_parsed
     }
 
+    pub fn headers_received(this: &crate::http::HttpRequest) -> bool {
+        this.inner.context.state >= HttpParseState::st_headers_end
+    }
+
+    pub fn reject_status(this: &crate::http::HttpRequest) -> Option<HttpStatus> {
+        this.inner.context.reject
+    }
+
+    pub fn body_skipped(this: &crate::http::HttpRequest) -> bool {
+        this.inner.context.body_skipped
+    }
+
+    pub fn write_pending(this: &crate::http::HttpRequest) -> bool {
+        !this.inner.context.interim.is_empty()
+    }
+
     pub fn parse(this: &mut crate::http::HttpRequest) -> HttpResult {
+        if this.inner.context.limits.is_none() {
+            this.inner.context.limits = Some(limits::default(&this.inner.client.server_addr));
+        }
+        if HttpRequest::send_interim(this)? == AGAIN {
+            return Ok(AGAIN);
+        }
         match HttpRequest::parse_request_line(this)? {
             OK => match HttpRequest::parse_headers(this)? {
                 OK => {
+                    let mut max_body_size = this.inner.context.limits().max_body_size;
+                    if this.inner.content_length.unwrap_or(0) != 0 || this.inner.context.chunked {
+                        if let Some(size) = http_server_core::max_body_size(this) {
+                            max_body_size = match max_body_size {
+                                0 => size,
+                                max_body_size => max_body_size.min(size)
+                            };
+                            // the chunks are checked against the limit of the route too
+                            if let Some(limits) = this.inner.context.limits.as_mut() {
+                                limits.max_body_size = max_body_size;
+                            }
+                        }
+                    }
+                    if !body_fits(max_body_size, 0, this.inner.content_length.unwrap_or(0)) {
+                        return this.inner.context.reject(HttpStatus::PAYLOAD_TOO_LARGE, "Request body is too large");
+                    }
+                    let body_size = this.inner.content_length.unwrap_or(0);
+                    if this.inner.context.spool.is_none() && (body_size != 0 || this.inner.context.chunked) {
+                        this.inner.context.spool = Some(spool::get(&this.inner.client.server_addr));
+                    }
+                    if this.inner.memory.is_none() {
+                        // headers and body are held in memory until the request is completed, except of the spooled body
+                        let size = this.inner.context.headers_size.saturating_add(match this.inner.context.spooled(body_size) {
+                            true => 0,
+                            false => body_size
+                        });
+                        match budget::reserve(&this.inner.client.server_addr, Category::Request, size) {
+                            Ok(memory) => this.inner.memory = memory,
+                            Err(err) => return this.inner.context.reject(HttpStatus::SERVICE_UNAVAILABLE, err.what())
+                        }
+                    }
                     if this.inner.context.expect_100_continue {
-                        this.inner.client.write(b"HTTP/1.1 100 Continue\r\ncontent-length: 0\r\n\r\n");
-                        this.inner.client.flush().or_else(|err| http_fatal!(err.what()))?;
                         this.inner.context.expect_100_continue = false;
-                        return Ok(AGAIN);
+                        match http_server_core::expect(this) {
+                            Expect::Continue => {
+                                this.inner.context.interim.extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+                                if HttpRequest::send_interim(this)? == AGAIN {
+                                    return Ok(AGAIN);
+                                }
+                            },
+                            Expect::Wait => {},
+                            Expect::Reject => {
+                                // the client may still send the body
+                                this.inner.context.body_skipped = true;
+                                this.inner.context.state = HttpParseState::st_parsed;
+                                return Ok(OK);
+                            }
+                        }
                     }
                     HttpRequest::read_body(this)
                 },
@@ -208,6 +494,27 @@ impl HttpRequest {
         }
     }
 
+    // Interim response goes to the socket directly, the buffer keeps the body received with the headers.
+    // AGAIN leaves the rest of it until the socket is writable.
+    fn send_interim(this: &mut crate::http::HttpRequest) -> HttpResult {
+        let inner = &mut this.inner;
+        while !inner.context.interim.is_empty() {
+            let stream: &mut TcpStream = &mut inner.client;
+            match stream.write(&inner.context.interim) {
+                Ok(0) => return http_fatal!("Client closed connection on 100 Continue"),
+                Ok(sz) => {
+                    inner.context.interim.drain(..sz);
+                },
+                Err(err) => match err.kind() {
+                    ErrorKind::Interrupted => {},
+                    ErrorKind::WouldBlock => return Ok(AGAIN),
+                    _ => return http_fatal!("Failed to send 100 Continue: {}", err)
+                }
+            }
+        }
+        Ok(OK)
+    }
+
     pub fn parse_request_line(this: &mut crate::http::HttpRequest) -> HttpResult {
         match this.inner.parse_method()? {
             OK => match this.inner.parse_uri()? {
@@ -255,7 +562,10 @@ impl HttpRequest {
                         };
                         return Ok(OK);
                     },
-                    c => self.context.method.push(c)
+                    c => {
+                        self.context.method.push(c);
+                        self.context.check_request_line()?;
+                    }
                 }
             }
 
@@ -296,7 +606,10 @@ impl HttpRequest {
                         };
                         return Ok(OK);
                     },
-                    c => self.context.protocol.push(c)
+                    c => {
+                        self.context.protocol.push(c);
+                        self.context.check_request_line()?;
+                    }
                 }
             }
             read_more!(client, "Client has closed connection on read request line");
@@ -307,17 +620,54 @@ impl HttpRequest {
         String::from(percent_decode(&s).decode_utf8_lossy())
     }
 
+    // Form fields, '+' stands for the space
+    fn parse_form(body: &[u8], args: &mut HttpQuery) {
+        for field in body.split(|c| *c == b'&').filter(|field| !field.is_empty()) {
+            let field: Vec<u8> = field.iter().map(|c| if *c == b'+' { b' ' } else { *c }).collect();
+            let (k, v) = match field.iter().position(|c| *c == b'=') {
+                Some(pos) => (&field[..pos], &field[pos + 1..]),
+                None => (&field[..], &b""[..])
+            };
+            args.add(&HttpRequest::url_decode(k), HttpRequest::url_decode(v));
+        }
+    }
+
     fn url_encode(s: &str) -> String {
-        utf8_percent_encode(s, NON_ALPHANUMERIC).to_string()
+        utf8_percent_encode(s, QUERY).to_string()
     }
 
+    // Raw pairs of the query string in order, 'a' without the value is ('a', '')
+    pub fn split_args(query_string: &str) -> impl Iterator<Item = (&str, &str)> {
+        query_string.split('&').filter(|field| !field.is_empty()).map(|field| {
+            match field.find('=') {
+                Some(pos) => (&field[..pos], &field[pos + 1..]),
+                None => (field, "")
+            }
+        })
+    }
+
+    // Unmodified args are the query string as sent,
+    // otherwise the args of the query string go first in their order and the added ones after them
     pub fn format_args(&self) -> String {
+        if !self.args_modified {
+            return self.query_string.clone();
+        }
+        let mut keys: Vec<Key> = Vec::with_capacity(self.args.len());
+        for (k, _) in HttpRequest::split_args(&self.query_string) {
+            let key = Key::from(HttpRequest::url_decode(k.as_bytes()));
+            if self.args.contains_key(&key) && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        let mut added: Vec<&Key> = self.args.keys().filter(|key| !keys.contains(key)).collect();
+        added.sort();
+        let keys: Vec<Key> = keys.iter().chain(added).cloned().collect();
         let mut args = Vec::with_capacity(self.args.len());
-        self.args.iter().for_each(|(k,v)| {
-            v.iter().for_each(|v| {
-                args.push(format!("{}={}", k, HttpRequest::url_encode(v)));
-            })
-        });
+        for key in keys.iter() {
+            for v in self.args[key].iter() {
+                args.push(format!("{}={}", HttpRequest::url_encode(key), HttpRequest::url_encode(v)));
+            }
+        }
         args.join("&")
     }
 
@@ -334,23 +684,108 @@ impl HttpRequest {
             while !client.buf.end() {
                 match client.buf.getc() {
                     b'?' => {
-                        self.uri = String::from_utf8_lossy(&self.context.uri).to_string();
+                        set_lossy(&mut self.uri, &self.context.uri);
+                        self.absolute_form()?;
+                        self.request_uri.clear();
+                        self.request_uri.push_str(&self.uri);
+                        self.normalize_uri()?;
                         self.context.state = HttpParseState::st_uri_end;
                         return Ok(OK);
                     },
                     b' ' => {
-                        self.uri = String::from_utf8_lossy(&self.context.uri).to_string();
-                        self.request_uri = self.uri.clone();
+                        set_lossy(&mut self.uri, &self.context.uri);
+                        self.absolute_form()?;
+                        self.request_uri.clear();
+                        self.request_uri.push_str(&self.uri);
+                        self.normalize_uri()?;
                         self.context.state = HttpParseState::st_query_end;
                         return Ok(OK);
                     },
-                    c => self.context.uri.push(c)
+                    c => {
+                        self.context.uri.push(c);
+                        self.context.check_request_line()?;
+                    }
                 }
             }
             read_more!(client, "Client has closed connection on read request line");
         }
     }
 
+    // http://host[:port]/path of the forward proxy requests, the host replaces the Host header
+    fn absolute_form(&mut self) -> HttpResult {
+        if self.uri.starts_with('/') || self.uri == "*" {
+            return Ok(OK);
+        }
+        let (scheme, rest) = match self.uri.find("://") {
+            Some(pos) => (self.uri[..pos].to_ascii_lowercase(), &self.uri[pos + 3..]),
+            None => return self.context.reject(HttpStatus::BAD_REQUEST, "Invalid request target")
+        };
+        self.scheme = match scheme.as_str() {
+            "http" => "http",
+            "https" => "https",
+            _ => return self.context.reject(HttpStatus::BAD_REQUEST, "Unsupported request target scheme")
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/")
+        };
+        if authority.is_empty() || authority.contains('@') {
+            return self.context.reject(HttpStatus::BAD_REQUEST, "Invalid request target authority");
+        }
+        self.host = authority.to_string();
+        self.context.authority = Some(authority.to_string());
+        self.uri = path.to_string();
+        Ok(OK)
+    }
+
+    // Decoded path with '.' and '..' resolved, so the routes and the file roots can't be bypassed,
+    // the request_uri is kept as sent
+    fn normalize_uri(&mut self) -> HttpResult {
+        if !self.uri.starts_with('/') {
+            return Ok(OK);
+        }
+        let decoded: Vec<u8> = percent_decode(self.uri.as_bytes()).collect();
+        if decoded.contains(&0) {
+            return self.context.reject(HttpStatus::BAD_REQUEST, "Invalid request URI");
+        }
+        let merge_slashes = self.context.limits().merge_slashes;
+        let mut segments: Vec<&[u8]> = Vec::with_capacity(8);
+        let mut trailing = false;
+        for segment in decoded[1..].split(|c| *c == b'/') {
+            trailing = true;
+            match segment {
+                b"." => {},
+                b".." => if segments.pop().is_none() {
+                    return self.context.reject(HttpStatus::BAD_REQUEST, "Request URI is out of the root");
+                },
+                b"" if merge_slashes => {},
+                segment => {
+                    segments.push(segment);
+                    trailing = false;
+                }
+            }
+        }
+        let mut uri = Vec::with_capacity(decoded.len());
+        for segment in segments.iter() {
+            uri.push(b'/');
+            uri.extend_from_slice(segment);
+        }
+        if trailing || uri.is_empty() {
+            uri.push(b'/');
+        }
+        set_lossy(&mut self.uri, &uri);
+        Ok(OK)
+    }
+
+    // Host with the default port of the scheme omitted
+    fn same_host(scheme: &str, a: &str, b: &str) -> bool {
+        let default_port = match scheme {
+            "https" => ":443",
+            _ => ":80"
+        };
+        a.strip_suffix(default_port).unwrap_or(a).eq_ignore_ascii_case(b.strip_suffix(default_port).unwrap_or(b))
+    }
+
     fn parse_args(&mut self) -> HttpResult {
         let client = &mut self.client;
 
@@ -363,57 +798,21 @@ impl HttpRequest {
         loop {
             while !client.buf.end() {
                 match client.buf.getc() {
-                    b'=' => {
-                        self.args = KeyVal::default();
-                        self.context.val = Some(Vec::with_capacity(16));
-                    },
                     b' ' => {
-                        if let Some(k) = &self.context.key {
-                            match &self.context.val {
-                                Some(v) => {
-                                    let k = HttpRequest::url_decode(&k);
-                                    let ll = self.args.entry(Key::from(k)).or_default();
-                                    ll.push_back(HttpRequest::url_decode(&v));
-                                    self.context.state = HttpParseState::st_query_end;
-                                    self.context.key = Some(Vec::with_capacity(64));
-                                    self.context.val = None;
-                                    self.query_string = String::from_utf8_lossy(&self.context.query_string).to_string();
-                                    self.request_uri = format!("{}?{}", self.uri, self.query_string);
-                                    return Ok(OK);
-                                },
-                                None => {
-                                    // No args
-                                    self.context.state = HttpParseState::st_query_end;
-                                    self.context.key = Some(Vec::with_capacity(64));
-                                    self.context.val = None;
-                                    self.request_uri = self.uri.clone();
-                                    return Ok(OK);
-                                }
-                            }
-                        }
-                        return http_throw!("Invalid query string");
-                    },
-                    b'&' => {
-                        if let Some(k) = &self.context.key {
-                            if let Some(v) = &self.context.val {
-                                let key = HttpRequest::url_decode(&k);
-                                let ll = self.args.entry(Key::from(key)).or_default();
-                                ll.push_back(HttpRequest::url_decode(&v));
-                                self.context.key = Some(Vec::with_capacity(16));
-                                self.context.val = None;
-                                continue;
-                            }
+                        set_lossy(&mut self.query_string, &self.context.query_string);
+                        self.request_uri.push('?');
+                        self.request_uri.push_str(&self.query_string);
+                        for (k, v) in HttpRequest::split_args(&self.query_string) {
+                            self.args.add(&HttpRequest::url_decode(k.as_bytes()), HttpRequest::url_decode(v.as_bytes()));
                         }
-                        return http_throw!("Invalid query string");
+                        self.context.state = HttpParseState::st_query_end;
+                        self.context.key = Some(Vec::with_capacity(64));
+                        self.context.val = None;
+                        return Ok(OK);
                     },
                     c => {
-                        if let Some(ref mut v) = &mut self.context.val {
-                            v.push(c);
-                        } else if let Some(ref mut k) = &mut self.context.key {
-                            assert!(self.context.val.is_none());
-                            k.push(c);
-                        }
                         self.context.query_string.push(c);
+                        self.context.check_request_line()?;
                     }
                 }
             }
@@ -433,14 +832,47 @@ impl HttpRequest {
         let mut last = 0u8;
         let mut last_crlf = false;
 
+        let limits = this.inner.context.limits();
+
         loop {
             while !client.buf.end() {
-                match client.buf.getc() {
+                let c = client.buf.getc();
+                let context = &mut this.inner.context;
+                context.header_line += 1;
+                context.headers_size += 1;
+                if limits.max_header_line() != 0 && context.header_line > limits.max_header_line() {
+                    return context.reject(HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE, "Request header line is too large");
+                }
+                if limits.max_headers_size() != 0 && context.headers_size > limits.max_headers_size() {
+                    return context.reject(HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE, "Request headers are too large");
+                }
+                match c {
                     LF => {
                         if last != CR {
                             return http_throw!("Invalid header line");
                         }
                         if last_crlf {
+                            if let Some(values) = this.inner.headers.deref().get(&Key::from("cookie")) {
+                                for value in values.iter() {
+                                    cookie::parse(value, &mut this.inner.cookies);
+                                }
+                            }
+                            if this.inner.context.chunked {
+                                if this.inner.content_length.is_some() && limits.strict_framing {
+                                    return this.inner.context.reject(HttpStatus::BAD_REQUEST, "Both Transfer-Encoding and Content-Length are present");
+                                }
+                                // Transfer-Encoding overrides Content-Length, the length is known when the last chunk is read
+                                this.inner.content_length = None;
+                                this.inner.headers.remove("content-length");
+                            } else if let Some(len) = this.inner.content_length {
+                                // upstreams see the single length
+                                this.inner.headers.set("content-length", len.to_string());
+                            }
+                            if let (Some(authority), false) = (&this.inner.context.authority, this.inner.context.host_header) {
+                                this.inner.headers.add("Host", authority.clone());
+                            }
+                            // the virtual host is known, the body is checked by the limits of its server
+                            this.inner.context.limits = Some(limits::get(&this.inner.client.server_addr, &this.inner.host));
                             this.inner.context.state = HttpParseState::st_headers_end;
                             return Ok(OK)
                         }
@@ -459,21 +891,56 @@ impl HttpRequest {
                                 let value = unsafe { std::str::from_utf8_unchecked(&v) }.trim();
                                 match name.to_ascii_lowercase().as_str() {
                                     "content-length" => {
-                                        match value.parse::<usize>() {
-                                            Ok(len) => {
-                                                this.inner.content_length = Some(len)
-                                            },
-                                            Err(_) => return http_throw!("Invalid header line")
+                                        // '5, 5' is the same as two headers
+                                        for len in value.split(',') {
+                                            let len = match content_length(len.trim()) {
+                                                Some(len) => len,
+                                                None => return http_throw!("Invalid header line")
+                                            };
+                                            match this.inner.content_length {
+                                                Some(other) if other != len => {
+                                                    return this.inner.context.reject(HttpStatus::BAD_REQUEST, "Conflicting Content-Length headers");
+                                                },
+                                                Some(_) if limits.strict_framing => {
+                                                    return this.inner.context.reject(HttpStatus::BAD_REQUEST, "Duplicate Content-Length header");
+                                                },
+                                                _ => this.inner.content_length = Some(len)
+                                            }
+                                        }
+                                    },
+                                    "transfer-encoding" => {
+                                        if this.inner.context.chunked && limits.strict_framing {
+                                            return this.inner.context.reject(HttpStatus::BAD_REQUEST, "Duplicate Transfer-Encoding header");
+                                        }
+                                        match chunked_only(value) {
+                                            true => this.inner.context.chunked = true,
+                                            false => return this.inner.context.reject(HttpStatus::METHOD_NOT_IMPLEMENTED, "Unsupported transfer encoding")
                                         }
                                     },
                                     "expect" if value.to_ascii_lowercase() == "100-continue" => {
                                         this.inner.context.expect_100_continue = true;
                                     },
-                                    "host" => this.inner.host = value.to_string(),
+                                    "host" => {
+                                        if this.inner.context.host_header {
+                                            return this.inner.context.reject(HttpStatus::BAD_REQUEST, "Duplicate Host header");
+                                        }
+                                        this.inner.context.host_header = true;
+                                        match &this.inner.context.authority {
+                                            Some(authority) => if !HttpRequest::same_host(this.inner.scheme, authority, value) {
+                                                return this.inner.context.reject(HttpStatus::BAD_REQUEST, "Host header conflicts with the request target");
+                                            },
+                                            None => this.inner.host = value.to_string()
+                                        }
+                                    },
                                     _ => { /* void */ }
                                 }
                                 let ll = this.inner.headers.entry(Key::from(name)).or_default();
                                 ll.push_back(value.to_string());
+                                this.inner.context.header_line = 0;
+                                this.inner.context.header_count += 1;
+                                if limits.max_header_count != 0 && this.inner.context.header_count > limits.max_header_count {
+                                    return this.inner.context.reject(HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE, "Too many request headers");
+                                }
                                 last = CR;
                                 this.inner.context.key = Some(Vec::with_capacity(64));
                                 this.inner.context.val = None;
@@ -512,11 +979,27 @@ impl HttpRequest {
 
         this.inner.context.state = HttpParseState::st_body;
 
-        if let Some(len) = this.inner.content_length {
-            if len > 0 {
+        let body_start = *this.inner.context.body_start.get_or_insert_with(Instant::now);
+
+        if this.inner.context.chunked {
+            match HttpRequest::read_chunked(this)? {
+                OK => {},
+                code => return Ok(code)
+            }
+        } else if let Some(len) = this.inner.content_length {
+            if this.inner.context.spooled(len) {
+                match HttpRequest::read_spooled(this, len)? {
+                    OK => {},
+                    code => return Ok(code)
+                }
+            } else if len > 0 {
                 loop {
                     match &mut this.inner.body {
-                        None => this.inner.body = Some(Vec::from(this.inner.client.buf.tail())),
+                        None => {
+                            let mut body = this.inner.client.buf.rent(std::cmp::min(len, MAX_BODY_PREALLOC));
+                            body.extend_from_slice(this.inner.client.buf.tail());
+                            this.inner.body = Some(Bytes::from(body));
+                        },
                         Some(ref mut body) => {
                             if body.len() == len {
                                 break;
@@ -534,7 +1017,246 @@ impl HttpRequest {
         }
 
         this.inner.context.state = HttpParseState::st_parsed;
+        this.inner.body_time = body_start.elapsed();
+
+        let form = match this.inner.headers.exact("content-type") {
+            Some(content_type) => content_type.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"),
+            None => false
+        };
+        if form {
+            if let Some(body) = &this.inner.body {
+                HttpRequest::parse_form(body, &mut this.inner.post_args);
+            }
+        }
+
+        Ok(OK)
+    }
+
+    fn body_size(&self) -> usize {
+        match &self.body_file {
+            Some(spooled) => spooled.size(),
+            None => self.body.as_ref().map(|body| body.len()).unwrap_or(0)
+        }
+    }
+
+    fn spool_body(&mut self) -> HttpResult {
+        let mut spooled = match SpooledBody::create(self.context.spool.as_ref().unwrap()) {
+            Ok(spooled) => spooled,
+            Err(err) => return self.context.reject(HttpStatus::INTERNAL_SERVER_ERROR, &format!("Failed to create body temp file: {}", err))
+        };
+        // already read part is moved to the file
+        if let Some(body) = self.body.take() {
+            spooled.write(&body).or_else(|err| http_fatal!("Failed to write body temp file: {}", err))?;
+            if let Ok(body) = body.try_into_vec() {
+                self.client.buf.give_back(body);
+            }
+        }
+        self.body_file = Some(spooled);
+        Ok(OK)
+    }
+
+    // Body over client_body_buffer_size goes to the temporary file as it is read
+    fn read_spooled(this: &mut crate::http::HttpRequest, len: usize) -> HttpResult {
+        let inner = &mut this.inner;
+
+        if inner.body_file.is_none() {
+            inner.spool_body()?;
+        }
+
+        let spooled = inner.body_file.as_mut().unwrap();
+
+        loop {
+            let data = inner.client.buf.chunk(len - spooled.size());
+            spooled.write(data).or_else(|err| http_fatal!("Failed to write body temp file: {}", err))?;
+            if spooled.size() == len {
+                return Ok(OK);
+            }
+            match inner.client.read() {
+                Ok(OK) => {},
+                Ok(AGAIN) => return Ok(AGAIN),
+                Err(err) => return http_fatal!(err.what()),
+                Ok(DECLINED) => return http_fatal!("Client has closed connection on read body")
+            }
+        }
+    }
+
+    // Decodes the chunked body, trailers are added to the request headers
+    fn read_chunked(this: &mut crate::http::HttpRequest) -> HttpResult {
+        let inner = &mut this.inner;
+        let limits = inner.context.limits();
+
+        loop {
+            while !inner.client.buf.end() {
+                if let ChunkState::Data(size) = inner.context.chunk {
+                    let read = match &mut inner.body_file {
+                        Some(spooled) => {
+                            let data = inner.client.buf.chunk(size);
+                            spooled.write(data).or_else(|err| http_fatal!("Failed to write body temp file: {}", err))?;
+                            data.len()
+                        },
+                        None => {
+                            if inner.body.is_none() {
+                                inner.body = Some(Bytes::from(inner.client.buf.rent(std::cmp::min(size, MAX_BODY_PREALLOC))));
+                            }
+                            let data = inner.client.buf.chunk(size);
+                            if let Some(body) = &mut inner.body {
+                                body.extend_from_slice(data);
+                            }
+                            data.len()
+                        }
+                    };
+                    inner.context.chunk = match size - read {
+                        0 => ChunkState::DataEnd,
+                        left => ChunkState::Data(left)
+                    };
+                    continue;
+                }
+                let context = &mut inner.context;
+                let c = inner.client.buf.getc();
+                if !chunk_line_end(&mut context.chunk_cr, c) {
+                    return http_throw!("Invalid chunk line end");
+                }
+                match (context.chunk, c) {
+                    (_, CR) => { /* skip */ },
+                    (ChunkState::Size, LF) | (ChunkState::Extension, LF) => {
+                        let size = usize::from_str_radix(unsafe {
+                            std::str::from_utf8_unchecked(&context.chunk_line)
+                        }, 16).or_else(|_| http_throw!("Invalid chunk size"))?;
+                        context.chunk_line.clear();
+                        if size == 0 {
+                            context.chunk = ChunkState::Trailer;
+                            continue;
+                        }
+                        if !body_fits(limits.max_body_size, inner.body_size(), size) {
+                            return inner.context.reject(HttpStatus::PAYLOAD_TOO_LARGE, "Request body is too large");
+                        }
+                        let body_size = inner.body_size().saturating_add(size);
+                        if inner.context.spooled(body_size) {
+                            if inner.body_file.is_none() {
+                                inner.spool_body()?;
+                            }
+                        } else if let Some(memory) = &mut inner.memory {
+                            if let Err(err) = memory.grow(size) {
+                                return inner.context.reject(HttpStatus::SERVICE_UNAVAILABLE, err.what());
+                            }
+                        }
+                        inner.context.chunk = ChunkState::Data(size);
+                    },
+                    (ChunkState::Size, b';') => context.chunk = ChunkState::Extension,
+                    (ChunkState::Size, c) => {
+                        if !c.is_ascii_hexdigit() || context.chunk_line.len() == MAX_CHUNK_SIZE_LENGTH {
+                            return http_throw!("Invalid chunk size");
+                        }
+                        context.chunk_line.push(c);
+                    },
+                    (ChunkState::Extension, _) => { /* ignored */ },
+                    (ChunkState::DataEnd, LF) => context.chunk = ChunkState::Size,
+                    (ChunkState::DataEnd, _) => return http_throw!("Invalid chunk end"),
+                    (ChunkState::Trailer, LF) => {
+                        if context.chunk_line.is_empty() {
+                            context.chunk = ChunkState::Done;
+                            break;
+                        }
+                        let line = String::from_utf8_lossy(&context.chunk_line).into_owned();
+                        context.chunk_line.clear();
+                        let (name, value) = match line.find(':') {
+                            Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                            None => return http_throw!("Invalid trailer line")
+                        };
+                        context.header_count += 1;
+                        if limits.max_header_count != 0 && context.header_count > limits.max_header_count {
+                            return context.reject(HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE, "Too many request headers");
+                        }
+                        match name.to_ascii_lowercase().as_str() {
+                            // framing and routing are defined by the headers only
+                            "content-length" | "transfer-encoding" | "host" => { /* void */ },
+                            _ => inner.headers.add(name, value.to_string())
+                        }
+                    },
+                    (ChunkState::Trailer, c) => {
+                        context.headers_size += 1;
+                        if limits.max_headers_size() != 0 && context.headers_size > limits.max_headers_size() {
+                            return context.reject(HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE, "Request headers are too large");
+                        }
+                        context.chunk_line.push(c);
+                    },
+                    (ChunkState::Data(_), _) | (ChunkState::Done, _) => unreachable!()
+                }
+            }
+            if inner.context.chunk == ChunkState::Done {
+                break;
+            }
+            match inner.client.read() {
+                Ok(OK) => {},
+                Ok(AGAIN) => return Ok(AGAIN),
+                Err(err) => return http_fatal!(err.what()),
+                Ok(DECLINED) => return http_fatal!("Client has closed connection on read body")
+            }
+        }
+
+        // handlers see the decoded body as the one with the length
+        let len = inner.body_size();
+        inner.content_length = Some(len);
+        inner.headers.remove("transfer-encoding");
+        inner.headers.set("content-length", len.to_string());
 
         Ok(OK)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn body_size() {
+        assert!(body_fits(0, 0, 99999999999));
+        assert!(body_fits(1024, 0, 1024));
+        assert!(!body_fits(1024, 0, 1025));
+        assert!(body_fits(1024, 1000, 24));
+        assert!(!body_fits(1024, 1000, 25));
+        // chunk size of 16 hex digits
+        assert!(!body_fits(1024, 1, std::usize::MAX));
+        assert!(!body_fits(1024 * 1024, 0, usize::from_str_radix("ffffffffffffffff", 16).unwrap()));
+    }
+
+    #[test]
+    fn content_lengths() {
+        assert_eq!(content_length("0"), Some(0));
+        assert_eq!(content_length("1024"), Some(1024));
+        assert_eq!(content_length("+5"), None);
+        assert_eq!(content_length("-5"), None);
+        assert_eq!(content_length("5 "), None);
+        assert_eq!(content_length(""), None);
+        assert_eq!(content_length("0x10"), None);
+        assert_eq!(content_length("99999999999999999999999"), None);
+    }
+
+    #[test]
+    fn chunk_line_ends() {
+        let mut cr = false;
+        assert!(chunk_line_end(&mut cr, b'a'));
+        assert!(chunk_line_end(&mut cr, CR));
+        assert!(chunk_line_end(&mut cr, LF));
+        // bare LF
+        assert!(!chunk_line_end(&mut cr, LF));
+        // bare CR
+        let mut cr = false;
+        assert!(chunk_line_end(&mut cr, CR));
+        assert!(!chunk_line_end(&mut cr, b'0'));
+        let mut cr = false;
+        assert!(chunk_line_end(&mut cr, CR));
+        assert!(!chunk_line_end(&mut cr, CR));
+    }
+
+    #[test]
+    fn transfer_encoding() {
+        assert!(chunked_only("chunked"));
+        assert!(chunked_only(" Chunked "));
+        assert!(!chunked_only("gzip, chunked"));
+        assert!(!chunked_only("chunked, chunked"));
+        assert!(!chunked_only("chunked, gzip"));
+        assert!(!chunked_only("gzip"));
+        assert!(!chunked_only(""));
+    }
+}
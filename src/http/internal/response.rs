@@ -3,18 +3,40 @@
  */
 
 use std::fs::File;
-use std::io::{ ErrorKind, prelude::* };
 use std::collections::HashMap;
 use regex::Regex;
 use std::mem::take;
+use std::time::{ Duration, Instant };
 
 use crate::http::error::HttpResult;
 use crate::error::{ CoreResult, FlushResult, Flush };
 use crate::http::*;
 use crate::http::{ HttpStatus, HttpProtocol };
+use crate::core::budget::{ self, Category, Reservation };
+use crate::http::internal::request::take_headers;
+use crate::variable::LazyHandler;
+use crate::bytes::Bytes;
+use crate::module::Deferred;
 
 const CRLF: &[u8] = &[ 0x0d, 0x0a ];
 
+// Chunk size in hex followed by CRLF
+fn chunk_line(line: &mut [u8; 18], size: usize) -> &[u8] {
+    const HEX: &[u8] = b"0123456789abcdef";
+    let mut pos = 16;
+    let mut size = size;
+    loop {
+        pos -= 1;
+        line[pos] = HEX[size & 0xf];
+        size >>= 4;
+        if size == 0 {
+            break;
+        }
+    }
+    line[16..].copy_from_slice(CRLF);
+    &line[pos..]
+}
+
 lazy_static! {
     static ref MIME: HashMap<&'static str, &'static str> = {
         let mut map = HashMap::new();
@@ -139,6 +161,9 @@ lazy_static! {
     };
 }
 
+// Larger bodies are not queued to the connection at once
+const LARGE_BODY_SIZE: usize = 262144;
+
 macro_rules! headers_already_sent {
     ($f:literal) => { log_error!("warn", "$f: Headers already sent") }
 }
@@ -147,13 +172,29 @@ pub (crate) struct HttpResponse {
     pub protocol: HttpProtocol,
     pub status: HttpStatus,
     pub headers: HttpHeaders,
+    // sent after the last chunk
+    pub trailers: HttpHeaders,
     pub content_length: Option<usize>,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<Bytes>,
+    memory: Option<Reservation>,
     pub transfer_encoding: TransferEncoding,
-    file: Option<File>,
+    source: Option<Box<dyn BodySource>>,
+    stream: Option<BodyStream>,
     closed: bool,
+    // HEAD: headers are sent as for GET, the body is dropped
+    head: bool,
     headers_sent: bool,
-    body_sent: bool
+    body_sent: bool,
+    pub limit_rate: usize,
+    pub limit_rate_after: usize,
+    // written to the connection, the headers included
+    pub sent: usize,
+    pub header_size: usize,
+    rate_timer: Option<Instant>,
+    send_start: Option<Instant>,
+    send_time: Option<Duration>,
+    // completes the response outside of the worker, e.g. on the async runtime
+    pub deferred: Option<Deferred<crate::http::HttpResponse>>
 }
 
 impl From<i64> for HttpStatus {
@@ -183,8 +224,12 @@ impl From<i64> for HttpStatus {
             408 => HttpStatus::REQUEST_TIMEOUT,
             409 => HttpStatus::CONFLICT,
             410 => HttpStatus::GONE,
+            413 => HttpStatus::PAYLOAD_TOO_LARGE,
+            414 => HttpStatus::URI_TOO_LONG,
+            415 => HttpStatus::UNSUPPORTED_MEDIA_TYPE,
             426 => HttpStatus::UPGRADE_REQUIRED,
             429 => HttpStatus::TOO_MANY_REQUESTS,
+            431 => HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE,
             444 => HttpStatus::CLOSE,
             451 => HttpStatus::ILLEGAL,
             500 => HttpStatus::INTERNAL_SERVER_ERROR,
@@ -199,49 +244,60 @@ impl From<i64> for HttpStatus {
     }
 }
 
-impl std::fmt::Display for HttpStatus {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl HttpStatus {
+    // Code and reason of the status line
+    pub fn as_str(&self) -> &'static str {
         match self {
-            HttpStatus::UNDEFINED => write!(f, "0 UNDEFINED"),
-            HttpStatus::CONTINUE => write!(f, "100 CONTINUE"),
-            HttpStatus::SWITCHING_PROTOCOLS => write!(f, "101 SWITCHING PROTOCOLS"),
-            HttpStatus::OK => write!(f, "200 OK"),
-            HttpStatus::CREATED => write!(f, "201 CREATED"),
-            HttpStatus::ACCEPTED => write!(f, "202 ACCEPTED"),
-            HttpStatus::NO_CONTENT => write!(f, "204 NO CONTENT"),
-            HttpStatus::PARTIAL_CONTENT => write!(f, "206 PARTIAL CONTENT"),
-            HttpStatus::SPECIAL_RESPONSE => write!(f, "300 SPECIAL RESPONSE"),
-            HttpStatus::MOVED_PERMANENTLY => write!(f, "301 MOVED PERMANENTLY"),
-            HttpStatus::MOVED_TEMPORARILY => write!(f, "302 MOVED TEMPORARILY"),
-            HttpStatus::SEE_OTHER => write!(f, "303 SEE OTHER"),
-            HttpStatus::NOT_MODIFIED => write!(f, "304 NOT MODIFIED"),
-            HttpStatus::TEMPORARY_REDIRECT => write!(f, "307 TEMPORARY REDIRECT"),
-            HttpStatus::PERMANENT_REDIRECT => write!(f, "308 PERMANENT REDIRECT"),
-            HttpStatus::BAD_REQUEST => write!(f, "400 BAD REQUEST"),
-            HttpStatus::UNAUTHORIZED => write!(f, "401 UNAUTHORIZED"),
-            HttpStatus::PAYMENT_REQUIRED => write!(f, "402 PAYMENT REQUIRED"),
-            HttpStatus::FORBIDDEN => write!(f, "403 FORBIDDEN"),
-            HttpStatus::NOT_FOUND => write!(f, "404 NOT FOUND"),
-            HttpStatus::NOT_ALLOWED => write!(f, "405 NOT ALLOWED"),
-            HttpStatus::NOT_ACCEPTABLE => write!(f, "406 NOT ACCEPTABLE"),
-            HttpStatus::REQUEST_TIMEOUT => write!(f, "408 REQUEST TIMEOUT"),
-            HttpStatus::CONFLICT => write!(f, "409 CONFLICT"),
-            HttpStatus::GONE => write!(f, "410 GONE"),
-            HttpStatus::UPGRADE_REQUIRED => write!(f, "426 UPGRADE REQUIRED"),
-            HttpStatus::TOO_MANY_REQUESTS => write!(f, "429 TOO MANY REQUESTS"),
-            HttpStatus::CLOSE => write!(f, "444 CLOSE"),
-            HttpStatus::ILLEGAL => write!(f, "451 ILLEGAL"),
-            HttpStatus::INTERNAL_SERVER_ERROR => write!(f, "500 INTERNAL SERVER ERROR"),
-            HttpStatus::METHOD_NOT_IMPLEMENTED => write!(f, "501 METHOD NOT IMPLEMENTED"),
-            HttpStatus::BAD_GATEWAY => write!(f, "502 BAD GATEWAY"),
-            HttpStatus::SERVICE_UNAVAILABLE => write!(f, "503 SERVICE UNAVAILABLE"),
-            HttpStatus::GATEWAY_TIMEOUT => write!(f, "504 GATEWAY TIMEOUT"),
-            HttpStatus::VERSION_NOT_SUPPORTED => write!(f, "505 VERSION NOT SUPPORTED"),
-            HttpStatus::INSUFFICIENT_STORAGE => write!(f, "507 INSUFFICIENT STORAGE")
+            HttpStatus::UNDEFINED => "0 UNDEFINED",
+            HttpStatus::CONTINUE => "100 CONTINUE",
+            HttpStatus::SWITCHING_PROTOCOLS => "101 SWITCHING PROTOCOLS",
+            HttpStatus::OK => "200 OK",
+            HttpStatus::CREATED => "201 CREATED",
+            HttpStatus::ACCEPTED => "202 ACCEPTED",
+            HttpStatus::NO_CONTENT => "204 NO CONTENT",
+            HttpStatus::PARTIAL_CONTENT => "206 PARTIAL CONTENT",
+            HttpStatus::SPECIAL_RESPONSE => "300 SPECIAL RESPONSE",
+            HttpStatus::MOVED_PERMANENTLY => "301 MOVED PERMANENTLY",
+            HttpStatus::MOVED_TEMPORARILY => "302 MOVED TEMPORARILY",
+            HttpStatus::SEE_OTHER => "303 SEE OTHER",
+            HttpStatus::NOT_MODIFIED => "304 NOT MODIFIED",
+            HttpStatus::TEMPORARY_REDIRECT => "307 TEMPORARY REDIRECT",
+            HttpStatus::PERMANENT_REDIRECT => "308 PERMANENT REDIRECT",
+            HttpStatus::BAD_REQUEST => "400 BAD REQUEST",
+            HttpStatus::UNAUTHORIZED => "401 UNAUTHORIZED",
+            HttpStatus::PAYMENT_REQUIRED => "402 PAYMENT REQUIRED",
+            HttpStatus::FORBIDDEN => "403 FORBIDDEN",
+            HttpStatus::NOT_FOUND => "404 NOT FOUND",
+            HttpStatus::NOT_ALLOWED => "405 NOT ALLOWED",
+            HttpStatus::NOT_ACCEPTABLE => "406 NOT ACCEPTABLE",
+            HttpStatus::REQUEST_TIMEOUT => "408 REQUEST TIMEOUT",
+            HttpStatus::CONFLICT => "409 CONFLICT",
+            HttpStatus::GONE => "410 GONE",
+            HttpStatus::PAYLOAD_TOO_LARGE => "413 PAYLOAD TOO LARGE",
+            HttpStatus::URI_TOO_LONG => "414 URI TOO LONG",
+            HttpStatus::UNSUPPORTED_MEDIA_TYPE => "415 UNSUPPORTED MEDIA TYPE",
+            HttpStatus::UPGRADE_REQUIRED => "426 UPGRADE REQUIRED",
+            HttpStatus::TOO_MANY_REQUESTS => "429 TOO MANY REQUESTS",
+            HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE => "431 REQUEST HEADER FIELDS TOO LARGE",
+            HttpStatus::CLOSE => "444 CLOSE",
+            HttpStatus::ILLEGAL => "451 ILLEGAL",
+            HttpStatus::INTERNAL_SERVER_ERROR => "500 INTERNAL SERVER ERROR",
+            HttpStatus::METHOD_NOT_IMPLEMENTED => "501 METHOD NOT IMPLEMENTED",
+            HttpStatus::BAD_GATEWAY => "502 BAD GATEWAY",
+            HttpStatus::SERVICE_UNAVAILABLE => "503 SERVICE UNAVAILABLE",
+            HttpStatus::GATEWAY_TIMEOUT => "504 GATEWAY TIMEOUT",
+            HttpStatus::VERSION_NOT_SUPPORTED => "505 VERSION NOT SUPPORTED",
+            HttpStatus::INSUFFICIENT_STORAGE => "507 INSUFFICIENT STORAGE"
         }
     }
 }
 
+impl std::fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl HttpResponse {
     pub fn new(request: &HttpRequest) -> HttpResponse {
         HttpResponse {
@@ -249,12 +305,24 @@ impl HttpResponse {
             body_sent: false,
             transfer_encoding: TransferEncoding(0),
             content_length: None,
-            file: None,
-            closed: request.is_mailformed(),
+            source: None,
+            stream: None,
+            closed: request.is_mailformed() || request.body_skipped(),
+            head: matches!(request.method(), HttpMethod::HEAD),
             status: HttpStatus::OK,
             protocol: request.protocol(),
-            headers: HttpHeaders::default(),
-            body: None
+            headers: take_headers(),
+            trailers: take_headers(),
+            body: None,
+            memory: None,
+            limit_rate: 0,
+            limit_rate_after: 0,
+            sent: 0,
+            header_size: 0,
+            rate_timer: None,
+            send_start: None,
+            send_time: None,
+            deferred: None
         }
     }
 
@@ -273,9 +341,12 @@ impl HttpResponse {
         this.inner.transfer_encoding = TransferEncoding(0);
         this.inner.content_length = None;
         this.inner.body = None;
-        this.inner.file = None;
+        this.inner.memory = None;
+        this.inner.source = None;
+        this.inner.stream = None;
         this.inner.headers.clear();
-        this.inner.closed = false;
+        this.inner.trailers.clear();
+        this.inner.closed = this.request.is_mailformed() || this.request.body_skipped();
 
         this.context().reset();
     }
@@ -284,9 +355,23 @@ impl HttpResponse {
         HttpResponse::set_status(this, status);
         match text {
             Some(text) => {
+                // body is held in memory until it is sent
+                this.inner.memory = None;
+                let server_addr = this.context().server_addr;
+                let (status, content_type, text) = match budget::reserve(&server_addr, Category::Body, text.len()) {
+                    Ok(memory) => {
+                        this.inner.memory = memory;
+                        (status, content_type, text)
+                    },
+                    Err(_) => {
+                        log_error!("warn", "Response body of {} bytes exceeds memory budget, uri={}", text.len(), this.get_request().uri());
+                        (HttpStatus::SERVICE_UNAVAILABLE, "text/plain", &b"Service unavailable"[..])
+                    }
+                };
+                HttpResponse::set_status(this, status);
                 HttpResponse::set_content_type(this, content_type);
                 HttpResponse::set_content_length(this, text.len());
-                this.inner.body = Some(Vec::from(text));
+                this.inner.body = Some(Bytes::from(text));
             },
             None => {
                 match status {
@@ -306,7 +391,7 @@ impl HttpResponse {
 
         this.inner.content_length = None;
         this.inner.body = None;
-        this.inner.file = None;
+        this.inner.source = None;
     }
 
     pub fn send_no_content(this: &mut crate::http::HttpResponse) {
@@ -346,6 +431,14 @@ impl HttpResponse {
         this.inner.headers.add(name, value.to_string())
     }
 
+    pub fn add_trailer(this: &mut crate::http::HttpResponse, name: &str, value: &str) {
+        if this.inner.body_sent {
+            return log_error!("warn", "add_trailer: Body already sent");
+        }
+
+        this.inner.trailers.add(name, value.to_string())
+    }
+
     pub fn remove_header(this: &mut crate::http::HttpResponse, name: &str) {
         this.inner.headers.remove(name);
     }
@@ -364,34 +457,50 @@ impl HttpResponse {
     }
 
     pub fn send_body_chunk(this: &mut crate::http::HttpResponse, data: Option<&[u8]>) -> HttpResult {
+        HttpResponse::send_body_bytes(this, data.map(Bytes::from))
+    }
+
+    // Chunk is passed to the body filters and the connection without copying
+    pub fn send_body_bytes(this: &mut crate::http::HttpResponse, data: Option<Bytes>) -> HttpResult {
         if this.inner.body_sent {
             return http_throw!("send_body_chunk: Body already sent");
         }
 
         HttpResponse::flush_headers(this);
 
-        let mut body = match data {
-            Some(data) => Some(Vec::from(data)),
-            None => None
-        };
+        if this.inner.head {
+            return Ok(OK);
+        }
+
+        let chunk_size = data.as_ref().map(|data| data.len()).unwrap_or(0);
+        let mut body = data;
 
         this.request.inner.body_filter.iter().for_each(|h| {
             body = h.handle(body.take())
         });
 
         if this.inner.transfer_encoding.is_chunked() {
-            let chunk_size = match data {
-                Some(text) => text.len(),
-                None => 0
-            };
-            this.context().write_str(&format!("{:x}\r\n", chunk_size));
+            let mut line = [0u8; 18];
+            this.context().write(chunk_line(&mut line, chunk_size));
         }
 
         if let Some(body) = body {
-            this.context().write(&body);
+            this.context().write_bytes(body);
         }
 
         if this.inner.transfer_encoding.is_chunked() {
+            if chunk_size == 0 {
+                // last chunk
+                let client = &mut this.request.inner.client;
+                this.inner.trailers.iter().for_each(|(key, ll)| {
+                    ll.iter().for_each(|v| {
+                        client.write_str(key);
+                        client.write(b": ");
+                        client.write_str(v);
+                        client.write(CRLF);
+                    })
+                });
+            }
             this.context().write(CRLF);
         }
 
@@ -422,7 +531,7 @@ impl HttpResponse {
                         HttpResponse::set_status(this, HttpStatus::OK);
                         HttpResponse::set_content_length(this, m.len() as usize);
                         HttpResponse::set_content_type(this, &mime(&file));
-                        this.inner.file = Some(f);
+                        this.inner.source = Some(Box::new(f));
                         return Ok(OK);
                     },
                     Err(err) => {
@@ -440,6 +549,25 @@ impl HttpResponse {
         Ok(OK)
     }
 
+    // Body is produced by the stream until the end, chunked for HTTP/1.1
+    pub fn send_stream(this: &mut crate::http::HttpResponse, status: HttpStatus, content_type: &str, stream: BodyStream) {
+        HttpResponse::reset(this);
+        HttpResponse::set_status(this, status);
+        HttpResponse::set_content_type(this, content_type);
+        this.inner.stream = Some(stream);
+    }
+
+    // Body is pulled from the source chunk by chunk while the connection drains
+    pub fn send_source(this: &mut crate::http::HttpResponse, status: HttpStatus, content_type: &str, source: Box<dyn BodySource>) {
+        HttpResponse::reset(this);
+        HttpResponse::set_status(this, status);
+        HttpResponse::set_content_type(this, content_type);
+        if let Some(size) = source.size() {
+            HttpResponse::set_content_length(this, size);
+        }
+        this.inner.source = Some(source);
+    }
+
     fn flush_headers(this: &mut crate::http::HttpResponse) {
         if this.inner.headers_sent {
             return;
@@ -450,6 +578,8 @@ impl HttpResponse {
         match this.inner.protocol {
             HttpProtocol::HTTP11 => {
                 let connection = match this.request.headers().exact("connection") {
+                    // the unread body of the request is left on the connection
+                    _ if this.inner.closed => "close",
                     Some(connection) if connection.to_ascii_lowercase() == "close" => {
                         this.inner.closed = true;
                         "close"
@@ -489,6 +619,10 @@ impl HttpResponse {
                     }
                 }
                 HttpResponse::replace_header(this, "Transfer-Encoding", this.inner.transfer_encoding.format().as_ref().map(|v| v.as_str()));
+                if this.inner.transfer_encoding.is_chunked() && !this.inner.trailers.is_empty() {
+                    let names: Vec<String> = this.inner.trailers.keys().map(|key| key.to_string()).collect();
+                    HttpResponse::set_header(this, "Trailer", &names.join(", "));
+                }
             }
 
             take(&mut this.request.inner.header_filter).into_iter().for_each(|h| {
@@ -511,32 +645,54 @@ impl HttpResponse {
             }
         }
 
-        let mut headers = Vec::with_capacity(4096);
+        // serialized right into the connection buffer
+
+        let client = &mut this.request.inner.client;
+        let mut header_size = 0;
 
-        this.inner.headers.iter().for_each(|(key,ll)| {
+        client.write(match this.inner.protocol {
+            HttpProtocol::HTTP10 => b"HTTP/1.0 ",
+            HttpProtocol::HTTP11 => b"HTTP/1.1 "
+        });
+        client.write_str(this.inner.status.as_str());
+        client.write(CRLF);
+        header_size += 9 + this.inner.status.as_str().len() + 2;
+
+        this.inner.headers.iter().for_each(|(key, ll)| {
             ll.iter().for_each(|v| {
-                headers.extend_from_slice(format!("{}: {}\r\n", &key, &v).as_bytes());
+                client.write_str(key);
+                client.write(b": ");
+                client.write_str(v);
+                client.write(CRLF);
+                header_size += key.len() + 2 + v.len() + 2;
             })
         });
 
-        let status_line = format!("HTTP/{} {}\r\n", this.inner.protocol, this.inner.status);
-
-        this.context().write_str(&status_line);
-        this.context().write(&headers);
-        this.context().write(CRLF);
+        client.write(CRLF);
+        this.inner.header_size = header_size + 2;
 
         this.inner.headers_sent = true;
     }
 
     fn flush_body(this: &mut crate::http::HttpResponse) {
-        if this.inner.body_sent {
+        if this.inner.body_sent || this.inner.source.is_some() {
             return;
         }
 
         this.inner.body = match this.inner.body.take() {
+            // large body is written by slices as the connection drains
+            Some(body) if body.len() > LARGE_BODY_SIZE => {
+                HttpResponse::set_content_length(this, body.len());
+                HttpResponse::flush_headers(this);
+                // no body for 204, 304 and HEAD
+                if this.inner.content_length.is_some() && !this.inner.head {
+                    this.inner.source = Some(Box::new(body.clone()));
+                }
+                Some(body)
+            },
             Some(body) => {
                 HttpResponse::set_content_length(this, body.len());
-                HttpResponse::send_body_chunk(this, Some(&body)).unwrap();
+                HttpResponse::send_body_bytes(this, Some(body.clone())).unwrap();
                 this.inner.body_sent = true;
                 Some(body)
             },
@@ -547,41 +703,159 @@ impl HttpResponse {
         }
     }
 
-    fn flush_file(this: &mut crate::http::HttpResponse) -> CoreResult {
-        if let Some(ref mut file) = &mut this.inner.file {
-            let mut b = [0u8; 16384];
-            return match file.read(&mut b) {
-                Ok(0) => {
-                    this.inner.body_sent = true;
-                    Ok(OK)
+    // File goes to the socket by sendfile when the body is sent as is: without body filters and chunked encoding.
+    // None leaves the source to flush_source
+    fn flush_file(this: &mut crate::http::HttpResponse, limit: usize) -> Result<Option<Code>, CoreError> {
+        if this.inner.head || !this.request.inner.body_filter.is_empty() {
+            return Ok(None);
+        }
+        let mut source = match this.inner.source.take() {
+            Some(source) => source,
+            None => return Ok(None)
+        };
+        if source.file().is_none() {
+            this.inner.source = Some(source);
+            return Ok(None);
+        }
+        if !this.inner.headers_sent {
+            HttpResponse::flush_headers(this);
+            this.inner.source = Some(source);
+            // headers are flushed first
+            return Ok(match this.inner.transfer_encoding.is_chunked() {
+                true => None,
+                false => Some(OK)
+            });
+        }
+        if this.inner.transfer_encoding.is_chunked() || limit == 0 {
+            let chunked = this.inner.transfer_encoding.is_chunked();
+            this.inner.source = Some(source);
+            return Ok(match chunked {
+                true => None,
+                false => Some(OK)
+            });
+        }
+        let (code, sent) = this.context().sendfile(source.file().unwrap(), limit)?;
+        this.inner.sent += sent;
+        match (&code, sent) {
+            (OK, 0) => {
+                this.context().reset();
+                this.inner.body_sent = true;
+            },
+            _ => this.inner.source = Some(source)
+        }
+        Ok(Some(code))
+    }
+
+    fn flush_source(this: &mut crate::http::HttpResponse) -> CoreResult {
+        let mut source = match this.inner.source.take() {
+            Some(source) => source,
+            None => return Ok(OK)
+        };
+        if this.inner.head {
+            HttpResponse::flush_headers(this);
+            this.inner.body_sent = true;
+            return Ok(AGAIN);
+        }
+        match source.next_chunk() {
+            Ok(Some(chunk)) => {
+                this.inner.source = Some(source);
+                if !chunk.is_empty() {
+                    this.context().reset();
+                    HttpResponse::send_body_bytes(this, Some(chunk)).or_else(|err| throw!("Failed to send body: {}", err.what()))?;
                 }
-                Ok(sz) => {
+                Ok(AGAIN)
+            },
+            Ok(None) => {
+                this.context().reset();
+                // empty source, nothing has been sent yet
+                HttpResponse::flush_headers(this);
+                if this.inner.transfer_encoding.is_chunked() {
+                    // last chunk
+                    HttpResponse::send_body_chunk(this, Some(b"")).or_else(|err| throw!("Failed to send body: {}", err.what()))?;
+                }
+                this.inner.body_sent = true;
+                Ok(AGAIN)
+            },
+            Err(err) => throw!("Failed to read body source: {}", err)
+        }
+    }
+
+    fn flush_stream(this: &mut crate::http::HttpResponse) -> Result<Code, Duration> {
+        let mut stream = match this.inner.stream.take() {
+            Some(stream) => stream,
+            None => return Ok(OK)
+        };
+        if this.inner.head {
+            HttpResponse::flush_headers(this);
+            this.inner.body_sent = true;
+            return Ok(AGAIN);
+        }
+        match stream(this) {
+            StreamChunk::Data(data) => {
+                if !data.is_empty() {
                     this.context().reset();
-                    HttpResponse::send_body_chunk(this, Some(&b[..sz])).unwrap();
-                    Ok(AGAIN)
-                },
-                Err(err) => if err.kind() == ErrorKind::Interrupted {
-                    Ok(AGAIN)
-                } else {
-                    throw!("Failed to read file: {}", err)
+                    HttpResponse::send_body_chunk(this, Some(&data)).unwrap();
                 }
+                this.inner.stream = Some(stream);
+                Ok(AGAIN)
+            },
+            StreamChunk::Wait(delay) => {
+                this.inner.stream = Some(stream);
+                Err(delay)
+            },
+            StreamChunk::End => {
+                // last chunk
+                this.context().reset();
+                HttpResponse::send_body_chunk(this, Some(b"")).unwrap();
+                this.inner.body_sent = true;
+                Ok(AGAIN)
             }
         }
-        Ok(OK)
+    }
+
+    // Bytes allowed to be written now or delay until the next portion
+    fn rate_allowance(this: &mut crate::http::HttpResponse) -> Result<usize, Duration> {
+        if this.inner.limit_rate == 0 {
+            return Ok(usize::MAX);
+        }
+
+        let rate = this.inner.limit_rate as f64;
+        let elapsed = this.inner.rate_timer.get_or_insert_with(Instant::now).elapsed().as_secs_f64();
+        let portion = std::cmp::min(this.inner.limit_rate, 16384) as f64;
+        let allowed = this.inner.limit_rate_after as f64 + portion + rate * elapsed - this.inner.sent as f64;
+
+        match allowed >= portion / 4.0 {
+            true => Ok(allowed as usize),
+            false => Err(Duration::from_secs_f64((portion / 4.0 - allowed) / rate))
+        }
+    }
+
+    // Time from the first flush to the completion of the response
+    fn send_completed(this: &mut crate::http::HttpResponse) {
+        if let (Some(send_start), None) = (this.inner.send_start, this.inner.send_time) {
+            let send_time = send_start.elapsed();
+            this.inner.send_time = Some(send_time);
+            add_var_lazy!(this, "response_send_time", move |_| send_time.as_millis());
+        }
     }
 
     pub fn flush(this: &mut crate::http::HttpResponse) -> FlushResult  {
+        if this.inner.send_start.is_none() {
+            this.inner.send_start = Some(Instant::now());
+        }
+
         loop {
             match this.request.inner.flush.pop_front() {
                 Some(h) => {
                     let res = h.handle(this)?;
                     match res {
-                        Flush::AGAIN | Flush::READ_MORE(_) | Flush::WRITE_MORE(_) | Flush::READ_WRITE_MORE(_) => {
+                        Flush::AGAIN | Flush::DELAY(_) | Flush::READ_MORE(_) | Flush::WRITE_MORE(_) | Flush::READ_WRITE_MORE(_) => {
                             this.request.inner.flush.push_front(h);
                             return Ok(res);
                         },
                         Flush::OK(None) | Flush::DECLINED => {},
                         Flush::OK(_) => {
+                            HttpResponse::send_completed(this);
                             return Ok(res)
                         }
                     }
@@ -594,15 +868,34 @@ impl HttpResponse {
         }
 
         loop {
-            return match this.context().flush()?.0 {
+            let limit = match HttpResponse::rate_allowance(this) {
+                Ok(limit) => limit,
+                Err(delay) => return Ok(Flush::DELAY(delay))
+            };
+            let (code, sent) = this.context().flush_limited(limit)?;
+            this.inner.sent += sent;
+            return match code {
+                AGAIN if sent == limit => continue,
                 AGAIN => Ok(Flush::AGAIN),
                 OK => {
-                    match HttpResponse::flush_file(this)? {
+                    match HttpResponse::flush_file(this, limit - sent)? {
+                        Some(AGAIN) => return Ok(Flush::AGAIN),
+                        Some(_) => continue,
+                        None => {}
+                    }
+                    match HttpResponse::flush_source(this)? {
                         AGAIN => continue,
-                        OK => Ok(match this.inner.closed {
-                            false => Flush::OK(None),
-                            true => Flush::DECLINED
-                        }),
+                        OK => match HttpResponse::flush_stream(this) {
+                            Ok(AGAIN) => continue,
+                            Ok(_) => {
+                                HttpResponse::send_completed(this);
+                                Ok(match this.inner.closed {
+                                    false => Flush::OK(None),
+                                    true => Flush::DECLINED
+                                })
+                            },
+                            Err(delay) => Ok(Flush::DELAY(delay))
+                        },
                         DECLINED => unreachable!()
                     }
                 },
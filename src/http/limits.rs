@@ -0,0 +1,137 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+
+use crate::error::CoreError;
+use crate::http::vhosts;
+
+// 'client_max_body_size' of the servers without it, 0 turns the limit off
+pub const MAX_BODY_SIZE_DEFAULT: usize = 1024 * 1024;
+
+// Request limits checked by the parser, zero means unlimited
+#[derive(Clone, Copy, Default, Debug)]
+pub struct HttpLimits {
+    pub max_body_size: usize,
+    pub header_buffer_size: usize,
+    pub header_buffers: usize,
+    // length of the header line, header_buffer_size if not set
+    pub header_line_size: usize,
+    // all headers together, header_buffers * header_buffer_size if not set
+    pub headers_size: usize,
+    pub max_header_count: usize,
+    // path with the query string
+    pub max_uri_length: usize,
+    // method, URI and protocol
+    pub max_request_line: usize,
+    // '//' in the path is the same as '/', on for the configured servers
+    pub merge_slashes: bool,
+    // repeated Content-Length or Transfer-Encoding, or both of them, is rejected,
+    // on for the configured servers
+    pub strict_framing: bool
+}
+
+impl HttpLimits {
+    pub fn max_header_line(&self) -> usize {
+        match self.header_line_size {
+            0 => self.header_buffer_size,
+            size => size
+        }
+    }
+
+    // Total size of all headers
+    pub fn max_headers_size(&self) -> usize {
+        match self.headers_size {
+            0 => self.header_buffer_size * self.header_buffers,
+            size => size
+        }
+    }
+}
+
+lazy_static! {
+    // limits of the servers by the listener and the virtual host, '*' is the server without virtual_host
+    static ref LIMITS: RwLock<HashMap<SocketAddr, HashMap<String, HttpLimits>>> = RwLock::new(HashMap::new());
+}
+
+pub fn set(addr: SocketAddr, virtual_host: &str, limits: HttpLimits) {
+    LIMITS.write().unwrap().entry(addr).or_default().insert(virtual_host.to_string(), limits);
+}
+
+pub fn remove(addr: SocketAddr) {
+    LIMITS.write().unwrap().remove(&addr);
+}
+
+fn lookup(addr: &SocketAddr, virtual_host: &str) -> HttpLimits {
+    let limits = LIMITS.read().unwrap();
+    limits.get(addr)
+          .and_then(|servers| servers.get(virtual_host).or_else(|| servers.get("*")))
+          .cloned()
+          .unwrap_or(HttpLimits {
+              max_body_size: MAX_BODY_SIZE_DEFAULT,
+              ..Default::default()
+          })
+}
+
+// Request line and headers are read before the Host is known, they are checked by the limits of the default server
+pub fn default(addr: &SocketAddr) -> HttpLimits {
+    lookup(addr, &vhosts::default(addr))
+}
+
+// Body is checked by the limits of the server selected by the Host
+pub fn get(addr: &SocketAddr, host: &str) -> HttpLimits {
+    lookup(addr, &vhosts::find(addr, host).name)
+}
+
+// Size with optional 'k' or 'm' suffix
+pub fn parse_size(s: &str) -> Result<usize, CoreError> {
+    let s = s.trim().to_ascii_lowercase();
+    let (num, mult) = match s.chars().last() {
+        Some('k') => (&s[..s.len() - 1], 1024),
+        Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        _ => (&s[..], 1)
+    };
+    match num.parse::<usize>() {
+        Ok(n) => Ok(n * mult),
+        Err(_) => throw!("invalid size '{}'", s)
+    }
+}
+
+// 'number size', e.g. '4 8k'
+pub fn parse_buffers(s: &str) -> Result<(usize, usize), CoreError> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    if parts.len() != 2 {
+        return throw!("invalid value '{}', 'number size' expected", s);
+    }
+    Ok((parse_size(parts[0])?, parse_size(parts[1])?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn body(max_body_size: usize) -> HttpLimits {
+        HttpLimits { max_body_size, ..Default::default() }
+    }
+
+    #[test]
+    fn virtual_hosts() {
+        let addr: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        vhosts::add(addr, "*", false).unwrap();
+        vhosts::add(addr, "upload.example.com", false).unwrap();
+        vhosts::add(addr, "api.example.com", true).unwrap();
+        set(addr, "*", body(1));
+        set(addr, "upload.example.com", body(2));
+        set(addr, "api.example.com", body(3));
+        assert_eq!(get(&addr, "upload.example.com:80").max_body_size, 2);
+        assert_eq!(get(&addr, "api.example.com").max_body_size, 3);
+        // unknown host goes to the default server
+        assert_eq!(get(&addr, "other.example.com").max_body_size, 3);
+        assert_eq!(default(&addr).max_body_size, 3);
+        remove(addr);
+        vhosts::remove(addr);
+        assert_eq!(get(&addr, "api.example.com").max_body_size, MAX_BODY_SIZE_DEFAULT);
+    }
+}
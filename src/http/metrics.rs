@@ -0,0 +1,146 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{ Arc, RwLock };
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::core::status::{ self, Json };
+use crate::module::Response;
+use crate::http::HttpResponse;
+
+// Upper bounds of the request time buckets, milliseconds
+const BUCKETS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+// Counters of the logical endpoint named by 'metric_name', the routes of the same name share them
+#[derive(Default)]
+pub struct Endpoint {
+    name: String,
+    // by the status class, 1xx..5xx
+    requests: [AtomicU64; 5],
+    bytes_sent: AtomicU64,
+    time: AtomicU64,
+    // requests not longer than the bucket, the last one is +Inf
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    // TCP_INFO of the client connection when the response is logged: rtt in microseconds and its samples,
+    // segments retransmitted during the responses
+    rtt: AtomicU64,
+    rtt_samples: AtomicU64,
+    retrans: AtomicU64
+}
+
+impl Endpoint {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn log(&self, resp: &mut HttpResponse) {
+        let class = (resp.status() as usize / 100).clamp(1, 5) - 1;
+        let time = resp.get_request().request_time();
+        self.requests[class].fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(resp.bytes_sent() as u64, Ordering::Relaxed);
+        self.time.fetch_add(time, Ordering::Relaxed);
+        let bucket = BUCKETS.iter().position(|le| time <= *le).unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let client = resp.context();
+        if let Ok(info) = client.tcp_info() {
+            self.rtt.fetch_add(info.rtt as u64, Ordering::Relaxed);
+            self.rtt_samples.fetch_add(1, Ordering::Relaxed);
+            if let Some(state) = client.inner.as_mut() {
+                self.retrans.fetch_add(state.retransmitted(info.retrans) as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.requests.iter().map(|requests| requests.load(Ordering::Relaxed)).sum()
+    }
+
+    fn report(&self) -> Json {
+        let mut fields = vec![("requests", self.count().into())];
+        let classes = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+        fields.extend(classes.iter().zip(self.requests.iter()).map(|(class, requests)| (*class, requests.load(Ordering::Relaxed).into())));
+        fields.push(("bytes_sent", self.bytes_sent.load(Ordering::Relaxed).into()));
+        fields.push(("request_time", self.time.load(Ordering::Relaxed).into()));
+        fields.push(("tcp_rtt", self.rtt.load(Ordering::Relaxed).into()));
+        fields.push(("tcp_rtt_samples", self.rtt_samples.load(Ordering::Relaxed).into()));
+        fields.push(("tcp_retransmits", self.retrans.load(Ordering::Relaxed).into()));
+        Json::object(fields)
+    }
+}
+
+lazy_static! {
+    static ref ENDPOINTS: RwLock<BTreeMap<String, Arc<Endpoint>>> = RwLock::new(BTreeMap::new());
+}
+
+// Endpoint of the name is created once, the servers and the routes of the same name share it
+pub fn endpoint(name: &str) -> Arc<Endpoint> {
+    let mut endpoints = ENDPOINTS.write().unwrap();
+    if let Some(endpoint) = endpoints.get(name) {
+        return Arc::clone(endpoint);
+    }
+    let endpoint = Arc::new(Endpoint {
+        name: name.to_string(),
+        ..Default::default()
+    });
+    endpoints.insert(name.to_string(), Arc::clone(&endpoint));
+    let name_ = name.to_string();
+    status::register("endpoints", name, Box::new(move || {
+        ENDPOINTS.read().unwrap().get(&name_).map(|endpoint| endpoint.report())
+    }));
+    endpoint
+}
+
+fn label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Prometheus text format of all the endpoints
+pub fn prometheus() -> String {
+    let endpoints = ENDPOINTS.read().unwrap();
+    let mut text = String::new();
+    text.push_str("# HELP http_requests_total Requests by the endpoint and the status class.\n");
+    text.push_str("# TYPE http_requests_total counter\n");
+    for (name, endpoint) in endpoints.iter() {
+        for (class, requests) in endpoint.requests.iter().enumerate() {
+            let _ = writeln!(text, "http_requests_total{{endpoint=\"{}\",code=\"{}xx\"}} {}",
+                             label(name), class + 1, requests.load(Ordering::Relaxed));
+        }
+    }
+    text.push_str("# HELP http_response_bytes_total Bytes sent by the endpoint, the headers included.\n");
+    text.push_str("# TYPE http_response_bytes_total counter\n");
+    for (name, endpoint) in endpoints.iter() {
+        let _ = writeln!(text, "http_response_bytes_total{{endpoint=\"{}\"}} {}", label(name), endpoint.bytes_sent.load(Ordering::Relaxed));
+    }
+    text.push_str("# HELP http_request_duration_seconds Time of the requests by the endpoint.\n");
+    text.push_str("# TYPE http_request_duration_seconds histogram\n");
+    for (name, endpoint) in endpoints.iter() {
+        let name = label(name);
+        let mut count = 0;
+        for (i, bucket) in endpoint.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match BUCKETS.get(i) {
+                Some(le) => format!("{}", *le as f64 / 1000.0),
+                None => "+Inf".to_string()
+            };
+            let _ = writeln!(text, "http_request_duration_seconds_bucket{{endpoint=\"{}\",le=\"{}\"}} {}", name, le, count);
+        }
+        let _ = writeln!(text, "http_request_duration_seconds_sum{{endpoint=\"{}\"}} {}", name, endpoint.time.load(Ordering::Relaxed) as f64 / 1000.0);
+        let _ = writeln!(text, "http_request_duration_seconds_count{{endpoint=\"{}\"}} {}", name, count);
+    }
+    text.push_str("# HELP http_client_rtt_seconds Smoothed RTT of the client connections at the end of the responses.\n");
+    text.push_str("# TYPE http_client_rtt_seconds summary\n");
+    for (name, endpoint) in endpoints.iter() {
+        let name = label(name);
+        let _ = writeln!(text, "http_client_rtt_seconds_sum{{endpoint=\"{}\"}} {}", name, endpoint.rtt.load(Ordering::Relaxed) as f64 / 1000000.0);
+        let _ = writeln!(text, "http_client_rtt_seconds_count{{endpoint=\"{}\"}} {}", name, endpoint.rtt_samples.load(Ordering::Relaxed));
+    }
+    text.push_str("# HELP http_client_retransmits_total Segments retransmitted to the clients during the responses.\n");
+    text.push_str("# TYPE http_client_retransmits_total counter\n");
+    for (name, endpoint) in endpoints.iter() {
+        let _ = writeln!(text, "http_client_retransmits_total{{endpoint=\"{}\"}} {}", label(name), endpoint.retrans.load(Ordering::Relaxed));
+    }
+    text
+}
@@ -6,6 +6,7 @@ use std::any::Any;
 use std::ops::Deref;
 use std::collections::{ HashMap, LinkedList };
 use std::mem::take;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::module::*;
@@ -15,9 +16,12 @@ use crate::keyval::*;
 use crate::handler::sync::Handler;
 use crate::handler::sync::RefHandler;
 use crate::client_context::ClientContext;
+use crate::bytes::Bytes;
+use crate::core::pool::FreeList;
 use crate::http::error::HttpResult;
 use crate::variable::Variable;
 use crate::config::{ Map, List };
+use crate::core::keyval_zone::{ self, KeyRef };
 
 pub struct HTTP;
 
@@ -38,6 +42,7 @@ pub type HttpComplexValue = Variable<HttpRequest>;
 
 pub type HttpHeaders = KeyVal<String>;
 pub type HttpQuery = KeyVal<String>;
+pub type HttpCookies = KeyVal<String>;
 pub type HttpVariables = KeyVal<HttpComplexValue>;
 
 pub type HttpModule = GenericModule<HTTP>;
@@ -121,8 +126,12 @@ pub enum HttpStatus {
     REQUEST_TIMEOUT = 408,
     CONFLICT = 409,
     GONE = 410,
+    PAYLOAD_TOO_LARGE = 413,
+    URI_TOO_LONG = 414,
+    UNSUPPORTED_MEDIA_TYPE = 415,
     UPGRADE_REQUIRED = 426,
     TOO_MANY_REQUESTS = 429,
+    REQUEST_HEADER_FIELDS_TOO_LARGE = 431,
     CLOSE = 444,
     ILLEGAL = 451,
     INTERNAL_SERVER_ERROR = 500,
@@ -134,6 +143,76 @@ pub enum HttpStatus {
     INSUFFICIENT_STORAGE = 507
 }
 
+// Portion of the streamed response body
+pub enum StreamChunk {
+    Data(Vec<u8>),
+    // nothing to send yet, ask again after the delay
+    Wait(Duration),
+    End
+}
+
+pub type BodyStream = Box<dyn FnMut(&mut HttpResponse) -> StreamChunk + Send>;
+
+// Pull based body, the next chunk is taken only when the previous one has been written out
+pub trait BodySource: Send {
+    // None at the end of the body
+    fn next_chunk(&mut self) -> std::io::Result<Option<Bytes>>;
+
+    // Known size is sent as Content-Length, otherwise the body is chunked
+    fn size(&self) -> Option<usize> {
+        None
+    }
+
+    // File sent by sendfile from its position when the body goes as is
+    fn file(&mut self) -> Option<&mut std::fs::File> {
+        None
+    }
+}
+
+const SOURCE_CHUNK_SIZE: usize = 16384;
+
+impl BodySource for std::fs::File {
+    fn next_chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        let mut chunk = vec![0u8; SOURCE_CHUNK_SIZE];
+        loop {
+            return match std::io::Read::read(self, &mut chunk) {
+                Ok(0) => Ok(None),
+                Ok(sz) => {
+                    chunk.truncate(sz);
+                    Ok(Some(Bytes::from(chunk)))
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err)
+            }
+        }
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.metadata().ok().map(|m| m.len() as usize)
+    }
+
+    fn file(&mut self) -> Option<&mut std::fs::File> {
+        Some(self)
+    }
+}
+
+// Body already in memory is handed out by slices
+impl BodySource for Bytes {
+    fn next_chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let len = std::cmp::min(self.len(), SOURCE_CHUNK_SIZE * 4);
+        let chunk = self.slice(..len);
+        *self = self.slice(len..);
+        Ok(Some(chunk))
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
 #[derive(Default)]
 pub struct TransferEncoding(u16);
 
@@ -228,18 +307,53 @@ impl std::fmt::Display for TransferEncoding {
     }
 }
 
+// Content-Length is ASCII digits only, the sign and the spaces accepted by parse() are not
+pub (crate) fn content_length(value: &str) -> Option<usize> {
+    match !value.is_empty() && value.bytes().all(|c| c.is_ascii_digit()) {
+        true => value.parse::<usize>().ok(),
+        false => None
+    }
+}
+
+// Header of the ${http_name} variable, '_' stands for '-' as in nginx
+fn header(headers: &HttpHeaders, name: &str) -> Option<String> {
+    headers.exact(name).or_else(|| headers.exact(&name.replace('_', "-"))).cloned()
+}
+
+// ${keyval_<zone>[key]}, the key variable is resolved by f, None for the other variables
+fn keyval<F>(var: &str, f: F) -> Option<Option<String>>
+where
+    F: Fn(&str) -> Option<String>
+{
+    let (zone, key) = keyval_zone::parse_var(var)?;
+    let key = match key {
+        KeyRef::Text(key) => Some(key.to_string()),
+        KeyRef::Var(name) => f(name)
+    };
+    Some(key.and_then(|key| keyval_zone::lookup(zone, &key)))
+}
+
 pub struct HttpRequest {
-    context: HashMap<&'static str, Box<dyn Any + Send>>,
+    context: Contexts,
     error_log: Option<String>,
+    error_log_level: Option<u8>,
     inner: internal::HttpRequest
 }
 
+type Contexts = HashMap<&'static str, Box<dyn Any + Send>>;
+
+lazy_static! {
+    // Contexts of the modules keep their capacity between the requests
+    static ref CONTEXTS: FreeList<Contexts> = FreeList::new("contexts", 256);
+}
+
 impl Request for HttpRequest {
     fn new(client: ClientContext) -> Self {
         HttpRequest {
             inner: internal::HttpRequest::new(client),
             error_log: None,
-            context: HashMap::new()
+            error_log_level: None,
+            context: CONTEXTS.take(HashMap::new)
         }
     }
 
@@ -257,6 +371,14 @@ impl Request for HttpRequest {
         }
     }
 
+    fn headers_received(&self) -> bool {
+        internal::HttpRequest::headers_received(self)
+    }
+
+    fn write_pending(&self) -> bool {
+        internal::HttpRequest::write_pending(self)
+    }
+
     fn context(&mut self) -> &mut ClientContext {
         &mut self.inner.client
     }
@@ -266,7 +388,8 @@ impl Request for HttpRequest {
     }
 
     fn close(self) -> ClientContext {
-        self.inner.client
+        CONTEXTS.put(self.context);
+        self.inner.recycle()
     }
 }
 
@@ -301,6 +424,10 @@ impl HttpRequest {
         }
     }
 
+    pub fn get_context<T: Send + 'static>(&self, module: &str) -> Option<&T> {
+        self.context.get(module).and_then(|context| context.downcast_ref::<T>())
+    }
+
     pub fn set_error_log(&mut self, error_log: &String) {
         self.error_log = Some(error_log.clone())
     }
@@ -309,6 +436,14 @@ impl HttpRequest {
         &self.error_log
     }
 
+    pub fn set_error_log_level(&mut self, level: Option<u8>) {
+        self.error_log_level = level
+    }
+
+    pub fn get_error_log_level(&self) -> Option<u8> {
+        self.error_log_level
+    }
+
     pub fn request_start(&self) -> chrono::DateTime<chrono::Utc> {
         self.inner.start
     }
@@ -317,6 +452,11 @@ impl HttpRequest {
         self.inner.timer.elapsed().as_millis() as u64
     }
 
+    // Time of reading the request body
+    pub fn body_time(&self) -> Duration {
+        self.inner.body_time
+    }
+
     pub fn content_length(&self) -> Option<usize> {
         self.inner.content_length
     }
@@ -329,6 +469,11 @@ impl HttpRequest {
         self.inner.protocol
     }
 
+    // http or https of the absolute-form request target
+    pub fn scheme(&self) -> &str {
+        self.inner.scheme
+    }
+
     pub fn host(&self) -> &String {
         &self.inner.host
     }
@@ -353,6 +498,11 @@ impl HttpRequest {
         &self.inner.query_string
     }
 
+    // Undecoded pairs of the query string in order of appearance, duplicates included
+    pub fn args_raw(&self) -> impl Iterator<Item = (&str, &str)> {
+        internal::HttpRequest::split_args(&self.inner.query_string)
+    }
+
     pub fn add_var(&mut self, name: &str, value: Variable<HttpRequest>) {
         self.inner.vars.add(name, value)
     }
@@ -369,11 +519,21 @@ impl HttpRequest {
         &self.inner.headers
     }
 
+    // Fields of the application/x-www-form-urlencoded body
+    pub fn post_args(&self) -> &HttpQuery {
+        &self.inner.post_args
+    }
+
+    pub fn cookies(&self) -> &HttpCookies {
+        &self.inner.cookies
+    }
+
     pub fn vars_mut(&mut self) -> &mut HttpVariables {
         &mut self.inner.vars
     }
 
     pub fn args_mut(&mut self) -> &mut HttpQuery {
+        self.inner.args_modified = true;
         &mut self.inner.args
     }
 
@@ -381,21 +541,30 @@ impl HttpRequest {
         &mut self.inner.headers
     }
 
+    fn var(&self, var: &str) -> Option<String> {
+        if let Some(name) = var.strip_prefix("http_") {
+            return header(&self.inner.headers, name)
+        }
+        if let Some(name) = var.strip_prefix("arg_") {
+            return self.inner.args.exact(name).cloned()
+        }
+        if let Some(name) = var.strip_prefix("post_arg_") {
+            return self.inner.post_args.exact(name).cloned()
+        }
+        if let Some(name) = var.strip_prefix("cookie_") {
+            return self.inner.cookies.exact(name).cloned()
+        }
+        if let Some(value) = keyval(var, |name| self.var(name)) {
+            return value
+        }
+        self.inner.vars.exact(var).map(|var| self.expand(var))
+    }
+
     pub fn expand(&self, cv: &Variable<HttpRequest>) -> String {
-        cv.expand_with(|var: &str| -> Option<String> {
-            if var.starts_with("http_") {
-                return self.inner.headers.exact(&var[5..]).map(|s| s.clone())
-            }
-            if var.starts_with("arg_") {
-                return self.inner.args.exact(&var[4..]).map(|s| s.clone())
-            }
-            match self.inner.vars.exact(var) {
-                Some(var) => Some(self.expand(var)),
-                None => None
-            }    
-        }, self)
+        cv.expand_with(|var| self.var(var), self)
     }
 
+    // None for the body spooled to disk, see body_source
     pub fn body(&self) -> Option<&[u8]> {
         match &self.inner.body {
             Some(body) => Some(body),
@@ -403,10 +572,85 @@ impl HttpRequest {
         }
     }
 
+    // Shared reference to the body, passed along without copying
+    pub fn body_bytes(&self) -> Option<Bytes> {
+        self.inner.body.clone()
+    }
+
+    // Temporary file of the body over client_body_buffer_size
+    pub fn body_path(&self) -> Option<&std::path::Path> {
+        self.inner.body_file.as_ref().map(|spooled| spooled.path())
+    }
+
+    // Body in memory or spooled to disk read in parts
+    pub fn body_source(&self) -> std::io::Result<Option<Box<dyn BodySource>>> {
+        match (&self.inner.body_file, &self.inner.body) {
+            (Some(spooled), _) => Ok(Some(Box::new(spooled.open()?))),
+            (None, Some(body)) => Ok(Some(Box::new(body.clone()))),
+            (None, None) => Ok(None)
+        }
+    }
+
+    // Whole body for the handlers keeping it in memory anyway,
+    // the spooled one is read back from its temporary file
+    pub fn body_contents(&self) -> Result<Option<Bytes>, CoreError> {
+        if self.inner.body_file.is_none() {
+            return Ok(self.inner.body.clone());
+        }
+        let mut source = match self.body_source() {
+            Ok(Some(source)) => source,
+            Ok(None) => return Ok(None),
+            Err(err) => return throw!("failed to read spooled body: {}", err)
+        };
+        let mut body = Bytes::with_capacity(self.content_length().unwrap_or(0));
+        loop {
+            match source.next_chunk() {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                Ok(None) => return Ok(Some(body)),
+                Err(err) => return throw!("failed to read spooled body: {}", err)
+            }
+        }
+    }
+
+    // Parts of the multipart/form-data body, limited by the multipart config of the server,
+    // the body is parsed as it is read from its source, large parts go to the temporary files
+    pub fn multipart(&self) -> Result<multipart::Multipart, CoreError> {
+        let content_type = match self.inner.headers.exact("content-type") {
+            Some(content_type) => content_type,
+            None => return throw!("Content-Type is missing")
+        };
+        let body = match self.body_source() {
+            Ok(Some(source)) => source,
+            Ok(None) => Box::new(Bytes::new()),
+            Err(err) => return throw!("failed to read spooled body: {}", err)
+        };
+        let server_addr = &self.inner.client.server_addr;
+        let mut config = multipart::get(server_addr);
+        if config.temp_path.is_none() {
+            config.temp_path = spool::get(server_addr).temp_path;
+        }
+        multipart::Multipart::new(content_type, body, config)
+    }
+
+    // Long running handlers poll it to stop working for the client which has gone away
+    pub fn client_closed(&self) -> bool {
+        self.inner.client.closed()
+    }
+
     pub fn is_mailformed(&self) -> bool {
         internal::HttpRequest::is_mailformed(self)
     }
 
+    // Request rejected before 100-continue, the body is not received
+    pub fn body_skipped(&self) -> bool {
+        internal::HttpRequest::body_skipped(self)
+    }
+
+    // Status of the request rejected by the parser limits
+    pub fn reject_status(&self) -> Option<HttpStatus> {
+        internal::HttpRequest::reject_status(self)
+    }
+
     pub fn add_flush(&mut self, h: FlushHandler) {
         self.inner.add_flush(h)
     }
@@ -448,8 +692,14 @@ impl Response for HttpResponse {
         &mut self.request
     }
 
+    fn take_deferred(&mut self) -> Option<Deferred<Self>> {
+        self.inner.deferred.take()
+    }
+
     fn close(mut self) -> ClientContext {
         take(&mut self.request.inner.log).iter().for_each(|h| h.handle(&mut self));
+        internal::request::put_headers(self.inner.headers);
+        internal::request::put_headers(self.inner.trailers);
         self.request.close()
     }
 }
@@ -466,6 +716,10 @@ impl HttpResponse {
         self.request.get_error_log()
     }
 
+    pub fn get_error_log_level(&self) -> Option<u8> {
+        self.request.get_error_log_level()
+    }
+
     pub fn set_context<T: Send + 'static>(&mut self, module: &'static str, context: T) {
         self.request.set_context::<T>(module, context)
     }
@@ -506,6 +760,15 @@ impl HttpResponse {
         internal::HttpResponse::add_header(self, name, value)
     }
 
+    pub fn add_cookie(&mut self, cookie: &cookie::Cookie) {
+        internal::HttpResponse::add_header(self, "Set-Cookie", &cookie.to_string())
+    }
+
+    // Sent with the chunked body only, the trailers added before the headers are advertised by 'Trailer'
+    pub fn add_trailer(&mut self, name: &str, value: &str) {
+        internal::HttpResponse::add_trailer(self, name, value)
+    }
+
     pub fn replace_header(&mut self, name: &str, value: Option<&str>) {
         internal::HttpResponse::replace_header(self, name, value)
     }
@@ -535,7 +798,7 @@ impl HttpResponse {
         self.inner.body = Some({
             let mut body = match self.inner.body.take() {
                 Some(body) => body,
-                None => Vec::with_capacity(match self.content_length() {
+                None => Bytes::with_capacity(match self.content_length() {
                     Some(content_length) => content_length,
                     None => chunk.len()
                 })
@@ -557,7 +820,7 @@ impl HttpResponse {
 
     pub fn set_body(&mut self, body: &[u8]) {
         self.set_content_length(body.len());
-        self.inner.body = Some(Vec::from(body));
+        self.inner.body = Some(Bytes::from(body));
     }
 
     pub fn send_body_chunk(&mut self, text: Option<&[u8]>) -> HttpResult {
@@ -568,6 +831,28 @@ impl HttpResponse {
         internal::HttpResponse::send_file(self, file)
     }
 
+    pub fn send_stream(&mut self, status: HttpStatus, content_type: &str, stream: BodyStream) {
+        internal::HttpResponse::send_stream(self, status, content_type, stream)
+    }
+
+    pub fn send_source<S: BodySource + 'static>(&mut self, status: HttpStatus, content_type: &str, source: S) {
+        internal::HttpResponse::send_source(self, status, content_type, Box::new(source))
+    }
+
+    // Response is handed over to 'deferred' when the content handler returns,
+    // it is sent after being passed to the completion
+    pub fn defer(&mut self, deferred: Deferred<HttpResponse>) {
+        self.inner.deferred = Some(deferred);
+    }
+
+    pub fn set_limit_rate(&mut self, limit_rate: usize) {
+        self.inner.limit_rate = limit_rate;
+    }
+
+    pub fn set_limit_rate_after(&mut self, limit_rate_after: usize) {
+        self.inner.limit_rate_after = limit_rate_after;
+    }
+
     pub fn set_chunked(&mut self) {
         self.inner.transfer_encoding.0 |= TransferEncoding::CHUNKED;
         self.inner.content_length = None;
@@ -585,6 +870,15 @@ impl HttpResponse {
         self.inner.status
     }
 
+    // Written to the client so far
+    pub fn bytes_sent(&self) -> usize {
+        self.inner.sent
+    }
+
+    pub fn body_bytes_sent(&self) -> usize {
+        self.inner.sent.saturating_sub(self.inner.header_size)
+    }
+
     pub fn content_length(&self) -> Option<usize> {
         self.inner.content_length
     }
@@ -608,22 +902,41 @@ impl HttpResponse {
         }
     }
 
+    // Shared reference to the body, passed along without copying
+    pub fn body_bytes(&self) -> Option<Bytes> {
+        self.inner.body.clone()
+    }
+
+    fn var(&self, var: &str) -> Option<String> {
+        if let Some(name) = var.strip_prefix("http_") {
+            return header(&self.request.inner.headers, name)
+        }
+        if let Some(name) = var.strip_prefix("arg_") {
+            return self.request.inner.args.exact(name).cloned()
+        }
+        if let Some(name) = var.strip_prefix("post_arg_") {
+            return self.request.inner.post_args.exact(name).cloned()
+        }
+        if let Some(name) = var.strip_prefix("cookie_") {
+            return self.request.inner.cookies.exact(name).cloned()
+        }
+        if let Some(name) = var.strip_prefix("sent_http_") {
+            return header(&self.inner.headers, name)
+        }
+        if let Some(value) = keyval(var, |name| self.var(name)) {
+            return value
+        }
+        match var {
+            "status" => return Some((self.inner.status as u16).to_string()),
+            "bytes_sent" => return Some(self.bytes_sent().to_string()),
+            "body_bytes_sent" => return Some(self.body_bytes_sent().to_string()),
+            _ => {}
+        }
+        self.request.inner.vars.exact(var).map(|var| self.expand(var))
+    }
+
     pub fn expand(&self, cv: &Variable<HttpRequest>) -> String {
-        cv.expand_with(|var: &str| -> Option<String> {
-            if var.starts_with("http_") {
-                return self.request.inner.headers.exact(&var[5..]).map(|s| s.clone())
-            }
-            if var.starts_with("arg_") {
-                return self.request.inner.args.exact(&var[4..]).map(|s| s.clone())
-            }
-            if var.starts_with("sent_http_") {
-                return self.inner.headers.exact(&var[10..]).map(|s| s.clone())
-            }
-            match self.request.inner.vars.exact(var) {
-                Some(var) => Some(self.expand(var)),
-                None => None
-            }    
-        }, &self.request)
+        cv.expand_with(|var| self.var(var), &self.request)
     }
 
     pub fn add_var(&mut self, name: &str, value: Variable<HttpRequest>) {
@@ -652,14 +965,19 @@ pub type RewriteHandler = RefHandler<HttpRequest, Code>;
 pub type AccessHandler = RefHandler<HttpRequest, Code>;
 pub type ContentHandler = Handler<HttpRequest, HttpResponse>;
 pub type HeaderFilterHandler = RefHandler<HttpResponse, ()>;
-pub type BodyFilterHandler = Handler<Option<Vec<u8>>, Option<Vec<u8>>>;
+pub type BodyFilterHandler = Handler<Option<Bytes>, Option<Bytes>>;
 pub type FlushHandler = RefHandler<HttpResponse, FlushResult>;
 pub type LogHandler = RefHandler<HttpResponse, ()>;
 
 #[derive(Clone, Default)]
 pub struct HttpContext {
     pub setvar: LinkedList<SetVarHandler>,
-    pub error_log: Option<String>
+    pub error_log: Option<String>,
+    pub error_log_level: Option<u8>,
+    // inherited by the servers, their own values and the values of the routes override them
+    pub limit_rate: Option<usize>,
+    pub limit_rate_after: Option<usize>,
+    pub limit_upload_rate: usize
 }
 
 #[derive(Clone, Default)]
@@ -667,12 +985,32 @@ pub struct ServerContext {
     pub workgroup: String,
     pub bind: String,
     pub error_log: Option<String>,
+    pub error_log_level: Option<u8>,
     pub virtual_host: Option<String>,
+    // serves the hosts unknown to the other servers of the bind
+    pub default_server: bool,
     pub routes: Option<LinkedList<RouteContext>>,
     pub request_timeout: Option<Duration>,
     pub response_timeout: Option<Duration>,
     pub keepalive_timeout: Option<Duration>,
     pub keepalive_requests: u64,
+    pub limit_upload_rate: usize,
+    pub shared: bool,
+    pub client_header_timeout: Option<Duration>,
+    pub max_half_read_connections: usize,
+    pub max_connections: usize,
+    pub limits: limits::HttpLimits,
+    pub multipart: multipart::MultipartConfig,
+    pub spool: spool::SpoolConfig,
+    // None means private when the clients are authenticated
+    pub private_cache: Option<bool>,
+    // set by the access handlers authenticating the clients, e.g. basic
+    pub auth: bool,
+    pub security_headers: Option<Arc<security_headers::SecurityHeaders>>,
+    // 'metric_name' of the routes without their own
+    pub metrics: Option<Arc<metrics::Endpoint>>,
+    // OPTIONS without a route of its own is answered with the Allow header
+    pub auto_options: bool,
     pub setvar: LinkedList<SetVarHandler>,
     pub rewrite: LinkedList<RewriteHandler>,
     pub access: LinkedList<AccessHandler>,
@@ -686,7 +1024,33 @@ pub struct RouteContext {
     pub host: Option<String>,
     pub pattern: String,
     pub method: Option<HttpMethod>,
+    // 'method: [GET, HEAD]', the route is added for each of them
+    pub methods: Vec<HttpMethod>,
+    // prefix and exact patterns are matched ignoring the case
+    pub ignore_case: bool,
+    // '/path/' is the same as '/path' for the prefix and exact patterns
+    pub ignore_trailing_slash: bool,
     pub error_log: Option<String>,
+    pub error_log_level: Option<u8>,
+    pub private_cache: Option<bool>,
+    // set by the access handlers authenticating the clients, e.g. basic, fgac, secure_link
+    pub auth: bool,
+    // content changes the state of the server, the route is refused without the authentication
+    pub admin: bool,
+    // values of the route override the values of the server
+    pub security_headers: Option<Arc<security_headers::SecurityHeaders>>,
+    // requests are counted by 'metric_name' instead of the uri
+    pub metrics: Option<Arc<metrics::Endpoint>>,
+    // false holds back 100 Continue, the body is read when the client sends it
+    pub expect_continue: Option<bool>,
+    // body limit of the route under the limit of the server, checked with the headers before the body is read
+    pub max_body_size: Option<usize>,
+    // proxy target for the routes test
+    pub upstream: Option<String>,
+    // route is skipped unless the condition holds
+    pub condition: Option<condition::Condition>,
+    // other routes of the same pattern and method with their own conditions
+    pub alternatives: Vec<RouteContext>,
     pub setvar: LinkedList<SetVarHandler>,
     pub rewrite: LinkedList<RewriteHandler>,
     pub access: LinkedList<AccessHandler>,
@@ -709,5 +1073,17 @@ pub mod error;
 pub mod routers;
 pub mod server;
 pub mod http_server_core;
+pub mod inflight;
+pub mod limits;
+pub mod vhosts;
+pub mod cookie;
+pub mod multipart;
+pub mod spool;
+pub mod condition;
+pub mod security_headers;
+pub mod metrics;
+pub mod fgac;
 pub mod plugins;
+#[cfg(feature = "async")]
+pub mod runtime;
 mod internal;
\ No newline at end of file
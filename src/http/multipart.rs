@@ -0,0 +1,508 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use percent_encoding::percent_decode;
+use std::collections::HashMap;
+use std::fs::{ self, File };
+use std::io::{ self, Write };
+use std::net::SocketAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::RwLock;
+
+use crate::bytes::Bytes;
+use crate::error::CoreError;
+use crate::http::{ BodySource, HttpHeaders };
+use crate::http::spool;
+
+// Headers of the part are expected to be small
+const MAX_PART_HEADERS: usize = 16384;
+
+// Multipart/form-data of the server, zero means unlimited or never spooled
+#[derive(Clone, Default, Debug)]
+pub struct MultipartConfig {
+    pub max_part_size: usize,
+    // larger parts are written to temporary files
+    pub spool_size: usize,
+    // client_body_temp_path of the server by default
+    pub temp_path: Option<String>
+}
+
+lazy_static! {
+    static ref CONFIGS: RwLock<HashMap<SocketAddr, MultipartConfig>> = RwLock::new(HashMap::new());
+}
+
+pub fn set(addr: SocketAddr, config: MultipartConfig) {
+    CONFIGS.write().unwrap().insert(addr, config);
+}
+
+pub fn remove(addr: SocketAddr) {
+    CONFIGS.write().unwrap().remove(&addr);
+}
+
+pub fn get(addr: &SocketAddr) -> MultipartConfig {
+    CONFIGS.read().unwrap().get(addr).cloned().unwrap_or_default()
+}
+
+pub enum PartContent {
+    // slice of the request body
+    Memory(Bytes),
+    // spooled part, the file is removed with the part unless persisted
+    File(PathBuf, usize)
+}
+
+pub struct Part {
+    headers: HttpHeaders,
+    name: Option<String>,
+    filename: Option<String>,
+    content: PartContent
+}
+
+impl Part {
+    pub fn headers(&self) -> &HttpHeaders {
+        &self.headers
+    }
+
+    // Field name of the Content-Disposition
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // Present for the uploaded files
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.exact("content-type").map(|s| s.as_str())
+    }
+
+    pub fn size(&self) -> usize {
+        match &self.content {
+            PartContent::Memory(data) => data.len(),
+            PartContent::File(_, size) => *size
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        match &self.content {
+            PartContent::File(path, _) => Some(path),
+            PartContent::Memory(_) => None
+        }
+    }
+
+    // Content read in parts, the spooled file is not loaded into memory
+    pub fn content(&self) -> io::Result<Box<dyn BodySource>> {
+        match &self.content {
+            PartContent::Memory(data) => Ok(Box::new(data.clone())),
+            PartContent::File(path, _) => Ok(Box::new(File::open(path)?))
+        }
+    }
+
+    pub fn bytes(&self) -> io::Result<Bytes> {
+        match &self.content {
+            PartContent::Memory(data) => Ok(data.clone()),
+            PartContent::File(path, _) => Ok(Bytes::from(fs::read(path)?))
+        }
+    }
+
+    // Moves the content to the file, e.g. to keep the upload
+    pub fn persist<P: AsRef<Path>>(mut self, to: P) -> io::Result<()> {
+        match &mut self.content {
+            PartContent::Memory(data) => fs::write(to, &data[..]),
+            PartContent::File(path, _) => {
+                if fs::rename(&path, &to).is_err() {
+                    // other file system
+                    fs::copy(&path, &to)?;
+                    fs::remove_file(&path)?;
+                }
+                self.content = PartContent::Memory(Bytes::new());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Part {
+    fn drop(&mut self) {
+        if let PartContent::File(path, _) = &self.content {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Parts of the multipart body in order of appearance, the body is read from its source in chunks,
+// only the tail which may hold a part of the delimiter is kept between them
+pub struct Multipart {
+    source: Box<dyn BodySource>,
+    // received and not consumed yet
+    buf: Vec<u8>,
+    eof: bool,
+    // CRLF and dashes are included
+    delimiter: Vec<u8>,
+    started: bool,
+    done: bool,
+    config: MultipartConfig
+}
+
+// Content of the part being received, it goes to the file once it exceeds spool_size
+enum Sink {
+    Memory(Vec<u8>),
+    File(File, PathBuf)
+}
+
+fn find(data: &[u8], what: &[u8], from: usize) -> Option<usize> {
+    if from > data.len() || what.len() > data.len() - from {
+        return None;
+    }
+    data[from..].windows(what.len()).position(|window| window == what).map(|pos| pos + from)
+}
+
+// Parameter of the header value, e.g. boundary of the Content-Type or filename of the Content-Disposition
+fn param(value: &str, name: &str) -> Option<String> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ';' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return None;
+        }
+        let eq = rest.find(['=', ';']).unwrap_or(rest.len());
+        let key = rest[..eq].trim();
+        rest = &rest[eq..];
+        let mut val = String::new();
+        if rest.starts_with('=') {
+            rest = rest[1..].trim_start();
+            if rest.starts_with('"') {
+                let mut chars = rest[1..].char_indices();
+                let mut end = rest.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => if let Some((_, c)) = chars.next() {
+                            val.push(c)
+                        },
+                        '"' => {
+                            end = i + 2;
+                            break;
+                        },
+                        c => val.push(c)
+                    }
+                }
+                rest = &rest[end..];
+            } else {
+                let end = rest.find(';').unwrap_or(rest.len());
+                val.push_str(rest[..end].trim());
+                rest = &rest[end..];
+            }
+        }
+        if key.eq_ignore_ascii_case(name) {
+            return Some(val);
+        }
+    }
+}
+
+// RFC 5987 value, e.g. UTF-8''%e2%82%ac.txt
+fn ext_value(value: &str) -> Option<String> {
+    let encoded = value.splitn(3, '\'').nth(2)?;
+    Some(percent_decode(encoded.as_bytes()).decode_utf8_lossy().into_owned())
+}
+
+impl Multipart {
+    pub fn new(content_type: &str, source: Box<dyn BodySource>, config: MultipartConfig) -> Result<Multipart, CoreError> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if !mime.to_ascii_lowercase().starts_with("multipart/") {
+            return throw!("not a multipart body: '{}'", mime);
+        }
+        let boundary = match param(content_type, "boundary") {
+            Some(boundary) if !boundary.is_empty() && boundary.len() <= 70 => boundary,
+            _ => return throw!("multipart boundary is missing or invalid")
+        };
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Ok(Multipart {
+            source,
+            buf: Vec::new(),
+            eof: false,
+            delimiter,
+            started: false,
+            done: false,
+            config
+        })
+    }
+
+    // Appends the next chunk of the body, false at the end of it
+    fn fill(&mut self) -> Result<bool, CoreError> {
+        if self.eof {
+            return Ok(false);
+        }
+        match self.source.next_chunk() {
+            Ok(Some(chunk)) => {
+                self.buf.extend_from_slice(&chunk);
+                Ok(true)
+            },
+            Ok(None) => {
+                self.eof = true;
+                Ok(false)
+            },
+            Err(err) => throw!("failed to read multipart body: {}", err)
+        }
+    }
+
+    // At least len bytes are received unless the body ends earlier
+    fn ensure(&mut self, len: usize) -> Result<bool, CoreError> {
+        while self.buf.len() < len {
+            if !self.fill()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Position of the sequence found within the limit
+    fn find_within(&mut self, what: &[u8], limit: usize) -> Result<Option<usize>, CoreError> {
+        let mut from = 0;
+        loop {
+            if let Some(pos) = find(&self.buf, what, from) {
+                return Ok(Some(pos));
+            }
+            if self.buf.len() > limit {
+                return Ok(None);
+            }
+            from = self.buf.len().saturating_sub(what.len() - 1);
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Skips the preamble up to the first delimiter
+    fn start(&mut self) -> Result<(), CoreError> {
+        let first = self.delimiter[2..].to_vec();
+        if self.ensure(first.len())? && self.buf.starts_with(&first) {
+            self.buf.drain(..first.len());
+            return Ok(());
+        }
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter, 0) {
+                self.buf.drain(..pos + self.delimiter.len());
+                return Ok(());
+            }
+            let keep = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            self.buf.drain(..keep);
+            if !self.fill()? {
+                return throw!("multipart delimiter is not found");
+            }
+        }
+    }
+
+    fn parse_headers(data: &[u8]) -> Result<HttpHeaders, CoreError> {
+        let mut headers = HttpHeaders::default();
+        for line in String::from_utf8_lossy(data).split("\r\n").filter(|line| !line.is_empty()) {
+            match line.find(':') {
+                Some(pos) => headers.add(line[..pos].trim(), line[pos + 1..].trim().to_string()),
+                None => return throw!("invalid multipart header line")
+            }
+        }
+        Ok(headers)
+    }
+
+    fn write(&self, sink: &mut Sink, size: usize, data: &[u8]) -> Result<(), CoreError> {
+        if self.config.max_part_size != 0 && size + data.len() > self.config.max_part_size {
+            return throw!("multipart part is too large: more than {} bytes", self.config.max_part_size);
+        }
+        if let Sink::Memory(content) = sink {
+            if self.config.spool_size != 0 && size + data.len() > self.config.spool_size {
+                let (mut file, path) = spool::temp_file(&self.config.temp_path, "multipart").or_else(|err| {
+                    throw!("failed to create multipart temp file: {}", err)
+                })?;
+                let spooled = file.write_all(content);
+                // the file is removed by the caller on error
+                *sink = Sink::File(file, path);
+                spooled.or_else(|err| throw!("failed to spool multipart part: {}", err))?;
+            }
+        }
+        match sink {
+            Sink::Memory(content) => {
+                content.extend_from_slice(data);
+                Ok(())
+            },
+            Sink::File(file, path) => file.write_all(data).or_else(|err| {
+                throw!("failed to spool multipart part to '{}': {}", path.display(), err)
+            })
+        }
+    }
+
+    // Content up to the next delimiter, the data which can't be a part of it is written out as it comes
+    fn content(&mut self, sink: &mut Sink) -> Result<usize, CoreError> {
+        let mut size = 0;
+        loop {
+            // the rest kept from the previous chunk is shorter than the delimiter
+            if let Some(end) = find(&self.buf, &self.delimiter, 0) {
+                let data: Vec<u8> = self.buf.drain(..end + self.delimiter.len()).take(end).collect();
+                self.write(sink, size, &data)?;
+                return Ok(size + data.len());
+            }
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe != 0 {
+                let data: Vec<u8> = self.buf.drain(..safe).collect();
+                self.write(sink, size, &data)?;
+                size += data.len();
+            }
+            if !self.fill()? {
+                return throw!("multipart body is truncated");
+            }
+        }
+    }
+
+    fn next_part(&mut self) -> Result<Option<Part>, CoreError> {
+        if !self.started {
+            self.start()?;
+            self.started = true;
+        }
+
+        // close delimiter
+        if self.ensure(2)? && self.buf.starts_with(b"--") {
+            return Ok(None);
+        }
+        // transport padding
+        let eol = match self.find_within(b"\r\n", MAX_PART_HEADERS)? {
+            Some(eol) if self.buf[..eol].iter().all(|c| *c == b' ' || *c == b'\t') => eol,
+            _ => return throw!("invalid multipart delimiter line")
+        };
+        self.buf.drain(..eol + 2);
+
+        let headers = match self.ensure(2)? && self.buf.starts_with(b"\r\n") {
+            true => {
+                self.buf.drain(..2);
+                HttpHeaders::default()
+            },
+            false => match self.find_within(b"\r\n\r\n", MAX_PART_HEADERS)? {
+                Some(end) if end <= MAX_PART_HEADERS => {
+                    let headers = Multipart::parse_headers(&self.buf[..end + 2])?;
+                    self.buf.drain(..end + 4);
+                    headers
+                },
+                Some(_) => return throw!("multipart part headers are too large"),
+                None if self.buf.len() > MAX_PART_HEADERS => return throw!("multipart part headers are too large"),
+                None => return throw!("multipart part headers are not terminated")
+            }
+        };
+
+        let (name, filename) = match headers.exact("content-disposition") {
+            Some(disposition) => (
+                param(disposition, "name"),
+                param(disposition, "filename*").and_then(|value| ext_value(&value)).or_else(|| param(disposition, "filename"))
+            ),
+            None => (None, None)
+        };
+
+        let mut sink = Sink::Memory(Vec::new());
+        let content = match self.content(&mut sink) {
+            Ok(size) => match sink {
+                Sink::Memory(content) => PartContent::Memory(Bytes::from(content)),
+                Sink::File(_, path) => PartContent::File(path, size)
+            },
+            Err(err) => {
+                if let Sink::File(_, path) = sink {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(err);
+            }
+        };
+
+        Ok(Some(Part {
+            headers,
+            name,
+            filename,
+            content
+        }))
+    }
+}
+
+impl Iterator for Multipart {
+    type Item = Result<Part, CoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_part() {
+            Ok(Some(part)) => Some(Ok(part)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Body handed out by the chunks of the size, the delimiter is split between them
+    struct Chunked(Vec<u8>, usize);
+
+    impl BodySource for Chunked {
+        fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+            if self.0.is_empty() {
+                return Ok(None);
+            }
+            let len = std::cmp::min(self.1, self.0.len());
+            Ok(Some(Bytes::from(self.0.drain(..len).collect::<Vec<u8>>())))
+        }
+    }
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=xyz";
+
+    fn body(file: &[u8]) -> Vec<u8> {
+        let mut body = b"preamble\r\n--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue\r\n--xyz\r\n".to_vec();
+        body.extend_from_slice(b"Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\nContent-Type: text/plain\r\n\r\n");
+        body.extend_from_slice(file);
+        body.extend_from_slice(b"\r\n--xyz--\r\n");
+        body
+    }
+
+    fn parts(body: Vec<u8>, chunk: usize, config: MultipartConfig) -> Result<Vec<Part>, CoreError> {
+        Multipart::new(CONTENT_TYPE, Box::new(Chunked(body, chunk)), config)?.collect()
+    }
+
+    #[test]
+    fn chunked() {
+        let file = b"line\r\n--xy\r\n-xyz--\r".repeat(100);
+        for chunk in &[1, 3, 7, 64, 1 << 20] {
+            let parts = parts(body(&file), *chunk, MultipartConfig::default()).unwrap();
+            assert_eq!(parts.len(), 2);
+            assert_eq!(parts[0].name(), Some("a"));
+            assert_eq!(&parts[0].bytes().unwrap()[..], b"value");
+            assert_eq!(parts[1].filename(), Some("f.txt"));
+            assert_eq!(parts[1].content_type(), Some("text/plain"));
+            assert_eq!(&parts[1].bytes().unwrap()[..], &file[..]);
+        }
+    }
+
+    #[test]
+    fn spooled() {
+        let file = vec![b'x'; 100000];
+        let config = MultipartConfig { max_part_size: 0, spool_size: 1000, temp_path: None };
+        let parts = parts(body(&file), 4096, config).unwrap();
+        assert!(parts[0].path().is_none());
+        let path = parts[1].path().unwrap().to_path_buf();
+        assert_eq!(parts[1].size(), file.len());
+        assert_eq!(fs::read(&path).unwrap(), file);
+        drop(parts);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn invalid() {
+        let config = MultipartConfig { max_part_size: 1000, spool_size: 0, temp_path: None };
+        assert!(parts(body(&[b'x'; 1001]), 512, config).is_err());
+        let mut truncated = body(b"data");
+        truncated.truncate(truncated.len() - 12);
+        assert!(parts(truncated, 5, MultipartConfig::default()).is_err());
+    }
+}
@@ -4,38 +4,149 @@
 
 register_http_plugin!(AccessLog);
 
-use std::fs::File;
 use std::collections::HashMap;
-use std::sync::{ Arc, Mutex, RwLock };
-use std::fs::OpenOptions;
-use std::io::prelude::*;
+use std::sync::{ Arc, Mutex, RwLock, atomic::{ AtomicBool, AtomicU64, Ordering } };
 use std::mem::take;
+use std::time::{ Duration, Instant };
+use std::{ thread, thread::JoinHandle };
+use std::net::SocketAddr;
+
+use yaml_rust::Yaml;
+use rand::Rng;
 
 use crate::plugin::*;
+use crate::config::{ Schema, SchemaType, Value, ConfigBlock };
 use crate::http::*;
-use crate::error::Code;
+use crate::http::inflight;
+use crate::http::condition::Condition;
+use crate::core::{ ring, shipper, signals };
+use crate::core::sink::{ self, Sink };
+use crate::core::budget::{ self, Category, Reservation };
+use crate::core::status::Json;
+use crate::error::{ Code, CoreError };
+
+// Fields of format_json keep the order of the config, strings are expanded
+#[derive(Clone)]
+pub enum JsonTemplate {
+    Expand(HttpComplexValue),
+    Int(i64),
+    Bool(bool),
+    Null,
+    Object(Vec<(String, JsonTemplate)>)
+}
+
+impl JsonTemplate {
+    fn parse(y: Yaml) -> Result<JsonTemplate, CoreError> {
+        match y {
+            Yaml::String(s) => HttpComplexValue::parse(&s).map(JsonTemplate::Expand).or_else(|err| throw!("format_json: {}", err)),
+            Yaml::Real(s) => Ok(JsonTemplate::Expand(HttpComplexValue::complex(&s))),
+            Yaml::Integer(i) => Ok(JsonTemplate::Int(i)),
+            Yaml::Boolean(b) => Ok(JsonTemplate::Bool(b)),
+            Yaml::Null => Ok(JsonTemplate::Null),
+            Yaml::Hash(h) => {
+                let mut fields = Vec::with_capacity(h.len());
+                for (k, v) in h {
+                    match k {
+                        Yaml::String(k) => fields.push((k, JsonTemplate::parse(v)?)),
+                        _ => return throw!("format_json: key type mismatch")
+                    }
+                }
+                Ok(JsonTemplate::Object(fields))
+            },
+            _ => throw!("format_json: value type mismatch")
+        }
+    }
+
+    fn render(&self, resp: &HttpResponse) -> Json {
+        match self {
+            JsonTemplate::Expand(cv) => Json::Str(resp.expand(cv)),
+            JsonTemplate::Int(i) => Json::Int(*i),
+            JsonTemplate::Bool(b) => Json::Bool(*b),
+            JsonTemplate::Null => Json::Null,
+            JsonTemplate::Object(fields) => Json::Object(fields.iter().map(|(k, v)| (k.clone(), v.render(resp))).collect())
+        }
+    }
+}
+
+impl Value for JsonTemplate {
+    type Type = JsonTemplate;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        match v {
+            Yaml::Hash(_) => JsonTemplate::parse(std::mem::replace(v, Yaml::Null)),
+            _ => throw!("format_json: map expected")
+        }
+    }
+}
+
+// Plain text line or one JSON object per line
+#[derive(Clone)]
+pub enum LogFormat {
+    Text(HttpComplexValue),
+    Json(JsonTemplate)
+}
+
+impl LogFormat {
+    fn render(&self, resp: &HttpResponse) -> String {
+        match self {
+            LogFormat::Text(format) => resp.expand(format),
+            LogFormat::Json(template) => template.render(resp).to_string()
+        }
+    }
+}
 
 #[derive(Default, Clone)]
 pub struct AccessLogFormatContext {
     name: Option<String>,
-    format: Option<HttpComplexValue>,
+    format: Option<LogFormat>,
+}
+
+#[derive(Default)]
+pub struct LogRingContext {
+    name: Option<String>,
+    size: usize
 }
 
 #[derive(Default, Clone)]
 pub struct AccessLogContext {
     filename: String,
-    format: Option<HttpComplexValue>,
-    buffer_size: usize
+    ring: Option<String>,
+    format: Option<LogFormat>,
+    buffer_size: usize,
+    flush_interval: Option<Duration>,
+    condition: Option<Condition>,
+    // percent of the requests to log
+    sample: Option<usize>
 }
 
 struct AccessFile {
-    file: File,
-    buffer: Vec<u8>
+    file: Sink,
+    buffer: Vec<u8>,
+    // buffered lines accounted in the workgroup budgets
+    memory: Vec<Reservation>,
+    flush_interval: Option<Duration>,
+    flushed: Instant
+}
+
+impl AccessFile {
+    fn flush(&mut self, filename: &str) {
+        if !self.buffer.is_empty() {
+            if let Err(err) = self.file.write(sink::INFO, &self.buffer) {
+                log_error!("error", "failed to write '{}', {}", filename, err)
+            }
+        }
+        self.buffer.clear();
+        self.memory.clear();
+        self.flushed = Instant::now();
+    }
 }
 
 pub struct AccessLog {
-    log_formats: Arc<RwLock<HashMap<String, HttpComplexValue>>>,
-    files: Arc<Mutex<HashMap<String, AccessFile>>>
+    log_formats: Arc<RwLock<HashMap<String, LogFormat>>>,
+    files: Arc<Mutex<HashMap<String, AccessFile>>>,
+    // the shortest flush_interval in milliseconds, zero if there is no one
+    flush_tick: Arc<AtomicU64>,
+    flusher: Option<JoinHandle<()>>,
+    stop: Arc<AtomicBool>
 }
 
 impl Plugin for AccessLog {
@@ -53,7 +164,12 @@ impl Plugin for AccessLog {
         })?;
 
         add_command!(Context::HTTP, "log_formats.log_format.format", |log_format: &mut AccessLogFormatContext, format: HttpComplexValue| {
-            log_format.format = Some(format);
+            log_format.format = Some(LogFormat::Text(format));
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "log_formats.log_format.format_json", |log_format: &mut AccessLogFormatContext, format: JsonTemplate| {
+            log_format.format = Some(LogFormat::Json(format));
             Ok(None)
         })?;
 
@@ -61,6 +177,11 @@ impl Plugin for AccessLog {
 
         add_empty_block!(Context::HTTP, "log_formats")?;
 
+        add_schema!(Context::HTTP, "log_formats.log_format", Schema::new()
+            .required("name", SchemaType::String)
+            .optional("format", SchemaType::String)
+            .optional("format_json", SchemaType::Map))?;
+
         add_block!(Context::HTTP, "log_formats.log_format", move |context| {
             match context.get_mut::<AccessLogFormatContext>() {
                 Some(log_format) => {
@@ -71,7 +192,7 @@ impl Plugin for AccessLog {
                             return Ok(None);
                         }
                     }
-                    throw!("log_format: 'name' and 'format' required")
+                    throw!("log_format: 'name' and 'format' or 'format_json' required")
                 },
                 None =>
                     // enter
@@ -79,6 +200,43 @@ impl Plugin for AccessLog {
             }
         })?;
 
+        // Rings
+
+        add_command!(Context::HTTP, "log_rings.log_ring.name", |log_ring: &mut LogRingContext, name: String| {
+            log_ring.name = Some(name);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "log_rings.log_ring.size", |log_ring: &mut LogRingContext, size: usize| {
+            log_ring.size = size;
+            Ok(None)
+        })?;
+
+        add_empty_block!(Context::HTTP, "log_rings")?;
+
+        add_schema!(Context::HTTP, "log_rings.log_ring", Schema::new()
+            .required("name", SchemaType::String)
+            .required("size", SchemaType::Integer)
+            .range("size", 1, i64::MAX))?;
+
+        add_block!(Context::HTTP, "log_rings.log_ring", |context| {
+            match context.get_mut::<LogRingContext>() {
+                Some(log_ring) => {
+                    // exit
+                    match &log_ring.name {
+                        Some(name) if log_ring.size != 0 => {
+                            ring::create(name, log_ring.size);
+                            Ok(None)
+                        },
+                        _ => throw!("log_ring: 'name' and 'size' required")
+                    }
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<LogRingContext>()))
+            }
+        })?;
+
         // Server
 
         add_command!(Context::SERVER, "access_log.filename", |access_log: &mut AccessLogContext, filename: String| {
@@ -86,11 +244,31 @@ impl Plugin for AccessLog {
             Ok(None)
         })?;
 
+        add_command!(Context::SERVER, "access_log.ring", |access_log: &mut AccessLogContext, ring: String| {
+            access_log.ring = Some(ring);
+            Ok(None)
+        })?;
+
         add_command!(Context::SERVER, "access_log.buffer_size", |access_log: &mut AccessLogContext, buffer_size: usize| {
             access_log.buffer_size = buffer_size;
             Ok(None)
         })?;
 
+        add_command!(Context::SERVER, "access_log.if", |access_log: &mut AccessLogContext, condition: String| {
+            access_log.condition = Some(Condition::parse(&condition)?);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "access_log.flush_interval", |access_log: &mut AccessLogContext, flush_interval: Duration| {
+            access_log.flush_interval = Some(flush_interval);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "access_log.sample", |access_log: &mut AccessLogContext, sample: usize| {
+            access_log.sample = Some(sample);
+            Ok(None)
+        })?;
+
         let log_formats_ = Arc::clone(&self.log_formats);
 
         add_command!(Context::SERVER, "access_log.format", move |access_log: &mut AccessLogContext, format: String| {
@@ -101,22 +279,20 @@ impl Plugin for AccessLog {
             Ok(None)
         })?;
 
+        add_schema!(Context::SERVER, "access_log", AccessLog::schema())?;
+
+        let flush_tick_ = Arc::clone(&self.flush_tick);
+
         add_block!(Context::SERVER, "access_log", move |context| {
             match context.get_mut::<AccessLogContext>() {
                 Some(access_log) => {
                     // exit
                     let access_log = take(access_log);
-                    if access_log.filename.len() != 0 {
-                        if let Some(format) = access_log.format.clone() {
-                            context.parent().unwrap()
-                                   .get_mut::<ServerContext>().unwrap()
-                                   .log.push_back(LogHandler::new(move |resp| {
-                                AccessLog::write(&access_log, resp.expand(&format));
-                            }));
-                            return Ok(None);
-                        }
-                    }
-                    throw!("access_log: 'filename' and 'format' required")
+                    let handler = AccessLog::handler(access_log, &flush_tick_)?;
+                    context.parent().unwrap()
+                           .get_mut::<ServerContext>().unwrap()
+                           .log.push_back(handler);
+                    Ok(None)
                 },
                 None =>
                     // enter
@@ -141,27 +317,97 @@ impl Plugin for AccessLog {
             Ok(None)
         })?;
 
+        add_command!(Context::ROUTE, "access_log.ring", |access_log: &mut AccessLogContext, ring: String| {
+            access_log.ring = Some(ring);
+            Ok(None)
+        })?;
+
         add_command!(Context::ROUTE, "access_log.buffer_size", |access_log: &mut AccessLogContext, buffer_size: usize| {
             access_log.buffer_size = buffer_size;
             Ok(None)
         })?;
 
+        add_command!(Context::ROUTE, "access_log.if", |access_log: &mut AccessLogContext, condition: String| {
+            access_log.condition = Some(Condition::parse(&condition)?);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "access_log.flush_interval", |access_log: &mut AccessLogContext, flush_interval: Duration| {
+            access_log.flush_interval = Some(flush_interval);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "access_log.sample", |access_log: &mut AccessLogContext, sample: usize| {
+            access_log.sample = Some(sample);
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "access_log", AccessLog::schema())?;
+
+        // Last lines of the ring, 'follow' streams new lines until the client goes away
+
+        add_command!(Context::ROUTE, "log_tail", |route: &mut RouteContext, name: String| {
+            route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                let lines = r.args().exact("n").and_then(|n| n.parse::<usize>().ok()).unwrap_or(100);
+                let follow = matches!(r.args().exact("follow").map(|s| s.as_str()), Some("on") | Some("true") | Some("1"));
+                let mut resp = HttpResponse::new(r);
+                resp.clear_context("inflight");
+                let ring = match ring::get(&name) {
+                    Some(ring) => ring,
+                    None => {
+                        resp.send(HttpStatus::NOT_FOUND, "text/plain", Some(b"ring not found"));
+                        return resp;
+                    }
+                };
+                let (tail, mut next) = {
+                    let ring = ring.lock().unwrap();
+                    (ring.tail(lines), ring.next())
+                };
+                let mut text = tail.join("\n");
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                if !follow {
+                    resp.send(HttpStatus::OK, "text/plain", Some(text.as_bytes()));
+                    return resp;
+                }
+                let mut pending = Some(text.into_bytes());
+                resp.send_stream(HttpStatus::OK, "text/plain", Box::new(move |resp| {
+                    // response timeout is applied to the idle stream
+                    let timeout = resp.context().response_timeout();
+                    resp.context().set_timeout(timeout);
+                    if let Some(text) = pending.take() {
+                        return StreamChunk::Data(text);
+                    }
+                    if inflight::draining() {
+                        return StreamChunk::End;
+                    }
+                    let ring = ring.lock().unwrap();
+                    if ring.next() == next {
+                        return StreamChunk::Wait(Duration::from_millis(200));
+                    }
+                    let mut text = ring.since(next).join("\n");
+                    text.push('\n');
+                    next = ring.next();
+                    StreamChunk::Data(text.into_bytes())
+                }));
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        let flush_tick_ = Arc::clone(&self.flush_tick);
+
         add_block!(Context::ROUTE, "access_log", move |context| {
             match context.get_mut::<AccessLogContext>() {
                 Some(access_log) => {
                     // exit
                     let access_log = take(access_log);
-                    if access_log.filename.len() != 0 {
-                        if let Some(format) = access_log.format.clone() {
-                            context.parent().unwrap()
-                                   .get_mut::<RouteContext>().unwrap()
-                                   .log.push_back(LogHandler::new(move |resp| {
-                                AccessLog::write(&access_log, resp.expand(&format));
-                            }));
-                            return Ok(None);
-                        }
-                    }
-                    throw!("access_log: 'filename' and 'format' required")
+                    let handler = AccessLog::handler(access_log, &flush_tick_)?;
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .log.push_back(handler);
+                    Ok(None)
                 },
                 None =>
                     // enter
@@ -171,17 +417,141 @@ impl Plugin for AccessLog {
 
         Ok(Code::OK)
     }
+
+    fn activate(&mut self) -> ActionResult {
+        let files = Arc::clone(&self.files);
+        signals::on_reopen(Box::new(move || {
+            for (filename, file) in files.lock().unwrap().iter_mut() {
+                file.flush(filename);
+                if let Err(err) = file.file.reopen(filename) {
+                    log_error!("error", "Failed to reopen log file '{}': {}", filename, err.what());
+                }
+            }
+        }));
+
+        let tick = self.flush_tick.load(Ordering::Relaxed);
+        if tick == 0 || self.flusher.is_some() {
+            return Ok(Code::OK);
+        }
+        self.stop.store(false, Ordering::Relaxed);
+        let files = Arc::clone(&self.files);
+        let stop = Arc::clone(&self.stop);
+        self.flusher = Some(thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(std::cmp::min(tick, 100)));
+                for (filename, file) in files.lock().unwrap().iter_mut() {
+                    if let Some(flush_interval) = file.flush_interval {
+                        if !file.buffer.is_empty() && file.flushed.elapsed() >= flush_interval {
+                            file.flush(filename);
+                        }
+                    }
+                }
+            }
+        }));
+        Ok(Code::OK)
+    }
+
+    fn deactivate(&mut self) -> ActionResult {
+        self.stop.store(true, Ordering::Relaxed);
+        self.flush_all();
+        Ok(Code::OK)
+    }
+
+    // Lines logged while the servers were draining are flushed at exit, the queued records are shipped
+    fn wait(&mut self) {
+        if let Some(flusher) = self.flusher.take() {
+            self.stop.store(true, Ordering::Relaxed);
+            let _ = flusher.join();
+        }
+        self.flush_all();
+        shipper::flush_all();
+    }
 }
 
 impl AccessLog {
     pub fn new() -> AccessLog {
         AccessLog {
             log_formats: Arc::new(RwLock::new(HashMap::new())),
-            files: Arc::new(Mutex::new(HashMap::new()))
+            files: Arc::new(Mutex::new(HashMap::new())),
+            flush_tick: Arc::new(AtomicU64::new(0)),
+            flusher: None,
+            stop: Arc::new(AtomicBool::new(false))
         }
     }
 
-    fn write(context: &AccessLogContext, text: String) {
+    fn flush_all(&self) {
+        for (filename, file) in self.files.lock().unwrap().iter_mut() {
+            file.flush(filename);
+        }
+    }
+
+    fn schema() -> Schema {
+        Schema::new()
+            .optional("filename", SchemaType::String)
+            .optional("ring", SchemaType::String)
+            .required("format", SchemaType::String)
+            .optional("buffer_size", SchemaType::Integer)
+            .range("buffer_size", 0, i64::MAX)
+            .optional("flush_interval", SchemaType::Integer)
+            .range("flush_interval", 1, i64::MAX)
+            .optional("if", SchemaType::String)
+            .optional("sample", SchemaType::Integer)
+            .range("sample", 0, 100)
+    }
+
+    // Log is written to the file, the ring or both
+    fn handler(access_log: AccessLogContext, flush_tick: &AtomicU64) -> Result<LogHandler, CoreError> {
+        let format = match access_log.format.clone() {
+            Some(format) => format,
+            None => return throw!("access_log: 'format' required")
+        };
+        if access_log.filename.is_empty() && access_log.ring.is_none() {
+            return throw!("access_log: 'filename' or 'ring' required");
+        }
+        if let Some(name) = &access_log.ring {
+            if ring::get(name).is_none() {
+                return throw!("access_log: ring '{}' is not defined", name);
+            }
+        }
+        if let Some(flush_interval) = access_log.flush_interval {
+            AccessLog::flush_every(flush_tick, flush_interval);
+        }
+        Ok(LogHandler::new(move |resp| {
+            if let Some(sample) = access_log.sample {
+                if rand::thread_rng().gen_range(0..100) >= sample {
+                    return;
+                }
+            }
+            if let Some(condition) = &access_log.condition {
+                if !condition.eval(|cv| resp.expand(cv)) {
+                    return;
+                }
+            }
+            let text = format.render(resp);
+            if let Some(name) = &access_log.ring {
+                ring::push(name, text.clone());
+            }
+            if !access_log.filename.is_empty() {
+                let server_addr = resp.context().server_addr;
+                AccessLog::write(&access_log.filename, access_log.buffer_size, access_log.flush_interval, &server_addr, text);
+            }
+        }))
+    }
+
+    fn flush_every(flush_tick: &AtomicU64, flush_interval: Duration) {
+        let ms = std::cmp::max(flush_interval.as_millis() as u64, 1);
+        let _ = flush_tick.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tick| {
+            Some(if tick == 0 { ms } else { std::cmp::min(tick, ms) })
+        });
+    }
+
+    // Files of the other modules are flushed by the same thread, it is started on activation
+    pub (crate) fn add_flush_interval(flush_interval: Duration) {
+        AccessLog::flush_every(&HttpModule::get_plugin::<AccessLog>().flush_tick, flush_interval);
+    }
+
+    // Buffer is flushed to disk when the memory budget is exceeded, the files are reopened with the signal
+    pub (crate) fn write(filename: &str, buffer_size: usize, flush_interval: Option<Duration>, server_addr: &SocketAddr, text: String) {
         thread_local!(
             static ACCESS_LOG: &'static mut AccessLog = HttpModule::get_plugin::<AccessLog>()
         );
@@ -189,38 +559,39 @@ impl AccessLog {
         ACCESS_LOG.with(|access_log| {
             let mut files = access_log.files.lock().unwrap();
 
-            let access_log_file = match files.get_mut(&context.filename) {
+            let access_log_file = match files.get_mut(filename) {
                 Some(file) => file,
                 None => {
-                    let file = match OpenOptions::new().append(true)
-                                                       .create(true)
-                                                       .open(&context.filename) {
+                    let file = match Sink::open(filename) {
                         Ok(file) => file,
                         Err(err) => {
-                            log_error!("error", "Failed to open log file '{}': {}", context.filename, err);
+                            log_error!("error", "Failed to open log file '{}': {}", filename, err.what());
                             return;
                         }
                     };
-                    files.insert(context.filename.clone(), AccessFile {
+                    files.insert(filename.to_string(), AccessFile {
                         file: file,
-                        buffer: Vec::with_capacity(context.buffer_size + 1024)
+                        buffer: Vec::with_capacity(buffer_size + 1024),
+                        memory: Vec::new(),
+                        flush_interval,
+                        flushed: Instant::now()
                     });
-                    files.get_mut(&context.filename).unwrap()
+                    files.get_mut(filename).unwrap()
                 }
             };
 
             access_log_file.buffer.extend_from_slice(text.as_bytes());
             access_log_file.buffer.extend_from_slice(b"\n");
 
-            if access_log_file.buffer.len() < context.buffer_size {
-                return;
-            }
-
-            if let Err(err) = access_log_file.file.write_all(&access_log_file.buffer) {
-                log_error!("error", "failed to write '{}', {}", context.filename, err)
+            if access_log_file.buffer.len() < buffer_size && access_log_file.file.buffered() {
+                match budget::reserve(server_addr, Category::Log, text.len() + 1) {
+                    Ok(Some(memory)) => return access_log_file.memory.push(memory),
+                    Ok(None) => return,
+                    Err(_) => { /* flush */ }
+                }
             }
 
-            access_log_file.buffer.clear();
+            access_log_file.flush(filename);
         })
     }
 }
\ No newline at end of file
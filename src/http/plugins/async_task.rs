@@ -5,29 +5,82 @@
 register_http_plugin!(AsyncTask);
 
 use std::{ thread, thread::JoinHandle };
-use std::time::Duration;
+use std::sync::{ Arc, Mutex, mpsc::{ channel, Receiver, Sender } };
+use std::sync::atomic::{ AtomicUsize, Ordering };
 
 use crate::plugin::*;
 use crate::http::*;
 
+const ASYNC_TASK_THREADS_DEFAULT: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    // Queue of the executor, none while it is not running
+    static ref QUEUE: Mutex<Option<Sender<Job>>> = Mutex::new(None);
+}
+
+// Runs the job on the dedicated threads, so the slow jobs don't hold the workers of the workgroup.
+// The job runs in the caller thread when the executor is not running.
+pub fn spawn<F>(f: F)
+where
+    F: FnOnce() + Send + 'static
+{
+    let job = match QUEUE.lock().unwrap().as_ref() {
+        Some(queue) => match queue.send(Box::new(f)) {
+            Ok(_) => return,
+            Err(err) => err.0
+        },
+        None => Box::new(f)
+    };
+    job()
+}
+
 pub struct AsyncTask {
-    thr: Option<JoinHandle<()>>
+    threads: Arc<AtomicUsize>,
+    thrs: Vec<JoinHandle<()>>
 }
 
 impl Plugin for AsyncTask {
     type ModuleType = HTTP;
 
     fn configure(&mut self) -> ActionResult {
+        let threads = Arc::clone(&self.threads);
+
+        add_command!(Context::HTTP, "async_task_threads", move |_: &mut HttpContext, n: usize| {
+            if n == 0 {
+                return throw!("async_task_threads: must be positive");
+            }
+            threads.store(n, Ordering::Relaxed);
+            Ok(None)
+        })?;
+
         Ok(OK)
     }
 
     fn activate(&mut self) -> ActionResult {
-        self.thr = Some(thread::spawn(|| {
-            for i in 0..10 {
-                println!("{}", i);
-                thread::sleep(Duration::from_millis(100));
+        let mut queue = QUEUE.lock().unwrap();
+        if queue.is_some() {
+            return Ok(DECLINED);
+        }
+        let (sender, receiver) = channel::<Job>();
+        let receiver: Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(receiver));
+        for i in 0..self.threads.load(Ordering::Relaxed) {
+            let receiver = Arc::clone(&receiver);
+            let thr = thread::Builder::new().name(format!("ws: task {}", i)).spawn(move || loop {
+                // the lock is released before the job runs
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break
+                }
+            });
+            match thr {
+                Ok(thr) => self.thrs.push(thr),
+                Err(err) => return throw!("async_task: failed to start the thread: {}", err)
             }
-        }));
+        }
+        *queue = Some(sender);
         Ok(OK)
     }
 
@@ -35,9 +88,11 @@ impl Plugin for AsyncTask {
         Ok(DECLINED)
     }
 
+    // Jobs in the queue are finished before the exit
     fn wait(&mut self) {
-        if let Some(thr) = self.thr.take() {
-            thr.join().unwrap();
+        QUEUE.lock().unwrap().take();
+        for thr in self.thrs.drain(..) {
+            let _ = thr.join();
         }
     }
 }
@@ -45,7 +100,8 @@ impl Plugin for AsyncTask {
 impl AsyncTask {
     pub fn new() -> AsyncTask {
         AsyncTask {
-            thr: None
+            threads: Arc::new(AtomicUsize::new(ASYNC_TASK_THREADS_DEFAULT)),
+            thrs: Vec::new()
         }
     }
-}
\ No newline at end of file
+}
@@ -16,6 +16,7 @@ impl Plugin for BasicAuth {
 
     fn configure(&mut self) -> ActionResult {
         add_command!(Context::SERVER, "basic", |server: &mut ServerContext, basic: String| {
+            server.auth = true;
             server.access.push_back(AccessHandler::new(|r| -> Code {
                 Code::DECLINED
             }));
@@ -24,6 +25,7 @@ impl Plugin for BasicAuth {
         })?;
 
         add_command!(Context::ROUTE, "basic", |route: &mut RouteContext, basic: String| {
+            route.auth = true;
             route.access.push_back(AccessHandler::new(|resp| -> Code {
                 Code::DECLINED
             }));
@@ -0,0 +1,216 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(Beacon);
+
+use std::fs::{ File, OpenOptions };
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use std::io::prelude::*;
+use std::mem::take;
+
+use chrono::Local;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::core::ring;
+
+// sendBeacon, CSP reports (report-uri and report-to) and NEL
+const CONTENT_TYPES: [&str; 5] = [
+    "text/plain",
+    "application/json",
+    "application/csp-report",
+    "application/reports+json",
+    "application/x-www-form-urlencoded"
+];
+
+pub struct BeaconContext {
+    filename: Option<String>,
+    ring: Option<String>,
+    max_size: usize,
+    content_types: HttpList
+}
+
+impl Default for BeaconContext {
+    fn default() -> BeaconContext {
+        BeaconContext {
+            filename: None,
+            ring: None,
+            max_size: 64 * 1024,
+            content_types: HttpList::new()
+        }
+    }
+}
+
+pub struct Beacon {
+    files: Arc<Mutex<HashMap<String, Arc<Mutex<File>>>>>
+}
+
+fn reject(r: HttpRequest, status: HttpStatus, text: &'static str) -> HttpResponse {
+    let mut resp = HttpResponse::new(r);
+    resp.send(status, "text/plain", Some(text.as_bytes()));
+    resp
+}
+
+// One payload per line, line breaks inside of the payload are escaped
+fn escape(body: &[u8]) -> String {
+    String::from_utf8_lossy(body).trim_end()
+                                 .replace('\\', "\\\\")
+                                 .replace('\r', "\\r")
+                                 .replace('\n', "\\n")
+}
+
+impl Plugin for Beacon {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Beacon"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::ROUTE, "beacon.filename", |beacon: &mut BeaconContext, filename: String| {
+            beacon.filename = Some(filename);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "beacon.ring", |beacon: &mut BeaconContext, ring: String| {
+            beacon.ring = Some(ring);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "beacon.max_size", |beacon: &mut BeaconContext, max_size: usize| {
+            beacon.max_size = max_size;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "beacon.content_types", |beacon: &mut BeaconContext, content_types: HttpList| {
+            beacon.content_types = content_types;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "beacon", Schema::new()
+            .optional("filename", SchemaType::String)
+            .optional("ring", SchemaType::String)
+            .optional("max_size", SchemaType::Integer)
+            .range("max_size", 1, i64::MAX)
+            .optional("content_types", SchemaType::List))?;
+
+        let files_ = Arc::clone(&self.files);
+
+        add_block!(Context::ROUTE, "beacon", move |context| {
+            match context.get_mut::<BeaconContext>() {
+                Some(beacon) => {
+                    // exit
+                    let beacon = take(beacon);
+
+                    if beacon.filename.is_none() && beacon.ring.is_none() {
+                        return throw!("beacon: 'filename' or 'ring' required");
+                    }
+                    if let Some(name) = &beacon.ring {
+                        if ring::get(name).is_none() {
+                            return throw!("beacon: ring '{}' is not defined", name);
+                        }
+                    }
+
+                    let file = match &beacon.filename {
+                        Some(filename) => {
+                            let mut files = files_.lock().unwrap();
+                            match files.get(filename) {
+                                Some(file) => Some(Arc::clone(file)),
+                                None => {
+                                    let file = match OpenOptions::new().append(true).create(true).open(filename) {
+                                        Ok(file) => Arc::new(Mutex::new(file)),
+                                        Err(err) => return throw!("Failed to open beacon log '{}': {}", filename, err)
+                                    };
+                                    files.insert(filename.clone(), Arc::clone(&file));
+                                    Some(file)
+                                }
+                            }
+                        },
+                        None => None
+                    };
+
+                    let content_types: Vec<String> = match beacon.content_types.is_empty() {
+                        true => CONTENT_TYPES.iter().map(|s| s.to_string()).collect(),
+                        false => beacon.content_types.iter().filter_map(|s| s.text()).map(|s| s.to_lowercase()).collect()
+                    };
+                    let max_size = beacon.max_size;
+                    let filename = beacon.filename.unwrap_or_default();
+                    let ring = beacon.ring;
+
+                    // larger body is rejected with 413 before it is read
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .max_body_size = Some(max_size);
+
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                               if !matches!(r.method(), HttpMethod::POST) {
+                                   return reject(r, HttpStatus::NOT_ALLOWED, "Method not allowed");
+                               }
+                               if r.content_length().unwrap_or(0) > max_size {
+                                   return reject(r, HttpStatus::PAYLOAD_TOO_LARGE, "Payload too large");
+                               }
+                               let content_type = r.headers().exact("Content-Type")
+                                                             .map(|s| s.split(';').next().unwrap().trim().to_lowercase())
+                                                             .unwrap_or_default();
+                               if !content_types.contains(&content_type) {
+                                   return reject(r, HttpStatus::UNSUPPORTED_MEDIA_TYPE, "Unsupported media type");
+                               }
+                               let body = match r.body_contents() {
+                                   Ok(body) => body.unwrap_or_default(),
+                                   Err(err) => {
+                                       log_http_error!(r, "error", "beacon: {}", err);
+                                       return reject(r, HttpStatus::INTERNAL_SERVER_ERROR, "Internal server error");
+                                   }
+                               };
+                               if body.len() > max_size {
+                                   return reject(r, HttpStatus::PAYLOAD_TOO_LARGE, "Payload too large");
+                               }
+
+                               let text = format!("{} {} {} {} {}",
+                                   Local::now().format("%Y/%m/%d-%H:%M:%S"), r.const_context().remote_addr(),
+                                   content_type, r.uri(), escape(&body));
+
+                               if let Some(name) = &ring {
+                                   ring::push(name, text.clone());
+                               }
+                               if let Some(file) = &file {
+                                   if let Err(err) = writeln!(file.lock().unwrap(), "{}", text) {
+                                       log_http_error!(r, "error", "failed to write '{}', {}", filename, err);
+                                   }
+                               }
+
+                               let mut resp = HttpResponse::new(r);
+                               resp.send_no_content();
+                               resp
+                           }));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<BeaconContext>()))
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl Default for Beacon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Beacon {
+    pub fn new() -> Beacon {
+        Beacon {
+            files: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+}
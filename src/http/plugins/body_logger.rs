@@ -4,44 +4,289 @@
 
 register_http_plugin!(BodyLogger);
 
+use std::mem::take;
+use std::sync::{ Arc, Mutex };
+
+use regex::{ Regex, Captures };
+
 use crate::plugin::*;
+use crate::config::{ Schema, SchemaType };
+use crate::module::*;
 use crate::http::*;
+use crate::http::plugins::access_log::AccessLog;
+use crate::error::{ Code, CoreError };
+
+const MAX_SIZE_DEFAULT: usize = 4096;
+
+// Card numbers are masked when their checksum is valid, not to hide the timestamps and ids
+const CARD: &str = r"\b\d(?:[ -]?\d){12,18}\b";
+
+// Credentials and tokens masked in every body
+const MASKS_DEFAULT: [&str; 2] = [
+    r#"(?i)\bauthorization"?\s*[:=]\s*"?(?:(?:bearer|basic|digest)\s+)?([^"&,;\s]+)"#,
+    r#"(?i)\b(?:password|passwd|secret|token|api_key|apikey|access_token|refresh_token)"?\s*[:=]\s*"?([^"&,;\s]+)"#
+];
+
+#[derive(Clone)]
+pub struct BodyLogContext {
+    // file, stdout, stderr or syslog://host:514?facility=local7
+    target: String,
+    request: bool,
+    response: bool,
+    // captured bytes of each body, the rest is counted only
+    max_size: usize,
+    mask: Vec<String>
+}
+
+impl Default for BodyLogContext {
+    fn default() -> BodyLogContext {
+        BodyLogContext {
+            target: "stdout".to_string(),
+            request: true,
+            response: true,
+            max_size: MAX_SIZE_DEFAULT,
+            mask: Vec::new()
+        }
+    }
+}
+
+struct BodyLog {
+    target: String,
+    request: bool,
+    response: bool,
+    max_size: usize,
+    card: Regex,
+    // match or its first group is replaced
+    masks: Vec<Regex>
+}
+
+// Head of the response body sent in chunks
+#[derive(Default)]
+struct Captured {
+    data: Vec<u8>,
+    size: usize
+}
 
 pub struct BodyLogger
 {}
 
-impl Plugin for BodyLogger {
-    type ModuleType = HTTP;
+// Luhn checksum of the digits, separators are skipped
+fn luhn(number: &str) -> bool {
+    let sum: u32 = number.chars().rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(i, d)| match i % 2 {
+            1 if d * 2 > 9 => d * 2 - 9,
+            1 => d * 2,
+            _ => d
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
 
-    fn configure(&mut self) -> ActionResult {
+impl BodyLog {
+    fn new(context: BodyLogContext) -> Result<BodyLog, CoreError> {
+        let mut masks = Vec::new();
+        for mask in MASKS_DEFAULT.iter().map(|mask| mask.to_string()).chain(context.mask) {
+            match Regex::new(&mask) {
+                Ok(re) => masks.push(re),
+                Err(err) => return throw!("body_log: invalid mask '{}': {}", mask, err)
+            }
+        }
+        Ok(BodyLog {
+            target: context.target,
+            request: context.request,
+            response: context.response,
+            max_size: context.max_size,
+            card: Regex::new(CARD).unwrap(),
+            masks
+        })
+    }
 
-        // Server
+    fn mask(&self, text: String) -> String {
+        let text = self.card.replace_all(&text, |caps: &Captures| match luhn(&caps[0]) {
+            true => "***".to_string(),
+            false => caps[0].to_string()
+        }).into_owned();
+        self.masks.iter().fold(text, |text, re| {
+            re.replace_all(&text, |caps: &Captures| match caps.get(1) {
+                Some(secret) => {
+                    let all = caps.get(0).unwrap();
+                    format!("{}***{}", &all.as_str()[..secret.start() - all.start()], &all.as_str()[secret.end() - all.start()..])
+                },
+                None => "***".to_string()
+            }).into_owned()
+        })
+    }
+
+    // One line per body, the control characters are escaped
+    fn format(&self, resp: &mut HttpResponse, kind: &str, data: &[u8], size: usize) -> String {
+        let status = resp.status() as u16;
+        let r = resp.get_request();
+        let mut body = String::with_capacity(data.len());
+        for c in self.mask(String::from_utf8_lossy(data).into_owned()).chars() {
+            match c {
+                '\n' => body.push_str("\\n"),
+                '\r' => body.push_str("\\r"),
+                '\t' => body.push_str("\\t"),
+                '\\' => body.push_str("\\\\"),
+                c if c.is_control() => body.push_str(&format!("\\x{:02x}", c as u32)),
+                c => body.push(c)
+            }
+        }
+        let truncated = match size > data.len() {
+            true => format!(" truncated={}", size - data.len()),
+            false => String::new()
+        };
+        format!("{} {} {} {} {} {} size={}{}: {}",
+                r.request_start().format("%Y/%m/%d-%H:%M:%S"),
+                r.const_context().request_id().unwrap_or_default(),
+                kind, r.method(), r.uri(), status, size, truncated, body)
+    }
+
+    // Request body in memory or spooled to disk, up to max_size
+    fn request_body(&self, r: &HttpRequest) -> Option<(Vec<u8>, usize)> {
+        let mut source = match r.body_source() {
+            Ok(Some(source)) => source,
+            _ => return None
+        };
+        let total = source.size();
+        let mut data = Vec::new();
+        let mut size = 0;
+        while let Ok(Some(chunk)) = source.next_chunk() {
+            size += chunk.len();
+            let len = std::cmp::min(chunk.len(), self.max_size - data.len());
+            data.extend_from_slice(&chunk[..len]);
+            if data.len() == self.max_size {
+                break;
+            }
+        }
+        Some((data, total.unwrap_or(size)))
+    }
+
+    fn write(&self, resp: &mut HttpResponse, text: String) {
+        let server_addr = resp.context().server_addr;
+        AccessLog::write(&self.target, 0, None, &server_addr, text);
+    }
 
-        add_command!(Context::SERVER, "body_log", |server: &mut ServerContext| {
-            server.body_filter.push_back(BodyFilterHandler::new(|body| {
+    // Response body is captured by the filter of the request, both are logged when it is done
+    fn start(body_log: &Arc<BodyLog>, resp: &mut HttpResponse) {
+        let captured = Arc::new(Mutex::new(Captured::default()));
+        if body_log.response {
+            let body_log = Arc::clone(body_log);
+            let captured = Arc::clone(&captured);
+            resp.add_body_filter(BodyFilterHandler::new(move |body| {
                 if let Some(body) = &body {
-                    println!("{:?}", body)
+                    let mut captured = captured.lock().unwrap();
+                    captured.size += body.len();
+                    let len = std::cmp::min(body.len(), body_log.max_size - captured.data.len());
+                    captured.data.extend_from_slice(&body[..len]);
                 }
                 body
             }));
+        }
+        let body_log = Arc::clone(body_log);
+        resp.add_log(LogHandler::new(move |resp| {
+            if body_log.request {
+                if let Some((data, size)) = body_log.request_body(resp.get_request()) {
+                    let text = body_log.format(resp, "request", &data, size);
+                    body_log.write(resp, text);
+                }
+            }
+            let captured = take(&mut *captured.lock().unwrap());
+            if captured.size != 0 {
+                let text = body_log.format(resp, "response", &captured.data, captured.size);
+                body_log.write(resp, text);
+            }
+        }));
+    }
+
+    fn handler(context: BodyLogContext) -> Result<HeaderFilterHandler, CoreError> {
+        let body_log = Arc::new(BodyLog::new(context)?);
+        Ok(HeaderFilterHandler::new(move |resp| {
+            BodyLog::start(&body_log, resp);
+        }))
+    }
+}
+
+impl Plugin for BodyLogger {
+    type ModuleType = HTTP;
+
+    fn configure(&mut self) -> ActionResult {
+
+        for base in [ Context::SERVER, Context::ROUTE ].iter() {
+
+            add_schema!(base, "body_log", Schema::new()
+                .optional("target", SchemaType::String)
+                .optional("request", SchemaType::Bool)
+                .optional("response", SchemaType::Bool)
+                .optional("max_size", SchemaType::Integer)
+                .range("max_size", 0, i64::MAX)
+                .optional("mask", SchemaType::Any))?;
 
-            Ok(None)
+            add_command!(base, "body_log.target", |body_log: &mut BodyLogContext, target: String| {
+                body_log.target = target;
+                Ok(None)
+            })?;
+
+            add_command!(base, "body_log.request", |body_log: &mut BodyLogContext, request: bool| {
+                body_log.request = request;
+                Ok(None)
+            })?;
+
+            add_command!(base, "body_log.response", |body_log: &mut BodyLogContext, response: bool| {
+                body_log.response = response;
+                Ok(None)
+            })?;
+
+            add_command!(base, "body_log.max_size", |body_log: &mut BodyLogContext, max_size: usize| {
+                body_log.max_size = max_size;
+                Ok(None)
+            })?;
+
+            add_command!(base, "body_log.mask", |body_log: &mut BodyLogContext, mask: Vec<String>| {
+                body_log.mask.extend(mask);
+                Ok(None)
+            })?;
+        }
+
+        // Server
+
+        add_block!(Context::SERVER, "body_log", |context| {
+            match context.get_mut::<BodyLogContext>() {
+                Some(body_log) => {
+                    // exit
+                    let handler = BodyLog::handler(take(body_log))?;
+                    context.parent().unwrap()
+                           .get_mut::<ServerContext>().unwrap()
+                           .header_filter.push_back(handler);
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<BodyLogContext>()))
+            }
         })?;
 
         // Route
 
-        add_command!(Context::ROUTE, "body_log", |route: &mut RouteContext| {
-            route.body_filter.push_back(BodyFilterHandler::new(|body| {
-                if let Some(body) = &body {
-                    println!("{:?}", body)
-                }
-                body
-            }));
-
-            Ok(None)
+        add_block!(Context::ROUTE, "body_log", |context| {
+            match context.get_mut::<BodyLogContext>() {
+                Some(body_log) => {
+                    // exit
+                    let handler = BodyLog::handler(take(body_log))?;
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .header_filter.push_back(handler);
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<BodyLogContext>()))
+            }
         })?;
 
-        Ok(OK)
+        Ok(Code::OK)
     }
 }
 
@@ -49,4 +294,4 @@ impl BodyLogger {
     pub fn new() -> BodyLogger {
         BodyLogger {}
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,296 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(Capture);
+
+use std::fs::File;
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Arc, Mutex };
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::mem::take;
+
+use crate::plugin::*;
+use crate::config::{ Schema, SchemaType };
+use crate::module::*;
+use crate::http::*;
+use crate::error::Code;
+
+const CAPTURE_KEEP_DEFAULT: usize = 100;
+
+#[derive(Clone)]
+pub struct CaptureContext {
+    filename: Option<String>,
+    request: bool,
+    response: bool,
+    sample: usize,
+    max_size: usize
+}
+
+impl Default for CaptureContext {
+    fn default() -> CaptureContext {
+        CaptureContext {
+            filename: None,
+            request: true,
+            response: true,
+            sample: 100,
+            max_size: usize::MAX
+        }
+    }
+}
+
+pub struct Capture {
+    keep: Arc<Mutex<usize>>,
+    captures: Arc<Mutex<VecDeque<String>>>,
+    files: Arc<Mutex<HashMap<String, File>>>
+}
+
+impl Plugin for Capture {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Capture"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        let keep_ = Arc::clone(&self.keep);
+
+        add_command!(Context::HTTP, "capture_keep", move |_: &mut HttpContext, keep: usize| {
+            *keep_.lock().unwrap() = keep;
+            Ok(None)
+        })?;
+
+        for base in [ Context::SERVER, Context::ROUTE ].iter() {
+
+            add_schema!(base, "capture", Schema::new()
+                .optional("filename", SchemaType::String)
+                .optional("request", SchemaType::Bool)
+                .optional("response", SchemaType::Bool)
+                .optional("sample", SchemaType::Integer)
+                .range("sample", 0, 100)
+                .optional("max_size", SchemaType::Integer)
+                .range("max_size", 0, i64::MAX))?;
+
+            add_command!(base, "capture.filename", |capture: &mut CaptureContext, filename: String| {
+                capture.filename = Some(filename);
+                Ok(None)
+            })?;
+
+            add_command!(base, "capture.request", |capture: &mut CaptureContext, request: bool| {
+                capture.request = request;
+                Ok(None)
+            })?;
+
+            add_command!(base, "capture.response", |capture: &mut CaptureContext, response: bool| {
+                capture.response = response;
+                Ok(None)
+            })?;
+
+            add_command!(base, "capture.sample", |capture: &mut CaptureContext, sample: usize| {
+                if sample > 100 {
+                    return throw!("capture: 'sample' must be in range 0..100");
+                }
+                capture.sample = sample;
+                Ok(None)
+            })?;
+
+            add_command!(base, "capture.max_size", |capture: &mut CaptureContext, max_size: usize| {
+                capture.max_size = max_size;
+                Ok(None)
+            })?;
+        }
+
+        add_block!(Context::SERVER, "capture", move |context| {
+            match context.get_mut::<CaptureContext>() {
+                Some(capture) => {
+                    // exit
+                    let capture = take(capture);
+                    context.parent().unwrap()
+                           .get_mut::<ServerContext>().unwrap()
+                           .log.push_back(LogHandler::new(move |resp| {
+                        Capture::capture(&capture, resp);
+                    }));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<CaptureContext>()))
+            }
+        })?;
+
+        add_block!(Context::ROUTE, "capture", move |context| {
+            match context.get_mut::<CaptureContext>() {
+                Some(capture) => {
+                    // exit
+                    let capture = take(capture);
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .log.push_back(LogHandler::new(move |resp| {
+                        Capture::capture(&capture, resp);
+                    }));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<CaptureContext>()))
+            }
+        })?;
+
+        // Capture API
+
+        let captures_ = Arc::clone(&self.captures);
+
+        add_command!(Context::ROUTE, "capture_api", move |route: &mut RouteContext| {
+            let captures_ = Arc::clone(&captures_);
+            route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                let clear = r.args().exact("clear").map(|v| v == "1" || v == "true").unwrap_or(false);
+                let limit = r.args().exact("limit").and_then(|v| v.parse::<usize>().ok());
+                let mut captures = captures_.lock().unwrap();
+                let skip = match limit {
+                    Some(limit) if limit < captures.len() => captures.len() - limit,
+                    _ => 0
+                };
+                let text = captures.iter().skip(skip).map(|s| s.as_str()).collect::<Vec<&str>>().concat();
+                if clear {
+                    captures.clear();
+                }
+                drop(captures);
+                let mut resp = HttpResponse::new(r);
+                resp.send(HttpStatus::OK, "text/plain", Some(text.as_bytes()));
+                resp
+            }));
+
+            Ok(None)
+        })?;
+
+        Ok(Code::OK)
+    }
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture {
+            keep: Arc::new(Mutex::new(CAPTURE_KEEP_DEFAULT)),
+            captures: Arc::new(Mutex::new(VecDeque::new())),
+            files: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    fn format_body(text: &mut String, body: Option<&[u8]>, max_size: usize) {
+        if let Some(body) = body {
+            let len = std::cmp::min(body.len(), max_size);
+            Capture::format_head(text, &body[..len], body.len());
+        }
+    }
+
+    fn format_head(text: &mut String, head: &[u8], len: usize) {
+        text.push_str(&String::from_utf8_lossy(head));
+        if head.len() < len {
+            text.push_str(&format!("\n... truncated {} bytes", len - head.len()));
+        }
+        text.push('\n');
+    }
+
+    // Only max_size of the spooled body is read from its file
+    fn format_spooled(text: &mut String, path: &std::path::Path, max_size: usize) {
+        let result = File::open(path).and_then(|file| {
+            let len = file.metadata()?.len() as usize;
+            let mut head = Vec::with_capacity(std::cmp::min(len, max_size));
+            file.take(max_size as u64).read_to_end(&mut head)?;
+            Ok((head, len))
+        });
+        match result {
+            Ok((head, len)) => Capture::format_head(text, &head, len),
+            Err(err) => text.push_str(&format!("... spooled body is not readable, {}\n", err))
+        }
+    }
+
+    fn format(context: &CaptureContext, resp: &mut HttpResponse) -> String {
+        let mut text = String::with_capacity(4096);
+
+        {
+            let r = resp.get_request();
+
+            text.push_str(&format!("=== {} {} client={} ===\n",
+                                   r.request_start().format("%Y/%m/%d-%H:%M:%S"),
+                                   r.const_context().request_id().unwrap_or_default(),
+                                   r.const_context().remote_addr()));
+
+            if context.request {
+                text.push_str(&format!("> {} {} HTTP/{}\n", r.method(), r.request_uri(), r.protocol()));
+                r.headers().iter().for_each(|(key, ll)| {
+                    ll.iter().for_each(|v| text.push_str(&format!("> {}: {}\n", key, v)));
+                });
+                text.push_str(">\n");
+                match r.body_path() {
+                    Some(path) => Capture::format_spooled(&mut text, path, context.max_size),
+                    None => Capture::format_body(&mut text, r.body(), context.max_size)
+                }
+            }
+        }
+
+        if context.response {
+            text.push_str(&format!("< HTTP/{} {}\n", resp.protocol(), resp.status()));
+            resp.headers().iter().for_each(|(key, ll)| {
+                ll.iter().for_each(|v| text.push_str(&format!("< {}: {}\n", key, v)));
+            });
+            text.push_str("<\n");
+            Capture::format_body(&mut text, resp.body(), context.max_size);
+        }
+
+        text
+    }
+
+    fn capture(context: &CaptureContext, resp: &mut HttpResponse) {
+        if context.sample == 0 || (context.sample < 100 && rand::random::<usize>() % 100 >= context.sample) {
+            return;
+        }
+
+        let text = Capture::format(context, resp);
+
+        let capture = HttpModule::get_plugin::<Capture>();
+
+        match &context.filename {
+            Some(filename) => {
+                let mut files = capture.files.lock().unwrap();
+
+                let file = match files.get_mut(filename) {
+                    Some(file) => file,
+                    None => {
+                        let file = match OpenOptions::new().append(true)
+                                                           .create(true)
+                                                           .open(filename) {
+                            Ok(file) => file,
+                            Err(err) => {
+                                log_http_error!(resp, "error", "Failed to open capture file '{}': {}", filename, err);
+                                return;
+                            }
+                        };
+                        files.insert(filename.clone(), file);
+                        files.get_mut(filename).unwrap()
+                    }
+                };
+
+                if let Err(err) = file.write_all(text.as_bytes()) {
+                    log_http_error!(resp, "error", "failed to write '{}', {}", filename, err)
+                }
+            },
+            None => {
+                let keep = *capture.keep.lock().unwrap();
+                let mut captures = capture.captures.lock().unwrap();
+                captures.push_back(text);
+                while captures.len() > keep {
+                    captures.pop_front();
+                }
+            }
+        }
+    }
+}
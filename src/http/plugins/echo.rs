@@ -4,19 +4,126 @@
 
 register_http_plugin!(Echo);
 
+use std::fs::File;
+use std::io::ErrorKind;
+use std::time::{ Duration, Instant };
+
 use crate::plugin::*;
 use crate::config::*;
 use crate::http::*;
+use crate::bytes::Bytes;
+use crate::error::Flush;
 
-#[derive(Default, Clone)]
+// 'echo: text' or { text: ..., status: 404, headers: {...}, delay_ms: 100, file: ..., repeat: 10 }
+#[derive(Default)]
 pub struct EchoContext {
-    status: Option<HttpStatus>,
-    text: Option<HttpComplexValue>
+    pub (crate) status: Option<HttpStatus>,
+    pub (crate) text: Option<HttpComplexValue>,
+    pub (crate) file: Option<HttpComplexValue>,
+    pub (crate) headers: HttpMap,
+    pub (crate) delay: Option<Duration>,
+    pub (crate) repeat: Option<usize>
+}
+
+// Body is sent the given number of times
+struct Repeat {
+    body: Bytes,
+    times: usize,
+    current: Bytes
+}
+
+impl BodySource for Repeat {
+    fn next_chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        if self.current.is_empty() {
+            if self.times == 0 {
+                return Ok(None);
+            }
+            self.times -= 1;
+            self.current = self.body.clone();
+        }
+        self.current.next_chunk()
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.current.len() + self.body.len() * self.times)
+    }
 }
 
 pub struct Echo
 {}
 
+fn repeat(body: Vec<u8>, times: usize) -> Repeat {
+    Repeat {
+        body: Bytes::from(body),
+        times,
+        current: Bytes::from(Vec::new())
+    }
+}
+
+// Missing file of the expanded path is 404
+fn send_file(resp: &mut HttpResponse, status: HttpStatus, content_type: &str, path: &str, times: usize) {
+    let sent = match times {
+        // file is streamed as is
+        1 => File::open(path).map(|file| resp.send_source(status, content_type, file)),
+        _ => std::fs::read(path).map(|body| resp.send_source(status, content_type, repeat(body, times)))
+    };
+    if let Err(err) = sent {
+        let uri = resp.get_request().uri().clone();
+        log_http_error!(resp, "error", "echo: file '{}', {}, uri={}", path, err, uri);
+        let status = match err.kind() {
+            ErrorKind::NotFound => HttpStatus::NOT_FOUND,
+            _ => HttpStatus::INTERNAL_SERVER_ERROR
+        };
+        resp.send(status, "text/plain", Some(format!("{}", status).as_bytes()));
+    }
+}
+
+// The response is held by the IO thread, the worker is not blocked
+pub (crate) fn delay(resp: &mut HttpResponse, delay: Duration) {
+    let deadline = Instant::now() + delay;
+    resp.add_flush(FlushHandler::new(move |_| {
+        let now = Instant::now();
+        match now < deadline {
+            true => Ok(Flush::DELAY(deadline - now)),
+            false => Ok(Flush::OK(None))
+        }
+    }));
+}
+
+// Headers are added after the body, sending the source resets the response
+pub (crate) fn send(resp: &mut HttpResponse, echo: &EchoContext) {
+    let status = echo.status.unwrap_or(HttpStatus::OK);
+    let times = echo.repeat.unwrap_or(1);
+    let mut content_type = "text/plain".to_string();
+    let mut headers = Vec::new();
+    for (name, values) in echo.headers.iter() {
+        for value in values.iter() {
+            let value = resp.expand(value);
+            match name.eq_ignore_ascii_case("content-type") {
+                true => content_type = value,
+                false => headers.push((name.to_string(), value))
+            }
+        }
+    }
+    match (&echo.file, times) {
+        (Some(file), _) => {
+            let path = resp.expand(file);
+            send_file(resp, status, &content_type, &path, times);
+        },
+        (None, 1) => {
+            let body = echo.text.as_ref().map(|text| resp.expand(text)).unwrap_or_default();
+            resp.send(status, &content_type, Some(body.as_bytes()));
+        },
+        (None, _) => {
+            let body = echo.text.as_ref().map(|text| resp.expand(text)).unwrap_or_default();
+            resp.send_source(status, &content_type, repeat(body.into_bytes(), times));
+        }
+    }
+    for (name, value) in headers {
+        resp.add_header(&name, &value);
+    }
+}
+
 impl Plugin for Echo {
     type ModuleType = HTTP;
 
@@ -32,19 +139,53 @@ impl Plugin for Echo {
             Ok(None)
         })?;
 
+        add_command!(Context::ROUTE, "echo.file", |echo: &mut EchoContext, file: HttpComplexValue| {
+            echo.file = Some(file);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "echo.headers", |echo: &mut EchoContext, headers: HttpMap| {
+            echo.headers = headers;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "echo.delay_ms", |echo: &mut EchoContext, delay: Duration| {
+            echo.delay = Some(delay);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "echo.repeat", |echo: &mut EchoContext, repeat: usize| {
+            echo.repeat = Some(repeat);
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "echo", Schema::new()
+            .optional("text", SchemaType::String)
+            .optional("status", SchemaType::Integer)
+            .range("status", 100, 599)
+            .optional("file", SchemaType::String)
+            .optional("headers", SchemaType::Map)
+            .optional("delay_ms", SchemaType::Integer)
+            .range("delay_ms", 0, i64::MAX)
+            .optional("repeat", SchemaType::Integer)
+            .range("repeat", 1, i64::MAX))?;
+
         add_block!(Context::ROUTE, "echo", move |context, cv: HttpComplexValue| {
             match context.get_mut::<EchoContext>() {
                 Some(echo) => {
                     // exit
                     let echo = std::mem::take(echo);
+                    if echo.text.is_some() && echo.file.is_some() {
+                        return throw!("echo: 'text' and 'file' are exclusive");
+                    }
                     context.parent().unwrap()
                            .get_mut::<RouteContext>().unwrap()
                            .content = Some(ContentHandler::new(move |r| -> HttpResponse {
-                               let text = r.expand(&echo.text.as_ref().unwrap());
                                let mut resp = HttpResponse::new(r);
-                               resp.send(echo.status.unwrap_or(HttpStatus::OK),
-                                         "text/plain",
-                                         Some(text.as_bytes()));
+                               if let Some(duration) = echo.delay {
+                                   delay(&mut resp, duration);
+                               }
+                               send(&mut resp, &echo);
                                resp
                            }));
                     Ok(None)
@@ -52,7 +193,9 @@ impl Plugin for Echo {
                 None => {
                     // enter
                     let mut echo = EchoContext::default();
-                    echo.text = Some(cv);
+                    if cv.text() != Some(String::new()) {
+                        echo.text = Some(cv);
+                    }
                     Ok(Some(CommandContext::new(echo)))
                 }
             }
@@ -66,4 +209,4 @@ impl Echo {
     pub fn new() -> Echo {
         Echo {}
     }
-}
\ No newline at end of file
+}
@@ -7,7 +7,8 @@ register_http_plugin!(ErrorLog);
 use crate::plugin::*;
 use crate::http::*;
 use crate::error::Code;
-use crate::core::plugins::error_log;
+use std::mem::take;
+use crate::core::plugins::error_log::{ self, ErrorLogTarget };
 
 type CoreErrorLog = error_log::ErrorLog;
 
@@ -23,21 +24,30 @@ impl Plugin for ErrorLog {
 
     fn configure(&mut self) -> ActionResult {
 
-        add_command!(Context::HTTP, "error_log", move |http: &mut HttpContext, filename: String| {
-            CoreErrorLog::open(&filename)?;
-            http.error_log = Some(filename);
+        add_command!(Context::HTTP, "error_log", move |http: &mut HttpContext, target: ErrorLogTarget| {
+            if let Some(filename) = &target.filename {
+                CoreErrorLog::open(filename)?;
+            }
+            http.error_log = target.filename.or(take(&mut http.error_log));
+            http.error_log_level = target.level.or(http.error_log_level);
             Ok(None)
         })?;
 
-        add_command!(Context::SERVER, "error_log", move |server: &mut ServerContext, filename: String| {
-            CoreErrorLog::open(&filename)?;
-            server.error_log = Some(filename);
+        add_command!(Context::SERVER, "error_log", move |server: &mut ServerContext, target: ErrorLogTarget| {
+            if let Some(filename) = &target.filename {
+                CoreErrorLog::open(filename)?;
+            }
+            server.error_log = target.filename.or(take(&mut server.error_log));
+            server.error_log_level = target.level.or(server.error_log_level);
             Ok(None)
         })?;
 
-        add_command!(Context::ROUTE, "error_log", move |route: &mut RouteContext, filename: String| {
-            CoreErrorLog::open(&filename)?;
-            route.error_log = Some(filename);
+        add_command!(Context::ROUTE, "error_log", move |route: &mut RouteContext, target: ErrorLogTarget| {
+            if let Some(filename) = &target.filename {
+                CoreErrorLog::open(filename)?;
+            }
+            route.error_log = target.filename.or(take(&mut route.error_log));
+            route.error_log_level = target.level.or(route.error_log_level);
             Ok(None)
         })?;
 
@@ -50,8 +60,8 @@ impl ErrorLog {
         ErrorLog {}
     }
 
-    pub fn log(level: &str, filename: &Option<String>, args: std::fmt::Arguments) {
-        CoreErrorLog::log("http", level, filename, args)
+    pub fn log(level: &str, filename: &Option<String>, min_level: Option<u8>, args: std::fmt::Arguments) {
+        CoreErrorLog::log_with("http", level, filename, min_level, args)
     }
 
     pub fn info(filename: &Option<String>, text: String) {
@@ -77,15 +87,15 @@ macro_rules! log_http_error {
         $crate::log_http_error!($r, $level, format_args!($fmt, $($arg)*))
     };
     ($r:expr, "info", $text:expr) => {
-        crate::http::plugins::error_log::ErrorLog::log("info", $r.get_error_log(), format_args!("{}", $text))
+        $crate::http::plugins::error_log::ErrorLog::log("info", $r.get_error_log(), $r.get_error_log_level(), format_args!("{}", $text))
     };
     ($r:expr, "warn", $text:expr) => {
-        crate::http::plugins::error_log::ErrorLog::log("warn", $r.get_error_log(), format_args!("{}", $text))
+        $crate::http::plugins::error_log::ErrorLog::log("warn", $r.get_error_log(), $r.get_error_log_level(), format_args!("{}", $text))
     };
     ($r:expr, "error", $text:expr) => {
-        crate::http::plugins::error_log::ErrorLog::log("error", $r.get_error_log(), format_args!("{}", $text))
+        $crate::http::plugins::error_log::ErrorLog::log("error", $r.get_error_log(), $r.get_error_log_level(), format_args!("{}", $text))
     };
     ($r:expr, "debug", $text:expr) => {
-        crate::http::plugins::error_log::ErrorLog::log("debug", $r.get_error_log(), format_args!("{}", $text))
+        $crate::http::plugins::error_log::ErrorLog::log("debug", $r.get_error_log(), $r.get_error_log_level(), format_args!("{}", $text))
     };
 }
\ No newline at end of file
@@ -0,0 +1,498 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(Exec);
+
+use std::fs::File;
+use std::io::{ ErrorKind, Read, Write };
+use std::mem::take;
+use std::os::unix::io::{ AsRawFd, FromRawFd, RawFd };
+use std::os::unix::process::CommandExt;
+use std::process::{ ChildStdin, Command, Stdio };
+use std::sync::{ Arc, atomic::{ AtomicUsize, Ordering } };
+use std::time::{ Duration, Instant };
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::error::CoreError;
+use crate::http::plugins::async_task;
+
+const EXEC_TIMEOUT_DEFAULT: Duration = Duration::from_secs(30);
+const EXEC_MAX_OUTPUT_DEFAULT: usize = 16 * 1024 * 1024;
+// stderr is logged up to this size
+const EXEC_MAX_ERRORS: usize = 64 * 1024;
+const EXEC_PIPE_CHUNK: usize = 64 * 1024;
+// exit is polled at this interval on the kernels without pidfd_open
+const EXEC_WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+// 'exec: /path/to/script' or { program: ..., args: [...], env: {...}, timeout: 5000, max_concurrent: 10, max_output: 1048576 }
+#[derive(Default)]
+pub struct ExecContext {
+    program: Option<String>,
+    args: HttpList,
+    env: HttpMap,
+    timeout: Option<Duration>,
+    max_concurrent: Option<usize>,
+    max_output: Option<usize>
+}
+
+struct Program {
+    path: String,
+    args: HttpList,
+    env: HttpMap,
+    timeout: Duration,
+    max_output: usize
+}
+
+enum Outcome {
+    Completed(Vec<u8>),
+    TimedOut,
+    TooLarge
+}
+
+// The running program is counted until the guard is dropped
+struct Running {
+    count: Arc<AtomicUsize>
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct Exec
+{}
+
+// CGI/1.1 meta-variables of the request
+fn cgi_env(r: &HttpRequest) -> Vec<(String, String)> {
+    let server_addr = r.const_context().server_addr;
+    let remote_addr = r.const_context().remote_addr();
+    let content_length = match (r.body(), r.body_path()) {
+        (Some(body), _) => body.len(),
+        (None, Some(path)) => std::fs::metadata(path).map(|meta| meta.len() as usize).unwrap_or(0),
+        _ => 0
+    };
+    let mut env = vec![
+        ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE".to_string(), "WS-Platform/0.0.1".to_string()),
+        ("SERVER_PROTOCOL".to_string(), format!("HTTP/{}", r.protocol())),
+        ("SERVER_NAME".to_string(), r.host().clone()),
+        ("SERVER_ADDR".to_string(), server_addr.ip().to_string()),
+        ("SERVER_PORT".to_string(), server_addr.port().to_string()),
+        ("REMOTE_ADDR".to_string(), remote_addr.ip().to_string()),
+        ("REMOTE_PORT".to_string(), remote_addr.port().to_string()),
+        ("REQUEST_METHOD".to_string(), format!("{}", r.method())),
+        ("REQUEST_URI".to_string(), r.request_uri().clone()),
+        ("SCRIPT_NAME".to_string(), String::new()),
+        ("PATH_INFO".to_string(), r.uri().clone()),
+        ("QUERY_STRING".to_string(), r.query_string().to_string())
+    ];
+    if content_length != 0 {
+        env.push(("CONTENT_LENGTH".to_string(), content_length.to_string()));
+    }
+    for (name, values) in r.headers().iter() {
+        let value = values.iter().map(String::as_str).collect::<Vec<_>>().join(", ");
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        match name.as_str() {
+            "CONTENT_TYPE" => env.push((name, value)),
+            "CONTENT_LENGTH" | "PROXY" => {},
+            _ => env.push((format!("HTTP_{}", name), value))
+        }
+    }
+    if let Ok(path) = std::env::var("PATH") {
+        env.push(("PATH".to_string(), path));
+    }
+    env
+}
+
+// Output of the program: headers, an empty line and the body
+struct CgiResponse {
+    status: HttpStatus,
+    content_type: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>
+}
+
+fn parse_output(output: &[u8]) -> Result<CgiResponse, CoreError> {
+    // no headers at all is an empty line first
+    let (end, skip) = match (output.windows(4).position(|w| w == b"\r\n\r\n"), output.windows(2).position(|w| w == b"\n\n")) {
+        _ if output.starts_with(b"\r\n") => (0, 2),
+        _ if output.starts_with(b"\n") => (0, 1),
+        (Some(crlf), Some(lf)) if lf < crlf => (lf, 2),
+        (Some(crlf), _) => (crlf, 4),
+        (None, Some(lf)) => (lf, 2),
+        (None, None) => return throw!("no end of the headers")
+    };
+    let mut resp = CgiResponse {
+        status: HttpStatus::OK,
+        content_type: "text/plain".to_string(),
+        headers: Vec::new(),
+        body: output[end + skip..].to_vec()
+    };
+    let mut status = None;
+    for line in String::from_utf8_lossy(&output[..end]).lines() {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return throw!("invalid header '{}'", line)
+        };
+        if name.eq_ignore_ascii_case("status") {
+            // 'Status: 404 Not Found'
+            match value.split_whitespace().next().and_then(|code| code.parse::<i64>().ok()) {
+                Some(code) => status = Some(HttpStatus::from(code)),
+                None => return throw!("invalid status '{}'", value)
+            }
+        } else if name.eq_ignore_ascii_case("content-type") {
+            resp.content_type = value.to_string();
+        } else {
+            if name.eq_ignore_ascii_case("location") && status.is_none() {
+                status = Some(HttpStatus::MOVED_TEMPORARILY);
+            }
+            resp.headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    if let Some(status) = status {
+        resp.status = status;
+    }
+    Ok(resp)
+}
+
+// The children of the program hold the pipes as well
+fn kill(pid: u32) {
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+fn nonblocking(fd: RawFd) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+}
+
+// Exit of the program is readable on the pidfd, None on the kernels without pidfd_open
+fn pidfd(pid: u32) -> Option<File> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    match fd >= 0 {
+        true => Some(unsafe { File::from_raw_fd(fd as RawFd) }),
+        false => None
+    }
+}
+
+// Body of the request, written to stdin as the pipe takes it
+struct Input {
+    stdin: Option<ChildStdin>,
+    body: Vec<u8>,
+    pos: usize,
+    // spooled body is read by chunks
+    file: Option<File>
+}
+
+impl Input {
+    fn write(&mut self) {
+        let stdin = match &mut self.stdin {
+            Some(stdin) => stdin,
+            None => return
+        };
+        loop {
+            if self.pos == self.body.len() {
+                self.pos = 0;
+                self.body.resize(EXEC_PIPE_CHUNK, 0);
+                let size = match &mut self.file {
+                    Some(file) => file.read(&mut self.body).unwrap_or(0),
+                    None => 0
+                };
+                self.body.truncate(size);
+                if size == 0 {
+                    // the end of the body is the end of stdin
+                    self.stdin = None;
+                    return;
+                }
+            }
+            match stdin.write(&self.body[self.pos..]) {
+                Ok(size) => self.pos += size,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    // the program doesn't read the rest
+                    self.stdin = None;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Reads what the pipe has, up to limit + 1 bytes are kept, false at the end of the output
+fn drain(pipe: &mut impl Read, output: &mut Vec<u8>, limit: usize) -> std::io::Result<bool> {
+    let mut buf = [0u8; 16384];
+    loop {
+        match pipe.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(size) => {
+                let size = std::cmp::min(size, (limit + 1).saturating_sub(output.len()));
+                output.extend_from_slice(&buf[..size]);
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(true),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+// The pipes and the exit of the program are served by poll() in the calling thread, so neither of the pipes
+// can block the program. Output over max_output or the timeout kills the program.
+fn run(r: &HttpRequest, program: &Program) -> Result<Outcome, CoreError> {
+    let mut command = Command::new(&program.path);
+    command.args(program.args.iter().map(|arg| r.expand(arg)))
+           .env_clear()
+           .envs(cgi_env(r))
+           .envs(program.env.iter().filter_map(|(name, values)| values.iter().last().map(|value| (name.to_string(), r.expand(value)))))
+           .stdin(Stdio::piped())
+           .stdout(Stdio::piped())
+           .stderr(Stdio::piped())
+           .process_group(0);
+    let mut child = command.spawn().or_else(|err| throw!("'{}': {}", program.path, err))?;
+    let pid = child.id();
+    let exit = pidfd(pid);
+
+    let mut input = Input {
+        stdin: child.stdin.take(),
+        body: r.body().map(|body| body.to_vec()).unwrap_or_default(),
+        pos: 0,
+        file: match (r.body(), r.body_path()) {
+            (None, Some(path)) => File::open(path).ok(),
+            _ => None
+        }
+    };
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+    input.stdin.iter().for_each(|stdin| nonblocking(stdin.as_raw_fd()));
+    stdout.iter().for_each(|stdout| nonblocking(stdout.as_raw_fd()));
+    stderr.iter().for_each(|stderr| nonblocking(stderr.as_raw_fd()));
+
+    let deadline = Instant::now() + program.timeout;
+    let (mut output, mut errors) = (Vec::new(), Vec::new());
+    let mut exited = false;
+    let mut timed_out = false;
+
+    loop {
+        input.write();
+        if let Some(pipe) = &mut stdout {
+            if !drain(pipe, &mut output, program.max_output).or_else(|err| throw!("'{}': {}", program.path, err))? {
+                stdout = None;
+            }
+            if output.len() > program.max_output {
+                kill(pid);
+                stdout = None;
+            }
+        }
+        if let Some(pipe) = &mut stderr {
+            if !drain(pipe, &mut errors, EXEC_MAX_ERRORS).unwrap_or(false) {
+                stderr = None;
+            }
+        }
+        if !exited {
+            exited = child.try_wait().or_else(|err| throw!("wait: {}", err))?.is_some();
+        }
+        if exited && stdout.is_none() && stderr.is_none() {
+            break;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            // the children of the program may hold the pipes after its exit
+            kill(pid);
+            let _ = child.wait();
+            timed_out = true;
+            break;
+        }
+        let mut timeout = deadline - now;
+
+        let mut fds = Vec::with_capacity(4);
+        let mut watch = |fd: RawFd, events: libc::c_short| fds.push(libc::pollfd { fd, events, revents: 0 });
+        input.stdin.iter().for_each(|stdin| watch(stdin.as_raw_fd(), libc::POLLOUT));
+        stdout.iter().for_each(|stdout| watch(stdout.as_raw_fd(), libc::POLLIN));
+        stderr.iter().for_each(|stderr| watch(stderr.as_raw_fd(), libc::POLLIN));
+        match (&exit, exited) {
+            (Some(exit), false) => watch(exit.as_raw_fd(), libc::POLLIN),
+            // the exit is not reported without pidfd, it is checked again shortly
+            (None, false) => timeout = std::cmp::min(timeout, EXEC_WAIT_INTERVAL),
+            _ => {}
+        }
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout.as_millis() as libc::c_int + 1) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                kill(pid);
+                let _ = child.wait();
+                return throw!("poll: {}", err);
+            }
+        }
+    }
+
+    for line in String::from_utf8_lossy(&errors).lines() {
+        log_http_error!(r, "warn", "exec '{}': {}", program.path, line);
+    }
+    match (output.len() > program.max_output, timed_out) {
+        (true, _) => Ok(Outcome::TooLarge),
+        (false, true) => Ok(Outcome::TimedOut),
+        (false, false) => Ok(Outcome::Completed(output))
+    }
+}
+
+fn error(resp: &mut HttpResponse, status: HttpStatus) {
+    resp.send(status, "text/plain", Some(format!("{}", status).as_bytes()));
+}
+
+fn respond(resp: &mut HttpResponse, program: &Program) {
+    let uri = resp.get_request().uri().clone();
+    let output = match run(resp.get_request(), program) {
+        Ok(Outcome::Completed(output)) => output,
+        Ok(Outcome::TimedOut) => {
+            log_http_error!(resp, "error", "exec '{}' has timed out, uri={}", program.path, uri);
+            return error(resp, HttpStatus::GATEWAY_TIMEOUT);
+        },
+        Ok(Outcome::TooLarge) => {
+            log_http_error!(resp, "error", "exec '{}': output exceeds max_output {}, uri={}", program.path, program.max_output, uri);
+            return error(resp, HttpStatus::BAD_GATEWAY);
+        },
+        Err(err) => {
+            log_http_error!(resp, "error", "exec {}, uri={}", err, uri);
+            return error(resp, HttpStatus::BAD_GATEWAY);
+        }
+    };
+    match parse_output(&output) {
+        Ok(cgi) => {
+            for (name, value) in cgi.headers {
+                resp.add_header(&name, &value);
+            }
+            resp.send(cgi.status, &cgi.content_type, Some(&cgi.body));
+        },
+        Err(err) => {
+            log_http_error!(resp, "error", "exec '{}': invalid response, {}, uri={}", program.path, err, uri);
+            error(resp, HttpStatus::BAD_GATEWAY)
+        }
+    }
+}
+
+impl Plugin for Exec {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Exec"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::ROUTE, "exec.program", |exec: &mut ExecContext, program: String| {
+            exec.program = Some(program);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "exec.args", |exec: &mut ExecContext, args: HttpList| {
+            exec.args = args;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "exec.env", |exec: &mut ExecContext, env: HttpMap| {
+            exec.env = env;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "exec.timeout", |exec: &mut ExecContext, timeout: Duration| {
+            exec.timeout = Some(timeout);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "exec.max_concurrent", |exec: &mut ExecContext, max_concurrent: usize| {
+            exec.max_concurrent = Some(max_concurrent);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "exec.max_output", |exec: &mut ExecContext, max_output: usize| {
+            exec.max_output = Some(max_output);
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "exec", Schema::new()
+            .optional("program", SchemaType::String)
+            .optional("args", SchemaType::List)
+            .optional("env", SchemaType::Map)
+            .optional("timeout", SchemaType::Integer)
+            .range("timeout", 1, i64::MAX)
+            .optional("max_concurrent", SchemaType::Integer)
+            .range("max_concurrent", 1, i64::MAX)
+            .optional("max_output", SchemaType::Integer)
+            .range("max_output", 1, i64::MAX))?;
+
+        add_block!(Context::ROUTE, "exec", |context, program: String| {
+            match context.get_mut::<ExecContext>() {
+                Some(exec) => {
+                    // exit
+                    let exec = take(exec);
+                    let path = match exec.program {
+                        Some(program) if !program.is_empty() => program,
+                        _ => return throw!("exec: 'program' is required")
+                    };
+                    let program = Arc::new(Program {
+                        path,
+                        args: exec.args,
+                        env: exec.env,
+                        timeout: exec.timeout.unwrap_or(EXEC_TIMEOUT_DEFAULT),
+                        max_output: exec.max_output.unwrap_or(EXEC_MAX_OUTPUT_DEFAULT)
+                    });
+                    let max_concurrent = exec.max_concurrent;
+                    let running = Arc::new(AtomicUsize::new(0));
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                               let count = running.fetch_add(1, Ordering::Relaxed) + 1;
+                               let running = Running { count: Arc::clone(&running) };
+                               let mut resp = HttpResponse::new(r);
+                               if let Some(max_concurrent) = max_concurrent {
+                                   if count > max_concurrent {
+                                       let uri = resp.get_request().uri().clone();
+                                       log_http_error!(resp, "warn", "exec: max_concurrent {} exceeded, uri={}", max_concurrent, uri);
+                                       error(&mut resp, HttpStatus::SERVICE_UNAVAILABLE);
+                                       return resp;
+                                   }
+                               }
+                               // the program runs on the executor of AsyncTask, not on the worker,
+                               // the response goes to the IO thread when it completes
+                               let program = Arc::clone(&program);
+                               resp.defer(Box::new(move |resp, completion| {
+                                   async_task::spawn(move || {
+                                       let mut resp = resp;
+                                       respond(&mut resp, &program);
+                                       drop(running);
+                                       completion.complete(resp);
+                                   });
+                               }));
+                               resp
+                           }));
+                    Ok(None)
+                },
+                None => {
+                    // enter
+                    let mut exec = ExecContext::default();
+                    if !program.is_empty() {
+                        exec.program = Some(program);
+                    }
+                    Ok(Some(CommandContext::new(exec)))
+                }
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl Exec {
+    pub fn new() -> Exec {
+        Exec {}
+    }
+}
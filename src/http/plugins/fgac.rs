@@ -0,0 +1,410 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(FGAC);
+
+use std::sync::{ Arc, RwLock };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::mem::take;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::http::fgac::{ self, Attributes, Effect, Pattern, Policy, Rule };
+use crate::core::status::{ self, Json };
+use crate::error::{ Code, CoreError };
+
+// Sources of the subject attributes
+#[derive(Default, Clone)]
+struct Subject {
+    jwt: Option<HttpComplexValue>,
+    jwt_secret: Option<String>,
+    attributes: Vec<(String, HttpComplexValue)>
+}
+
+#[derive(Default)]
+pub struct FGACContext {
+    subject: Subject,
+    policies: Vec<Policy>,
+    policy_files: Vec<String>,
+    decision_log: bool
+}
+
+pub struct PolicyContext {
+    name: String,
+    default: Effect,
+    rules: Vec<Rule>
+}
+
+impl Default for PolicyContext {
+    fn default() -> PolicyContext {
+        PolicyContext {
+            name: String::new(),
+            default: Effect::Deny,
+            rules: Vec::new()
+        }
+    }
+}
+
+pub struct RuleContext {
+    effect: Effect,
+    subject: Vec<(String, Vec<Pattern>)>,
+    resources: Vec<Pattern>,
+    methods: Vec<String>
+}
+
+impl Default for RuleContext {
+    fn default() -> RuleContext {
+        RuleContext {
+            effect: Effect::Allow,
+            subject: Vec::new(),
+            resources: Vec::new(),
+            methods: Vec::new()
+        }
+    }
+}
+
+pub struct FGAC {
+    subject: Arc<RwLock<Subject>>,
+    decision_log: Arc<AtomicBool>
+}
+
+fn pattern(value: &HttpComplexValue) -> Result<Pattern, CoreError> {
+    match value.text() {
+        Some(text) => Pattern::parse(&text),
+        None => throw!("variables are not allowed in patterns")
+    }
+}
+
+fn attributes(subject: &Subject, r: &HttpRequest) -> Result<Attributes, CoreError> {
+    let mut attrs = match &subject.jwt {
+        Some(jwt) => {
+            let token = r.expand(jwt);
+            if token.is_empty() {
+                Attributes::new()
+            } else {
+                fgac::jwt_claims(&token, subject.jwt_secret.as_ref().map(|secret| secret.as_bytes()).unwrap_or_default())?
+            }
+        },
+        None => Attributes::new()
+    };
+    // claims of the signed token take precedence, the request can't add values to them
+    let claims: Vec<String> = attrs.keys().cloned().collect();
+    for (name, value) in subject.attributes.iter() {
+        if claims.contains(name) {
+            continue;
+        }
+        let value = r.expand(value);
+        if !value.is_empty() {
+            attrs.entry(name.clone()).or_default().push(value);
+        }
+    }
+    Ok(attrs)
+}
+
+fn forbidden(r: &mut HttpRequest) {
+    r.set_context("content", ContentHandler::new(|r| -> HttpResponse {
+        let mut resp = HttpResponse::new(r);
+        resp.send(HttpStatus::FORBIDDEN, "text/plain", Some(b"Forbidden"));
+        resp
+    }));
+}
+
+impl Plugin for FGAC {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "FGAC"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        // Rule
+
+        add_command!(Context::HTTP, "fgac.policies.policy.rules.rule.effect", |rule: &mut RuleContext, effect: String| {
+            rule.effect = Effect::parse(&effect)?;
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fgac.policies.policy.rules.rule.subject", |rule: &mut RuleContext, subject: HttpMap| {
+            for (name, values) in subject.iter() {
+                let mut patterns = Vec::with_capacity(values.len());
+                for value in values.iter() {
+                    patterns.push(pattern(value)?);
+                }
+                rule.subject.push((name.to_string(), patterns));
+            }
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fgac.policies.policy.rules.rule.resources", |rule: &mut RuleContext, resources: HttpList| {
+            for resource in resources.iter() {
+                rule.resources.push(pattern(resource)?);
+            }
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fgac.policies.policy.rules.rule.methods", |rule: &mut RuleContext, methods: HttpList| {
+            for method in methods.iter() {
+                match method.text() {
+                    Some(method) => rule.methods.push(method),
+                    None => return throw!("variables are not allowed in methods")
+                }
+            }
+            Ok(None)
+        })?;
+
+        add_schema!(Context::HTTP, "fgac.policies.policy.rules.rule", Schema::new()
+            .required("effect", SchemaType::String)
+            .optional("subject", SchemaType::Map)
+            .optional("resources", SchemaType::List)
+            .optional("methods", SchemaType::List))?;
+
+        add_block!(Context::HTTP, "fgac.policies.policy.rules.rule", |context| {
+            match context.get_mut::<RuleContext>() {
+                Some(rule) => {
+                    // exit
+                    let rule = take(rule);
+                    context.parent().unwrap()
+                           .get_mut::<PolicyContext>().unwrap()
+                           .rules.push(Rule {
+                               effect: rule.effect,
+                               subject: rule.subject,
+                               resources: rule.resources,
+                               methods: rule.methods
+                           });
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<RuleContext>()))
+            }
+        })?;
+
+        add_empty_block!(Context::HTTP, "fgac.policies.policy.rules")?;
+
+        // Policy
+
+        add_command!(Context::HTTP, "fgac.policies.policy.name", |policy: &mut PolicyContext, name: String| {
+            policy.name = name;
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fgac.policies.policy.default", |policy: &mut PolicyContext, default: String| {
+            policy.default = Effect::parse(&default)?;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::HTTP, "fgac.policies.policy", Schema::new()
+            .required("name", SchemaType::String)
+            .optional("default", SchemaType::String)
+            .optional("rules", SchemaType::List))?;
+
+        add_block!(Context::HTTP, "fgac.policies.policy", |context| {
+            match context.get_mut::<PolicyContext>() {
+                Some(policy) => {
+                    // exit
+                    let policy = take(policy);
+                    if policy.name.is_empty() {
+                        return throw!("'fgac.policies.policy.name' is not defined");
+                    }
+                    context.parent().unwrap()
+                           .get_mut::<FGACContext>().unwrap()
+                           .policies.push(Policy {
+                               name: policy.name,
+                               default: policy.default,
+                               rules: policy.rules
+                           });
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<PolicyContext>()))
+            }
+        })?;
+
+        add_empty_block!(Context::HTTP, "fgac.policies")?;
+
+        // Subject
+
+        add_command!(Context::HTTP, "fgac.jwt", |fgac: &mut FGACContext, jwt: HttpComplexValue| {
+            fgac.subject.jwt = Some(jwt);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fgac.jwt_secret", |fgac: &mut FGACContext, jwt_secret: String| {
+            fgac.subject.jwt_secret = Some(jwt_secret);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fgac.attributes", |fgac: &mut FGACContext, attributes: HttpMap| {
+            for (name, values) in attributes.iter() {
+                for value in values.iter() {
+                    fgac.subject.attributes.push((name.to_string(), value.clone()));
+                }
+            }
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fgac.policy_files", |fgac: &mut FGACContext, policy_files: HttpList| {
+            for filename in policy_files.iter() {
+                match filename.text() {
+                    Some(filename) => fgac.policy_files.push(filename),
+                    None => return throw!("variables are not allowed in policy_files")
+                }
+            }
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fgac.decision_log", |fgac: &mut FGACContext, decision_log: bool| {
+            fgac.decision_log = decision_log;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::HTTP, "fgac", Schema::new()
+            .optional("jwt", SchemaType::String)
+            .optional("jwt_secret", SchemaType::String)
+            .optional("attributes", SchemaType::Map)
+            .optional("policies", SchemaType::List)
+            .optional("policy_files", SchemaType::List)
+            .optional("decision_log", SchemaType::Bool))?;
+
+        let subject_ = Arc::clone(&self.subject);
+        let decision_log_ = Arc::clone(&self.decision_log);
+
+        add_block!(Context::HTTP, "fgac", move |context| {
+            match context.get_mut::<FGACContext>() {
+                Some(fgac) => {
+                    // exit
+                    let fgac = take(fgac);
+                    // claims of the unsigned tokens are never trusted
+                    if fgac.subject.jwt.is_some() && fgac.subject.jwt_secret.as_ref().map(|secret| secret.is_empty()).unwrap_or(true) {
+                        return throw!("fgac: 'jwt_secret' is required with 'jwt'");
+                    }
+                    *subject_.write().unwrap() = fgac.subject;
+                    decision_log_.store(fgac.decision_log, Ordering::Relaxed);
+                    for policy in fgac.policies {
+                        fgac::add(policy);
+                    }
+                    for filename in fgac.policy_files.iter() {
+                        fgac::add_file(filename);
+                    }
+                    fgac::reload()?;
+                    status::register("plugins", "fgac", Box::new(|| {
+                        Some(Json::object(vec![
+                            ("policies", Json::Array(fgac::names().into_iter().map(|name| name.into()).collect()))
+                        ]))
+                    }));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<FGACContext>()))
+            }
+        })?;
+
+        // Route
+
+        // Policies from the request body are added or replaced, without body policy files are reloaded,
+        // the route or its server must authenticate the clients
+
+        add_command!(Context::ROUTE, "fgac_reload", |route: &mut RouteContext| {
+            route.admin = true;
+            route.content = Some(ContentHandler::new(|r| -> HttpResponse {
+                let result = match r.body_contents() {
+                    Ok(Some(body)) if !body.is_empty() => {
+                        fgac::parse(&String::from_utf8_lossy(&body)).map(|policies| {
+                            let count = policies.len();
+                            for policy in policies {
+                                fgac::add(policy);
+                            }
+                            count
+                        })
+                    },
+                    Ok(_) => fgac::reload(),
+                    Err(err) => Err(err)
+                };
+                let mut resp = HttpResponse::new(r);
+                match result {
+                    Ok(count) => {
+                        log_http_error!(resp, "info", "fgac: {} policies reloaded", count);
+                        resp.send(HttpStatus::OK, "text/plain", Some(format!("reloaded: {}\n", count).as_bytes()));
+                    },
+                    Err(err) => {
+                        log_http_error!(resp, "error", "fgac: reload failed, {}", err.what());
+                        resp.send(HttpStatus::BAD_REQUEST, "text/plain", Some(format!("{}\n", err.what()).as_bytes()));
+                    }
+                }
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        let subject_ = Arc::clone(&self.subject);
+        let decision_log_ = Arc::clone(&self.decision_log);
+
+        add_command!(Context::ROUTE, "fgac", move |route: &mut RouteContext, name: String| {
+            let subject_ = Arc::clone(&subject_);
+            let decision_log_ = Arc::clone(&decision_log_);
+            route.auth = true;
+            route.access.push_back(AccessHandler::new(move |r| -> Code {
+                // policy is resolved on request, unknown policy denies access
+                let policy = match fgac::get(&name) {
+                    Some(policy) => policy,
+                    None => {
+                        log_http_error!(r, "error", "fgac: policy '{}' is not defined", name);
+                        forbidden(r);
+                        return Code::DECLINED;
+                    }
+                };
+                let subject = subject_.read().unwrap().clone();
+                let attrs = match attributes(&subject, r) {
+                    Ok(attrs) => attrs,
+                    Err(err) => {
+                        log_http_error!(r, "warn", "fgac: policy '{}', {}", name, err.what());
+                        forbidden(r);
+                        return Code::DECLINED;
+                    }
+                };
+                let method = format!("{}", r.method());
+                let decision = policy.evaluate(&attrs, r.uri(), &method);
+                let rule = match decision.rule {
+                    Some(i) => i.to_string(),
+                    None => String::from("default")
+                };
+                if decision_log_.load(Ordering::Relaxed) {
+                    let sub = attrs.get("sub").and_then(|sub| sub.first()).map(|sub| sub.as_str()).unwrap_or("-");
+                    log_http_error!(r, "info", "fgac decision: policy={} rule={} effect={} subject={} client={} method={} uri={}",
+                                    name, rule, decision.effect, sub, r.const_context().remote_addr(), method, r.uri());
+                }
+                r.add_var("fgac_policy", HttpComplexValue::simple(&name));
+                r.add_var("fgac_rule", HttpComplexValue::simple(&rule));
+                r.add_var("fgac_effect", HttpComplexValue::simple(&decision.effect.to_string()));
+                if decision.effect == Effect::Deny {
+                    forbidden(r);
+                }
+                Code::DECLINED
+            }));
+
+            Ok(None)
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl Default for FGAC {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FGAC {
+    pub fn new() -> FGAC {
+        FGAC {
+            subject: Arc::new(RwLock::new(Subject::default())),
+            decision_log: Arc::new(AtomicBool::new(false))
+        }
+    }
+}
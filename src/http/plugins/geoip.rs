@@ -0,0 +1,183 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(GeoIP);
+
+use std::collections::HashMap;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::{ Arc, Mutex };
+
+use maxminddb::{ Reader, geoip2 };
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::variable::*;
+use crate::error::Code;
+
+type Database = Arc<Reader<Vec<u8>>>;
+
+type Field = fn(&geoip2::City) -> Option<String>;
+
+fn name_en(names: &Option<std::collections::BTreeMap<&str, &str>>) -> Option<String> {
+    names.as_ref().and_then(|names| names.get("en")).map(|name| name.to_string())
+}
+
+const FIELDS: [(&str, Field); 11] = [
+    ("geoip_country_code", |city| city.country.as_ref()?.iso_code.map(|s| s.to_string())),
+    ("geoip_country_name", |city| name_en(&city.country.as_ref()?.names)),
+    ("geoip_continent_code", |city| city.continent.as_ref()?.code.map(|s| s.to_string())),
+    ("geoip_region", |city| city.subdivisions.as_ref()?.first()?.iso_code.map(|s| s.to_string())),
+    ("geoip_region_name", |city| name_en(&city.subdivisions.as_ref()?.first()?.names)),
+    ("geoip_city", |city| name_en(&city.city.as_ref()?.names)),
+    ("geoip_postal_code", |city| city.postal.as_ref()?.code.map(|s| s.to_string())),
+    ("geoip_latitude", |city| city.location.as_ref()?.latitude.map(|v| v.to_string())),
+    ("geoip_longitude", |city| city.location.as_ref()?.longitude.map(|v| v.to_string())),
+    ("geoip_time_zone", |city| city.location.as_ref()?.time_zone.map(|s| s.to_string())),
+    ("geoip_registered_country_code", |city| city.registered_country.as_ref()?.iso_code.map(|s| s.to_string()))
+];
+
+pub struct GeoIPContext {
+    database: Option<String>,
+    source: HttpComplexValue
+}
+
+impl Default for GeoIPContext {
+    fn default() -> GeoIPContext {
+        GeoIPContext {
+            database: None,
+            source: HttpComplexValue::complex("${remote_addr}")
+        }
+    }
+}
+
+pub struct GeoIP {
+    databases: Arc<Mutex<HashMap<String, Database>>>
+}
+
+// Source may be an address with port or X-Forwarded-For list, the first address is used
+fn client_ip(source: &str) -> Option<IpAddr> {
+    let source = source.split(',').next()?.trim();
+    match source.parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) => source.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+    }
+}
+
+// Fields of the record of the request, looked up by the first variable read
+#[derive(Default)]
+struct Record(Mutex<Option<Vec<String>>>);
+
+fn lookup(database: &Database, source: &str) -> Vec<String> {
+    let city = client_ip(source).and_then(|ip| database.lookup::<geoip2::City>(ip).ok());
+    FIELDS.iter().map(|(_, field)| {
+        city.as_ref().and_then(|city| field(city)).unwrap_or_default()
+    }).collect()
+}
+
+fn field(r: &HttpRequest, database: &Database, source: &HttpComplexValue, i: usize) -> String {
+    let record = match r.get_context::<Record>("geoip") {
+        Some(record) => record,
+        None => return String::new()
+    };
+    if let Some(fields) = &*record.0.lock().unwrap() {
+        return fields[i].clone();
+    }
+    // source is expanded without the lock, it may be a variable of another module
+    let fields = lookup(database, &r.expand(source));
+    let value = fields[i].clone();
+    *record.0.lock().unwrap() = Some(fields);
+    value
+}
+
+impl Plugin for GeoIP {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "GeoIP"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::SERVER, "geoip.database", |geoip: &mut GeoIPContext, database: String| {
+            geoip.database = Some(database);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "geoip.source", |geoip: &mut GeoIPContext, source: HttpComplexValue| {
+            geoip.source = source;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::SERVER, "geoip", Schema::new()
+            .required("database", SchemaType::String)
+            .optional("source", SchemaType::String))?;
+
+        let databases_ = Arc::clone(&self.databases);
+
+        add_block!(Context::SERVER, "geoip", move |context| {
+            match context.get_mut::<GeoIPContext>() {
+                Some(geoip) => {
+                    // exit
+                    let filename = match &geoip.database {
+                        Some(filename) => filename.clone(),
+                        None => return throw!("'geoip.database' is not defined")
+                    };
+
+                    let database = {
+                        let mut databases = databases_.lock().unwrap();
+                        match databases.get(&filename) {
+                            Some(database) => Arc::clone(database),
+                            None => {
+                                let database = match Reader::open_readfile(&filename) {
+                                    Ok(reader) => Arc::new(reader),
+                                    Err(err) => return throw!("Failed to open geoip database '{}': {}", filename, err)
+                                };
+                                databases.insert(filename, Arc::clone(&database));
+                                database
+                            }
+                        }
+                    };
+
+                    let source = geoip.source.clone();
+
+                    context.parent().unwrap()
+                           .get_mut::<ServerContext>().unwrap()
+                           .setvar.push_back(SetVarHandler::new(move |r| {
+                               r.set_context("geoip", Record::default());
+                               for (i, (name, _)) in FIELDS.iter().enumerate() {
+                                   let database = Arc::clone(&database);
+                                   let source = source.clone();
+                                   r.add_var(name, HttpComplexValue::lazy(LazyHandler::new(move |r: &HttpRequest| {
+                                       field(r, &database, &source, i)
+                                   })));
+                               }
+                               Code::DECLINED
+                           }));
+
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<GeoIPContext>()))
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl Default for GeoIP {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GeoIP {
+    pub fn new() -> GeoIP {
+        GeoIP {
+            databases: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+}
@@ -0,0 +1,125 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(Health);
+
+use std::mem::take;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::http::inflight;
+use crate::core::{ signals, upgrade };
+
+#[derive(Clone, Copy, PartialEq)]
+enum Check {
+    // the process serves the requests
+    Liveness,
+    // the process accepts the new traffic
+    Readiness
+}
+
+// 'health: readiness' or { check: readiness, status: 200, unavailable_status: 503 }
+#[derive(Default)]
+pub struct HealthContext {
+    check: Option<String>,
+    status: Option<HttpStatus>,
+    unavailable_status: Option<HttpStatus>
+}
+
+#[derive(Default)]
+pub struct Health
+{}
+
+// Reason of the process not being ready
+fn not_ready() -> Option<&'static str> {
+    if !upgrade::started() {
+        Some("starting")
+    } else if upgrade::upgrading() {
+        Some("reloading")
+    } else if signals::shutdown_requested() || inflight::draining() {
+        Some("draining")
+    } else {
+        None
+    }
+}
+
+impl Plugin for Health {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Health"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::ROUTE, "health.check", |health: &mut HealthContext, check: String| {
+            health.check = Some(check);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "health.status", |health: &mut HealthContext, status: i64| {
+            health.status = Some(HttpStatus::from(status));
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "health.unavailable_status", |health: &mut HealthContext, status: i64| {
+            health.unavailable_status = Some(HttpStatus::from(status));
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "health", Schema::new()
+            .optional("check", SchemaType::String)
+            .optional("status", SchemaType::Integer)
+            .range("status", 100, 599)
+            .optional("unavailable_status", SchemaType::Integer)
+            .range("unavailable_status", 100, 599))?;
+
+        add_block!(Context::ROUTE, "health", |context, check: String| {
+            match context.get_mut::<HealthContext>() {
+                Some(health) => {
+                    // exit
+                    let health = take(health);
+                    let check = match health.check.as_deref() {
+                        Some("liveness") | None => Check::Liveness,
+                        Some("readiness") => Check::Readiness,
+                        Some(check) => return throw!("health: unknown check '{}', 'liveness' or 'readiness' expected", check)
+                    };
+                    let status = health.status.unwrap_or(HttpStatus::OK);
+                    let unavailable_status = health.unavailable_status.unwrap_or(HttpStatus::SERVICE_UNAVAILABLE);
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                               let mut resp = HttpResponse::new(r);
+                               let (status, text) = match (check, not_ready()) {
+                                   (Check::Readiness, Some(reason)) => (unavailable_status, reason),
+                                   (Check::Readiness, None) => (status, "ready"),
+                                   (Check::Liveness, _) => (status, "alive")
+                               };
+                               resp.send(status, "text/plain", Some(format!("{}\n", text).as_bytes()));
+                               resp.set_header("Cache-Control", "no-store");
+                               resp
+                           }));
+                    Ok(None)
+                },
+                None => {
+                    // enter
+                    let mut health = HealthContext::default();
+                    if !check.is_empty() {
+                        health.check = Some(check);
+                    }
+                    Ok(Some(CommandContext::new(health)))
+                }
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {}
+    }
+}
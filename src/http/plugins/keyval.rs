@@ -0,0 +1,330 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(Keyval);
+
+use std::mem::take;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+use yaml_rust::Yaml;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::core::keyval_zone::{ self, KeyvalZone, Shared, State };
+use crate::core::status::Json;
+use crate::error::CoreError;
+use crate::redis;
+
+#[derive(Default)]
+pub struct KeyvalZoneContext {
+    name: Option<String>,
+    timeout: Option<Duration>,
+    max_entries: Option<usize>,
+    state_file: Option<String>,
+    sync_interval: Option<Duration>,
+    redis: Option<String>,
+    redis_prefix: Option<String>,
+    // values of the redis zone looked up by the variables are fresh during cache_ttl
+    cache_ttl: Option<Duration>,
+    cache_stale_ttl: Option<Duration>,
+    // entries of the snapshot, the zone in memory only
+    entries: Vec<(String, String)>
+}
+
+// { key: value, ... }
+struct Entries(Vec<(String, String)>);
+
+impl crate::config::Value for Entries {
+    type Type = Entries;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        match v {
+            Yaml::Hash(h) => take(h).iter().map(|(key, value)| match (key.as_str(), value.as_str()) {
+                (Some(key), Some(value)) => Ok((key.to_string(), value.to_string())),
+                _ => throw!("type mismatch")
+            }).collect::<Result<_, _>>().map(Entries),
+            _ => throw!("type mismatch")
+        }
+    }
+}
+
+// Zones with the state file are saved by the syncer and once more at exit
+pub struct Keyval {
+    stop: Arc<AtomicBool>,
+    syncer: Option<JoinHandle<()>>
+}
+
+// GET ?key=k is the value, GET is all the entries as JSON,
+// POST/PUT/PATCH ?key=k&value=v (or the body) sets, DELETE ?key=k removes, DELETE clears
+fn call(zone: &KeyvalZone, method: HttpMethod, key: Option<String>, value: Option<String>) -> Result<(HttpStatus, String), CoreError> {
+    let not_found = (HttpStatus::NOT_FOUND, "key not found".to_string());
+    let done = (HttpStatus::NO_CONTENT, String::new());
+    Ok(match (method, key) {
+        (HttpMethod::GET, Some(key)) | (HttpMethod::HEAD, Some(key)) => match zone.get(&key)? {
+            Some(value) => (HttpStatus::OK, value),
+            None => not_found
+        },
+        (HttpMethod::GET, None) | (HttpMethod::HEAD, None) => {
+            let entries = zone.entries()?.into_iter().map(|(key, value)| (key, Json::from(value))).collect();
+            (HttpStatus::OK, Json::Object(entries).to_string())
+        },
+        (HttpMethod::POST, Some(key)) | (HttpMethod::PUT, Some(key)) | (HttpMethod::PATCH, Some(key)) => {
+            zone.set(&key, &value.unwrap_or_default())?;
+            done
+        },
+        (HttpMethod::POST, None) | (HttpMethod::PUT, None) | (HttpMethod::PATCH, None) =>
+            (HttpStatus::BAD_REQUEST, "key required".to_string()),
+        (HttpMethod::DELETE, Some(key)) => match zone.delete(&key)? {
+            true => done,
+            false => not_found
+        },
+        (HttpMethod::DELETE, None) => {
+            zone.clear()?;
+            done
+        },
+        _ => (HttpStatus::NOT_ALLOWED, "method not allowed".to_string())
+    })
+}
+
+// Failure of the shared zone is 502
+fn api(r: HttpRequest, zone: &KeyvalZone) -> HttpResponse {
+    let method = r.method();
+    let all = matches!(method, HttpMethod::GET | HttpMethod::HEAD) && r.args().exact("key").is_none();
+    let key = r.args().exact("key").cloned();
+    let value = match r.args().exact("value") {
+        Some(value) => Ok(Some(value.clone())),
+        None => r.body_contents().map(|body| body.map(|body| String::from_utf8_lossy(&body).to_string()))
+    };
+    let mut resp = HttpResponse::new(r);
+    let value = match value {
+        Ok(value) => value,
+        Err(err) => {
+            let uri = resp.get_request().uri().clone();
+            log_http_error!(resp, "error", "keyval_api: {}, uri={}", err, uri);
+            resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(b"Internal server error"));
+            return resp;
+        }
+    };
+    match call(zone, method, key, value) {
+        Ok((HttpStatus::NO_CONTENT, _)) => resp.send(HttpStatus::NO_CONTENT, "text/plain", None),
+        Ok((HttpStatus::OK, text)) if all => resp.send(HttpStatus::OK, "application/json", Some(text.as_bytes())),
+        Ok((status, text)) => resp.send(status, "text/plain", Some(text.as_bytes())),
+        Err(err) => {
+            let uri = resp.get_request().uri().clone();
+            log_http_error!(resp, "error", "keyval_api: {}, uri={}", err, uri);
+            resp.send(HttpStatus::BAD_GATEWAY, "text/plain", Some(b"Bad gateway"));
+        }
+    }
+    resp
+}
+
+impl Plugin for Keyval {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Keyval"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.name", |keyval_zone: &mut KeyvalZoneContext, name: String| {
+            keyval_zone.name = Some(name);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.timeout", |keyval_zone: &mut KeyvalZoneContext, timeout: Duration| {
+            keyval_zone.timeout = Some(timeout);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.max_entries", |keyval_zone: &mut KeyvalZoneContext, max_entries: usize| {
+            keyval_zone.max_entries = Some(max_entries);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.state_file", |keyval_zone: &mut KeyvalZoneContext, state_file: String| {
+            keyval_zone.state_file = Some(state_file);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.sync_interval", |keyval_zone: &mut KeyvalZoneContext, sync_interval: Duration| {
+            keyval_zone.sync_interval = Some(sync_interval);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.redis", |keyval_zone: &mut KeyvalZoneContext, redis: String| {
+            keyval_zone.redis = Some(redis);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.redis_prefix", |keyval_zone: &mut KeyvalZoneContext, redis_prefix: String| {
+            keyval_zone.redis_prefix = Some(redis_prefix);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.cache_ttl", |keyval_zone: &mut KeyvalZoneContext, cache_ttl: Duration| {
+            keyval_zone.cache_ttl = Some(cache_ttl);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.cache_stale_ttl", |keyval_zone: &mut KeyvalZoneContext, cache_stale_ttl: Duration| {
+            keyval_zone.cache_stale_ttl = Some(cache_stale_ttl);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "keyval_zones.keyval_zone.entries", |keyval_zone: &mut KeyvalZoneContext, entries: Entries| {
+            keyval_zone.entries = entries.0;
+            Ok(None)
+        })?;
+
+        add_empty_block!(Context::HTTP, "keyval_zones")?;
+
+        add_schema!(Context::HTTP, "keyval_zones.keyval_zone", Schema::new()
+            .required("name", SchemaType::String)
+            .optional("timeout", SchemaType::Integer)
+            .range("timeout", 1, i64::MAX)
+            .optional("max_entries", SchemaType::Integer)
+            .range("max_entries", 1, i64::MAX)
+            .optional("state_file", SchemaType::String)
+            .optional("sync_interval", SchemaType::Integer)
+            .range("sync_interval", 1, i64::MAX)
+            .optional("redis", SchemaType::String)
+            .optional("redis_prefix", SchemaType::String)
+            .optional("cache_ttl", SchemaType::Integer)
+            .range("cache_ttl", 1, i64::MAX)
+            .optional("cache_stale_ttl", SchemaType::Integer)
+            .range("cache_stale_ttl", 0, i64::MAX)
+            .optional("entries", SchemaType::Map))?;
+
+        add_block!(Context::HTTP, "keyval_zones.keyval_zone", |context| {
+            match context.get_mut::<KeyvalZoneContext>() {
+                Some(keyval_zone) => {
+                    // exit
+                    let name = match keyval_zone.name.take() {
+                        Some(name) => name,
+                        None => return throw!("keyval_zone: 'name' required")
+                    };
+                    if name.contains('[') {
+                        return throw!("keyval_zone: invalid name '{}'", name);
+                    }
+                    let state = keyval_zone.state_file.take().map(|path| State {
+                        path,
+                        sync_interval: keyval_zone.sync_interval.unwrap_or(Duration::from_secs(1))
+                    });
+                    let cache = match (keyval_zone.cache_ttl, keyval_zone.cache_stale_ttl) {
+                        (Some(ttl), stale_ttl) => Some((ttl, stale_ttl.unwrap_or_default())),
+                        (None, Some(_)) => return throw!("keyval_zone: 'cache_stale_ttl' requires 'cache_ttl'"),
+                        (None, None) => None
+                    };
+                    let shared = match keyval_zone.redis.take() {
+                        Some(server) => Some(Shared::new(&name,
+                            match redis::get(&server) {
+                                Some(redis) => redis,
+                                None => return throw!("keyval_zone: redis '{}' is not defined", server)
+                            },
+                            keyval_zone.redis_prefix.take().unwrap_or_else(|| format!("keyval:{}:", name)),
+                            cache,
+                            keyval_zone.max_entries
+                        )),
+                        None if cache.is_some() => return throw!("keyval_zone: 'cache_ttl' is accepted with 'redis' only"),
+                        None => None
+                    };
+                    if !keyval_zone.entries.is_empty() && (state.is_some() || shared.is_some()) {
+                        return throw!("keyval_zone: 'entries' are not accepted with 'state_file' or 'redis'");
+                    }
+                    if let Err(err) = keyval_zone::create(&name, keyval_zone.timeout, keyval_zone.max_entries, state, shared) {
+                        return throw!("keyval_zone: {}", err);
+                    }
+                    let zone = keyval_zone::get(&name).unwrap();
+                    for (key, value) in keyval_zone.entries.drain(..) {
+                        zone.set(&key, &value).or_else(|err| throw!("keyval_zone '{}': {}", name, err.what()))?;
+                    }
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<KeyvalZoneContext>()))
+            }
+        })?;
+
+        // entries of the zones in memory, the rest are kept by the state file or redis
+        Config::add_snapshot_provider(Box::new(|doc| {
+            for block in blocks_mut(node_mut(doc, &["http", "keyval_zones"]), "keyval_zone") {
+                let zone = match block.get(&Yaml::String("name".to_string())).and_then(|name| name.as_str()).and_then(keyval_zone::get) {
+                    Some(zone) if !zone.persistent() => zone,
+                    _ => continue
+                };
+                let entries = zone.entries().unwrap_or_default().into_iter()
+                    .map(|(key, value)| (Yaml::String(key), Yaml::String(value)))
+                    .collect();
+                block.insert(Yaml::String("entries".to_string()), Yaml::Hash(entries));
+            }
+        }));
+
+        // Route
+
+        add_command!(Context::ROUTE, "keyval_api", |route: &mut RouteContext, name: String| {
+            let zone = match keyval_zone::get(&name) {
+                Some(zone) => zone,
+                None => return throw!("keyval_api: zone '{}' is not defined", name)
+            };
+            route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                api(r, &zone)
+            }));
+            Ok(None)
+        })?;
+
+        Ok(OK)
+    }
+
+    fn activate(&mut self) -> ActionResult {
+        if !keyval_zone::persistent() || self.syncer.is_some() {
+            return Ok(OK);
+        }
+        self.stop.store(false, Ordering::Relaxed);
+        let stop = Arc::clone(&self.stop);
+        self.syncer = Some(thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(100));
+                keyval_zone::sync_all(false);
+            }
+        }));
+        Ok(OK)
+    }
+
+    // The next activation starts the syncer again
+    fn deactivate(&mut self) -> ActionResult {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(syncer) = self.syncer.take() {
+            let _ = syncer.join();
+        }
+        Ok(OK)
+    }
+
+    // Values set while the servers were draining are saved at exit
+    fn wait(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(syncer) = self.syncer.take() {
+            let _ = syncer.join();
+        }
+        keyval_zone::sync_all(true);
+    }
+}
+
+impl Default for Keyval {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Keyval {
+    pub fn new() -> Keyval {
+        Keyval {
+            stop: Arc::new(AtomicBool::new(false)),
+            syncer: None
+        }
+    }
+}
@@ -0,0 +1,81 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(LimitRate);
+
+use crate::plugin::*;
+use crate::http::*;
+
+#[derive(Default)]
+pub struct LimitRate
+{}
+
+impl Plugin for LimitRate {
+    type ModuleType = HTTP;
+
+    fn configure(&mut self) -> ActionResult {
+
+        // Http, inherited by the servers
+
+        add_command!(Context::HTTP, "limit_rate", |http: &mut HttpContext, limit_rate: usize| {
+            http.limit_rate = Some(limit_rate);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "limit_rate_after", |http: &mut HttpContext, limit_rate_after: usize| {
+            http.limit_rate_after = Some(limit_rate_after);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "limit_upload_rate", |http: &mut HttpContext, limit_upload_rate: usize| {
+            http.limit_upload_rate = limit_upload_rate;
+            Ok(None)
+        })?;
+
+        // Server
+
+        add_command!(Context::SERVER, "limit_rate", |server: &mut ServerContext, limit_rate: usize| {
+            server.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                resp.set_limit_rate(limit_rate)
+            }));
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "limit_rate_after", |server: &mut ServerContext, limit_rate_after: usize| {
+            server.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                resp.set_limit_rate_after(limit_rate_after)
+            }));
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "limit_upload_rate", |server: &mut ServerContext, limit_upload_rate: usize| {
+            server.limit_upload_rate = limit_upload_rate;
+            Ok(None)
+        })?;
+
+        // Route
+
+        add_command!(Context::ROUTE, "limit_rate", |route: &mut RouteContext, limit_rate: usize| {
+            route.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                resp.set_limit_rate(limit_rate)
+            }));
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "limit_rate_after", |route: &mut RouteContext, limit_rate_after: usize| {
+            route.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                resp.set_limit_rate_after(limit_rate_after)
+            }));
+            Ok(None)
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl LimitRate {
+    pub fn new() -> LimitRate {
+        LimitRate {}
+    }
+}
@@ -4,14 +4,87 @@
 
 register_http_plugin!(LuaAPI);
 
+use std::cell::{ Cell, RefCell };
+use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{ Hash, Hasher };
-use rlua::{ Function, Lua };
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpStream };
+use std::sync::{ Arc, RwLock, atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering } };
+use std::time::{ Duration, Instant };
+use rlua::{ Context as LuaContext, Function, Lua, Table, Value as LuaValue, Variadic };
 
 use crate::plugin::*;
 use crate::http::*;
+use crate::http::multipart::Part;
+use crate::keyval::KeyVal;
+use crate::tcp_socket::TcpSocket;
 
-pub struct LuaAPI {}
+const LUA_POOL_SIZE_DEFAULT: usize = 4;
+
+// Capture blocks the worker running the script until the subrequest is served by another worker of the
+// thread pool of the workgroup. With every worker waiting for its capture nobody serves the subrequests
+// and all of them end by lua_capture_timeout, so lua_capture_limit is kept below thread_pool_size
+// and the excess fails at once instead of waiting
+static CAPTURE_LIMIT: AtomicUsize = AtomicUsize::new(2);
+static CAPTURES: AtomicUsize = AtomicUsize::new(0);
+// whole subrequest, milliseconds
+static CAPTURE_TIMEOUT: AtomicU64 = AtomicU64::new(5000);
+// response with the headers
+static CAPTURE_MAX_SIZE: AtomicUsize = AtomicUsize::new(1024 * 1024);
+
+// Capture in progress
+struct CaptureSlot;
+
+impl CaptureSlot {
+    fn acquire() -> Result<CaptureSlot, String> {
+        let limit = CAPTURE_LIMIT.load(Ordering::Relaxed);
+        match CAPTURES.fetch_add(1, Ordering::SeqCst) < limit {
+            true => Ok(CaptureSlot),
+            false => {
+                CAPTURES.fetch_sub(1, Ordering::SeqCst);
+                Err(format!("too many captures in progress, lua_capture_limit={}", limit))
+            }
+        }
+    }
+}
+
+impl Drop for CaptureSlot {
+    fn drop(&mut self) {
+        CAPTURES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// lua_code_cache is off for the development, the script is compiled for every request in a new state
+pub struct LuaAPI {
+    code_cache: Arc<AtomicBool>,
+    pool_size: Arc<AtomicUsize>
+}
+
+lazy_static! {
+    // Bytecode of the scripts compiled by any thread
+    static ref BYTECODE: RwLock<HashMap<String, Arc<Vec<u8>>>> = RwLock::new(HashMap::new());
+}
+
+thread_local! {
+    // Idle states of the thread, loaded scripts are kept in their globals
+    static STATES: RefCell<Vec<Lua>> = const { RefCell::new(Vec::new()) };
+}
+
+// Response of the script: ngx.status, ngx.header and the output of ngx.say and ngx.print
+#[derive(Default)]
+struct LuaOutput {
+    status: Option<i64>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>
+}
+
+// Response of ngx.location.capture
+struct Captured {
+    status: i64,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>
+}
 
 fn get_hash<T: Hash>(t: &T) -> String {
     let mut s = DefaultHasher::new();
@@ -19,30 +92,411 @@ fn get_hash<T: Hash>(t: &T) -> String {
     format!("closure_{}", s.finish())
 }
 
+// Parts as the array of { name, filename, content_type, size, data or path of the spooled one }
+fn parts_table<'lua>(ctx: LuaContext<'lua>, parts: &[Part]) -> rlua::Result<Table<'lua>> {
+    let table = ctx.create_table()?;
+    for (i, part) in parts.iter().enumerate() {
+        let t = ctx.create_table()?;
+        t.set("name", part.name())?;
+        t.set("filename", part.filename())?;
+        t.set("content_type", part.content_type())?;
+        t.set("size", part.size())?;
+        match part.path() {
+            Some(path) => t.set("path", path.to_string_lossy().into_owned())?,
+            None => t.set("data", ctx.create_string(&part.bytes().map_err(rlua::Error::external)?[..])?)?
+        }
+        table.set(i + 1, t)?;
+    }
+    Ok(table)
+}
+
+// Repeated names are the arrays of the values, names of the headers are in lower case
+fn keyval_table<'lua>(ctx: LuaContext<'lua>, keyval: &KeyVal<String>, lower: bool) -> rlua::Result<Table<'lua>> {
+    let table = ctx.create_table()?;
+    for (name, values) in keyval.iter() {
+        let name = match lower {
+            true => name.to_ascii_lowercase(),
+            false => name.to_string()
+        };
+        match values.len() {
+            1 => table.set(name, values.front().unwrap().as_str())?,
+            _ => table.set(name, ctx.create_sequence_from(values.iter().map(|value| value.as_str()))?)?
+        }
+    }
+    Ok(table)
+}
+
+// Arguments of ngx.say and ngx.print, the arrays are flattened
+fn output<'lua>(ctx: LuaContext<'lua>, value: LuaValue<'lua>, body: &mut Vec<u8>) -> rlua::Result<()> {
+    match value {
+        LuaValue::Nil => body.extend_from_slice(b"nil"),
+        LuaValue::Boolean(b) => body.extend_from_slice(if b { b"true" } else { b"false" }),
+        LuaValue::Table(table) => {
+            for value in table.sequence_values::<LuaValue>() {
+                output(ctx, value?, body)?;
+            }
+        },
+        value => match ctx.coerce_string(value)? {
+            Some(s) => body.extend_from_slice(s.as_bytes()),
+            None => return Err(rlua::Error::RuntimeError("string expected".to_string()))
+        }
+    }
+    Ok(())
+}
+
+// Name with the string or the array of strings
+fn header_values<'lua>(name: String, value: LuaValue<'lua>, headers: &mut Vec<(String, String)>) -> rlua::Result<()> {
+    match value {
+        LuaValue::String(s) => headers.push((name, s.to_str()?.to_string())),
+        LuaValue::Integer(i) => headers.push((name, i.to_string())),
+        LuaValue::Number(n) => headers.push((name, n.to_string())),
+        LuaValue::Table(table) => {
+            for value in table.sequence_values::<String>() {
+                headers.push((name.clone(), value?));
+            }
+        },
+        _ => return Err(rlua::Error::RuntimeError(format!("invalid value of the header '{}'", name)))
+    }
+    Ok(())
+}
+
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut body = Vec::with_capacity(data.len());
+    loop {
+        let eol = data.windows(2).position(|w| w == b"\r\n").ok_or("invalid chunk")?;
+        let size = String::from_utf8_lossy(&data[..eol]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap().trim(), 16).or(Err("invalid chunk size"))?;
+        data = &data[eol + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size + 2 {
+            return Err("truncated chunk".to_string());
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+// Method and header names are tokens, uri and header values have no controls,
+// so the script can't split the subrequest with CR or LF
+fn capture_request(method: &str, uri: &str, headers: &[(String, String)]) -> Result<(), String> {
+    let token = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_graphic() && !b"\"(),/:;<=>?@[\\]{}".contains(&c));
+    if !token(method) {
+        return Err(format!("invalid method '{}'", method.escape_debug()));
+    }
+    if uri.is_empty() || uri.bytes().any(|c| c <= b' ' || c == 0x7f) {
+        return Err(format!("invalid uri '{}'", uri.escape_debug()));
+    }
+    for (name, value) in headers {
+        if !token(name) {
+            return Err(format!("invalid header name '{}'", name.escape_debug()));
+        }
+        if value.bytes().any(|c| (c < b' ' && c != b'\t') || c == 0x7f) {
+            return Err(format!("invalid value of the header '{}'", name));
+        }
+    }
+    Ok(())
+}
+
+// Subrequest is the HTTP/1.0 request to the plain listener of the request, the script waits for the response
+// up to lua_capture_timeout, the response is limited by lua_capture_max_size
+fn capture(addr: SocketAddr, scheme: &str, host: &str, method: &str, uri: &str, headers: &[(String, String)], body: Option<&[u8]>) -> Result<Captured, String> {
+    if scheme == "https" {
+        return Err("TLS listeners are not supported".to_string());
+    }
+    capture_request(method, uri, headers)?;
+    let _slot = CaptureSlot::acquire()?;
+
+    let deadline = Instant::now() + Duration::from_millis(CAPTURE_TIMEOUT.load(Ordering::Relaxed));
+    let remaining = || match deadline.checked_duration_since(Instant::now()) {
+        Some(timeout) if timeout > Duration::from_millis(0) => Ok(timeout),
+        _ => Err("timed out".to_string())
+    };
+    let max_size = CAPTURE_MAX_SIZE.load(Ordering::Relaxed);
+
+    let mut stream = TcpStream::connect_timeout(&addr, remaining()?).map_err(|err| err.to_string())?;
+
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n", method, uri, host);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(body) = body {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    stream.set_write_timeout(Some(remaining()?)).map_err(|err| err.to_string())?;
+    stream.write_all(request.as_bytes()).map_err(|err| err.to_string())?;
+    if let Some(body) = body {
+        stream.set_write_timeout(Some(remaining()?)).map_err(|err| err.to_string())?;
+        stream.write_all(body).map_err(|err| err.to_string())?;
+    }
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 16384];
+    loop {
+        stream.set_read_timeout(Some(remaining()?)).map_err(|err| err.to_string())?;
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) if response.len() + n > max_size => return Err(format!("response is larger than lua_capture_max_size={}", max_size)),
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err.to_string())
+        }
+    }
+
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").ok_or("invalid response")?;
+    let head = String::from_utf8_lossy(&response[..end]).to_string();
+    let mut lines = head.split("\r\n");
+    let status = lines.next()
+                      .and_then(|line| line.split_whitespace().nth(1))
+                      .and_then(|status| status.parse::<i64>().ok())
+                      .ok_or("invalid status line")?;
+    let headers: Vec<(String, String)> = lines.filter_map(|line| line.split_once(':'))
+                                              .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                                              .collect();
+    let body = &response[end + 4..];
+    let chunked = headers.iter().any(|(name, value)| name.eq_ignore_ascii_case("transfer-encoding") && value.to_ascii_lowercase().contains("chunked"));
+    let body = match chunked {
+        true => dechunk(body)?,
+        false => body.to_vec()
+    };
+    Ok(Captured { status, headers, body })
+}
+
+// State of the pool is returned unless the pool is full, the state is not reused without the code cache
+fn with_state<R>(code_cache: bool, pool_size: usize, f: impl FnOnce(&Lua) -> R) -> R {
+    if !code_cache {
+        return f(&Lua::new());
+    }
+    let lua = STATES.with(|states| states.borrow_mut().pop()).unwrap_or_default();
+    let result = f(&lua);
+    STATES.with(|states| {
+        let mut states = states.borrow_mut();
+        if states.len() < pool_size {
+            states.push(lua);
+        }
+    });
+    result
+}
+
+// Script loaded into the state before, loaded from the bytecode of another thread, or compiled
+fn closure<'lua>(ctx: LuaContext<'lua>, closure_name: &str, code: &str, code_cache: bool) -> rlua::Result<Function<'lua>> {
+    if !code_cache {
+        return ctx.load(code).set_name(closure_name)?.into_function();
+    }
+    let globals = ctx.globals();
+    if let Ok(closure) = globals.get::<_, Function>(closure_name) {
+        return Ok(closure);
+    }
+    let bytecode = BYTECODE.read().unwrap().get(closure_name).cloned();
+    let closure = match bytecode {
+        Some(bytecode) => {
+            let load: Function = globals.get("load")?;
+            load.call::<_, Function>((ctx.create_string(&bytecode[..])?, closure_name, "b"))?
+        },
+        None => {
+            let closure = ctx.load(code).set_name(closure_name)?.into_function()?;
+            let dump: Function = globals.get::<_, Table>("string")?.get("dump")?;
+            let bytecode = dump.call::<_, rlua::String>(closure.clone())?;
+            BYTECODE.write().unwrap().insert(closure_name.to_string(), Arc::new(bytecode.as_bytes().to_vec()));
+            closure
+        }
+    };
+    globals.set(closure_name, closure.clone())?;
+    Ok(closure)
+}
+
+fn run<'lua>(
+    ctx: LuaContext<'lua>,
+    closure: Function<'lua>,
+    r: &HttpRequest,
+    client: &TcpSocket,
+    parts: &Result<Vec<Part>, String>
+) -> rlua::Result<LuaOutput> {
+    let globals = ctx.globals();
+
+    let out = RefCell::new(LuaOutput::default());
+    let exited = Cell::new(false);
+    let ngx = ctx.create_table()?;
+    let header = ctx.create_table()?;
+    ngx.set("header", header.clone())?;
+
+    ctx.scope(|scope| {
+        // the script polls cancelled() to stop working for the client which has gone away
+        globals.set("cancelled", scope.create_function(|_, ()| Ok(client.closed()))?)?;
+        // multipart() returns the parts or nil and the error
+        globals.set("multipart", scope.create_function(|ctx, ()| match parts {
+            Ok(parts) => Ok((Some(parts_table(ctx, parts)?), None)),
+            Err(err) => Ok((None, Some(err.clone())))
+        })?)?;
+
+        let req = ctx.create_table()?;
+        req.set("get_method", scope.create_function(|_, ()| Ok(format!("{}", r.method())))?)?;
+        req.set("get_uri", scope.create_function(|_, ()| Ok(r.uri().clone()))?)?;
+        req.set("get_headers", scope.create_function(|ctx, ()| keyval_table(ctx, r.headers(), true))?)?;
+        req.set("get_uri_args", scope.create_function(|ctx, ()| keyval_table(ctx, r.args(), false))?)?;
+        // the spooled body is read back from its file, get_body_file is the name of that file or nil
+        req.set("get_body_data", scope.create_function(|ctx, ()| {
+            let body = r.body_contents().map_err(|err| rlua::Error::RuntimeError(err.what().to_string()))?;
+            body.map(|body| ctx.create_string(&body[..])).transpose()
+        })?)?;
+        req.set("get_body_file", scope.create_function(|_, ()| Ok(r.body_path().map(|path| path.to_string_lossy().to_string())))?)?;
+        ngx.set("req", req)?;
+
+        // ngx.var.name is the variable of the request, nil if empty
+        let var = ctx.create_table()?;
+        let meta = ctx.create_table()?;
+        meta.set("__index", scope.create_function(|_, (_, name): (Table, String)| {
+            let value = r.expand(&HttpComplexValue::complex(&format!("${{{}}}", name)));
+            Ok(match value.is_empty() {
+                true => None,
+                false => Some(value)
+            })
+        })?)?;
+        var.set_metatable(Some(meta));
+        ngx.set("var", var)?;
+
+        ngx.set("print", scope.create_function(|ctx, args: Variadic<LuaValue>| {
+            let body = &mut out.borrow_mut().body;
+            args.into_iter().try_for_each(|arg| output(ctx, arg, body))
+        })?)?;
+        ngx.set("say", scope.create_function(|ctx, args: Variadic<LuaValue>| {
+            let body = &mut out.borrow_mut().body;
+            args.into_iter().try_for_each(|arg| output(ctx, arg, body))?;
+            body.push(b'\n');
+            Ok(())
+        })?)?;
+        // the script is stopped by the error, which is not reported
+        ngx.set("exit", scope.create_function(|_, status: i64| -> rlua::Result<()> {
+            out.borrow_mut().status = Some(status);
+            exited.set(true);
+            Err(rlua::Error::RuntimeError("exit".to_string()))
+        })?)?;
+
+        // ngx.location.capture(uri, { method, body, headers }) returns { status, header, body }
+        let location = ctx.create_table()?;
+        location.set("capture", scope.create_function(|ctx, (uri, options): (String, Option<Table>)| {
+            let mut method = "GET".to_string();
+            let mut body: Option<rlua::String> = None;
+            let mut headers = Vec::new();
+            if let Some(options) = options {
+                method = options.get::<_, Option<String>>("method")?.unwrap_or(method).to_uppercase();
+                body = options.get("body")?;
+                if let Some(table) = options.get::<_, Option<Table>>("headers")? {
+                    for pair in table.pairs::<String, LuaValue>() {
+                        let (name, value) = pair?;
+                        header_values(name, value, &mut headers)?;
+                    }
+                }
+            }
+            let captured = capture(r.const_context().server_addr, r.scheme(), r.host(), &method, &uri, &headers, body.as_ref().map(|body| body.as_bytes()))
+                .map_err(|err| rlua::Error::RuntimeError(format!("capture '{}': {}", uri, err)))?;
+            let result = ctx.create_table()?;
+            result.set("status", captured.status)?;
+            let header = ctx.create_table()?;
+            for (name, value) in captured.headers {
+                header.set(name.to_ascii_lowercase(), value)?;
+            }
+            result.set("header", header)?;
+            result.set("body", ctx.create_string(&captured.body)?)?;
+            Ok(result)
+        })?)?;
+        ngx.set("location", location)?;
+
+        globals.set("ngx", ngx.clone())?;
+
+        // text returned by the script is the end of the body
+        match closure.call::<_, Option<rlua::String>>(()) {
+            Ok(Some(text)) => out.borrow_mut().body.extend_from_slice(text.as_bytes()),
+            Ok(None) => {},
+            Err(_) if exited.get() => {},
+            Err(err) => return Err(err)
+        }
+        Ok(())
+    })?;
+
+    let mut out = out.into_inner();
+    if out.status.is_none() {
+        out.status = ngx.get::<_, Option<i64>>("status")?;
+    }
+    for pair in header.pairs::<String, LuaValue>() {
+        let (name, value) = pair?;
+        header_values(name, value, &mut out.headers)?;
+    }
+    Ok(out)
+}
+
+fn send(resp: &mut HttpResponse, out: LuaOutput) {
+    let status = HttpStatus::from(out.status.unwrap_or(200));
+    let mut content_type = "text/plain".to_string();
+    for (name, value) in out.headers {
+        match name.eq_ignore_ascii_case("content-type") {
+            true => content_type = value,
+            false => resp.add_header(&name, &value)
+        }
+    }
+    match out.body.is_empty() && out.status.unwrap_or(200) >= 400 {
+        true => resp.send(status, &content_type, Some(format!("{}", status).as_bytes())),
+        false => resp.send(status, &content_type, Some(&out.body))
+    }
+}
+
 impl Plugin for LuaAPI {
     type ModuleType = HTTP;
 
     fn configure(&mut self) -> ActionResult {
-        add_command!(Context::ROUTE, "lua", |route: &mut RouteContext, code: String| {
+        let code_cache_ = Arc::clone(&self.code_cache);
+
+        add_command!(Context::HTTP, "lua_code_cache", move |_: &mut HttpContext, code_cache: bool| {
+            code_cache_.store(code_cache, Ordering::Relaxed);
+            Ok(None)
+        })?;
+
+        let pool_size_ = Arc::clone(&self.pool_size);
+
+        add_command!(Context::HTTP, "lua_pool_size", move |_: &mut HttpContext, pool_size: usize| {
+            pool_size_.store(pool_size, Ordering::Relaxed);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "lua_capture_limit", |_: &mut HttpContext, limit: usize| {
+            CAPTURE_LIMIT.store(limit, Ordering::Relaxed);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "lua_capture_timeout", |_: &mut HttpContext, timeout: Duration| {
+            CAPTURE_TIMEOUT.store(timeout.as_millis() as u64, Ordering::Relaxed);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "lua_capture_max_size", |_: &mut HttpContext, max_size: usize| {
+            CAPTURE_MAX_SIZE.store(max_size, Ordering::Relaxed);
+            Ok(None)
+        })?;
+
+        let (code_cache, pool_size) = (Arc::clone(&self.code_cache), Arc::clone(&self.pool_size));
+
+        add_command!(Context::ROUTE, "lua", move |route: &mut RouteContext, code: String| {
             let closure_name = get_hash(&code);
-            thread_local!(static LUA_STATE: Lua = Lua::new());
+            let (code_cache, pool_size) = (Arc::clone(&code_cache), Arc::clone(&pool_size));
             route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
-                let mut resp = HttpResponse::new(r);
-                LUA_STATE.with(|lua| {
-                    let closure_name_ = closure_name.clone();
-                    lua.context(|ctx| {
-                        let globals = ctx.globals();
-                        let closure = match globals.get::<_, Function>(closure_name_.clone()) {
-                            Ok(closure) => closure,
-                            _ => {
-                                ctx.load(&format!("function {}() {} end", &closure_name_, code)).exec().unwrap();
-                                globals.get::<_, Function>(closure_name_).unwrap()
-                            }
-                        };
-                        let text = closure.call::<_, String>(()).unwrap();
-                        resp.send(HttpStatus::OK, "text/plain", Some(text.as_bytes()));
-                    })
+                let client = r.const_context().weak();
+                let parts: Result<Vec<Part>, String> = r.multipart()
+                    .and_then(|parts| parts.collect())
+                    .map_err(|err| err.what().to_string());
+                let code_cache = code_cache.load(Ordering::Relaxed);
+                let out = with_state(code_cache, pool_size.load(Ordering::Relaxed), |lua| {
+                    lua.context(|ctx| run(ctx, closure(ctx, &closure_name, &code, code_cache)?, &r, &client, &parts))
                 });
+                let mut resp = HttpResponse::new(r);
+                match out {
+                    Ok(out) => send(&mut resp, out),
+                    Err(err) => {
+                        log_http_error!(resp, "error", "lua: {}", err);
+                        resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(b"Internal server error"));
+                    }
+                }
                 resp
             }));
 
@@ -53,6 +507,26 @@ impl Plugin for LuaAPI {
 
 impl LuaAPI {
     pub fn new() -> LuaAPI {
-        LuaAPI {}
+        LuaAPI {
+            code_cache: Arc::new(AtomicBool::new(true)),
+            pool_size: Arc::new(AtomicUsize::new(LUA_POOL_SIZE_DEFAULT))
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture_requests() {
+        let header = |name: &str, value: &str| vec![(name.to_string(), value.to_string())];
+        assert!(capture_request("GET", "/a?b=c", &header("X-Id", "1\t2")).is_ok());
+        assert!(capture_request("GET /x HTTP/1.0\r\n", "/a", &[]).is_err());
+        assert!(capture_request("GET", "/a HTTP/1.0\r\nX-Id: 1\r\n", &[]).is_err());
+        assert!(capture_request("GET", "/a", &header("X-Id\r\nHost", "a")).is_err());
+        assert!(capture_request("GET", "/a", &header("X-Id: 1", "a")).is_err());
+        assert!(capture_request("GET", "/a", &header("X-Id", "1\r\nHost: b")).is_err());
+        assert!(capture_request("GET", "/a", &header("X-Id", "1\nHost: b")).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(MaxConcurrency);
+
+use std::sync::{ Arc, Mutex, Condvar };
+use std::time::{ Duration, Instant };
+use std::mem::take;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::error::Code;
+
+#[derive(Default)]
+pub struct MaxConcurrencyContext {
+    limit: usize,
+    queue: Option<usize>,
+    queue_timeout: Option<Duration>
+}
+
+#[derive(Default)]
+struct Usage {
+    active: usize,
+    waiting: usize
+}
+
+struct Slots {
+    limit: usize,
+    // requests waiting for the slot, each of them holds the worker
+    queue: usize,
+    queue_timeout: Option<Duration>,
+    usage: Mutex<Usage>,
+    released: Condvar
+}
+
+// Slot is held until the request is dropped
+struct SlotGuard {
+    slots: Arc<Slots>
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        self.slots.usage.lock().unwrap().active -= 1;
+        self.slots.released.notify_one();
+    }
+}
+
+impl Slots {
+    fn new(limit: usize, queue: usize, queue_timeout: Option<Duration>) -> Slots {
+        Slots {
+            limit,
+            queue,
+            queue_timeout,
+            usage: Mutex::new(Usage::default()),
+            released: Condvar::new()
+        }
+    }
+
+    // Without queue_timeout excess is rejected immediately, otherwise up to 'queue' requests wait
+    // for the free slot during queue_timeout, the rest are rejected
+    fn acquire(slots: &Arc<Slots>) -> Option<SlotGuard> {
+        let mut usage = slots.usage.lock().unwrap();
+        if usage.active >= slots.limit {
+            let queue_timeout = match slots.queue_timeout {
+                Some(queue_timeout) if usage.waiting < slots.queue => queue_timeout,
+                _ => return None
+            };
+            let deadline = Instant::now() + queue_timeout;
+            usage.waiting += 1;
+            while usage.active >= slots.limit {
+                let now = Instant::now();
+                if now >= deadline {
+                    usage.waiting -= 1;
+                    return None;
+                }
+                usage = slots.released.wait_timeout(usage, deadline - now).unwrap().0;
+            }
+            usage.waiting -= 1;
+        }
+        usage.active += 1;
+        Some(SlotGuard {
+            slots: Arc::clone(slots)
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct MaxConcurrency
+{}
+
+impl Plugin for MaxConcurrency {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "MaxConcurrency"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::ROUTE, "max_concurrency.limit", |max_concurrency: &mut MaxConcurrencyContext, limit: usize| {
+            max_concurrency.limit = limit;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "max_concurrency.queue", |max_concurrency: &mut MaxConcurrencyContext, queue: usize| {
+            max_concurrency.queue = Some(queue);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "max_concurrency.queue_timeout", |max_concurrency: &mut MaxConcurrencyContext, queue_timeout: Duration| {
+            max_concurrency.queue_timeout = Some(queue_timeout);
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "max_concurrency", Schema::new()
+            .required("limit", SchemaType::Integer)
+            .range("limit", 1, i64::MAX)
+            .optional("queue", SchemaType::Integer)
+            .range("queue", 0, i64::MAX)
+            .optional("queue_timeout", SchemaType::Integer)
+            .range("queue_timeout", 1, i64::MAX))?;
+
+        add_block!(Context::ROUTE, "max_concurrency", |context| {
+            match context.get_mut::<MaxConcurrencyContext>() {
+                Some(max_concurrency) => {
+                    // exit
+                    let max_concurrency = take(max_concurrency);
+                    if max_concurrency.limit == 0 {
+                        return throw!("'max_concurrency.limit' is not defined");
+                    }
+                    if max_concurrency.queue.is_some() && max_concurrency.queue_timeout.is_none() {
+                        return throw!("'max_concurrency.queue' requires 'max_concurrency.queue_timeout'");
+                    }
+                    // queue is as long as the limit by default
+                    let queue = max_concurrency.queue.unwrap_or(max_concurrency.limit);
+                    let slots = Arc::new(Slots::new(max_concurrency.limit, queue, max_concurrency.queue_timeout));
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .access.push_back(AccessHandler::new(move |r| -> Code {
+                               match Slots::acquire(&slots) {
+                                   Some(guard) => r.set_context("max_concurrency", guard),
+                                   None => {
+                                       log_http_error!(r, "warn", "max_concurrency {} exceeded, uri={}", slots.limit, r.uri());
+                                       r.set_context("content", ContentHandler::new(|r| -> HttpResponse {
+                                           let mut resp = HttpResponse::new(r);
+                                           resp.send(HttpStatus::SERVICE_UNAVAILABLE, "text/plain", Some(b"Service unavailable"));
+                                           resp
+                                       }));
+                                   }
+                               }
+                               Code::DECLINED
+                           }));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<MaxConcurrencyContext>()))
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl MaxConcurrency {
+    pub fn new() -> MaxConcurrency {
+        MaxConcurrency {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn reject() {
+        let slots = Arc::new(Slots::new(2, 2, None));
+        let first = Slots::acquire(&slots).unwrap();
+        let _second = Slots::acquire(&slots).unwrap();
+        assert!(Slots::acquire(&slots).is_none());
+        drop(first);
+        assert!(Slots::acquire(&slots).is_some());
+    }
+
+    #[test]
+    fn queue() {
+        let slots = Arc::new(Slots::new(1, 1, Some(Duration::from_secs(60))));
+        let active = Slots::acquire(&slots).unwrap();
+        let waiter = {
+            let slots = Arc::clone(&slots);
+            thread::spawn(move || Slots::acquire(&slots).is_some())
+        };
+        while slots.usage.lock().unwrap().waiting == 0 {
+            thread::yield_now();
+        }
+        // the queue is full
+        assert!(Slots::acquire(&slots).is_none());
+        drop(active);
+        assert!(waiter.join().unwrap());
+        assert_eq!(slots.usage.lock().unwrap().waiting, 0);
+    }
+
+    #[test]
+    fn queue_timeout() {
+        let slots = Arc::new(Slots::new(1, 1, Some(Duration::from_millis(10))));
+        let _active = Slots::acquire(&slots).unwrap();
+        assert!(Slots::acquire(&slots).is_none());
+        let usage = slots.usage.lock().unwrap();
+        assert_eq!((usage.active, usage.waiting), (1, 0));
+    }
+}
@@ -0,0 +1,248 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(Mock);
+
+use std::mem::{ discriminant, take };
+use std::time::Duration;
+
+use yaml_rust::Yaml;
+use rand::Rng;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::http::condition::Condition;
+use crate::http::plugins::echo::{ self, EchoContext };
+use crate::error::CoreError;
+
+// { id: 42, name: '~ ^a' } are the exact values and the regexes of the request arguments
+struct ArgMatchers(Vec<Condition>);
+
+impl crate::config::Value for ArgMatchers {
+    type Type = ArgMatchers;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        match v {
+            Yaml::Hash(h) => take(h).into_iter().map(|(name, value)| {
+                let name = match name.as_str() {
+                    Some(name) => format!("${{arg_{}}}", name),
+                    None => return throw!("key type mismatch")
+                };
+                let value = match value {
+                    Yaml::String(s) => s,
+                    Yaml::Integer(i) => i.to_string(),
+                    Yaml::Real(r) => r,
+                    Yaml::Boolean(b) => b.to_string(),
+                    _ => return throw!("value type mismatch")
+                };
+                match value.strip_prefix("~ ") {
+                    Some(regex) => Condition::matches(&name, regex.trim()),
+                    None => Ok(Condition::equals(&name, &value))
+                }
+            }).collect::<Result<_, _>>().map(ArgMatchers),
+            _ => throw!("type mismatch")
+        }
+    }
+}
+
+// Canned response of the request matching all the matchers
+#[derive(Default)]
+pub struct MockResponseContext {
+    methods: Vec<HttpMethod>,
+    conditions: Vec<Condition>,
+    echo: EchoContext
+}
+
+impl MockResponseContext {
+    fn matches(&self, r: &HttpRequest) -> bool {
+        let method = r.method();
+        (self.methods.is_empty() || self.methods.iter().any(|m| discriminant(m) == discriminant(&method)))
+            && self.conditions.iter().all(|condition| condition.eval(|cv| r.expand(cv)))
+    }
+}
+
+#[derive(Default)]
+pub struct MockContext {
+    responses: Vec<MockResponseContext>,
+    latency: Option<Duration>,
+    // percents of the requests
+    latency_percent: Option<usize>,
+    error_percent: Option<usize>,
+    error_status: Option<HttpStatus>
+}
+
+#[derive(Default)]
+pub struct Mock
+{}
+
+fn chance(percent: usize) -> bool {
+    match percent {
+        0 => false,
+        100 => true,
+        _ => rand::thread_rng().gen_range(0..100) < percent
+    }
+}
+
+fn status(resp: &mut HttpResponse, status: HttpStatus) {
+    resp.send(status, "text/plain", Some(format!("{}", status).as_bytes()));
+}
+
+impl Plugin for Mock {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Mock"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::ROUTE, "mock.latency_ms", |mock: &mut MockContext, latency: Duration| {
+            mock.latency = Some(latency);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.latency_percent", |mock: &mut MockContext, percent: usize| {
+            mock.latency_percent = Some(percent);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.error_percent", |mock: &mut MockContext, percent: usize| {
+            mock.error_percent = Some(percent);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.error_status", |mock: &mut MockContext, status: i64| {
+            mock.error_status = Some(HttpStatus::from(status));
+            Ok(None)
+        })?;
+
+        // Response
+
+        add_command!(Context::ROUTE, "mock.responses.response.method", |response: &mut MockResponseContext, methods: Vec<HttpMethod>| {
+            response.methods = methods;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.responses.response.args", |response: &mut MockResponseContext, args: ArgMatchers| {
+            response.conditions.extend(args.0);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.responses.response.if", |response: &mut MockResponseContext, condition: String| {
+            response.conditions.push(Condition::parse(&condition)?);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.responses.response.status", |response: &mut MockResponseContext, status: i64| {
+            response.echo.status = Some(HttpStatus::from(status));
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.responses.response.headers", |response: &mut MockResponseContext, headers: HttpMap| {
+            response.echo.headers = headers;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.responses.response.body", |response: &mut MockResponseContext, body: HttpComplexValue| {
+            response.echo.text = Some(body);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.responses.response.file", |response: &mut MockResponseContext, file: HttpComplexValue| {
+            response.echo.file = Some(file);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "mock.responses.response.delay_ms", |response: &mut MockResponseContext, delay: Duration| {
+            response.echo.delay = Some(delay);
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "mock", Schema::new()
+            .required("responses", SchemaType::List)
+            .optional("latency_ms", SchemaType::Integer)
+            .range("latency_ms", 0, i64::MAX)
+            .optional("latency_percent", SchemaType::Integer)
+            .range("latency_percent", 0, 100)
+            .optional("error_percent", SchemaType::Integer)
+            .range("error_percent", 0, 100)
+            .optional("error_status", SchemaType::Integer)
+            .range("error_status", 100, 599))?;
+
+        add_schema!(Context::ROUTE, "mock.responses.response", Schema::new()
+            .optional("args", SchemaType::Map)
+            .optional("if", SchemaType::String)
+            .optional("status", SchemaType::Integer)
+            .range("status", 100, 599)
+            .optional("headers", SchemaType::Map)
+            .optional("body", SchemaType::String)
+            .optional("file", SchemaType::String)
+            .optional("delay_ms", SchemaType::Integer)
+            .range("delay_ms", 0, i64::MAX))?;
+
+        add_empty_block!(Context::ROUTE, "mock.responses")?;
+
+        add_block!(Context::ROUTE, "mock.responses.response", |context| {
+            match context.get_mut::<MockResponseContext>() {
+                Some(response) => {
+                    // exit
+                    let response = take(response);
+                    if response.echo.text.is_some() && response.echo.file.is_some() {
+                        return throw!("mock: 'body' and 'file' are exclusive");
+                    }
+                    context.parent().unwrap()
+                           .get_mut::<MockContext>().unwrap()
+                           .responses.push(response);
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<MockResponseContext>()))
+            }
+        })?;
+
+        add_block!(Context::ROUTE, "mock", |context| {
+            match context.get_mut::<MockContext>() {
+                Some(mock) => {
+                    // exit
+                    let mock = take(mock);
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                               let response = mock.responses.iter().find(|response| response.matches(&r));
+                               let mut resp = HttpResponse::new(r);
+                               // injected latency is added to the delay of the response
+                               let latency = match mock.latency {
+                                   Some(latency) if chance(mock.latency_percent.unwrap_or(100)) => latency,
+                                   _ => Duration::ZERO
+                               };
+                               let latency = latency + response.and_then(|response| response.echo.delay).unwrap_or_default();
+                               if !latency.is_zero() {
+                                   echo::delay(&mut resp, latency);
+                               }
+                               match response {
+                                   _ if chance(mock.error_percent.unwrap_or(0)) =>
+                                       status(&mut resp, mock.error_status.unwrap_or(HttpStatus::INTERNAL_SERVER_ERROR)),
+                                   Some(response) => echo::send(&mut resp, &response.echo),
+                                   None => status(&mut resp, HttpStatus::NOT_FOUND)
+                               }
+                               resp
+                           }));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<MockContext>()))
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl Mock {
+    pub fn new() -> Mock {
+        Mock {}
+    }
+}
@@ -5,14 +5,30 @@ pub mod server;
 pub mod async_task;
 pub mod lua;
 pub mod python;
+pub mod exec;
 pub mod basic_auth;
 pub mod rewrite;
 pub mod echo;
+pub mod mock;
+pub mod health;
+pub mod redis;
+pub mod keyval;
 pub mod access_log;
 pub mod proxy;
 pub mod upstream;
 pub mod least_conn;
 pub mod mod_headers;
+pub mod security_headers;
 pub mod mod_args;
 pub mod mod_vars;
-pub mod body_logger;
\ No newline at end of file
+pub mod body_logger;
+pub mod capture;
+pub mod snapshot;
+pub mod limit_rate;
+pub mod geoip;
+pub mod split_clients;
+pub mod max_concurrency;
+pub mod secure_link;
+pub mod fgac;
+pub mod beacon;
+pub mod negative_cache;
\ No newline at end of file
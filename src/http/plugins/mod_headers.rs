@@ -4,8 +4,82 @@
 
 register_http_plugin!(ModHeaders);
 
+use yaml_rust::Yaml;
+
 use crate::plugin::*;
+use crate::config::{ Value, ConfigBlock };
 use crate::http::*;
+use crate::error::CoreError;
+
+// Statuses of the response getting the header without 'always', the same as of nginx
+const SUCCESS: [i64; 10] = [200, 201, 204, 206, 301, 302, 303, 304, 307, 308];
+
+// { name: X-Cache, value: HIT, always: true } or { ..., status: [200, 404] }
+#[derive(Clone)]
+pub struct AddHeader {
+    name: String,
+    value: HttpComplexValue,
+    // the errors are included
+    always: bool,
+    // replaces the default statuses
+    status: Option<Vec<i64>>
+}
+
+impl AddHeader {
+    fn parse(y: Yaml) -> Result<AddHeader, CoreError> {
+        let h = match y {
+            Yaml::Hash(h) => h,
+            _ => return throw!("add_header: map expected")
+        };
+        let mut name = None;
+        let mut value = None;
+        let mut always = false;
+        let mut status = None;
+        for (k, v) in h {
+            match (k.as_str(), v) {
+                (Some("name"), Yaml::String(s)) => name = Some(s),
+                (Some("value"), Yaml::String(s)) => value = Some(HttpComplexValue::parse(&s).or_else(|err| throw!("add_header: {}", err))?),
+                (Some("value"), Yaml::Integer(i)) => value = Some(HttpComplexValue::simple(&i.to_string())),
+                (Some("always"), Yaml::Boolean(b)) => always = b,
+                (Some("status"), Yaml::Integer(i)) => status = Some(vec![i]),
+                (Some("status"), Yaml::Array(list)) => status = Some(list.iter().map(|v| match v {
+                    Yaml::Integer(i) => Ok(*i),
+                    _ => throw!("add_header: 'status' is the list of the integers")
+                }).collect::<Result<Vec<i64>, CoreError>>()?),
+                (Some(key), _) => return throw!("add_header: invalid '{}'", key),
+                _ => return throw!("add_header: key type mismatch")
+            }
+        }
+        match (name, value) {
+            (Some(name), Some(value)) => Ok(AddHeader {
+                name,
+                value,
+                always,
+                status
+            }),
+            _ => throw!("add_header: 'name' and 'value' are required")
+        }
+    }
+
+    fn applies(&self, status: HttpStatus) -> bool {
+        let status = status as i64;
+        match &self.status {
+            Some(list) => list.contains(&status),
+            None => self.always || SUCCESS.contains(&status)
+        }
+    }
+}
+
+// One header or the list of them
+impl Value for Vec<AddHeader> {
+    type Type = Vec<AddHeader>;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        match std::mem::replace(v, Yaml::Null) {
+            Yaml::Array(list) => list.into_iter().map(AddHeader::parse).collect(),
+            y => Ok(vec![AddHeader::parse(y)?])
+        }
+    }
+}
 
 pub struct ModHeaders
 {}
@@ -23,6 +97,13 @@ impl Plugin for ModHeaders {
             })
         }
 
+        fn add_header(headers: &[AddHeader], resp: &mut HttpResponse) {
+            let status = resp.status();
+            headers.iter().filter(|header| header.applies(status)).for_each(|header| {
+                resp.add_header(&header.name, &resp.expand(&header.value));
+            })
+        }
+
         fn clear_headers(headers: &HttpList, resp: &mut HttpResponse) {
             headers.iter().for_each(|key| {
                 resp.remove_header(&resp.expand(&key));
@@ -59,6 +140,14 @@ impl Plugin for ModHeaders {
             Ok(None)
         })?;
 
+        add_command!(Context::SERVER, "add_header", |server: &mut ServerContext, headers: Vec<AddHeader>| {
+            server.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                add_header(&headers, resp);
+            }));
+
+            Ok(None)
+        })?;
+
         add_command!(Context::SERVER, "clear_headers", |server: &mut ServerContext, headers: HttpList| {
             server.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
                 clear_headers(&headers, resp);
@@ -83,8 +172,20 @@ impl Plugin for ModHeaders {
             Ok(None)
         })?;
 
+        // Cache-Control: private, no-store and no validators, on by default behind authentication
+
+        add_command!(Context::SERVER, "private_cache", |server: &mut ServerContext, private_cache: bool| {
+            server.private_cache = Some(private_cache);
+            Ok(None)
+        })?;
+
         // Route
 
+        add_command!(Context::ROUTE, "private_cache", |route: &mut RouteContext, private_cache: bool| {
+            route.private_cache = Some(private_cache);
+            Ok(None)
+        })?;
+
         add_command!(Context::ROUTE, "add_headers", |route: &mut RouteContext, headers: HttpMap| {
             route.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
                 add_headers(&headers, resp);
@@ -93,6 +194,14 @@ impl Plugin for ModHeaders {
             Ok(None)
         })?;
 
+        add_command!(Context::ROUTE, "add_header", |route: &mut RouteContext, headers: Vec<AddHeader>| {
+            route.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                add_header(&headers, resp);
+            }));
+
+            Ok(None)
+        })?;
+
         add_command!(Context::ROUTE, "clear_headers", |server: &mut RouteContext, headers: HttpList| {
             server.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
                 clear_headers(&headers, resp);
@@ -0,0 +1,159 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(NegativeCache);
+
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use std::time::Duration;
+use std::mem::take;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::error::Code;
+use crate::core::status::{ self, Json };
+use crate::core::zone::Zone;
+
+pub struct NegativeCacheContext {
+    ttl: Duration,
+    statuses: Vec<HttpStatus>,
+    key: Option<String>,
+    max_entries: usize
+}
+
+impl Default for NegativeCacheContext {
+    fn default() -> NegativeCacheContext {
+        NegativeCacheContext {
+            ttl: Duration::from_secs(10),
+            statuses: vec![HttpStatus::NOT_FOUND, HttpStatus::NOT_ALLOWED],
+            key: None,
+            max_entries: 10000
+        }
+    }
+}
+
+// Remembered negative results of the route, keyed by the expanded 'key',
+// the result is not remembered while the zone is full
+struct Entries {
+    zone: Zone<String, HttpStatus>,
+    hits: AtomicU64
+}
+
+#[derive(Default)]
+pub struct NegativeCache
+{}
+
+impl Plugin for NegativeCache {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "NegativeCache"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::ROUTE, "negative_cache.ttl", |negative_cache: &mut NegativeCacheContext, ttl: Duration| {
+            negative_cache.ttl = ttl;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "negative_cache.statuses", |negative_cache: &mut NegativeCacheContext, statuses: HttpList| {
+            negative_cache.statuses.clear();
+            for status in statuses.iter().filter_map(|status| status.text()) {
+                match status.parse::<i64>().map(HttpStatus::from) {
+                    Ok(HttpStatus::UNDEFINED) | Err(_) => return throw!("negative_cache: unsupported status '{}'", status),
+                    Ok(status) => negative_cache.statuses.push(status)
+                }
+            }
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "negative_cache.key", |negative_cache: &mut NegativeCacheContext, key: String| {
+            negative_cache.key = Some(key);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "negative_cache.max_entries", |negative_cache: &mut NegativeCacheContext, max_entries: usize| {
+            negative_cache.max_entries = max_entries;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "negative_cache", Schema::new()
+            .optional("ttl", SchemaType::Integer)
+            .range("ttl", 1, i64::MAX)
+            .optional("statuses", SchemaType::List)
+            .optional("key", SchemaType::String)
+            .optional("max_entries", SchemaType::Integer)
+            .range("max_entries", 1, i64::MAX))?;
+
+        add_block!(Context::ROUTE, "negative_cache", |context| {
+            match context.get_mut::<NegativeCacheContext>() {
+                Some(negative_cache) => {
+                    // exit
+                    let negative_cache = take(negative_cache);
+                    let key = Variable::complex(negative_cache.key.as_deref().unwrap_or("${http_host}${request_uri}"));
+                    let statuses = negative_cache.statuses;
+                    let entries = Arc::new(Entries {
+                        zone: Zone::new(Some(negative_cache.ttl), Some(negative_cache.max_entries)),
+                        hits: AtomicU64::new(0)
+                    });
+
+                    static CACHES: AtomicUsize = AtomicUsize::new(0);
+                    let probe = Arc::clone(&entries);
+                    status::register("caches", &format!("negative_cache#{}", CACHES.fetch_add(1, Ordering::SeqCst)), Box::new(move || {
+                        Some(Json::object(vec![
+                            ("entries", probe.zone.len().into()),
+                            ("hits", probe.hits.load(Ordering::Relaxed).into()),
+                            ("ttl", probe.zone.ttl().map(|ttl| ttl.as_millis() as u64).into())
+                        ]))
+                    }));
+
+                    let mut parent = context.parent().unwrap();
+                    let route = parent.get_mut::<RouteContext>().unwrap();
+
+                    let cached = Arc::clone(&entries);
+                    route.access.push_back(AccessHandler::new(move |r| -> Code {
+                        let key = r.expand(&key);
+                        match cached.zone.get(&key) {
+                            Some(status) => {
+                                cached.hits.fetch_add(1, Ordering::Relaxed);
+                                r.set_context("content", ContentHandler::new(move |r| -> HttpResponse {
+                                    let mut resp = HttpResponse::new(r);
+                                    resp.send(status, "text/plain", Some(status.to_string().as_bytes()));
+                                    resp
+                                }));
+                            },
+                            // the response decides whether the key is remembered
+                            None => r.set_context("negative_cache", key)
+                        }
+                        Code::DECLINED
+                    }));
+
+                    route.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                        if let Some(key) = resp.take_context::<String>("negative_cache") {
+                            let status = resp.status();
+                            if statuses.contains(&status) {
+                                entries.zone.insert(key, status);
+                            }
+                        }
+                    }));
+
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<NegativeCacheContext>()))
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl NegativeCache {
+    pub fn new() -> NegativeCache {
+        NegativeCache {}
+    }
+}
@@ -4,7 +4,9 @@
 
 register_http_plugin!(Proxy);
 
-use std::sync::Arc;
+use percent_encoding::{ utf8_percent_encode, AsciiSet, CONTROLS };
+use std::mem::take;
+use std::sync::{ Arc, atomic::{ AtomicUsize, Ordering } };
 use std::net::SocketAddr;
 use std::time::{ Duration, Instant };
 
@@ -25,6 +27,12 @@ const CRLF: &[u8] = &[ 0x0d, 0x0a ];
 const CR: u8 = 0x0D;
 const LF: u8 = 0x0A;
 
+// Headers of the connection, not forwarded in both directions along with the ones listed by 'Connection'
+const HOP_BY_HOP: [&str; 7] = [ "connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade" ];
+
+// Decoded uri is encoded back for the request line
+const PATH: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
 #[derive(PartialEq, PartialOrd)]
 #[allow(non_camel_case_types)]
 enum HttpProxyState {
@@ -44,6 +52,7 @@ enum HttpProxyState {
 
 struct HttpProxyContext {
     timer: Instant,
+    header_time: Option<Duration>,
     client: ClientContext,
     peer: Peer,
     state: HttpProxyState,
@@ -51,13 +60,21 @@ struct HttpProxyContext {
     protocol: Vec<u8>,
     key: Option<Vec<u8>>,
     val: Option<Vec<u8>>,
-    chunk: (Vec<u8>, Option<usize>)
+    chunk: (Vec<u8>, Option<usize>),
+    // upstream headers are forwarded once 'Connection' is known
+    headers: Vec<(String, String)>,
+    // lowercase names listed by 'Connection'
+    connection: Vec<String>,
+    preserve_header_case: bool,
+    // spooled request body sent by parts
+    source: Option<Box<dyn BodySource>>
 }
 
 impl HttpProxyContext {
-    fn new(peer: Peer) -> HttpProxyContext {
+    fn new(peer: Peer, preserve_header_case: bool) -> HttpProxyContext {
         HttpProxyContext {
             timer: Instant::now(),
+            header_time: None,
             client: ClientContext::new(peer.stream.weak(), peer.remote_addr()),
             peer: peer,
             state: HttpProxyState::st_connecting,
@@ -65,26 +82,35 @@ impl HttpProxyContext {
             protocol: Vec::with_capacity(16),
             key: Some(Vec::with_capacity(64)),
             val: None,
-            chunk: (Vec::with_capacity(256), None)
+            chunk: (Vec::with_capacity(256), None),
+            headers: Vec::new(),
+            connection: Vec::new(),
+            preserve_header_case,
+            source: None
         }
     }
 
     fn prepare_request(&mut self, r: &mut HttpRequest) -> CoreResult {
-        if self.state > HttpProxyState::st_request_prepared {
+        if self.state >= HttpProxyState::st_request_prepared {
             return Ok(OK);
         }
 
         let client = &mut self.client;
 
         client.write_str(&format!("{} ", r.method()));
-        client.write_str(&r.uri());
-        if !r.args_mut().is_empty() {
+        client.write_str(&utf8_percent_encode(r.uri(), PATH).to_string());
+        // query string is forwarded as sent unless the args are changed
+        let args = r.format_args();
+        if !args.is_empty() {
             client.write(b"?");
-            client.write_str(&r.format_args());
+            client.write_str(&args);
         }
         client.write(b" HTTP/1.1\r\n");
 
-        r.headers_mut().remove("connection");
+        let listed = r.headers().exact("connection").map(|connection| tokens(connection)).unwrap_or_default();
+        for name in HOP_BY_HOP.iter().filter(|name| **name != "transfer-encoding").map(|name| name.to_string()).chain(listed) {
+            r.headers_mut().remove(&name);
+        }
 
         for (key, ll) in r.headers().iter() {
             for v in ll.iter() {
@@ -94,8 +120,10 @@ impl HttpProxyContext {
 
         client.write(CRLF);
 
-        if let Some(body) = r.body() {
-            client.write(body);
+        if let Some(body) = r.body_bytes() {
+            client.write_bytes(body);
+        } else if r.body_path().is_some() {
+            self.source = r.body_source().or_else(|err| throw!("Failed to open spooled body: {}", err))?;
         }
 
         self.state = HttpProxyState::st_request_prepared;
@@ -106,17 +134,24 @@ impl HttpProxyContext {
     fn send_request(&mut self, r: &mut HttpRequest) -> CoreResult {
         match self.prepare_request(r) {
             Ok(OK) => {
-                if self.state < HttpProxyState::st_request_sent {
-                    return match self.client.flush() {
+                while self.state < HttpProxyState::st_request_sent {
+                    match self.client.flush() {
                         Ok((AGAIN, _)) => {
-                            Ok(AGAIN)
+                            return Ok(AGAIN)
                         },
                         Ok((OK, _)) => {
                             self.client.reset();
-                            self.state = HttpProxyState::st_request_sent;
-                            Ok(OK)
+                            // spooled body is read as the upstream drains
+                            match self.source.as_mut().map(|source| source.next_chunk()) {
+                                Some(Ok(Some(chunk))) => self.client.write_bytes(chunk),
+                                Some(Err(err)) => return throw!("Failed to read spooled body: {}", err),
+                                Some(Ok(None)) | None => {
+                                    self.source = None;
+                                    self.state = HttpProxyState::st_request_sent;
+                                }
+                            }
                         },
-                        Err(err) => throw!(err.what()),
+                        Err(err) => return throw!(err.what()),
                         Ok((DECLINED, _)) => unreachable!()
                     }
                 }
@@ -223,7 +258,9 @@ impl HttpProxyContext {
                             return http_throw!("Invalid header line");
                         }
                         if last_crlf {
+                            self.forward_headers(resp);
                             self.state = HttpProxyState::st_headers_end;
+                            self.header_time = Some(self.timer.elapsed());
                             return Ok(OK)
                         }
                         last = LF;
@@ -241,26 +278,30 @@ impl HttpProxyContext {
                                 let value = unsafe { std::str::from_utf8_unchecked(&v) }.trim();
                                 match name.to_ascii_lowercase().as_str() {
                                     "content-length" => {
-                                        match value.parse::<usize>() {
-                                            Ok(len) => {
+                                        match content_length(value) {
+                                            Some(len) => {
                                                 resp.set_content_length(len)
                                             },
-                                            Err(_) => return http_throw!("Invalid header line")
+                                            None => return http_throw!("Invalid header line")
                                         }
                                     },
                                     "connection" => {
-                                      if value.to_ascii_lowercase() == "close" {
-                                          self.peer.release();
-                                      }
+                                        for token in tokens(value) {
+                                            match token.as_str() {
+                                                "close" => self.peer.release(),
+                                                _ => self.connection.push(token)
+                                            }
+                                        }
                                     },
-                                    "server" => {},
-                                    "transfer-encoding" if value.to_ascii_lowercase() == "chunked" => {
-                                        resp.set_chunked();
+                                    // the last coding is applied last
+                                    "transfer-encoding" => {
+                                        if tokens(value).last().map(|coding| coding == "chunked").unwrap_or(false) {
+                                            resp.set_chunked();
+                                        }
                                     },
-                                    _ => {
-                                        let ll = resp.headers().entry(Key::from(name)).or_default();
-                                        ll.push_back(value.to_string());
-                                    }
+                                    "server" => {},
+                                    lowercase if HOP_BY_HOP.contains(&lowercase) => {},
+                                    _ => self.headers.push((name.to_string(), value.to_string()))
                                 }
                                 last = CR;
                                 self.key = Some(Vec::with_capacity(64));
@@ -293,6 +334,20 @@ impl HttpProxyContext {
         }
     }
 
+    fn forward_headers(&mut self, resp: &mut HttpResponse) {
+        for (name, value) in take(&mut self.headers) {
+            if self.connection.contains(&name.to_ascii_lowercase()) {
+                continue;
+            }
+            let name = match self.preserve_header_case {
+                true => name,
+                false => canonical(&name)
+            };
+            let ll = resp.headers().entry(Key::from(name)).or_default();
+            ll.push_back(value);
+        }
+    }
+
     fn read_chunk_size(&mut self) -> HttpResult {
         if self.chunk.1.is_some() {
             return Ok(OK)
@@ -350,6 +405,12 @@ impl HttpProxyContext {
 
         self.state = HttpProxyState::st_body;
 
+        // response to HEAD has no body, Content-Length of the upstream is kept
+        if matches!(resp.get_request().method(), HttpMethod::HEAD) {
+            self.state = HttpProxyState::st_parsed;
+            return Ok(OK);
+        }
+
         match resp.content_length() {
             Some(content_length) => {
                 resp.append_body(self.client.buf.chunk(content_length - resp.body_len()));
@@ -456,7 +517,8 @@ impl HttpProxyContext {
 #[derive(Default, Clone)]
 struct ProxyPass {
     pass: Option<SocketAddr>,
-    upstream: Option<HttpComplexValue>
+    upstream: Option<HttpComplexValue>,
+    target: String
 }
 
 #[derive(Clone)]
@@ -466,6 +528,15 @@ pub struct ProxyContext {
     proxy_timeout: Option<Duration>,
     keepalive_timeout: Option<Duration>,
     keepalive_requests: Option<u64>,
+    // TLS to the upstream, the server name is sent in SNI and verified by the certificate
+    tls: bool,
+    server_name: Option<String>,
+    // CA certificates of the upstream, the system ones by default
+    trusted_certificate: Option<String>,
+    // base64 sha256 digests of accepted upstream public keys, several pins allow rotation
+    pin_sha256: Vec<Vec<u8>>,
+    // upstream header names are sent as is, otherwise as 'Content-Type'
+    preserve_header_case: bool,
     primary: ProxyPass,
     backup: ProxyPass
 }
@@ -478,6 +549,11 @@ impl Default for ProxyContext {
             proxy_timeout: None,
             keepalive_timeout: None,
             keepalive_requests: None,
+            tls: false,
+            server_name: None,
+            trusted_certificate: None,
+            pin_sha256: Vec::new(),
+            preserve_header_case: false,
             primary: ProxyPass::default(),
             backup: ProxyPass::default()
         }
@@ -516,11 +592,45 @@ impl Plugin for Proxy {
             Ok(None)
         })?;
 
+        add_command!(Context::ROUTE, "proxy.tls", |proxy: &mut ProxyContext, tls: bool| {
+            if tls && cfg!(not(feature = "tls")) {
+                return throw!("proxy.tls: is not supported, the binary is built without the 'tls' feature");
+            }
+            proxy.tls = tls;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "proxy.server_name", |proxy: &mut ProxyContext, server_name: String| {
+            proxy.server_name = Some(server_name);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "proxy.trusted_certificate", |proxy: &mut ProxyContext, trusted_certificate: String| {
+            proxy.trusted_certificate = Some(trusted_certificate);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "proxy.pin_sha256", |proxy: &mut ProxyContext, pins: HttpList| {
+            for pin in pins.iter().filter_map(|pin| pin.text()) {
+                match crate::crypto::base64_decode(&pin) {
+                    Some(digest) if digest.len() == 32 => proxy.pin_sha256.push(digest),
+                    _ => return throw!("proxy.pin_sha256: '{}' is not a base64 sha256 digest", pin)
+                }
+            }
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "proxy.preserve_header_case", |proxy: &mut ProxyContext, preserve_header_case: bool| {
+            proxy.preserve_header_case = preserve_header_case;
+            Ok(None)
+        })?;
+
         add_command!(Context::ROUTE, "proxy.pass", |proxy: &mut ProxyContext, pass: String| {
             match get_addr(&pass) {
                 Ok(addr) => proxy.primary.pass = Some(addr),
                 _ => proxy.primary.upstream = Some(Variable::complex(&pass))
             }
+            proxy.primary.target = pass;
             Ok(None)
         })?;
 
@@ -529,16 +639,49 @@ impl Plugin for Proxy {
                 Ok(addr) => proxy.backup.pass = Some(addr),
                 _ => proxy.backup.upstream = Some(Variable::complex(&pass))
             }
+            proxy.backup.target = pass;
             Ok(None)
         })?;
 
+        add_schema!(Context::ROUTE, "proxy", Schema::new()
+            .optional("pass", SchemaType::String)
+            .optional("backup", SchemaType::String)
+            .optional("keepalive", SchemaType::Integer)
+            .optional("max_active", SchemaType::Integer)
+            .optional("proxy_timeout", SchemaType::Integer)
+            .optional("keepalive_timeout", SchemaType::Integer)
+            .optional("keepalive_requests", SchemaType::Integer)
+            .optional("tls", SchemaType::Bool)
+            .optional("server_name", SchemaType::String)
+            .optional("trusted_certificate", SchemaType::String)
+            .optional("pin_sha256", SchemaType::List)
+            .optional("preserve_header_case", SchemaType::Bool))?;
+
         add_block!(Context::ROUTE, "proxy", |context, pass: String| {
             match context.get_mut::<ProxyContext>() {
                 Some(proxy) => {
                     // exit
                     let proxy = std::mem::take(proxy);
+                    // the identity of the plain connection can't be verified, so fail closed
+                    let tls = match proxy.tls {
+                        true => Some(TlsPin {
+                            server_name: proxy.server_name.clone(),
+                            pin_sha256: proxy.pin_sha256.clone(),
+                            trusted_certificate: proxy.trusted_certificate.clone()
+                        }),
+                        false if !proxy.pin_sha256.is_empty() => return throw!("proxy.pin_sha256: requires proxy.tls"),
+                        false if proxy.server_name.is_some() => return throw!("proxy.server_name: requires proxy.tls"),
+                        false if proxy.trusted_certificate.is_some() => return throw!("proxy.trusted_certificate: requires proxy.tls"),
+                        false => None
+                    };
+                    let target = proxy.primary.target.clone();
+                    let preserve_header_case = proxy.preserve_header_case;
                     let upstream_module = HttpModule::get_plugin::<HttpUpstream>();
 
+                    // keep-alived connections of named upstreams may be pinned to the route
+                    static ROUTES: AtomicUsize = AtomicUsize::new(0);
+                    let route_id = format!("proxy#{}", ROUTES.fetch_add(1, Ordering::SeqCst));
+
                     let get = |u: &ProxyPass| -> Result<Option<Arc<Upstream>>, CoreError> {
                         match u.upstream {
                             Some(_) => Ok(None),
@@ -567,7 +710,7 @@ impl Plugin for Proxy {
                         match match &primary {
                             None => match &proxy.primary.upstream {
                                 Some(upstream) => {
-                                    match upstream_module.connect(&r.expand(&upstream), proxy.proxy_timeout) {
+                                    match upstream_module.connect(&r.expand(upstream), proxy.proxy_timeout, Some(&route_id), tls.as_ref()) {
                                         Ok(peer) => Ok(peer),
                                         Err(err) if proxy.backup.pass.is_none() && proxy.backup.upstream.is_none() => {
                                             return throw!(err)
@@ -577,16 +720,17 @@ impl Plugin for Proxy {
                                 },
                                 None => unreachable!()
                             },
-                            Some(primary) => primary.connect(proxy.proxy_timeout)
+                            Some(primary) => primary.connect(proxy.proxy_timeout, None, tls.as_ref())
                         } {
                             Ok(peer) => Ok(peer),
-                            _ => {
+                            Err(err) => {
                                 match &backup {
                                     None => match &proxy.backup.upstream {
-                                        Some(upstream) => upstream_module.connect(&r.expand(&upstream), proxy.proxy_timeout),
-                                        None => unreachable!()
+                                        Some(upstream) => upstream_module.connect(&r.expand(upstream), proxy.proxy_timeout, Some(&route_id), tls.as_ref()),
+                                        // no backup
+                                        None => Err(err)
                                     },
-                                    Some(backup) => backup.connect(proxy.proxy_timeout)
+                                    Some(backup) => backup.connect(proxy.proxy_timeout, None, tls.as_ref())
                                 }
                             }
                         }
@@ -601,6 +745,7 @@ impl Plugin for Proxy {
                            .get_mut::<RouteContext>()
                            .map(|route|
                     {
+                        route.upstream = Some(target);
                         route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
                             HttpResponse::with_status(r, HttpStatus::UNDEFINED)
                         }));
@@ -609,15 +754,17 @@ impl Plugin for Proxy {
                             loop {
                                 let mut context = match resp.take_context::<HttpProxyContext>("proxy") {
                                     Some(context) => context,
-                                    None => match connect(resp.get_request()) {
-                                        Ok(peer) => {
+                                    None => match (Instant::now(), connect(resp.get_request())) {
+                                        (start, Ok(peer)) => {
                                             let upstream_addr = peer.remote_addr();
                                             let upstream_name = peer.upstream();
+                                            let upstream_connect_time = start.elapsed().as_millis();
                                             add_var_lazy!(resp, "upstream_name", move |_| upstream_name);
                                             add_var_lazy!(resp, "upstream_addr", move |_| upstream_addr);
-                                            HttpProxyContext::new(peer)
+                                            add_var_lazy!(resp, "upstream_connect_time", move |_| upstream_connect_time);
+                                            HttpProxyContext::new(peer, preserve_header_case)
                                         },
-                                        Err(err) => {
+                                        (_, Err(err)) => {
                                             log_http_error!(resp, "error", err);
                                             return bad_gateway(resp);
                                         }
@@ -633,7 +780,9 @@ impl Plugin for Proxy {
                                     },
                                     Ok(Flush::OK(Some(peer))) => {
                                         let upstream_response_time = context.timer.elapsed().as_millis();
+                                        let upstream_header_time = context.header_time.unwrap_or_default().as_millis();
                                         let status = resp.status();
+                                        add_var_lazy!(resp, "upstream_header_time", move |_| upstream_header_time);
                                         add_var_lazy!(resp, "upstream_response_time", move |_| upstream_response_time);
                                         add_var_lazy!(resp, "upstream_status", move |_| status);
                                         return Ok(Flush::OK(Some(peer)));
@@ -678,6 +827,28 @@ impl Proxy {
     }
 }
 
+// Comma separated lowercase tokens of the header
+fn tokens(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+// 'content-type' and 'CONTENT-TYPE' are sent as 'Content-Type'
+fn canonical(name: &str) -> String {
+    name.split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase(),
+                None => String::new()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
 fn get_addr(addr: &str) -> Result<SocketAddr, CoreError> {
     match addr.parse() {
         Ok(addr) => Ok(addr),
@@ -4,14 +4,21 @@
 
 register_http_plugin!(PythonAPI);
 
-use pyo3::{ prelude::*, PyCell, types::{ PyDict } };
-use regex::Regex;
-use std::ops::Deref;
+use pyo3::{ prelude::*, PyCell, exceptions::PyValueError, types::{ PyBytes, PyDict, PyList } };
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::sync::{ Arc, atomic::{ AtomicBool, Ordering } };
 
 use crate::plugin::*;
 use crate::http::*;
 use crate::error::CoreError;
 use crate::http::HttpStatus;
+use crate::keyval::KeyVal;
+use crate::tcp_socket::TcpSocket;
+use crate::http::multipart::Part;
+use crate::http::plugins::async_task;
 
 macro_rules! python_throw {
     ($py:ident,$err:ident,$msg:literal) => {
@@ -20,102 +27,253 @@ macro_rules! python_throw {
     }
 }
 
-pub struct PythonAPI {}
+// python_offload runs the scripts on the executor of async_task, the workers don't wait for the GIL
+pub struct PythonAPI {
+    offload: Arc<AtomicBool>
+}
+
+thread_local! {
+    // Globals of the scripts by the thread, the module level state of the script lives between the requests
+    // of the thread, as pyo3 does not support the sub-interpreters
+    static NAMESPACES: RefCell<HashMap<String, Py<PyDict>>> = RefCell::new(HashMap::new());
+}
 
 #[derive(Default)]
 struct PythonResponse {
+    pub status: Option<i64>,
+    pub headers: Vec<(String, String)>,
     pub text: String
 }
 
+// response.text, response.status and response.set_header(name, value)
 #[pyclass]
 struct PythonResponseWrapper {
-    pub response: Option<PythonResponse>
+    pub response: PythonResponse
 }
 
 #[pymethods]
 impl PythonResponseWrapper {
     #[setter(text)]
     fn set_text(&mut self, text: &str) -> PyResult<()> {
-        match &mut self.response {
-            None => {
-                self.response = Some(PythonResponse { text: String::from(text) })
-            },
-            Some(response) => {
-                response.text = String::from(text)
+        self.response.text = String::from(text);
+        Ok(())
+    }
+
+    #[setter(status)]
+    fn set_status(&mut self, status: i64) -> PyResult<()> {
+        self.response.status = Some(status);
+        Ok(())
+    }
+
+    fn set_header(&mut self, name: &str, value: &str) {
+        self.response.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.response.headers.push((name.to_string(), value.to_string()));
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) {
+        self.response.headers.push((name.to_string(), value.to_string()));
+    }
+}
+
+// request.cancelled() lets the script stop working for the client which has gone away
+#[pyclass]
+struct PythonRequestWrapper {
+    client: TcpSocket,
+    parts: Result<Vec<Part>, String>,
+    #[pyo3(get)]
+    method: String,
+    #[pyo3(get)]
+    uri: String,
+    headers: Vec<(String, String)>,
+    args: Vec<(String, String)>,
+    body: Option<Vec<u8>>
+}
+
+// Repeated names are the lists of the values
+fn pairs_dict(py: Python, pairs: &[(String, String)]) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    for (name, value) in pairs {
+        match dict.get_item(name) {
+            None => dict.set_item(name, value)?,
+            Some(item) => match item.downcast::<PyList>() {
+                Ok(list) => list.append(value)?,
+                Err(_) => dict.set_item(name, PyList::new(py, [item, value.to_object(py).as_ref(py)]))?
             }
+        }
+    }
+    Ok(dict.to_object(py))
+}
+
+// Names of the headers are in lower case
+fn pairs(keyval: &KeyVal<String>, lower: bool) -> Vec<(String, String)> {
+    keyval.iter().flat_map(|(name, values)| {
+        let name = match lower {
+            true => name.to_ascii_lowercase(),
+            false => name.to_string()
         };
-        Ok(())
-     }
+        values.iter().map(move |value| (name.clone(), value.clone()))
+    }).collect()
 }
 
-fn import(py: &Python, dict: &PyDict, modules: &[(String, String)]) -> PyResult<()> {
-    for (name, module) in modules.iter() {
-        dict.set_item(name, py.import(&module)?)?;
+#[pymethods]
+impl PythonRequestWrapper {
+    fn cancelled(&self) -> bool {
+        self.client.closed()
+    }
+
+    #[getter]
+    fn headers(&self, py: Python) -> PyResult<PyObject> {
+        pairs_dict(py, &self.headers)
+    }
+
+    #[getter]
+    fn args(&self, py: Python) -> PyResult<PyObject> {
+        pairs_dict(py, &self.args)
+    }
+
+    #[getter]
+    fn body(&self, py: Python) -> Option<PyObject> {
+        self.body.as_ref().map(|body| PyBytes::new(py, body).to_object(py))
+    }
+
+    // Parts as the list of dicts with name, filename, content_type, size and data or path of the spooled one
+    fn multipart(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        let parts = self.parts.as_ref().map_err(|err| PyValueError::new_err(err.clone()))?;
+        parts.iter().map(|part| {
+            let dict = PyDict::new(py);
+            dict.set_item("name", part.name())?;
+            dict.set_item("filename", part.filename())?;
+            dict.set_item("content_type", part.content_type())?;
+            dict.set_item("size", part.size())?;
+            match part.path() {
+                Some(path) => dict.set_item("path", path.to_string_lossy().into_owned())?,
+                None => {
+                    let data = part.bytes().map_err(|err| PyValueError::new_err(err.to_string()))?;
+                    dict.set_item("data", PyBytes::new(py, &data))?
+                }
+            }
+            Ok(dict.to_object(py))
+        }).collect()
     }
-    Ok(())
 }
 
-fn exec(modules: &[(String, String)], code: Option<&str>) -> Result<Option<PythonResponse>, CoreError> {
+fn get_hash<T: Hash>(t: &T) -> String {
+    let mut s = DefaultHasher::new();
+    t.hash(&mut s);
+    format!("python_{}", s.finish())
+}
+
+// Code object of the script, compiled once with the config
+fn compile(name: &str, code: &str) -> Result<PyObject, CoreError> {
     let gil = Python::acquire_gil();
     let py = gil.python();
-    let dict = PyDict::new(py);
-    import(&py, dict, &modules).or_else(|err| {
-        python_throw!(py, err, "import failed");
+    let builtins = py.import("builtins").or_else(|err| {
+        python_throw!(py, err, "python failed");
     })?;
-    if let Some(code) = code {
-        let wrap = PyCell::new(py, PythonResponseWrapper {
-            response: None
-        }).or_else(|err| {
-            python_throw!(py, err, "import failed");
-        })?;
-        dict.set_item("response", &wrap).or_else(|err| {
-            python_throw!(py, err, "python failed");
-        })?;
-        py.run(code, None, Some(dict)).or_else(|err| {
-            python_throw!(py, err, "exec failed");
-        })?;
-        return Ok(wrap.borrow_mut().response.take());
+    match builtins.call1("compile", (code, name, "exec")) {
+        Ok(code) => Ok(code.to_object(py)),
+        Err(err) => {
+            python_throw!(py, err, "invalid code");
+        }
     }
-    Ok(None)
-}
-
-fn find_imports(code: &str) -> (String, Vec<(String, String)>) {
-    let mut modules = vec![];
-    let re_import = Regex::new("[^\r\n]*import[ \t]+(.+)").unwrap();
-    let re_import_coma = Regex::new("([^,]+)").unwrap();
-    let re_module = Regex::new("^([^ \t]+)[ \t]+as[ \t]+([^ \t]+)").unwrap();
-    for cap in re_import.captures_iter(code) {
-        for cap in re_import_coma.captures_iter(&cap[1]) {
-            let module = String::from(cap[1].trim());
-            match re_module.captures(&module) {
-                None => {
-                    modules.push((module.clone(), module))
-                },
-                Some(module) => {
-                    modules.push((String::from(&module[2]), String::from(&module[1])))
+}
+
+fn exec(name: &str, code: &PyObject, request: PythonRequestWrapper) -> Result<PythonResponse, CoreError> {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+    let globals = NAMESPACES.with(|namespaces| {
+        namespaces.borrow_mut().entry(name.to_string()).or_insert_with(|| PyDict::new(py).into()).clone_ref(py)
+    });
+    let globals = globals.as_ref(py);
+    let request = PyCell::new(py, request).or_else(|err| {
+        python_throw!(py, err, "python failed");
+    })?;
+    globals.set_item("request", request).or_else(|err| {
+        python_throw!(py, err, "python failed");
+    })?;
+    let wrap = PyCell::new(py, PythonResponseWrapper {
+        response: PythonResponse::default()
+    }).or_else(|err| {
+        python_throw!(py, err, "python failed");
+    })?;
+    globals.set_item("response", wrap).or_else(|err| {
+        python_throw!(py, err, "python failed");
+    })?;
+    let builtins = py.import("builtins").or_else(|err| {
+        python_throw!(py, err, "python failed");
+    })?;
+    builtins.call1("exec", (code, globals)).or_else(|err| {
+        python_throw!(py, err, "exec failed");
+    })?;
+    let response = std::mem::take(&mut wrap.borrow_mut().response);
+    Ok(response)
+}
+
+fn send(resp: &mut HttpResponse, result: Result<PythonResponse, CoreError>) {
+    match result {
+        Ok(response) => {
+            let mut content_type = "text/plain".to_string();
+            for (name, value) in response.headers {
+                match name.eq_ignore_ascii_case("content-type") {
+                    true => content_type = value,
+                    false => resp.add_header(&name, &value)
                 }
             }
-        }
+            resp.send(HttpStatus::from(response.status.unwrap_or(200)), &content_type, Some(response.text.as_bytes()))
+        },
+        Err(err) => resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(err.what().as_bytes()))
     }
-    (String::from(re_import.replace_all(code, "").deref()), modules)
 }
 
 impl Plugin for PythonAPI {
     type ModuleType = HTTP;
 
     fn configure(&mut self) -> ActionResult {
-        add_command!(Context::ROUTE, "python", |route: &mut RouteContext, code: String| {
-            let (code, modules) = find_imports(&code);
-            if exec(&modules, None).is_err() {
-                return throw!("invalid code");
-            }
+        let offload_ = Arc::clone(&self.offload);
+
+        add_command!(Context::HTTP, "python_offload", move |_: &mut HttpContext, offload: bool| {
+            offload_.store(offload, Ordering::Relaxed);
+            Ok(None)
+        })?;
+
+        let offload = Arc::clone(&self.offload);
+
+        add_command!(Context::ROUTE, "python", move |route: &mut RouteContext, code: String| {
+            let name = Arc::new(get_hash(&code));
+            let code = Arc::new(compile(&name, &code)?);
+            let offload = Arc::clone(&offload);
             route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
-                let mut resp = HttpResponse::new(r);
-                match exec(&modules, Some(&code)) {
-                    Ok(Some(response)) => resp.send(HttpStatus::OK, "text/plain", Some(response.text.as_bytes())),
-                    Err(err) => resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(err.what().as_bytes())),
-                    Ok(None) => unreachable!()
+                let body = match r.body_contents() {
+                    Ok(body) => body.map(|body| body.to_vec()),
+                    Err(err) => {
+                        let mut resp = HttpResponse::new(r);
+                        resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(err.what().as_bytes()));
+                        return resp;
+                    }
+                };
+                let request = PythonRequestWrapper {
+                    client: r.const_context().weak(),
+                    parts: r.multipart().and_then(|parts| parts.collect()).map_err(|err| err.what().to_string()),
+                    method: format!("{}", r.method()),
+                    uri: r.uri().clone(),
+                    headers: pairs(r.headers(), true),
+                    args: pairs(r.args(), false),
+                    body
                 };
+                let mut resp = HttpResponse::new(r);
+                if !offload.load(Ordering::Relaxed) {
+                    send(&mut resp, exec(&name, &code, request));
+                    return resp;
+                }
+                // the response goes to the IO thread from the executor
+                let (name, code) = (Arc::clone(&name), Arc::clone(&code));
+                resp.defer(Box::new(move |resp, completion| {
+                    async_task::spawn(move || {
+                        let mut resp = resp;
+                        send(&mut resp, exec(&name, &code, request));
+                        completion.complete(resp);
+                    });
+                }));
                 resp
             }));
             Ok(None)
@@ -125,6 +283,8 @@ impl Plugin for PythonAPI {
 
 impl PythonAPI {
     pub fn new() -> PythonAPI {
-        PythonAPI {}
+        PythonAPI {
+            offload: Arc::new(AtomicBool::new(false))
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,127 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(RedisServers);
+
+use std::net::ToSocketAddrs;
+use std::mem::take;
+use std::time::Duration;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::redis::{ self, Redis };
+
+#[derive(Default)]
+pub struct RedisServerContext {
+    name: Option<String>,
+    address: Option<String>,
+    password: Option<String>,
+    db: Option<i64>,
+    timeout: Option<Duration>,
+    max_keepalive: Option<usize>,
+    max_active: Option<usize>
+}
+
+// Servers are declared before the zones and the plugins using them
+#[derive(Default)]
+pub struct RedisServers
+{}
+
+impl Plugin for RedisServers {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Redis"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::HTTP, "redis_servers.redis_server.name", |server: &mut RedisServerContext, name: String| {
+            server.name = Some(name);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "redis_servers.redis_server.address", |server: &mut RedisServerContext, address: String| {
+            server.address = Some(address);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "redis_servers.redis_server.password", |server: &mut RedisServerContext, password: String| {
+            server.password = Some(password);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "redis_servers.redis_server.db", |server: &mut RedisServerContext, db: i64| {
+            server.db = Some(db);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "redis_servers.redis_server.timeout", |server: &mut RedisServerContext, timeout: Duration| {
+            server.timeout = Some(timeout);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "redis_servers.redis_server.max_keepalive", |server: &mut RedisServerContext, max_keepalive: usize| {
+            server.max_keepalive = Some(max_keepalive);
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "redis_servers.redis_server.max_active", |server: &mut RedisServerContext, max_active: usize| {
+            server.max_active = Some(max_active);
+            Ok(None)
+        })?;
+
+        add_empty_block!(Context::HTTP, "redis_servers")?;
+
+        add_schema!(Context::HTTP, "redis_servers.redis_server", Schema::new()
+            .required("name", SchemaType::String)
+            .required("address", SchemaType::String)
+            .optional("password", SchemaType::String)
+            .optional("db", SchemaType::Integer)
+            .range("db", 0, i64::MAX)
+            .optional("timeout", SchemaType::Integer)
+            .range("timeout", 1, i64::MAX)
+            .optional("max_keepalive", SchemaType::Integer)
+            .range("max_keepalive", 1, i64::MAX)
+            .optional("max_active", SchemaType::Integer)
+            .range("max_active", 1, i64::MAX))?;
+
+        add_block!(Context::HTTP, "redis_servers.redis_server", |context| {
+            match context.get_mut::<RedisServerContext>() {
+                Some(server) => {
+                    // exit
+                    let server = take(server);
+                    let (name, address) = match (server.name, server.address) {
+                        (Some(name), Some(address)) => (name, address),
+                        _ => return throw!("redis_server: 'name' and 'address' required")
+                    };
+                    let addr = match address.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+                        Some(addr) => addr,
+                        None => return throw!("redis_server: invalid address '{}'", address)
+                    };
+                    let max_keepalive = server.max_keepalive.unwrap_or(16);
+                    let redis = Redis::new(&name, addr, server.password, server.db,
+                                           server.timeout.unwrap_or(Duration::from_secs(1)),
+                                           max_keepalive, server.max_active.unwrap_or(std::cmp::max(max_keepalive, 256)));
+                    match redis::create(redis) {
+                        Ok(_) => Ok(None),
+                        Err(err) => throw!("redis_server: {}", err)
+                    }
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<RedisServerContext>()))
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl RedisServers {
+    pub fn new() -> RedisServers {
+        RedisServers {}
+    }
+}
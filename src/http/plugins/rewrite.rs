@@ -4,9 +4,136 @@
 
 register_http_plugin!(Rewrite);
 
+use yaml_rust::Yaml;
+use regex::{ Regex, Captures };
+
 use crate::plugin::*;
+use crate::config::{ Value, ConfigBlock };
 use crate::http::*;
-use crate::error::Code;
+use crate::error::{ Code, CoreError };
+
+#[derive(Clone, Copy)]
+enum Flag {
+    // search the route for the new uri
+    Last,
+    // stay in the current route
+    Break,
+    Redirect,
+    Permanent
+}
+
+// '/target' or { re: '^/old/(.*)$', to: '/new/$1', flag: last }
+#[derive(Clone)]
+pub struct RewriteRule {
+    re: Option<Regex>,
+    to: HttpComplexValue,
+    flag: Flag
+}
+
+impl RewriteRule {
+    fn parse(y: Yaml) -> Result<RewriteRule, CoreError> {
+        match y {
+            Yaml::String(s) => Ok(RewriteRule {
+                re: None,
+                to: HttpComplexValue::parse(&s).or_else(|err| throw!("rewrite: {}", err))?,
+                flag: Flag::Last
+            }),
+            Yaml::Hash(h) => {
+                let mut rule = RewriteRule { re: None, to: HttpComplexValue::complex(""), flag: Flag::Last };
+                let mut to = None;
+                for (k, v) in h {
+                    match (k.as_str(), v.as_str()) {
+                        (Some("re"), Some(re)) => match Regex::new(re) {
+                            Ok(re) => rule.re = Some(re),
+                            Err(err) => return throw!("rewrite: invalid regex '{}': {}", re, err)
+                        },
+                        (Some("to"), Some(s)) => to = Some(HttpComplexValue::parse(s).or_else(|err| throw!("rewrite: {}", err))?),
+                        (Some("flag"), Some(flag)) => rule.flag = match flag {
+                            "last" => Flag::Last,
+                            "break" => Flag::Break,
+                            "redirect" => Flag::Redirect,
+                            "permanent" => Flag::Permanent,
+                            _ => return throw!("rewrite: unknown flag '{}'", flag)
+                        },
+                        (Some(key), _) => return throw!("rewrite: invalid '{}'", key),
+                        _ => return throw!("rewrite: key type mismatch")
+                    }
+                }
+                match to {
+                    Some(to) => rule.to = to,
+                    None => return throw!("rewrite: 'to' is required")
+                }
+                Ok(rule)
+            },
+            _ => throw!("rewrite: type mismatch")
+        }
+    }
+
+    fn handle(&self, r: &mut HttpRequest) -> Code {
+        let target = match &self.re {
+            Some(re) => match re.captures(r.uri()) {
+                Some(caps) => substitute(&r.expand(&self.to), &caps),
+                None => return Code::DECLINED
+            },
+            None => r.expand(&self.to)
+        };
+        match self.flag {
+            Flag::Last => {
+                r.rewrite(&target);
+                Code::AGAIN
+            },
+            Flag::Break => {
+                r.rewrite(&target);
+                Code::OK
+            },
+            Flag::Redirect => redirect(r, HttpStatus::MOVED_TEMPORARILY, target),
+            Flag::Permanent => redirect(r, HttpStatus::MOVED_PERMANENTLY, target)
+        }
+    }
+}
+
+impl Value for RewriteRule {
+    type Type = RewriteRule;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        RewriteRule::parse(std::mem::replace(v, Yaml::Null))
+    }
+}
+
+// $0..$9 are the captures of the regex, '$$' is '$'
+fn substitute(s: &str, caps: &Captures) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('$', Some(&d)) if d.is_ascii_digit() => {
+                chars.next();
+                if let Some(m) = caps.get(d as usize - '0' as usize) {
+                    result.push_str(m.as_str());
+                }
+            },
+            ('$', Some('$')) => {
+                chars.next();
+                result.push('$');
+            },
+            _ => result.push(c)
+        }
+    }
+    result
+}
+
+// External redirect keeps the query string unless the target has its own
+fn redirect(r: &mut HttpRequest, status: HttpStatus, mut location: String) -> Code {
+    if !location.contains('?') && !r.query_string().is_empty() {
+        location = format!("{}?{}", location, r.query_string());
+    }
+    r.set_context("content", ContentHandler::new(move |r| -> HttpResponse {
+        let mut resp = HttpResponse::new(r);
+        resp.set_header("Location", &location);
+        resp.send(status, "text/plain", Some(format!("{}", status).as_bytes()));
+        resp
+    }));
+    Code::OK
+}
 
 pub struct Rewrite {
 }
@@ -15,19 +142,17 @@ impl Plugin for Rewrite {
     type ModuleType = HTTP;
 
     fn configure(&mut self) -> ActionResult {
-        add_command!(Context::SERVER, "rewrite", |server: &mut ServerContext, rewrite: HttpComplexValue| {
+        add_command!(Context::SERVER, "rewrite", |server: &mut ServerContext, rule: RewriteRule| {
             server.rewrite.push_back(RewriteHandler::new(move |r| -> Code {
-                r.rewrite(&r.expand(&rewrite));
-                Code::AGAIN
+                rule.handle(r)
             }));
 
             Ok(None)
         })?;
 
-        add_command!(Context::ROUTE, "rewrite", |route: &mut RouteContext, rewrite: HttpComplexValue| {
+        add_command!(Context::ROUTE, "rewrite", |route: &mut RouteContext, rule: RewriteRule| {
             route.rewrite.push_back(RewriteHandler::new(move |r| -> Code {
-                r.rewrite(&r.expand(&rewrite));
-                Code::AGAIN
+                rule.handle(r)
             }));
 
             Ok(None)
@@ -49,4 +174,4 @@ impl Rewrite {
     pub fn new() -> Rewrite {
         Rewrite {}
     }
-}
\ No newline at end of file
+}
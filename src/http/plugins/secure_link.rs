@@ -0,0 +1,138 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(SecureLink);
+
+use std::time::{ SystemTime, UNIX_EPOCH };
+use std::mem::take;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::crypto;
+use crate::error::Code;
+
+pub struct SecureLinkContext {
+    secret: Option<String>,
+    signature: HttpComplexValue,
+    expires: HttpComplexValue,
+    message: HttpComplexValue
+}
+
+impl Default for SecureLinkContext {
+    fn default() -> SecureLinkContext {
+        SecureLinkContext {
+            secret: None,
+            signature: HttpComplexValue::complex("${arg_sig}"),
+            expires: HttpComplexValue::complex("${arg_expires}"),
+            message: HttpComplexValue::complex("${uri}${arg_expires}")
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SecureLink
+{}
+
+fn reject(r: &mut HttpRequest, status: HttpStatus) {
+    r.set_context("content", ContentHandler::new(move |r| -> HttpResponse {
+        let mut resp = HttpResponse::new(r);
+        resp.send(status, "text/plain", Some(format!("{}", status).as_bytes()));
+        resp
+    }));
+}
+
+// Signature is hex encoded HMAC-SHA256 of the message, expires is unix time in seconds
+fn check(secure_link: &SecureLinkContext, secret: &[u8], r: &HttpRequest) -> Result<(), HttpStatus> {
+    let signature = r.expand(&secure_link.signature).to_ascii_lowercase();
+    let message = r.expand(&secure_link.message);
+
+    let expected = crypto::hex(&crypto::hmac_sha256(secret, message.as_bytes()));
+    if signature.is_empty() || !crypto::constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+        return Err(HttpStatus::FORBIDDEN);
+    }
+
+    let expires = r.expand(&secure_link.expires);
+    if !expires.is_empty() {
+        let expires = expires.parse::<u64>().or(Err(HttpStatus::FORBIDDEN))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if expires < now {
+            return Err(HttpStatus::GONE);
+        }
+    }
+
+    Ok(())
+}
+
+impl Plugin for SecureLink {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "SecureLink"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::ROUTE, "secure_link.secret", |secure_link: &mut SecureLinkContext, secret: String| {
+            secure_link.secret = Some(secret);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "secure_link.signature", |secure_link: &mut SecureLinkContext, signature: HttpComplexValue| {
+            secure_link.signature = signature;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "secure_link.expires", |secure_link: &mut SecureLinkContext, expires: HttpComplexValue| {
+            secure_link.expires = expires;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "secure_link.message", |secure_link: &mut SecureLinkContext, message: HttpComplexValue| {
+            secure_link.message = message;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "secure_link", Schema::new()
+            .required("secret", SchemaType::String)
+            .optional("signature", SchemaType::String)
+            .optional("expires", SchemaType::String)
+            .optional("message", SchemaType::String))?;
+
+        add_block!(Context::ROUTE, "secure_link", |context| {
+            match context.get_mut::<SecureLinkContext>() {
+                Some(secure_link) => {
+                    // exit
+                    let secure_link = take(secure_link);
+                    let secret = match &secure_link.secret {
+                        Some(secret) => secret.clone().into_bytes(),
+                        None => return throw!("'secure_link.secret' is not defined")
+                    };
+                    let mut parent = context.parent().unwrap();
+                    let route = parent.get_mut::<RouteContext>().unwrap();
+                    route.auth = true;
+                    route.access.push_back(AccessHandler::new(move |r| -> Code {
+                        if let Err(status) = check(&secure_link, &secret, r) {
+                            log_http_error!(r, "warn", "secure_link rejected with {}, uri={}", status, r.uri());
+                            reject(r, status);
+                        }
+                        Code::DECLINED
+                    }));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<SecureLinkContext>()))
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl SecureLink {
+    pub fn new() -> SecureLink {
+        SecureLink {}
+    }
+}
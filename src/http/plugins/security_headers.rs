@@ -0,0 +1,128 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(SecurityHeadersPlugin);
+
+use std::mem::take;
+use std::sync::Arc;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::http::security_headers::SecurityHeaders;
+
+// 'security_headers: on', 'security_headers: off' or { hsts: ..., frame_options: ..., ... }
+#[derive(Default)]
+pub struct SecurityHeadersPlugin
+{}
+
+// Scalar form of the block, the map is 'on'
+fn enabled(value: &str) -> Result<bool, CoreError> {
+    match value {
+        "" | "on" => Ok(true),
+        "off" => Ok(false),
+        _ => throw!("security_headers: 'on', 'off' or the headers expected, found '{}'", value)
+    }
+}
+
+impl Plugin for SecurityHeadersPlugin {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "SecurityHeaders"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        for context in [Context::SERVER, Context::ROUTE].iter() {
+            add_command!(context, "security_headers.hsts", |headers: &mut SecurityHeaders, hsts: String| {
+                headers.hsts = Some(hsts);
+                Ok(None)
+            })?;
+
+            add_command!(context, "security_headers.frame_options", |headers: &mut SecurityHeaders, frame_options: String| {
+                headers.frame_options = Some(frame_options);
+                Ok(None)
+            })?;
+
+            add_command!(context, "security_headers.content_type_options", |headers: &mut SecurityHeaders, content_type_options: String| {
+                headers.content_type_options = Some(content_type_options);
+                Ok(None)
+            })?;
+
+            add_command!(context, "security_headers.referrer_policy", |headers: &mut SecurityHeaders, referrer_policy: String| {
+                headers.referrer_policy = Some(referrer_policy);
+                Ok(None)
+            })?;
+
+            add_command!(context, "security_headers.content_security_policy", |headers: &mut SecurityHeaders, policy: String| {
+                headers.content_security_policy = Some(policy);
+                Ok(None)
+            })?;
+
+            add_command!(context, "security_headers.content_security_policy_report_only", |headers: &mut SecurityHeaders, policy: String| {
+                headers.content_security_policy_report_only = Some(policy);
+                Ok(None)
+            })?;
+
+            add_schema!(context, "security_headers", Schema::new()
+                .optional("hsts", SchemaType::String)
+                .optional("frame_options", SchemaType::String)
+                .optional("content_type_options", SchemaType::String)
+                .optional("referrer_policy", SchemaType::String)
+                .optional("content_security_policy", SchemaType::String)
+                .optional("content_security_policy_report_only", SchemaType::String))?;
+        }
+
+        // Server
+
+        add_block!(Context::SERVER, "security_headers", |context, value: String| {
+            match context.get_mut::<SecurityHeaders>() {
+                Some(headers) => {
+                    // exit
+                    let headers = take(headers);
+                    context.parent().unwrap()
+                           .get_mut::<ServerContext>().unwrap()
+                           .security_headers = Some(Arc::new(headers));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new(SecurityHeaders {
+                        enabled: Some(enabled(&value)?),
+                        ..Default::default()
+                    })))
+            }
+        })?;
+
+        // Route
+
+        add_block!(Context::ROUTE, "security_headers", |context, value: String| {
+            match context.get_mut::<SecurityHeaders>() {
+                Some(headers) => {
+                    // exit
+                    let headers = take(headers);
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .security_headers = Some(Arc::new(headers));
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new(SecurityHeaders {
+                        enabled: Some(enabled(&value)?),
+                        ..Default::default()
+                    })))
+            }
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl SecurityHeadersPlugin {
+    pub fn new() -> SecurityHeadersPlugin {
+        SecurityHeadersPlugin {}
+    }
+}
@@ -5,42 +5,149 @@
 register_http_plugin!(HttpServer);
 
 use chrono::prelude::*;
-use std::sync::{ Arc, Mutex };
+use std::sync::{ Arc, Mutex, atomic::{ AtomicUsize, Ordering } };
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::{ HashMap, LinkedList };
 use std::mem::take;
 use std::time::Duration;
+use std::fs;
 
 use crate::plugin::*;
 use crate::config::*;
 use crate::http::*;
 use crate::http::http_server_core::*;
+use crate::http::{ inflight, limits, metrics, vhosts };
+use crate::http::condition::Condition;
+use crate::core::{ fd, budget, buffers, cgroup, upgrade, affinity, poller::Engine, status::{ self, Json } };
+use crate::core::accept::{ Accept, AcceptGroup, Balancing };
 use crate::http::HttpMethod;
 use crate::variable::*;
+use crate::error::CoreError;
+
+use yaml_rust::{ yaml, Yaml };
 
 type ServerType = Rc<RefCell<HttpServerCore>>;
 
+// Condition of the route, 'var' is compared with 'equals' or matched with 'regex'
+#[derive(Default)]
+struct IfContext {
+    var: Option<String>,
+    equals: Option<String>,
+    regex: Option<String>
+}
+
+// 'GET' or '[GET, HEAD]'
+impl crate::config::Value for Vec<HttpMethod> {
+    type Type = Vec<HttpMethod>;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        let method = |v: &Yaml| match v.as_str().map(|method| HttpMethod::from(method.to_uppercase())) {
+            Some(HttpMethod::UNSUPPORTED) | None => throw!("invalid value"),
+            Some(method) => Ok(method)
+        };
+        match v {
+            Yaml::Array(methods) if !methods.is_empty() => methods.iter().map(method).collect(),
+            Yaml::Array(_) => throw!("empty list"),
+            _ => Ok(vec![method(v)?])
+        }
+    }
+}
+
+// Sizes not configured explicitly are derived from the cgroup limits
 struct WorkgroupContext {
     name: String,
-    event_pool_size: usize,
-    thread_pool_size: usize,
-    socket_pool_size: usize
+    event_pool_size: Option<usize>,
+    thread_pool_size: Option<usize>,
+    socket_pool_size: Option<usize>,
+    events_batch: Option<usize>,
+    memory_budget: Option<usize>,
+    buffer_pool_size: Option<usize>,
+    cpu_affinity: Vec<usize>,
+    io_engine: Engine,
+    accept_balancing: Balancing,
+    multi_accept: usize,
+    async_threads: usize
 }
 
 impl Default for WorkgroupContext {
     fn default() -> WorkgroupContext {
         WorkgroupContext {
             name: "default".to_string(),
-            event_pool_size: 1,
-            thread_pool_size: 10,
-            socket_pool_size: 1024
+            event_pool_size: None,
+            thread_pool_size: None,
+            socket_pool_size: None,
+            events_batch: None,
+            memory_budget: None,
+            buffer_pool_size: None,
+            cpu_affinity: Vec::new(),
+            io_engine: Engine::default(),
+            accept_balancing: Balancing::default(),
+            multi_accept: MULTI_ACCEPT,
+            async_threads: 0
+        }
+    }
+}
+
+// New binary must start accepting in this time
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Connections accepted by the event pool before serving the others
+const MULTI_ACCEPT: usize = 64;
+
+// Approximate memory of the connection with its buffers
+const SOCKET_MEMORY: usize = 256 * 1024;
+
+impl WorkgroupContext {
+    fn named(name: &str) -> WorkgroupContext {
+        WorkgroupContext {
+            name: name.to_string(),
+            ..Default::default()
         }
     }
+
+    // Without cgroup limits the defaults are 1 event pool, 10 threads, 1024 sockets and no budget
+    fn create(&self, group: &mut Vec<ServerType>) -> ActionResult {
+        let limits = cgroup::limits();
+        let event_pool_size = self.event_pool_size.or(limits.cpus()).unwrap_or(1);
+        let thread_pool_size = self.thread_pool_size.or(limits.cpus().map(|cpus| std::cmp::min(cpus * 4, 10))).unwrap_or(10);
+        let socket_pool_size = self.socket_pool_size.or(limits.memory.map(|memory| {
+            (memory / SOCKET_MEMORY / std::cmp::max(event_pool_size, 1)).clamp(64, 1024)
+        })).unwrap_or(1024);
+        let events_batch = self.events_batch.unwrap_or(socket_pool_size);
+        let memory_budget = self.memory_budget.or(limits.memory.map(|memory| memory / 2)).unwrap_or(0);
+
+        let accept = AcceptGroup::new(self.accept_balancing, event_pool_size, self.multi_accept);
+        for n in 0..event_pool_size {
+            let affinity = affinity::event_pool(&self.cpu_affinity, n, event_pool_size);
+            group.push(Rc::new(RefCell::new(HttpServerCore::new(thread_pool_size, events_batch, affinity, self.io_engine, Accept::new(&accept, n))?)))
+        }
+        status::register("accept", &self.name, Box::new(move || Some(accept.report())));
+        register_workgroup(&self.name, group, thread_pool_size, socket_pool_size, events_batch);
+        budget::create(&self.name, memory_budget);
+        buffers::create(&self.name, self.buffer_pool_size.unwrap_or(socket_pool_size));
+        #[cfg(feature = "async")]
+        crate::http::runtime::create(&self.name, self.async_threads)?;
+        Ok(OK)
+    }
+}
+
+// (bind, host, pattern, method)
+type RouteKey = (String, String, String, Option<String>);
+
+lazy_static! {
+    // Routes taken out of the running servers by routes_admin
+    static ref REMOVED_ROUTES: Mutex<HashMap<RouteKey, RouteContext>> = Mutex::new(HashMap::new());
+    // Documents of the routes added to the running servers by routes_admin in the order they are added
+    static ref ADDED_ROUTES: Mutex<Vec<Yaml>> = Mutex::new(Vec::new());
 }
 
 pub struct HttpServer {
-    groups: Arc<Mutex<HashMap<String, Vec<ServerType>>>>
+    groups: Arc<Mutex<HashMap<String, Vec<ServerType>>>>,
+    shutdown_timeout: Arc<Mutex<Duration>>,
+    // 'removed_routes' of the snapshot, taken out when the servers are configured
+    removed_routes: Arc<Mutex<Vec<String>>>,
+    // 'added_routes' of the snapshot, added before the removed ones are taken out
+    added_routes: Arc<Mutex<Vec<Yaml>>>
 }
 
 impl Plugin for HttpServer {
@@ -59,10 +166,7 @@ impl Plugin for HttpServer {
                 Some(context) => {
                     // exit
                     let mut groups = groups_.lock().unwrap();
-                    let e = groups.entry(context.name.clone()).or_default();
-                    for _ in 0..context.event_pool_size {
-                        e.push(Rc::new(RefCell::new(HttpServerCore::new(context.thread_pool_size, context.socket_pool_size)?)))
-                    }
+                    context.create(groups.entry(context.name.clone()).or_default())?;
                     Ok(None)
                 },
                 None =>
@@ -77,17 +181,341 @@ impl Plugin for HttpServer {
         })?;
 
         add_command!(Context::WORKGROUP, "event_pool_size", |workgroup: &mut WorkgroupContext, event_pool_size: usize| {
-            workgroup.event_pool_size = event_pool_size;
+            workgroup.event_pool_size = Some(event_pool_size);
             Ok(None)
         })?;
 
         add_command!(Context::WORKGROUP, "thread_pool_size", |workgroup: &mut WorkgroupContext, thread_pool_size: usize| {
-            workgroup.thread_pool_size = thread_pool_size;
+            workgroup.thread_pool_size = Some(thread_pool_size);
+            Ok(None)
+        })?;
+
+        // Request buffers, response bodies and log buffers of the workgroup servers, zero is unlimited
+
+        add_command!(Context::WORKGROUP, "memory_budget", |workgroup: &mut WorkgroupContext, memory_budget: usize| {
+            workgroup.memory_budget = Some(memory_budget);
+            Ok(None)
+        })?;
+
+        // Connection buffers cached by the workgroup, socket_pool_size by default, zero disables pooling
+
+        add_command!(Context::WORKGROUP, "buffer_pool_size", |workgroup: &mut WorkgroupContext, buffer_pool_size: usize| {
+            workgroup.buffer_pool_size = Some(buffer_pool_size);
+            Ok(None)
+        })?;
+
+        // IO thread of every event pool is pinned to one of the cores, worker threads to all of them
+
+        add_command!(Context::WORKGROUP, "worker_cpu_affinity", |workgroup: &mut WorkgroupContext, cpus: String| {
+            workgroup.cpu_affinity = affinity::parse(&cpus).or_else(|err| throw!("worker_cpu_affinity: {}", err.what()))?;
             Ok(None)
         })?;
 
         add_command!(Context::WORKGROUP, "socket_pool_size", |workgroup: &mut WorkgroupContext, socket_pool_size: usize| {
-            workgroup.socket_pool_size = socket_pool_size;
+            workgroup.socket_pool_size = Some(socket_pool_size);
+            Ok(None)
+        })?;
+
+        // Events taken by one poll of the event pool, socket_pool_size by default
+
+        add_command!(Context::WORKGROUP, "events_batch", |workgroup: &mut WorkgroupContext, events_batch: usize| {
+            if events_batch == 0 {
+                return throw!("events_batch: must be positive");
+            }
+            workgroup.events_batch = Some(events_batch);
+            Ok(None)
+        })?;
+
+        // Event engine of the event pools: epoll or uring (io_uring, requires the 'uring' feature)
+
+        add_command!(Context::WORKGROUP, "io_engine", |workgroup: &mut WorkgroupContext, io_engine: String| {
+            workgroup.io_engine = Engine::parse(&io_engine)?;
+            Ok(None)
+        })?;
+
+        // Distribution of connections between the event pools: reuseport (a socket per event pool)
+        // or leader (one shared socket, accepted by the least loaded event pools)
+
+        add_command!(Context::WORKGROUP, "accept_balancing", |workgroup: &mut WorkgroupContext, accept_balancing: String| {
+            workgroup.accept_balancing = Balancing::parse(&accept_balancing)?;
+            Ok(None)
+        })?;
+
+        // Connections accepted by the event pool at once, the rest after the ready events, zero is unlimited
+
+        add_command!(Context::WORKGROUP, "multi_accept", |workgroup: &mut WorkgroupContext, multi_accept: usize| {
+            workgroup.multi_accept = multi_accept;
+            Ok(None)
+        })?;
+
+        // Threads of the tokio runtime running async content handlers (requires the 'async' feature), zero disables it
+
+        add_command!(Context::WORKGROUP, "async_threads", |workgroup: &mut WorkgroupContext, async_threads: usize| {
+            if cfg!(not(feature = "async")) && async_threads != 0 {
+                return throw!("async_threads: is not supported, the binary is built without the 'async' feature");
+            }
+            workgroup.async_threads = async_threads;
+            Ok(None)
+        })?;
+
+        // Shutdown
+
+        let shutdown_timeout_ = self.shutdown_timeout.clone();
+
+        add_command!(Context::HTTP, "shutdown_timeout", move |_: &mut HttpContext, shutdown_timeout: Duration| {
+            *shutdown_timeout_.lock().unwrap() = shutdown_timeout;
+            Ok(None)
+        })?;
+
+        add_command!(Context::HTTP, "fd_high_water", |_: &mut HttpContext, fd_high_water: usize| {
+            if fd_high_water > 100 {
+                return throw!("'fd_high_water' must be a percent of open files limit");
+            }
+            fd::set_high_water(fd_high_water);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "fd_status", |route: &mut RouteContext| {
+            route.content = Some(ContentHandler::new(|r| -> HttpResponse {
+                let mut resp = HttpResponse::new(r);
+                resp.send(HttpStatus::OK, "text/plain", Some(fd::report().as_bytes()));
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        status::register("plugins", "fd", Box::new(|| {
+            Some(Json::object(vec![
+                ("open", fd::open().into()),
+                ("limit", fd::limit().into()),
+                ("high_water", fd::high_water().into())
+            ]))
+        }));
+
+        status::register("plugins", "inflight", Box::new(|| {
+            Some(Json::object(vec![
+                ("draining", inflight::draining().into()),
+                ("inflight", inflight::count().into())
+            ]))
+        }));
+
+        add_command!(Context::ROUTE, "status", |route: &mut RouteContext| {
+            route.content = Some(ContentHandler::new(|r| -> HttpResponse {
+                let mut resp = HttpResponse::new(r);
+                resp.clear_context("inflight");
+                resp.send(HttpStatus::OK, "application/json", Some(format!("{}\n", status::report()).as_bytes()));
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        // Counters of the endpoints in the Prometheus text format
+
+        add_command!(Context::ROUTE, "metrics", |route: &mut RouteContext| {
+            route.content = Some(ContentHandler::new(|r| -> HttpResponse {
+                let mut resp = HttpResponse::new(r);
+                resp.clear_context("inflight");
+                resp.send(HttpStatus::OK, "text/plain; version=0.0.4", Some(metrics::prometheus().as_bytes()));
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        // Body is a list of sample requests 'METHOD URI [HOST [BIND]]', one per line,
+        // the answer is the server, route and upstream each of them would select
+
+        add_command!(Context::ROUTE, "routes_test", |route: &mut RouteContext| {
+            route.content = Some(ContentHandler::new(|r| -> HttpResponse {
+                let server_addr = r.const_context().server_addr;
+                let body = r.body_contents().map(|body| String::from_utf8_lossy(&body.unwrap_or_default()).to_string());
+                let mut resp = HttpResponse::new(r);
+                let body = match body {
+                    Ok(body) => body,
+                    Err(err) => {
+                        resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(format!("{}\n", err.what()).as_bytes()));
+                        return resp;
+                    }
+                };
+                resp.clear_context("inflight");
+                let mut report = String::new();
+                for line in body.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                    let parts: Vec<&str> = line.split_whitespace().collect();
+                    if parts.len() < 2 {
+                        report.push_str(&format!("{} -> invalid, 'METHOD URI [HOST [BIND]]' expected\n", line));
+                        continue;
+                    }
+                    let uri = parts[1].split('?').next().unwrap();
+                    let host = parts.get(2).filter(|host| **host != "-").unwrap_or(&"*");
+                    let bind = match parts.get(3).map(|bind| bind.parse()) {
+                        Some(Ok(bind)) => bind,
+                        Some(Err(_)) => {
+                            report.push_str(&format!("{} -> invalid bind\n", line));
+                            continue;
+                        },
+                        None => server_addr
+                    };
+                    match http_server_core::resolve(&bind, host, &parts[0].to_uppercase(), uri) {
+                        Some(resolved) => report.push_str(&format!("{} -> server={} {} route={} upstream={}\n", line, bind, resolved.host,
+                            resolved.pattern.unwrap_or("-".to_string()), resolved.upstream.unwrap_or("-".to_string()))),
+                        None => report.push_str(&format!("{} -> no server on {}\n", line, bind))
+                    }
+                }
+                resp.send(HttpStatus::OK, "text/plain", Some(report.as_bytes()));
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        // Body is a list of routes 'METHOD PATTERN [HOST [BIND]]', one per line, '*' is any method,
+        // DELETE takes them out of the running server, POST puts the removed ones back, GET lists removed.
+        // Body of PUT is the YAML document '{ bind, virtual_host, routes }', its routes are added to the running server.
+
+        add_command!(Context::ROUTE, "routes_admin", |route: &mut RouteContext| {
+            route.content = Some(ContentHandler::new(|r| -> HttpResponse {
+                let server_addr = r.const_context().server_addr;
+                let body = r.body_contents().map(|body| String::from_utf8_lossy(&body.unwrap_or_default()).to_string());
+                let mut resp = HttpResponse::new(r);
+                let body = match body {
+                    Ok(body) => body,
+                    Err(err) => {
+                        resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(format!("{}\n", err.what()).as_bytes()));
+                        return resp;
+                    }
+                };
+                resp.clear_context("inflight");
+                let method = resp.get_request().method();
+                let mut removed = REMOVED_ROUTES.lock().unwrap();
+                let mut report = String::new();
+                if matches!(method, HttpMethod::PUT) {
+                    drop(removed);
+                    match added_routes(&body, &server_addr.to_string()) {
+                        Ok(report) => resp.send(HttpStatus::OK, "text/plain", Some(report.as_bytes())),
+                        Err(err) => resp.send(HttpStatus::BAD_REQUEST, "text/plain", Some(format!("{}\n", err.what()).as_bytes()))
+                    }
+                    return resp;
+                }
+                if matches!(method, HttpMethod::GET) {
+                    for ((bind, host, pattern, method), _) in removed.iter() {
+                        report.push_str(&format!("{} {} {} {}\n", method.as_deref().unwrap_or("*"), pattern, host, bind));
+                    }
+                    resp.send(HttpStatus::OK, "text/plain", Some(report.as_bytes()));
+                    return resp;
+                }
+                for line in body.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                    let route = match AdminRoute::parse(line, &server_addr.to_string()) {
+                        Ok(route) => route,
+                        Err(err) => {
+                            report.push_str(&format!("{} -> {}\n", line, err));
+                            continue;
+                        }
+                    };
+                    let key = route.key();
+                    let result = match method {
+                        HttpMethod::DELETE => route.remove(&mut removed),
+                        HttpMethod::POST => match removed.remove(&key) {
+                            Some(route) => match http_server_core::add_route(&key.0, &route) {
+                                Ok(_) => "restored".to_string(),
+                                Err(err) => {
+                                    let err = err.what().to_string();
+                                    removed.insert(key, route);
+                                    err
+                                }
+                            },
+                            None => "not removed".to_string()
+                        },
+                        _ => {
+                            resp.send(HttpStatus::NOT_ALLOWED, "text/plain", Some(b"Method not allowed"));
+                            return resp;
+                        }
+                    };
+                    report.push_str(&format!("{} -> {}\n", line, result));
+                }
+                resp.send(HttpStatus::OK, "text/plain", Some(report.as_bytes()));
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        // Routes taken out by routes_admin are saved by config_export as 'METHOD PATTERN HOST BIND'
+        // and taken out again on start from the snapshot
+
+        let removed_routes_ = self.removed_routes.clone();
+
+        add_command!(Context::HTTP, "removed_routes", move |_: &mut HttpContext, lines: Vec<String>| {
+            if let Some(line) = lines.iter().find(|line| admin_line(line).len() != 4) {
+                return throw!("removed_routes: '{}', 'METHOD PATTERN HOST BIND' expected", line);
+            }
+            *removed_routes_.lock().unwrap() = lines;
+            Ok(None)
+        })?;
+
+        let added_routes_ = self.added_routes.clone();
+
+        add_command!(Context::HTTP, "added_routes", move |_: &mut HttpContext, docs: Yaml| {
+            *added_routes_.lock().unwrap() = docs.into_vec().unwrap_or_default();
+            Ok(None)
+        })?;
+
+        Config::add_snapshot_provider(Box::new(|doc| {
+            let added = ADDED_ROUTES.lock().unwrap().clone();
+            if let Some(Yaml::Hash(http)) = node_mut(doc, &["http"]) {
+                let key = Yaml::String("added_routes".to_string());
+                match added.is_empty() {
+                    true => http.remove(&key),
+                    false => http.insert(key, Yaml::Array(added))
+                };
+            }
+        }));
+
+        Config::add_snapshot_provider(Box::new(|doc| {
+            let mut lines: Vec<String> = REMOVED_ROUTES.lock().unwrap().keys().map(|(bind, host, pattern, method)| {
+                format!("{} {} {} {}", method.as_deref().unwrap_or("*"), pattern, host, bind)
+            }).collect();
+            lines.sort();
+            if let Some(Yaml::Hash(http)) = node_mut(doc, &["http"]) {
+                let key = Yaml::String("removed_routes".to_string());
+                match lines.is_empty() {
+                    true => http.remove(&key),
+                    false => http.insert(key, Yaml::Array(lines.into_iter().map(Yaml::String).collect()))
+                };
+            }
+        }));
+
+        // POST starts the binary ('' is the current one) with the listeners of this process,
+        // when it is ready this process stops accepting and drains
+
+        add_command!(Context::ROUTE, "binary_upgrade", |route: &mut RouteContext, binary: String| {
+            route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                let mut resp = HttpResponse::new(r);
+                resp.clear_context("inflight");
+                if !matches!(resp.get_request().method(), HttpMethod::POST) {
+                    resp.send(HttpStatus::NOT_ALLOWED, "text/plain", Some(b"Method not allowed"));
+                    return resp;
+                }
+                let binary = match binary.is_empty() {
+                    true => None,
+                    false => Some(binary.as_str())
+                };
+                match upgrade::upgrade(binary, UPGRADE_TIMEOUT) {
+                    Ok(pid) => resp.send(HttpStatus::OK, "text/plain", Some(format!("upgraded, pid={}\n", pid).as_bytes())),
+                    Err(err) => {
+                        log_http_error!(resp, "error", "{}", err.what());
+                        resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(format!("{}\n", err.what()).as_bytes()))
+                    }
+                }
+                resp
+            }));
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "inflight_status", |route: &mut RouteContext| {
+            route.content = Some(ContentHandler::new(|r| -> HttpResponse {
+                // admin request itself is not reported
+                let mut resp = HttpResponse::new(r);
+                resp.clear_context("inflight");
+                resp.send(HttpStatus::OK, "text/plain", Some(inflight::report().as_bytes()));
+                resp
+            }));
             Ok(None)
         })?;
 
@@ -124,19 +552,89 @@ impl Plugin for HttpServer {
             }
         })?;
 
+        // Group is flattened into the routes before the config is validated
+
+        add_expander!(Context::SERVER, "routes.route_group", route_group)?;
+
+        add_expander!(Context::SERVER, "routes.route", nested_routes)?;
+
         add_command!(Context::ROUTE, "match", |route: &mut RouteContext, pattern: String| {
             route.pattern = pattern;
             Ok(None)
         })?;
 
-        add_command!(Context::ROUTE, "method", |route: &mut RouteContext, method: String| {
-            route.method = match HttpMethod::from(method) {
-                HttpMethod::UNSUPPORTED => return throw!("invalid value"),
-                m => Some(m)
-            };
+        add_command!(Context::ROUTE, "method", |route: &mut RouteContext, methods: Vec<HttpMethod>| {
+            match methods.len() {
+                1 => route.method = Some(methods[0]),
+                _ => route.methods = methods
+            }
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "ignore_case", |route: &mut RouteContext, ignore_case: bool| {
+            route.ignore_case = ignore_case;
             Ok(None)
         })?;
 
+        add_command!(Context::ROUTE, "ignore_trailing_slash", |route: &mut RouteContext, ignore_trailing_slash: bool| {
+            route.ignore_trailing_slash = ignore_trailing_slash;
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "metric_name", |route: &mut RouteContext, metric_name: String| {
+            route.metrics = Some(metrics::endpoint(&metric_name));
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "expect_continue", |route: &mut RouteContext, expect_continue: bool| {
+            route.expect_continue = Some(expect_continue);
+            Ok(None)
+        })?;
+
+        // Route is skipped unless the condition holds, the next matching route is taken
+
+        add_command!(Context::ROUTE, "if.var", |cond: &mut IfContext, var: String| {
+            cond.var = Some(var);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "if.equals", |cond: &mut IfContext, equals: String| {
+            cond.equals = Some(equals);
+            Ok(None)
+        })?;
+
+        add_command!(Context::ROUTE, "if.regex", |cond: &mut IfContext, regex: String| {
+            cond.regex = Some(regex);
+            Ok(None)
+        })?;
+
+        add_schema!(Context::ROUTE, "if", Schema::new()
+            .required("var", SchemaType::String)
+            .optional("equals", SchemaType::String)
+            .optional("regex", SchemaType::String))?;
+
+        add_block!(Context::ROUTE, "if", |context| {
+            match context.get_mut::<IfContext>() {
+                Some(cond) => {
+                    // exit
+                    let cond = take(cond);
+                    let var = cond.var.unwrap_or_default();
+                    let condition = match (cond.equals, cond.regex) {
+                        (Some(equals), None) => Condition::equals(&var, &equals),
+                        (None, Some(regex)) => Condition::matches(&var, &regex)?,
+                        _ => return throw!("if: one of 'equals' or 'regex' is required")
+                    };
+                    context.parent().unwrap()
+                           .get_mut::<RouteContext>().unwrap()
+                           .condition = Some(condition);
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<IfContext>()))
+            }
+        })?;
+
         // Server
 
         add_empty_block!(Context::HTTP, "servers")?;
@@ -156,6 +654,23 @@ impl Plugin for HttpServer {
             Ok(None)
         })?;
 
+        add_command!(Context::SERVER, "client_header_timeout", |server: &mut ServerContext, client_header_timeout: Duration| {
+            server.client_header_timeout = Some(client_header_timeout);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "max_half_read_connections", |server: &mut ServerContext, max_half_read_connections: usize| {
+            server.max_half_read_connections = max_half_read_connections;
+            Ok(None)
+        })?;
+
+        // Connections over the limit are answered with 503 and closed
+
+        add_command!(Context::SERVER, "max_connections", |server: &mut ServerContext, max_connections: usize| {
+            server.max_connections = max_connections;
+            Ok(None)
+        })?;
+
         add_command!(Context::SERVER, "keepalive_timeout", |server: &mut ServerContext, keepalive_timeout: Duration| {
             server.keepalive_timeout = Some(keepalive_timeout);
             Ok(None)
@@ -166,42 +681,179 @@ impl Plugin for HttpServer {
             Ok(None)
         })?;
 
+        add_command!(Context::SERVER, "client_max_body_size", |server: &mut ServerContext, client_max_body_size: usize| {
+            server.limits.max_body_size = client_max_body_size;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "large_client_header_buffers", |server: &mut ServerContext, buffers: String| {
+            let (number, size) = limits::parse_buffers(&buffers)?;
+            server.limits.header_buffers = number;
+            server.limits.header_buffer_size = size;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "max_header_line", |server: &mut ServerContext, max_header_line: usize| {
+            server.limits.header_line_size = max_header_line;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "max_headers_size", |server: &mut ServerContext, max_headers_size: usize| {
+            server.limits.headers_size = max_headers_size;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "strict_framing", |server: &mut ServerContext, strict_framing: bool| {
+            server.limits.strict_framing = strict_framing;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "max_uri_length", |server: &mut ServerContext, max_uri_length: usize| {
+            server.limits.max_uri_length = max_uri_length;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "merge_slashes", |server: &mut ServerContext, merge_slashes: bool| {
+            server.limits.merge_slashes = merge_slashes;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "max_request_line", |server: &mut ServerContext, max_request_line: usize| {
+            server.limits.max_request_line = max_request_line;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "max_header_count", |server: &mut ServerContext, max_header_count: usize| {
+            server.limits.max_header_count = max_header_count;
+            Ok(None)
+        })?;
+
+        // Request bodies over the buffer size are written to temporary files
+
+        add_command!(Context::SERVER, "client_body_buffer_size", |server: &mut ServerContext, buffer_size: usize| {
+            server.spool.buffer_size = buffer_size;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "client_body_temp_path", |server: &mut ServerContext, temp_path: String| {
+            server.spool.temp_path = Some(temp_path);
+            Ok(None)
+        })?;
+
+        // Parts of multipart/form-data bodies parsed by HttpRequest::multipart
+
+        add_command!(Context::SERVER, "multipart_max_part_size", |server: &mut ServerContext, max_part_size: usize| {
+            server.multipart.max_part_size = max_part_size;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "multipart_spool_size", |server: &mut ServerContext, spool_size: usize| {
+            server.multipart.spool_size = spool_size;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "multipart_temp_path", |server: &mut ServerContext, temp_path: String| {
+            server.multipart.temp_path = Some(temp_path);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "metric_name", |server: &mut ServerContext, metric_name: String| {
+            server.metrics = Some(metrics::endpoint(&metric_name));
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "auto_options", |server: &mut ServerContext, auto_options: bool| {
+            server.auto_options = auto_options;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "shared", |server: &mut ServerContext, shared: bool| {
+            server.shared = shared;
+            Ok(None)
+        })?;
+
         add_command!(Context::SERVER, "group", |server: &mut ServerContext, workgroup: String| {
             server.workgroup = workgroup;
             Ok(None)
         })?;
 
         add_command!(Context::SERVER, "virtual_host", |server: &mut ServerContext, virtual_host: String| {
+            vhosts::validate(&virtual_host)?;
             server.virtual_host = Some(virtual_host);
             Ok(None)
         })?;
 
+        add_command!(Context::SERVER, "default_server", |server: &mut ServerContext, default_server: bool| {
+            server.default_server = default_server;
+            Ok(None)
+        })?;
+
         let groups_ = self.groups.clone();
 
         add_block!(Context::HTTP, "servers.server", move |context| {
             match context.get_mut::<ServerContext>() {
                 Some(context) => {
                     // exit
+                    if !context.auth {
+                        unauthenticated(context.routes.iter().flatten())?;
+                    }
                     if context.bind.len() != 0 {
+                        // known when the server block is complete
+                        let server_name = context.virtual_host.clone().unwrap_or_default();
+                        context.setvar.push_front(SetVarHandler::new(move |r| {
+                            let server_name = server_name.clone();
+                            add_var_lazy!(r, "server_name", move |_| {
+                                server_name.as_str()
+                            });
+                            Code::DECLINED
+                        }));
                         let mut guard = groups_.lock().unwrap();
-                        let groups = guard.entry(context.workgroup.clone()).or_insert_with(||
-                            vec![Rc::new(RefCell::new(HttpServerCore::new(10, 1024).unwrap()))]
-                        );
+                        if !guard.contains_key(&context.workgroup) {
+                            let mut groups = Vec::new();
+                            WorkgroupContext::named(&context.workgroup).create(&mut groups)?;
+                            guard.insert(context.workgroup.clone(), groups);
+                        }
+                        let groups = guard.get(&context.workgroup).unwrap();
                         for group in groups.iter() {
                             let mut group = group.borrow_mut();
                             group.add_server(&context, None)?;
                         }
+                        register_server(context);
                         Ok(None)
                     } else {
                         return throw!("'bind' is not defined");
                     }
                 },
                 None => {
-                    // enter
-                    let mut context = ServerContext::default();
+                    // enter, the error log and the rate limits of the http block are inherited
+                    let http = context.get_mut::<HttpContext>().cloned().unwrap_or_default();
+
+                    let mut context = ServerContext {
+                        error_log: http.error_log,
+                        error_log_level: http.error_log_level,
+                        ..ServerContext::default()
+                    };
 
+                    if let Some(limit_rate) = http.limit_rate {
+                        context.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                            resp.set_limit_rate(limit_rate)
+                        }));
+                    }
+                    if let Some(limit_rate_after) = http.limit_rate_after {
+                        context.header_filter.push_back(HeaderFilterHandler::new(move |resp| {
+                            resp.set_limit_rate_after(limit_rate_after)
+                        }));
+                    }
+                    context.limit_upload_rate = http.limit_upload_rate;
                     context.workgroup = "default".to_string();
                     context.keepalive_requests = std::u64::MAX;
+                    context.limits.merge_slashes = true;
+                    context.limits.strict_framing = true;
+                    context.limits.max_body_size = limits::MAX_BODY_SIZE_DEFAULT;
+
+                    let hostname = Arc::new(fs::read_to_string("/proc/sys/kernel/hostname")
+                        .map(|s| s.trim().to_string())
+                        .unwrap_or_else(|_| "localhost".to_string()));
     
                     context.setvar.push_back(SetVarHandler::new(move |r| {
                         add_var_lazy!(r, "uri", |r: &HttpRequest| {
@@ -219,9 +871,18 @@ impl Plugin for HttpServer {
                         add_var_lazy!(r, "protocol", |r: &HttpRequest| {
                             r.protocol()
                         });
+                        add_var_lazy!(r, "scheme", |r: &HttpRequest| {
+                            r.scheme()
+                        });
+                        add_var_lazy!(r, "host", |r: &HttpRequest| {
+                            r.host()
+                        });
                         add_var_lazy!(r, "content-length", |r: &HttpRequest| {
                             r.content_length().unwrap_or(0)
                         });
+                        add_var_lazy!(r, "request_body_time", |r: &HttpRequest| {
+                            r.body_time().as_millis()
+                        });
                         add_var_lazy!(r, "local_time", |_| {
                             format!("{}", Local::now().format("%Y/%m/%d-%H:%M:%S"))
                         });
@@ -234,6 +895,43 @@ impl Plugin for HttpServer {
                         add_var_lazy!(r, "request_time", |r: &HttpRequest| {
                             r.request_time()
                         });
+                        add_var_lazy!(r, "request_id", |r: &HttpRequest| {
+                            r.const_context().request_id().unwrap_or_default()
+                        });
+                        add_var_lazy!(r, "server_port", |r: &HttpRequest| {
+                            r.const_context().server_addr.port()
+                        });
+                        add_var_lazy!(r, "connection", |r: &HttpRequest| {
+                            r.const_context().connection().unwrap_or_default()
+                        });
+                        add_var_lazy!(r, "connection_requests", |r: &HttpRequest| {
+                            r.const_context().connection_requests().unwrap_or_default()
+                        });
+                        let hostname = hostname.clone();
+                        add_var_lazy!(r, "hostname", move |_| {
+                            hostname.as_str()
+                        });
+                        // seconds with milliseconds at the moment of evaluation
+                        add_var_lazy!(r, "msec", |_| {
+                            let now = Utc::now();
+                            format!("{}.{:03}", now.timestamp(), now.timestamp_subsec_millis())
+                        });
+                        // TCP_INFO of the client connection, taken at the moment of evaluation
+                        add_var_lazy!(r, "tcpinfo_rtt", |r: &HttpRequest| {
+                            r.const_context().tcp_info().map(|info| info.rtt).unwrap_or_default()
+                        });
+                        add_var_lazy!(r, "tcpinfo_rttvar", |r: &HttpRequest| {
+                            r.const_context().tcp_info().map(|info| info.rttvar).unwrap_or_default()
+                        });
+                        add_var_lazy!(r, "tcpinfo_snd_cwnd", |r: &HttpRequest| {
+                            r.const_context().tcp_info().map(|info| info.snd_cwnd).unwrap_or_default()
+                        });
+                        add_var_lazy!(r, "tcpinfo_rcv_space", |r: &HttpRequest| {
+                            r.const_context().tcp_info().map(|info| info.rcv_space).unwrap_or_default()
+                        });
+                        add_var_lazy!(r, "tcpinfo_retrans", |r: &HttpRequest| {
+                            r.const_context().tcp_info().map(|info| info.retrans).unwrap_or_default()
+                        });
                         Code::DECLINED
                     }));
         
@@ -246,11 +944,47 @@ impl Plugin for HttpServer {
     }
 
     fn activate(&mut self) -> ActionResult {
+        for doc in take(&mut *self.added_routes.lock().unwrap()) {
+            if let Err(err) = add_routes(&doc, "") {
+                log_error!("warn", "added_routes: {}", err.what());
+            }
+        }
+        let lines = take(&mut *self.removed_routes.lock().unwrap());
+        let mut removed = REMOVED_ROUTES.lock().unwrap();
+        for line in lines {
+            let result = match AdminRoute::parse(&line, "") {
+                Ok(route) => route.remove(&mut removed),
+                Err(err) => err
+            };
+            if result != "removed" {
+                log_error!("warn", "removed_routes: '{}' -> {}", line, result);
+            }
+        }
         Ok(DECLINED)
     }
 
     fn deactivate(&mut self) -> ActionResult {
-        Ok(DECLINED)
+        let shutdown_timeout = *self.shutdown_timeout.lock().unwrap();
+        if let Ok(groups) = self.groups.lock() {
+            let groups = & *groups;
+            inflight::set_draining(true);
+            groups.iter().for_each(|(_, group)| {
+                for group in group.iter() {
+                    group.borrow_mut().close_listeners()
+                }
+            });
+            let count = inflight::drain(shutdown_timeout);
+            if count != 0 {
+                log_error!("warn", "shutdown timeout {}ms expired, force close\n{}",
+                           shutdown_timeout.as_millis(), inflight::report());
+            }
+            groups.iter().for_each(|(_, group)| {
+                for group in group.iter() {
+                    group.borrow_mut().stop()
+                }
+            });
+        }
+        Ok(OK)
     }
 
     fn wait(&mut self) {
@@ -265,10 +999,358 @@ impl Plugin for HttpServer {
     }
 }
 
+// Route of routes_admin taken out of the running server
+struct AdminRoute {
+    bind: String,
+    host: Option<String>,
+    pattern: String,
+    method: Option<HttpMethod>
+}
+
+impl AdminRoute {
+    fn parse(line: &str, server_addr: &str) -> Result<AdminRoute, String> {
+        let parts = admin_line(line);
+        if parts.len() < 2 {
+            return Err("invalid, 'METHOD PATTERN [HOST [BIND]]' expected".to_string());
+        }
+        let method = match parts[0].as_str() {
+            "*" => None,
+            m => match HttpMethod::from(m.to_uppercase()) {
+                HttpMethod::UNSUPPORTED => return Err("invalid method".to_string()),
+                m => Some(m)
+            }
+        };
+        Ok(AdminRoute {
+            bind: parts.get(3).cloned().unwrap_or(server_addr.to_string()),
+            host: parts.get(2).filter(|host| *host != "-").cloned(),
+            pattern: parts[1].clone(),
+            method
+        })
+    }
+
+    fn key(&self) -> RouteKey {
+        (self.bind.clone(), self.host.clone().unwrap_or("*".to_string()), self.pattern.clone(), self.method.map(|m| format!("{}", m)))
+    }
+
+    fn remove(&self, removed: &mut HashMap<RouteKey, RouteContext>) -> String {
+        match http_server_core::remove_route(&self.bind, self.host.clone(), &self.pattern, self.method) {
+            Ok(Some(route)) => {
+                removed.insert(self.key(), route);
+                "removed".to_string()
+            },
+            Ok(None) => "not found".to_string(),
+            Err(err) => err.what().to_string()
+        }
+    }
+}
+
+// Routes of the document '{ bind, virtual_host, routes }' are added to the running server or replace its routes,
+// the bind is the server of routes_admin by default
+fn add_routes(doc: &Yaml, server_addr: &str) -> Result<String, CoreError> {
+    let bind = match (doc["bind"].as_str(), server_addr) {
+        (Some(bind), _) => bind.to_string(),
+        (None, "") => return throw!("'bind' expected"),
+        (None, server_addr) => server_addr.to_string()
+    };
+    let host = doc["virtual_host"].as_str().map(|host| host.to_string());
+    if doc["routes"].as_vec().map(|routes| routes.is_empty()).unwrap_or(true) {
+        return throw!("'routes' expected");
+    }
+
+    let server = ServerContext {
+        bind: bind.clone(),
+        virtual_host: host.clone(),
+        ..ServerContext::default()
+    };
+    let context = CommandContext::new(server);
+    Config::parse_block::<HTTP>(&Context::SERVER, Rc::clone(&context), &Yaml::Hash(
+        vec![(Yaml::String("routes".to_string()), doc["routes"].clone())].into_iter().collect()
+    ))?;
+    let routes = context.borrow_mut().get_mut::<ServerContext>().unwrap().routes.take().unwrap_or_default();
+
+    unauthenticated(routes.iter())?;
+
+    let mut report = String::new();
+    for mut route in routes {
+        route.host = host.clone();
+        http_server_core::add_route(&bind, &route)?;
+        let method = match (route.method, route.methods.is_empty()) {
+            (Some(method), _) => method.to_string(),
+            (None, false) => route.methods.iter().map(|method| method.to_string()).collect::<Vec<String>>().join(","),
+            (None, true) => "*".to_string()
+        };
+        report.push_str(&format!("{} {} {} {} -> added\n", method, route.pattern, host.as_deref().unwrap_or("-"), bind));
+    }
+
+    let mut doc = doc.clone();
+    if let Yaml::Hash(h) = &mut doc {
+        h.insert(Yaml::String("bind".to_string()), Yaml::String(bind));
+    }
+    let mut added = ADDED_ROUTES.lock().unwrap();
+    added.retain(|added| *added != doc);
+    added.push(doc);
+
+    Ok(report)
+}
+
+// Routes changing the state of the server authenticate the clients or the server does it for them
+fn unauthenticated<'a>(mut routes: impl Iterator<Item = &'a RouteContext>) -> ActionResult {
+    match routes.find(|route| route.admin && !route.auth) {
+        Some(route) => throw!("route '{}' changes the server, it requires the authentication of the clients ('basic', 'fgac', 'secure_link')", route.pattern),
+        None => Ok(OK)
+    }
+}
+
+// Body of PUT of routes_admin
+fn added_routes(body: &str, server_addr: &str) -> Result<String, CoreError> {
+    match yaml::YamlLoader::load_from_str(body) {
+        Ok(docs) if docs.len() == 1 => add_routes(&docs[0], server_addr),
+        Ok(_) => throw!("one document '{{ bind, virtual_host, routes }}' expected"),
+        Err(err) => throw!("{}", err)
+    }
+}
+
+// 'METHOD PATTERN [HOST [BIND]]' of routes_admin, the modifier separated by a space
+// ('~ regex', '~* regex', '= /path', '^~ /path') is a part of the pattern
+fn admin_line(line: &str) -> Vec<String> {
+    let mut parts: Vec<String> = line.split_whitespace().map(|part| part.to_string()).collect();
+    if parts.len() > 2 && matches!(parts[1].as_str(), "~" | "~*" | "=" | "^~") {
+        let path = parts.remove(2);
+        parts[1] = format!("{} {}", parts[1], path);
+    }
+    parts
+}
+
+// Regex of the group is anchored under the prefix, the unanchored one matches anywhere after it
+fn prefixed(prefix: &str, pattern: &str) -> String {
+    if pattern.starts_with('@') || prefix.is_empty() {
+        return pattern.to_string();
+    }
+    for modifier in ["~*", "~"] {
+        if let Some(re) = pattern.strip_prefix(modifier) {
+            let re = re.trim_start();
+            return match re.strip_prefix('^') {
+                Some(re) => format!("{} ^{}(?:{})", modifier, regex::escape(prefix), re),
+                None => format!("{} ^{}.*(?:{})", modifier, regex::escape(prefix), re)
+            };
+        }
+    }
+    for modifier in ["=", "^~"] {
+        if let Some(path) = pattern.strip_prefix(modifier) {
+            return format!("{} {}{}", modifier, prefix, path.trim_start());
+        }
+    }
+    format!("{}{}", prefix, pattern)
+}
+
+// Settings of the group except 'prefix' and 'routes' are shared by the nested routes,
+// settings of the route override the shared ones, nested groups inherit the prefix and settings.
+fn route_group(group: &Yaml) -> Result<Vec<Yaml>, CoreError> {
+    let group = match group.as_hash() {
+        Some(group) => group,
+        None => return throw!("map expected")
+    };
+
+    let mut prefix = String::new();
+    let mut shared = yaml::Hash::new();
+    let mut routes = None;
+
+    for (k, v) in group {
+        match k.as_str() {
+            Some("prefix") => match v.as_str() {
+                Some(s) => prefix = s.to_string(),
+                None => return throw!("'prefix' must be a string")
+            },
+            Some("routes") => match v.as_vec() {
+                Some(v) => routes = Some(v),
+                None => return throw!("'routes' must be a list")
+            },
+            _ => {
+                shared.insert(k.clone(), v.clone());
+            }
+        }
+    }
+
+    let routes = match routes {
+        Some(routes) => routes,
+        None => return throw!("'routes' is not defined")
+    };
+
+    let mut flattened = Vec::new();
+
+    for item in routes {
+        let (kind, body) = match item.as_hash() {
+            Some(h) if h.len() == 1 => {
+                let (kind, body) = h.front().unwrap();
+                (kind.as_str().unwrap_or_default(), body)
+            },
+            _ => return throw!("'route' or 'route_group' expected")
+        };
+
+        let body = match body {
+            Yaml::Hash(h) => h.clone(),
+            Yaml::Null => yaml::Hash::new(),
+            _ => return throw!("'{}' must be a map", kind)
+        };
+
+        let mut merged = shared.clone();
+
+        match kind {
+            "route" => {
+                let pattern = body.get(&Yaml::from_str("match")).and_then(|v| v.as_str()).unwrap_or_default();
+                for (k, v) in body.iter() {
+                    merged.insert(k.clone(), v.clone());
+                }
+                merged.insert(Yaml::from_str("match"), Yaml::String(prefixed(&prefix, pattern)));
+            },
+            "route_group" => {
+                let nested = body.get(&Yaml::from_str("prefix")).and_then(|v| v.as_str()).unwrap_or_default();
+                for (k, v) in body.iter() {
+                    merged.insert(k.clone(), v.clone());
+                }
+                merged.insert(Yaml::from_str("prefix"), Yaml::String(format!("{}{}", prefix, nested)));
+            },
+            _ => return throw!("'route' or 'route_group' expected, found '{}'", kind)
+        }
+
+        let mut item = yaml::Hash::new();
+        item.insert(Yaml::from_str(kind), Yaml::Hash(merged));
+        flattened.push(Yaml::Hash(item));
+    }
+
+    Ok(flattened)
+}
+
+// Settings of the route inherited by its nested routes: headers, args, filters and error_log,
+// the rest (content handlers, access, proxy, ...) belongs to the parent route only
+const NESTED_INHERITED: [&str; 12] = [
+    "add_header", "add_headers", "clear_headers", "set_request_headers", "clear_request_headers", "security_headers",
+    "add_args", "clear_args",
+    "limit_rate", "limit_rate_after", "body_log",
+    "error_log"
+];
+
+// Routes nested into the route inherit NESTED_INHERITED of its settings,
+// their patterns are prefixed with the prefix of the parent, e.g. '/api/v1' of '/api/v1/*'.
+fn nested_routes(route: &Yaml) -> Result<Vec<Yaml>, CoreError> {
+    let item = |kind: &str, body: yaml::Hash| {
+        let mut item = yaml::Hash::new();
+        item.insert(Yaml::from_str(kind), Yaml::Hash(body));
+        Yaml::Hash(item)
+    };
+
+    let route = match route {
+        Yaml::Hash(route) => route,
+        _ => return throw!("map expected")
+    };
+
+    let routes_key = Yaml::from_str("routes");
+    let routes = match route.get(&routes_key) {
+        Some(routes) => routes,
+        None => return Ok(vec![item("route", route.clone())])
+    };
+
+    let pattern = route.get(&Yaml::from_str("match")).and_then(|v| v.as_str()).unwrap_or_default();
+    if pattern.starts_with(['~', '=', '@']) {
+        return throw!("nested routes require the prefix route, found '{}'", pattern);
+    }
+    let prefix = pattern.trim_start_matches("^~").trim_start().trim_end_matches('*').trim_end_matches('/');
+
+    let mut parent = route.clone();
+    parent.remove(&routes_key);
+
+    let mut group = yaml::Hash::new();
+    for (k, v) in route {
+        if k.as_str().map(|k| NESTED_INHERITED.contains(&k)).unwrap_or(false) {
+            group.insert(k.clone(), v.clone());
+        }
+    }
+    group.insert(Yaml::from_str("prefix"), Yaml::String(prefix.to_string()));
+    group.insert(routes_key, routes.clone());
+
+    Ok(vec![item("route", parent), item("route_group", group)])
+}
+
+// Queue depth of the workgroup is a sum over its event pools
+fn register_workgroup(name: &str, group: &[ServerType], thread_pool_size: usize, socket_pool_size: usize, events_batch: usize) {
+    let queues: Vec<Arc<AtomicUsize>> = group.iter().map(|server| server.borrow().queue()).collect();
+    let io_engine = group.first().map(|server| server.borrow().engine()).unwrap_or_default().name();
+    status::register("workgroups", name, Box::new(move || {
+        Some(Json::object(vec![
+            ("event_pool_size", queues.len().into()),
+            ("thread_pool_size", thread_pool_size.into()),
+            ("socket_pool_size", socket_pool_size.into()),
+            ("events_batch", events_batch.into()),
+            ("io_engine", io_engine.into()),
+            ("queue", queues.iter().map(|queue| queue.load(Ordering::Relaxed)).sum::<usize>().into())
+        ]))
+    }));
+}
+
+fn register_server(server: &ServerContext) {
+    let name = match &server.virtual_host {
+        Some(virtual_host) => format!("{} {}", server.bind, virtual_host),
+        None => server.bind.clone()
+    };
+    let routes: Vec<String> = server.routes.iter().flatten().map(|route| route.pattern.clone()).collect();
+    let (bind, workgroup, virtual_host) = (server.bind.clone(), server.workgroup.clone(), server.virtual_host.clone());
+    status::register("servers", &name, Box::new(move || {
+        Some(Json::object(vec![
+            ("bind", bind.as_str().into()),
+            ("workgroup", workgroup.as_str().into()),
+            ("virtual_host", virtual_host.clone().into()),
+            ("routes", Json::Array(routes.iter().map(|route| route.as_str().into()).collect()))
+        ]))
+    }));
+}
+
 impl HttpServer {
     pub fn new() -> HttpServer {
         HttpServer {
-            groups: Arc::new(Mutex::new(HashMap::new()))
+            groups: Arc::new(Mutex::new(HashMap::new())),
+            shutdown_timeout: Arc::new(Mutex::new(Duration::from_secs(10))),
+            removed_routes: Arc::new(Mutex::new(Vec::new())),
+            added_routes: Arc::new(Mutex::new(Vec::new()))
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn admin_lines() {
+        assert_eq!(admin_line("GET /x"), vec!["GET", "/x"]);
+        assert_eq!(admin_line("GET ~ ^/x"), vec!["GET", "~ ^/x"]);
+        assert_eq!(admin_line("GET ~* ^/x example.com"), vec!["GET", "~* ^/x", "example.com"]);
+        assert_eq!(admin_line("* = /x - 127.0.0.1:8080"), vec!["*", "= /x", "-", "127.0.0.1:8080"]);
+        assert_eq!(admin_line("GET ^~ /static"), vec!["GET", "^~ /static"]);
+        assert_eq!(admin_line("GET ~*"), vec!["GET", "~*"]);
+    }
+
+    #[test]
+    fn prefixed_patterns() {
+        assert_eq!(prefixed("/api", "/users"), "/api/users");
+        assert_eq!(prefixed("/api", "= /ping"), "= /api/ping");
+        assert_eq!(prefixed("/api", "^~ /static"), "^~ /api/static");
+        assert_eq!(prefixed("/api", "@named"), "@named");
+        assert_eq!(prefixed("/api", "~ ^/v[0-9]+/"), "~ ^/api(?:/v[0-9]+/)");
+        assert_eq!(prefixed("/api", "~* ^/a|/b"), "~* ^/api(?:/a|/b)");
+        assert_eq!(prefixed("/api", "~ \\.php$"), "~ ^/api.*(?:\\.php$)");
+        assert_eq!(prefixed("", "~ \\.php$"), "~ \\.php$");
+
+        let re = regex::Regex::new(&prefixed("/api.v1", "~ \\.php$")[2..]).unwrap();
+        assert!(re.is_match("/api.v1/x/index.php"));
+        assert!(!re.is_match("/other/index.php"));
+        assert!(!re.is_match("/apixv1/index.php"));
+    }
+
+    #[test]
+    fn admin_routes() {
+        let mut route = RouteContext { pattern: "/admin/fgac".to_string(), admin: true, ..RouteContext::default() };
+        assert!(unauthenticated([RouteContext::default(), route.clone()].iter()).is_err());
+        route.auth = true;
+        assert!(unauthenticated([RouteContext::default(), route].iter()).is_ok());
+    }
+}
@@ -0,0 +1,93 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(Snapshot);
+
+use std::sync::{ Arc, Mutex };
+use std::fs;
+
+use crate::plugin::*;
+use crate::http::*;
+use crate::config::Config;
+
+pub struct Snapshot {
+    filename: Arc<Mutex<Option<String>>>
+}
+
+impl Plugin for Snapshot {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "Snapshot"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        let filename_ = Arc::clone(&self.filename);
+
+        add_command!(Context::HTTP, "config_snapshot", move |_: &mut HttpContext, filename: String| {
+            *filename_.lock().unwrap() = Some(filename);
+            Ok(None)
+        })?;
+
+        let filename_ = Arc::clone(&self.filename);
+
+        add_command!(Context::ROUTE, "config_export", move |route: &mut RouteContext| {
+            let filename_ = Arc::clone(&filename_);
+            route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                let mut resp = HttpResponse::new(r);
+
+                let snapshot = match Config::snapshot() {
+                    Ok(snapshot) => snapshot,
+                    Err(err) => {
+                        log_http_error!(resp, "error", "config_export: {}", err.what());
+                        resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(b"failed to build snapshot"));
+                        return resp;
+                    }
+                };
+
+                match resp.get_request().method() {
+                    HttpMethod::POST | HttpMethod::PUT => {
+                        let filename = match &*filename_.lock().unwrap() {
+                            Some(filename) => filename.clone(),
+                            None => {
+                                resp.send(HttpStatus::CONFLICT, "text/plain", Some(b"config_snapshot is not configured"));
+                                return resp;
+                            }
+                        };
+                        // write and rename to never leave a partial snapshot
+                        let temp = format!("{}.tmp", filename);
+                        if let Err(err) = fs::write(&temp, snapshot.as_bytes()).and_then(|_| fs::rename(&temp, &filename)) {
+                            log_http_error!(resp, "error", "failed to save snapshot '{}', {}", filename, err);
+                            resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(b"failed to save snapshot"));
+                            return resp;
+                        }
+                        resp.send(HttpStatus::OK, "text/plain", Some(format!("saved to {}\n", filename).as_bytes()));
+                    },
+                    _ => resp.send(HttpStatus::OK, "application/x-yaml", Some(snapshot.as_bytes()))
+                }
+
+                resp
+            }));
+
+            Ok(None)
+        })?;
+
+        Ok(OK)
+    }
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot {
+    pub fn new() -> Snapshot {
+        Snapshot {
+            filename: Arc::new(Mutex::new(None))
+        }
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_http_plugin!(SplitClients);
+
+use std::mem::take;
+use std::sync::Arc;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::http::*;
+use crate::variable::*;
+use crate::error::{ Code, CoreError };
+
+// Percents are kept in hundredths, 10000 is 100%
+const TOTAL: u64 = 10000;
+
+#[derive(Default)]
+pub struct SplitContext {
+    source: Option<HttpComplexValue>,
+    variable: Option<String>,
+    buckets: HttpList
+}
+
+// Value of the variable for the clients with the hash below the bound
+struct Bucket {
+    bound: u64,
+    value: String
+}
+
+#[derive(Default)]
+pub struct SplitClients {}
+
+// MurmurHash2 of nginx, the same sources are split the same way
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1e995;
+    let mut h = data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let tail = chunks.remainder();
+    if tail.len() >= 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+// '12.5% value' or '* value', the rest of the clients go to '*'
+fn parse_bucket(bucket: &str) -> Result<(Option<u64>, String), CoreError> {
+    let bucket = bucket.trim();
+    let (percent, value) = match bucket.find(char::is_whitespace) {
+        Some(pos) => (&bucket[..pos], bucket[pos..].trim()),
+        None => (bucket, "")
+    };
+    if percent == "*" {
+        return Ok((None, value.to_string()));
+    }
+    let percent = match percent.strip_suffix('%').and_then(|percent| percent.parse::<f64>().ok()) {
+        Some(percent) if percent > 0.0 && percent <= 100.0 => percent,
+        _ => return throw!("split_clients: invalid percent in '{}'", bucket)
+    };
+    Ok((Some((percent * 100.0).round() as u64), value.to_string()))
+}
+
+fn buckets(list: &HttpList) -> Result<Vec<Bucket>, CoreError> {
+    let mut buckets = Vec::with_capacity(list.len());
+    let mut sum = 0;
+    for bucket in list.iter() {
+        let bucket = match bucket.text() {
+            Some(bucket) => bucket,
+            None => return throw!("split_clients: bucket is not a text")
+        };
+        match parse_bucket(&bucket)? {
+            (Some(percent), value) => {
+                sum += percent;
+                if sum > TOTAL {
+                    return throw!("split_clients: percents exceed 100%");
+                }
+                buckets.push(Bucket { bound: (sum << 32) / TOTAL, value });
+            },
+            (None, value) => {
+                buckets.push(Bucket { bound: 1 << 32, value });
+                break;
+            }
+        }
+    }
+    Ok(buckets)
+}
+
+fn bucket_of(buckets: &[Bucket], source: &str) -> String {
+    let hash = murmur2(source.as_bytes()) as u64;
+    buckets.iter().find(|bucket| hash < bucket.bound).map(|bucket| bucket.value.clone()).unwrap_or_default()
+}
+
+impl Plugin for SplitClients {
+    type ModuleType = HTTP;
+
+    fn name() -> &'static str {
+        "SplitClients"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::SERVER, "split_clients.split.source", |split: &mut SplitContext, source: HttpComplexValue| {
+            split.source = Some(source);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "split_clients.split.variable", |split: &mut SplitContext, variable: String| {
+            split.variable = Some(variable);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "split_clients.split.buckets", |split: &mut SplitContext, buckets: HttpList| {
+            split.buckets = buckets;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::SERVER, "split_clients.split", Schema::new()
+            .required("source", SchemaType::String)
+            .required("variable", SchemaType::String)
+            .required("buckets", SchemaType::List))?;
+
+        add_block!(Context::SERVER, "split_clients.split", |context| {
+            match context.get_mut::<SplitContext>() {
+                Some(split) => {
+                    // exit
+                    let split = take(split);
+
+                    let (source, variable) = match (split.source, split.variable) {
+                        (Some(source), Some(variable)) => (source, variable),
+                        _ => return throw!("split_clients: 'source' and 'variable' are required")
+                    };
+                    let buckets = Arc::new(buckets(&split.buckets)?);
+
+                    context.parent().unwrap()
+                           .get_mut::<ServerContext>().unwrap()
+                           .setvar.push_back(SetVarHandler::new(move |r| {
+                               let buckets = Arc::clone(&buckets);
+                               let source = source.clone();
+                               r.add_var(&variable, HttpComplexValue::lazy(LazyHandler::new(move |r: &HttpRequest| {
+                                   bucket_of(&buckets, &r.expand(&source))
+                               })));
+                               Code::DECLINED
+                           }));
+
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<SplitContext>()))
+            }
+        })?;
+
+        add_empty_block!(Context::SERVER, "split_clients")?;
+
+        Ok(OK)
+    }
+}
+
+impl SplitClients {
+    pub fn new() -> SplitClients {
+        SplitClients {}
+    }
+}
@@ -8,20 +8,24 @@ use std::sync::{ Arc, RwLock };
 use std::net::SocketAddr;
 use std::collections::{ HashMap, LinkedList };
 use std::time::Duration;
+use yaml_rust::Yaml;
 
 use crate::plugin::*;
 use crate::config::*;
 use crate::http::*;
 use crate::error::CoreError;
 use crate::upstream;
-use crate::connection_pool::Peer;
+use crate::core::status;
+use crate::connection_pool::{ Peer, TlsPin };
 
 #[derive(Clone)]
 pub struct ServerContext {
     keepalive: usize,
     max_active: usize,
     address: Option<SocketAddr>,
-    backup: bool
+    backup: bool,
+    // server drained at runtime stays drained with the snapshot
+    drain: bool
 }
 
 pub struct UpstreamContext {
@@ -30,6 +34,7 @@ pub struct UpstreamContext {
     max_active: usize,
     keepalive_timeout: Option<Duration>,
     keepalive_requests: Option<u64>,
+    keepalive_cross_route: bool,
     servers: LinkedList<ServerContext>,
     pub balancer: Box<dyn upstream::UpstreamBalance>
 }
@@ -40,7 +45,8 @@ impl Default for ServerContext {
             keepalive: 0,
             max_active: std::usize::MAX,
             address: None,
-            backup: false
+            backup: false,
+            drain: false
         }
     }
 }
@@ -53,6 +59,7 @@ impl Default for UpstreamContext {
             max_active: std::usize::MAX,
             keepalive_timeout: None,
             keepalive_requests: None,
+            keepalive_cross_route: true,
             servers: LinkedList::new(),
             balancer: Box::new(upstream::RoundRobin::new())
         }
@@ -91,6 +98,20 @@ impl Plugin for Upstream {
             Ok(None)
         })?;
 
+        add_command!(Context::UPSTREAM, "servers.server.drain", |server: &mut ServerContext, drain: bool| {
+            server.drain = drain;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::UPSTREAM, "servers.server", Schema::new()
+            .required("address", SchemaType::String)
+            .optional("max_active", SchemaType::Integer)
+            .range("max_active", 0, i64::MAX)
+            .optional("keepalive", SchemaType::Integer)
+            .range("keepalive", 0, i64::MAX)
+            .optional("backup", SchemaType::Bool)
+            .optional("drain", SchemaType::Bool))?;
+
         add_block!(Context::UPSTREAM, "servers.server", |context| {
             match context.get_mut::<ServerContext>() {
                 Some(server) => {
@@ -129,6 +150,11 @@ impl Plugin for Upstream {
             Ok(None)
         })?;
 
+        add_command!(Context::UPSTREAM, "keepalive_cross_route", |upstream: &mut UpstreamContext, keepalive_cross_route: bool| {
+            upstream.keepalive_cross_route = keepalive_cross_route;
+            Ok(None)
+        })?;
+
         add_command!(Context::UPSTREAM, "name", |upstream: &mut UpstreamContext, name: String| {
             upstream.name = name;
             Ok(None)
@@ -136,6 +162,17 @@ impl Plugin for Upstream {
 
         let upstreams_ = self.upstreams.clone();
 
+        add_schema!(Context::HTTP, "upstreams.upstream", Schema::new()
+            .required("name", SchemaType::String)
+            .optional("max_active", SchemaType::Integer)
+            .range("max_active", 0, i64::MAX)
+            .optional("keepalive", SchemaType::Integer)
+            .range("keepalive", 0, i64::MAX)
+            .optional("keepalive_timeout", SchemaType::Integer)
+            .optional("keepalive_requests", SchemaType::Integer)
+            .optional("keepalive_cross_route", SchemaType::Bool)
+            .optional("servers", SchemaType::List))?;
+
         add_block!(Context::HTTP, "upstreams.upstream", move |context| {
             match context.get_mut::<UpstreamContext>() {
                 Some(upstream) => {
@@ -148,6 +185,7 @@ impl Plugin for Upstream {
                                                         None,
                                                         upstream.keepalive_timeout,
                                                         upstream.keepalive_requests);
+                    u.set_cross_route(upstream.keepalive_cross_route);
                     for server in upstream.servers.iter() {
                         if let Some(address) = server.address {
                             if server.backup {
@@ -155,10 +193,18 @@ impl Plugin for Upstream {
                             } else {
                                 u.add_primary(address, server.keepalive, server.max_active);
                             }
+                            if server.drain {
+                                u.drain(&address, true)?;
+                            }
                         }
                     }
                     upstreams_.write().unwrap()
                               .insert(upstream.name.clone(), u);
+                    let upstreams = upstreams_.clone();
+                    let name = upstream.name.clone();
+                    status::register("upstreams", &upstream.name, Box::new(move || {
+                        upstreams.read().unwrap().get(&name).map(|u| u.report())
+                    }));
                     Ok(None)
                 },
                 None =>
@@ -171,10 +217,34 @@ impl Plugin for Upstream {
 
         let upstreams_ = self.upstreams.clone();
 
+        // drain state of the servers set by upstream_drain
+        Config::add_snapshot_provider(Box::new(move |doc| {
+            let upstreams = upstreams_.read().unwrap();
+            for block in blocks_mut(node_mut(doc, &["http", "upstreams"]), "upstream") {
+                let upstream = match block.get(&Yaml::String("name".to_string())).and_then(|name| name.as_str()) {
+                    Some(name) => match upstreams.get(name) {
+                        Some(upstream) => upstream,
+                        None => continue
+                    },
+                    None => continue
+                };
+                for server in blocks_mut(block.get_mut(&Yaml::String("servers".to_string())), "server") {
+                    let addr = server.get(&Yaml::String("address".to_string()))
+                                     .and_then(|address| address.as_str())
+                                     .and_then(|address| get_addr(address).ok());
+                    if let Some((draining, _, _, _)) = addr.and_then(|addr| upstream.drain_status(&addr)) {
+                        server.insert(Yaml::String("drain".to_string()), Yaml::Boolean(draining));
+                    }
+                }
+            }
+        }));
+
+        let upstreams_ = self.upstreams.clone();
+
         add_command!(Context::ROUTE, "upstream_status", move |route: &mut RouteContext| {
             let upstreams_ = upstreams_.clone();
-            route.content = Some(ContentHandler::new(move |mut r| -> HttpResponse {
-                match r.args_mut().exact("upstream") {
+            route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                match r.args().exact("upstream") {
                     Some(upstream) => match upstreams_.read().unwrap().get(upstream) {
                         Some(upstream) => {
                             let mut resp = HttpResponse::new(r);
@@ -199,10 +269,57 @@ impl Plugin for Upstream {
             Ok(None)
         })?;
 
+        let upstreams_ = self.upstreams.clone();
+
+        // ?upstream=name&server=addr[&drain=on|off], without 'drain' the drain status is returned
+
+        add_command!(Context::ROUTE, "upstream_drain", move |route: &mut RouteContext| {
+            let upstreams_ = upstreams_.clone();
+            route.content = Some(ContentHandler::new(move |r| -> HttpResponse {
+                let (status, text) = upstream_drain(&upstreams_.read().unwrap(), r.args());
+                let mut resp = HttpResponse::new(r);
+                resp.send(status, "text/plain", Some(text.as_bytes()));
+                resp
+            }));
+            Ok(None)
+        })?;
+
         Ok(OK)
     }
 }
 
+fn upstream_drain(upstreams: &HashMap<String, upstream::Upstream>, args: &HttpQuery) -> (HttpStatus, String) {
+    let upstream = match args.exact("upstream") {
+        Some(name) => match upstreams.get(name) {
+            Some(upstream) => upstream,
+            None => return (HttpStatus::NOT_FOUND, "upstream not found".to_string())
+        },
+        None => return (HttpStatus::BAD_REQUEST, "upstream parameter required".to_string())
+    };
+    let addr: SocketAddr = match args.exact("server").map(|server| server.parse()) {
+        Some(Ok(addr)) => addr,
+        Some(Err(_)) => return (HttpStatus::BAD_REQUEST, "invalid server address".to_string()),
+        None => return (HttpStatus::BAD_REQUEST, "server parameter required".to_string())
+    };
+    let draining = match args.exact("drain").map(|s| s.as_str()) {
+        Some("on") => Some(true),
+        Some("off") => Some(false),
+        Some(_) => return (HttpStatus::BAD_REQUEST, "drain must be 'on' or 'off'".to_string()),
+        None => None
+    };
+    if let Some(draining) = draining {
+        if let Err(err) = upstream.drain(&addr, draining) {
+            return (HttpStatus::NOT_FOUND, err.what().to_string());
+        }
+    }
+    match upstream.drain_status(&addr) {
+        Some((draining, active, idle, drained)) =>
+            (HttpStatus::OK, format!("draining: {}\nactive: {}\nidle: {}\ncomplete: {}\n", draining, active, idle, drained)),
+        None =>
+            (HttpStatus::NOT_FOUND, "server not found".to_string())
+    }
+}
+
 impl Upstream {
     pub fn new() -> Upstream {
         Upstream {
@@ -210,9 +327,9 @@ impl Upstream {
         }
     }
 
-    pub fn connect(&self, name: &str, timeout: Option<Duration>) -> Result<Peer, CoreError> {
+    pub fn connect(&self, name: &str, timeout: Option<Duration>, route: Option<&str>, tls: Option<&TlsPin>) -> Result<Peer, CoreError> {
         if let Some(upstream) = self.upstreams.read().unwrap().get(name) {
-            return upstream.connect(timeout);
+            return upstream.connect(timeout, route, tls);
         }
         throw!("Upstream '{}' not found", name)
     }
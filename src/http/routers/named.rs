@@ -3,7 +3,7 @@
  */
 
 use std::sync::RwLock;
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 
 use crate::error::{ Code::*, CoreError, CoreResult };
 use crate::http::routers::result::*;
@@ -103,6 +103,11 @@ impl<Context: Default> NamedRouter<Context> {
     }
 
     pub fn remove(&mut self, name: &str, method: Option<String>) -> bool {
+        self.take(name, method).is_some()
+    }
+
+    // Removes the route and returns its context
+    pub fn take(&mut self, name: &str, method: Option<String>) -> Option<Context> {
         let _guard = self.lock.write();
         let routes = &mut self.routes;
 
@@ -111,39 +116,53 @@ impl<Context: Default> NamedRouter<Context> {
         for i in 0..routes.len() {
             let route = &mut routes[i];
             if route.name == name {
-                route.context.remove(&method);
+                let context = route.context.remove(&method);
                 if route.context.is_empty() {
                     routes.remove(i);
                 }
-                return true
+                return context
             }
         }
 
-        false
+        None
+    }
+
+    // Route of the request, the select hook may skip the matched route
+    pub fn get(&self, r: &HttpRequest, select: &dyn for<'c> Fn(&'c Context, &HttpRequest) -> Option<&'c Context>) -> Option<NamedResult<'_, Context>> {
+        self.find_with(r.uri(), &format!("{}", r.method()), &|context| select(context, r))
+    }
+
+    pub fn find(&self, name: &str, method: &str) -> Option<NamedResult<'_, Context>> {
+        self.find_with(name, method, &|context| Some(context))
     }
 
-    pub fn get(&self, r: &HttpRequest) -> Option<NamedResult<'_, Context>> {
+    pub fn find_with(
+        &self,
+        name: &str,
+        method: &str,
+        select: &dyn for<'c> Fn(&'c Context) -> Option<&'c Context>
+    ) -> Option<NamedResult<'_, Context>> {
         let guard = self.lock.read().unwrap();
         let routes = &self.routes;
 
-        let name = r.uri();
-        let method = format!("{}", r.method());
-
         for p in routes.iter() {
             if p.matched(name) {
-                return match p.context.get(&method) {
-                    Some(context) => Some(NamedResult::new(guard, context)),
-                    None => match p.context.get("*") {
-                        Some(context) => Some(NamedResult::new(guard, context)),
-                        None => None
-                    }
-                }
+                return [method, "*"].iter()
+                    .filter_map(|method| p.context.get(*method))
+                    .find_map(select)
+                    .map(|context| NamedResult::new(guard, context));
             }
         }
 
         None
     }
 
+    // Methods of all routes, '*' is any method
+    pub fn methods(&self) -> HashSet<String> {
+        let _guard = self.lock.read().unwrap();
+        self.routes.iter().flat_map(|route| route.context.keys().cloned()).collect()
+    }
+
     pub fn upsert<F>(&mut self, name: &str, method: Option<String>, f: F) -> CoreResult
     where
         F: Fn(&mut Context, bool)
@@ -4,7 +4,7 @@
 
 use regex::Regex;
 use std::sync::RwLock;
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 
 use crate::http::HttpRequest;
 use crate::error::{ Code::*, CoreError, CoreResult };
@@ -13,6 +13,8 @@ use crate::variable::Variable;
 
 type RegexResult<'a, Context> = RouteResult<'a, Context>;
 type RegexResultMut<'a, Context> = RouteResultMut<'a, Context>;
+// Route and the values of the named captures
+type RegexMatch<'a, 'p, Context> = (RegexResult<'a, Context>, Vec<(&'a str, &'p str)>);
 
 struct RegexRoute<Context: Default> {
     pattern: String,
@@ -136,6 +138,11 @@ impl<Context: Default> RegexRouter<Context> {
     }
 
     pub fn remove(&mut self, pattern: &str, method: Option<String>) -> bool {
+        self.take(pattern, method).is_some()
+    }
+
+    // Removes the route and returns its context
+    pub fn take(&mut self, pattern: &str, method: Option<String>) -> Option<Context> {
         let _guard = self.lock.write();
         let routes = &mut self.routes;
 
@@ -144,39 +151,59 @@ impl<Context: Default> RegexRouter<Context> {
         for i in 0..routes.len() {
             let route = &mut routes[i];
             if route.pattern == pattern {
-                route.context.remove(&method);
+                let context = route.context.remove(&method);
                 if route.context.is_empty() {
                     routes.remove(i);
                 }
-                return true
+                return context
             }
         }
 
-        false
+        None
     }
 
-    pub fn get(&self, r: &mut HttpRequest) -> Option<RegexResult<'_, Context>> {
-        let guard = self.lock.read().unwrap();
-        let routes = &self.routes;
-
+    // Route of the request, the select hook may skip the matched route in favor of the next candidate
+    pub fn get(&self, r: &mut HttpRequest, select: &dyn for<'c> Fn(&'c Context, &HttpRequest) -> Option<&'c Context>) -> Option<RegexResult<'_, Context>> {
         let path = r.uri().clone();
         let method = format!("{}", r.method());
 
+        let found = {
+            let r: &HttpRequest = r;
+            self.find_with(&path, &method, &|context| select(context, r))
+        };
+        match found {
+            Some((result, vars)) => {
+                vars.iter().for_each(|(name, val)| r.vars_mut().set(name, Variable::simple(val)));
+                Some(result)
+            },
+            None => None
+        }
+    }
+
+    // Route with the values of the named captures
+    pub fn find<'a>(&self, path: &'a str, method: &str) -> Option<RegexMatch<'_, 'a, Context>> {
+        self.find_with(path, method, &|context| Some(context))
+    }
+
+    pub fn find_with<'a>(
+        &self,
+        path: &'a str,
+        method: &str,
+        select: &dyn for<'c> Fn(&'c Context) -> Option<&'c Context>
+    ) -> Option<RegexMatch<'_, 'a, Context>> {
+        let guard = self.lock.read().unwrap();
+        let routes = &self.routes;
+
         for p in routes.iter() {
-            let (matched, vars) = p.matches(&path);
+            let (matched, vars) = p.matches(path);
             if matched {
-                return match p.context.get(&method) {
-                    Some(context) => {
-                        vars.iter().for_each(|(name, val)| r.vars_mut().set(name, Variable::simple(val)));
-                        Some(RegexResult::new(guard, context))
-                    },
-                    None => match p.context.get("*") {
-                        Some(context) => {
-                            vars.iter().for_each(|(name, val)| r.vars_mut().set(name, Variable::simple(val)));
-                            Some(RegexResult::new(guard, context))
-                        },
-                        None => None
-                    }
+                let contexts: Vec<&Context> = [method, "*"].iter().filter_map(|method| p.context.get(*method)).collect();
+                if contexts.is_empty() {
+                    return None;
+                }
+                // skipped routes fall through to the next pattern
+                if let Some(context) = contexts.into_iter().find_map(select) {
+                    return Some((RegexResult::new(guard, context), vars));
                 }
             }
         }
@@ -184,6 +211,12 @@ impl<Context: Default> RegexRouter<Context> {
         None
     }
 
+    // Methods of all routes, '*' is any method
+    pub fn methods(&self) -> HashSet<String> {
+        let _guard = self.lock.read().unwrap();
+        self.routes.iter().flat_map(|route| route.context.keys().cloned()).collect()
+    }
+
     pub fn upsert<F>(&mut self, path: &str, method: Option<String>, f: F) -> CoreResult
     where
         F: Fn(&mut Context, bool)
@@ -2,7 +2,7 @@
  * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
  */
 
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use std::sync::RwLock;
 
 use crate::error::{ Code::*, CoreError, CoreResult };
@@ -12,11 +12,41 @@ use crate::variable::Variable;
 
 type TrieResult<'a, Context> = RouteResult<'a, Context>;
 type TrieResultMut<'a, Context> = RouteResultMut<'a, Context>;
+// Route, exact match flag and the values of the uri variables
+type TrieMatch<'a, Context> = (TrieResult<'a, Context>, bool, Vec<(String, String)>);
+
+// Matching options of the route
+#[derive(Default, Clone, Copy)]
+pub struct TrieOptions {
+    // segments of the path are compared ignoring the case
+    pub ignore_case: bool,
+    // '/path/' is the same as '/path'
+    pub ignore_trailing_slash: bool
+}
+
+impl TrieOptions {
+    // Path of the route as it is stored in the trie
+    fn normalize(&self, path: &str) -> String {
+        let path = match self.ignore_trailing_slash && path.len() > 1 {
+            true => path.trim_end_matches('/'),
+            false => path
+        };
+        match self.ignore_case {
+            true => path.split("/").map(|word| match word.starts_with("{") {
+                // names of the variables are kept
+                true => word.to_string(),
+                false => word.to_ascii_lowercase()
+            }).collect::<Vec<String>>().join("/"),
+            false => path.to_string()
+        }
+    }
+}
 
 #[derive(Default, Clone)]
 struct Data<Context> {
     context: Context,
-    uri_parts: Vec<Option<String>>
+    uri_parts: Vec<Option<String>>,
+    options: TrieOptions
 }
 
 struct TrieNode<Context: Default> {
@@ -40,6 +70,11 @@ impl<Context: Default> TrieNode<Context> {
     pub fn is_empty(&self) -> bool {
         self.words.is_empty()
     }
+
+    fn methods(&self, methods: &mut HashSet<String>) {
+        methods.extend(self.context.keys().cloned());
+        self.words.values().for_each(|node| node.methods(methods));
+    }
 }
 
 impl<Context: Default> Default for TrieNode<Context> {
@@ -74,7 +109,7 @@ impl<Context: Default> TrieRouter<Context> {
         method: Option<String>,
         context: Context
     ) -> Result<(TrieResultMut<'_, Context>, bool), CoreError> {
-        self.insert(path, method, context, false)
+        self.insert(path, method, context, false, TrieOptions::default())
     }
 
     pub fn replace(
@@ -83,7 +118,7 @@ impl<Context: Default> TrieRouter<Context> {
         method: Option<String>,
         context: Context
     ) -> Result<(TrieResultMut<'_, Context>, bool), CoreError> {
-        self.insert(path, method, context, true)
+        self.insert(path, method, context, true, TrieOptions::default())
     }
 
     fn insert(
@@ -91,12 +126,14 @@ impl<Context: Default> TrieRouter<Context> {
         path: &str,
         method: Option<String>,
         context: Context,
-        replace: bool
+        replace: bool,
+        options: TrieOptions
     ) -> Result<(TrieResultMut<'_, Context>, bool), CoreError> {
         let guard = self.lock.write().unwrap();
         let mut node = &mut self.root;
         let method = method.unwrap_or(String::from("*"));
         let mut uri_parts = vec![];
+        let path = options.normalize(path);
 
         for word in path.split("/") {
             let var = word.trim_start_matches("{").trim_end_matches("}");
@@ -112,36 +149,94 @@ impl<Context: Default> TrieRouter<Context> {
         if node.context.contains_key(&method) {
             return match replace {
                 true => {
-                    node.context.insert(method.clone(), Data { context, uri_parts });
+                    node.context.insert(method.clone(), Data { context, uri_parts, options });
                     Ok((TrieResultMut::new(guard, &mut node.context.get_mut(&method).unwrap().context), false))
                 },
                 false =>  {
-                    Ok((TrieResultMut::new(guard, &mut node.context.get_mut(&method).unwrap().context), true))
+                    Ok((TrieResultMut::new(guard, &mut node.context.get_mut(&method).unwrap().context), false))
                 }
             }
         }
 
-        node.context.insert(method.clone(), Data { context, uri_parts });
+        node.context.insert(method.clone(), Data { context, uri_parts, options });
         Ok((TrieResultMut::new(guard, &mut node.context.get_mut(&method).unwrap().context), true))
     }
 
     pub fn remove(&mut self, path: &str, method: Option<String>) -> bool {
+        self.take(path, method).is_some()
+    }
+
+    // Removes the route and returns its context, the path is stored normalized for the routes with options
+    pub fn take(&mut self, path: &str, method: Option<String>) -> Option<Context> {
+        [(false, false), (true, false), (false, true), (true, true)].iter().find_map(|&(ignore_case, ignore_trailing_slash)| {
+            let path = TrieOptions { ignore_case, ignore_trailing_slash }.normalize(path);
+            self.take_path(&path, method.clone())
+        })
+    }
+
+    fn take_path(&mut self, path: &str, method: Option<String>) -> Option<Context> {
         let _guard = self.lock.write().unwrap();
         let mut node = &mut self.root;
 
         for word in path.split("/") {
+            let word = match word.starts_with("{") && word.ends_with("}") {
+                true => "*",
+                false => word
+            };
             match node.words.get_mut(word) {
                 Some(n) => {
                     node = n;
                 },
-                None => return false
+                None => return None
             }
         }
 
-        node.context.remove(&method.unwrap_or(String::from("*"))).is_some()
+        node.context.remove(&method.unwrap_or(String::from("*"))).map(|data| data.context)
     }
 
-    pub fn get(&self, r: &mut HttpRequest) -> Option<(TrieResult<'_, Context>, bool)> {
+    // Route of the request, the select hook may skip the matched route in favor of the next candidate
+    pub fn get(&self, r: &mut HttpRequest, select: &dyn for<'c> Fn(&'c Context, &HttpRequest) -> Option<&'c Context>) -> Option<(TrieResult<'_, Context>, bool)> {
+        self.lookup(r, select, false)
+    }
+
+    // Route of the whole uri only, prefixes are not matched
+    pub fn get_exact(&self, r: &mut HttpRequest, select: &dyn for<'c> Fn(&'c Context, &HttpRequest) -> Option<&'c Context>) -> Option<TrieResult<'_, Context>> {
+        self.lookup(r, select, true).map(|(result, _)| result)
+    }
+
+    fn lookup(
+        &self,
+        r: &mut HttpRequest,
+        select: &dyn for<'c> Fn(&'c Context, &HttpRequest) -> Option<&'c Context>,
+        exact_only: bool
+    ) -> Option<(TrieResult<'_, Context>, bool)> {
+        let method = format!("{}", r.method());
+        let uri = r.uri().to_string();
+        let found = {
+            let r: &HttpRequest = r;
+            self.find_with(&uri, &method, &|context| select(context, r))
+        };
+        match found {
+            Some((result, exact, vars)) if exact || !exact_only => {
+                let r_vars = r.vars_mut();
+                vars.into_iter().for_each(|(name, val)| r_vars.set(&name, Variable::simple(&val)));
+                Some((result, exact))
+            },
+            _ => None
+        }
+    }
+
+    // Route with the values of the uri variables, the flag is true for an exact match
+    pub fn find(&self, uri: &str, method: &str) -> Option<TrieMatch<'_, Context>> {
+        self.find_with(uri, method, &|context| Some(context))
+    }
+
+    pub fn find_with(
+        &self,
+        uri: &str,
+        method: &str,
+        select: &dyn for<'c> Fn(&'c Context) -> Option<&'c Context>
+    ) -> Option<TrieMatch<'_, Context>> {
         let guard = self.lock.read().unwrap();
         let root = &self.root;
 
@@ -151,68 +246,85 @@ impl<Context: Default> TrieRouter<Context> {
 
         struct Traverser<'a, 'b, Context: Default> {
             parts: Vec<&'a str>,
-            method: &'a String,
-            star: Option<&'b Data<Context>>
+            method: &'a str,
+            select: &'a dyn for<'c> Fn(&'c Context) -> Option<&'c Context>,
+            star: Option<(&'b Data<Context>, &'b Context)>
         }
 
         impl<'a, 'b, Context: Default> Traverser<'a, 'b, Context> {
-            fn new(path: &'a str, method: &'a String) -> Traverser<'a, 'b, Context> {
+            fn new(
+                path: &'a str,
+                method: &'a str,
+                select: &'a dyn for<'c> Fn(&'c Context) -> Option<&'c Context>
+            ) -> Traverser<'a, 'b, Context> {
                 Traverser {
                     parts: path.split("/").collect(),
                     method: method,
+                    select,
                     star: None
                 }
             }
 
+            // Route of the method or of any method, unless skipped by the select hook,
+            // the path with the case folded is matched by the routes ignoring the case only
+            fn candidate(&self, node: &'b TrieNode<Context>, folded: bool, slash: bool) -> Option<(&'b Data<Context>, &'b Context)> {
+                [self.method, "*"].iter()
+                    .filter_map(|method| node.context.get(*method))
+                    .filter(|data| (!folded || data.options.ignore_case) && (!slash || data.options.ignore_trailing_slash))
+                    .find_map(|data| (self.select)(&data.context).map(|context| (data, context)))
+            }
+
             fn traverse(
                 &mut self,
                 i: usize,
                 node: &'b TrieNode<Context>,
-                data: Option<&'b Data<Context>>
-            ) -> Option<(&'b Data<Context>, bool)> {
+                data: Option<(&'b Data<Context>, &'b Context)>,
+                folded: bool
+            ) -> Option<(&'b Data<Context>, &'b Context, bool)> {
                 if let Some(data) = data {
                     self.star = Some(data)
                 }
 
                 if i == self.parts.len() {
                     // leaf
-                    return match node.context.get(self.method) {
-                        Some(h) => Some((h, true)),
-                        None => match node.context.get("*") {
-                            Some(h) => Some((h, true)),
-                            None => None
-                        }
-                    }
+                    return self.candidate(node, folded, false).map(|(data, context)| (data, context, true));
                 }
 
-                let lp = node.words.get(self.parts[i]);
+                let word = self.parts[i];
+                let lp = node.words.get(word);
                 let la = node.words.get("*");
-
-                if la.is_none() && lp.is_none() {
-                    return match node.context.get(self.method) {
-                        Some(h) => Some((h, false)),
-                        None => match node.context.get("*") {
-                            Some(h) => Some((h, false)),
-                            None => None
-                        }
+                let lf = match word.bytes().any(|b| b.is_ascii_uppercase()) {
+                    true => node.words.get(&word.to_ascii_lowercase()),
+                    false => None
+                };
+
+                if lp.is_none() && i + 1 == self.parts.len() && word.is_empty() {
+                    // '/path/' of the route ignoring the trailing slash
+                    if let Some((data, context)) = self.candidate(node, folded, true) {
+                        return Some((data, context, true));
                     }
                 }
 
+                if la.is_none() && lp.is_none() && lf.is_none() {
+                    return self.candidate(node, folded, false).map(|(data, context)| (data, context, false));
+                }
+
                 let mut f = None;
 
                 if let Some(lp) = lp {
-                    f = self.traverse(i + 1, lp, None);
+                    f = self.traverse(i + 1, lp, None, folded);
+                }
+
+                if f.is_none() {
+                    if let Some(lf) = lf {
+                        f = self.traverse(i + 1, lf, None, true);
+                    }
                 }
 
                 if f.is_none() {
                     if let Some(la) = la {
-                        f = self.traverse(i + 1, la, match node.context.get(self.method) {
-                            Some(h) => Some(h),
-                            None => match node.context.get("*") {
-                                Some(h) => Some(h),
-                                None => None
-                            }
-                        });
+                        let data = self.candidate(node, folded, false);
+                        f = self.traverse(i + 1, la, data, folded);
                     }
                 }
 
@@ -220,37 +332,45 @@ impl<Context: Default> TrieRouter<Context> {
                     Some(f) => Some(f),
                     None => match self.star {
                         None => None,
-                        Some(data) => Some((data, false))
+                        Some((data, context)) => Some((data, context, false))
                     }
                 }
             }
         }
 
-        let method = format!("{}", r.method());
-        let uri = r.uri().to_string();
-        let mut traverser = Traverser::new(&uri, &method);
-
-        match traverser.traverse(0, &root, None) {
-            Some((data, exact)) => {
-                let vars = r.vars_mut();
-                let mut index = 0;
-                data.uri_parts.iter().for_each(|var| {
-                    if let Some(var) = var {
-                        vars.set(&var, Variable::simple(traverser.parts[index]))
-                    }
-                    index += 1;
-                });
-                Some((TrieResult::new(guard, &data.context), exact))
+        let mut traverser = Traverser::new(uri, method, select);
+
+        match traverser.traverse(0, root, None, false) {
+            Some((data, context, exact)) => {
+                let vars = data.uri_parts.iter().enumerate().filter_map(|(index, var)| {
+                    var.as_ref().map(|var| (var.clone(), traverser.parts[index].to_string()))
+                }).collect();
+                Some((TrieResult::new(guard, context), exact, vars))
             },
             None => None
         }
     }
 
+    // Methods of all routes, '*' is any method
+    pub fn methods(&self) -> HashSet<String> {
+        let _guard = self.lock.read().unwrap();
+        let mut methods = HashSet::new();
+        self.root.methods(&mut methods);
+        methods
+    }
+
     pub fn upsert<F>(&mut self, path: &str, method: Option<String>, f: F) -> CoreResult
     where
         F: Fn(&mut Context, bool)
     {
-        return match self.add(path, method, Context::default()) {
+        self.upsert_with(path, method, TrieOptions::default(), f)
+    }
+
+    pub fn upsert_with<F>(&mut self, path: &str, method: Option<String>, options: TrieOptions, f: F) -> CoreResult
+    where
+        F: Fn(&mut Context, bool)
+    {
+        return match self.insert(path, method, Context::default(), false, options) {
             Err(err) => Err(err),
             Ok((route, added)) => {
                 (f)(route.context, added);
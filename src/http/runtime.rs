@@ -0,0 +1,87 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{ Arc, RwLock };
+
+use tokio::runtime::{ Builder, Runtime };
+
+use crate::error::CoreError;
+use crate::module::Response;
+use crate::http::*;
+
+lazy_static! {
+    static ref RUNTIMES: RwLock<HashMap<String, Arc<Runtime>>> = RwLock::new(HashMap::new());
+    static ref SERVERS: RwLock<HashMap<SocketAddr, Arc<Runtime>>> = RwLock::new(HashMap::new());
+}
+
+// Zero threads leaves the workgroup without the runtime
+pub fn create(workgroup: &str, threads: usize) -> Result<(), CoreError> {
+    if threads == 0 {
+        RUNTIMES.write().unwrap().remove(workgroup);
+        return Ok(());
+    }
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(threads)
+        .thread_name(format!("ws: async {}", workgroup))
+        .enable_time()
+        .build()
+        .or_else(|err| throw!("Failed to create async runtime of workgroup '{}': {}", workgroup, err))?;
+    RUNTIMES.write().unwrap().insert(workgroup.to_string(), Arc::new(runtime));
+    Ok(())
+}
+
+// Async content handlers of the server run on the runtime of its workgroup
+pub fn attach(addr: SocketAddr, workgroup: &str) {
+    match RUNTIMES.read().unwrap().get(workgroup) {
+        Some(runtime) => SERVERS.write().unwrap().insert(addr, Arc::clone(runtime)),
+        None => SERVERS.write().unwrap().remove(&addr)
+    };
+}
+
+pub fn get(addr: &SocketAddr) -> Option<Arc<Runtime>> {
+    SERVERS.read().unwrap().get(addr).cloned()
+}
+
+// Content handler made of the async fn, which takes the response and gives it back completed.
+// The future is spawned when the worker returns, the response goes to the IO thread when it resolves.
+// Future not resolved in response_timeout is dropped with the connection.
+pub fn content<F, Fut>(f: F) -> ContentHandler
+where
+    F: Fn(HttpResponse) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = HttpResponse> + Send + 'static
+{
+    let f = Arc::new(f);
+    ContentHandler::new(move |r: HttpRequest| -> HttpResponse {
+        let mut resp = HttpResponse::new(r);
+        let runtime = match get(&resp.context().server_addr) {
+            Some(runtime) => runtime,
+            None => {
+                log_error!("error", "Async content handler requires 'async_threads' in the workgroup, uri={}", resp.get_request().uri());
+                resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(b"Internal server error"));
+                return resp;
+            }
+        };
+        let f = Arc::clone(&f);
+        resp.defer(Box::new(move |mut resp, completion| {
+            let timeout = resp.context().response_timeout();
+            let uri = resp.get_request().uri().clone();
+            let fut = f(resp);
+            // runtime itself is released here, it can't be dropped on its own threads
+            runtime.spawn(async move {
+                let resp = match timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, fut).await {
+                        Ok(resp) => resp,
+                        Err(_) => return log_error!("warn", "Async content handler has timed out, uri={}", uri)
+                    },
+                    None => fut.await
+                };
+                completion.complete(resp);
+            });
+        }));
+        resp
+    })
+}
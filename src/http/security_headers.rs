@@ -0,0 +1,64 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::sync::Arc;
+
+use crate::http::{ HttpResponse, HeaderFilterHandler };
+
+// Header value 'off' drops the header of the server or the default
+const OFF: &str = "off";
+
+// Response headers of the 'security_headers' block, the values not set by the route are
+// taken from the server, the values not set by both of them are the defaults
+#[derive(Clone, Default, Debug)]
+pub struct SecurityHeaders {
+    // 'security_headers: off' of the route disables the headers of the server
+    pub enabled: Option<bool>,
+    pub hsts: Option<String>,
+    pub frame_options: Option<String>,
+    pub content_type_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    // not sent by default, the policy depends on the content
+    pub content_security_policy: Option<String>,
+    pub content_security_policy_report_only: Option<String>
+}
+
+type Field = fn(&SecurityHeaders) -> &Option<String>;
+
+const HEADERS: [(&str, Field, Option<&str>); 6] = [
+    ("Strict-Transport-Security", |h| &h.hsts, Some("max-age=31536000; includeSubDomains")),
+    ("X-Frame-Options", |h| &h.frame_options, Some("SAMEORIGIN")),
+    ("X-Content-Type-Options", |h| &h.content_type_options, Some("nosniff")),
+    ("Referrer-Policy", |h| &h.referrer_policy, Some("strict-origin-when-cross-origin")),
+    ("Content-Security-Policy", |h| &h.content_security_policy, None),
+    ("Content-Security-Policy-Report-Only", |h| &h.content_security_policy_report_only, None)
+];
+
+// Headers already set by the content handler or the upstream are kept
+fn apply(resp: &mut HttpResponse, route: Option<&SecurityHeaders>, server: Option<&SecurityHeaders>) {
+    for (name, field, default) in HEADERS.iter() {
+        let value = route.and_then(|route| field(route).as_deref())
+            .or_else(|| server.and_then(|server| field(server).as_deref()))
+            .or(*default);
+        match value {
+            Some(value) if value != OFF && resp.header(name).is_none() => resp.set_header(name, value),
+            _ => {}
+        }
+    }
+}
+
+// Header filter of the route, None if the headers are not enabled for it
+pub fn filter(route: Option<&Arc<SecurityHeaders>>, server: Option<&Arc<SecurityHeaders>>) -> Option<HeaderFilterHandler> {
+    let enabled = route.and_then(|route| route.enabled)
+        .or_else(|| server.and_then(|server| server.enabled))
+        .unwrap_or(false);
+    if !enabled {
+        return None;
+    }
+    let route = route.cloned();
+    let server = server.cloned();
+    Some(HeaderFilterHandler::new(move |resp| {
+        apply(resp, route.as_deref(), server.as_deref());
+    }))
+}
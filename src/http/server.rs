@@ -3,9 +3,10 @@
  */
 
 use std::net::SocketAddr;
+use std::sync::{ Arc, atomic::AtomicUsize };
 use std::time::Duration;
 
-use crate::core::Options;
+use crate::core::{ Options, affinity::Affinity, poller::Engine, accept::Accept };
 use crate::core::server::Server;
 use crate::module::*;
 use crate::http::*;
@@ -25,6 +26,9 @@ impl HttpServer {
     pub fn new(
         worker_pool_size: usize,
         socket_poll_size: usize,
+        affinity: Affinity,
+        engine: Engine,
+        accept: Accept,
         default_handler: ContentHandler
     )
         -> Result<HttpServer, CoreError>
@@ -32,13 +36,14 @@ impl HttpServer {
         match Server::<HttpServer>::new(
             worker_pool_size,
             socket_poll_size,
+            affinity,
+            engine,
+            accept,
             ContentHandler::new(move |request| -> HttpResponse {
                 if !request.is_mailformed() {
                     return default_handler.handle(request);
                 };
-                let mut bad_request = HttpResponse::new(request);
-                bad_request.send(HttpStatus::BAD_REQUEST, "text/plain", Some(b"Bad request"));
-                bad_request
+                HttpServer::reject(request)
             })
         ) {
             Ok(server) => {
@@ -50,19 +55,50 @@ impl HttpServer {
         }
     }
 
+    fn reject(request: HttpRequest) -> HttpResponse {
+        let mut resp = HttpResponse::new(request);
+        match resp.get_request().reject_status() {
+            Some(HttpStatus::PAYLOAD_TOO_LARGE) =>
+                resp.send(HttpStatus::PAYLOAD_TOO_LARGE, "text/plain", Some(b"Payload too large")),
+            Some(HttpStatus::URI_TOO_LONG) =>
+                resp.send(HttpStatus::URI_TOO_LONG, "text/plain", Some(b"URI too long")),
+            Some(HttpStatus::SERVICE_UNAVAILABLE) =>
+                resp.send(HttpStatus::SERVICE_UNAVAILABLE, "text/plain", Some(b"Service unavailable")),
+            Some(HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE) =>
+                resp.send(HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE, "text/plain", Some(b"Request header fields too large")),
+            Some(HttpStatus::INTERNAL_SERVER_ERROR) =>
+                resp.send(HttpStatus::INTERNAL_SERVER_ERROR, "text/plain", Some(b"Internal server error")),
+            Some(HttpStatus::METHOD_NOT_IMPLEMENTED) =>
+                resp.send(HttpStatus::METHOD_NOT_IMPLEMENTED, "text/plain", Some(b"Not implemented")),
+            _ =>
+                resp.send(HttpStatus::BAD_REQUEST, "text/plain", Some(b"Bad request"))
+        }
+        resp
+    }
+
     pub fn add_listener(
         &mut self,
         addr: SocketAddr,
         request_timeout: Option<Duration>,
         response_timeout: Option<Duration>,
         keepalive_timeout: Option<Duration>,
-        keepalive_requests: u64
+        keepalive_requests: u64,
+        limit_upload_rate: usize,
+        shared: bool,
+        client_header_timeout: Option<Duration>,
+        max_half_read_connections: usize,
+        max_connections: usize
     ) -> CoreResult {
         self.server.add_listener(addr, Some(Options {
             request_timeout: request_timeout,
             response_timeout: response_timeout,
             keepalive_timeout: keepalive_timeout,
-            keepalive_requests: keepalive_requests
+            keepalive_requests,
+            limit_upload_rate,
+            shared,
+            client_header_timeout,
+            max_half_read_connections,
+            max_connections
         }))
     }
 
@@ -77,20 +113,28 @@ impl HttpServer {
         request_timeout: Option<Duration>,
         response_timeout: Option<Duration>,
         keepalive_timeout: Option<Duration>,
-        keepalive_requests: u64
+        keepalive_requests: u64,
+        limit_upload_rate: usize,
+        shared: bool,
+        client_header_timeout: Option<Duration>,
+        max_half_read_connections: usize,
+        max_connections: usize
     ) -> CoreResult {
         self.server.add_server_handler(addr, ContentHandler::new(move |request| -> HttpResponse {
             if !request.is_mailformed() {
                 return handler.handle(request);
             };
-            let mut bad_request = HttpResponse::new(request);
-            bad_request.send(HttpStatus::BAD_REQUEST, "text/plain", Some(b"Bad request"));
-            bad_request
+            HttpServer::reject(request)
         }), Some(Options {
             request_timeout: request_timeout,
             response_timeout: response_timeout,
             keepalive_timeout: keepalive_timeout,
-            keepalive_requests: keepalive_requests
+            keepalive_requests,
+            limit_upload_rate,
+            shared,
+            client_header_timeout,
+            max_half_read_connections,
+            max_connections
         }))
     }
 
@@ -98,6 +142,14 @@ impl HttpServer {
         self.server.remove_server_handler(addr)
     }
 
+    pub fn queue(&self) -> Arc<AtomicUsize> {
+        self.server.queue()
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.server.engine()
+    }
+
     pub fn stop(&mut self) {
         self.server.stop();
     }
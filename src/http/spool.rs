@@ -0,0 +1,96 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, Write };
+use std::net::SocketAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::RwLock;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+// Request bodies over the buffer size are written to temporary files, zero keeps them in memory
+#[derive(Clone, Default, Debug)]
+pub struct SpoolConfig {
+    pub buffer_size: usize,
+    // system temporary directory by default
+    pub temp_path: Option<String>
+}
+
+impl SpoolConfig {
+    pub fn exceeded(&self, size: usize) -> bool {
+        self.buffer_size != 0 && size > self.buffer_size
+    }
+}
+
+lazy_static! {
+    static ref CONFIGS: RwLock<HashMap<SocketAddr, SpoolConfig>> = RwLock::new(HashMap::new());
+    static ref TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+}
+
+pub fn set(addr: SocketAddr, config: SpoolConfig) {
+    CONFIGS.write().unwrap().insert(addr, config);
+}
+
+pub fn remove(addr: SocketAddr) {
+    CONFIGS.write().unwrap().remove(&addr);
+}
+
+pub fn get(addr: &SocketAddr) -> SpoolConfig {
+    CONFIGS.read().unwrap().get(addr).cloned().unwrap_or_default()
+}
+
+// New file with the unique name in the temporary directory
+pub fn temp_file(temp_path: &Option<String>, prefix: &str) -> io::Result<(File, PathBuf)> {
+    let dir = match temp_path {
+        Some(path) => PathBuf::from(path),
+        None => std::env::temp_dir()
+    };
+    let path = dir.join(format!("ws_{}_{}_{}", prefix, std::process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+    let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+    Ok((file, path))
+}
+
+// Body written to the temporary file, removed with the request
+pub struct SpooledBody {
+    file: File,
+    path: PathBuf,
+    size: usize
+}
+
+impl SpooledBody {
+    pub fn create(config: &SpoolConfig) -> io::Result<SpooledBody> {
+        let (file, path) = temp_file(&config.temp_path, "body")?;
+        Ok(SpooledBody {
+            file,
+            path,
+            size: 0
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.size += data.len();
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Reader from the beginning, independent of the writer
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+}
+
+impl Drop for SpooledBody {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::cmp::Reverse;
+use std::collections::{ HashMap, HashSet };
+use std::net::SocketAddr;
+use std::sync::RwLock;
+
+use regex::Regex;
+
+use crate::error::CoreError;
+use crate::http::HttpComplexValue;
+
+// Virtual hosts of the listener, in the order of lookup
+#[derive(Default)]
+struct VirtualHosts {
+    exact: HashSet<String>,
+    // '*.example.com' and '.example.com', the longest suffix wins
+    suffixes: Vec<(String, String)>,
+    // 'www.example.*', the longest prefix wins
+    prefixes: Vec<(String, String)>,
+    // '~^api\.(?<tenant>.+)$', the first match in the config order wins
+    regexes: Vec<(Regex, String)>,
+    // server for the unknown hosts, the server without virtual_host if not set
+    default: Option<String>
+}
+
+// Virtual host serving the request with the named captures of its regex
+pub struct Matched {
+    pub name: String,
+    pub captures: Vec<(String, String)>
+}
+
+impl Matched {
+    // Captured text is taken as is, '${...}' sent in the Host is not expanded
+    pub fn vars(self) -> impl Iterator<Item = (String, HttpComplexValue)> {
+        self.captures.into_iter().map(|(name, value)| (name, HttpComplexValue::simple(&value)))
+    }
+}
+
+lazy_static! {
+    static ref VHOSTS: RwLock<HashMap<SocketAddr, VirtualHosts>> = RwLock::new(HashMap::new());
+}
+
+fn regex(name: &str) -> Result<Option<Regex>, CoreError> {
+    match name.strip_prefix('~') {
+        Some(re) => match Regex::new(re.trim_start()) {
+            Ok(re) => Ok(Some(re)),
+            Err(err) => throw!("virtual_host: invalid regex '{}': {}", re, err)
+        },
+        None => Ok(None)
+    }
+}
+
+pub fn validate(name: &str) -> Result<(), CoreError> {
+    regex(name).map(|_| ())
+}
+
+// Registered by every event pool of the listener, so the same name may come again
+pub fn add(addr: SocketAddr, name: &str, default: bool) -> Result<(), CoreError> {
+    let re = regex(name)?;
+    let mut vhosts = VHOSTS.write().unwrap();
+    let vhosts = vhosts.entry(addr).or_default();
+    if default {
+        vhosts.default = Some(name.to_string());
+    }
+    if name == "*" {
+        return Ok(());
+    }
+    let name_ = name.to_string();
+    let known = |list: &Vec<(String, String)>| list.iter().any(|(_, name)| *name == name_);
+    if let Some(re) = re {
+        if !vhosts.regexes.iter().any(|(_, name)| *name == name_) {
+            vhosts.regexes.push((re, name_));
+        }
+    } else if name.starts_with('*') || name.starts_with('.') {
+        if !known(&vhosts.suffixes) {
+            vhosts.suffixes.push((name.trim_start_matches('*').to_ascii_lowercase(), name_));
+            vhosts.suffixes.sort_by_key(|(name, _)| Reverse(name.len()));
+        }
+    } else if let Some(prefix) = name.strip_suffix('*') {
+        if !known(&vhosts.prefixes) {
+            vhosts.prefixes.push((prefix.to_ascii_lowercase(), name_));
+            vhosts.prefixes.sort_by_key(|(name, _)| Reverse(name.len()));
+        }
+    } else {
+        vhosts.exact.insert(name_);
+    }
+    Ok(())
+}
+
+pub fn remove(addr: SocketAddr) {
+    VHOSTS.write().unwrap().remove(&addr);
+}
+
+// Server for the unknown hosts
+pub fn default(addr: &SocketAddr) -> String {
+    VHOSTS.read().unwrap().get(addr).and_then(|vhosts| vhosts.default.clone()).unwrap_or_else(|| "*".to_string())
+}
+
+// Host header as it came, then without the port: exact name, wildcards, regexes and the default server
+pub fn find(addr: &SocketAddr, host: &str) -> Matched {
+    let matched = |name: &str| Matched { name: name.to_string(), captures: Vec::new() };
+    let vhosts = VHOSTS.read().unwrap();
+    let vhosts = match vhosts.get(addr) {
+        Some(vhosts) => vhosts,
+        None => return matched(host)
+    };
+    if vhosts.exact.contains(host) {
+        return matched(host);
+    }
+    let host = host.rsplit_once(':')
+                   .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+                   .map(|(host, _)| host)
+                   .unwrap_or(host)
+                   .to_ascii_lowercase();
+    if vhosts.exact.contains(&host) {
+        return matched(&host);
+    }
+    // '.example.com' is example.com as well
+    if let Some((_, name)) = vhosts.suffixes.iter().find(|(suffix, name)| {
+        host.ends_with(suffix.as_str()) || (name.starts_with('.') && host == suffix[1..])
+    }) {
+        return matched(name);
+    }
+    if let Some((_, name)) = vhosts.prefixes.iter().find(|(prefix, _)| host.starts_with(prefix.as_str())) {
+        return matched(name);
+    }
+    for (re, name) in vhosts.regexes.iter() {
+        if let Some(caps) = re.captures(&host) {
+            return Matched {
+                name: name.clone(),
+                captures: re.capture_names()
+                            .flatten()
+                            .filter_map(|var| caps.name(var).map(|value| (var.to_string(), value.as_str().to_string())))
+                            .collect()
+            };
+        }
+    }
+    matched(vhosts.default.as_deref().unwrap_or("*"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn captures() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        add(addr, r"~^(?P<sub>.+)\.example\.com$", false).unwrap();
+        let matched = find(&addr, "${sub}.example.com");
+        assert_eq!(matched.name, r"~^(?P<sub>.+)\.example\.com$");
+        let vars: Vec<(String, HttpComplexValue)> = matched.vars().collect();
+        assert_eq!(vars.len(), 1);
+        assert_eq!(vars[0].0, "sub");
+        // template of the client is plain text, not the variable referring to itself
+        assert_eq!(vars[0].1.text().as_deref(), Some("${sub}"));
+        let vars: Vec<(String, HttpComplexValue)> = find(&addr, "${http_authorization}.example.com").vars().collect();
+        assert_eq!(vars[0].1.text().as_deref(), Some("${http_authorization}"));
+        remove(addr);
+    }
+}
@@ -8,6 +8,8 @@ use std::collections::{ LinkedList, HashMap };
 use std::hash::{ Hash, Hasher };
 use unicase::Ascii;
 
+use crate::core::pool::Recycle;
+
 pub enum Value<'a, T: Clone> {
     Single(&'a T),
     Multi(&'a LinkedList<T>)
@@ -76,6 +78,13 @@ impl std::fmt::Display for Key {
     }
 }
 
+// Cleared map keeps its capacity
+impl<T> Recycle for KeyVal<T> {
+    fn recycle(&mut self) {
+        self.0.clear();
+    }
+}
+
 impl<T: Clone> KeyVal<T> {
     fn get_arg(&self, name: &str, exact: bool) -> Option<Value<'_, T>> {
         match self.0.get(&Key::from(name)) {
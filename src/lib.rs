@@ -33,6 +33,7 @@ pub mod core;
 pub mod variable;
 pub mod tcp_socket;
 pub mod buffer;
+pub mod bytes;
 #[macro_use]
 pub mod client_context;
 pub mod module;
@@ -42,4 +43,7 @@ pub mod http;
 pub mod tcp;
 pub mod connection_pool;
 pub mod upstream;
-pub mod fgac;
\ No newline at end of file
+pub mod cache;
+pub mod redis;
+pub mod fgac;
+pub mod crypto;
\ No newline at end of file
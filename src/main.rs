@@ -8,6 +8,7 @@ extern crate yaml_rust;
 use web_server::core::*;
 use web_server::http::*;
 use web_server::tcp::tcp::*;
+use web_server::config::Config;
 
 fn main() {
 
@@ -20,6 +21,8 @@ error_log: error.log
 ---
 http:
   error_log: error.log
+  config_snapshot: snapshot.yaml
+  shutdown_timeout: 10000
   log_formats:
     - log_format:
         name: default
@@ -27,6 +30,15 @@ http:
     - log_format:
         name: upstream
         format: '${request_start} ${local_time} [${remote_addr}] ${protocol} ${request_uri} ${request_time}ms ${upstream_name} ${upstream_addr} ${upstream_status} ${upstream_response_time}ms'
+    - log_format:
+        name: json
+        format_json:
+          time: ${local_time}
+          remote_addr: ${remote_addr}
+          request:
+            method: ${request_method}
+            uri: ${request_uri}
+          request_time: ${request_time}
   workgroups:
     - workgroup:
         name: default
@@ -213,6 +225,12 @@ http:
           - route:
               match: /upstream/status
               upstream_status: get upstream status
+          - route:
+              match: /inflight/status
+              inflight_status: get in-flight requests
+          - route:
+              match: /status
+              status: get servers, workgroups, upstreams and caches state
           - route:
               match: '@internal'
               echo: Hello from internal!
@@ -335,20 +353,45 @@ http:
               proxy: u1
 ";
 
+    // previously exported runtime configuration wins over the builtin one
+    let snapshot_file = Config::snapshot_file(conf_http);
+    let snapshot = snapshot_file.as_ref().and_then(|filename| std::fs::read_to_string(filename).ok());
+
     CoreModule::configure();
-    CoreModule::config_parse(conf_main).unwrap();
+    match &snapshot {
+        Some(snapshot) => CoreModule::config_import(snapshot).unwrap(),
+        None => CoreModule::config_parse(conf_main).unwrap()
+    };
+
+    // master process supervising the workers doesn't return from here
+    master::prefork();
+
+    // workers started on reload pick up the current configuration
+    let snapshot = snapshot_file.as_ref().and_then(|filename| std::fs::read_to_string(filename).ok());
 
     HttpModule::configure();
-    HttpModule::config_parse(conf_http).unwrap();
+    match &snapshot {
+        Some(snapshot) => HttpModule::config_import(snapshot).unwrap(),
+        None => HttpModule::config_parse(conf_http).unwrap()
+    };
 
     TcpModule::configure();
+    if let Some(snapshot) = &snapshot {
+        TcpModule::config_import(snapshot).unwrap();
+    }
 
     CoreModule::activate();
     HttpModule::activate();
     TcpModule::activate();
 
-    // HttpModule::deactivate();
-    // TcpModule::deactivate();
+    // the previous binary stops accepting when this one is ready
+    upgrade::ready();
+
+    // serves until SIGTERM or the upgraded binary has taken over the listeners
+    signals::wait_shutdown();
+
+    HttpModule::deactivate();
+    TcpModule::deactivate();
 
     HttpModule::wait();
     TcpModule::wait();
@@ -3,8 +3,8 @@
  */
 
 use std::time::{ SystemTime, Duration };
-use std::collections::HashMap;
-use std::sync::Once;
+use std::collections::{ HashMap, HashSet, LinkedList };
+use std::sync::{ Arc, Mutex, Once };
 use std::mem::transmute_copy;
 
 use crate::error::CoreResult;
@@ -12,6 +12,7 @@ use crate::client_context::ClientContext;
 use crate::plugin::*;
 use crate::config::*;
 use crate::error::{ Code::*, CoreError, FlushResult };
+use crate::core::poller::Waker;
 
 pub trait Request: Sized + Send {
 
@@ -23,6 +24,16 @@ pub trait Request: Sized + Send {
 
     fn parse(&mut self) -> CoreResult;
 
+    // Request line and headers are received, the rest is limited by request_timeout
+    fn headers_received(&self) -> bool {
+        true
+    }
+
+    // Interim data is queued, the socket is waited for being writable as well
+    fn write_pending(&self) -> bool {
+        false
+    }
+
     fn context(&mut self) -> &mut ClientContext;
 
     fn const_context(&self) -> &ClientContext;
@@ -62,9 +73,47 @@ pub trait Response: Sized + Send {
         self.get_request().on_timedout()
     }
 
+    // Response is completed later, the worker hands it over instead of queueing it to the IO thread
+    fn take_deferred(&mut self) -> Option<Deferred<Self>> {
+        None
+    }
+
     fn close(self) -> ClientContext;
 }
 
+// Puts the response completed outside of the worker threads into the ready queue of its IO thread
+pub struct Completion<R> {
+    ready: Arc<Mutex<LinkedList<R>>>,
+    waker: Arc<Waker>
+}
+
+impl<R> Clone for Completion<R> {
+    fn clone(&self) -> Self {
+        Completion {
+            ready: Arc::clone(&self.ready),
+            waker: Arc::clone(&self.waker)
+        }
+    }
+}
+
+impl<R> Completion<R> {
+    pub (crate) fn new(ready: Arc<Mutex<LinkedList<R>>>, waker: Arc<Waker>) -> Completion<R> {
+        Completion {
+            ready,
+            waker
+        }
+    }
+
+    pub fn complete(self, resp: R) {
+        self.ready.lock().unwrap().push_back(resp);
+        if let Err(err) = self.waker.wake() {
+            log_error!("error", "Failed to wake up poll: {}", err);
+        }
+    }
+}
+
+pub type Deferred<R> = Box<dyn FnOnce(R, Completion<R>) + Send>;
+
 pub trait ModuleType {
     type Request: Request;
     type Response: Response;
@@ -79,13 +128,19 @@ pub trait ModuleType {
 }
 
 pub struct ModuleConfig {
-    pub commands: HashMap<String, Command>
+    pub commands: HashMap<String, Command>,
+    pub blocks: HashSet<String>,
+    pub schemas: HashMap<String, Schema>,
+    pub expanders: HashMap<String, Expander>
 }
 
 impl Default for ModuleConfig {
     fn default() -> ModuleConfig {
         ModuleConfig {
-            commands: HashMap::new()
+            commands: HashMap::new(),
+            blocks: HashSet::new(),
+            schemas: HashMap::new(),
+            expanders: HashMap::new()
         }
     }
 }
@@ -182,6 +237,7 @@ impl<T: ModuleType + 'static> GenericModule<T> {
     }
 
     pub fn configure() {
+        GenericModule::<T>::add_block("root", T::name());
         GenericModule::<T>::add_command("root", T::name(), CommandHandler::new(|_,_| -> CommandResult {
             Ok(T::root_context())
         })).unwrap();
@@ -217,6 +273,40 @@ impl<T: ModuleType + 'static> GenericModule<T> {
         Ok(OK)
     }
 
+    pub fn add_block(path: &str, cmd: &str) {
+        GenericModule::<T>::instance().config.blocks.insert(format!("{}.{}", path, cmd));
+    }
+
+    pub fn is_block(path: &str) -> bool {
+        GenericModule::<T>::instance().config.blocks.contains(path)
+    }
+
+    pub fn add_schema(path: &str, cmd: &str, schema: Schema) -> ActionResult {
+        if GenericModule::<T>::instance().config.schemas.insert(format!("{}.{}", path, cmd), schema).is_some() {
+            panic!("Schema {}.{} has conflicts", path, cmd);
+        }
+        Ok(OK)
+    }
+
+    pub fn schema(path: &str) -> Option<&'static Schema> {
+        GenericModule::<T>::instance().config.schemas.get(path)
+    }
+
+    pub fn add_expander(path: &str, cmd: &str, expander: Expander) -> ActionResult {
+        if GenericModule::<T>::instance().config.expanders.insert(format!("{}.{}", path, cmd), expander).is_some() {
+            panic!("Expander {}.{} has conflicts", path, cmd);
+        }
+        Ok(OK)
+    }
+
+    pub fn expander(path: &str) -> Option<&'static Expander> {
+        GenericModule::<T>::instance().config.expanders.get(path)
+    }
+
+    pub fn has_command(path: &str, cmd: &str) -> bool {
+        GenericModule::<T>::instance().config.commands.contains_key(&format!("{}.{}", path, cmd))
+    }
+
     pub fn wait() {
         GenericModule::<T>::instance().wait()
     }
@@ -231,8 +321,11 @@ impl<T: ModuleType + 'static> GenericModule<T> {
                         None => Ok(None),
                         Some(new_context) => {
                             let mut block = block.clone();
+                            let (path_, cmd_) = (path.to_string(), cmd.to_string());
                             new_context.borrow_mut().flush = Some(Box::new(move |new_context| {
-                                (*command.handler)(new_context, &mut block).unwrap();
+                                (*command.handler)(new_context, &mut block)
+                                    .map(|_| ())
+                                    .or_else(|err| throw!("Failed to handle command '{}.{}': {}", path_, cmd_, err.what()))
                             }));
                             Ok(Some(new_context))
                         }
@@ -240,7 +333,20 @@ impl<T: ModuleType + 'static> GenericModule<T> {
                     Err(err) => throw!(format!("Failed to handle command '{}.{}': {}", path, cmd, err.what()))
                 }
             },
-            None => throw!("Unknown command: '{}.{}'", path, cmd)
+            None => Err(GenericModule::<T>::unknown_command(path, cmd))
+        }
+    }
+
+    pub fn unknown_command(path: &str, cmd: &str) -> CoreError {
+        let prefix = format!("{}.", path);
+        let commands = &GenericModule::<T>::instance().config.commands;
+        let candidates = commands.keys()
+                                 .filter(|name| name.starts_with(&prefix))
+                                 .map(|name| &name[prefix.len()..])
+                                 .filter(|name| !name.contains('.'));
+        match suggest(cmd, candidates) {
+            Some(name) => CoreError::from(format!("Unknown command: '{}.{}', did you mean '{}'?", path, cmd, name).as_str()),
+            None => CoreError::from(format!("Unknown command: '{}.{}'", path, cmd).as_str())
         }
     }
 
@@ -252,6 +358,10 @@ impl<T: ModuleType + 'static> GenericModule<T> {
         Config::parse::<T>(s)
     }
 
+    pub fn config_import(s: &str) -> ActionResult {
+        Config::import::<T>(s)
+    }
+
     fn instance() -> &'static mut GenericModule<T> {
         static mut MODULES: Option<HashMap<String, Box<dyn ModuleBase>>> = None;
         static INIT: Once = Once::new();
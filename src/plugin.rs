@@ -11,6 +11,15 @@ use crate::error::{ Code, Code::*, CoreError };
 
 pub type ActionResult = Result<Code, CoreError>;
 
+// Symbols of the plugin library loaded by 'load_plugin'
+pub const PLUGIN_VERSION_SYMBOL: &str = "ws_plugin_version";
+pub const PLUGIN_REGISTER_SYMBOL: &str = "ws_plugin_register";
+// The library must be built against the same version of the server, by the same compiler,
+// with the same features (build.rs), the layouts of the types depend on all of them
+pub const PLUGIN_API_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"), " ", env!("WS_RUSTC_VERSION"), " features ", env!("WS_FEATURES_HASH"), "\0"
+);
+
 pub trait Plugin {
 
     type ModuleType: ModuleType + 'static;
@@ -47,6 +56,26 @@ pub trait Plugin {
         Config::add_command::<Self::ModuleType, Context, T>(path, cmd, handler)
     }
 
+    fn add_schema(
+        path: &str,
+        cmd: &str,
+        schema: Schema
+    )
+        -> ActionResult where Self: Sized
+    {
+        Config::add_schema::<Self::ModuleType>(path, cmd, schema)
+    }
+
+    fn add_expander(
+        path: &str,
+        cmd: &str,
+        expander: Expander
+    )
+        -> ActionResult where Self: Sized
+    {
+        Config::add_expander::<Self::ModuleType>(path, cmd, expander)
+    }
+
     fn name() -> &'static str where Self: Sized {
         unimplemented!()
     }
@@ -237,4 +266,39 @@ macro_rules! register_plugin {
         #[link_section = ".init_array"]
         static $name: extern "C" fn() = $module;
     }
-}
\ No newline at end of file
+}
+
+// Registration of the plugins of the shared library, 'export_plugins!(HttpModule: Foo, Bar; TcpModule: Baz)'.
+// The library links the server crate dynamically, so the plugins register in the modules of the server.
+#[macro_export]
+macro_rules! export_plugins {
+    ($($module:ident: $($name:ident),+);+) => {
+        #[no_mangle]
+        pub extern "C" fn ws_plugin_version() -> *const std::os::raw::c_char {
+            $crate::plugin::PLUGIN_API_VERSION.as_ptr() as *const std::os::raw::c_char
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ws_plugin_register() {
+            $($(
+                $module::register($name::new(), &format!("{}::{}", $module::name(), stringify!($name))).unwrap();
+            )+)+
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn api_version() {
+        let version = PLUGIN_API_VERSION.strip_suffix('\0').unwrap();
+        assert!(!version.contains('\0'));
+        assert!(version.starts_with(concat!(env!("CARGO_PKG_VERSION"), " rustc ")));
+        // features hash
+        let hash = version.rsplit(" features ").next().unwrap();
+        assert_eq!(hash.len(), 16);
+        assert!(hash.bytes().all(|c| c.is_ascii_hexdigit()));
+    }
+}
@@ -0,0 +1,418 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::io::{ self, ErrorKind, Read, Write };
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::{ Arc, RwLock };
+use std::time::{ Duration, Instant };
+
+use crate::connection_pool::{ ConnectionPool, Peer, PeerPin };
+use crate::core::status::{ self, Json };
+use crate::error::CoreError;
+
+pub enum Reply {
+    Nil,
+    Status(String),
+    // error reply of the server, the connection stays usable
+    Error(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>)
+}
+
+impl Reply {
+    pub fn text(self) -> Option<String> {
+        match self {
+            Reply::Status(s) => Some(s),
+            Reply::Int(i) => Some(i.to_string()),
+            Reply::Bulk(b) => Some(String::from_utf8_lossy(&b).into_owned()),
+            _ => None
+        }
+    }
+
+    pub fn int(&self) -> Option<i64> {
+        match self {
+            Reply::Int(i) => Some(*i),
+            _ => None
+        }
+    }
+}
+
+// Position after the line starting at pos, None if the line is not received completely
+fn line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    buf[pos..].windows(2).position(|w| w == b"\r\n").map(|n| (&buf[pos..pos + n], pos + n + 2))
+}
+
+fn number(s: &[u8]) -> Result<i64, CoreError> {
+    String::from_utf8_lossy(s).parse::<i64>().or_else(|_| throw!("invalid number '{}'", String::from_utf8_lossy(s)))
+}
+
+// Bulk string of the server is 512m at most
+const MAX_BULK_SIZE: i64 = 512 * 1024 * 1024;
+// Items preallocated for the array, the length is sent by the server
+const MAX_ARRAY_PREALLOC: usize = 1024;
+
+// Value starting at pos or the length of the array the items of which follow it
+enum Item {
+    Value(Reply),
+    Array(usize)
+}
+
+// Item starting at pos and the position after it, None while incomplete
+fn item(buf: &[u8], pos: usize) -> Result<Option<(Item, usize)>, CoreError> {
+    let (head, next) = match line(buf, pos) {
+        Some(line) => line,
+        None => return Ok(None)
+    };
+    if head.is_empty() {
+        return throw!("protocol error");
+    }
+    let (kind, value) = (head[0], &head[1..]);
+    let value = match kind {
+        b'+' => Reply::Status(String::from_utf8_lossy(value).into_owned()),
+        b'-' => Reply::Error(String::from_utf8_lossy(value).into_owned()),
+        b':' => Reply::Int(number(value)?),
+        b'$' => match number(value)? {
+            n if n < 0 => Reply::Nil,
+            n if n > MAX_BULK_SIZE => return throw!("bulk string of {} bytes is too large", n),
+            n => {
+                let end = next + n as usize;
+                if buf.len() < end + 2 {
+                    return Ok(None);
+                }
+                return Ok(Some((Item::Value(Reply::Bulk(buf[next..end].to_vec())), end + 2)));
+            }
+        },
+        b'*' => match number(value)? {
+            n if n < 0 => Reply::Nil,
+            n => return Ok(Some((Item::Array(n as usize), next)))
+        },
+        _ => return throw!("protocol error")
+    };
+    Ok(Some((Item::Value(value), next)))
+}
+
+// RESP2 reply received in parts, the complete items are not parsed again
+#[derive(Default)]
+struct Parser {
+    pos: usize,
+    // arrays in progress with the number of their missing items
+    stack: Vec<(Vec<Reply>, usize)>
+}
+
+impl Parser {
+    // Reply once the buffer has all of it
+    fn parse(&mut self, buf: &[u8]) -> Result<Option<Reply>, CoreError> {
+        loop {
+            let mut reply = match item(buf, self.pos)? {
+                Some((item, next)) => {
+                    self.pos = next;
+                    match item {
+                        Item::Array(0) => Reply::Array(Vec::new()),
+                        Item::Array(n) => {
+                            self.stack.push((Vec::with_capacity(n.min(MAX_ARRAY_PREALLOC)), n));
+                            continue;
+                        },
+                        Item::Value(value) => value
+                    }
+                },
+                None => return Ok(None)
+            };
+            loop {
+                match self.stack.last_mut() {
+                    Some((items, missing)) => {
+                        items.push(reply);
+                        *missing -= 1;
+                        if *missing != 0 {
+                            break;
+                        }
+                        reply = Reply::Array(self.stack.pop().unwrap().0);
+                    },
+                    None => return Ok(Some(reply))
+                }
+            }
+        }
+    }
+}
+
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+// Socket of the pool is non-blocking, the caller waits for it until the deadline
+fn wait(peer: &Peer, events: libc::c_short, deadline: Instant) -> io::Result<()> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout.is_zero() {
+        return Err(io::Error::new(ErrorKind::TimedOut, "timed out"));
+    }
+    let mut fds = [libc::pollfd { fd: peer.stream.as_raw_fd(), events, revents: 0 }];
+    match unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout.as_millis() as libc::c_int + 1) } {
+        -1 => match io::Error::last_os_error() {
+            err if err.kind() == ErrorKind::Interrupted => Ok(()),
+            err => Err(err)
+        },
+        0 => Err(io::Error::new(ErrorKind::TimedOut, "timed out")),
+        _ => Ok(())
+    }
+}
+
+// Client of the redis server, connections are kept alive in the pool
+pub struct Redis {
+    name: String,
+    addr: SocketAddr,
+    password: Option<String>,
+    db: Option<i64>,
+    timeout: Duration,
+    pool: ConnectionPool
+}
+
+impl Redis {
+    pub fn new(name: &str, addr: SocketAddr, password: Option<String>, db: Option<i64>,
+               timeout: Duration, max_keepalive: usize, max_active: usize) -> Redis {
+        Redis {
+            name: name.to_string(),
+            addr,
+            password,
+            db,
+            timeout,
+            pool: ConnectionPool::with_timeouts(&format!("redis:{}", name), max_keepalive, max_active, Some(timeout), None, None)
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(peer: &mut Peer, args: &[&[u8]], deadline: Instant) -> Result<(), CoreError> {
+        let buf = encode(args);
+        let mut sent = 0;
+        while sent < buf.len() {
+            match peer.stream.write(&buf[sent..]) {
+                Ok(0) => return throw!("connection closed"),
+                Ok(n) => sent += n,
+                Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::NotConnected =>
+                    wait(peer, libc::POLLOUT, deadline).or_else(|err| throw!(err))?,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => return throw!(err)
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(peer: &mut Peer, deadline: Instant) -> Result<Reply, CoreError> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        let mut parser = Parser::default();
+        loop {
+            if let Some(reply) = parser.parse(&buf)? {
+                return Ok(reply);
+            }
+            match peer.stream.read(&mut chunk) {
+                Ok(0) => return throw!("connection closed"),
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock =>
+                    wait(peer, libc::POLLIN, deadline).or_else(|err| throw!(err))?,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => return throw!(err)
+            }
+        }
+    }
+
+    fn call(peer: &mut Peer, args: &[&[u8]], deadline: Instant) -> Result<Reply, CoreError> {
+        Redis::send(peer, args, deadline)?;
+        Redis::receive(peer, deadline)
+    }
+
+    fn check(reply: Reply) -> Result<Reply, CoreError> {
+        match reply {
+            Reply::Error(err) => throw!(err),
+            reply => Ok(reply)
+        }
+    }
+
+    // New connection is authenticated and switched to the db
+    fn connect(&self, deadline: Instant) -> Result<Peer, CoreError> {
+        let pin = PeerPin {
+            upstream: self.name.clone(),
+            ..PeerPin::default()
+        };
+        let mut peer = self.pool.connect(&self.addr, Some(self.timeout), &pin)?;
+        if peer.requests() != 0 {
+            return Ok(peer);
+        }
+        let prepared = (|| {
+            if let Some(password) = &self.password {
+                Redis::check(Redis::call(&mut peer, &[b"AUTH", password.as_bytes()], deadline)?)?;
+            }
+            if let Some(db) = self.db {
+                Redis::check(Redis::call(&mut peer, &[b"SELECT", db.to_string().as_bytes()], deadline)?)?;
+            }
+            Ok(())
+        })();
+        match prepared {
+            Ok(_) => Ok(peer),
+            Err(err) => {
+                peer.close();
+                Err(err)
+            }
+        }
+    }
+
+    // Reply of the command, the kept alive connection closed by the server is retried once
+    pub fn command(&self, args: &[&[u8]]) -> Result<Reply, CoreError> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let mut peer = self.connect(deadline).or_else(|err| throw!("redis '{}': {}", self.name, err))?;
+            let reused = peer.requests() != 0;
+            match Redis::call(&mut peer, args, deadline) {
+                Ok(Reply::Error(err)) => return throw!("redis '{}': {}", self.name, err),
+                Ok(reply) => return Ok(reply),
+                Err(_) if reused => peer.close(),
+                Err(err) => {
+                    peer.close();
+                    return throw!("redis '{}': {}", self.name, err);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, CoreError> {
+        self.command(&[b"GET", key.as_bytes()]).map(|reply| reply.text())
+    }
+
+    // Value expires after the ttl
+    pub fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), CoreError> {
+        match ttl {
+            Some(ttl) => self.command(&[b"SET", key.as_bytes(), value.as_bytes(), b"PX", ttl.as_millis().to_string().as_bytes()]),
+            None => self.command(&[b"SET", key.as_bytes(), value.as_bytes()])
+        }.map(|_| ())
+    }
+
+    // False if the key is not found
+    pub fn del(&self, key: &str) -> Result<bool, CoreError> {
+        self.command(&[b"DEL", key.as_bytes()]).map(|reply| reply.int() == Some(1))
+    }
+
+    // Keys starting with the prefix
+    pub fn keys(&self, prefix: &str) -> Result<Vec<String>, CoreError> {
+        let pattern = format!("{}*", prefix.replace('\\', "\\\\").replace('*', "\\*").replace('?', "\\?").replace('[', "\\["));
+        let mut keys = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let reply = self.command(&[b"SCAN", cursor.as_bytes(), b"MATCH", pattern.as_bytes(), b"COUNT", b"1000"])?;
+            match reply {
+                Reply::Array(mut items) if items.len() == 2 => {
+                    if let Reply::Array(page) = items.pop().unwrap() {
+                        keys.extend(page.into_iter().filter_map(|key| key.text()));
+                    }
+                    cursor = items.pop().unwrap().text().unwrap_or_default();
+                },
+                _ => return throw!("redis '{}': unexpected SCAN reply", self.name)
+            }
+            if cursor == "0" {
+                return Ok(keys);
+            }
+        }
+    }
+
+    // Values of the keys, missing keys are None
+    pub fn mget(&self, keys: &[String]) -> Result<Vec<Option<String>>, CoreError> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut args: Vec<&[u8]> = vec![b"MGET"];
+        args.extend(keys.iter().map(|key| key.as_bytes()));
+        match self.command(&args)? {
+            Reply::Array(values) => Ok(values.into_iter().map(|value| value.text()).collect()),
+            _ => throw!("redis '{}': unexpected MGET reply", self.name)
+        }
+    }
+
+    fn report(&self) -> Json {
+        Json::object(vec![
+            ("address", self.addr.to_string().into()),
+            ("active", self.pool.active().into()),
+            ("idle", self.pool.idle().into()),
+            ("fails", self.pool.fails().into())
+        ])
+    }
+}
+
+lazy_static! {
+    static ref SERVERS: RwLock<HashMap<String, Arc<Redis>>> = RwLock::new(HashMap::new());
+}
+
+// Server of the same name is declared once
+pub fn create(redis: Redis) -> Result<Arc<Redis>, String> {
+    let mut servers = SERVERS.write().unwrap();
+    if servers.contains_key(&redis.name) {
+        return Err(format!("'{}' is already defined", redis.name));
+    }
+    let name = redis.name.clone();
+    let redis = Arc::new(redis);
+    servers.insert(name.clone(), Arc::clone(&redis));
+    let name_ = name.clone();
+    status::register("redis", &name, Box::new(move || get(&name_).map(|redis| redis.report())));
+    Ok(redis)
+}
+
+pub fn get(name: &str) -> Option<Arc<Redis>> {
+    SERVERS.read().unwrap().get(name).cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(data: &[u8]) -> Result<Option<Reply>, CoreError> {
+        Parser::default().parse(data)
+    }
+
+    #[test]
+    fn replies() {
+        assert!(matches!(parse(b"+OK\r\n"), Ok(Some(Reply::Status(s))) if s == "OK"));
+        assert!(matches!(parse(b"-ERR wrong\r\n"), Ok(Some(Reply::Error(s))) if s == "ERR wrong"));
+        assert!(matches!(parse(b":-42\r\n"), Ok(Some(Reply::Int(-42)))));
+        assert!(matches!(parse(b"$5\r\nhe\r\no\r\n"), Ok(Some(Reply::Bulk(b))) if b == b"he\r\no"));
+        assert!(matches!(parse(b"$-1\r\n"), Ok(Some(Reply::Nil))));
+        assert!(matches!(parse(b"*-1\r\n"), Ok(Some(Reply::Nil))));
+        assert!(matches!(parse(b"*0\r\n"), Ok(Some(Reply::Array(items))) if items.is_empty()));
+        match parse(b"*2\r\n*2\r\n:1\r\n$1\r\na\r\n$-1\r\n") {
+            Ok(Some(Reply::Array(items))) => {
+                assert_eq!(items.len(), 2);
+                assert!(matches!(&items[0], Reply::Array(nested) if nested.len() == 2));
+                assert!(matches!(&items[1], Reply::Nil));
+            },
+            _ => panic!("array is expected")
+        }
+    }
+
+    #[test]
+    fn incomplete() {
+        let data = b"*3\r\n$3\r\nfoo\r\n:7\r\n*1\r\n+x\r\n";
+        let mut parser = Parser::default();
+        for n in 0..data.len() {
+            assert!(matches!(parser.parse(&data[..n]), Ok(None)));
+        }
+        assert!(matches!(parser.parse(data), Ok(Some(Reply::Array(items))) if items.len() == 3));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse(b"\r\n").is_err());
+        assert!(parse(b"!x\r\n").is_err());
+        assert!(parse(b":x\r\n").is_err());
+        assert!(parse(b"$1073741824\r\n").is_err());
+        // the length of the array is not preallocated as is
+        assert!(matches!(parse(b"*9223372036854775807\r\n:1\r\n"), Ok(None)));
+    }
+}
@@ -0,0 +1,113 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+
+use crate::core::zone::Zone;
+
+// Sessions and bytes per second of the client address, the address is forgotten with its last session
+pub struct Limits {
+    limit_conn: Option<usize>,
+    limit_rate: Option<usize>,
+    clients: Zone<IpAddr, Client>
+}
+
+struct Client {
+    sessions: usize,
+    // bytes of the token bucket, one second of the rate at most, negative is the debt
+    allowance: f64,
+    updated: Instant
+}
+
+// Session of the client is counted until the slot is dropped
+pub struct Slot {
+    limits: Arc<Limits>,
+    ip: IpAddr
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.limits.clients.remove_if(&self.ip, |client| {
+            client.sessions -= 1;
+            client.sessions == 0
+        });
+    }
+}
+
+impl Client {
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        self.allowance = f64::min(rate, self.allowance + rate * now.duration_since(self.updated).as_secs_f64());
+        self.updated = now;
+    }
+}
+
+impl Limits {
+    pub fn new(limit_conn: Option<usize>, limit_rate: Option<usize>) -> Arc<Limits> {
+        Arc::new(Limits {
+            limit_conn,
+            limit_rate,
+            clients: Zone::new(None, None)
+        })
+    }
+
+    // None if the client has limit_conn sessions already
+    pub fn acquire(limits: &Arc<Limits>, ip: IpAddr) -> Option<Slot> {
+        let init = || Client {
+            sessions: 0,
+            allowance: limits.limit_rate.unwrap_or(0) as f64,
+            updated: Instant::now()
+        };
+        let acquired = limits.clients.update(ip, init, |client| {
+            match limits.limit_conn {
+                Some(limit_conn) if client.sessions >= limit_conn => false,
+                _ => {
+                    client.sessions += 1;
+                    true
+                }
+            }
+        });
+        match acquired {
+            Some(true) => Some(Slot {
+                limits: Arc::clone(limits),
+                ip
+            }),
+            _ => None
+        }
+    }
+
+    // Bytes are accounted, the stream is delayed until the debt is paid
+    pub fn throttle(&self, ip: IpAddr, bytes: usize) -> Duration {
+        let rate = match self.limit_rate {
+            Some(rate) => rate as f64,
+            None => return Duration::ZERO
+        };
+        self.clients.modify(&ip, |client| {
+            client.refill(rate);
+            client.allowance -= bytes as f64;
+            match client.allowance < 0.0 {
+                true => Duration::from_secs_f64(-client.allowance / rate),
+                false => Duration::ZERO
+            }
+        }).unwrap_or(Duration::ZERO)
+    }
+
+    // Datagram over the rate is dropped
+    pub fn admit(&self, ip: IpAddr, bytes: usize) -> bool {
+        let rate = match self.limit_rate {
+            Some(rate) => rate as f64,
+            None => return true
+        };
+        self.clients.modify(&ip, |client| {
+            client.refill(rate);
+            if client.allowance < bytes as f64 {
+                return false;
+            }
+            client.allowance -= bytes as f64;
+            true
+        }).unwrap_or(true)
+    }
+}
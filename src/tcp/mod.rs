@@ -4,4 +4,9 @@ pub mod tcp;
 pub mod request;
 pub mod response;
 pub mod options;
+pub mod session;
+pub mod limit;
+pub mod tls;
+pub mod stream;
+pub mod udp;
 pub mod plugins;
\ No newline at end of file
@@ -1 +1,2 @@
-pub mod sample;
\ No newline at end of file
+pub mod stream;
+pub mod stream_log;
\ No newline at end of file
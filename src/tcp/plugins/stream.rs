@@ -0,0 +1,302 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_tcp_plugin!(Stream);
+
+use std::mem::take;
+use std::net::{ SocketAddr, ToSocketAddrs };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use yaml_rust::Yaml;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::tcp::tcp::*;
+use crate::tcp::stream::*;
+use crate::tcp::udp::{ UdpProxy, UdpServer, UDP_MAX_SESSIONS_DEFAULT, UDP_PROXY_TIMEOUT_DEFAULT };
+use crate::tcp::limit::Limits;
+use crate::error::{ Code, CoreError };
+
+enum Proxy {
+    Tcp(TcpProxy),
+    Udp(UdpProxy)
+}
+
+enum Server {
+    Tcp(TcpServer),
+    Udp(UdpServer)
+}
+
+// Servers are configured before they start on activation
+pub struct Stream {
+    configured: Arc<Mutex<Vec<Proxy>>>,
+    servers: Vec<Server>
+}
+
+// { api.example.com: 10.0.0.1:443, '*.example.com': 10.0.0.2:443 }
+struct ServerNames(Vec<(String, SocketAddr)>);
+
+impl Value for ServerNames {
+    type Type = ServerNames;
+    fn get(v: &mut ConfigBlock) -> Result<Self::Type, CoreError> {
+        match v {
+            Yaml::Hash(h) => take(h).iter().map(|(name, upstream)| match (name.as_str(), upstream.as_str()) {
+                (Some(name), Some(upstream)) => Ok((name.to_ascii_lowercase(), resolve(upstream)?)),
+                _ => throw!("type mismatch")
+            }).collect::<Result<_, _>>().map(ServerNames),
+            _ => throw!("type mismatch")
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn acceptor(server: &ServerContext) -> Result<Option<openssl::ssl::SslAcceptor>, CoreError> {
+    match (&server.ssl_certificate, &server.ssl_certificate_key) {
+        (Some(certificate), Some(key)) => Ok(Some(crate::tcp::tls::acceptor(certificate, key)?)),
+        (None, None) => Ok(None),
+        _ => throw!("'ssl_certificate' and 'ssl_certificate_key' are required both")
+    }
+}
+
+// Host name of the upstream is resolved with the config
+fn resolve(addr: &str) -> Result<SocketAddr, CoreError> {
+    match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => Ok(addr),
+        Ok(None) => throw!("'{}' has no addresses", addr),
+        Err(err) => throw!("failed to resolve '{}': {}", addr, err)
+    }
+}
+
+impl Plugin for Stream {
+    type ModuleType = TCP;
+
+    fn name() -> &'static str {
+        "Stream"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::SERVER, "bind", |server: &mut ServerContext, bind: String| {
+            let (transport, addr) = match bind.strip_prefix("udp:") {
+                Some(addr) => (Transport::UDP, addr),
+                None => (Transport::TCP, bind.strip_prefix("tcp:").unwrap_or(&bind))
+            };
+            server.transport = transport;
+            server.bind = Some(addr.parse().or_else(|_| throw!("invalid address '{}'", addr))?);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "proxy_pass", |server: &mut ServerContext, upstream: String| {
+            server.proxy_pass = Some(resolve(&upstream)?);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "proxy_timeout", |server: &mut ServerContext, proxy_timeout: Duration| {
+            server.proxy_timeout = Some(proxy_timeout);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "proxy_responses", |server: &mut ServerContext, proxy_responses: usize| {
+            server.proxy_responses = Some(proxy_responses);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "proxy_connect_timeout", |server: &mut ServerContext, proxy_connect_timeout: Duration| {
+            server.proxy_connect_timeout = Some(proxy_connect_timeout);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "server_names", |server: &mut ServerContext, server_names: ServerNames| {
+            server.server_names = server_names.0;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "ssl_preread", |server: &mut ServerContext, ssl_preread: bool| {
+            server.ssl_preread = ssl_preread;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "preread_timeout", |server: &mut ServerContext, preread_timeout: Duration| {
+            server.preread_timeout = Some(preread_timeout);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "ssl_certificate", |server: &mut ServerContext, certificate: String| {
+            if cfg!(not(feature = "tls")) {
+                return throw!("ssl_certificate: is not supported, the binary is built without the 'tls' feature");
+            }
+            server.ssl_certificate = Some(certificate);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "ssl_certificate_key", |server: &mut ServerContext, key: String| {
+            if cfg!(not(feature = "tls")) {
+                return throw!("ssl_certificate_key: is not supported, the binary is built without the 'tls' feature");
+            }
+            server.ssl_certificate_key = Some(key);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "limit_conn", |server: &mut ServerContext, limit_conn: usize| {
+            server.limit_conn = Some(limit_conn);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "limit_rate", |server: &mut ServerContext, limit_rate: usize| {
+            server.limit_rate = Some(limit_rate);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "max_sessions", |server: &mut ServerContext, max_sessions: usize| {
+            server.max_sessions = Some(max_sessions);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "shared", |server: &mut ServerContext, shared: bool| {
+            server.shared = shared;
+            Ok(None)
+        })?;
+
+        add_schema!(Context::TCP, "servers.server", Schema::new()
+            .required("bind", SchemaType::String)
+            .optional("proxy_pass", SchemaType::String)
+            .optional("proxy_timeout", SchemaType::Integer)
+            .range("proxy_timeout", 1, i64::MAX)
+            .optional("proxy_connect_timeout", SchemaType::Integer)
+            .range("proxy_connect_timeout", 1, i64::MAX)
+            .optional("proxy_responses", SchemaType::Integer)
+            .range("proxy_responses", 1, i64::MAX)
+            .optional("server_names", SchemaType::Map)
+            .optional("ssl_preread", SchemaType::Bool)
+            .optional("preread_timeout", SchemaType::Integer)
+            .range("preread_timeout", 1, i64::MAX)
+            .optional("ssl_certificate", SchemaType::String)
+            .optional("ssl_certificate_key", SchemaType::String)
+            .optional("limit_conn", SchemaType::Integer)
+            .range("limit_conn", 1, i64::MAX)
+            .optional("limit_rate", SchemaType::Integer)
+            .range("limit_rate", 1, i64::MAX)
+            .optional("max_sessions", SchemaType::Integer)
+            .range("max_sessions", 1, i64::MAX)
+            .optional("shared", SchemaType::Bool))?;
+
+        let configured = Arc::clone(&self.configured);
+
+        add_block!(Context::TCP, "servers.server", move |context| {
+            match context.get_mut::<ServerContext>() {
+                Some(server) => {
+                    // exit
+                    let server = take(server);
+                    let addr = match server.bind {
+                        Some(addr) => addr,
+                        None => return throw!("'bind' is required")
+                    };
+                    if server.proxy_pass.is_none() && server.server_names.is_empty() {
+                        return throw!("bind {}: 'proxy_pass' or 'server_names' is required", addr);
+                    }
+                    let limits = Limits::new(server.limit_conn, server.limit_rate);
+                    let proxy = match server.transport {
+                        Transport::UDP if !server.server_names.is_empty() || server.ssl_preread || server.shared
+                                       || server.ssl_certificate.is_some() || server.ssl_certificate_key.is_some() =>
+                            return throw!("bind udp:{}: 'server_names', 'ssl_preread', 'ssl_certificate', 'ssl_certificate_key' and 'shared' are tcp only", addr),
+                        Transport::UDP => match server.proxy_pass {
+                            Some(upstream) => Proxy::Udp(UdpProxy {
+                                addr,
+                                upstream,
+                                timeout: server.proxy_timeout.unwrap_or(UDP_PROXY_TIMEOUT_DEFAULT),
+                                responses: server.proxy_responses,
+                                limits,
+                                max_sessions: server.max_sessions.unwrap_or(UDP_MAX_SESSIONS_DEFAULT),
+                                log: server.log
+                            }),
+                            None => return throw!("bind udp:{}: 'proxy_pass' is required", addr)
+                        },
+                        Transport::TCP => {
+                            if server.ssl_preread && server.ssl_certificate.is_some() {
+                                return throw!("bind {}: 'ssl_preread' and 'ssl_certificate' are exclusive", addr);
+                            }
+                            // the server name is known from the ClientHello or the handshake only
+                            if !server.server_names.is_empty() && !server.ssl_preread && server.ssl_certificate.is_none() {
+                                return throw!("bind {}: 'server_names' requires 'ssl_preread' or 'ssl_certificate'", addr);
+                            }
+                            Proxy::Tcp(TcpProxy {
+                                addr,
+                                upstream: server.proxy_pass,
+                                ssl_preread: server.ssl_preread,
+                                preread_timeout: server.preread_timeout.unwrap_or(TCP_PREREAD_TIMEOUT_DEFAULT),
+                                connect_timeout: server.proxy_connect_timeout.unwrap_or(TCP_CONNECT_TIMEOUT_DEFAULT),
+                                timeout: server.proxy_timeout.unwrap_or(TCP_PROXY_TIMEOUT_DEFAULT),
+                                #[cfg(feature = "tls")]
+                                acceptor: acceptor(&server)?,
+                                limits,
+                                max_sessions: server.max_sessions.unwrap_or(TCP_MAX_SESSIONS_DEFAULT),
+                                shared: server.shared,
+                                log: server.log,
+                                server_names: server.server_names
+                            })
+                        }
+                    };
+                    configured.lock().unwrap().push(proxy);
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<ServerContext>()))
+            }
+        })?;
+
+        add_empty_block!(Context::TCP, "servers")?;
+
+        Ok(Code::OK)
+    }
+
+    fn activate(&mut self) -> ActionResult {
+        for proxy in take(&mut *self.configured.lock().unwrap()) {
+            match proxy {
+                Proxy::Tcp(proxy) => {
+                    let server = TcpServer::start(proxy)?;
+                    log_error!("info", "tcp {} has started", server.addr());
+                    self.servers.push(Server::Tcp(server));
+                },
+                Proxy::Udp(proxy) => {
+                    let server = UdpServer::start(proxy)?;
+                    log_error!("info", "udp {} has started", server.addr());
+                    self.servers.push(Server::Udp(server));
+                }
+            }
+        }
+        Ok(Code::OK)
+    }
+
+    fn deactivate(&mut self) -> ActionResult {
+        self.servers.iter().for_each(|server| match server {
+            Server::Tcp(server) => server.stop(),
+            Server::Udp(server) => server.stop()
+        });
+        Ok(Code::OK)
+    }
+
+    fn wait(&mut self) {
+        self.servers.iter_mut().for_each(|server| match server {
+            Server::Tcp(server) => server.wait(),
+            Server::Udp(server) => server.wait()
+        });
+        self.servers.clear();
+    }
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream {
+            configured: Arc::new(Mutex::new(Vec::new())),
+            servers: Vec::new()
+        }
+    }
+}
@@ -0,0 +1,186 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+register_tcp_plugin!(StreamLog);
+
+use std::collections::HashMap;
+use std::mem::take;
+use std::sync::{ Arc, RwLock };
+use std::time::Duration;
+
+use crate::plugin::*;
+use crate::config::*;
+use crate::tcp::tcp::*;
+use crate::tcp::session::*;
+use crate::core::ring;
+use crate::http::plugins::access_log::AccessLog;
+use crate::error::{ Code, CoreError };
+
+#[derive(Default)]
+pub struct StreamLogFormatContext {
+    name: Option<String>,
+    format: Option<StreamComplexValue>
+}
+
+// Lines go to the files of the http access log, they share the buffering and reopening
+#[derive(Default)]
+pub struct StreamLogContext {
+    filename: String,
+    ring: Option<String>,
+    format: Option<StreamComplexValue>,
+    buffer_size: usize,
+    flush_interval: Option<Duration>
+}
+
+pub struct StreamLog {
+    log_formats: Arc<RwLock<HashMap<String, StreamComplexValue>>>
+}
+
+fn handler(access_log: StreamLogContext) -> Result<SessionLogHandler, CoreError> {
+    let format = match access_log.format {
+        Some(format) => format,
+        None => return throw!("access_log: 'format' required")
+    };
+    if access_log.filename.is_empty() && access_log.ring.is_none() {
+        return throw!("access_log: 'filename' or 'ring' required");
+    }
+    if let Some(name) = &access_log.ring {
+        if ring::get(name).is_none() {
+            return throw!("access_log: ring '{}' is not defined", name);
+        }
+    }
+    if let Some(flush_interval) = access_log.flush_interval {
+        AccessLog::add_flush_interval(flush_interval);
+    }
+    let (filename, ring, buffer_size, flush_interval) = (access_log.filename, access_log.ring, access_log.buffer_size, access_log.flush_interval);
+    Ok(SessionLogHandler::new(move |session| {
+        let text = session.expand(&format);
+        if let Some(name) = &ring {
+            ring::push(name, text.clone());
+        }
+        if !filename.is_empty() {
+            AccessLog::write(&filename, buffer_size, flush_interval, &session.server_addr, text);
+        }
+    }))
+}
+
+impl Plugin for StreamLog {
+    type ModuleType = TCP;
+
+    fn name() -> &'static str {
+        "StreamLog"
+    }
+
+    fn configure(&mut self) -> ActionResult {
+
+        add_command!(Context::TCP, "log_formats.log_format.name", |log_format: &mut StreamLogFormatContext, name: String| {
+            log_format.name = Some(name);
+            Ok(None)
+        })?;
+
+        add_command!(Context::TCP, "log_formats.log_format.format", |log_format: &mut StreamLogFormatContext, format: String| {
+            log_format.format = Some(StreamComplexValue::parse(&format).or_else(|err| throw!("format: {}", err))?);
+            Ok(None)
+        })?;
+
+        add_empty_block!(Context::TCP, "log_formats")?;
+
+        add_schema!(Context::TCP, "log_formats.log_format", Schema::new()
+            .required("name", SchemaType::String)
+            .required("format", SchemaType::String))?;
+
+        let log_formats = Arc::clone(&self.log_formats);
+
+        add_block!(Context::TCP, "log_formats.log_format", move |context| {
+            match context.get_mut::<StreamLogFormatContext>() {
+                Some(log_format) => {
+                    // exit
+                    match (log_format.name.take(), log_format.format.take()) {
+                        (Some(name), Some(format)) => {
+                            log_formats.write().unwrap().insert(name, format);
+                            Ok(None)
+                        },
+                        _ => throw!("log_format: 'name' and 'format' required")
+                    }
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<StreamLogFormatContext>()))
+            }
+        })?;
+
+        // Server
+
+        add_command!(Context::SERVER, "access_log.filename", |access_log: &mut StreamLogContext, filename: String| {
+            access_log.filename = filename;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "access_log.ring", |access_log: &mut StreamLogContext, ring: String| {
+            access_log.ring = Some(ring);
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "access_log.buffer_size", |access_log: &mut StreamLogContext, buffer_size: usize| {
+            access_log.buffer_size = buffer_size;
+            Ok(None)
+        })?;
+
+        add_command!(Context::SERVER, "access_log.flush_interval", |access_log: &mut StreamLogContext, flush_interval: Duration| {
+            access_log.flush_interval = Some(flush_interval);
+            Ok(None)
+        })?;
+
+        let log_formats = Arc::clone(&self.log_formats);
+
+        add_command!(Context::SERVER, "access_log.format", move |access_log: &mut StreamLogContext, format: String| {
+            access_log.format = match log_formats.read().unwrap().get(&format) {
+                Some(format) => Some(format.clone()),
+                None => return throw!("Format '{}' is not found", format)
+            };
+            Ok(None)
+        })?;
+
+        add_schema!(Context::SERVER, "access_log", Schema::new()
+            .optional("filename", SchemaType::String)
+            .optional("ring", SchemaType::String)
+            .required("format", SchemaType::String)
+            .optional("buffer_size", SchemaType::Integer)
+            .range("buffer_size", 0, i64::MAX)
+            .optional("flush_interval", SchemaType::Integer)
+            .range("flush_interval", 1, i64::MAX))?;
+
+        add_block!(Context::SERVER, "access_log", |context| {
+            match context.get_mut::<StreamLogContext>() {
+                Some(access_log) => {
+                    // exit
+                    let handler = handler(take(access_log))?;
+                    context.parent().unwrap()
+                           .get_mut::<ServerContext>().unwrap()
+                           .log.push(handler);
+                    Ok(None)
+                },
+                None =>
+                    // enter
+                    Ok(Some(CommandContext::new_default::<StreamLogContext>()))
+            }
+        })?;
+
+        Ok(Code::OK)
+    }
+}
+
+impl Default for StreamLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamLog {
+    pub fn new() -> StreamLog {
+        StreamLog {
+            log_formats: Arc::new(RwLock::new(HashMap::new()))
+        }
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::net::SocketAddr;
+use std::time::{ Duration, Instant };
+
+use chrono::prelude::*;
+
+use crate::handler::sync::ConstRefHandler;
+use crate::tcp::tcp::Transport;
+use crate::variable::Variable;
+
+pub type StreamComplexValue = Variable<StreamSession>;
+pub type SessionLogHandler = ConstRefHandler<StreamSession, ()>;
+
+// Status of the completed session
+pub const STATUS_OK: u16 = 200;
+// TLS handshake of the client has failed
+pub const STATUS_BAD_REQUEST: u16 = 400;
+pub const STATUS_INTERNAL_ERROR: u16 = 500;
+// no upstream for the server name or it is unavailable
+pub const STATUS_BAD_GATEWAY: u16 = 502;
+// limit_conn of the client address is exceeded
+pub const STATUS_SERVICE_UNAVAILABLE: u16 = 503;
+
+// Client session of the stream server, it is logged when it is closed
+pub struct StreamSession {
+    pub transport: Transport,
+    pub remote_addr: SocketAddr,
+    pub server_addr: SocketAddr,
+    pub upstream_addr: Option<SocketAddr>,
+    pub server_name: Option<String>,
+    // bytes of the client and bytes sent to it
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub status: u16,
+    start: DateTime<Local>,
+    timer: Instant,
+    duration: Duration
+}
+
+impl StreamSession {
+    pub fn new(transport: Transport, remote_addr: SocketAddr, server_addr: SocketAddr) -> StreamSession {
+        StreamSession {
+            transport,
+            remote_addr,
+            server_addr,
+            upstream_addr: None,
+            server_name: None,
+            bytes_received: 0,
+            bytes_sent: 0,
+            status: STATUS_OK,
+            start: Local::now(),
+            timer: Instant::now(),
+            duration: Duration::ZERO
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.duration = self.timer.elapsed();
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn expand(&self, cv: &StreamComplexValue) -> String {
+        cv.expand_with(|name| self.var(name), self)
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "remote_addr" => self.remote_addr.ip().to_string(),
+            "remote_port" => self.remote_addr.port().to_string(),
+            "server_addr" => self.server_addr.ip().to_string(),
+            "server_port" => self.server_addr.port().to_string(),
+            "protocol" => match self.transport {
+                Transport::TCP => "TCP",
+                Transport::UDP => "UDP"
+            }.to_string(),
+            "upstream_addr" => self.upstream_addr?.to_string(),
+            "ssl_server_name" => self.server_name.clone()?,
+            "bytes_received" => self.bytes_received.to_string(),
+            "bytes_sent" => self.bytes_sent.to_string(),
+            // milliseconds as the request_time of http
+            "session_time" => self.duration.as_millis().to_string(),
+            "session_start" => format!("{}", self.start.format("%Y/%m/%d-%H:%M:%S")),
+            "status" => self.status.to_string(),
+            "local_time" => format!("{}", Local::now().format("%Y/%m/%d-%H:%M:%S")),
+            "msec" => {
+                let now = Utc::now();
+                format!("{}.{:03}", now.timestamp(), now.timestamp_subsec_millis())
+            },
+            _ => return None
+        })
+    }
+}
@@ -0,0 +1,393 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::io::{ ErrorKind, Read, Write };
+use std::net::{ Shutdown, SocketAddr, TcpStream };
+use std::os::unix::io::{ AsRawFd, FromRawFd, IntoRawFd, RawFd };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+
+use mio::{ Events, Interest, Poll, Token, Waker };
+use mio::net::TcpListener;
+
+use crate::client_context::ClientContext;
+use crate::core::{ listen, upgrade };
+use crate::error::CoreError;
+use crate::tcp::tcp::Transport;
+use crate::tcp::tls::{ self, Preread };
+use crate::tcp::session::*;
+use crate::tcp::limit::Limits;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+pub const TCP_PROXY_TIMEOUT_DEFAULT: Duration = Duration::from_secs(600);
+pub const TCP_CONNECT_TIMEOUT_DEFAULT: Duration = Duration::from_secs(60);
+pub const TCP_PREREAD_TIMEOUT_DEFAULT: Duration = Duration::from_secs(30);
+pub const TCP_MAX_SESSIONS_DEFAULT: usize = 1024;
+
+// ClientHello larger than this is not a ClientHello
+const PREREAD_BUFFER_SIZE: usize = 16384;
+const BUFFER_SIZE: usize = 16384;
+
+pub struct TcpProxy {
+    pub addr: SocketAddr,
+    pub upstream: Option<SocketAddr>,
+    pub server_names: Vec<(String, SocketAddr)>,
+    pub ssl_preread: bool,
+    pub preread_timeout: Duration,
+    pub connect_timeout: Duration,
+    pub timeout: Duration,
+    pub limits: Arc<Limits>,
+    pub max_sessions: usize,
+    pub shared: bool,
+    pub log: Vec<SessionLogHandler>,
+    #[cfg(feature = "tls")]
+    pub acceptor: Option<openssl::ssl::SslAcceptor>
+}
+
+impl TcpProxy {
+    // Exact name, then the longest wildcard '*.example.com' or '.example.com', then proxy_pass
+    fn route(&self, name: Option<&str>) -> Option<SocketAddr> {
+        let name = match name {
+            Some(name) => name,
+            None => return self.upstream
+        };
+        if let Some((_, addr)) = self.server_names.iter().find(|(server_name, _)| server_name == name) {
+            return Some(*addr);
+        }
+        self.server_names.iter()
+            .filter(|(server_name, _)| {
+                let suffix = server_name.trim_start_matches('*');
+                suffix.starts_with('.') && (name.ends_with(suffix) || (server_name.starts_with('.') && name == &suffix[1..]))
+            })
+            .max_by_key(|(server_name, _)| server_name.len())
+            .map(|(_, addr)| *addr)
+            .or(self.upstream)
+    }
+}
+
+// Side of the session, the client may be TLS terminated
+trait Endpoint: Read + Write {
+    fn fd(&self) -> RawFd;
+
+    // decrypted bytes not read yet
+    fn pending(&self) -> usize {
+        0
+    }
+
+    fn shutdown_write(&mut self);
+}
+
+impl Endpoint for TcpStream {
+    fn fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+
+    fn shutdown_write(&mut self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Endpoint for openssl::ssl::SslStream<TcpStream> {
+    fn fd(&self) -> RawFd {
+        self.get_ref().as_raw_fd()
+    }
+
+    fn pending(&self) -> usize {
+        self.ssl().pending()
+    }
+
+    fn shutdown_write(&mut self) {
+        let _ = self.shutdown();
+        let _ = self.get_ref().shutdown(Shutdown::Write);
+    }
+}
+
+fn relay(client: &mut dyn Endpoint, upstream: &mut TcpStream, timeout: Duration, limits: &Limits, session: &mut StreamSession) {
+    let ip = session.remote_addr.ip();
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let (mut client_open, mut upstream_open) = (true, true);
+    while client_open || upstream_open {
+        let mut fds = [
+            libc::pollfd { fd: client.fd(), events: if client_open { libc::POLLIN } else { 0 }, revents: 0 },
+            libc::pollfd { fd: upstream.as_raw_fd(), events: if upstream_open { libc::POLLIN } else { 0 }, revents: 0 }
+        ];
+        if client.pending() == 0 {
+            match unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout.as_millis() as libc::c_int) } {
+                // idle
+                0 => return,
+                -1 if std::io::Error::last_os_error().kind() == ErrorKind::Interrupted => continue,
+                -1 => return,
+                _ => {}
+            }
+        } else {
+            fds[0].revents = libc::POLLIN;
+        }
+        if client_open && fds[0].revents != 0 {
+            match client.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    client_open = false;
+                    upstream.shutdown_write();
+                },
+                Ok(n) => {
+                    session.bytes_received += n as u64;
+                    if upstream.write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                    thread::sleep(limits.throttle(ip, n));
+                }
+            }
+        }
+        if upstream_open && fds[1].revents != 0 {
+            match upstream.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    upstream_open = false;
+                    client.shutdown_write();
+                },
+                Ok(n) => {
+                    if client.write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                    session.bytes_sent += n as u64;
+                    thread::sleep(limits.throttle(ip, n));
+                }
+            }
+        }
+    }
+}
+
+// Bytes of the ClientHello, they go to the upstream first
+fn preread(client: &mut TcpStream, timeout: Duration) -> (Vec<u8>, Option<String>) {
+    let deadline = Instant::now() + timeout;
+    let mut data = Vec::new();
+    let mut buf = vec![0u8; PREREAD_BUFFER_SIZE];
+    while data.len() < PREREAD_BUFFER_SIZE {
+        let now = Instant::now();
+        if now >= deadline || client.set_read_timeout(Some(deadline - now)).is_err() {
+            break;
+        }
+        match client.read(&mut buf[..PREREAD_BUFFER_SIZE - data.len()]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => data.extend_from_slice(&buf[..n])
+        }
+        match tls::preread(&data) {
+            Preread::Incomplete => continue,
+            Preread::Hello(name) => return (data, name),
+            Preread::NotTls => break
+        }
+    }
+    (data, None)
+}
+
+// Status of the failed session is the stage it has failed on
+fn session(proxy: &TcpProxy, mut client: TcpStream, session: &mut StreamSession) -> Result<(), CoreError> {
+    let peer = session.remote_addr;
+    client.set_nonblocking(false).or_else(|err| throw!("{}", err))?;
+
+    let (data, name) = match proxy.ssl_preread {
+        true => preread(&mut client, proxy.preread_timeout),
+        false => (Vec::new(), None)
+    };
+    let _ = client.set_read_timeout(None);
+    let _ = client.set_nodelay(true);
+
+    // the server name of the terminated TLS is known after the handshake
+    #[cfg(feature = "tls")]
+    let (mut client, name): (Box<dyn Endpoint>, Option<String>) = match &proxy.acceptor {
+        Some(acceptor) => {
+            session.status = STATUS_BAD_REQUEST;
+            let _ = client.set_read_timeout(Some(proxy.preread_timeout));
+            let ssl = acceptor.accept(client).or_else(|err| throw!("client {}: ssl handshake, {}", peer, err))?;
+            let _ = ssl.get_ref().set_read_timeout(None);
+            let name = ssl.ssl().servername(openssl::ssl::NameType::HOST_NAME).map(|name| name.to_ascii_lowercase());
+            (Box::new(ssl), name)
+        },
+        None => (Box::new(client), name)
+    };
+    #[cfg(not(feature = "tls"))]
+    let mut client: Box<dyn Endpoint> = Box::new(client);
+
+    session.status = STATUS_BAD_GATEWAY;
+    session.server_name = name;
+    let addr = match proxy.route(session.server_name.as_deref()) {
+        Some(addr) => addr,
+        None => return throw!("client {}: no upstream for the server name '{}'", peer, session.server_name.clone().unwrap_or_default())
+    };
+    session.upstream_addr = Some(addr);
+    let mut upstream = TcpStream::connect_timeout(&addr, proxy.connect_timeout)
+        .or_else(|err| throw!("client {}: connect to upstream {}, {}", peer, addr, err))?;
+    let _ = upstream.set_nodelay(true);
+    upstream.write_all(&data).or_else(|err| throw!("client {}: upstream {}, {}", peer, addr, err))?;
+
+    session.status = STATUS_OK;
+    session.bytes_received = data.len() as u64;
+    relay(&mut *client, &mut upstream, proxy.timeout, &proxy.limits, session);
+    Ok(())
+}
+
+fn serve(proxy: &TcpProxy, client: TcpStream) {
+    let remote_addr = match client.peer_addr() {
+        Ok(addr) => addr,
+        Err(err) => return log_error!("warn", "tcp {}: {}", proxy.addr, err)
+    };
+    let mut stream = StreamSession::new(Transport::TCP, remote_addr, proxy.addr);
+    match Limits::acquire(&proxy.limits, remote_addr.ip()) {
+        Some(_slot) => {
+            stream.status = STATUS_INTERNAL_ERROR;
+            if let Err(err) = session(proxy, client, &mut stream) {
+                log_error!("warn", "tcp {}: {}", proxy.addr, err);
+            }
+        },
+        None => {
+            log_error!("warn", "tcp {}: limit_conn of the client {} is exceeded", proxy.addr, remote_addr.ip());
+            stream.status = STATUS_SERVICE_UNAVAILABLE;
+        }
+    }
+    stream.finish();
+    proxy.log.iter().for_each(|log| log.handle(&stream));
+}
+
+// Session thread is counted until it exits, the connection above max_sessions is closed
+fn spawn(proxy: &Arc<TcpProxy>, sessions: &Arc<AtomicUsize>, stream: TcpStream) {
+    if sessions.fetch_add(1, Ordering::AcqRel) >= proxy.max_sessions {
+        sessions.fetch_sub(1, Ordering::AcqRel);
+        return log_error!("warn", "tcp {}: max_sessions {} is reached, connection is closed", proxy.addr, proxy.max_sessions);
+    }
+    let (proxy_, sessions_) = (Arc::clone(proxy), Arc::clone(sessions));
+    let spawned = thread::Builder::new().name(format!("ws: tcp {}", proxy.addr)).spawn(move || {
+        serve(&proxy_, stream);
+        sessions_.fetch_sub(1, Ordering::AcqRel);
+    });
+    if let Err(err) = spawned {
+        sessions.fetch_sub(1, Ordering::AcqRel);
+        log_error!("error", "tcp {}: failed to start the session, {}", proxy.addr, err);
+    }
+}
+
+// The listener is polled by its thread, every session has its own thread.
+// The address shared with the http module is listened by it, non HTTP connections are handed off.
+pub struct TcpServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    waker: Option<Arc<Waker>>,
+    thr: Option<JoinHandle<()>>
+}
+
+impl TcpServer {
+    pub fn start(proxy: TcpProxy) -> Result<TcpServer, CoreError> {
+        let addr = proxy.addr;
+        listen::bind(addr, "tcp", proxy.shared)?;
+        let server = match proxy.shared && listen::bindings().iter().any(|(bound, module, _)| *bound == addr && module != "tcp") {
+            true => TcpServer::handoff(proxy),
+            false => TcpServer::listen(proxy)
+        };
+        if server.is_err() {
+            listen::unbind(addr, "tcp");
+        }
+        server
+    }
+
+    fn handoff(proxy: TcpProxy) -> Result<TcpServer, CoreError> {
+        let addr = proxy.addr;
+        let proxy = Arc::new(proxy);
+        let sessions = Arc::new(AtomicUsize::new(0));
+        listen::set_handoff(addr, Arc::new(move |client: ClientContext| {
+            let mut client = client;
+            let stream = unsafe { TcpStream::from_raw_fd(client.take().into_raw_fd()) };
+            spawn(&proxy, &sessions, stream);
+        }));
+        Ok(TcpServer {
+            addr,
+            stop: Arc::new(AtomicBool::new(false)),
+            waker: None,
+            thr: None
+        })
+    }
+
+    fn listen(proxy: TcpProxy) -> Result<TcpServer, CoreError> {
+        let addr = proxy.addr;
+        // the listener of the previous binary keeps the connections of its accept queue
+        let listener = match upgrade::inherited(addr) {
+            Some(listener) => listener,
+            None => listen::bind_reuseport(addr).or_else(|err| throw!("Failed to bind {}: {}", addr, err))?
+        };
+        listener.set_nonblocking(true).or_else(|err| throw!("Failed to bind {}: {}", addr, err))?;
+        let mut listener = TcpListener::from_std(listener);
+        let poll = Poll::new().or_else(|err| throw!("tcp {}: {}", addr, err))?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE).or_else(|err| throw!("tcp {}: {}", addr, err))?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).or_else(|err| throw!("tcp {}: {}", addr, err))?);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_ = Arc::clone(&stop);
+        let proxy = Arc::new(proxy);
+        let sessions = Arc::new(AtomicUsize::new(0));
+
+        let fd = listener.as_raw_fd();
+        upgrade::track(addr, fd);
+
+        let thr = thread::Builder::new().name(format!("ws: tcp {}", addr)).spawn(move || {
+            let mut poll = poll;
+            let mut events = Events::with_capacity(128);
+            while !stop_.load(Ordering::Relaxed) {
+                if let Err(err) = poll.poll(&mut events, None) {
+                    if err.kind() != ErrorKind::Interrupted {
+                        log_error!("error", "tcp {}: poll, {}", addr, err);
+                        break;
+                    }
+                }
+                for event in events.iter() {
+                    if event.token() != LISTENER {
+                        continue;
+                    }
+                    loop {
+                        match listener.accept() {
+                            Ok((stream, _)) => spawn(&proxy, &sessions, unsafe { TcpStream::from_raw_fd(stream.into_raw_fd()) }),
+                            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                            Err(err) => {
+                                log_error!("warn", "tcp {}: accept, {}", addr, err);
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            upgrade::untrack(listener.as_raw_fd());
+        }).or_else(|err| {
+            upgrade::untrack(fd);
+            throw!("tcp {}: {}", addr, err)
+        })?;
+
+        Ok(TcpServer {
+            addr,
+            stop,
+            waker: Some(waker),
+            thr: Some(thr)
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Sessions in progress are completed on their own
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        match &self.waker {
+            Some(waker) => {
+                let _ = waker.wake();
+            },
+            None => listen::remove_handoff(self.addr)
+        }
+        listen::unbind(self.addr, "tcp");
+    }
+
+    pub fn wait(&mut self) {
+        if let Some(thr) = self.thr.take() {
+            let _ = thr.join();
+        }
+    }
+}
@@ -2,11 +2,15 @@
  * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
  */
 
+use std::net::SocketAddr;
 use std::ops::Deref;
+use std::time::Duration;
 
 use crate::module::*;
+use crate::config::{ CommandContext, CommandContextType };
 use crate::tcp::request::TcpRequest;
 use crate::tcp::response::TcpResponse;
+use crate::tcp::session::SessionLogHandler;
 
 pub struct TCP {}
 
@@ -16,6 +20,50 @@ impl ModuleType for TCP {
     fn name() -> &'static str {
         "tcp"
     }
+    fn root_context() -> Option<CommandContextType> {
+        Some(CommandContext::new_default::<TcpContext>())
+    }
+}
+
+#[derive(Default)]
+pub struct TcpContext {}
+
+#[derive(Clone, Copy, PartialEq)]
+#[derive(Default)]
+pub enum Transport {
+    #[default]
+    TCP,
+    UDP
+}
+
+
+// 'bind: 0.0.0.0:5000' or 'bind: udp:0.0.0.0:53'
+#[derive(Default)]
+pub struct ServerContext {
+    pub bind: Option<SocketAddr>,
+    pub transport: Transport,
+    pub proxy_pass: Option<SocketAddr>,
+    // session without the datagrams in both directions is closed
+    pub proxy_timeout: Option<Duration>,
+    // datagrams expected from the upstream for the datagram of the client, 'proxy_responses: 1' for DNS
+    pub proxy_responses: Option<usize>,
+    pub proxy_connect_timeout: Option<Duration>,
+    // upstreams by the server name of the TLS handshake, 'proxy_pass' is for the rest
+    pub server_names: Vec<(String, SocketAddr)>,
+    // server name is taken from the ClientHello without the termination of TLS
+    pub ssl_preread: bool,
+    pub preread_timeout: Option<Duration>,
+    pub ssl_certificate: Option<String>,
+    pub ssl_certificate_key: Option<String>,
+    // sessions and bytes per second of the client address
+    pub limit_conn: Option<usize>,
+    pub limit_rate: Option<usize>,
+    // session threads of the listener
+    pub max_sessions: Option<usize>,
+    // the address is shared with the http module, it hands off the non HTTP connections
+    pub shared: bool,
+    // handlers of the closed sessions
+    pub log: Vec<SessionLogHandler>
 }
 
 pub type TcpModule = GenericModule<TCP>;
@@ -0,0 +1,231 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+pub enum Preread {
+    // more bytes of the ClientHello are required
+    Incomplete,
+    // ClientHello with the server name if the client has sent it
+    Hello(Option<String>),
+    // not a TLS handshake
+    NotTls
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<usize> {
+    Some(((*data.get(pos)? as usize) << 8) | *data.get(pos + 1)? as usize)
+}
+
+// Server name of the ClientHello, the handshake may span a few records
+pub fn preread(data: &[u8]) -> Preread {
+    // records of the handshake: type 22, version, length
+    let mut handshake = Vec::new();
+    let mut pos = 0;
+    loop {
+        if data.len() < pos + 5 {
+            return Preread::Incomplete;
+        }
+        if data[pos] != 22 || data[pos + 1] != 3 {
+            return Preread::NotTls;
+        }
+        let len = u16_at(data, pos + 3).unwrap();
+        let end = std::cmp::min(data.len(), pos + 5 + len);
+        handshake.extend_from_slice(&data[pos + 5..end]);
+        // ClientHello: type 1, length of 3 bytes
+        if handshake.len() >= 4 {
+            if handshake[0] != 1 {
+                return Preread::NotTls;
+            }
+            let hello_len = ((handshake[1] as usize) << 16) | u16_at(&handshake, 2).unwrap();
+            if handshake.len() >= 4 + hello_len {
+                return match server_name(&handshake[4..4 + hello_len]) {
+                    Some(name) => Preread::Hello(name),
+                    None => Preread::NotTls
+                };
+            }
+        }
+        if end < pos + 5 + len {
+            return Preread::Incomplete;
+        }
+        pos = end;
+    }
+}
+
+// None is the malformed ClientHello
+fn server_name(hello: &[u8]) -> Option<Option<String>> {
+    // version and random
+    let mut pos = 2 + 32;
+    // session id
+    pos += 1 + *hello.get(pos)? as usize;
+    // cipher suites
+    pos += 2 + u16_at(hello, pos)?;
+    // compression methods
+    pos += 1 + *hello.get(pos)? as usize;
+    if pos == hello.len() {
+        // no extensions
+        return Some(None);
+    }
+    let end = pos + 2 + u16_at(hello, pos)?;
+    pos += 2;
+    while pos + 4 <= end {
+        let (kind, len) = (u16_at(hello, pos)?, u16_at(hello, pos + 2)?);
+        pos += 4;
+        if kind == 0 {
+            // server_name: list length, then name type 0 (host_name), length and name
+            let list = hello.get(pos..pos + len)?;
+            let mut i = 2;
+            while i + 3 <= list.len() {
+                let name_len = u16_at(list, i + 1)?;
+                if list[i] == 0 {
+                    let name = list.get(i + 3..i + 3 + name_len)?;
+                    return Some(Some(String::from_utf8_lossy(name).to_ascii_lowercase()));
+                }
+                i += 3 + name_len;
+            }
+            return Some(None);
+        }
+        pos += len;
+    }
+    Some(None)
+}
+
+#[cfg(feature = "tls")]
+pub fn acceptor(certificate: &str, key: &str) -> Result<openssl::ssl::SslAcceptor, crate::error::CoreError> {
+    use openssl::ssl::{ SslAcceptor, SslFiletype, SslMethod };
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+        .or_else(|err| throw!("ssl: {}", err))?;
+    builder.set_certificate_chain_file(certificate)
+           .or_else(|err| throw!("ssl_certificate '{}': {}", certificate, err))?;
+    builder.set_private_key_file(key, SslFiletype::PEM)
+           .or_else(|err| throw!("ssl_certificate_key '{}': {}", key, err))?;
+    builder.check_private_key()
+           .or_else(|err| throw!("ssl_certificate_key '{}': {}", key, err))?;
+    Ok(builder.build())
+}
+
+// TLS of the upstream connections
+#[cfg(feature = "tls")]
+pub mod upstream {
+    use std::collections::HashMap;
+    use std::io::{ self, IoSlice, Read, Write };
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::{ FromRawFd, RawFd };
+    use std::sync::{ Arc, Mutex };
+    use std::time::{ Duration, SystemTime };
+    use mio::net::TcpStream;
+    use openssl::ssl::{ ErrorCode, HandshakeError, NameType, SslConnector, SslMethod, SslRef, SslStream };
+
+    use crate::connection_pool::TlsPin;
+    use crate::core::poller;
+    use crate::crypto;
+    use crate::error::CoreError;
+
+    // Socket of the session, the fd is owned and closed by the TcpSocket
+    pub struct Fd(ManuallyDrop<TcpStream>);
+
+    impl Read for Fd {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            poller::recv(&mut self.0, buf)
+        }
+    }
+
+    impl Write for Fd {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            poller::send(&mut self.0, &[IoSlice::new(buf)])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Session is shared by the weak copies of the socket
+    pub type Session = Arc<Mutex<SslStream<Fd>>>;
+
+    fn connector(trusted_certificate: &Option<String>) -> Result<SslConnector, CoreError> {
+        lazy_static! {
+            static ref CONNECTORS: Mutex<HashMap<Option<String>, SslConnector>> = Mutex::new(HashMap::new());
+        }
+
+        let mut connectors = CONNECTORS.lock().unwrap();
+        if let Some(connector) = connectors.get(trusted_certificate) {
+            return Ok(connector.clone());
+        }
+        // the system CAs unless the trusted certificate is given
+        let mut builder = SslConnector::builder(SslMethod::tls()).or_else(|err| throw!("ssl: {}", err))?;
+        if let Some(ca) = trusted_certificate {
+            builder.set_ca_file(ca).or_else(|err| throw!("trusted_certificate '{}': {}", ca, err))?;
+        }
+        let connector = builder.build();
+        connectors.insert(trusted_certificate.clone(), connector.clone());
+        Ok(connector)
+    }
+
+    // Sha256 of the public key (SubjectPublicKeyInfo) of the certificate
+    fn spki_sha256(ssl: &SslRef) -> Option<[u8; 32]> {
+        let der = ssl.peer_certificate()?.public_key().ok()?.public_key_to_der().ok()?;
+        Some(crypto::sha256(&der))
+    }
+
+    // Session was established with the server name of the pin and one of its keys
+    pub fn matches(session: &Session, pin: &TlsPin) -> bool {
+        let session = session.lock().unwrap();
+        let ssl = session.ssl();
+        if ssl.servername(NameType::HOST_NAME) != pin.server_name.as_deref() {
+            return false;
+        }
+        match spki_sha256(ssl) {
+            Some(digest) => pin.pin_sha256.is_empty() || pin.pin_sha256.iter().any(|pin| crypto::constant_time_eq(pin, &digest)),
+            None => false
+        }
+    }
+
+    // Handshake of the connecting socket, the certificate is verified by the CAs and the server name,
+    // then its public key by the pins
+    pub fn connect(fd: RawFd, pin: &TlsPin, deadline: Option<SystemTime>) -> Result<Session, CoreError> {
+        let mut config = connector(&pin.trusted_certificate)?.configure().or_else(|err| throw!("ssl: {}", err))?;
+        if pin.server_name.is_none() {
+            config.set_use_server_name_indication(false);
+            config.set_verify_hostname(false);
+        }
+
+        let stream = Fd(ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) }));
+        let mut result = config.connect(pin.server_name.as_deref().unwrap_or_default(), stream);
+        let stream = loop {
+            match result {
+                Ok(stream) => break stream,
+                Err(HandshakeError::WouldBlock(mid)) => {
+                    let events = match mid.error().code() {
+                        ErrorCode::WANT_READ => libc::POLLIN,
+                        _ => libc::POLLOUT
+                    };
+                    let timeout = match deadline {
+                        Some(deadline) => match deadline.duration_since(SystemTime::now()) {
+                            Ok(left) if left > Duration::from_millis(0) => left.as_millis() as libc::c_int,
+                            _ => return throw!("ssl handshake timed out")
+                        },
+                        None => -1
+                    };
+                    let mut fds = [libc::pollfd { fd, events, revents: 0 }];
+                    match unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) } {
+                        0 => return throw!("ssl handshake timed out"),
+                        -1 if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted
+                            => return throw!("ssl handshake, {}", io::Error::last_os_error()),
+                        _ => {}
+                    }
+                    result = mid.handshake();
+                },
+                Err(HandshakeError::Failure(mid))
+                    => return throw!("ssl handshake, {} ({})", mid.error(), mid.ssl().verify_result()),
+                Err(HandshakeError::SetupFailure(err))
+                    => return throw!("ssl: {}", err)
+            }
+        };
+
+        let session = Arc::new(Mutex::new(stream));
+        if !matches(&session, pin) {
+            return throw!("ssl handshake, the public key of the certificate is not pinned");
+        }
+        Ok(session)
+    }
+}
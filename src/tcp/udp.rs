@@ -0,0 +1,312 @@
+/*
+ * Copyright (C) 2020 Aleksei Konovkin (alkon2000@mail.ru)
+ */
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+
+use mio::{ Events, Interest, Poll, Token, Waker };
+use mio::net::UdpSocket;
+use net2::unix::UnixUdpBuilderExt;
+
+use crate::error::CoreError;
+use crate::tcp::tcp::Transport;
+use crate::tcp::session::*;
+use crate::tcp::limit::{ Limits, Slot };
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_SESSION: usize = 2;
+
+pub const UDP_PROXY_TIMEOUT_DEFAULT: Duration = Duration::from_secs(10);
+pub const UDP_MAX_SESSIONS_DEFAULT: usize = 1024;
+
+// Largest datagram
+const DATAGRAM_SIZE: usize = 65536;
+
+pub struct UdpProxy {
+    pub addr: SocketAddr,
+    pub upstream: SocketAddr,
+    pub timeout: Duration,
+    pub responses: Option<usize>,
+    pub limits: Arc<Limits>,
+    // sessions of the listener, the least recently active one is closed for the new one
+    pub max_sessions: usize,
+    pub log: Vec<SessionLogHandler>
+}
+
+// Datagrams of the client go to the upstream through its own socket,
+// so the datagrams of the upstream are associated with the client by the socket they came to
+struct Session {
+    client: SocketAddr,
+    upstream: UdpSocket,
+    active: Instant,
+    responses: usize,
+    stream: StreamSession,
+    _slot: Slot
+}
+
+pub struct UdpServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+    thr: Option<JoinHandle<()>>
+}
+
+// Every worker process listens its own socket of the address
+fn bind(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let builder = match addr {
+        SocketAddr::V4(_) => net2::UdpBuilder::new_v4()?,
+        SocketAddr::V6(_) => net2::UdpBuilder::new_v6()?
+    };
+    let socket = builder.reuse_address(true)?.reuse_port(true)?.bind(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket))
+}
+
+fn connect(upstream: SocketAddr) -> std::io::Result<UdpSocket> {
+    let local: SocketAddr = match upstream {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(upstream)?;
+    Ok(socket)
+}
+
+struct Sessions {
+    proxy: UdpProxy,
+    sessions: HashMap<Token, Session>,
+    clients: HashMap<SocketAddr, Token>,
+    next: usize
+}
+
+impl Sessions {
+    // None if limit_conn of the client address is exceeded
+    fn get_or_create(&mut self, poll: &Poll, client: SocketAddr) -> std::io::Result<Option<Token>> {
+        if let Some(token) = self.clients.get(&client) {
+            return Ok(Some(*token));
+        }
+        let slot = match Limits::acquire(&self.proxy.limits, client.ip()) {
+            Some(slot) => slot,
+            None => return Ok(None)
+        };
+        if self.sessions.len() >= self.proxy.max_sessions {
+            self.evict(poll);
+        }
+        let mut stream = StreamSession::new(Transport::UDP, client, self.proxy.addr);
+        stream.upstream_addr = Some(self.proxy.upstream);
+        let mut upstream = connect(self.proxy.upstream)?;
+        let token = Token(self.next);
+        self.next = match self.next + 1 {
+            // the largest token is reserved by mio
+            usize::MAX => FIRST_SESSION,
+            next => next
+        };
+        poll.registry().register(&mut upstream, token, Interest::READABLE)?;
+        self.sessions.insert(token, Session {
+            client,
+            upstream,
+            active: Instant::now(),
+            responses: 0,
+            stream,
+            _slot: slot
+        });
+        self.clients.insert(client, token);
+        Ok(Some(token))
+    }
+
+    fn close(&mut self, poll: &Poll, token: Token, status: u16) {
+        if let Some(mut session) = self.sessions.remove(&token) {
+            let _ = poll.registry().deregister(&mut session.upstream);
+            self.clients.remove(&session.client);
+            session.stream.status = status;
+            self.log(&mut session.stream);
+        }
+    }
+
+    fn evict(&mut self, poll: &Poll) {
+        let idle = self.sessions.iter().min_by_key(|(_, session)| session.active).map(|(token, _)| *token);
+        if let Some(token) = idle {
+            log_error!("warn", "udp {}: max_sessions {} is reached, the session of the client {} is closed",
+                       self.proxy.addr, self.proxy.max_sessions, self.sessions[&token].client);
+            self.close(poll, token, STATUS_SERVICE_UNAVAILABLE);
+        }
+    }
+
+    fn log(&self, stream: &mut StreamSession) {
+        stream.finish();
+        self.proxy.log.iter().for_each(|log| log.handle(stream));
+    }
+
+    fn client_datagram(&mut self, poll: &Poll, client: SocketAddr, datagram: &[u8]) {
+        let status = match self.get_or_create(poll, client) {
+            Ok(Some(token)) => return self.send(poll, token, datagram),
+            Ok(None) => {
+                log_error!("warn", "udp {}: limit_conn of the client {} is exceeded", self.proxy.addr, client.ip());
+                STATUS_SERVICE_UNAVAILABLE
+            },
+            Err(err) => {
+                log_error!("error", "udp {}: failed to connect upstream {}, {}", self.proxy.addr, self.proxy.upstream, err);
+                STATUS_BAD_GATEWAY
+            }
+        };
+        let mut stream = StreamSession::new(Transport::UDP, client, self.proxy.addr);
+        stream.upstream_addr = Some(self.proxy.upstream);
+        stream.bytes_received = datagram.len() as u64;
+        stream.status = status;
+        self.log(&mut stream);
+    }
+
+    fn send(&mut self, poll: &Poll, token: Token, datagram: &[u8]) {
+        let session = self.sessions.get_mut(&token).unwrap();
+        session.active = Instant::now();
+        // datagram over limit_rate is dropped
+        if !self.proxy.limits.admit(session.client.ip(), datagram.len()) {
+            return;
+        }
+        session.stream.bytes_received += datagram.len() as u64;
+        match session.upstream.send(datagram) {
+            Ok(_) => {},
+            // datagram is dropped as the network would do
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {},
+            Err(err) => {
+                log_error!("warn", "udp {}: send to upstream {}, {}", self.proxy.addr, self.proxy.upstream, err);
+                self.close(poll, token, STATUS_BAD_GATEWAY);
+            }
+        }
+    }
+
+    fn upstream_readable(&mut self, poll: &Poll, listener: &UdpSocket, token: Token, buf: &mut [u8]) {
+        let mut closed = None;
+        if let Some(session) = self.sessions.get_mut(&token) {
+            loop {
+                match session.upstream.recv(buf) {
+                    Ok(n) => {
+                        session.active = Instant::now();
+                        session.responses += 1;
+                        // datagram over limit_rate is dropped
+                        if self.proxy.limits.admit(session.client.ip(), n) {
+                            match listener.send_to(&buf[..n], session.client) {
+                                Ok(_) => session.stream.bytes_sent += n as u64,
+                                Err(err) if err.kind() == ErrorKind::WouldBlock => {},
+                                Err(err) => log_error!("warn", "udp {}: send to client {}, {}", self.proxy.addr, session.client, err)
+                            }
+                        }
+                        if Some(session.responses) == self.proxy.responses {
+                            closed = Some(STATUS_OK);
+                            break;
+                        }
+                    },
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    // ICMP port unreachable of the upstream comes as the error of the connected socket
+                    Err(err) => {
+                        log_error!("warn", "udp {}: upstream {}, {}", self.proxy.addr, self.proxy.upstream, err);
+                        closed = Some(STATUS_BAD_GATEWAY);
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(status) = closed {
+            self.close(poll, token, status);
+        }
+    }
+
+    fn expire(&mut self, poll: &Poll) {
+        let timeout = self.proxy.timeout;
+        let expired: Vec<Token> = self.sessions.iter()
+                                      .filter(|(_, session)| session.active.elapsed() >= timeout)
+                                      .map(|(token, _)| *token)
+                                      .collect();
+        for token in expired {
+            self.close(poll, token, STATUS_OK);
+        }
+    }
+
+    // Sessions in progress are logged when the server stops
+    fn close_all(&mut self, poll: &Poll) {
+        let tokens: Vec<Token> = self.sessions.keys().cloned().collect();
+        for token in tokens {
+            self.close(poll, token, STATUS_OK);
+        }
+    }
+}
+
+impl UdpServer {
+    pub fn start(proxy: UdpProxy) -> Result<UdpServer, CoreError> {
+        let addr = proxy.addr;
+        let mut listener = bind(addr).or_else(|err| throw!("Failed to bind udp:{}: {}", addr, err))?;
+        let poll = Poll::new().or_else(|err| throw!("udp {}: {}", addr, err))?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE).or_else(|err| throw!("udp {}: {}", addr, err))?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).or_else(|err| throw!("udp {}: {}", addr, err))?);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_ = Arc::clone(&stop);
+
+        let thr = thread::Builder::new().name(format!("ws: udp {}", addr)).spawn(move || {
+            let mut poll = poll;
+            let mut events = Events::with_capacity(1024);
+            let mut buf = vec![0u8; DATAGRAM_SIZE];
+            let tick = std::cmp::min(proxy.timeout, Duration::from_secs(1));
+            let mut sessions = Sessions {
+                proxy,
+                sessions: HashMap::new(),
+                clients: HashMap::new(),
+                next: FIRST_SESSION
+            };
+            while !stop_.load(Ordering::Relaxed) {
+                if let Err(err) = poll.poll(&mut events, Some(tick)) {
+                    if err.kind() != ErrorKind::Interrupted {
+                        log_error!("error", "udp {}: poll, {}", addr, err);
+                        break;
+                    }
+                }
+                for event in events.iter() {
+                    match event.token() {
+                        LISTENER => loop {
+                            match listener.recv_from(&mut buf) {
+                                Ok((n, client)) => sessions.client_datagram(&poll, client, &buf[..n]),
+                                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                                Err(err) => {
+                                    log_error!("warn", "udp {}: recv, {}", addr, err);
+                                    break;
+                                }
+                            }
+                        },
+                        WAKER => {},
+                        token => sessions.upstream_readable(&poll, &listener, token, &mut buf)
+                    }
+                }
+                sessions.expire(&poll);
+            }
+            sessions.close_all(&poll);
+        }).or_else(|err| throw!("udp {}: {}", addr, err))?;
+
+        Ok(UdpServer {
+            addr,
+            stop,
+            waker,
+            thr: Some(thr)
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.waker.wake();
+    }
+
+    pub fn wait(&mut self) {
+        if let Some(thr) = self.thr.take() {
+            let _ = thr.join();
+        }
+    }
+}
@@ -5,20 +5,37 @@
 use std::ops::{ Deref, DerefMut };
 use mio::net::TcpStream;
 use std::net::{ SocketAddr, Shutdown };
-use std::os::unix::io::{ IntoRawFd, FromRawFd, AsRawFd };
+use std::os::unix::io::{ IntoRawFd, FromRawFd, AsRawFd, RawFd };
 use std::time::{ SystemTime, Duration };
 use mio::event::Source;
 use mio::{ Interest, Registry, Token };
-use std::io;
+use std::io::{ self, IoSlice };
 
 use crate::error::CoreError;
+use crate::connection_pool::TlsPin;
+use crate::core::poller;
+#[cfg(feature = "tls")]
+use crate::tcp::tls::upstream as tls;
 
 pub struct TcpSocket {
     stream: Option<TcpStream>,
     owned: bool,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
-    pub (crate) exp: Option<SystemTime>
+    pub (crate) exp: Option<SystemTime>,
+    // TLS of the upstream connection
+    #[cfg(feature = "tls")]
+    session: Option<tls::Session>
+}
+
+// Kernel TCP_INFO snapshot, times are in microseconds
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TcpInfo {
+    pub rtt: u32,
+    pub rttvar: u32,
+    pub snd_cwnd: u32,
+    pub rcv_space: u32,
+    pub retrans: u32
 }
 
 impl Deref for TcpSocket {
@@ -40,6 +57,24 @@ impl DerefMut for TcpSocket {
     }
 }
 
+impl AsRawFd for TcpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.stream {
+            Some(stream) => stream.as_raw_fd(),
+            None => unreachable!()
+        }
+    }
+}
+
+impl IntoRawFd for TcpSocket {
+    fn into_raw_fd(mut self) -> RawFd {
+        match self.stream.take() {
+            Some(stream) => stream.into_raw_fd(),
+            None => unreachable!()
+        }
+    }
+}
+
 impl Source for TcpSocket {
     fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
         -> io::Result<()>
@@ -74,7 +109,9 @@ impl TcpSocket {
             remote_addr: stream.peer_addr().or_else(|err| throw!(err))?,
             stream: Some(TcpStream::from(stream)),
             owned: true,
-            exp: None
+            exp: None,
+            #[cfg(feature = "tls")]
+            session: None
         })
     }
 
@@ -88,7 +125,9 @@ impl TcpSocket {
             exp: match timeout {
                 Some(timeout) => Some(SystemTime::now() + timeout),
                 None => None
-            }
+            },
+            #[cfg(feature = "tls")]
+            session: None
         })
     }
 
@@ -98,7 +137,9 @@ impl TcpSocket {
             owned: false,
             local_addr: self.local_addr,
             remote_addr: self.remote_addr,
-            exp: self.exp
+            exp: self.exp,
+            #[cfg(feature = "tls")]
+            session: self.session.clone()
         }
     }
 
@@ -114,8 +155,58 @@ impl TcpSocket {
             owned: owned,
             local_addr: self.local_addr,
             remote_addr: self.remote_addr,
-            exp: self.exp
+            exp: self.exp,
+            #[cfg(feature = "tls")]
+            session: self.session.take()
+        }
+    }
+
+    // TLS handshake of the connecting socket, it is bounded by the timeout of the connect
+    #[cfg(feature = "tls")]
+    pub fn handshake(&mut self, pin: &TlsPin) -> Result<(), CoreError> {
+        self.session = Some(tls::connect(self.as_raw_fd(), pin, self.exp)?);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn handshake(&mut self, _pin: &TlsPin) -> Result<(), CoreError> {
+        throw!("TLS upstreams are not supported, the binary is built without the 'tls' feature")
+    }
+
+    // Connection has the TLS identity of the pin, or no TLS at all without the pin
+    #[cfg(feature = "tls")]
+    pub fn identified(&self, pin: Option<&TlsPin>) -> bool {
+        match (&self.session, pin) {
+            (None, None) => true,
+            (Some(session), Some(pin)) => tls::matches(session, pin),
+            _ => false
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn identified(&self, pin: Option<&TlsPin>) -> bool {
+        pin.is_none()
+    }
+
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(session) = &self.session {
+                return io::Read::read(&mut *session.lock().unwrap(), buf);
+            }
         }
+        poller::recv(self, buf)
+    }
+
+    // TLS record takes one slice at once
+    pub fn send(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(session) = &self.session {
+                return io::Write::write(&mut *session.lock().unwrap(), &bufs[0]);
+            }
+        }
+        poller::send(self, bufs)
     }
 
     pub fn local_addr(&self) -> SocketAddr {
@@ -126,7 +217,34 @@ impl TcpSocket {
         self.remote_addr
     }
 
+    pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        let stream = match &self.stream {
+            Some(stream) => stream,
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected))
+        };
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO,
+                             &mut info as *mut libc::tcp_info as *mut libc::c_void, &mut len)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TcpInfo {
+            rtt: info.tcpi_rtt,
+            rttvar: info.tcpi_rttvar,
+            snd_cwnd: info.tcpi_snd_cwnd,
+            rcv_space: info.tcpi_rcv_space,
+            retrans: info.tcpi_total_retrans
+        })
+    }
+
     pub fn close(&mut self) {
+        #[cfg(feature = "tls")]
+        {
+            self.session = None;
+        }
         if let Some(stream) = self.stream.take() {
             let _ = stream.shutdown(Shutdown::Both);
         }
@@ -141,6 +259,19 @@ impl TcpSocket {
         false
     }
 
+    // Peer has gone away, pending data (e.g. a pipelined request) means it is still there
+    pub fn closed(&self) -> bool {
+        let stream = match &self.stream {
+            Some(stream) => stream,
+            None => return true
+        };
+        let mut byte = [0u8; 1];
+        match stream.peek(&mut byte) {
+            Ok(n) => n == 0,
+            Err(err) => !matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted)
+        }
+    }
+
     pub fn timedout(&self) -> bool {
         match self.exp {
             Some(exp) => exp <= SystemTime::now(),
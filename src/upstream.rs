@@ -10,6 +10,7 @@ use std::cmp::min;
 
 use crate::connection_pool::*;
 use crate::error::CoreError;
+use crate::core::status::Json;
 
 pub trait UpstreamBalance: Send + Sync {
     fn balance(&self, iter: Iter<SocketAddr, ConnectionPool>) -> Option<SocketAddr>;
@@ -44,6 +45,7 @@ pub struct Upstream {
     keepalive_timeout: Option<Duration>,
     keepalive_requests: Option<u64>,
     active: Arc<usize>,
+    cross_route: bool,
     servers: RwLock<[HashMap<SocketAddr, ConnectionPool>; 2]>,
     balancer: Box<dyn UpstreamBalance>
 }
@@ -59,14 +61,16 @@ impl Upstream {
         keepalive_requests: Option<u64>
     ) -> Upstream {
         Upstream {
-            max_keepalive: max_keepalive,
-            max_active: max_active,
+            // zero is unlimited as in the connection pool
+            max_keepalive: if max_keepalive == 0 { usize::MAX } else { max_keepalive },
+            max_active: if max_active == 0 { usize::MAX } else { max_active },
             timeout: timeout,
             keepalive_timeout: keepalive_timeout,
             keepalive_requests: keepalive_requests,
             name: name.to_string(),
             servers: RwLock::new([HashMap::new(), HashMap::new()]),
             active: Arc::new(0),
+            cross_route: true,
             balancer: balancer
         }
     }
@@ -97,24 +101,41 @@ impl Upstream {
                     ));
     }
 
-    pub fn connect(&self, timeout: Option<Duration>) -> Result<Peer, CoreError> {
+    pub fn set_cross_route(&mut self, cross_route: bool) {
+        self.cross_route = cross_route
+    }
+
+    pub fn connect(&self, timeout: Option<Duration>, route: Option<&str>, tls: Option<&TlsPin>) -> Result<Peer, CoreError> {
         let userdata = Box::new(Arc::clone(&self.active));
 
+        let pin = PeerPin {
+            upstream: self.name.clone(),
+            route: match self.cross_route {
+                true => None,
+                false => route.map(|route| route.to_string())
+            },
+            tls: tls.cloned()
+        };
+
         if self.active() == self.max_active {
             return throw!("Bad gateway");
         }
 
         let servers = self.servers.read().unwrap();
 
-        for i in 0..1 {
+        // backup servers are used when all primary ones are failed or draining
+        for i in 0..2 {
             for _ in 0..servers[i].len() {
                 match self.balancer.balance(servers[i].iter()) {
                     Some(addr) => {
                         match servers[i].get(&addr) {
                             Some(pool) => {
-                                if let Ok(mut peer) = pool.connect(&addr, timeout) {
-                                    peer.attach_userdata(userdata);
-                                    return Ok(peer);
+                                match pool.connect(&addr, timeout, &pin) {
+                                    Ok(mut peer) => {
+                                        peer.attach_userdata(userdata);
+                                        return Ok(peer);
+                                    },
+                                    Err(err) => log_error!("warn", "Failed to connect '{}' in upstream '{}': {}", addr, self.name, err)
                                 }
                             },
                             None => {
@@ -135,10 +156,53 @@ impl Upstream {
         min(self.max_active, Arc::strong_count(&self.active) - 1)
     }
 
+    pub fn drain(&self, addr: &SocketAddr, draining: bool) -> Result<(), CoreError> {
+        let servers = self.servers.read().unwrap();
+        match servers.iter().find_map(|servers| servers.get(addr)) {
+            Some(pool) => {
+                log_error!("info", "Upstream '{}' server {} drain {}", self.name, addr, if draining { "on" } else { "off" });
+                pool.drain(draining);
+                Ok(())
+            },
+            None => throw!("server {} not found in upstream '{}'", addr, self.name)
+        }
+    }
+
+    // draining, active, idle and drain completed flag
+    pub fn drain_status(&self, addr: &SocketAddr) -> Option<(bool, usize, usize, bool)> {
+        let servers = self.servers.read().unwrap();
+        servers.iter().find_map(|servers| servers.get(addr)).map(|pool| {
+            (pool.draining(), pool.active(), pool.idle(), pool.drained())
+        })
+    }
+
+    pub fn report(&self) -> Json {
+        let servers = self.servers.read().unwrap();
+        let mut peers = Vec::new();
+        for (i, kind) in ["primary", "backup"].iter().enumerate() {
+            for (addr, pool) in servers[i].iter() {
+                peers.push(Json::object(vec![
+                    ("address", addr.to_string().into()),
+                    ("kind", (*kind).into()),
+                    ("active", pool.active().into()),
+                    ("idle", pool.idle().into()),
+                    ("fails", pool.fails().into()),
+                    ("draining", pool.draining().into()),
+                    ("drained", pool.drained().into())
+                ]));
+            }
+        }
+        Json::object(vec![
+            ("active", self.active().into()),
+            ("idle", self.idle().into()),
+            ("peers", Json::Array(peers))
+        ])
+    }
+
     pub fn idle(&self) -> usize {
         let servers = self.servers.read().unwrap();
         let mut count = 0;
-        for i in 0..1 {
+        for i in 0..2 {
             for server in servers[i].values() {
                 count += server.idle()
             }
@@ -3,16 +3,90 @@
  */
 
 use std::str::FromStr;
+use percent_encoding::{ utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC };
 use regex::Regex;
 
 use crate::handler::sync::ConstRefHandler;
+use crate::core::status::Json;
 
 pub type LazyHandler<T> = ConstRefHandler<T, String>;
 
+// Unreserved characters are kept by the url filter
+const URL: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(Clone, Copy)]
+enum Filter {
+    // inside of the JSON string, quotes are not added
+    Json,
+    Url
+}
+
+impl Filter {
+    fn parse(name: &str) -> Option<Filter> {
+        match name.trim() {
+            "json" => Some(Filter::Json),
+            "url" => Some(Filter::Url),
+            _ => None
+        }
+    }
+
+    fn apply(&self, value: String) -> String {
+        match self {
+            Filter::Json => {
+                let quoted = Json::Str(value).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            },
+            Filter::Url => utf8_percent_encode(&value, URL).to_string()
+        }
+    }
+}
+
+// ${name:-default|filter|...}, the default is taken for the empty or undefined value
+#[derive(Clone)]
+struct Var {
+    name: String,
+    default: Option<String>,
+    filters: Vec<Filter>
+}
+
+impl Var {
+    // Unknown filters are an error of the strict parse and skipped otherwise
+    fn parse(s: &str, strict: bool) -> Result<Var, String> {
+        let mut parts = s.split('|');
+        let head = parts.next().unwrap_or_default();
+        let (name, default) = match head.find(":-") {
+            Some(pos) => (&head[..pos], Some(head[pos + 2..].to_string())),
+            None => (head, None)
+        };
+        let mut filters = vec![];
+        for filter in parts {
+            match Filter::parse(filter) {
+                Some(filter) => filters.push(filter),
+                None if strict => return Err(format!("unknown filter '{}' of '${{{}}}'", filter.trim(), s)),
+                None => {}
+            }
+        }
+        Ok(Var {
+            name: name.trim().to_string(),
+            default,
+            filters
+        })
+    }
+
+    fn expand(&self, value: Option<String>) -> String {
+        let value = match (value, &self.default) {
+            (Some(value), _) if !value.is_empty() => value,
+            (_, Some(default)) => default.clone(),
+            (value, None) => value.unwrap_or_default()
+        };
+        self.filters.iter().fold(value, |value, filter| filter.apply(value))
+    }
+}
+
 #[derive(Clone)]
 enum Part {
     Text(String),
-    Var(String)
+    Var(Var)
 }
 
 enum Inner<T> {
@@ -51,8 +125,6 @@ impl<T> Clone for Variable<T> {
     }
 }
 
-const EMPTY_STR: String = String::new();
-
 impl<T> Variable<T> {
     pub fn simple(s: &str) -> Variable<T> {
         Variable {
@@ -60,6 +132,7 @@ impl<T> Variable<T> {
         }
     }
 
+    // Unknown filters are skipped, parse() reports them
     pub fn complex(s: &str) -> Variable<T> {
         let mut parts = vec![];
 
@@ -68,8 +141,9 @@ impl<T> Variable<T> {
 
         re.find_iter(s).for_each(|m| {
             let var = m.as_str().trim_start_matches("${").trim_end_matches("}");
+            let var = Var::parse(var, false).unwrap();
             parts.push(Part::Text(s[start..m.start()].to_string()));
-            parts.push(Part::Var(var.to_string()));
+            parts.push(Part::Var(var));
             start = m.end();
         });
 
@@ -80,12 +154,39 @@ impl<T> Variable<T> {
         }
     }
 
+    // Complex value with the filters checked
+    pub fn parse(s: &str) -> Result<Variable<T>, String> {
+        let re = Regex::new("\\$\\{([^}]+)}").unwrap();
+        for captures in re.captures_iter(s) {
+            Var::parse(&captures[1], true)?;
+        }
+        Ok(Variable::complex(s))
+    }
+
     pub fn lazy(h: LazyHandler<T>) -> Variable<T> {
         Variable {
             inner: Inner::Lazy(h)
         }
     }
 
+    // Value without variables
+    pub fn text(&self) -> Option<String> {
+        match &self.inner {
+            Inner::CV(parts) => {
+                let mut text = String::new();
+                for p in parts {
+                    match p {
+                        Part::Text(s) => text.push_str(s),
+                        Part::Var(_) => return None
+                    }
+                }
+                Some(text)
+            },
+            Inner::Simple(s) => Some(s.clone()),
+            Inner::Lazy(_) => None
+        }
+    }
+
     pub fn expand_with<F>(&self, f: F, r: &T) -> String
     where
         F: Fn(&str) -> Option<String>
@@ -96,10 +197,7 @@ impl<T> Variable<T> {
                 parts.iter().for_each(|p| {
                     ll.push(match p {
                         Part::Text(text) => text.clone(),
-                        Part::Var(var) => match (f)(&var) {
-                            Some(s) => s,
-                            None => EMPTY_STR
-                        }
+                        Part::Var(var) => var.expand((f)(&var.name))
                     })
                 });
                 ll.concat()
@@ -114,7 +212,7 @@ impl<T> FromStr for Variable<T> {
     type Err = String;
     #[inline]
     fn from_str(s: &str) -> Result<Variable<T>, Self::Err> {
-        Ok(Variable::complex(s))
+        Variable::parse(s)
     }
 }
 